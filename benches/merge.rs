@@ -0,0 +1,95 @@
+//! Merge throughput: a 16-frame python stack interleaved into a 256-frame native one, and
+//! the boundary check on its own, matcher against the substring chain it replaced.
+//!
+//! Run with `cargo bench --bench merge` (add `--features parallel` for `merge_batch`).
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use std::hint::black_box;
+
+use mixed_stack_tracer::{BoundaryDetector, CPythonBoundaryDetector, CallFrame, SignalTracer};
+
+fn native_stack() -> Vec<CallFrame> {
+    (0..256)
+        .map(|i| {
+            // Mostly framework frames, with an eval loop every 16 frames.
+            let func = if i % 16 == 0 {
+                "_PyEval_EvalFrameDefault".to_string()
+            } else {
+                format!(
+                    "torch::autograd::Engine::evaluate_function_{}(std::shared_ptr<GraphTask>&, Node*)",
+                    i
+                )
+            };
+            CallFrame::native(format!("{:#x}", i), "libtorch.so", func, 0)
+        })
+        .collect()
+}
+
+fn python_stack() -> Vec<CallFrame> {
+    (0..16)
+        .map(|i| CallFrame::python(format!("{:#x}", i), "app.py", format!("g{}", i), i))
+        .collect()
+}
+
+fn substring_chain(frame: &CallFrame) -> bool {
+    let CallFrame::CFrame { func, .. } = frame else {
+        return false;
+    };
+    func.contains("PyEval_EvalFrame")
+        || func.contains("PyEval_EvalCode")
+        || func.starts_with("PyEval")
+        || func.contains("EvalFrameDefault")
+        || func.contains("EvalFrameEx")
+}
+
+fn boundary(c: &mut Criterion) {
+    let frames = native_stack();
+    let mut group = c.benchmark_group("boundary");
+    group.throughput(Throughput::Elements(frames.len() as u64));
+    group.bench_function("matcher", |b| {
+        b.iter(|| {
+            frames
+                .iter()
+                .filter(|f| CPythonBoundaryDetector.is_boundary(black_box(f)))
+                .count()
+        })
+    });
+    group.bench_function("substring_chain", |b| {
+        b.iter(|| {
+            frames
+                .iter()
+                .filter(|f| substring_chain(black_box(f)))
+                .count()
+        })
+    });
+    group.finish();
+}
+
+fn merge(c: &mut Criterion) {
+    let (python, native) = (python_stack(), native_stack());
+    let mut group = c.benchmark_group("merge");
+    group.throughput(Throughput::Elements(native.len() as u64));
+    group.bench_function("256x16", |b| {
+        b.iter_batched(
+            || (python.clone(), native.clone()),
+            |(python, native)| SignalTracer::merge_python_native_stacks(python, native),
+            BatchSize::SmallInput,
+        )
+    });
+    #[cfg(feature = "parallel")]
+    {
+        let tracer = SignalTracer::new();
+        group.throughput(Throughput::Elements(1000 * native.len() as u64));
+        group.bench_function("batch_1000", |b| {
+            b.iter_batched(
+                || vec![(python.clone(), native.clone()); 1000],
+                |stacks| tracer.merge_batch(stacks),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, boundary, merge);
+criterion_main!(benches);
@@ -0,0 +1,58 @@
+//! Cost of converting stacks between Python and Rust in `merge_python_native_stacks`:
+//! dict frames against `Frame` objects, for a 64-frame python stack interleaved into a
+//! 256-frame native one.
+//!
+//! Run with `cargo bench --features python --bench python_frames`.
+
+use std::time::{Duration, Instant};
+
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyModule};
+
+const ITERATIONS: u32 = 2_000;
+
+const SETUP: &std::ffi::CStr = c"
+def native_stack(make):
+    return [make('0x%x' % i, 'libpython.so', 'PyEval_EvalFrameDefault' if i % 4 == 0 else 'f%d' % i, 0)
+            for i in range(256)]
+
+def python_stack(make):
+    return [make('0x%x' % i, 'app.py', 'g%d' % i, i) for i in range(64)]
+
+def as_dict(ip, file, func, lineno):
+    return {'ip': ip, 'file': file, 'func': func, 'lineno': lineno}
+
+dict_inputs = (python_stack(as_dict), native_stack(as_dict))
+frame_inputs = (python_stack(mst.PyFrame), native_stack(mst.CFrame))
+";
+
+fn time(name: &str, merge: &Bound<'_, PyAny>, inputs: &Bound<'_, PyAny>) -> PyResult<Duration> {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        merge.call1(inputs.cast::<pyo3::types::PyTuple>()?)?;
+    }
+    let per_call = start.elapsed() / ITERATIONS;
+    println!("{:<8} {:>10.1?} per merge", name, per_call);
+    Ok(per_call)
+}
+
+fn main() -> PyResult<()> {
+    Python::initialize();
+    Python::attach(|py| {
+        let module = PyModule::new(py, "mixed_stack_tracer")?;
+        mixed_stack_tracer::python::mixed_stack_tracer(&module)?;
+        let globals = PyDict::new(py);
+        globals.set_item("mst", &module)?;
+        py.run(SETUP, Some(&globals), None)?;
+
+        let merge = module.getattr("merge_python_native_stacks")?;
+        let get = |name: &str| globals.get_item(name).map(Option::unwrap);
+        let dicts = time("dicts", &merge, &get("dict_inputs")?)?;
+        let frames = time("frames", &merge, &get("frame_inputs")?)?;
+        println!(
+            "Frame inputs take {:.0}% of the dict time",
+            100.0 * frames.as_secs_f64() / dicts.as_secs_f64()
+        );
+        Ok(())
+    })
+}
@@ -0,0 +1,57 @@
+//! `libmst_preload.so`: mixed-stack-tracer for processes you cannot modify.
+//!
+//! ```text
+//! LD_PRELOAD=/path/to/libmst_preload.so python serve.py
+//! ```
+//!
+//! When loaded into a Python process, installs the crash handler and starts the control
+//! socket (`control::socket_path()`, `/tmp/mixed-stack-tracer-<pid>.sock` by default), so
+//! that a crash leaves a stack on stderr and `dump` or `start-sampling` can be sent later.
+//! `MST_MODE` replaces the default `crash,control` and the other variables of
+//! `mixed_stack_tracer::auto` apply. `LD_PRELOAD` is inherited, so processes that are not
+//! Python (shells, compilers spawned by the service) are left alone unless
+//! `MST_PRELOAD_ANY=1`. Stacks are native ones, with the interpreter frames standing where
+//! the Python frames would be.
+
+#[cfg(target_os = "linux")]
+mod preload {
+    use mixed_stack_tracer::auto;
+
+    const DEFAULT_MODES: &str = "crash,control";
+    /// Also instrument processes that are not Python.
+    const ANY_ENV: &str = "MST_PRELOAD_ANY";
+
+    /// Whether the process has a Python interpreter linked in, statically or through
+    /// libpython.
+    fn is_python_process() -> bool {
+        !unsafe { libc::dlsym(libc::RTLD_DEFAULT, c"Py_IsInitialized".as_ptr()) }.is_null()
+    }
+
+    fn should_activate() -> bool {
+        let any = std::env::var_os(ANY_ENV).is_some_and(|v| v == "1");
+        // `MST_ENABLE=0` opts a process tree out without touching `LD_PRELOAD`.
+        let disabled = std::env::var(auto::ENABLE_ENV).is_ok_and(|v| v.trim() == "0");
+        !disabled && (any || is_python_process())
+    }
+
+    #[ctor::ctor(unsafe)]
+    fn activate_at_load() {
+        if !should_activate() {
+            return;
+        }
+        if let Err(err) = auto::activate(DEFAULT_MODES) {
+            eprintln!("mst-preload: not activated: {}", err);
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_is_python_process() {
+            // The test binary does not link libpython.
+            assert!(!is_python_process());
+        }
+    }
+}
@@ -0,0 +1,605 @@
+//! Allocation tracing: bytes and objects by merged stack, for leak hunting.
+//!
+//! `TracingAllocator` wraps a `GlobalAlloc` (the system allocator by default) and, while an
+//! `AllocTracer` runs, records the native stack of allocations as raw ips, together with the
+//! Python stack when the allocating thread holds the GIL and `python_stacks` is set. Frees
+//! are matched to their allocation, so a snapshot tells both what was allocated and what
+//! is still live, site by site.
+//!
+//! ```ignore
+//! #[global_allocator]
+//! static GLOBAL: TracingAllocator = TracingAllocator::system();
+//!
+//! let tracer = AllocTracer::start()?;
+//! run_workload();
+//! for site in tracer.stop().leaks() {
+//!     println!("{} bytes live at {}", site.live_bytes, site.frames[0].func());
+//! }
+//! ```
+//!
+//! Only allocations made through the wrapper are seen: Rust code, Rust extension modules
+//! included, but not C extensions calling `malloc`. Every allocation and free takes a lock
+//! while tracing, unless `sample_bytes` sets a sampling interval; sampled sites are scaled
+//! to the interval. Stacks are symbolized in `snapshot`, not when allocating.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use crate::capture::resolve_ip;
+use crate::frame_table::{FrameId, FrameTable};
+use crate::profile::{Profile, SampledStack};
+use crate::stack_tracer::SignalTracer;
+use crate::CallFrame;
+
+const MAX_DEPTH: usize = 64;
+
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+/// 0 records every allocation.
+static SAMPLE_BYTES: AtomicU64 = AtomicU64::new(0);
+static PYTHON_STACKS: AtomicBool = AtomicBool::new(false);
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+
+thread_local! {
+    /// Set while the thread is inside the tracer, whose own allocations are not traced.
+    static IN_TRACER: Cell<bool> = const { Cell::new(false) };
+    /// Bytes allocated since the last sampled allocation.
+    static UNSAMPLED: Cell<u64> = const { Cell::new(0) };
+}
+
+/// `GlobalAlloc` recording allocations while an `AllocTracer` runs, and passing them to
+/// `A` either way.
+#[derive(Debug, Default)]
+pub struct TracingAllocator<A = System> {
+    inner: A,
+}
+
+impl TracingAllocator<System> {
+    pub const fn system() -> Self {
+        TracingAllocator { inner: System }
+    }
+}
+
+impl<A> TracingAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        TracingAllocator { inner }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for TracingAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        allocated(ptr, layout.size());
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc_zeroed(layout);
+        allocated(ptr, layout.size());
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        freed(ptr);
+        self.inner.dealloc(ptr, layout);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = self.inner.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            freed(ptr);
+            allocated(new_ptr, new_size);
+        }
+        new_ptr
+    }
+}
+
+/// Options of `AllocTracer::start_with`.
+#[derive(Clone, Debug, Default)]
+pub struct AllocTracerOptions {
+    sample_bytes: u64,
+    python_stacks: bool,
+}
+
+impl AllocTracerOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record about one allocation every `bytes` allocated per thread instead of all of
+    /// them; 0 (the default) records all.
+    pub fn sample_bytes(mut self, bytes: u64) -> Self {
+        self.sample_bytes = bytes;
+        self
+    }
+
+    /// Also record the Python stack of allocations made while holding the GIL. Runs
+    /// Python's frame accessors inside the allocator, so only for code that allocates
+    /// from Python callbacks at safe points.
+    pub fn python_stacks(mut self, enabled: bool) -> Self {
+        self.python_stacks = enabled;
+        self
+    }
+
+    pub fn get_sample_bytes(&self) -> u64 {
+        self.sample_bytes
+    }
+}
+
+/// One allocation site: a merged stack, leaf first, with what it allocated.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AllocSite {
+    pub frames: Vec<CallFrame>,
+    pub allocated_bytes: u64,
+    pub allocated_objects: u64,
+    /// Allocated and not freed yet.
+    pub live_bytes: u64,
+    pub live_objects: u64,
+}
+
+/// What an `AllocProfile` to `Profile` conversion counts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AllocMetric {
+    AllocatedBytes,
+    AllocatedObjects,
+    LiveBytes,
+    LiveObjects,
+}
+
+/// Allocation sites of a tracing session, in first-seen order.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AllocProfile {
+    pub sites: Vec<AllocSite>,
+}
+
+impl AllocProfile {
+    pub fn total_allocated_bytes(&self) -> u64 {
+        self.sites.iter().map(|s| s.allocated_bytes).sum()
+    }
+
+    pub fn total_live_bytes(&self) -> u64 {
+        self.sites.iter().map(|s| s.live_bytes).sum()
+    }
+
+    /// Sites with live bytes, most first.
+    pub fn leaks(&self) -> Vec<&AllocSite> {
+        let mut leaks: Vec<&AllocSite> = self.sites.iter().filter(|s| s.live_bytes > 0).collect();
+        leaks.sort_by_key(|s| std::cmp::Reverse(s.live_bytes));
+        leaks
+    }
+
+    /// A `Profile` whose counts are `metric`, for the flame graph exporters. Threads are
+    /// not distinguished (`tid` 0).
+    pub fn to_profile(&self, metric: AllocMetric) -> Profile {
+        let stacks: Vec<SampledStack> = self
+            .sites
+            .iter()
+            .map(|site| SampledStack {
+                tid: 0,
+                frames: site.frames.clone(),
+                count: match metric {
+                    AllocMetric::AllocatedBytes => site.allocated_bytes,
+                    AllocMetric::AllocatedObjects => site.allocated_objects,
+                    AllocMetric::LiveBytes => site.live_bytes,
+                    AllocMetric::LiveObjects => site.live_objects,
+                },
+                ..SampledStack::default()
+            })
+            .filter(|stack| stack.count > 0)
+            .collect();
+        Profile {
+            total_samples: stacks.iter().map(|s| s.count).sum(),
+            stacks,
+            dropped_samples: 0,
+        }
+    }
+}
+
+/// A running tracing session. Only one can be active per process, and it needs
+/// `TracingAllocator` to be the global allocator.
+pub struct AllocTracer {
+    _private: (),
+}
+
+impl AllocTracer {
+    pub fn start() -> io::Result<AllocTracer> {
+        Self::start_with(AllocTracerOptions::new())
+    }
+
+    pub fn start_with(options: AllocTracerOptions) -> io::Result<AllocTracer> {
+        // Goes through the global allocator, which marks itself installed.
+        drop(std::hint::black_box(Box::new(0u8)));
+        if !INSTALLED.load(Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "TracingAllocator is not the global allocator",
+            ));
+        }
+        with_state(|state| {
+            if state.is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    "an allocation tracer is already running",
+                ));
+            }
+            *state = Some(State::default());
+            Ok(())
+        })?;
+        SAMPLE_BYTES.store(options.sample_bytes, Ordering::SeqCst);
+        PYTHON_STACKS.store(options.python_stacks, Ordering::SeqCst);
+        ACTIVE.store(true, Ordering::SeqCst);
+        Ok(AllocTracer { _private: () })
+    }
+
+    /// Sites recorded so far, symbolized.
+    pub fn snapshot(&self) -> AllocProfile {
+        let raw = with_state(|state| state.as_ref().map(State::raw_sites).unwrap_or_default());
+        symbolize(raw)
+    }
+
+    /// Stop tracing and return the sites; allocations still live are reported live.
+    pub fn stop(self) -> AllocProfile {
+        self.snapshot()
+    }
+}
+
+impl Drop for AllocTracer {
+    fn drop(&mut self) {
+        ACTIVE.store(false, Ordering::SeqCst);
+        let state = with_state(Option::take);
+        // Freed outside the lock, untraced like the tracer's other allocations.
+        untraced(|| drop(state));
+    }
+}
+
+#[derive(Default)]
+struct State {
+    python: FrameTable,
+    index: HashMap<(Vec<usize>, Vec<FrameId>), usize>,
+    sites: Vec<Site>,
+    /// Address of every traced allocation still live: its site and the amounts it
+    /// stands for.
+    live: HashMap<usize, (usize, u64, u64)>,
+}
+
+struct Site {
+    ips: Vec<usize>,
+    python: Vec<FrameId>,
+    allocated: (u64, u64),
+    live: (u64, u64),
+}
+
+struct RawSite {
+    ips: Vec<usize>,
+    python: Vec<CallFrame>,
+    allocated: (u64, u64),
+    live: (u64, u64),
+}
+
+impl State {
+    fn record(&mut self, ptr: usize, ips: &[usize], python: &[CallFrame], amounts: (u64, u64)) {
+        let key = (ips.to_vec(), self.python.intern_stack(python));
+        let site = match self.index.get(&key) {
+            Some(i) => *i,
+            None => {
+                let i = self.sites.len();
+                self.sites.push(Site {
+                    ips: key.0.clone(),
+                    python: key.1.clone(),
+                    allocated: (0, 0),
+                    live: (0, 0),
+                });
+                self.index.insert(key, i);
+                i
+            }
+        };
+        let site_amounts = &mut self.sites[site];
+        site_amounts.allocated.0 += amounts.0;
+        site_amounts.allocated.1 += amounts.1;
+        site_amounts.live.0 += amounts.0;
+        site_amounts.live.1 += amounts.1;
+        self.live.insert(ptr, (site, amounts.0, amounts.1));
+    }
+
+    fn free(&mut self, ptr: usize) {
+        if let Some((site, bytes, objects)) = self.live.remove(&ptr) {
+            let live = &mut self.sites[site].live;
+            live.0 -= bytes;
+            live.1 -= objects;
+        }
+    }
+
+    fn raw_sites(&self) -> Vec<RawSite> {
+        self.sites
+            .iter()
+            .map(|site| RawSite {
+                ips: site.ips.clone(),
+                python: self.python.stack(&site.python),
+                allocated: site.allocated,
+                live: site.live,
+            })
+            .collect()
+    }
+}
+
+/// Run `f` with allocations of this thread untraced.
+fn untraced<T>(f: impl FnOnce() -> T) -> T {
+    let was = IN_TRACER.with(|t| t.replace(true));
+    let result = f();
+    IN_TRACER.with(|t| t.set(was));
+    result
+}
+
+/// Run `f` on the state, untraced, so that allocating under the lock cannot re-enter it.
+fn with_state<T>(f: impl FnOnce(&mut Option<State>) -> T) -> T {
+    untraced(|| f(&mut STATE.lock().unwrap_or_else(|e| e.into_inner())))
+}
+
+/// Whether this thread may trace now; false inside the tracer and once its thread-locals
+/// are gone.
+fn may_trace() -> bool {
+    ACTIVE.load(Ordering::Relaxed) && IN_TRACER.try_with(|t| !t.get()).unwrap_or(false)
+}
+
+fn allocated(ptr: *mut u8, size: usize) {
+    if !INSTALLED.load(Ordering::Relaxed) {
+        INSTALLED.store(true, Ordering::Relaxed);
+    }
+    if ptr.is_null() || size == 0 || !may_trace() {
+        return;
+    }
+    let Some(amounts) = sampled_amounts(size as u64) else {
+        return;
+    };
+    untraced(|| record(ptr as usize, amounts));
+}
+
+fn freed(ptr: *mut u8) {
+    if may_trace() {
+        with_state(|state| {
+            if let Some(state) = state.as_mut() {
+                state.free(ptr as usize);
+            }
+        });
+    }
+}
+
+/// `(bytes, objects)` an allocation of `size` stands for, `None` when it is not sampled.
+fn sampled_amounts(size: u64) -> Option<(u64, u64)> {
+    let interval = SAMPLE_BYTES.load(Ordering::Relaxed);
+    if interval == 0 {
+        return Some((size, 1));
+    }
+    let unsampled = UNSAMPLED.with(|u| u.get()) + size;
+    if unsampled < interval {
+        UNSAMPLED.with(|u| u.set(unsampled));
+        return None;
+    }
+    UNSAMPLED.with(|u| u.set(unsampled % interval));
+    let bytes = size.max(interval);
+    Some((bytes, bytes / size))
+}
+
+#[inline(never)]
+fn record(ptr: usize, amounts: (u64, u64)) {
+    let mut ips = [0usize; MAX_DEPTH];
+    let mut depth = 0;
+    // Frames of the unwinder and of this function are not recorded.
+    let mut below_record = true;
+    unsafe {
+        backtrace::trace_unsynchronized(|frame| {
+            if below_record {
+                below_record =
+                    !std::ptr::eq(frame.symbol_address() as *const (), record as *const ());
+                return true;
+            }
+            ips[depth] = frame.ip() as usize;
+            depth += 1;
+            depth < MAX_DEPTH
+        })
+    };
+    let python = python_stack();
+    with_state(|state| {
+        if let Some(state) = state.as_mut() {
+            state.record(ptr, &ips[..depth], &python, amounts);
+        }
+    });
+}
+
+#[cfg(feature = "python")]
+fn python_stack() -> Vec<CallFrame> {
+    use pyo3::Python;
+
+    extern "C" {
+        // Missing from pyo3's limited-API bindings, exported by every CPython 3.
+        fn PyGILState_Check() -> std::ffi::c_int;
+    }
+
+    if !PYTHON_STACKS.load(Ordering::Relaxed)
+        || unsafe { pyo3::ffi::Py_IsInitialized() } == 0
+        || unsafe { PyGILState_Check() } == 0
+    {
+        return Vec::new();
+    }
+    Python::attach(SignalTracer::capture_python_stack)
+        .map(|trace| trace.frames)
+        .unwrap_or_default()
+}
+
+#[cfg(not(feature = "python"))]
+fn python_stack() -> Vec<CallFrame> {
+    Vec::new()
+}
+
+fn symbolize(raw: Vec<RawSite>) -> AllocProfile {
+    let mut symbols: HashMap<usize, CallFrame> = HashMap::new();
+    let sites = raw
+        .into_iter()
+        .map(|site| {
+            let native: Vec<CallFrame> = site
+                .ips
+                .iter()
+                .map(|ip| {
+                    symbols
+                        .entry(*ip)
+                        .or_insert_with(|| resolve_ip(*ip))
+                        .clone()
+                })
+                .collect();
+            AllocSite {
+                frames: SignalTracer::merge_python_native_stacks(
+                    site.python,
+                    strip_allocator_frames(native),
+                ),
+                allocated_bytes: site.allocated.0,
+                allocated_objects: site.allocated.1,
+                live_bytes: site.live.0,
+                live_objects: site.live.1,
+            }
+        })
+        .collect();
+    AllocProfile { sites }
+}
+
+/// Drop the leaf frames of the wrapper and of the allocator shims (`__rust_alloc`,
+/// `__rg_alloc`, ...), so that stacks start at the allocating code.
+fn strip_allocator_frames(mut frames: Vec<CallFrame>) -> Vec<CallFrame> {
+    let machinery = |f: &CallFrame| {
+        let func = f.func();
+        // Shims may be qualified, e.g. `__rustc[<hash>]::__rust_alloc`.
+        let name = func.rsplit("::").next().unwrap_or(func);
+        func.contains("alloc_tracer::")
+            || ["__rust_", "__rdl_", "__rg_"]
+                .iter()
+                .any(|shim| name.starts_with(shim))
+    };
+    let leading = frames.iter().take_while(|f| machinery(f)).count();
+    frames.drain(..leading);
+    frames
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[global_allocator]
+    static GLOBAL: TracingAllocator = TracingAllocator::system();
+
+    /// Serializes the tests, as only one tracer can run per process.
+    static LOCK: Mutex<()> = Mutex::new(());
+
+    #[inline(never)]
+    fn leak_buffers(n: usize) -> Vec<Vec<u8>> {
+        (0..n)
+            .map(|_| std::hint::black_box(vec![0u8; 4096]))
+            .collect()
+    }
+
+    fn sites_in<'a>(profile: &'a AllocProfile, func: &str) -> Vec<&'a AllocSite> {
+        profile
+            .sites
+            .iter()
+            .filter(|s| s.frames.iter().any(|f| f.func().contains(func)))
+            .collect()
+    }
+
+    #[test]
+    fn test_live_and_freed_allocations() {
+        let _guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let tracer = AllocTracer::start().unwrap();
+        assert!(AllocTracer::start().is_err());
+
+        let kept = leak_buffers(4);
+        drop(leak_buffers(3));
+        let profile = tracer.stop();
+        drop(kept);
+
+        let sites = sites_in(&profile, "leak_buffers");
+        let allocated: u64 = sites.iter().map(|s| s.allocated_bytes).sum();
+        let live: u64 = sites.iter().map(|s| s.live_bytes).sum();
+        assert!(allocated >= 7 * 4096, "{:?}", sites);
+        assert!((4 * 4096..5 * 4096).contains(&live), "{:?}", sites);
+        // The allocator frames are gone from the leaf end.
+        assert!(sites
+            .iter()
+            .all(|s| !s.frames[0].func().contains("alloc_tracer::allocated")));
+
+        let by_live = profile.to_profile(AllocMetric::LiveBytes);
+        assert_eq!(by_live.total_samples, profile.total_live_bytes());
+        assert!(profile
+            .leaks()
+            .windows(2)
+            .all(|w| w[0].live_bytes >= w[1].live_bytes));
+    }
+
+    #[test]
+    fn test_sampled_allocations() {
+        let _guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let tracer =
+            AllocTracer::start_with(AllocTracerOptions::new().sample_bytes(16 * 4096)).unwrap();
+        let kept = leak_buffers(64);
+        let profile = tracer.stop();
+        drop(kept);
+
+        // 256 KiB in samples of 64 KiB, give or take where the interval falls.
+        let sites = sites_in(&profile, "leak_buffers");
+        let bytes: u64 = sites.iter().map(|s| s.allocated_bytes).sum();
+        assert!((2..=6).contains(&(bytes / (16 * 4096))), "{:?}", sites);
+        assert!(sites.iter().all(|s| s.allocated_bytes % (16 * 4096) == 0));
+    }
+
+    #[cfg(feature = "python")]
+    #[test]
+    fn test_python_stacks() {
+        use pyo3::prelude::*;
+        use pyo3::types::{PyCFunction, PyDict};
+
+        let _guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        Python::initialize();
+        let profile = Python::attach(|py| {
+            let allocate =
+                PyCFunction::new_closure(py, None, None, |_args, _kwargs| -> PyResult<usize> {
+                    Ok(leak_buffers(2).len())
+                })
+                .unwrap();
+            let globals = PyDict::new(py);
+            globals.set_item("allocate", allocate).unwrap();
+            let tracer =
+                AllocTracer::start_with(AllocTracerOptions::new().python_stacks(true)).unwrap();
+            py.run(
+                c"def handler():
+    return allocate()
+handler()
+",
+                Some(&globals),
+                None,
+            )
+            .unwrap();
+            tracer.stop()
+        });
+        let sites = sites_in(&profile, "leak_buffers");
+        assert!(
+            sites
+                .iter()
+                .any(|s| s.frames.iter().any(|f| f.func() == "handler")),
+            "{:?}",
+            sites
+        );
+    }
+
+    #[test]
+    fn test_strip_allocator_frames() {
+        let frames = vec![
+            CallFrame::native("0x1", "", "mixed_stack_tracer::alloc_tracer::record", 0),
+            CallFrame::native("0x2", "", "__rustc[5f3a]::__rust_alloc", 0),
+            CallFrame::native("0x3", "", "app::build", 0),
+        ];
+        let stripped = strip_allocator_frames(frames);
+        assert_eq!(stripped.len(), 1);
+        assert_eq!(stripped[0].func(), "app::build");
+    }
+}
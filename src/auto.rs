@@ -0,0 +1,273 @@
+//! Activation from environment variables, for enabling dumps without code changes.
+//!
+//! ```text
+//! MST_ENABLE=1 MST_MODE=crash,sigusr1,sample:49 python train.py
+//! ```
+//!
+//! | variable | meaning |
+//! |----------|---------|
+//! | `MST_ENABLE` | `1`, `true`, `yes` or `on` to activate anything at all |
+//! | `MST_MODE` | comma-separated modes, default `crash,sigusr1` |
+//! | `MST_OUTPUT` | file crash and signal dumps are appended to, default stderr |
+//! | `MST_PROFILE` | where `sample` writes folded stacks at exit, default `mst-profile-<pid>.folded` |
+//!
+//! Modes: `crash` (`crash_handler::install`), `faulthandler` (`crash_handler::enable`),
+//! `sigusr1` and `sigusr2` (`SignalTracer::install_dump_on_signal`), `sample[:HZ]` (a
+//! `Sampler` at HZ, default 99, until the process exits) and `control`
+//! (`control::ensure_started`).
+//!
+//! With the `auto` feature, `activate_from_env` runs when the library is loaded, so that
+//! preloading it or importing the extension module is all it takes; errors are reported
+//! on stderr and never abort the host. Stacks are native only: nothing can be assumed
+//! about a Python interpreter at load time.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::os::unix::io::IntoRawFd;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use crate::crash_handler;
+use crate::output::folded::{self, FoldedOptions};
+use crate::sampler::Sampler;
+use crate::signal_dump::{DumpSink, SignalDump};
+use crate::stack_tracer::SignalTracer;
+
+pub const ENABLE_ENV: &str = "MST_ENABLE";
+pub const MODE_ENV: &str = "MST_MODE";
+pub const OUTPUT_ENV: &str = "MST_OUTPUT";
+pub const PROFILE_ENV: &str = "MST_PROFILE";
+
+const DEFAULT_MODES: &str = "crash,sigusr1";
+const DEFAULT_HZ: u32 = 99;
+
+/// A subsystem `MST_MODE` can turn on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Crash,
+    Faulthandler,
+    /// Dump on this signal.
+    Signal(libc::c_int),
+    /// Sample at this frequency until exit.
+    Sample(u32),
+    Control,
+}
+
+/// What activation started; kept alive until the process exits.
+struct Active {
+    modes: Vec<Mode>,
+    _signal_dumps: Vec<SignalDump>,
+    sampler: Option<Sampler>,
+    profile: PathBuf,
+}
+
+static ACTIVE: Mutex<Option<Active>> = Mutex::new(None);
+
+/// Parse a `MST_MODE` value such as `crash,sigusr1,sample:49`.
+pub fn parse_modes(spec: &str) -> Result<Vec<Mode>, String> {
+    let mut modes = Vec::new();
+    for word in spec.split(',').map(str::trim).filter(|w| !w.is_empty()) {
+        let mode = match word.split_once(':') {
+            None if word == "crash" => Mode::Crash,
+            None if word == "faulthandler" => Mode::Faulthandler,
+            None if word == "sigusr1" => Mode::Signal(libc::SIGUSR1),
+            None if word == "sigusr2" => Mode::Signal(libc::SIGUSR2),
+            None if word == "sample" => Mode::Sample(DEFAULT_HZ),
+            None if word == "control" => Mode::Control,
+            Some(("sample", hz)) => Mode::Sample(
+                hz.trim_end_matches("hz")
+                    .parse()
+                    .ok()
+                    .filter(|hz| *hz > 0)
+                    .ok_or_else(|| format!("invalid sampling rate `{}`", hz))?,
+            ),
+            _ => return Err(format!("unknown mode `{}`", word)),
+        };
+        if matches!(mode, Mode::Crash | Mode::Faulthandler)
+            && modes
+                .iter()
+                .any(|m| matches!(m, Mode::Crash | Mode::Faulthandler))
+        {
+            return Err("`crash` and `faulthandler` exclude each other".to_string());
+        }
+        if !modes.contains(&mode) {
+            modes.push(mode);
+        }
+    }
+    Ok(modes)
+}
+
+/// Whether `MST_ENABLE` is set to a true value.
+pub fn is_enabled() -> bool {
+    std::env::var(ENABLE_ENV).is_ok_and(|v| {
+        matches!(
+            v.trim().to_ascii_lowercase().as_str(),
+            "1" | "true" | "yes" | "on"
+        )
+    })
+}
+
+/// Activate the modes of `MST_MODE` if `MST_ENABLE` is set, returning them (none when
+/// disabled). Activating again returns the modes of the first activation.
+pub fn activate_from_env() -> io::Result<Vec<Mode>> {
+    if !is_enabled() {
+        return Ok(Vec::new());
+    }
+    activate(DEFAULT_MODES)
+}
+
+/// Activate the modes of `MST_MODE`, or of `default_modes` when it is unset, whatever
+/// `MST_ENABLE` says. For loaders that are an opt-in of their own, like `LD_PRELOAD`.
+pub fn activate(default_modes: &str) -> io::Result<Vec<Mode>> {
+    let mut active = ACTIVE.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(active) = active.as_ref() {
+        return Ok(active.modes.clone());
+    }
+    let spec = std::env::var(MODE_ENV).unwrap_or_else(|_| default_modes.to_string());
+    let modes = parse_modes(&spec)
+        .map_err(|message| io::Error::new(io::ErrorKind::InvalidInput, message))?;
+    let output = std::env::var_os(OUTPUT_ENV)
+        .filter(|p| !p.is_empty())
+        .map(PathBuf::from);
+    let profile = std::env::var_os(PROFILE_ENV)
+        .filter(|p| !p.is_empty())
+        .map_or_else(
+            || PathBuf::from(format!("mst-profile-{}.folded", std::process::id())),
+            PathBuf::from,
+        );
+
+    let mut signal_dumps = Vec::new();
+    let mut sampler = None;
+    for mode in &modes {
+        match *mode {
+            Mode::Crash | Mode::Faulthandler => {
+                // The handler writes to this descriptor until the process dies.
+                let fd = match &output {
+                    Some(path) => append(path)?.into_raw_fd(),
+                    None => libc::STDERR_FILENO,
+                };
+                if *mode == Mode::Crash {
+                    crash_handler::install(fd)?;
+                } else {
+                    crash_handler::enable(fd)?;
+                }
+            }
+            Mode::Signal(signal) => {
+                let sink = output.clone().map_or(DumpSink::Stderr, DumpSink::File);
+                signal_dumps.push(SignalTracer::install_dump_on_signal(signal, sink)?);
+            }
+            Mode::Sample(hz) => sampler = Some(Sampler::start(hz)?),
+            Mode::Control => {
+                crate::control::ensure_started(None)?;
+            }
+        }
+    }
+    if sampler.is_some() {
+        unsafe { libc::atexit(write_profile_at_exit) };
+    }
+    *active = Some(Active {
+        modes: modes.clone(),
+        _signal_dumps: signal_dumps,
+        sampler,
+        profile,
+    });
+    Ok(modes)
+}
+
+fn append(path: &PathBuf) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+extern "C" fn write_profile_at_exit() {
+    let mut active = ACTIVE.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(active) = active.as_mut() else {
+        return;
+    };
+    let Some(sampler) = active.sampler.take() else {
+        return;
+    };
+    if sampler.is_forked() {
+        // A forked child exiting: the profile is the parent's to write.
+        return;
+    }
+    let profile = sampler.stop();
+    let written = File::create(&active.profile).and_then(|file| {
+        let mut out = BufWriter::new(file);
+        folded::write_profile(&mut out, &profile, &FoldedOptions::new())?;
+        out.flush()
+    });
+    if let Err(err) = written {
+        eprintln!(
+            "mixed-stack-tracer: cannot write {}: {}",
+            active.profile.display(),
+            err
+        );
+    }
+}
+
+#[cfg(feature = "auto")]
+#[ctor::ctor(unsafe)]
+fn activate_at_load() {
+    if let Err(err) = activate_from_env() {
+        eprintln!("mixed-stack-tracer: {} not activated: {}", MODE_ENV, err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use std::time::{Duration, Instant};
+
+    const CHILD_ENV: &str = "MST_TEST_AUTO_CHILD";
+
+    #[test]
+    fn test_parse_modes() {
+        assert_eq!(
+            parse_modes("crash, sigusr1,sample:49,sample:49hz"),
+            Ok(vec![
+                Mode::Crash,
+                Mode::Signal(libc::SIGUSR1),
+                Mode::Sample(49)
+            ])
+        );
+        assert_eq!(parse_modes("sample"), Ok(vec![Mode::Sample(99)]));
+        assert_eq!(parse_modes(""), Ok(vec![]));
+        assert!(parse_modes("sample:0").is_err());
+        assert!(parse_modes("crash,faulthandler").is_err());
+        assert!(parse_modes("tracing").is_err());
+    }
+
+    #[test]
+    fn test_activate_from_env() {
+        if std::env::var_os(CHILD_ENV).is_some() {
+            assert!(activate_from_env().unwrap().len() == 2);
+            unsafe { libc::raise(libc::SIGUSR1) };
+            let start = Instant::now();
+            while start.elapsed() < Duration::from_millis(300) {
+                std::hint::black_box(0u64);
+            }
+            // Runs the `atexit` handler writing the profile.
+            std::process::exit(0);
+        }
+
+        let dir = std::env::temp_dir().join(format!("mst-auto-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (output, profile) = (dir.join("dumps.txt"), dir.join("profile.folded"));
+        let status = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "auto::tests::test_activate_from_env"])
+            .env(CHILD_ENV, "1")
+            .env(ENABLE_ENV, "1")
+            .env(MODE_ENV, "sigusr1,sample:200")
+            .env(OUTPUT_ENV, &output)
+            .env(PROFILE_ENV, &profile)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let dumps = std::fs::read_to_string(&output).unwrap();
+        assert!(dumps.contains("Thread "), "{}", dumps);
+        let folded = std::fs::read_to_string(&profile).unwrap();
+        assert!(folded.contains("test_activate_from_env"), "{}", folded);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,415 @@
+//! `mst`: merged Python + native stacks of a running process.
+//!
+//! ```text
+//! mst dump <pid>                                   one-shot stacks of all threads
+//! mst record <pid> [-d SECS] [-r HZ] [-o FILE] [-f FORMAT] [--follow] [--count-recursion]
+//! mst watch <pid> [-i SECS]                        refresh the dump periodically
+//! mst progress <pid> [-i SECS] [-n SAMPLES]         tell a hung process from a slow one
+//! mst symbolize-offline <trace.jsonl> -s DIR [-o FILE]
+//!                                                  resolve raw ips with debug files in DIR
+//! ```
+
+use std::process::ExitCode;
+
+#[cfg(any(target_os = "linux", target_os = "macos", windows))]
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = match cli::parse(&args) {
+        Ok(command) => command,
+        Err(message) => {
+            eprintln!("mst: {}\n\n{}", message, cli::USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    match cli::run(command) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("mst: {}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn main() -> ExitCode {
+    eprintln!("mst: only Linux, macOS and Windows targets are supported");
+    ExitCode::FAILURE
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos", windows))]
+mod cli {
+    use std::fs::File;
+    use std::io::{self, BufWriter, Write};
+    use std::thread;
+    use std::time::Duration;
+
+    use mixed_stack_tracer::output::folded::{self, FoldedOptions};
+    use mixed_stack_tracer::output::{pprof, speedscope, text};
+    use mixed_stack_tracer::FrameFilter;
+    #[cfg(target_os = "linux")]
+    use mixed_stack_tracer::OfflineSymbolizer;
+    use mixed_stack_tracer::RemoteProcess;
+    use mixed_stack_tracer::StackMonitor;
+
+    pub const USAGE: &str = "usage:
+  mst dump <pid>
+  mst record <pid> [-d SECS] [-r HZ] [-o FILE] [-f folded|speedscope|pprof] [--follow]
+             [--count-recursion]
+  mst watch <pid> [-i SECS]
+  mst progress <pid> [-i SECS] [-n SAMPLES]
+  mst symbolize-offline <trace.jsonl> -s DIR [-o FILE]";
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Format {
+        Folded,
+        Speedscope,
+        Pprof,
+    }
+
+    impl Format {
+        fn parse(name: &str) -> Result<Format, String> {
+            match name {
+                "folded" => Ok(Format::Folded),
+                "speedscope" => Ok(Format::Speedscope),
+                "pprof" => Ok(Format::Pprof),
+                other => Err(format!("unknown format `{}`", other)),
+            }
+        }
+
+        /// Guess from an output file name, defaulting to folded stacks.
+        fn from_path(path: &str) -> Format {
+            if path.ends_with(".json") {
+                Format::Speedscope
+            } else if path.ends_with(".pb.gz") || path.ends_with(".pprof") {
+                Format::Pprof
+            } else {
+                Format::Folded
+            }
+        }
+    }
+
+    #[derive(Debug, PartialEq)]
+    pub enum Command {
+        Dump {
+            pid: i32,
+        },
+        Record {
+            pid: i32,
+            duration: Duration,
+            rate_hz: u32,
+            output: Option<String>,
+            format: Format,
+            /// Also record the children of the target (Linux).
+            follow: bool,
+            /// Collapse recursive call cycles before export (see `FrameFilter::count_recursion`).
+            count_recursion: bool,
+        },
+        Watch {
+            pid: i32,
+            interval: Duration,
+        },
+        Progress {
+            pid: i32,
+            interval: Duration,
+            samples: usize,
+        },
+        SymbolizeOffline {
+            input: String,
+            symbol_dir: String,
+            output: Option<String>,
+        },
+    }
+
+    pub fn parse(args: &[String]) -> Result<Command, String> {
+        let (command, rest) = args.split_first().ok_or("missing command")?;
+        if command == "symbolize-offline" {
+            return parse_symbolize_offline(rest);
+        }
+        let (pid, options) = rest.split_first().ok_or("missing pid")?;
+        let pid: i32 = pid.parse().map_err(|_| format!("invalid pid `{}`", pid))?;
+
+        let mut duration = Duration::from_secs(10);
+        let mut rate_hz = 100;
+        let mut interval = Duration::from_secs(1);
+        let mut samples = 5;
+        let mut output = None;
+        let mut format = None;
+        let mut follow = false;
+        let mut count_recursion = false;
+        let mut options = options.iter();
+        while let Some(flag) = options.next() {
+            let mut value = || options.next().ok_or(format!("`{}` needs a value", flag));
+            match (command.as_str(), flag.as_str()) {
+                ("record", "-d" | "--duration") => duration = seconds(value()?)?,
+                ("record", "-r" | "--rate") => {
+                    let v = value()?;
+                    rate_hz = v
+                        .parse()
+                        .ok()
+                        .filter(|r| *r > 0)
+                        .ok_or(format!("invalid rate `{}`", v))?;
+                }
+                ("record", "-o" | "--output") => output = Some(value()?.clone()),
+                ("record", "-f" | "--format") => format = Some(Format::parse(value()?)?),
+                ("record", "-F" | "--follow") => follow = true,
+                ("record", "--count-recursion") => count_recursion = true,
+                ("watch" | "progress", "-i" | "--interval") => interval = seconds(value()?)?,
+                ("progress", "-n" | "--samples") => {
+                    let v = value()?;
+                    samples = v
+                        .parse()
+                        .ok()
+                        .filter(|n| *n > 1)
+                        .ok_or(format!("invalid sample count `{}`", v))?;
+                }
+                _ => return Err(format!("unexpected argument `{}`", flag)),
+            }
+        }
+
+        match command.as_str() {
+            "dump" => Ok(Command::Dump { pid }),
+            "record" => Ok(Command::Record {
+                pid,
+                duration,
+                rate_hz,
+                format: format
+                    .unwrap_or_else(|| output.as_deref().map_or(Format::Folded, Format::from_path)),
+                output,
+                follow,
+                count_recursion,
+            }),
+            "watch" => Ok(Command::Watch { pid, interval }),
+            "progress" => Ok(Command::Progress {
+                pid,
+                interval,
+                samples,
+            }),
+            other => Err(format!("unknown command `{}`", other)),
+        }
+    }
+
+    fn parse_symbolize_offline(args: &[String]) -> Result<Command, String> {
+        let (input, options) = args.split_first().ok_or("missing trace file")?;
+        let mut symbol_dir = None;
+        let mut output = None;
+        let mut options = options.iter();
+        while let Some(flag) = options.next() {
+            let value = options.next().ok_or(format!("`{}` needs a value", flag))?;
+            match flag.as_str() {
+                "-s" | "--symbols" => symbol_dir = Some(value.clone()),
+                "-o" | "--output" => output = Some(value.clone()),
+                _ => return Err(format!("unexpected argument `{}`", flag)),
+            }
+        }
+        Ok(Command::SymbolizeOffline {
+            input: input.clone(),
+            symbol_dir: symbol_dir.ok_or("missing symbol directory (-s DIR)")?,
+            output,
+        })
+    }
+
+    fn seconds(value: &str) -> Result<Duration, String> {
+        value
+            .parse::<f64>()
+            .ok()
+            .filter(|s| s.is_finite() && *s > 0.0)
+            .map(Duration::from_secs_f64)
+            .ok_or(format!("invalid duration `{}`", value))
+    }
+
+    pub fn run(command: Command) -> io::Result<()> {
+        match command {
+            Command::Dump { pid } => {
+                let stacks = RemoteProcess::attach(pid)?.dump()?;
+                text::write_thread_stacks(&mut io::stdout().lock(), &stacks)
+            }
+            Command::Record {
+                pid,
+                duration,
+                rate_hz,
+                output,
+                format,
+                follow,
+                count_recursion,
+            } => {
+                let process = RemoteProcess::attach(pid)?;
+                let interval = Duration::from_secs_f64(1.0 / rate_hz as f64);
+                let profile = if follow {
+                    record_following(&process, interval, duration)?
+                } else {
+                    process.record(interval, duration)?
+                };
+                let profile = if count_recursion {
+                    FrameFilter::new()
+                        .count_recursion(true)
+                        .apply_to_profile(profile)
+                } else {
+                    profile
+                };
+                eprintln!(
+                    "mst: {} samples ({} ticks missed)",
+                    profile.total_samples, profile.dropped_samples
+                );
+                let mut out: Box<dyn Write> = match &output {
+                    Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+                    None => Box::new(io::stdout().lock()),
+                };
+                match format {
+                    Format::Folded => {
+                        folded::write_profile(&mut out, &profile, &FoldedOptions::new())?
+                    }
+                    Format::Speedscope => {
+                        speedscope::write_profile(&mut out, &profile, &format!("mst {}", pid))?
+                    }
+                    Format::Pprof => pprof::write_profile(&mut out, &profile)?,
+                }
+                out.flush()
+            }
+            Command::Watch { pid, interval } => {
+                let process = RemoteProcess::attach(pid)?;
+                loop {
+                    let stacks = process.dump()?;
+                    let mut out = io::stdout().lock();
+                    // Clear the screen and home the cursor before each refresh.
+                    write!(out, "\x1b[2J\x1b[H")?;
+                    text::write_thread_stacks(&mut out, &stacks)?;
+                    out.flush()?;
+                    drop(out);
+                    thread::sleep(interval);
+                }
+            }
+            Command::Progress {
+                pid,
+                interval,
+                samples,
+            } => {
+                let report = StackMonitor::is_progressing(pid, interval, samples)?;
+                print!("{}", report);
+                Ok(())
+            }
+            #[cfg(target_os = "linux")]
+            Command::SymbolizeOffline {
+                input,
+                symbol_dir,
+                output,
+            } => {
+                let out: Box<dyn Write> = match &output {
+                    Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+                    None => Box::new(io::stdout().lock()),
+                };
+                let lines = OfflineSymbolizer::symbolize_file(&input, out, &symbol_dir)?;
+                eprintln!("mst: {} records symbolized", lines);
+                Ok(())
+            }
+            // Debug files are looked up by GNU build-id, which PE modules do not have.
+            #[cfg(not(target_os = "linux"))]
+            Command::SymbolizeOffline { .. } => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "symbolize-offline needs ELF modules",
+            )),
+        }
+    }
+
+    /// Profile of `process` and its descendants, stacks rooted at their pid.
+    #[cfg(target_os = "linux")]
+    fn record_following(
+        process: &RemoteProcess,
+        interval: Duration,
+        duration: Duration,
+    ) -> io::Result<mixed_stack_tracer::Profile> {
+        let session = process.record_following(interval, duration)?;
+        eprintln!("mst: followed {} processes", session.processes.len());
+        Ok(session.merged())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn record_following(
+        _: &RemoteProcess,
+        _: Duration,
+        _: Duration,
+    ) -> io::Result<mixed_stack_tracer::Profile> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "--follow needs Linux",
+        ))
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn args(line: &str) -> Vec<String> {
+            line.split_whitespace().map(String::from).collect()
+        }
+
+        #[test]
+        fn test_parse_commands() {
+            assert_eq!(parse(&args("dump 42")), Ok(Command::Dump { pid: 42 }));
+            assert_eq!(
+                parse(&args("record 42 -d 30 -o out.json -r 50")),
+                Ok(Command::Record {
+                    pid: 42,
+                    duration: Duration::from_secs(30),
+                    rate_hz: 50,
+                    output: Some("out.json".to_string()),
+                    format: Format::Speedscope,
+                    follow: false,
+                    count_recursion: false,
+                })
+            );
+            assert_eq!(
+                parse(&args(
+                    "record 42 -o out.json -f folded --follow --count-recursion"
+                )),
+                Ok(Command::Record {
+                    pid: 42,
+                    duration: Duration::from_secs(10),
+                    rate_hz: 100,
+                    output: Some("out.json".to_string()),
+                    format: Format::Folded,
+                    follow: true,
+                    count_recursion: true,
+                })
+            );
+            assert_eq!(
+                parse(&args("watch 42 -i 0.5")),
+                Ok(Command::Watch {
+                    pid: 42,
+                    interval: Duration::from_millis(500),
+                })
+            );
+            assert_eq!(
+                parse(&args("progress 42 -n 3")),
+                Ok(Command::Progress {
+                    pid: 42,
+                    interval: Duration::from_secs(1),
+                    samples: 3,
+                })
+            );
+            assert_eq!(
+                parse(&args(
+                    "symbolize-offline trace.jsonl -s /debug -o out.jsonl"
+                )),
+                Ok(Command::SymbolizeOffline {
+                    input: "trace.jsonl".to_string(),
+                    symbol_dir: "/debug".to_string(),
+                    output: Some("out.jsonl".to_string()),
+                })
+            );
+        }
+
+        #[test]
+        fn test_parse_errors() {
+            assert!(parse(&args("")).is_err());
+            assert!(parse(&args("dump")).is_err());
+            assert!(parse(&args("dump abc")).is_err());
+            assert!(parse(&args("dump 42 -d 3")).is_err());
+            assert!(parse(&args("record 42 -r 0")).is_err());
+            assert!(parse(&args("record 42 -d")).is_err());
+            assert!(parse(&args("progress 42 -n 1")).is_err());
+            assert!(parse(&args("frobnicate 42")).is_err());
+            assert!(parse(&args("symbolize-offline trace.jsonl")).is_err());
+            assert!(parse(&args("symbolize-offline trace.jsonl -s")).is_err());
+        }
+    }
+}
@@ -0,0 +1,436 @@
+//! Pluggable detection of interpreter boundary frames in native stacks.
+//! A boundary is the native frame where an interpreter evaluates one of its own frames.
+//! CPython and PyPy have detectors of their own; `PythonImplementation` picks one.
+//!
+//! The merge itself knows nothing about Python: the detectors for LuaJIT and Julia show how
+//! another runtime plugs in, with its frames as `CallFrame::InterpreterFrame`.
+
+use std::sync::LazyLock;
+
+use aho_corasick::{AhoCorasick, Anchored, Input, StartKind};
+use memchr::memmem::Finder;
+
+use crate::CallFrame;
+
+/// Substrings of CPython eval loop symbols, robust across versions
+/// (`_PyEval_EvalFrameDefault`, `PyEval_EvalFrameEx`, ...).
+const CPYTHON_CONTAINS: &[&str] = &[
+    "PyEval_EvalFrame",
+    "PyEval_EvalCode",
+    "EvalFrameDefault",
+    "EvalFrameEx",
+];
+
+/// Prefixes of CPython boundary symbols.
+const CPYTHON_PREFIXES: &[&str] = &["PyEval"];
+
+/// Prefix of the translated `PyFrame.execute_frame`, which PyPy's interpreter runs once
+/// per app-level frame (`pypy_g_execute_frame`, `pypy_g_execute_frame__AccessDirect_*`).
+/// Frames the JIT compiled run in anonymous machine code and have no boundary.
+const PYPY_PREFIXES: &[&str] = &["pypy_g_execute_frame"];
+
+/// LuaJIT's C API entries into Lua code (`lua_call`, `lua_pcall`, 5.2-style `*k` forms).
+/// Lua-to-Lua calls stay inside the VM, so one entry may evaluate several Lua frames.
+const LUAJIT_PREFIXES: &[&str] = &["lua_call", "lua_pcall"];
+
+/// Julia's dynamic dispatch, exported as `ijl_apply_generic` since 1.8.
+const JULIA_CONTAINS: &[&str] = &["jl_apply_generic"];
+
+static CPYTHON: LazyLock<BoundaryMatcher> = LazyLock::new(BoundaryMatcher::cpython);
+static PYPY: LazyLock<BoundaryMatcher> = LazyLock::new(BoundaryMatcher::pypy);
+static LUAJIT: LazyLock<BoundaryMatcher> = LazyLock::new(BoundaryMatcher::luajit);
+static JULIA: LazyLock<BoundaryMatcher> = LazyLock::new(BoundaryMatcher::julia);
+
+/// Decides whether a native frame marks an interpreter boundary.
+///
+/// Implement this to teach the merge about other interpreters (PyPy, Cython, embedded
+/// runtimes). Plain closures `Fn(&CallFrame) -> bool` implement it as well.
+pub trait BoundaryDetector: Send + Sync {
+    fn is_boundary(&self, frame: &CallFrame) -> bool;
+}
+
+impl<F> BoundaryDetector for F
+where
+    F: Fn(&CallFrame) -> bool + Send + Sync,
+{
+    fn is_boundary(&self, frame: &CallFrame) -> bool {
+        self(frame)
+    }
+}
+
+/// Default detector for CPython: matches `PyEval_*` / `*EvalFrame*` style symbols.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CPythonBoundaryDetector;
+
+impl BoundaryDetector for CPythonBoundaryDetector {
+    fn is_boundary(&self, frame: &CallFrame) -> bool {
+        CPYTHON.is_boundary(frame)
+    }
+}
+
+/// Detector for PyPy: matches the RPython function evaluating an app-level frame.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PyPyBoundaryDetector;
+
+impl BoundaryDetector for PyPyBoundaryDetector {
+    fn is_boundary(&self, frame: &CallFrame) -> bool {
+        PYPY.is_boundary(frame)
+    }
+}
+
+/// Detector for LuaJIT embedded in a native program; see `LUAJIT_PREFIXES`.
+///
+/// ```
+/// use mixed_stack_tracer::{CallFrame, LuaJitBoundaryDetector, SignalTracer};
+///
+/// let native = vec![
+///     CallFrame::native("0x1", "", "lj_vm_call", 0),
+///     CallFrame::native("0x2", "", "lua_pcall", 0),
+///     CallFrame::native("0x3", "", "main", 0),
+/// ];
+/// let lua = vec![CallFrame::interpreter("lua", "init.lua", "on_load", 12)];
+/// let merged =
+///     SignalTracer::merge_python_native_stacks_with_detector(lua, native, &LuaJitBoundaryDetector);
+/// let funcs: Vec<&str> = merged.iter().map(CallFrame::func).collect();
+/// assert_eq!(funcs, ["lj_vm_call", "on_load", "main"]);
+/// ```
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LuaJitBoundaryDetector;
+
+impl BoundaryDetector for LuaJitBoundaryDetector {
+    fn is_boundary(&self, frame: &CallFrame) -> bool {
+        LUAJIT.is_boundary(frame)
+    }
+}
+
+/// Detector for Julia: every dynamically dispatched call enters `jl_apply_generic`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JuliaBoundaryDetector;
+
+impl BoundaryDetector for JuliaBoundaryDetector {
+    fn is_boundary(&self, frame: &CallFrame) -> bool {
+        JULIA.is_boundary(frame)
+    }
+}
+
+/// Python implementation running in a process, which decides the boundary detector.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PythonImplementation {
+    #[default]
+    CPython,
+    PyPy,
+}
+
+impl PythonImplementation {
+    /// From `sys.implementation.name`; other implementations are treated as CPython.
+    pub fn from_name(name: &str) -> Self {
+        match name {
+            "pypy" => PythonImplementation::PyPy,
+            _ => PythonImplementation::CPython,
+        }
+    }
+
+    /// From the path of a loaded module or executable, if it is an interpreter
+    /// (`libpypy3.10-c.so`, `pypy3`, `libpython3.11.so.1.0`, ...).
+    pub fn from_path(path: &str) -> Option<Self> {
+        let name = path.rsplit(['/', '\\']).next()?.to_ascii_lowercase();
+        if name.starts_with("libpypy") || name.starts_with("pypy") {
+            Some(PythonImplementation::PyPy)
+        } else if name.starts_with("libpython") || name.starts_with("python") {
+            Some(PythonImplementation::CPython)
+        } else {
+            None
+        }
+    }
+
+    /// Boundary detector for native stacks of this implementation.
+    pub fn detector(self) -> &'static dyn BoundaryDetector {
+        match self {
+            PythonImplementation::CPython => &CPythonBoundaryDetector,
+            PythonImplementation::PyPy => &PyPyBoundaryDetector,
+        }
+    }
+}
+
+/// Boundary detector matching native function names against substring and prefix
+/// patterns, each set compiled into one Aho-Corasick automaton so a frame is checked in
+/// a single pass however many patterns there are.
+///
+/// Most frames are not boundaries; when all patterns share a substring (`Eval` for
+/// CPython), a SIMD substring search for it rejects those before the automata run.
+///
+/// ```
+/// use mixed_stack_tracer::{BoundaryDetector, BoundaryMatcher, CallFrame};
+///
+/// let matcher = BoundaryMatcher::cpython().starts_with("pypy_g_execute");
+/// assert!(matcher.is_boundary(&CallFrame::native("0x1", "", "pypy_g_execute_frame", 0)));
+/// assert!(matcher.is_boundary(&CallFrame::native("0x2", "", "_PyEval_EvalFrameDefault", 0)));
+/// ```
+#[derive(Clone, Debug)]
+pub struct BoundaryMatcher {
+    contains: Vec<String>,
+    prefixes: Vec<String>,
+    contains_automaton: Option<AhoCorasick>,
+    prefix_automaton: Option<AhoCorasick>,
+    /// Substring of every pattern, which any match contains.
+    required: Option<Finder<'static>>,
+}
+
+impl Default for BoundaryMatcher {
+    fn default() -> Self {
+        BoundaryMatcher::new()
+    }
+}
+
+impl BoundaryMatcher {
+    /// A matcher without patterns; it matches nothing.
+    pub fn new() -> Self {
+        BoundaryMatcher {
+            contains: Vec::new(),
+            prefixes: Vec::new(),
+            contains_automaton: None,
+            prefix_automaton: None,
+            required: None,
+        }
+    }
+
+    /// The patterns of `CPythonBoundaryDetector`, to extend with more.
+    pub fn cpython() -> Self {
+        let matcher = CPYTHON_CONTAINS
+            .iter()
+            .fold(Self::new(), |m, p| m.contains(*p));
+        CPYTHON_PREFIXES
+            .iter()
+            .fold(matcher, |m, p| m.starts_with(*p))
+    }
+
+    /// The patterns of `PyPyBoundaryDetector`.
+    pub fn pypy() -> Self {
+        PYPY_PREFIXES
+            .iter()
+            .fold(Self::new(), |m, p| m.starts_with(*p))
+    }
+
+    /// The patterns of `LuaJitBoundaryDetector`.
+    pub fn luajit() -> Self {
+        LUAJIT_PREFIXES
+            .iter()
+            .fold(Self::new(), |m, p| m.starts_with(*p))
+    }
+
+    /// The patterns of `JuliaBoundaryDetector`.
+    pub fn julia() -> Self {
+        JULIA_CONTAINS
+            .iter()
+            .fold(Self::new(), |m, p| m.contains(*p))
+    }
+
+    /// Also match functions containing `pattern`.
+    pub fn contains(mut self, pattern: impl Into<String>) -> Self {
+        self.contains.push(pattern.into());
+        self.contains_automaton = compile(&self.contains, StartKind::Unanchored);
+        self.required = self.common_substring();
+        self
+    }
+
+    /// Also match functions starting with `pattern`.
+    pub fn starts_with(mut self, pattern: impl Into<String>) -> Self {
+        self.prefixes.push(pattern.into());
+        self.prefix_automaton = compile(&self.prefixes, StartKind::Anchored);
+        self.required = self.common_substring();
+        self
+    }
+
+    pub fn get_contains(&self) -> &[String] {
+        &self.contains
+    }
+
+    pub fn get_prefixes(&self) -> &[String] {
+        &self.prefixes
+    }
+
+    /// Whether `func` matches any pattern.
+    pub fn matches(&self, func: &str) -> bool {
+        if let Some(required) = &self.required {
+            if required.find(func.as_bytes()).is_none() {
+                return false;
+            }
+        }
+        self.prefix_automaton
+            .as_ref()
+            .is_some_and(|ac| ac.is_match(Input::new(func).anchored(Anchored::Yes)))
+            || self
+                .contains_automaton
+                .as_ref()
+                .is_some_and(|ac| ac.is_match(func))
+    }
+
+    /// Longest substring (of at least 3 bytes) found in every pattern.
+    fn common_substring(&self) -> Option<Finder<'static>> {
+        let patterns: Vec<&str> = self
+            .contains
+            .iter()
+            .chain(&self.prefixes)
+            .map(String::as_str)
+            .collect();
+        let shortest = patterns.iter().min_by_key(|p| p.len())?;
+        (3..=shortest.len()).rev().find_map(|len| {
+            (0..=shortest.len() - len)
+                .filter_map(|start| shortest.get(start..start + len))
+                .find(|needle| patterns.iter().all(|p| p.contains(needle)))
+                .map(|needle| Finder::new(needle).into_owned())
+        })
+    }
+}
+
+impl BoundaryDetector for BoundaryMatcher {
+    fn is_boundary(&self, frame: &CallFrame) -> bool {
+        match frame {
+            CallFrame::CFrame { func, .. } => self.matches(func),
+            _ => false,
+        }
+    }
+}
+
+fn compile(patterns: &[String], start: StartKind) -> Option<AhoCorasick> {
+    AhoCorasick::builder()
+        .start_kind(start)
+        .build(patterns)
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::SignalTracer;
+
+    fn cframe(name: &str) -> CallFrame {
+        CallFrame::native("0x0", "", name, 0)
+    }
+
+    #[test]
+    fn test_cpython_detector() {
+        let detector = CPythonBoundaryDetector;
+        assert!(detector.is_boundary(&cframe("_PyEval_EvalFrameDefault")));
+        assert!(detector.is_boundary(&cframe("PyEval_EvalCode")));
+        assert!(detector.is_boundary(&cframe("PyEval_EvalFrameEx")));
+        assert!(!detector.is_boundary(&cframe("main")));
+        assert!(!detector.is_boundary(&cframe("PyObject_Call")));
+    }
+
+    #[test]
+    fn test_pypy_detector() {
+        let detector = PyPyBoundaryDetector;
+        assert!(detector.is_boundary(&cframe("pypy_g_execute_frame")));
+        assert!(detector.is_boundary(&cframe("pypy_g_execute_frame__AccessDirect_None")));
+        assert!(!detector.is_boundary(&cframe("pypy_g_dispatch_bytecode")));
+        assert!(!detector.is_boundary(&cframe("_PyEval_EvalFrameDefault")));
+        assert!(!CPythonBoundaryDetector.is_boundary(&cframe("pypy_g_execute_frame")));
+    }
+
+    #[test]
+    fn test_runtime_detectors() {
+        assert!(LuaJitBoundaryDetector.is_boundary(&cframe("lua_call")));
+        assert!(LuaJitBoundaryDetector.is_boundary(&cframe("lua_pcallk")));
+        assert!(!LuaJitBoundaryDetector.is_boundary(&cframe("lj_vm_call")));
+        assert!(JuliaBoundaryDetector.is_boundary(&cframe("ijl_apply_generic")));
+        assert!(JuliaBoundaryDetector.is_boundary(&cframe("jl_apply_generic")));
+        assert!(!JuliaBoundaryDetector.is_boundary(&cframe("jl_invoke")));
+        // Interpreter frames are never boundaries themselves.
+        let frame = CallFrame::interpreter("lua", "a.lua", "lua_call", 1);
+        assert!(!LuaJitBoundaryDetector.is_boundary(&frame));
+    }
+
+    #[test]
+    fn test_merge_julia_stack() {
+        let native = vec![
+            cframe("jl_f_sleep"),
+            cframe("ijl_apply_generic"),
+            cframe("julia_worker_123"),
+            cframe("ijl_apply_generic"),
+            cframe("jl_repl_entrypoint"),
+        ];
+        let julia = vec![
+            CallFrame::interpreter("julia", "worker.jl", "step", 8),
+            CallFrame::interpreter("julia", "worker.jl", "run", 20),
+        ];
+        let merged = SignalTracer::merge_python_native_stacks_with_detector(
+            julia,
+            native,
+            &JuliaBoundaryDetector,
+        );
+        assert_eq!(
+            merged,
+            vec![
+                cframe("jl_f_sleep"),
+                CallFrame::interpreter("julia", "worker.jl", "step", 8),
+                cframe("julia_worker_123"),
+                CallFrame::interpreter("julia", "worker.jl", "run", 20),
+                cframe("jl_repl_entrypoint"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_python_implementation() {
+        use PythonImplementation::*;
+        assert_eq!(PythonImplementation::from_name("pypy"), PyPy);
+        assert_eq!(PythonImplementation::from_name("cpython"), CPython);
+        assert_eq!(
+            PythonImplementation::from_path("/opt/pypy/bin/libpypy3.10-c.so"),
+            Some(PyPy)
+        );
+        assert_eq!(
+            PythonImplementation::from_path("/usr/bin/pypy3"),
+            Some(PyPy)
+        );
+        assert_eq!(
+            PythonImplementation::from_path("/usr/lib/libpython3.11.so.1.0"),
+            Some(CPython)
+        );
+        assert_eq!(PythonImplementation::from_path("/lib/libc.so.6"), None);
+        assert!(PyPy.detector().is_boundary(&cframe("pypy_g_execute_frame")));
+        assert!(CPython
+            .detector()
+            .is_boundary(&cframe("_PyEval_EvalFrameDefault")));
+    }
+
+    #[test]
+    fn test_boundary_matcher() {
+        let matcher = BoundaryMatcher::new()
+            .starts_with("lua_")
+            .contains("EvalFrame");
+        assert!(matcher.is_boundary(&cframe("lua_pcall")));
+        assert!(!matcher.is_boundary(&cframe("call_lua_pcall")));
+        assert!(matcher.is_boundary(&cframe("_PyEval_EvalFrameDefault")));
+        assert!(!matcher.is_boundary(&CallFrame::python("0x0", "", "lua_x", 0)));
+        assert!(!BoundaryMatcher::new().is_boundary(&cframe("lua_pcall")));
+
+        // Same answers as the substring chain it replaced.
+        let cpython = BoundaryMatcher::cpython();
+        for func in [
+            "_PyEval_EvalFrameDefault",
+            "PyEval_EvalCode",
+            "PyEval_EvalFrameEx",
+            "PyEval_RestoreThread",
+            "_PyEval_Vector",
+            "main",
+            "PyObject_Call",
+            "",
+        ] {
+            let chain = func.contains("PyEval_EvalFrame")
+                || func.contains("PyEval_EvalCode")
+                || func.starts_with("PyEval")
+                || func.contains("EvalFrameDefault")
+                || func.contains("EvalFrameEx");
+            assert_eq!(cpython.matches(func), chain, "{}", func);
+        }
+    }
+
+    #[test]
+    fn test_closure_detector() {
+        let detector = |f: &CallFrame| matches!(f, CallFrame::CFrame { func, .. } if func == "x");
+        assert!(detector.is_boundary(&cframe("x")));
+        assert!(!detector.is_boundary(&cframe("y")));
+    }
+}
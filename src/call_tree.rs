@@ -0,0 +1,301 @@
+//! Weighted call tree: many merged stacks accumulated into a prefix tree, root first.
+//!
+//! Every node counts the samples that ended in it (`self_count`) and the samples that passed
+//! through it (`total_count`). Trees export to the folded and pprof formats.
+
+use std::io::{self, Write};
+
+use crate::frame_table::{FrameId, FrameTable};
+use crate::output::folded::{self, FoldedOptions};
+use crate::output::pprof;
+use crate::profile::{Profile, SampledStack};
+use crate::CallFrame;
+
+const ROOT: usize = 0;
+
+#[derive(Clone, Debug)]
+struct Node {
+    /// `None` only for the root.
+    frame: Option<FrameId>,
+    parent: usize,
+    /// Child node indices in first-seen order.
+    children: Vec<usize>,
+    self_count: u64,
+    total_count: u64,
+}
+
+/// Prefix tree of stacks. Frames are identified as in `FrameTable` (kind, function, file,
+/// line), and threads are not distinguished.
+#[derive(Clone, Debug)]
+pub struct CallTree {
+    table: FrameTable,
+    nodes: Vec<Node>,
+}
+
+impl Default for CallTree {
+    fn default() -> Self {
+        CallTree {
+            table: FrameTable::new(),
+            nodes: vec![Node {
+                frame: None,
+                parent: ROOT,
+                children: Vec::new(),
+                self_count: 0,
+                total_count: 0,
+            }],
+        }
+    }
+}
+
+impl CallTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Tree of all stacks of `profile`, summed across threads.
+    pub fn from_profile(profile: &Profile) -> Self {
+        let mut tree = Self::new();
+        for stack in &profile.stacks {
+            tree.add_stack(&stack.frames, stack.count);
+        }
+        tree
+    }
+
+    /// Add `count` samples of a merged stack (leaf first, as the merge produces it).
+    pub fn add_stack(&mut self, frames: &[CallFrame], count: u64) {
+        let mut node = ROOT;
+        self.nodes[ROOT].total_count += count;
+        for frame in frames.iter().rev() {
+            let id = self.table.intern(frame);
+            node = self.child(node, id);
+            self.nodes[node].total_count += count;
+        }
+        self.nodes[node].self_count += count;
+    }
+
+    /// Samples added to the tree.
+    pub fn total_samples(&self) -> u64 {
+        self.nodes[ROOT].total_count
+    }
+
+    /// The root, which stands for no frame and parents the outermost frames.
+    pub fn root(&self) -> CallTreeNode<'_> {
+        CallTreeNode {
+            tree: self,
+            index: ROOT,
+        }
+    }
+
+    /// Remove subtrees hit by fewer than `min_total` samples.
+    ///
+    /// Their samples are attributed to the parent's `self_count`, so totals and exports
+    /// still add up to `total_samples`.
+    pub fn prune(&mut self, min_total: u64) {
+        let mut stack = vec![ROOT];
+        while let Some(node) = stack.pop() {
+            let children = std::mem::take(&mut self.nodes[node].children);
+            let (kept, removed): (Vec<usize>, Vec<usize>) = children
+                .into_iter()
+                .partition(|c| self.nodes[*c].total_count >= min_total);
+            let removed: u64 = removed.iter().map(|c| self.nodes[*c].total_count).sum();
+            self.nodes[node].self_count += removed;
+            stack.extend(&kept);
+            self.nodes[node].children = kept;
+        }
+        self.compact();
+    }
+
+    /// One stack per node with samples ending in it (`tid` 0), leaf first.
+    pub fn to_profile(&self) -> Profile {
+        let mut stacks = Vec::new();
+        let mut pending = vec![ROOT];
+        while let Some(node) = pending.pop() {
+            if node != ROOT && self.nodes[node].self_count > 0 {
+                stacks.push(SampledStack {
+                    tid: 0,
+                    frames: self.table.stack(&self.path(node)),
+                    count: self.nodes[node].self_count,
+                    // The tree counts samples only.
+                    ..SampledStack::default()
+                });
+            }
+            // Reversed so siblings come out in first-seen order.
+            pending.extend(self.nodes[node].children.iter().rev());
+        }
+        Profile {
+            stacks,
+            total_samples: self.total_samples(),
+            dropped_samples: 0,
+        }
+    }
+
+    pub fn write_folded<W: Write>(&self, out: &mut W, options: &FoldedOptions) -> io::Result<()> {
+        folded::write_profile(out, &self.to_profile(), options)
+    }
+
+    /// Gzipped pprof protobuf, see `output::pprof`.
+    pub fn write_pprof<W: Write>(&self, out: W) -> io::Result<()> {
+        pprof::write_profile(out, &self.to_profile())
+    }
+
+    fn child(&mut self, node: usize, frame: FrameId) -> usize {
+        let existing = self.nodes[node]
+            .children
+            .iter()
+            .copied()
+            .find(|c| self.nodes[*c].frame == Some(frame));
+        existing.unwrap_or_else(|| {
+            let child = self.nodes.len();
+            self.nodes.push(Node {
+                frame: Some(frame),
+                parent: node,
+                children: Vec::new(),
+                self_count: 0,
+                total_count: 0,
+            });
+            self.nodes[node].children.push(child);
+            child
+        })
+    }
+
+    /// Frame ids from `node` up to the outermost frame, leaf first.
+    fn path(&self, mut node: usize) -> Vec<FrameId> {
+        let mut path = Vec::new();
+        while let Some(frame) = self.nodes[node].frame {
+            path.push(frame);
+            node = self.nodes[node].parent;
+        }
+        path
+    }
+
+    /// Drop nodes no longer reachable from the root, renumbering the rest.
+    fn compact(&mut self) {
+        let mut remap = vec![usize::MAX; self.nodes.len()];
+        let mut order = vec![ROOT];
+        let mut i = 0;
+        while i < order.len() {
+            remap[order[i]] = i;
+            order.extend(self.nodes[order[i]].children.iter().copied());
+            i += 1;
+        }
+        let mut nodes: Vec<Node> = order.iter().map(|n| self.nodes[*n].clone()).collect();
+        for node in &mut nodes {
+            node.parent = remap[node.parent];
+            for child in &mut node.children {
+                *child = remap[*child];
+            }
+        }
+        self.nodes = nodes;
+    }
+}
+
+/// Read-only view of one node of a `CallTree`.
+#[derive(Clone, Copy)]
+pub struct CallTreeNode<'a> {
+    tree: &'a CallTree,
+    index: usize,
+}
+
+impl<'a> CallTreeNode<'a> {
+    /// The node's frame; `None` for the root.
+    pub fn frame(&self) -> Option<CallFrame> {
+        self.node().frame.map(|id| self.tree.table.frame(id))
+    }
+
+    /// Function name of the node's frame; empty for the root.
+    pub fn func(&self) -> &'a str {
+        self.node().frame.map_or("", |id| self.tree.table.func(id))
+    }
+
+    pub fn self_count(&self) -> u64 {
+        self.node().self_count
+    }
+
+    pub fn total_count(&self) -> u64 {
+        self.node().total_count
+    }
+
+    /// Callees in first-seen order.
+    pub fn children(&self) -> impl Iterator<Item = CallTreeNode<'a>> + 'a {
+        let tree = self.tree;
+        self.node()
+            .children
+            .iter()
+            .map(move |c| CallTreeNode { tree, index: *c })
+    }
+
+    /// Child whose function is `func`, if any.
+    pub fn child(&self, func: &str) -> Option<CallTreeNode<'a>> {
+        self.children().find(|c| c.func() == func)
+    }
+
+    fn node(&self) -> &'a Node {
+        &self.tree.nodes[self.index]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frames(funcs: &[&str]) -> Vec<CallFrame> {
+        // Given root first for readability; stacks are leaf first.
+        funcs
+            .iter()
+            .rev()
+            .map(|f| CallFrame::native("0x0", "", *f, 0))
+            .collect()
+    }
+
+    fn tree() -> CallTree {
+        let mut tree = CallTree::new();
+        tree.add_stack(&frames(&["main", "a", "leaf"]), 5);
+        tree.add_stack(&frames(&["main", "a"]), 2);
+        tree.add_stack(&frames(&["main", "b"]), 1);
+        tree
+    }
+
+    #[test]
+    fn test_counts() {
+        let tree = tree();
+        assert_eq!(tree.total_samples(), 8);
+        let main = tree.root().child("main").unwrap();
+        assert_eq!((main.self_count(), main.total_count()), (0, 8));
+        let a = main.child("a").unwrap();
+        assert_eq!((a.self_count(), a.total_count()), (2, 7));
+        assert_eq!(a.child("leaf").unwrap().total_count(), 5);
+        let names: Vec<&str> = main.children().map(|c| c.func()).collect();
+        assert_eq!(names, ["a", "b"]);
+        assert_eq!(a.frame(), Some(CallFrame::native("0x0", "", "a", 0)));
+    }
+
+    #[test]
+    fn test_prune_keeps_totals() {
+        let mut tree = tree();
+        tree.prune(2);
+        let main = tree.root().child("main").unwrap();
+        assert!(main.child("b").is_none());
+        assert_eq!(main.self_count(), 1);
+        assert_eq!(main.total_count(), 8);
+
+        let mut out = Vec::new();
+        tree.write_folded(&mut out, &FoldedOptions::new()).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "main 1\nmain;a 2\nmain;a;leaf 5\n"
+        );
+    }
+
+    #[test]
+    fn test_profile_round_trip() {
+        let profile = tree().to_profile();
+        assert_eq!(profile.total_samples, 8);
+        assert_eq!(profile.stacks.iter().map(|s| s.count).sum::<u64>(), 8);
+        let again = CallTree::from_profile(&profile).to_profile();
+        assert_eq!(again, profile);
+
+        let mut pprof = Vec::new();
+        tree().write_pprof(&mut pprof).unwrap();
+        assert_eq!(&pprof[..2], &[0x1f, 0x8b]);
+    }
+}
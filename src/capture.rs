@@ -0,0 +1,188 @@
+//! Native stack capture for the current thread, backed by the `backtrace` crate.
+//! Frames are returned leaf first (innermost call at index 0), like the unwinder emits them.
+
+use crate::stack_trace::{CaptureSource, StackTrace};
+use crate::stack_tracer::SignalTracer;
+use crate::{CallFrame, InlineFrame};
+
+impl SignalTracer {
+    /// Walk the calling thread's native frames and resolve their symbols.
+    ///
+    /// Frames belonging to the capture machinery itself are skipped, so the first frame
+    /// is the caller of `capture_native_stack`. Unresolved frames keep their ip with an
+    /// empty file, `"??"` as func and line 0.
+    #[inline(never)]
+    pub fn capture_native_stack() -> StackTrace {
+        let mut walked = Vec::new();
+        backtrace::trace(|frame| {
+            walked.push(frame.clone());
+            true
+        });
+
+        // Drop everything up to and including this function (backtrace internals + us).
+        let own = walked.iter().position(|frame| {
+            std::ptr::eq(
+                frame.symbol_address() as *const (),
+                Self::capture_native_stack as *const (),
+            )
+        });
+        let first = own.map_or(0, |pos| pos + 1);
+        let frames = walked[first..].iter().map(resolve_frame).collect();
+
+        StackTrace::captured(frames, CaptureSource::InProcess)
+    }
+}
+
+fn resolve_frame(frame: &backtrace::Frame) -> CallFrame {
+    let ip = frame.ip() as usize;
+    let mut resolved = Resolved::default();
+    backtrace::resolve_frame(frame, |symbol| resolved.record(symbol));
+    resolved.into_frame(ip)
+}
+
+/// Resolve a raw instruction pointer (a return address from a stack walk) into a CFrame.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub(crate) fn resolve_ip(ip: usize) -> CallFrame {
+    let mut resolved = Resolved::default();
+    backtrace::resolve(ip as *mut std::ffi::c_void, |symbol| {
+        resolved.record(symbol)
+    });
+    resolved.into_frame(ip)
+}
+
+/// Frames a walk goes on counting once its buffer is full.
+pub(crate) const MAX_COUNTED_FRAMES: usize = 1024;
+
+/// Walk the interrupted thread's stack from inside a signal handler.
+///
+/// Stores raw ips into `out` and returns how many frames the stack has: past a full
+/// `out` the walk goes on for up to `MAX_COUNTED_FRAMES` frames, only counting them. The
+/// unwinder, `handler` itself and the kernel's signal trampoline right above it are
+/// skipped. Only touches memory owned by the caller, so it is usable from async-signal
+/// context.
+#[cfg(unix)]
+pub(crate) unsafe fn trace_signal_context(handler: *const (), out: &mut [usize]) -> usize {
+    let mut count = 0;
+    let mut skip_trampoline = false;
+    backtrace::trace_unsynchronized(|frame| {
+        if std::ptr::eq(frame.symbol_address() as *const (), handler) {
+            // What was walked so far is the unwinder and the handler.
+            count = 0;
+            skip_trampoline = true;
+            return true;
+        }
+        if std::mem::take(&mut skip_trampoline) {
+            return true;
+        }
+        if let Some(slot) = out.get_mut(count) {
+            *slot = frame.ip() as usize;
+        }
+        count += 1;
+        count < out.len() + MAX_COUNTED_FRAMES
+    });
+    count
+}
+
+/// Symbols reported for one ip: inlined calls first, the physical function last.
+#[derive(Default)]
+struct Resolved {
+    symbols: Vec<InlineFrame>,
+}
+
+impl Resolved {
+    fn record(&mut self, symbol: &backtrace::Symbol) {
+        self.symbols.push(InlineFrame {
+            func: symbol
+                .name()
+                .map_or_else(|| "??".to_string(), |n| n.to_string()),
+            file: symbol
+                .filename()
+                .map(|p| p.display().to_string())
+                .unwrap_or_default(),
+            lineno: symbol.lineno().map_or(0, i64::from),
+        });
+    }
+
+    fn into_frame(mut self, ip: usize) -> CallFrame {
+        let ip = format!("{:#x}", ip);
+        match self.symbols.pop() {
+            Some(outer) => CallFrame::native(ip, outer.file, outer.func, outer.lineno)
+                .with_inlined(self.symbols),
+            None => CallFrame::native(ip, "", "??", 0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[inline(never)]
+    fn capture_here() -> Vec<CallFrame> {
+        SignalTracer::capture_native_stack().into_frames()
+    }
+
+    #[test]
+    fn test_capture_native_stack() {
+        let frames = capture_here();
+        assert!(!frames.is_empty());
+        assert!(frames
+            .iter()
+            .all(|f| matches!(f, CallFrame::CFrame { ip, .. } if ip.starts_with("0x"))));
+
+        let names: Vec<&str> = frames
+            .iter()
+            .filter_map(|f| match f {
+                CallFrame::CFrame { func, .. } => Some(func.as_str()),
+                _ => None,
+            })
+            .collect();
+        // The capture machinery is trimmed, our caller is the leaf.
+        assert!(!names
+            .iter()
+            .any(|n| n.contains("SignalTracer>::capture_native_stack")));
+        assert!(names[0].contains("capture_here"), "leaf was {}", names[0]);
+    }
+
+    #[inline(always)]
+    fn inlined_here() -> Vec<CallFrame> {
+        SignalTracer::capture_native_stack().into_frames()
+    }
+
+    #[inline(never)]
+    fn calls_inlined() -> Vec<CallFrame> {
+        inlined_here()
+    }
+
+    #[test]
+    fn test_capture_reports_inlined_frames() {
+        match &calls_inlined()[0] {
+            CallFrame::CFrame { func, inlined, .. } => {
+                assert!(func.contains("calls_inlined"), "{}", func);
+                assert!(
+                    inlined.iter().any(|f| f.func.contains("inlined_here")),
+                    "{:?}",
+                    inlined
+                );
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[test]
+    fn test_resolve_ip() {
+        let mut ip = 0;
+        backtrace::trace(|frame| {
+            ip = frame.ip() as usize;
+            false
+        });
+        match resolve_ip(ip) {
+            CallFrame::CFrame { ip: hex, func, .. } => {
+                assert_eq!(hex, format!("{:#x}", ip));
+                assert_ne!(func, "??");
+            }
+            _ => panic!("expected a native frame"),
+        }
+    }
+}
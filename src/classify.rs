@@ -0,0 +1,204 @@
+//! Module and package names of Python frames, derived from their file paths.
+//!
+//! `classify_frames` fills `module`, `package` and `in_app` of every `PyFrame`, so
+//! exporters and `FrameFilter::in_app_only` can tell application code from the standard
+//! library and installed packages.
+
+use crate::frame_filter::{library_kind, Library};
+use crate::stack_tracer::SignalTracer;
+use crate::CallFrame;
+
+/// Options for `SignalTracer::classify_frames_with`.
+#[derive(Clone, Debug, Default)]
+pub struct ClassifyOptions {
+    app_roots: Vec<String>,
+}
+
+impl ClassifyOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Directory application modules are named relative to, e.g. `/srv/app` makes
+    /// `/srv/app/jobs/train.py` the module `jobs.train` of package `jobs`. Once any root is
+    /// given, only files below one are in-app.
+    pub fn app_root(mut self, root: impl Into<String>) -> Self {
+        let mut root = root.into();
+        if !root.ends_with('/') {
+            root.push('/');
+        }
+        self.app_roots.push(root);
+        self
+    }
+}
+
+/// The `(module, package, in_app)` to record for a Python frame of `file`.
+pub fn classify_file(
+    file: &str,
+    options: &ClassifyOptions,
+) -> (Option<String>, Option<String>, bool) {
+    // `<frozen importlib._bootstrap>`
+    if let Some(module) = file
+        .strip_prefix("<frozen ")
+        .and_then(|f| f.strip_suffix('>'))
+    {
+        return named(module.to_string(), false);
+    }
+    // `<string>`, `<stdin>`, ... have no module.
+    if file.is_empty() || file.starts_with('<') {
+        return (None, None, false);
+    }
+    match library_kind(file) {
+        Some(Library::Package) => {
+            let at = ["/site-packages/", "/dist-packages/"]
+                .iter()
+                .filter_map(|marker| file.rfind(marker).map(|i| i + marker.len()))
+                .max()
+                .unwrap_or(0);
+            module_of(&file[at..]).map_or((None, None, false), |m| named(m, false))
+        }
+        Some(Library::Stdlib) => {
+            // Past `/lib/python3.11/`.
+            let start = file
+                .find("/lib/python")
+                .map_or(0, |i| i + "/lib/python".len());
+            let at = file[start..]
+                .find('/')
+                .map_or(file.len(), |i| start + i + 1);
+            module_of(&file[at..]).map_or((None, None, false), |m| named(m, false))
+        }
+        None if options.app_roots.is_empty() => {
+            let name = file.rsplit('/').next().unwrap_or(file);
+            (module_of(name), None, true)
+        }
+        None => match options
+            .app_roots
+            .iter()
+            .find(|r| file.starts_with(r.as_str()))
+        {
+            Some(root) => {
+                module_of(&file[root.len()..]).map_or((None, None, true), |m| named(m, true))
+            }
+            None => {
+                let name = file.rsplit('/').next().unwrap_or(file);
+                (module_of(name), None, false)
+            }
+        },
+    }
+}
+
+fn named(module: String, in_app: bool) -> (Option<String>, Option<String>, bool) {
+    let package = module.split('.').next().map(str::to_string);
+    (Some(module), package, in_app)
+}
+
+/// `torch/nn/__init__.py` -> `torch.nn`.
+fn module_of(relative: &str) -> Option<String> {
+    let stem = [".py", ".pyc", ".pyx", ".pyi"]
+        .iter()
+        .find_map(|ext| relative.strip_suffix(ext))?;
+    let mut parts: Vec<&str> = stem.split('/').filter(|p| !p.is_empty()).collect();
+    if parts.last() == Some(&"__init__") {
+        parts.pop();
+    }
+    (!parts.is_empty()).then(|| parts.join("."))
+}
+
+impl SignalTracer {
+    /// Fill `module`, `package` and `in_app` of every Python frame from its file.
+    pub fn classify_frames(frames: &mut [CallFrame]) {
+        Self::classify_frames_with(frames, &ClassifyOptions::new());
+    }
+
+    pub fn classify_frames_with(frames: &mut [CallFrame], options: &ClassifyOptions) {
+        for frame in frames {
+            if let CallFrame::PyFrame {
+                file,
+                module,
+                package,
+                in_app,
+                ..
+            } = frame
+            {
+                (*module, *package, *in_app) = classify_file(file, options);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owned(
+        module: Option<&str>,
+        package: Option<&str>,
+        in_app: bool,
+    ) -> (Option<String>, Option<String>, bool) {
+        (
+            module.map(str::to_string),
+            package.map(str::to_string),
+            in_app,
+        )
+    }
+
+    #[test]
+    fn test_classify_file() {
+        let default = ClassifyOptions::new();
+        assert_eq!(
+            classify_file(
+                "/venv/lib/python3.11/site-packages/torch/nn/modules/linear.py",
+                &default
+            ),
+            owned(Some("torch.nn.modules.linear"), Some("torch"), false)
+        );
+        assert_eq!(
+            classify_file("/usr/lib/python3/dist-packages/yaml/__init__.py", &default),
+            owned(Some("yaml"), Some("yaml"), false)
+        );
+        assert_eq!(
+            classify_file("/usr/lib/python3.11/concurrent/futures/thread.py", &default),
+            owned(Some("concurrent.futures.thread"), Some("concurrent"), false)
+        );
+        assert_eq!(
+            classify_file("<frozen importlib._bootstrap>", &default),
+            owned(Some("importlib._bootstrap"), Some("importlib"), false)
+        );
+        assert_eq!(
+            classify_file("<string>", &default),
+            owned(None, None, false)
+        );
+        assert_eq!(
+            classify_file("/srv/app/jobs/train.py", &default),
+            owned(Some("train"), None, true)
+        );
+
+        let rooted = ClassifyOptions::new().app_root("/srv/app");
+        assert_eq!(
+            classify_file("/srv/app/jobs/train.py", &rooted),
+            owned(Some("jobs.train"), Some("jobs"), true)
+        );
+        assert_eq!(
+            classify_file("/opt/tools/run.py", &rooted),
+            owned(Some("run"), None, false)
+        );
+    }
+
+    #[test]
+    fn test_classify_frames() {
+        let mut frames = vec![
+            CallFrame::native("0x1", "ceval.c", "_PyEval_EvalFrameDefault", 0),
+            CallFrame::python("0x2", "/srv/app/main.py", "main", 3),
+        ];
+        SignalTracer::classify_frames(&mut frames);
+        assert_eq!(
+            frames[0],
+            CallFrame::native("0x1", "ceval.c", "_PyEval_EvalFrameDefault", 0)
+        );
+        let CallFrame::PyFrame { module, in_app, .. } = &frames[1] else {
+            panic!("expected a python frame");
+        };
+        assert_eq!(module.as_deref(), Some("main"));
+        assert!(in_app);
+    }
+}
@@ -0,0 +1,269 @@
+//! Annotation pass for NCCL and MPI collective frames.
+//!
+//! Stuck collectives are the usual reason a distributed job hangs: one rank never
+//! reaches the allreduce every other rank is waiting in. This pass tags NCCL
+//! (`ncclAllReduce`, `ncclKernel_*`, `c10d::ProcessGroupNCCL`) and MPI (`MPI_*`,
+//! `PMPI_*`) frames with `COLLECTIVE_CATEGORY` and puts a `[nccl AllReduce]` marker at the
+//! leaf of the stack naming the collective in progress. When the stack has a
+//! `torch.distributed` frame with a `group` local (see `LocalsPolicy`), its value names
+//! the communicator: `[nccl AllReduce group=<ProcessGroupNCCL ...>]`.
+//!
+//! Like `annotate_torch_frames`, it expects demangled names.
+
+use crate::stack_tracer::SignalTracer;
+use crate::torch::qualified_name;
+use crate::{CallFrame, Value};
+
+/// Category given to recognized collective frames and to the leaf marker.
+pub const COLLECTIVE_CATEGORY: &str = "collective";
+
+/// Operations that block until the other ranks take part, compared lowercase without
+/// underscores.
+const BLOCKING_OPS: &[&str] = &[
+    "allreduce",
+    "broadcast",
+    "bcast",
+    "reduce",
+    "allgather",
+    "allgatherv",
+    "reducescatter",
+    "alltoall",
+    "alltoallv",
+    "alltoallbase",
+    "barrier",
+    "gather",
+    "scatter",
+    "send",
+    "recv",
+    "sendrecv",
+    "wait",
+    "waitall",
+    "groupend",
+    "allreducecoalesced",
+    "allgatherintotensorcoalesced",
+];
+
+/// Locals of `torch.distributed` functions holding the process group.
+const GROUP_LOCALS: &[&str] = &["group", "pg", "process_group"];
+
+/// Library a collective frame belongs to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CollectiveLibrary {
+    Nccl,
+    Mpi,
+}
+
+impl CollectiveLibrary {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CollectiveLibrary::Nccl => "nccl",
+            CollectiveLibrary::Mpi => "mpi",
+        }
+    }
+}
+
+/// The collective a stack is in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Collective {
+    pub library: CollectiveLibrary,
+    /// Operation as the library names it, e.g. `AllReduce` or `Allreduce`.
+    pub op: String,
+    /// `repr()` of the process group, when a captured local tells.
+    pub communicator: Option<String>,
+}
+
+impl Collective {
+    /// Label of the leaf marker.
+    pub fn label(&self) -> String {
+        match &self.communicator {
+            Some(comm) => format!("[{} {} group={}]", self.library.as_str(), self.op, comm),
+            None => format!("[{} {}]", self.library.as_str(), self.op),
+        }
+    }
+
+    fn is_blocking(&self) -> bool {
+        let op = self.op.to_ascii_lowercase().replace('_', "");
+        BLOCKING_OPS.contains(&op.as_str())
+    }
+}
+
+/// Library and operation of a collective frame's function or kernel, `None` for other
+/// functions.
+///
+/// - `ncclAllReduce`, `pncclAllReduce` → NCCL `AllReduce`
+/// - `ncclKernel_AllReduce_RING_LL_Sum_float`, `ncclDevKernel_AllGather_RING_LL` → NCCL
+///   `AllReduce`, `AllGather`
+/// - `c10d::ProcessGroupNCCL::allreduce(...)` → NCCL `allreduce`
+/// - `MPI_Allreduce`, `PMPI_Allreduce` → MPI `Allreduce`
+pub fn collective_op(symbol: &str) -> Option<(CollectiveLibrary, String)> {
+    let path = qualified_name(symbol);
+    for prefix in ["ncclKernel_", "ncclDevKernel_", "ncclDevFunc_"] {
+        if let Some(rest) = path.strip_prefix(prefix) {
+            let op = rest.split('_').next().filter(|op| !op.is_empty())?;
+            return Some((CollectiveLibrary::Nccl, op.to_string()));
+        }
+    }
+    if let Some(rest) = path.strip_prefix("c10d::ProcessGroupNCCL::") {
+        let method = rest.rsplit("::").next()?;
+        return Some((CollectiveLibrary::Nccl, method.to_string()));
+    }
+    let api = |prefixes: [&str; 2]| {
+        prefixes.iter().find_map(|prefix| {
+            path.strip_prefix(prefix)
+                .filter(|op| op.starts_with(|c: char| c.is_ascii_uppercase()))
+        })
+    };
+    if let Some(op) = api(["nccl", "pnccl"]) {
+        return Some((CollectiveLibrary::Nccl, op.to_string()));
+    }
+    api(["MPI_", "PMPI_"]).map(|op| (CollectiveLibrary::Mpi, op.to_string()))
+}
+
+impl SignalTracer {
+    /// Tag NCCL and MPI frames with `COLLECTIVE_CATEGORY` and insert a marker naming the
+    /// collective at the leaf, returning it. `None`, with frames left untouched, when the
+    /// stack is not in a collective.
+    ///
+    /// The collective is the innermost blocking operation (allreduce, barrier, wait...),
+    /// else the innermost collective frame. Annotating twice inserts one marker.
+    pub fn annotate_collective_frames(frames: &mut Vec<CallFrame>) -> Option<Collective> {
+        let mut found: Option<Collective> = None;
+        for frame in frames.iter_mut() {
+            let (name, category) = match frame {
+                CallFrame::CFrame { func, category, .. } => (func.as_str(), Some(category)),
+                CallFrame::GpuFrame { kernel, .. } => (kernel.as_str(), None),
+                _ => continue,
+            };
+            let Some((library, op)) = collective_op(name) else {
+                continue;
+            };
+            if let Some(category) = category {
+                *category = Some(COLLECTIVE_CATEGORY.to_string());
+            }
+            let collective = Collective {
+                library,
+                op,
+                communicator: None,
+            };
+            if found
+                .as_ref()
+                .is_none_or(|f| !f.is_blocking() && collective.is_blocking())
+            {
+                found = Some(collective);
+            }
+        }
+        let mut collective = found?;
+        collective.communicator = frames.iter().find_map(communicator);
+
+        let marked = matches!(
+            frames.first(),
+            Some(CallFrame::Synthetic { category, .. }) if category == COLLECTIVE_CATEGORY
+        );
+        if marked {
+            frames.remove(0);
+        }
+        frames.insert(
+            0,
+            CallFrame::synthetic(collective.label(), COLLECTIVE_CATEGORY),
+        );
+        Some(collective)
+    }
+}
+
+/// The process group a `torch.distributed` frame was called with, from its locals.
+fn communicator(frame: &CallFrame) -> Option<String> {
+    let CallFrame::PyFrame { file, locals, .. } = frame else {
+        return None;
+    };
+    if !file.replace('\\', "/").contains("torch/distributed/") {
+        return None;
+    }
+    GROUP_LOCALS
+        .iter()
+        .find_map(|name| match locals.get(*name)? {
+            Value::None => None,
+            Value::Repr { repr, .. } => Some(repr.clone()),
+            Value::Str(s) => Some(s.clone()),
+            other => Some(format!("{:?}", other)),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collective_op() {
+        let nccl = |op: &str| Some((CollectiveLibrary::Nccl, op.to_string()));
+        let cases = [
+            ("ncclAllReduce", nccl("AllReduce")),
+            ("pncclGroupEnd", nccl("GroupEnd")),
+            ("ncclKernel_AllReduce_RING_LL_Sum_float(ncclDevComm*)", nccl("AllReduce")),
+            ("ncclDevKernel_AllGather_RING_LL", nccl("AllGather")),
+            (
+                "c10d::ProcessGroupNCCL::allreduce(std::vector<at::Tensor>&, c10d::AllreduceOptions const&)",
+                nccl("allreduce"),
+            ),
+            (
+                "MPI_Allreduce",
+                Some((CollectiveLibrary::Mpi, "Allreduce".to_string())),
+            ),
+            (
+                "PMPI_Barrier",
+                Some((CollectiveLibrary::Mpi, "Barrier".to_string())),
+            ),
+            ("ncclx", None),
+            ("MPIR_Err_return", None),
+            ("main", None),
+        ];
+        for (symbol, expected) in cases {
+            assert_eq!(collective_op(symbol), expected, "{}", symbol);
+        }
+    }
+
+    #[test]
+    fn test_annotate_collective_frames() {
+        let mut distributed = CallFrame::python(
+            "0x4",
+            "/site-packages/torch/distributed/distributed_c10d.py",
+            "all_reduce",
+            2050,
+        );
+        if let CallFrame::PyFrame { locals, .. } = &mut distributed {
+            locals.insert(
+                "group".to_string(),
+                Value::repr("<ProcessGroupNCCL 0x1>", "ProcessGroupNCCL"),
+            );
+        }
+        let mut frames = vec![
+            CallFrame::native("0x1", "", "ncclGroupEndInternal", 0),
+            CallFrame::native("0x2", "", "ncclAllReduce", 0),
+            CallFrame::native("0x3", "", "c10d::ProcessGroupNCCL::collective", 0),
+            distributed,
+            CallFrame::python("0x5", "train.py", "step", 9),
+        ];
+        let collective = SignalTracer::annotate_collective_frames(&mut frames).unwrap();
+        assert_eq!(collective.library, CollectiveLibrary::Nccl);
+        assert_eq!(collective.op, "AllReduce");
+        assert_eq!(
+            frames[0],
+            CallFrame::synthetic(
+                "[nccl AllReduce group=<ProcessGroupNCCL 0x1>]",
+                COLLECTIVE_CATEGORY
+            )
+        );
+        match &frames[2] {
+            CallFrame::CFrame { category, .. } => {
+                assert_eq!(category.as_deref(), Some(COLLECTIVE_CATEGORY))
+            }
+            other => panic!("{:?}", other),
+        }
+
+        SignalTracer::annotate_collective_frames(&mut frames).unwrap();
+        assert_eq!(frames.len(), 6);
+
+        let mut plain = vec![CallFrame::native("0x1", "", "main", 0)];
+        assert_eq!(SignalTracer::annotate_collective_frames(&mut plain), None);
+        assert_eq!(plain.len(), 1);
+    }
+}
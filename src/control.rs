@@ -0,0 +1,435 @@
+//! Control server on a Unix domain socket, for tools driving a running process.
+//!
+//! One command per line; the reply is zero or more lines of output followed by `ok` or
+//! `error: <message>`:
+//!
+//! ```text
+//! dump                     merged stacks of all threads, as `mst dump` prints them
+//! start-sampling [99hz]    start a profile (default 99 Hz)
+//! stop                     stop it and print the folded stacks
+//! set-filter [RULE...]     filter dumps and profiles; no rules clears the filter
+//! status                   `sampling <hz>hz` or `idle`, then `filter on|off`
+//! help                     this list
+//! ```
+//!
+//! Filter rules are `include-func=RE`, `exclude-func=RE`, `include-file=RE`,
+//! `exclude-file=RE`, `max-depth=N` and the flags `drop-stdlib`, `drop-site-packages`,
+//! `in-app-only`, `collapse-recursion` and `count-recursion` (see `FrameFilter`).
+//!
+//! `ControlServer::bind` listens on a given path. `ensure_started` starts the process-wide
+//! server at `socket_path()` on its first call, so instrumented code can offer control
+//! without deciding up front; later calls only return the path.
+
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+
+use regex::Regex;
+
+use crate::events::{self, DumpTrigger};
+use crate::fork::BackgroundThread;
+use crate::frame_filter::FrameFilter;
+use crate::output::folded::{self, FoldedOptions};
+use crate::output::text;
+use crate::sampler::{PythonStacksProvider, Sampler};
+use crate::stack_tracer::SignalTracer;
+
+const DEFAULT_HZ: u32 = 99;
+/// Overrides the path of `ensure_started`.
+pub const SOCKET_ENV: &str = "MST_CONTROL_SOCKET";
+
+const HELP: &str = "dump
+start-sampling [HZ]
+stop
+set-filter [include-func=RE|exclude-func=RE|include-file=RE|exclude-file=RE|max-depth=N|drop-stdlib|drop-site-packages|in-app-only|collapse-recursion|count-recursion ...]
+status
+help
+";
+
+static GLOBAL: OnceLock<Mutex<Option<(PathBuf, ControlServer)>>> = OnceLock::new();
+
+/// `$MST_CONTROL_SOCKET`, or `mixed-stack-tracer-<pid>.sock` in the temp directory.
+pub fn socket_path() -> PathBuf {
+    match std::env::var_os(SOCKET_ENV) {
+        Some(path) if !path.is_empty() => PathBuf::from(path),
+        _ => std::env::temp_dir().join(format!("mixed-stack-tracer-{}.sock", std::process::id())),
+    }
+}
+
+/// Start the process-wide control server at `socket_path()` unless it runs already, and
+/// return its path. `provider`, when given, merges Python stacks into dumps and profiles;
+/// it is ignored once the server runs.
+pub fn ensure_started(provider: Option<PythonStacksProvider>) -> io::Result<PathBuf> {
+    let mut global = GLOBAL
+        .get_or_init(|| Mutex::new(None))
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    if let Some((path, _)) = global.as_ref() {
+        return Ok(path.clone());
+    }
+    let path = socket_path();
+    let server = ControlServer::bind_inner(&path, provider)?;
+    *global = Some((path.clone(), server));
+    // The server lives as long as the process; only its socket file needs cleaning up.
+    unsafe { libc::atexit(remove_socket_at_exit) };
+    Ok(path)
+}
+
+extern "C" fn remove_socket_at_exit() {
+    if let Some(global) = GLOBAL.get() {
+        if let Some((path, _)) = global.lock().unwrap_or_else(|e| e.into_inner()).as_ref() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+type SharedProvider = Arc<Mutex<PythonStacksProvider>>;
+
+/// What commands act on, shared by the connections of one server.
+#[derive(Default)]
+struct State {
+    provider: Option<SharedProvider>,
+    sampler: Mutex<Option<(Sampler, u32)>>,
+    filter: Mutex<FrameFilter>,
+}
+
+/// A running control server; stops listening and removes the socket when dropped.
+pub struct ControlServer {
+    path: PathBuf,
+    stop: Arc<AtomicBool>,
+    thread: Option<BackgroundThread<()>>,
+}
+
+impl ControlServer {
+    /// Listen on `path`, replacing a stale socket left there.
+    pub fn bind(path: impl AsRef<Path>) -> io::Result<ControlServer> {
+        Self::bind_inner(path.as_ref(), None)
+    }
+
+    /// Listen on `path`, merging Python stacks from `provider` into dumps and profiles.
+    pub fn bind_with_python(
+        path: impl AsRef<Path>,
+        provider: PythonStacksProvider,
+    ) -> io::Result<ControlServer> {
+        Self::bind_inner(path.as_ref(), Some(provider))
+    }
+
+    fn bind_inner(
+        path: &Path,
+        provider: Option<PythonStacksProvider>,
+    ) -> io::Result<ControlServer> {
+        if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket())
+            && UnixStream::connect(path).is_err()
+        {
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        let state = Arc::new(State {
+            provider: provider.map(|p| Arc::new(Mutex::new(p))),
+            ..State::default()
+        });
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = Arc::clone(&stop);
+            thread::Builder::new()
+                .name("mst-control".to_string())
+                .spawn(move || {
+                    for stream in listener.incoming() {
+                        if stop.load(Ordering::SeqCst) {
+                            break;
+                        }
+                        let Ok(stream) = stream else { continue };
+                        let state = Arc::clone(&state);
+                        let _ = thread::Builder::new()
+                            .name("mst-control-conn".to_string())
+                            .spawn(move || serve(stream, &state));
+                    }
+                })?
+        };
+        Ok(ControlServer {
+            path: path.to_path_buf(),
+            stop,
+            thread: Some(BackgroundThread::new(thread)),
+        })
+    }
+
+    /// Path of the socket.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        if self
+            .thread
+            .as_ref()
+            .is_some_and(BackgroundThread::is_forked)
+        {
+            // In a forked child: the socket and its thread belong to the parent.
+            return;
+        }
+        self.stop.store(true, Ordering::SeqCst);
+        // Wake the accept loop up.
+        let _ = UnixStream::connect(&self.path);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn serve(stream: UnixStream, state: &State) {
+    let Ok(reader) = stream.try_clone() else {
+        return;
+    };
+    let mut out = BufWriter::new(stream);
+    for line in BufReader::new(reader).lines() {
+        let Ok(line) = line else { break };
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let result = run(line, state, &mut out);
+        let written = match result {
+            Ok(()) => writeln!(out, "ok"),
+            Err(message) => writeln!(out, "error: {}", message.replace('\n', " ")),
+        };
+        if written.and_then(|()| out.flush()).is_err() {
+            break;
+        }
+    }
+}
+
+/// Run one command, writing its output to `out`.
+fn run(line: &str, state: &State, out: &mut impl Write) -> Result<(), String> {
+    let mut words = line.split_whitespace();
+    let command = words.next().unwrap_or_default();
+    let args: Vec<&str> = words.collect();
+    let io_error = |err: io::Error| err.to_string();
+    match (command, args.as_slice()) {
+        ("dump", []) => {
+            let python = state
+                .provider
+                .as_ref()
+                .map(|p| (p.lock().unwrap_or_else(|e| e.into_inner()))())
+                .unwrap_or_default();
+            let mut stacks = SignalTracer::capture_all_threads_with_python_stacks(python, None)
+                .map_err(|err| err.to_string())?;
+            events::stacks_dumped(DumpTrigger::Control, stacks.len());
+            let filter = state.filter.lock().unwrap_or_else(|e| e.into_inner());
+            if !filter.is_noop() {
+                for stack in &mut stacks {
+                    stack.frames = filter.apply(std::mem::take(&mut stack.frames));
+                }
+            }
+            text::write_thread_stacks(out, &stacks).map_err(io_error)
+        }
+        ("start-sampling", [] | [_]) => {
+            let hz = match args.first() {
+                None => DEFAULT_HZ,
+                Some(rate) => parse_rate(rate)?,
+            };
+            let mut sampler = state.sampler.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some((_, running)) = sampler.as_ref() {
+                return Err(format!("already sampling at {}hz", running));
+            }
+            let started = match &state.provider {
+                Some(provider) => {
+                    let provider = Arc::clone(provider);
+                    Sampler::start_with_python(
+                        hz,
+                        Box::new(move || (provider.lock().unwrap_or_else(|e| e.into_inner()))()),
+                    )
+                }
+                None => Sampler::start(hz),
+            };
+            *sampler = Some((started.map_err(io_error)?, hz));
+            Ok(())
+        }
+        ("stop", []) => {
+            let sampler = state
+                .sampler
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .take();
+            let (sampler, _) = sampler.ok_or("not sampling")?;
+            let mut profile = sampler.stop();
+            let filter = state.filter.lock().unwrap_or_else(|e| e.into_inner());
+            if !filter.is_noop() {
+                profile = filter.apply_to_profile(profile);
+            }
+            folded::write_profile(out, &profile, &FoldedOptions::new()).map_err(io_error)
+        }
+        ("set-filter", rules) => {
+            let filter = parse_filter(rules)?;
+            *state.filter.lock().unwrap_or_else(|e| e.into_inner()) = filter;
+            Ok(())
+        }
+        ("status", []) => {
+            match state
+                .sampler
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .as_ref()
+            {
+                Some((_, hz)) => writeln!(out, "sampling {}hz", hz),
+                None => writeln!(out, "idle"),
+            }
+            .map_err(io_error)?;
+            let noop = state
+                .filter
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .is_noop();
+            writeln!(out, "filter {}", if noop { "off" } else { "on" }).map_err(io_error)
+        }
+        ("help", []) => out.write_all(HELP.as_bytes()).map_err(io_error),
+        ("dump" | "stop" | "status" | "help" | "start-sampling", _) => {
+            Err(format!("bad arguments for `{}`, see `help`", command))
+        }
+        _ => Err(format!("unknown command `{}`, see `help`", command)),
+    }
+}
+
+/// `99`, `99hz` or `99Hz`.
+fn parse_rate(rate: &str) -> Result<u32, String> {
+    let digits = rate
+        .strip_suffix("hz")
+        .or_else(|| rate.strip_suffix("Hz"))
+        .unwrap_or(rate);
+    digits
+        .parse()
+        .ok()
+        .filter(|hz| *hz > 0)
+        .ok_or_else(|| format!("invalid rate `{}`", rate))
+}
+
+fn parse_filter(rules: &[&str]) -> Result<FrameFilter, String> {
+    let mut filter = FrameFilter::new();
+    for rule in rules {
+        let regex = |value: &str| Regex::new(value).map_err(|e| format!("`{}`: {}", rule, e));
+        filter = match rule.split_once('=') {
+            Some(("include-func", value)) => filter.include_func(regex(value)?),
+            Some(("exclude-func", value)) => filter.exclude_func(regex(value)?),
+            Some(("include-file", value)) => filter.include_file(regex(value)?),
+            Some(("exclude-file", value)) => filter.exclude_file(regex(value)?),
+            Some(("max-depth", value)) => filter.max_depth(
+                value
+                    .parse()
+                    .map_err(|_| format!("invalid depth `{}`", value))?,
+            ),
+            None if *rule == "drop-stdlib" => filter.drop_stdlib(true),
+            None if *rule == "drop-site-packages" => filter.drop_site_packages(true),
+            None if *rule == "in-app-only" => filter.in_app_only(true),
+            None if *rule == "collapse-recursion" => filter.collapse_recursion(true),
+            None if *rule == "count-recursion" => filter.count_recursion(true),
+            _ => return Err(format!("unknown filter rule `{}`", rule)),
+        };
+    }
+    Ok(filter)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::time::{Duration, Instant};
+
+    /// Send `commands`, one per line, and return the replies up to each `ok` or `error`.
+    fn send(path: &Path, commands: &[&str]) -> Vec<String> {
+        let mut stream = UnixStream::connect(path).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut replies = Vec::new();
+        for command in commands {
+            writeln!(stream, "{}", command).unwrap();
+            let mut reply = String::new();
+            loop {
+                let mut line = String::new();
+                assert!(reader.read_line(&mut line).unwrap() > 0, "{}", reply);
+                reply.push_str(&line);
+                if line == "ok\n" || line.starts_with("error: ") {
+                    break;
+                }
+            }
+            replies.push(reply);
+        }
+        replies
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse_rate("99hz"), Ok(99));
+        assert_eq!(parse_rate("250"), Ok(250));
+        assert!(parse_rate("0hz").is_err());
+        let filter = parse_filter(&["exclude-func=^_", "max-depth=3", "drop-stdlib"]).unwrap();
+        assert_eq!(filter.get_max_depth(), Some(3));
+        assert!(parse_filter(&[]).unwrap().is_noop());
+        assert!(parse_filter(&["exclude-func=("]).is_err());
+        assert!(parse_filter(&["frobnicate"]).is_err());
+    }
+
+    #[inline(never)]
+    fn spin(duration: Duration) {
+        let start = Instant::now();
+        while start.elapsed() < duration {
+            std::hint::black_box(0u64);
+        }
+    }
+
+    #[test]
+    fn test_commands() {
+        let _lock = crate::sampler::TEST_SAMPLER_LOCK
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let path = std::env::temp_dir().join(format!("mst-control-{}.sock", std::process::id()));
+        // A stale socket from an earlier run is replaced.
+        drop(UnixListener::bind(&path));
+        let server = ControlServer::bind(&path).unwrap();
+
+        let replies = send(
+            server.path(),
+            &[
+                "status",
+                "dump",
+                "set-filter max-depth=1",
+                "dump",
+                "start-sampling 500hz",
+                "start-sampling",
+                "status",
+            ],
+        );
+        assert_eq!(replies[0], "idle\nfilter off\nok\n");
+        // `comm` keeps the first 15 bytes of a thread name.
+        assert!(replies[1].contains("\"mst-control-con\""), "{}", replies[1]);
+        assert_eq!(replies[2], "ok\n");
+        // At most the leaf frame of each thread, then the marker for the others.
+        assert!(replies[3].contains("  #0 "));
+        assert!(replies[3].contains("[truncated "), "{}", replies[3]);
+        assert!(!replies[3].contains("  #2 "), "{}", replies[3]);
+        assert_eq!(replies[4], "ok\n");
+        assert_eq!(replies[5], "error: already sampling at 500hz\n");
+        assert_eq!(replies[6], "sampling 500hz\nfilter on\nok\n");
+
+        spin(Duration::from_millis(200));
+        let replies = send(
+            server.path(),
+            &["set-filter", "stop", "stop", "bogus", "dump now"],
+        );
+        assert!(replies[1].contains("spin"), "{}", replies[1]);
+        assert_eq!(replies[2], "error: not sampling\n");
+        assert!(replies[3].starts_with("error: unknown command `bogus`"));
+        assert!(replies[4].starts_with("error: bad arguments for `dump`"));
+
+        drop(server);
+        assert!(!path.exists());
+        let mut unused = String::new();
+        assert!(UnixStream::connect(&path)
+            .map(|mut s| s.read_to_string(&mut unused))
+            .is_err());
+    }
+}
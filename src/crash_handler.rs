@@ -0,0 +1,937 @@
+//! Crash handler: dumps the merged Python + native stack of the faulting thread on
+//! SIGSEGV, SIGBUS and SIGABRT, then chains to whatever handler was installed before.
+//!
+//! Everything that runs inside the handler is async-signal-safe in practice: no heap
+//! allocation, no locks of our own, output only through `write(2)` on a descriptor opened
+//! before the crash. Native symbols come from a table of the loaded modules and their
+//! function symbols that `install` reads beforehand (`dladdr` may take the loader's lock,
+//! which the crashing thread can hold); ips in modules loaded since then are written raw.
+//! Boundaries are matched with the built-in CPython patterns.
+//!
+//! Stack overflows are reported on threads with an alternate signal stack only: the
+//! installing thread, threads spawned by the Rust standard library, and threads that call
+//! `install_alt_stack`.
+//!
+//! `install` prints a `#i` numbered merged stack; `enable` the format of CPython's
+//! `faulthandler` instead (see `output::faulthandler`), for tools that parse those dumps.
+//! Either appends the window of a `FlightRecorder` registered with `dump_on_crash`.
+
+use std::ffi::{CStr, OsStr};
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicPtr, AtomicU8, AtomicUsize, Ordering};
+#[cfg(target_os = "linux")]
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use object::read::ReadCache;
+use object::{Object, ObjectSection, ObjectSymbol, SectionKind, SymbolKind};
+
+use crate::capture::{trace_signal_context, MAX_COUNTED_FRAMES};
+#[cfg(target_os = "linux")]
+use crate::flight_recorder::{StackRing, SLOT_BYTES};
+use crate::signal_cell::SignalCell;
+
+const SIGNALS: [libc::c_int; 3] = [libc::SIGSEGV, libc::SIGBUS, libc::SIGABRT];
+const MAX_NATIVE_FRAMES: usize = 128;
+const MAX_PYTHON_FRAMES: usize = 128;
+const MAX_NAME_LEN: usize = 256;
+const ALT_STACK_SIZE: usize = 256 * 1024;
+
+const MERGED: u8 = 0;
+const FAULTHANDLER: u8 = 1;
+
+/// Byte patterns identifying CPython eval-loop frames (mirrors `CPythonBoundaryDetector`).
+const BOUNDARY_PATTERNS: [&[u8]; 4] = [
+    b"PyEval_EvalFrame",
+    b"PyEval_EvalCode",
+    b"EvalFrameDefault",
+    b"EvalFrameEx",
+];
+
+/// A Python frame reported by a `PythonFrameSource`; borrows its strings.
+#[derive(Clone, Copy, Debug)]
+pub struct RawPyFrame<'a> {
+    pub file: &'a [u8],
+    pub func: &'a [u8],
+    pub lineno: i64,
+}
+
+/// Provider of the crashing thread's Python frames, leaf first.
+///
+/// It runs inside the signal handler, so it must be async-signal-safe: read memory and
+/// call `emit` once per frame, nothing else. `emit` returns `false` once no more frames fit.
+pub type PythonFrameSource = fn(emit: &mut dyn FnMut(RawPyFrame<'_>) -> bool);
+
+static FD: AtomicI32 = AtomicI32::new(-1);
+static FORMAT: AtomicU8 = AtomicU8::new(MERGED);
+static PYTHON_SOURCE: AtomicUsize = AtomicUsize::new(0);
+#[cfg(target_os = "linux")]
+static FLIGHT_RECORDER: AtomicPtr<StackRing> = AtomicPtr::new(std::ptr::null_mut());
+static IN_HANDLER: AtomicBool = AtomicBool::new(false);
+/// Leaked `SymbolTable` of the last `install`.
+static SYMBOLS: AtomicPtr<SymbolTable> = AtomicPtr::new(std::ptr::null_mut());
+static INSTALLED: Mutex<bool> = Mutex::new(false);
+// Statics below are serialized by `INSTALLED` (install/uninstall) and `IN_HANDLER`.
+static PREVIOUS: SignalCell<[libc::sigaction; SIGNALS.len()]> =
+    SignalCell::new(unsafe { std::mem::zeroed() });
+static PY_SLOTS: SignalCell<[PyFrameSlot; MAX_PYTHON_FRAMES]> =
+    SignalCell::new([PyFrameSlot::EMPTY; MAX_PYTHON_FRAMES]);
+
+#[derive(Clone, Copy)]
+struct PyFrameSlot {
+    file: [u8; MAX_NAME_LEN],
+    file_len: usize,
+    func: [u8; MAX_NAME_LEN],
+    func_len: usize,
+    lineno: i64,
+}
+
+impl PyFrameSlot {
+    const EMPTY: PyFrameSlot = PyFrameSlot {
+        file: [0; MAX_NAME_LEN],
+        file_len: 0,
+        func: [0; MAX_NAME_LEN],
+        func_len: 0,
+        lineno: 0,
+    };
+
+    fn fill(&mut self, frame: RawPyFrame<'_>) {
+        self.file_len = copy_truncated(&mut self.file, frame.file);
+        self.func_len = copy_truncated(&mut self.func, frame.func);
+        self.lineno = frame.lineno;
+    }
+}
+
+/// Install the crash handler; dumps are written to `fd`, which must stay open.
+///
+/// Installing again replaces the target descriptor and format, and re-reads the symbols of
+/// the loaded modules, e.g. after extension modules were imported.
+pub fn install(fd: RawFd) -> io::Result<()> {
+    install_with_format(fd, MERGED)
+}
+
+/// Like `install`, writing dumps as `faulthandler.enable(file)` would, with native frames
+/// between the Python ones:
+///
+/// ```text
+/// Fatal Python error: Aborted
+///
+/// Current thread 0x00007f3a2c9e8740 (most recent call first):
+///   Binary file "/lib/x86_64-linux-gnu/libc.so.6", at abort+0xd7 [0x7f3a2ca2a8ff]
+///   File "train.py", line 12 in step
+/// ```
+pub fn enable(fd: RawFd) -> io::Result<()> {
+    install_with_format(fd, FAULTHANDLER)
+}
+
+/// `uninstall`, under the name `faulthandler` uses.
+pub fn disable() -> io::Result<()> {
+    uninstall()
+}
+
+fn install_with_format(fd: RawFd, format: u8) -> io::Result<()> {
+    let mut installed = INSTALLED.lock().unwrap_or_else(|e| e.into_inner());
+    FD.store(fd, Ordering::SeqCst);
+    FORMAT.store(format, Ordering::SeqCst);
+    // The previous table is leaked: a crashing thread may still be reading it.
+    let symbols = Box::into_raw(Box::new(SymbolTable::current()));
+    SYMBOLS.store(symbols, Ordering::SeqCst);
+    if *installed {
+        return Ok(());
+    }
+
+    install_alt_stack()?;
+
+    let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+    action.sa_sigaction = handle_fatal_signal as *const () as libc::sighandler_t;
+    action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+    unsafe { libc::sigemptyset(&mut action.sa_mask) };
+
+    let previous = unsafe { &mut *PREVIOUS.get() };
+    for (i, sig) in SIGNALS.iter().enumerate() {
+        if unsafe { libc::sigaction(*sig, &action, &mut previous[i]) } != 0 {
+            let err = io::Error::last_os_error();
+            // Roll back the handlers installed so far
+            for (j, sig) in SIGNALS.iter().enumerate().take(i) {
+                unsafe { libc::sigaction(*sig, &previous[j], std::ptr::null_mut()) };
+            }
+            return Err(err);
+        }
+    }
+
+    *installed = true;
+    Ok(())
+}
+
+/// Restore the handlers that were active before `install`.
+pub fn uninstall() -> io::Result<()> {
+    let mut installed = INSTALLED.lock().unwrap_or_else(|e| e.into_inner());
+    if !*installed {
+        return Ok(());
+    }
+
+    let previous = unsafe { &*PREVIOUS.get() };
+    for (i, sig) in SIGNALS.iter().enumerate() {
+        if unsafe { libc::sigaction(*sig, &previous[i], std::ptr::null_mut()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+
+    FD.store(-1, Ordering::SeqCst);
+    *installed = false;
+    Ok(())
+}
+
+/// Register (or clear) the source of Python frames used by the dump.
+///
+/// Without a source, boundary frames are printed as plain native frames.
+pub fn set_python_frame_source(source: Option<PythonFrameSource>) {
+    PYTHON_SOURCE.store(source.map_or(0, |f| f as usize), Ordering::SeqCst);
+}
+
+fn python_frame_source() -> Option<PythonFrameSource> {
+    match PYTHON_SOURCE.load(Ordering::SeqCst) {
+        0 => None,
+        ptr => Some(unsafe { std::mem::transmute::<usize, PythonFrameSource>(ptr) }),
+    }
+}
+
+/// Register (or clear) the ring whose samples are appended to the dump.
+#[cfg(target_os = "linux")]
+pub(crate) fn set_flight_recorder(ring: Option<Arc<StackRing>>) {
+    let ptr = ring.map_or(std::ptr::null_mut(), |r| Arc::into_raw(r) as *mut StackRing);
+    let previous = FLIGHT_RECORDER.swap(ptr, Ordering::SeqCst);
+    if !previous.is_null() {
+        drop(unsafe { Arc::from_raw(previous) });
+    }
+}
+
+/// Clear the registered ring if it is `ring`.
+#[cfg(target_os = "linux")]
+pub(crate) fn clear_flight_recorder(ring: &Arc<StackRing>) {
+    let ptr = Arc::as_ptr(ring) as *mut StackRing;
+    if FLIGHT_RECORDER
+        .compare_exchange(
+            ptr,
+            std::ptr::null_mut(),
+            Ordering::SeqCst,
+            Ordering::SeqCst,
+        )
+        .is_ok()
+    {
+        drop(unsafe { Arc::from_raw(ptr) });
+    }
+}
+
+/// Make sure the calling thread has an alternate signal stack, so that its stack overflows
+/// are reported. `install` does so for the installing thread; other threads not spawned by
+/// the Rust standard library (which gives them one) need to call this themselves. The
+/// 256 KiB stack is leaked with the thread.
+pub fn install_alt_stack() -> io::Result<()> {
+    let mut current: libc::stack_t = unsafe { std::mem::zeroed() };
+    if unsafe { libc::sigaltstack(std::ptr::null(), &mut current) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if current.ss_flags & libc::SS_DISABLE == 0 {
+        return Ok(());
+    }
+
+    // Leaked on purpose: the stack must outlive any signal delivered to this thread.
+    let stack = Box::leak(vec![0u8; ALT_STACK_SIZE].into_boxed_slice());
+    let alt = libc::stack_t {
+        ss_sp: stack.as_mut_ptr() as *mut libc::c_void,
+        ss_flags: 0,
+        ss_size: ALT_STACK_SIZE,
+    };
+    if unsafe { libc::sigaltstack(&alt, std::ptr::null_mut()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+extern "C" fn handle_fatal_signal(
+    sig: libc::c_int,
+    _info: *mut libc::siginfo_t,
+    _context: *mut libc::c_void,
+) {
+    // A crash inside the dump itself falls straight through to the previous handler.
+    if !IN_HANDLER.swap(true, Ordering::SeqCst) {
+        let fd = FD.load(Ordering::SeqCst);
+        if fd >= 0 {
+            dump(fd, sig);
+        }
+    }
+
+    // Chain: put the previous disposition back and re-deliver the signal to it.
+    if let Some(i) = SIGNALS.iter().position(|s| *s == sig) {
+        unsafe {
+            let previous = &*PREVIOUS.get();
+            libc::sigaction(sig, &previous[i], std::ptr::null_mut());
+            libc::raise(sig);
+        }
+    }
+}
+
+fn dump(fd: RawFd, sig: libc::c_int) {
+    let faulthandler = FORMAT.load(Ordering::SeqCst) == FAULTHANDLER;
+    let mut out = FdWriter::new(fd);
+    if faulthandler {
+        out.str("Fatal Python error: ");
+        out.str(fault_description(sig));
+        out.str("\n\nCurrent thread ");
+        out.hex_padded(unsafe { libc::pthread_self() } as usize);
+        out.str(" (most recent call first):\n");
+    } else {
+        out.str("Fatal signal ");
+        out.dec(sig as i64);
+        out.str(" (");
+        out.str(signal_name(sig));
+        out.str(")\n");
+    }
+
+    let mut ips = [0usize; MAX_NATIVE_FRAMES];
+    let native_walked = unsafe { trace_signal_context(handle_fatal_signal as *const (), &mut ips) };
+    let native_count = native_walked.min(MAX_NATIVE_FRAMES);
+
+    // Frames past the buffers are counted, for the closing `[truncated N frames]` line.
+    let slots = unsafe { &mut *PY_SLOTS.get() };
+    let mut python_walked = 0;
+    if let Some(source) = python_frame_source() {
+        source(&mut |frame| {
+            if let Some(slot) = slots.get_mut(python_walked) {
+                slot.fill(frame);
+            }
+            python_walked += 1;
+            python_walked < MAX_PYTHON_FRAMES + MAX_COUNTED_FRAMES
+        });
+    }
+    let python_count = python_walked.min(MAX_PYTHON_FRAMES);
+
+    if !faulthandler {
+        out.str("Merged stack (most recent call first):\n");
+    }
+    let (python_frame, native_frame): (PythonLine, NativeLine) = if faulthandler {
+        (
+            write_faulthandler_python_frame,
+            write_faulthandler_native_frame,
+        )
+    } else {
+        (write_python_frame, write_native_frame)
+    };
+    let mut python_index = 0;
+    let mut depth = 0;
+    for ip in ips[..native_count].iter().filter(|ip| **ip != 0) {
+        let symbol = lookup_symbol(*ip);
+
+        let is_boundary = symbol
+            .name
+            .is_some_and(|name| BOUNDARY_PATTERNS.iter().any(|p| contains(name, p)));
+        if is_boundary && python_index < python_count {
+            python_frame(&mut out, depth, &slots[python_index]);
+            python_index += 1;
+        } else {
+            native_frame(&mut out, depth, *ip, &symbol);
+        }
+        depth += 1;
+    }
+
+    // Same rule as the regular merge: leftover python frames are appended.
+    for slot in &slots[python_index..python_count] {
+        python_frame(&mut out, depth, slot);
+        depth += 1;
+    }
+    let truncated = native_walked - native_count + python_walked - python_count;
+    if truncated > 0 {
+        write_truncated(&mut out, depth, truncated, faulthandler);
+    }
+
+    #[cfg(target_os = "linux")]
+    write_flight_recorder(&mut out);
+    out.flush();
+}
+
+/// The registered flight recorder window, one `timestamp_ns tid stack` line per sample.
+#[cfg(target_os = "linux")]
+fn write_flight_recorder(out: &mut FdWriter) {
+    let ring = FLIGHT_RECORDER.load(Ordering::SeqCst);
+    if ring.is_null() {
+        return;
+    }
+    let mut buf = [0u8; SLOT_BYTES];
+    out.str("\nFlight recorder (oldest first):\n");
+    unsafe { &*ring }.for_each(&mut buf, |tid, timestamp_ns, stack| {
+        out.str("  ");
+        out.dec(timestamp_ns as i64);
+        out.str(" ");
+        out.dec(tid as i64);
+        out.str(" ");
+        out.bytes(stack);
+        out.str("\n");
+    });
+}
+
+/// `  #130 [truncated 72 frames]`, closing a stack cut to the frame buffers.
+fn write_truncated(out: &mut FdWriter, depth: usize, count: usize, faulthandler: bool) {
+    out.str("  ");
+    if !faulthandler {
+        out.str("#");
+        out.dec(depth as i64);
+        out.str(" ");
+    }
+    out.str("[truncated ");
+    out.dec(count as i64);
+    out.str(if count == 1 {
+        " frame]\n"
+    } else {
+        " frames]\n"
+    });
+}
+
+fn write_native_frame(out: &mut FdWriter, depth: usize, ip: usize, symbol: &Symbol) {
+    out.str("  #");
+    out.dec(depth as i64);
+    out.str(" ");
+    out.hex(ip);
+    out.str(" ");
+    match symbol.name {
+        Some(name) => {
+            out.bytes(name);
+            out.str("+");
+            out.hex(ip.wrapping_sub(symbol.addr));
+        }
+        None => out.str("??"),
+    }
+    if let Some(object) = symbol.object {
+        out.str(" (");
+        out.bytes(object);
+        out.str(")");
+    }
+    out.str("\n");
+}
+
+fn write_python_frame(out: &mut FdWriter, depth: usize, slot: &PyFrameSlot) {
+    out.str("  #");
+    out.dec(depth as i64);
+    out.str(" [py] ");
+    out.bytes(&slot.func[..slot.func_len]);
+    out.str(" (");
+    out.bytes(&slot.file[..slot.file_len]);
+    out.str(":");
+    out.dec(slot.lineno);
+    out.str(")\n");
+}
+
+/// `  Binary file "libc.so.6", at abort+0xd7 [0x7f3a2ca2a8ff]`, as in CPython's C stack dumps.
+fn write_faulthandler_native_frame(out: &mut FdWriter, _depth: usize, ip: usize, symbol: &Symbol) {
+    let Some(object) = symbol.object else {
+        out.str("  <unknown> at ");
+        out.hex(ip);
+        out.str("\n");
+        return;
+    };
+    out.str("  Binary file \"");
+    out.bytes(object);
+    out.str("\"");
+    if let Some(name) = symbol.name {
+        out.str(", at ");
+        out.bytes(name);
+        out.str("+");
+        out.hex(ip.wrapping_sub(symbol.addr));
+    }
+    out.str(" [");
+    out.hex(ip);
+    out.str("]\n");
+}
+
+/// `  File "train.py", line 12 in step`
+fn write_faulthandler_python_frame(out: &mut FdWriter, _depth: usize, slot: &PyFrameSlot) {
+    out.str("  File \"");
+    out.bytes(&slot.file[..slot.file_len]);
+    out.str("\", line ");
+    out.dec(slot.lineno);
+    out.str(" in ");
+    out.bytes(&slot.func[..slot.func_len]);
+    out.str("\n");
+}
+
+/// Writers of one python or native frame line at a depth, in either format.
+type PythonLine = fn(&mut FdWriter, usize, &PyFrameSlot);
+type NativeLine = fn(&mut FdWriter, usize, usize, &Symbol);
+
+struct Symbol {
+    name: Option<&'static [u8]>,
+    addr: usize,
+    object: Option<&'static [u8]>,
+}
+
+fn lookup_symbol(ip: usize) -> Symbol {
+    let table = SYMBOLS.load(Ordering::SeqCst);
+    if table.is_null() {
+        return Symbol {
+            name: None,
+            addr: 0,
+            object: None,
+        };
+    }
+    unsafe { &*table }.lookup(ip)
+}
+
+/// Text ranges and function symbols of the loaded modules, searched by the handler.
+#[derive(Default)]
+struct SymbolTable {
+    /// Text range and path of each module, sorted by start address.
+    modules: Vec<(Range<usize>, Range<usize>)>,
+    /// Start, size (0 when unknown) and name of each function, sorted by start address.
+    functions: Vec<(usize, usize, Range<usize>)>,
+    /// Module paths and function names, indexed by the ranges above.
+    names: Vec<u8>,
+}
+
+impl SymbolTable {
+    /// Table of the calling process. Modules that cannot be read, such as the vdso or
+    /// libraries of the dyld shared cache, are left out.
+    fn current() -> SymbolTable {
+        let mut table = SymbolTable::default();
+        for (path, bias) in loaded_modules() {
+            table.add_module(&path, bias);
+        }
+        table.modules.sort_by_key(|(range, _)| range.start);
+        table.functions.sort_by_key(|(addr, _, _)| *addr);
+        table.functions.dedup_by_key(|(addr, _, _)| *addr);
+        table
+    }
+
+    fn add_module(&mut self, path: &Path, bias: usize) -> Option<()> {
+        let cache = ReadCache::new(File::open(path).ok()?);
+        #[cfg(target_os = "macos")]
+        let data = crate::symbolize::dsym::host_slice(&cache)?.0;
+        #[cfg(not(target_os = "macos"))]
+        let data = &cache;
+        let file = object::File::parse(data).ok()?;
+        let text = file
+            .sections()
+            .filter(|s| s.kind() == SectionKind::Text)
+            .map(|s| s.address()..s.address() + s.size())
+            .reduce(|a, b| a.start.min(b.start)..a.end.max(b.end))?;
+        let runtime = |addr: u64| (addr as usize).wrapping_add(bias);
+        let path = self.push_name(path.as_os_str().as_bytes());
+        self.modules
+            .push((runtime(text.start)..runtime(text.end), path));
+        for symbol in file.symbols().chain(file.dynamic_symbols()) {
+            if symbol.kind() != SymbolKind::Text || symbol.address() == 0 {
+                continue;
+            }
+            if let Ok(name) = symbol.name_bytes() {
+                let name = self.push_name(name);
+                let size = symbol.size() as usize;
+                self.functions.push((runtime(symbol.address()), size, name));
+            }
+        }
+        Some(())
+    }
+
+    fn push_name(&mut self, name: &[u8]) -> Range<usize> {
+        let start = self.names.len();
+        self.names.extend_from_slice(name);
+        start..self.names.len()
+    }
+
+    /// The function and module holding `ip`. Only searches memory, so it is safe in the
+    /// signal handler.
+    fn lookup(&'static self, ip: usize) -> Symbol {
+        // ip is a return address; look up ip - 1 so tail positions resolve to the caller.
+        let target = ip.saturating_sub(1);
+        let module = self
+            .modules
+            .get(
+                self.modules
+                    .partition_point(|(range, _)| range.end <= target),
+            )
+            .filter(|(range, _)| range.contains(&target));
+        let function = module.and_then(|(range, _)| {
+            let index = self
+                .functions
+                .partition_point(|(addr, _, _)| *addr <= target)
+                .checked_sub(1)?;
+            let (addr, size, name) = &self.functions[index];
+            (*addr >= range.start && (*size == 0 || target - addr < *size)).then_some((*addr, name))
+        });
+        Symbol {
+            name: function.map(|(_, name)| &self.names[name.clone()]),
+            addr: function.map_or(0, |(addr, _)| addr),
+            object: module.map(|(_, path)| &self.names[path.clone()]),
+        }
+    }
+}
+
+/// Path and load bias (runtime minus link-time address) of every loaded module.
+#[cfg(target_os = "linux")]
+fn loaded_modules() -> Vec<(PathBuf, usize)> {
+    extern "C" fn push(
+        info: *mut libc::dl_phdr_info,
+        _size: libc::size_t,
+        modules: *mut libc::c_void,
+    ) -> libc::c_int {
+        let (info, modules) = unsafe { (&*info, &mut *(modules as *mut Vec<(PathBuf, usize)>)) };
+        let name = if info.dlpi_name.is_null() {
+            &[][..]
+        } else {
+            unsafe { CStr::from_ptr(info.dlpi_name) }.to_bytes()
+        };
+        modules.push((
+            PathBuf::from(OsStr::from_bytes(name)),
+            info.dlpi_addr as usize,
+        ));
+        0
+    }
+
+    let mut modules: Vec<(PathBuf, usize)> = Vec::new();
+    unsafe { libc::dl_iterate_phdr(Some(push), &mut modules as *mut _ as *mut libc::c_void) };
+    // The executable comes first, without a name.
+    if let (Some(first), Ok(exe)) = (modules.first_mut(), std::env::current_exe()) {
+        if first.0.as_os_str().is_empty() {
+            first.0 = exe;
+        }
+    }
+    modules
+}
+
+#[cfg(target_os = "macos")]
+fn loaded_modules() -> Vec<(PathBuf, usize)> {
+    (0..unsafe { mach2::dyld::_dyld_image_count() })
+        .filter_map(|i| {
+            let name = unsafe { mach2::dyld::_dyld_get_image_name(i) };
+            if name.is_null() {
+                return None;
+            }
+            let name = unsafe { CStr::from_ptr(name) }.to_bytes();
+            let slide = unsafe { mach2::dyld::_dyld_get_image_vmaddr_slide(i) };
+            Some((PathBuf::from(OsStr::from_bytes(name)), slide as usize))
+        })
+        .collect()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn loaded_modules() -> Vec<(PathBuf, usize)> {
+    Vec::new()
+}
+
+fn signal_name(sig: libc::c_int) -> &'static str {
+    match sig {
+        libc::SIGSEGV => "SIGSEGV",
+        libc::SIGBUS => "SIGBUS",
+        libc::SIGABRT => "SIGABRT",
+        _ => "unknown",
+    }
+}
+
+/// What `faulthandler` calls the fault.
+fn fault_description(sig: libc::c_int) -> &'static str {
+    match sig {
+        libc::SIGSEGV => "Segmentation fault",
+        libc::SIGBUS => "Bus error",
+        libc::SIGABRT => "Aborted",
+        _ => "unknown signal",
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle)
+}
+
+fn copy_truncated(dst: &mut [u8], src: &[u8]) -> usize {
+    let len = src.len().min(dst.len());
+    dst[..len].copy_from_slice(&src[..len]);
+    len
+}
+
+/// Buffered writer over a raw fd that formats without allocating.
+struct FdWriter {
+    fd: RawFd,
+    buf: [u8; 1024],
+    len: usize,
+}
+
+impl FdWriter {
+    fn new(fd: RawFd) -> Self {
+        FdWriter {
+            fd,
+            buf: [0; 1024],
+            len: 0,
+        }
+    }
+
+    fn bytes(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            if self.len == self.buf.len() {
+                self.flush();
+            }
+            let n = data.len().min(self.buf.len() - self.len);
+            self.buf[self.len..self.len + n].copy_from_slice(&data[..n]);
+            self.len += n;
+            data = &data[n..];
+        }
+    }
+
+    fn str(&mut self, s: &str) {
+        self.bytes(s.as_bytes());
+    }
+
+    fn dec(&mut self, value: i64) {
+        let mut digits = [0u8; 20];
+        let mut n = value.unsigned_abs();
+        let mut i = digits.len();
+        loop {
+            i -= 1;
+            digits[i] = b'0' + (n % 10) as u8;
+            n /= 10;
+            if n == 0 {
+                break;
+            }
+        }
+        if value < 0 {
+            self.bytes(b"-");
+        }
+        self.bytes(&digits[i..]);
+    }
+
+    fn hex(&mut self, value: usize) {
+        let mut digits = [0u8; 16];
+        let mut n = value;
+        let mut i = digits.len();
+        loop {
+            i -= 1;
+            digits[i] = b"0123456789abcdef"[n & 0xf];
+            n >>= 4;
+            if n == 0 {
+                break;
+            }
+        }
+        self.bytes(b"0x");
+        self.bytes(&digits[i..]);
+    }
+
+    /// `0x` and 16 digits, as `faulthandler` prints thread ids.
+    fn hex_padded(&mut self, value: usize) {
+        let mut digits = [b'0'; 16];
+        let mut n = value;
+        for digit in digits.iter_mut().rev() {
+            *digit = b"0123456789abcdef"[n & 0xf];
+            n >>= 4;
+        }
+        self.bytes(b"0x");
+        self.bytes(&digits);
+    }
+
+    fn flush(&mut self) {
+        let mut written = 0;
+        while written < self.len {
+            let n = unsafe {
+                libc::write(
+                    self.fd,
+                    self.buf[written..self.len].as_ptr() as *const libc::c_void,
+                    self.len - written,
+                )
+            };
+            if n <= 0 {
+                // EINTR is retried; anything else drops the rest of the buffer.
+                if n < 0 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                break;
+            }
+            written += n as usize;
+        }
+        self.len = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    const CHILD_ENV: &str = "MST_CRASH_HANDLER_CHILD";
+
+    fn python_source(emit: &mut dyn FnMut(RawPyFrame<'_>) -> bool) {
+        emit(RawPyFrame {
+            file: b"train.py",
+            func: b"step",
+            lineno: 12,
+        });
+    }
+
+    #[test]
+    fn test_dump_on_abort() {
+        if std::env::var_os(CHILD_ENV).is_some() {
+            // Child: install on stderr and crash.
+            set_python_frame_source(Some(python_source));
+            install(2).unwrap();
+            std::process::abort();
+        }
+
+        let output = Command::new(std::env::current_exe().unwrap())
+            .args([
+                "--exact",
+                "crash_handler::tests::test_dump_on_abort",
+                "--nocapture",
+            ])
+            .env(CHILD_ENV, "1")
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+
+        assert!(!output.status.success());
+        assert!(stderr.contains("Fatal signal 6 (SIGABRT)"), "{}", stderr);
+        assert!(stderr.contains("Merged stack (most recent call first):"));
+        assert!(stderr.contains("[py] step (train.py:12)"), "{}", stderr);
+        assert!(stderr.contains("  #0 0x"), "{}", stderr);
+    }
+
+    fn deep_python_source(emit: &mut dyn FnMut(RawPyFrame<'_>) -> bool) {
+        for lineno in 0..MAX_PYTHON_FRAMES as i64 + 72 {
+            let frame = RawPyFrame {
+                file: b"fib.py",
+                func: b"fib",
+                lineno,
+            };
+            if !emit(frame) {
+                return;
+            }
+        }
+    }
+
+    #[test]
+    fn test_truncated_dump_on_abort() {
+        if std::env::var_os(CHILD_ENV).is_some() {
+            set_python_frame_source(Some(deep_python_source));
+            install(2).unwrap();
+            std::process::abort();
+        }
+
+        let output = Command::new(std::env::current_exe().unwrap())
+            .args([
+                "--exact",
+                "crash_handler::tests::test_truncated_dump_on_abort",
+                "--nocapture",
+            ])
+            .env(CHILD_ENV, "1")
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+
+        assert!(stderr.contains("[py] fib (fib.py:127)"), "{}", stderr);
+        assert!(!stderr.contains("[py] fib (fib.py:128)"), "{}", stderr);
+        assert!(stderr.contains(" [truncated 72 frames]\n"), "{}", stderr);
+    }
+
+    #[test]
+    fn test_faulthandler_format_on_abort() {
+        if std::env::var_os(CHILD_ENV).is_some() {
+            set_python_frame_source(Some(python_source));
+            enable(2).unwrap();
+            std::process::abort();
+        }
+
+        let output = Command::new(std::env::current_exe().unwrap())
+            .args([
+                "--exact",
+                "crash_handler::tests::test_faulthandler_format_on_abort",
+                "--nocapture",
+            ])
+            .env(CHILD_ENV, "1")
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+
+        assert!(!output.status.success());
+        assert!(
+            stderr.contains("Fatal Python error: Aborted\n\nCurrent thread 0x"),
+            "{}",
+            stderr
+        );
+        assert!(stderr.contains(" (most recent call first):\n"));
+        assert!(
+            stderr.contains("  File \"train.py\", line 12 in step\n"),
+            "{}",
+            stderr
+        );
+        assert!(stderr.contains("  Binary file \""), "{}", stderr);
+        assert!(!stderr.contains("  #0 "));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_flight_recorder_on_abort() {
+        if std::env::var_os(CHILD_ENV).is_some() {
+            let ring = Arc::new(StackRing::new(4, std::time::Duration::from_secs(1)));
+            ring.record(7, 100, "main;train;step");
+            ring.record(8, 200, "main;load");
+            set_flight_recorder(Some(ring));
+            install(2).unwrap();
+            std::process::abort();
+        }
+
+        let output = Command::new(std::env::current_exe().unwrap())
+            .args([
+                "--exact",
+                "crash_handler::tests::test_flight_recorder_on_abort",
+                "--nocapture",
+            ])
+            .env(CHILD_ENV, "1")
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+
+        assert!(!output.status.success());
+        assert!(
+            stderr.contains(
+                "\nFlight recorder (oldest first):\n  100 7 main;train;step\n  200 8 main;load\n"
+            ),
+            "{}",
+            stderr
+        );
+    }
+
+    #[test]
+    fn test_symbol_table_lookup() {
+        let table: &'static SymbolTable = Box::leak(Box::new(SymbolTable::current()));
+        let function = test_symbol_table_lookup as *const () as usize;
+        let symbol = table.lookup(function + 4);
+        assert_eq!(symbol.addr, function);
+        assert!(symbol
+            .name
+            .is_some_and(|name| contains(name, b"test_symbol_table_lookup")));
+        assert!(symbol.object.is_some());
+
+        let unknown = table.lookup(0x10);
+        assert!(unknown.name.is_none() && unknown.object.is_none());
+    }
+
+    #[test]
+    fn test_fd_writer_formatting() {
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+
+        let mut out = FdWriter::new(fds[1]);
+        out.dec(-42);
+        out.str(" ");
+        out.hex(0xdead);
+        out.str(" ");
+        out.dec(0);
+        out.str(" ");
+        out.hex_padded(0x4d2);
+        out.flush();
+        unsafe { libc::close(fds[1]) };
+
+        let mut buf = [0u8; 64];
+        let n = unsafe { libc::read(fds[0], buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+        unsafe { libc::close(fds[0]) };
+        assert_eq!(&buf[..n as usize], b"-42 0xdead 0 0x00000000000004d2");
+    }
+}
@@ -0,0 +1,279 @@
+//! CUDA kernel launch correlation through CUPTI (feature `cuda`, Linux only).
+//!
+//! `libcupti` is loaded with `dlopen` when tracking starts, so building needs no CUDA
+//! toolkit. CUPTI then calls us on the launching thread around every runtime and driver
+//! API call; for kernel launches the kernel name is recorded per thread, and stacks
+//! captured meanwhile get a `CallFrame::GpuFrame` beneath their launch site.
+
+use std::collections::BTreeMap;
+use std::ffi::{c_char, c_int, c_void, CStr};
+use std::io;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::demangle::demangle;
+use crate::thread_stack::ThreadId;
+use crate::threads::current_tid;
+use crate::CallFrame;
+
+const LIBRARY_NAMES: &[&str] = &["libcupti.so", "libcupti.so.12", "libcupti.so.11"];
+
+const CUPTI_SUCCESS: c_int = 0;
+const CUPTI_CB_DOMAIN_DRIVER_API: u32 = 1;
+const CUPTI_CB_DOMAIN_RUNTIME_API: u32 = 2;
+const CUPTI_API_ENTER: u32 = 0;
+
+/// `CUpti_CallbackData` from `cupti_callbacks.h`.
+#[repr(C)]
+struct CallbackData {
+    callback_site: u32,
+    function_name: *const c_char,
+    function_params: *const c_void,
+    function_return_value: *mut c_void,
+    /// Kernel symbol; only set for launch calls.
+    symbol_name: *const c_char,
+    context: *mut c_void,
+    context_uid: u32,
+    correlation_data: *mut u64,
+    correlation_id: u32,
+}
+
+type Callback = extern "C" fn(*mut c_void, u32, u32, *const c_void);
+type SubscribeFn = unsafe extern "C" fn(*mut *mut c_void, Callback, *mut c_void) -> c_int;
+type EnableDomainFn = unsafe extern "C" fn(u32, *mut c_void, u32) -> c_int;
+type UnsubscribeFn = unsafe extern "C" fn(*mut c_void) -> c_int;
+type GetDeviceIdFn = unsafe extern "C" fn(*mut c_void, *mut u32) -> c_int;
+
+/// A kernel launch seen on one thread.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KernelLaunch {
+    /// Demangled kernel name.
+    pub kernel: String,
+    pub device: Option<u32>,
+    pub correlation_id: u32,
+    /// The launch call has not returned yet.
+    pub in_progress: bool,
+}
+
+impl KernelLaunch {
+    pub fn frame(&self) -> CallFrame {
+        CallFrame::gpu(self.kernel.clone(), self.device, self.correlation_id)
+    }
+}
+
+static LAUNCHES: Mutex<BTreeMap<ThreadId, KernelLaunch>> = Mutex::new(BTreeMap::new());
+
+/// Entry points resolved from `libcupti`.
+struct Cupti {
+    handle: *mut c_void,
+    enable_domain: EnableDomainFn,
+    unsubscribe: UnsubscribeFn,
+    get_device_id: Option<GetDeviceIdFn>,
+}
+
+/// Records CUDA kernel launches of every thread while alive.
+///
+/// CUPTI allows a single subscriber per process, so starting fails while another tool
+/// (Nsight, the PyTorch profiler, a second tracker) is subscribed.
+pub struct CudaLaunchTracker {
+    cupti: Box<Cupti>,
+    subscriber: *mut c_void,
+}
+
+// The subscriber handle is only passed back to CUPTI, which is thread-safe.
+unsafe impl Send for CudaLaunchTracker {}
+unsafe impl Sync for CudaLaunchTracker {}
+
+impl CudaLaunchTracker {
+    /// Load `libcupti` and subscribe to runtime and driver API callbacks.
+    pub fn start() -> io::Result<CudaLaunchTracker> {
+        let handle = LIBRARY_NAMES
+            .iter()
+            .map(|name| {
+                let name = format!("{}\0", name);
+                unsafe { libc::dlopen(name.as_ptr().cast(), libc::RTLD_NOW | libc::RTLD_LOCAL) }
+            })
+            .find(|h| !h.is_null())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "libcupti not found"))?;
+
+        let symbol = |name: &str| {
+            let name = format!("{}\0", name);
+            let ptr = unsafe { libc::dlsym(handle, name.as_ptr().cast()) };
+            (!ptr.is_null()).then_some(ptr)
+        };
+        let resolved = (
+            symbol("cuptiSubscribe"),
+            symbol("cuptiEnableDomain"),
+            symbol("cuptiUnsubscribe"),
+        );
+        let (Some(subscribe), Some(enable_domain), Some(unsubscribe)) = resolved else {
+            unsafe { libc::dlclose(handle) };
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "libcupti lacks the callback API",
+            ));
+        };
+        let cupti = Box::new(Cupti {
+            handle,
+            enable_domain: unsafe {
+                std::mem::transmute::<*mut c_void, EnableDomainFn>(enable_domain)
+            },
+            unsubscribe: unsafe { std::mem::transmute::<*mut c_void, UnsubscribeFn>(unsubscribe) },
+            get_device_id: symbol("cuptiGetDeviceId")
+                .map(|f| unsafe { std::mem::transmute::<*mut c_void, GetDeviceIdFn>(f) }),
+        });
+        let subscribe = unsafe { std::mem::transmute::<*mut c_void, SubscribeFn>(subscribe) };
+
+        let mut subscriber = std::ptr::null_mut();
+        let userdata = &*cupti as *const Cupti as *mut c_void;
+        if let Err(e) = check("cuptiSubscribe", unsafe {
+            subscribe(&mut subscriber, on_api_call, userdata)
+        }) {
+            unsafe { libc::dlclose(handle) };
+            return Err(e);
+        }
+        let tracker = CudaLaunchTracker { cupti, subscriber };
+        for domain in [CUPTI_CB_DOMAIN_RUNTIME_API, CUPTI_CB_DOMAIN_DRIVER_API] {
+            check("cuptiEnableDomain", unsafe {
+                (tracker.cupti.enable_domain)(1, subscriber, domain)
+            })?;
+        }
+        Ok(tracker)
+    }
+
+    /// The launch thread `tid` is inside of right now, if any.
+    pub fn current_launch(&self, tid: ThreadId) -> Option<KernelLaunch> {
+        current_launch(tid)
+    }
+
+    /// The most recent launch of thread `tid`, finished or not.
+    pub fn last_launch(&self, tid: ThreadId) -> Option<KernelLaunch> {
+        launches().get(&tid).cloned()
+    }
+
+    /// Insert a `GpuFrame` for the kernel `tid` is launching at the leaf of its merged
+    /// stack. Returns whether one was inserted.
+    pub fn annotate(&self, tid: ThreadId, frames: &mut Vec<CallFrame>) -> bool {
+        annotate(tid, frames)
+    }
+}
+
+impl Drop for CudaLaunchTracker {
+    fn drop(&mut self) {
+        unsafe {
+            (self.cupti.unsubscribe)(self.subscriber);
+            libc::dlclose(self.cupti.handle);
+        }
+    }
+}
+
+fn launches() -> MutexGuard<'static, BTreeMap<ThreadId, KernelLaunch>> {
+    LAUNCHES.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn current_launch(tid: ThreadId) -> Option<KernelLaunch> {
+    launches().get(&tid).filter(|l| l.in_progress).cloned()
+}
+
+fn annotate(tid: ThreadId, frames: &mut Vec<CallFrame>) -> bool {
+    match current_launch(tid) {
+        Some(launch) => {
+            frames.insert(0, launch.frame());
+            true
+        }
+        None => false,
+    }
+}
+
+fn check(call: &str, result: c_int) -> io::Result<()> {
+    if result == CUPTI_SUCCESS {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!(
+            "{} failed with CUPTI error {}",
+            call, result
+        )))
+    }
+}
+
+/// CUPTI callback, run on the thread making the API call.
+extern "C" fn on_api_call(userdata: *mut c_void, _domain: u32, _cbid: u32, data: *const c_void) {
+    let Some(data) = (unsafe { data.cast::<CallbackData>().as_ref() }) else {
+        return;
+    };
+    if data.symbol_name.is_null() {
+        return;
+    }
+    let tid = current_tid();
+    if data.callback_site != CUPTI_API_ENTER {
+        if let Some(launch) = launches().get_mut(&tid) {
+            launch.in_progress = false;
+        }
+        return;
+    }
+
+    let symbol = unsafe { CStr::from_ptr(data.symbol_name) }.to_string_lossy();
+    let kernel = demangle(&symbol).unwrap_or_else(|| symbol.into_owned());
+    let cupti = unsafe { userdata.cast::<Cupti>().as_ref() };
+    let device = cupti.and_then(|c| c.get_device_id).and_then(|get| {
+        let mut device = 0;
+        (unsafe { get(data.context, &mut device) } == CUPTI_SUCCESS).then_some(device)
+    });
+    launches().insert(
+        tid,
+        KernelLaunch {
+            kernel,
+            device,
+            correlation_id: data.correlation_id,
+            in_progress: true,
+        },
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn callback_data(site: u32, symbol: &CStr, correlation_id: u32) -> CallbackData {
+        CallbackData {
+            callback_site: site,
+            function_name: c"cudaLaunchKernel".as_ptr(),
+            function_params: std::ptr::null(),
+            function_return_value: std::ptr::null_mut(),
+            symbol_name: symbol.as_ptr(),
+            context: std::ptr::null_mut(),
+            context_uid: 0,
+            correlation_data: std::ptr::null_mut(),
+            correlation_id,
+        }
+    }
+
+    fn call(data: &CallbackData) {
+        let data = data as *const CallbackData as *const c_void;
+        on_api_call(std::ptr::null_mut(), CUPTI_CB_DOMAIN_RUNTIME_API, 0, data);
+    }
+
+    #[test]
+    fn test_launch_callbacks_record_kernel() {
+        let tid = current_tid();
+        let symbol = c"_Z10add_kernelPfS_";
+
+        call(&callback_data(CUPTI_API_ENTER, symbol, 7));
+        let mut frames = vec![CallFrame::native("0x1", "", "cudaLaunchKernel", 0)];
+        assert!(annotate(tid, &mut frames));
+        assert_eq!(
+            frames[0],
+            CallFrame::gpu("add_kernel(float*, float*)", None, 7)
+        );
+
+        call(&callback_data(1, symbol, 7));
+        assert_eq!(current_launch(tid), None);
+        assert!(!launches()[&tid].in_progress);
+        assert!(!annotate(tid, &mut frames));
+
+        // Calls that launch nothing are ignored.
+        let mut other = callback_data(CUPTI_API_ENTER, symbol, 8);
+        other.symbol_name = std::ptr::null();
+        call(&other);
+        assert_eq!(launches()[&tid].correlation_id, 7);
+    }
+}
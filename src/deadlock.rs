@@ -0,0 +1,411 @@
+//! Deadlock detection: wait-for cycles between threads and the locks they hold.
+//!
+//! A `LockGraph` records which thread owns which lock and which lock each blocked thread
+//! waits for; `find_deadlocks` follows thread → awaited lock → owner until it comes back
+//! to a thread it started from. Edges come from wherever owners can be read:
+//!
+//! - `LockGraph::add_pthread_mutexes` (Linux): threads blocked in `pthread_mutex_lock`
+//!   wait on the futex named by their `futex` system call, whose glibc `__owner` field
+//!   holds the owner's tid.
+//! - `LockGraph::add_python_locks` (`python` feature, Linux): `threading` locks and
+//!   RLocks of CPython up to 3.12, matched to the futex their waiters sleep on. Only RLocks
+//!   remember their owner, so cycles through plain `Lock`s cannot be closed.
+//! - `LockGraph::add_owner` and `add_waiter` for anything else the caller knows.
+//!
+//! ```text
+//! Deadlock between 2 threads:
+//! Thread 12 "worker-1" waits for pthread mutex 0x5591c0a04040 held by thread 13
+//!   #0 0x7f.. __lll_lock_wait (/lib/libc.so.6)
+//!   ...
+//! Thread 13 "worker-2" waits for pthread mutex 0x5591c0a04080 held by thread 12
+//!   ...
+//! ```
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io::{self, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::output::text;
+use crate::thread_stack::{ThreadId, ThreadStack};
+use crate::CallFrame;
+
+/// What kind of lock a `LockId` names.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockKind {
+    PthreadMutex,
+    /// `threading.Lock` (`_thread.lock`).
+    PythonLock,
+    /// `threading.RLock` (`_thread.RLock`).
+    PythonRLock,
+    /// A lock described by the caller.
+    Other,
+}
+
+impl LockKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LockKind::PthreadMutex => "pthread mutex",
+            LockKind::PythonLock => "Lock",
+            LockKind::PythonRLock => "RLock",
+            LockKind::Other => "lock",
+        }
+    }
+}
+
+/// A lock, by kind and address (of the mutex, or of the Python object).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct LockId {
+    pub kind: LockKind,
+    pub address: u64,
+}
+
+impl LockId {
+    pub fn new(kind: LockKind, address: u64) -> Self {
+        LockId { kind, address }
+    }
+}
+
+impl fmt::Display for LockId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {:#x}", self.kind.as_str(), self.address)
+    }
+}
+
+/// Who owns which lock, and which lock each blocked thread waits for.
+#[derive(Clone, Debug, Default)]
+pub struct LockGraph {
+    owners: HashMap<LockId, ThreadId>,
+    waits: HashMap<ThreadId, LockId>,
+}
+
+impl LockGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_owner(&mut self, lock: LockId, tid: ThreadId) -> &mut Self {
+        self.owners.insert(lock, tid);
+        self
+    }
+
+    /// `tid` is blocked acquiring `lock`; a thread waits for one lock at a time.
+    pub fn add_waiter(&mut self, tid: ThreadId, lock: LockId) -> &mut Self {
+        self.waits.insert(tid, lock);
+        self
+    }
+
+    pub fn owner(&self, lock: &LockId) -> Option<ThreadId> {
+        self.owners.get(lock).copied()
+    }
+
+    pub fn waiting_for(&self, tid: ThreadId) -> Option<LockId> {
+        self.waits.get(&tid).copied()
+    }
+
+    /// Every wait-for cycle, each starting from its lowest tid, ordered by that tid.
+    /// `stacks` supplies the names and frames of the threads involved.
+    pub fn find_deadlocks(&self, stacks: &[ThreadStack]) -> Vec<Deadlock> {
+        let by_tid: HashMap<ThreadId, &ThreadStack> = stacks
+            .iter()
+            .filter(|s| !s.greenlet)
+            .map(|s| (s.tid, s))
+            .collect();
+        let mut starts: Vec<ThreadId> = self.waits.keys().copied().collect();
+        starts.sort_unstable();
+
+        let mut visited = HashSet::new();
+        let mut deadlocks = Vec::new();
+        for start in starts {
+            // Follow the chain from `start` until it ends, loops, or joins a chain
+            // already explored.
+            let mut path: Vec<ThreadId> = Vec::new();
+            let mut next = Some(start);
+            while let Some(tid) = next.filter(|tid| visited.insert(*tid)) {
+                path.push(tid);
+                next = self.waiting_for(tid).and_then(|lock| self.owner(&lock));
+            }
+            let Some(at) = next.and_then(|tid| path.iter().position(|t| *t == tid)) else {
+                continue;
+            };
+            let mut cycle = path.split_off(at);
+            let lowest = cycle
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, t)| **t)
+                .map(|(i, _)| i);
+            cycle.rotate_left(lowest.unwrap_or(0));
+            deadlocks.push(Deadlock {
+                threads: cycle
+                    .into_iter()
+                    .map(|tid| {
+                        let lock = self.waits[&tid];
+                        let stack = by_tid.get(&tid);
+                        DeadlockedThread {
+                            tid,
+                            name: stack.map(|s| s.name.clone()).unwrap_or_default(),
+                            waits_for: lock,
+                            held_by: self.owners[&lock],
+                            frames: stack.map(|s| s.frames.clone()).unwrap_or_default(),
+                        }
+                    })
+                    .collect(),
+            });
+        }
+        deadlocks.sort_by_key(|d| d.threads[0].tid);
+        deadlocks
+    }
+}
+
+/// A thread of a wait-for cycle.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DeadlockedThread {
+    pub tid: ThreadId,
+    pub name: String,
+    pub waits_for: LockId,
+    /// Owner of `waits_for`: the next thread of the cycle.
+    pub held_by: ThreadId,
+    /// Merged frames, leaf first.
+    pub frames: Vec<CallFrame>,
+}
+
+/// Threads each waiting for a lock held by the next, the last for one held by the first.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Deadlock {
+    pub threads: Vec<DeadlockedThread>,
+}
+
+impl Deadlock {
+    pub fn tids(&self) -> Vec<ThreadId> {
+        self.threads.iter().map(|t| t.tid).collect()
+    }
+}
+
+/// Write `deadlocks` as text, one block per cycle with the stack of every thread in it.
+pub fn write_report<W: Write>(out: &mut W, deadlocks: &[Deadlock]) -> io::Result<()> {
+    if deadlocks.is_empty() {
+        return writeln!(out, "No deadlock found.");
+    }
+    for deadlock in deadlocks {
+        writeln!(out, "Deadlock between {} threads:", deadlock.threads.len())?;
+        for thread in &deadlock.threads {
+            writeln!(
+                out,
+                "Thread {} \"{}\" waits for {} held by thread {}",
+                thread.tid, thread.name, thread.waits_for, thread.held_by
+            )?;
+            text::write_frames(out, &thread.frames)?;
+        }
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// Functions a thread blocked on a pthread mutex sleeps in.
+#[cfg(target_os = "linux")]
+const MUTEX_WAIT_FUNCTIONS: &[&str] = &[
+    "__lll_lock_wait",
+    "__lll_lock_wait_private",
+    "pthread_mutex_lock",
+    "___pthread_mutex_lock",
+    "__pthread_mutex_lock",
+    "__pthread_mutex_lock_full",
+    "pthread_mutex_timedlock",
+    "__pthread_mutex_clocklock_common",
+];
+
+/// Offset of `__owner` in glibc's `pthread_mutex_t`, after `__lock` and `__count`.
+#[cfg(target_os = "linux")]
+const MUTEX_OWNER: u64 = 8;
+
+/// Address of the futex thread `tid` of process `pid` sleeps on, if it is in a `futex`
+/// system call.
+#[cfg(target_os = "linux")]
+pub(crate) fn futex_address(pid: &str, tid: ThreadId) -> Option<u64> {
+    let syscall = std::fs::read_to_string(format!("/proc/{}/task/{}/syscall", pid, tid)).ok()?;
+    parse_futex_address(&syscall)
+}
+
+#[cfg(target_os = "linux")]
+fn parse_futex_address(syscall: &str) -> Option<u64> {
+    let mut fields = syscall.split_whitespace();
+    let nr: libc::c_long = fields.next()?.parse().ok()?;
+    if nr != libc::SYS_futex {
+        return None;
+    }
+    let uaddr = fields.next()?.trim_start_matches("0x");
+    u64::from_str_radix(uaddr, 16).ok().filter(|a| *a != 0)
+}
+
+/// Whether the native frames of `frames` are those of a `pthread_mutex_lock` in progress.
+#[cfg(target_os = "linux")]
+fn in_mutex_lock(frames: &[CallFrame]) -> bool {
+    frames.iter().any(|frame| match frame {
+        CallFrame::CFrame { func, .. } => MUTEX_WAIT_FUNCTIONS.contains(&func.as_str()),
+        _ => false,
+    })
+}
+
+#[cfg(target_os = "linux")]
+impl LockGraph {
+    /// Add the pthread mutexes the threads of `stacks` (of process `pid`) are blocked on,
+    /// and their owners. Owners outside `stacks` are ignored; reading another process
+    /// needs ptrace permission on it.
+    pub fn add_pthread_mutexes(&mut self, pid: i32, stacks: &[ThreadStack]) -> &mut Self {
+        let memory = crate::remote::memory::ProcessMemory::new(pid);
+        let tids: HashSet<ThreadId> = stacks.iter().map(|s| s.tid).collect();
+        let pid = pid.to_string();
+        for stack in stacks.iter().filter(|s| in_mutex_lock(&s.frames)) {
+            let Some(mutex) = futex_address(&pid, stack.tid) else {
+                continue;
+            };
+            let Ok(owner) = memory.read_i32(mutex + MUTEX_OWNER) else {
+                continue;
+            };
+            // The owner field keeps only the tid, with no flag bits for ordinary mutexes.
+            if owner > 0 && owner != stack.tid && tids.contains(&owner) {
+                let lock = LockId::new(LockKind::PthreadMutex, mutex);
+                self.add_waiter(stack.tid, lock).add_owner(lock, owner);
+            }
+        }
+        self
+    }
+}
+
+/// Native-only deadlock check of the calling process: capture every thread, read the
+/// pthread mutexes the blocked ones wait on and report the cycles.
+#[cfg(target_os = "linux")]
+pub fn detect_native_deadlocks() -> Result<Vec<Deadlock>, crate::TracerError> {
+    let stacks = crate::SignalTracer::capture_all_threads()?;
+    let mut graph = LockGraph::new();
+    graph.add_pthread_mutexes(std::process::id() as i32, &stacks);
+    Ok(graph.find_deadlocks(&stacks))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::thread_stack::ThreadState;
+
+    fn lock(address: u64) -> LockId {
+        LockId::new(LockKind::Other, address)
+    }
+
+    fn thread(tid: ThreadId) -> ThreadStack {
+        ThreadStack {
+            tid,
+            name: format!("t{}", tid),
+            os_state: ThreadState::Sleeping,
+            is_gil_holder: false,
+            greenlet: false,
+            interpreter_id: None,
+            frames: vec![CallFrame::python("0x0", "app.py", format!("f{}", tid), 1)],
+        }
+    }
+
+    #[test]
+    fn test_find_deadlocks() {
+        let mut graph = LockGraph::new();
+        // 3 -> 4 -> 5 -> 3 is a cycle, 1 waits on it without being part of it, 2 waits
+        // for a lock nobody holds.
+        graph
+            .add_owner(lock(0xa), 4)
+            .add_owner(lock(0xb), 5)
+            .add_owner(lock(0xc), 3)
+            .add_waiter(5, lock(0xc))
+            .add_waiter(3, lock(0xa))
+            .add_waiter(4, lock(0xb))
+            .add_waiter(1, lock(0xa))
+            .add_waiter(2, lock(0xd));
+        let stacks: Vec<_> = (1..=5).map(thread).collect();
+        let deadlocks = graph.find_deadlocks(&stacks);
+
+        assert_eq!(deadlocks.len(), 1);
+        let deadlock = &deadlocks[0];
+        assert_eq!(deadlock.tids(), vec![3, 4, 5]);
+        assert_eq!(deadlock.threads[0].waits_for, lock(0xa));
+        assert_eq!(deadlock.threads[0].held_by, 4);
+        assert_eq!(deadlock.threads[2].held_by, 3);
+        assert_eq!(deadlock.threads[1].frames[0].func(), "f4");
+
+        let mut report = Vec::new();
+        write_report(&mut report, &deadlocks).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(
+            report.starts_with("Deadlock between 3 threads:\n"),
+            "{}",
+            report
+        );
+        assert!(report.contains("Thread 3 \"t3\" waits for lock 0xa held by thread 4\n"));
+        assert!(report.contains("[py] f5 (app.py:1)"));
+
+        assert!(LockGraph::new().find_deadlocks(&stacks).is_empty());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_parse_futex_address() {
+        let futex = format!(
+            "{} 0x55d1c0a04040 0x80 0x2 0x0 0x0 0x0 0x7ffd 0x7f00",
+            libc::SYS_futex
+        );
+        assert_eq!(parse_futex_address(&futex), Some(0x55d1c0a04040));
+        assert_eq!(parse_futex_address("running"), None);
+        assert_eq!(parse_futex_address("-1 0x7ffd 0x0"), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_detect_native_deadlocks() {
+        struct Mutex(std::cell::UnsafeCell<libc::pthread_mutex_t>);
+        unsafe impl Sync for Mutex {}
+        impl Mutex {
+            fn lock(&self) {
+                unsafe { libc::pthread_mutex_lock(self.0.get()) };
+            }
+        }
+        static FIRST: Mutex = Mutex(std::cell::UnsafeCell::new(libc::PTHREAD_MUTEX_INITIALIZER));
+        static SECOND: Mutex = Mutex(std::cell::UnsafeCell::new(libc::PTHREAD_MUTEX_INITIALIZER));
+
+        // Two threads taking the mutexes in opposite orders; they stay blocked for the rest
+        // of the test run.
+        let barrier = std::sync::Arc::new(std::sync::Barrier::new(2));
+        let mut tids = Vec::new();
+        for (a, b) in [(&FIRST, &SECOND), (&SECOND, &FIRST)] {
+            let (barrier, (sender, tid)) = (barrier.clone(), std::sync::mpsc::channel());
+            std::thread::spawn(move || {
+                sender.send(crate::threads::current_tid()).unwrap();
+                a.lock();
+                barrier.wait();
+                b.lock();
+            });
+            tids.push(tid.recv().unwrap());
+        }
+        tids.sort_unstable();
+
+        let start = std::time::Instant::now();
+        let deadlock = loop {
+            let found = detect_native_deadlocks().unwrap();
+            if let Some(deadlock) = found.into_iter().find(|d| d.tids() == tids) {
+                break deadlock;
+            }
+            assert!(start.elapsed() < std::time::Duration::from_secs(5));
+            std::thread::sleep(std::time::Duration::from_millis(20));
+        };
+        assert!(deadlock
+            .threads
+            .iter()
+            .all(|t| t.waits_for.kind == LockKind::PthreadMutex && in_mutex_lock(&t.frames)));
+        let addresses: HashSet<u64> = deadlock
+            .threads
+            .iter()
+            .map(|t| t.waits_for.address)
+            .collect();
+        assert_eq!(
+            addresses,
+            [FIRST.0.get() as u64, SECOND.0.get() as u64].into()
+        );
+    }
+}
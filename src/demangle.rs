@@ -0,0 +1,131 @@
+//! Demangling of native symbol names: Rust (legacy and v0) and the Itanium C++ ABI.
+
+use crate::stack_tracer::SignalTracer;
+use crate::CallFrame;
+
+/// Options for `SignalTracer::demangle_frames_with`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DemangleOptions {
+    keep_raw_name: bool,
+}
+
+impl DemangleOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store the mangled name in `CFrame::raw_func` when a frame is demangled.
+    pub fn keep_raw_name(mut self, keep: bool) -> Self {
+        self.keep_raw_name = keep;
+        self
+    }
+}
+
+impl SignalTracer {
+    /// Demangle every native frame's `func` in place. Names that are not mangled, and
+    /// Python frames, are left alone.
+    pub fn demangle_frames(frames: &mut [CallFrame]) {
+        Self::demangle_frames_with(frames, &DemangleOptions::new());
+    }
+
+    pub fn demangle_frames_with(frames: &mut [CallFrame], options: &DemangleOptions) {
+        for frame in frames {
+            let CallFrame::CFrame {
+                func,
+                raw_func,
+                inlined,
+                ..
+            } = frame
+            else {
+                continue;
+            };
+            for inline in inlined {
+                if let Some(demangled) = demangle(&inline.func) {
+                    inline.func = demangled;
+                }
+            }
+            if let Some(demangled) = demangle(func) {
+                let raw = std::mem::replace(func, demangled);
+                if options.keep_raw_name {
+                    *raw_func = Some(raw);
+                }
+            }
+        }
+    }
+}
+
+/// Demangled form of `name`, or `None` if it is not a mangled Rust or C++ symbol.
+///
+/// Rust names lose their trailing hash (`::h0123…`), as flamegraphs would otherwise
+/// split one function per build.
+pub fn demangle(name: &str) -> Option<String> {
+    // Rust first: legacy Rust symbols are valid Itanium names too, but read worse that way.
+    if let Ok(symbol) = rustc_demangle::try_demangle(name) {
+        return Some(format!("{:#}", symbol));
+    }
+    if !name.starts_with("_Z") {
+        return None;
+    }
+    cpp_demangle::Symbol::new(name)
+        .ok()
+        .and_then(|symbol| symbol.demangle().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_demangle() {
+        assert_eq!(
+            demangle("_ZN4core3ptr13drop_in_place17h0123456789abcdefE").as_deref(),
+            Some("core::ptr::drop_in_place")
+        );
+        assert_eq!(
+            demangle("_ZN5torch8autograd6Engine7executeEv").as_deref(),
+            Some("torch::autograd::Engine::execute()")
+        );
+        assert_eq!(
+            demangle("_ZNSt6vectorIiSaIiEE9push_backERKi").as_deref(),
+            Some("std::vector<int, std::allocator<int> >::push_back(int const&)")
+        );
+        assert_eq!(demangle("_PyEval_EvalFrameDefault"), None);
+        assert_eq!(demangle("main"), None);
+    }
+
+    #[test]
+    fn test_demangle_frames() {
+        let mut frames = vec![
+            CallFrame::native("0x1", "", "_ZN5torch8autograd6Engine7executeEv", 0),
+            CallFrame::native("0x2", "", "main", 0),
+            CallFrame::python("0x3", "a.py", "_ZN1fEv", 1),
+        ];
+        SignalTracer::demangle_frames_with(
+            &mut frames,
+            &DemangleOptions::new().keep_raw_name(true),
+        );
+        assert_eq!(
+            frames[0],
+            CallFrame::CFrame {
+                ip: "0x1".to_string(),
+                file: String::new(),
+                func: "torch::autograd::Engine::execute()".to_string(),
+                lineno: 0,
+                raw_func: Some("_ZN5torch8autograd6Engine7executeEv".to_string()),
+                inlined: Vec::new(),
+                category: None,
+                source: None,
+                module_path: None,
+                build_id: None,
+                offset: None,
+                eval_frame: None,
+            }
+        );
+        assert_eq!(frames[1], CallFrame::native("0x2", "", "main", 0));
+        assert_eq!(frames[2], CallFrame::python("0x3", "a.py", "_ZN1fEv", 1));
+
+        let mut plain = vec![CallFrame::native("0x1", "", "_ZN1fEv", 0)];
+        SignalTracer::demangle_frames(&mut plain);
+        assert_eq!(plain[0], CallFrame::native("0x1", "", "f()", 0));
+    }
+}
@@ -0,0 +1,270 @@
+//! Differences between stacks and between call trees ("what changed between these two
+//! profiles"), including input for differential flamegraphs.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::call_tree::{CallTree, CallTreeNode};
+use crate::frame_table::{FrameId, FrameTable};
+use crate::output::folded::{fold_stack, FoldedOptions};
+use crate::stack_tracer::SignalTracer;
+use crate::{CallFrame, FrameKind};
+
+/// One frame position in a `diff_stacks` result, root first.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[allow(clippy::large_enum_variant)]
+pub enum FrameChange {
+    Same(CallFrame),
+    /// Same function, different line (e.g. the call site moved).
+    Changed {
+        before: CallFrame,
+        after: CallFrame,
+    },
+    Removed(CallFrame),
+    Added(CallFrame),
+}
+
+impl SignalTracer {
+    /// Compare two merged stacks (leaf first) from the root down.
+    ///
+    /// Frames match while they run the same function; once the stacks diverge, the rest of
+    /// `before` is reported as removed, then the rest of `after` as added.
+    pub fn diff_stacks(before: &[CallFrame], after: &[CallFrame]) -> Vec<FrameChange> {
+        let mut before = before.iter().rev().peekable();
+        let mut after = after.iter().rev().peekable();
+        let mut changes = Vec::new();
+        while let (Some(b), Some(a)) = (before.peek(), after.peek()) {
+            let change = match (location(b), location(a)) {
+                (lb, la) if lb == la => FrameChange::Same((*a).clone()),
+                ((kb, fb, fileb, _), (ka, fa, filea, _)) if (kb, fb, fileb) == (ka, fa, filea) => {
+                    FrameChange::Changed {
+                        before: (*b).clone(),
+                        after: (*a).clone(),
+                    }
+                }
+                _ => break,
+            };
+            changes.push(change);
+            before.next();
+            after.next();
+        }
+        changes.extend(before.map(|f| FrameChange::Removed(f.clone())));
+        changes.extend(after.map(|f| FrameChange::Added(f.clone())));
+        changes
+    }
+}
+
+fn location(frame: &CallFrame) -> (FrameKind, &str, &str, i64) {
+    (frame.kind(), frame.func(), frame.file(), frame.lineno())
+}
+
+/// How a call path's samples changed between two trees.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Change {
+    Added,
+    Removed,
+    Changed,
+    Unchanged,
+}
+
+/// Sample counts of one call path in both trees.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiffEntry {
+    /// The call path, leaf first.
+    pub frames: Vec<CallFrame>,
+    pub before_self: u64,
+    pub before_total: u64,
+    pub after_self: u64,
+    pub after_total: u64,
+}
+
+impl DiffEntry {
+    pub fn change(&self) -> Change {
+        match (self.before_total, self.after_total) {
+            (0, _) => Change::Added,
+            (_, 0) => Change::Removed,
+            _ if self.before_self != self.after_self || self.before_total != self.after_total => {
+                Change::Changed
+            }
+            _ => Change::Unchanged,
+        }
+    }
+
+    /// `after_total - before_total`.
+    pub fn delta(&self) -> i64 {
+        self.after_total as i64 - self.before_total as i64
+    }
+}
+
+/// Result of `CallTree::diff`: every call path of either tree, parents before children.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CallTreeDiff {
+    pub entries: Vec<DiffEntry>,
+}
+
+impl CallTreeDiff {
+    /// Entries whose `change()` is `change`.
+    pub fn with_change(&self, change: Change) -> impl Iterator<Item = &DiffEntry> {
+        self.entries.iter().filter(move |e| e.change() == change)
+    }
+
+    /// Two-column folded stacks (`root;...;leaf before after`), the input of
+    /// `difffolded.pl`-style differential flamegraphs. Paths without self samples in
+    /// either tree are skipped.
+    pub fn write_folded<W: Write>(&self, out: &mut W, options: &FoldedOptions) -> io::Result<()> {
+        for entry in &self.entries {
+            if entry.before_self == 0 && entry.after_self == 0 {
+                continue;
+            }
+            writeln!(
+                out,
+                "{} {} {}",
+                fold_stack(&entry.frames, options),
+                entry.before_self,
+                entry.after_self
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl CallTree {
+    /// Compare `self` (before) with `other` (after), path by path.
+    pub fn diff(&self, other: &CallTree) -> CallTreeDiff {
+        let mut merged = DiffBuilder::default();
+        merged.walk(self.root(), true);
+        merged.walk(other.root(), false);
+        let table = merged.table;
+        CallTreeDiff {
+            entries: merged
+                .entries
+                .into_iter()
+                .map(|(path, counts)| DiffEntry {
+                    frames: table.stack(&path),
+                    before_self: counts[0],
+                    before_total: counts[1],
+                    after_self: counts[2],
+                    after_total: counts[3],
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Paths of both trees re-interned into one table so they can be matched.
+#[derive(Default)]
+struct DiffBuilder {
+    table: FrameTable,
+    /// Leaf-first path and `[before_self, before_total, after_self, after_total]`.
+    entries: Vec<(Vec<FrameId>, [u64; 4])>,
+    index: HashMap<Vec<FrameId>, usize>,
+}
+
+impl DiffBuilder {
+    fn walk(&mut self, root: CallTreeNode<'_>, before: bool) {
+        let offset = if before { 0 } else { 2 };
+        // Root-first paths while walking; stored reversed (leaf first).
+        let mut pending: Vec<(CallTreeNode<'_>, Vec<FrameId>)> = root
+            .children()
+            .collect::<Vec<_>>()
+            .into_iter()
+            .rev()
+            .map(|c| (c, Vec::new()))
+            .collect();
+        while let Some((node, mut path)) = pending.pop() {
+            let frame = node.frame().expect("only the root has no frame");
+            path.push(self.table.intern(&frame));
+            let key: Vec<FrameId> = path.iter().rev().copied().collect();
+            let i = match self.index.get(&key) {
+                Some(i) => *i,
+                None => {
+                    self.index.insert(key.clone(), self.entries.len());
+                    self.entries.push((key, [0; 4]));
+                    self.entries.len() - 1
+                }
+            };
+            self.entries[i].1[offset] += node.self_count();
+            self.entries[i].1[offset + 1] += node.total_count();
+
+            let children: Vec<_> = node.children().collect();
+            pending.extend(children.into_iter().rev().map(|c| (c, path.clone())));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn native(func: &str, lineno: i64) -> CallFrame {
+        CallFrame::native("0x0", "", func, lineno)
+    }
+
+    #[test]
+    fn test_diff_stacks() {
+        // Leaf first.
+        let before = vec![native("old_leaf", 1), native("work", 10), native("main", 1)];
+        let after = vec![
+            native("new_leaf", 1),
+            native("helper", 5),
+            native("work", 12),
+            native("main", 1),
+        ];
+        assert_eq!(
+            SignalTracer::diff_stacks(&before, &after),
+            vec![
+                FrameChange::Same(native("main", 1)),
+                FrameChange::Changed {
+                    before: native("work", 10),
+                    after: native("work", 12),
+                },
+                FrameChange::Removed(native("old_leaf", 1)),
+                FrameChange::Added(native("helper", 5)),
+                FrameChange::Added(native("new_leaf", 1)),
+            ]
+        );
+        assert!(SignalTracer::diff_stacks(&before, &before)
+            .iter()
+            .all(|c| matches!(c, FrameChange::Same(_))));
+    }
+
+    #[test]
+    fn test_call_tree_diff() {
+        let mut before = CallTree::new();
+        before.add_stack(&[native("a", 0), native("main", 0)], 4);
+        before.add_stack(&[native("b", 0), native("main", 0)], 1);
+        let mut after = CallTree::new();
+        after.add_stack(&[native("a", 0), native("main", 0)], 4);
+        after.add_stack(&[native("c", 0), native("main", 0)], 3);
+
+        let diff = before.diff(&after);
+        let summary: Vec<(String, Change, i64)> = diff
+            .entries
+            .iter()
+            .map(|e| {
+                (
+                    fold_stack(&e.frames, &FoldedOptions::new()),
+                    e.change(),
+                    e.delta(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("main".to_string(), Change::Changed, 2),
+                ("main;a".to_string(), Change::Unchanged, 0),
+                ("main;b".to_string(), Change::Removed, -1),
+                ("main;c".to_string(), Change::Added, 3),
+            ]
+        );
+        assert_eq!(diff.with_change(Change::Added).count(), 1);
+
+        let mut out = Vec::new();
+        diff.write_folded(&mut out, &FoldedOptions::new()).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "main;a 4 4\nmain;b 1 0\nmain;c 0 3\n"
+        );
+    }
+}
@@ -0,0 +1,338 @@
+//! Background dumps of all thread stacks into a directory, for post-mortem analysis.
+//!
+//! Every interval the merged stacks of all threads are written to
+//! `stacks-<UTC timestamp>-<pid>.txt` in the format of `output::text`. Old dumps are
+//! removed past the retention limits, which apply to every `stacks-*.txt` in the
+//! directory so files left by earlier runs rotate out too.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::events::{self, DumpTrigger};
+use crate::fork::BackgroundThread;
+use crate::output::text;
+use crate::sampler::PythonStacksProvider;
+use crate::stack_tracer::SignalTracer;
+
+const PREFIX: &str = "stacks-";
+const SUFFIX: &str = ".txt";
+
+/// Retention and capture settings of a `PeriodicDumper`.
+pub struct DumperOptions {
+    max_files: usize,
+    max_age: Option<Duration>,
+    python_stacks: Option<PythonStacksProvider>,
+}
+
+impl Default for DumperOptions {
+    fn default() -> Self {
+        DumperOptions {
+            max_files: 100,
+            max_age: None,
+            python_stacks: None,
+        }
+    }
+}
+
+impl DumperOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep at most `n` dumps, deleting the oldest first (default 100, at least 1).
+    pub fn max_files(mut self, n: usize) -> Self {
+        self.max_files = n.max(1);
+        self
+    }
+
+    /// Delete dumps older than `age` (by the timestamp in their name).
+    pub fn max_age(mut self, age: Duration) -> Self {
+        self.max_age = Some(age);
+        self
+    }
+
+    /// Merge each native stack with the Python stack of the same thread.
+    pub fn python_stacks(mut self, provider: PythonStacksProvider) -> Self {
+        self.python_stacks = Some(provider);
+        self
+    }
+
+    pub fn get_max_files(&self) -> usize {
+        self.max_files
+    }
+
+    pub fn get_max_age(&self) -> Option<Duration> {
+        self.max_age
+    }
+}
+
+struct Shared {
+    stopped: Mutex<bool>,
+    wake: Condvar,
+    written: AtomicU64,
+}
+
+/// A running dumper thread, stopped when dropped.
+pub struct PeriodicDumper {
+    dir: PathBuf,
+    shared: Arc<Shared>,
+    thread: Option<BackgroundThread<()>>,
+}
+
+impl PeriodicDumper {
+    /// Dump now and then every `interval` into `dir`, created if missing.
+    pub fn start(interval: Duration, dir: impl Into<PathBuf>) -> io::Result<PeriodicDumper> {
+        Self::start_with(interval, dir, DumperOptions::new())
+    }
+
+    pub fn start_with(
+        interval: Duration,
+        dir: impl Into<PathBuf>,
+        options: DumperOptions,
+    ) -> io::Result<PeriodicDumper> {
+        if interval.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "dump interval must be positive",
+            ));
+        }
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let shared = Arc::new(Shared {
+            stopped: Mutex::new(false),
+            wake: Condvar::new(),
+            written: AtomicU64::new(0),
+        });
+        let thread = {
+            let shared = Arc::clone(&shared);
+            let dir = dir.clone();
+            thread::Builder::new()
+                .name("mst-dumper".to_string())
+                .spawn(move || run(&shared, interval, &dir, options))?
+        };
+        Ok(PeriodicDumper {
+            dir,
+            shared,
+            thread: Some(BackgroundThread::new(thread)),
+        })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Number of dumps written so far, including those rotated out since.
+    pub fn dumps_written(&self) -> u64 {
+        self.shared.written.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for PeriodicDumper {
+    fn drop(&mut self) {
+        if self
+            .thread
+            .as_ref()
+            .is_some_and(BackgroundThread::is_forked)
+        {
+            // In a forked child: the dumper thread stayed in the parent.
+            return;
+        }
+        *self.shared.stopped.lock().unwrap() = true;
+        self.shared.wake.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn run(shared: &Shared, interval: Duration, dir: &Path, mut options: DumperOptions) {
+    let mut stopped = shared.stopped.lock().unwrap();
+    while !*stopped {
+        drop(stopped);
+        match dump(dir, &mut options) {
+            Ok(_) => {
+                shared.written.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(err) => eprintln!("mixed-stack-tracer: periodic dump failed: {}", err),
+        }
+        stopped = shared.stopped.lock().unwrap();
+        stopped = shared
+            .wake
+            .wait_timeout_while(stopped, interval, |stopped| !*stopped)
+            .unwrap()
+            .0;
+    }
+}
+
+fn dump(dir: &Path, options: &mut DumperOptions) -> io::Result<PathBuf> {
+    let python = options
+        .python_stacks
+        .as_mut()
+        .map(|p| p())
+        .unwrap_or_default();
+    let stacks = SignalTracer::capture_all_threads_with_python_stacks(python, None)?;
+    events::stacks_dumped(DumpTrigger::Periodic, stacks.len());
+
+    let now = SystemTime::now();
+    let name = format!(
+        "{}{}-{}{}",
+        PREFIX,
+        utc_timestamp(now),
+        std::process::id(),
+        SUFFIX
+    );
+    let path = dir.join(&name);
+    // Written under a temporary name so a process dying mid-dump leaves no partial file.
+    let partial = dir.join(format!(".{}.partial", name));
+    let mut out = BufWriter::new(File::create(&partial)?);
+    text::write_thread_stacks(&mut out, &stacks)?;
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    fs::rename(&partial, &path)?;
+
+    rotate(dir, now, options.max_files, options.max_age)?;
+    Ok(path)
+}
+
+/// Delete the oldest dumps beyond `max_files` and those older than `max_age`.
+fn rotate(
+    dir: &Path,
+    now: SystemTime,
+    max_files: usize,
+    max_age: Option<Duration>,
+) -> io::Result<()> {
+    let mut dumps: Vec<String> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+        .filter(|name| name.starts_with(PREFIX) && name.ends_with(SUFFIX))
+        .collect();
+    // Timestamps are fixed width, so name order is age order.
+    dumps.sort();
+    let excess = dumps.len().saturating_sub(max_files);
+    let cutoff = max_age
+        .and_then(|age| now.checked_sub(age))
+        .map(utc_timestamp);
+    for (i, name) in dumps.iter().enumerate() {
+        let expired = cutoff
+            .as_deref()
+            .is_some_and(|cutoff| name[PREFIX.len()..] < *cutoff);
+        if i < excess || expired {
+            fs::remove_file(dir.join(name))?;
+        }
+    }
+    Ok(())
+}
+
+/// `YYYYMMDDTHHMMSS.mmmZ`, sortable as text.
+fn utc_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, day_secs) = (secs / 86_400, secs % 86_400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's days_from_civil inverse).
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}{:02}{:02}T{:02}{:02}{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        day_secs / 3600,
+        day_secs / 60 % 60,
+        day_secs % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mst-dumper-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn dumps(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn test_utc_timestamp() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        assert_eq!(utc_timestamp(time), "20231114T221320.123Z");
+        assert_eq!(utc_timestamp(UNIX_EPOCH), "19700101T000000.000Z");
+        let leap_day = UNIX_EPOCH + Duration::from_secs(951_782_400);
+        assert_eq!(utc_timestamp(leap_day), "20000229T000000.000Z");
+    }
+
+    #[test]
+    fn test_rotate() {
+        let dir = temp_dir("rotate");
+        fs::create_dir_all(&dir).unwrap();
+        for name in [
+            "stacks-20231114T000000.000Z-1.txt",
+            "stacks-20231114T010000.000Z-1.txt",
+            "stacks-20231114T020000.000Z-2.txt",
+            "stacks-20231114T030000.000Z-2.txt",
+            "notes.txt",
+        ] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+        let now = UNIX_EPOCH + Duration::from_secs(1_699_931_000); // 2023-11-14T03:03:20Z
+        rotate(&dir, now, 3, None).unwrap();
+        assert_eq!(dumps(&dir).len(), 4);
+        assert!(!dumps(&dir).contains(&"stacks-20231114T000000.000Z-1.txt".to_string()));
+
+        rotate(&dir, now, 3, Some(Duration::from_secs(2 * 3600))).unwrap();
+        assert_eq!(
+            dumps(&dir),
+            [
+                "notes.txt",
+                "stacks-20231114T020000.000Z-2.txt",
+                "stacks-20231114T030000.000Z-2.txt"
+            ]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_periodic_dumps() {
+        let dir = temp_dir("periodic");
+        let dumper = PeriodicDumper::start_with(
+            Duration::from_millis(20),
+            &dir,
+            DumperOptions::new().max_files(2),
+        )
+        .unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while dumper.dumps_written() < 3 && std::time::Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(dumper.dumps_written() >= 3);
+        drop(dumper);
+
+        let names = dumps(&dir);
+        assert_eq!(names.len(), 2, "{:?}", names);
+        let last = fs::read_to_string(dir.join(&names[1])).unwrap();
+        assert!(last.contains("\"mst-dumper\""), "{}", last);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
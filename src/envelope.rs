@@ -0,0 +1,161 @@
+//! Versioned envelopes for shipping frames and traces between processes.
+//!
+//! Every payload travels with the `FORMAT_VERSION` of its serde representation. Data
+//! written by an older version stays readable (new fields default); data from a newer
+//! version is rejected instead of being misread. The `msgpack` feature adds a compact
+//! MessagePack encoding. Only self-describing formats fit: `CallFrame` is an internally
+//! tagged enum with optional fields, which bincode-style formats cannot represent.
+
+#[cfg(feature = "msgpack")]
+use std::io::{self, Read, Write};
+
+use serde::{Deserialize, Serialize};
+
+/// Version of the serialized representation, raised on incompatible changes.
+pub const FORMAT_VERSION: u32 = 1;
+
+/// A payload tagged with the format version and the producing crate version.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    pub format_version: u32,
+    /// `mixed-stack-tracer` version that wrote the payload, for diagnostics.
+    pub producer: String,
+    pub payload: T,
+}
+
+impl<T> Envelope<T> {
+    pub fn new(payload: T) -> Self {
+        Envelope {
+            format_version: FORMAT_VERSION,
+            producer: env!("CARGO_PKG_VERSION").to_string(),
+            payload,
+        }
+    }
+
+    /// Whether this crate can read the payload.
+    pub fn is_supported(&self) -> bool {
+        self.format_version <= FORMAT_VERSION
+    }
+}
+
+/// Envelope fields read before the payload, to check the version first.
+#[cfg(feature = "msgpack")]
+#[derive(Deserialize)]
+struct Header {
+    format_version: u32,
+    producer: String,
+}
+
+/// Encode `payload` in an envelope as MessagePack, with field names so that fields can be
+/// added later.
+#[cfg(feature = "msgpack")]
+pub fn to_msgpack<T: Serialize>(payload: &T) -> io::Result<Vec<u8>> {
+    rmp_serde::to_vec_named(&Envelope::new(payload)).map_err(io::Error::other)
+}
+
+/// Decode an envelope written by `to_msgpack` and return its payload.
+///
+/// Fails with `InvalidData` on malformed input or a newer, unsupported format version.
+#[cfg(feature = "msgpack")]
+pub fn from_msgpack<T: serde::de::DeserializeOwned>(bytes: &[u8]) -> io::Result<T> {
+    let header: Header = rmp_serde::from_slice(bytes).map_err(invalid_data)?;
+    if header.format_version > FORMAT_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "format version {} (written by {}) is newer than the supported {}",
+                header.format_version, header.producer, FORMAT_VERSION
+            ),
+        ));
+    }
+    let envelope: Envelope<T> = rmp_serde::from_slice(bytes).map_err(invalid_data)?;
+    Ok(envelope.payload)
+}
+
+/// Write `payload` as a length-prefixed (u32, big endian) MessagePack envelope, for
+/// streams carrying several of them.
+#[cfg(feature = "msgpack")]
+pub fn write_msgpack<W: Write, T: Serialize>(out: &mut W, payload: &T) -> io::Result<()> {
+    let bytes = to_msgpack(payload)?;
+    let len = u32::try_from(bytes.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "envelope over 4 GiB"))?;
+    out.write_all(&len.to_be_bytes())?;
+    out.write_all(&bytes)
+}
+
+/// Read one envelope written by `write_msgpack`; `None` at a clean end of stream.
+#[cfg(feature = "msgpack")]
+pub fn read_msgpack<R: Read, T: serde::de::DeserializeOwned>(
+    input: &mut R,
+) -> io::Result<Option<T>> {
+    let mut len = [0; 4];
+    match input.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let mut bytes = vec![0; u32::from_be_bytes(len) as usize];
+    input.read_exact(&mut bytes)?;
+    from_msgpack(&bytes).map(Some)
+}
+
+#[cfg(feature = "msgpack")]
+fn invalid_data(e: rmp_serde::decode::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(all(test, feature = "msgpack"))]
+mod tests {
+    use super::*;
+    use crate::stack_trace::{CaptureSource, StackTrace};
+    use crate::{CallFrame, Value};
+
+    fn trace() -> StackTrace {
+        let mut python = CallFrame::python("0x20", "app.py", "run", 3);
+        if let CallFrame::PyFrame { locals, .. } = &mut python {
+            locals.insert("n".to_string(), Value::Int(4));
+        }
+        StackTrace::captured(
+            vec![
+                CallFrame::native("0x10", "", "leaf", 0),
+                python,
+                CallFrame::gpu("add_kernel", Some(0), 7),
+                CallFrame::synthetic("[GIL wait]", "gil"),
+            ],
+            CaptureSource::InProcess,
+        )
+    }
+
+    #[test]
+    fn test_msgpack_round_trip() {
+        let trace = trace();
+        let bytes = to_msgpack(&trace).unwrap();
+        let decoded: StackTrace = from_msgpack(&bytes).unwrap();
+        assert_eq!(decoded, trace);
+        assert!(bytes.len() < serde_json::to_vec(&Envelope::new(&trace)).unwrap().len());
+    }
+
+    #[test]
+    fn test_msgpack_stream() {
+        let trace = trace();
+        let mut stream = Vec::new();
+        write_msgpack(&mut stream, &trace).unwrap();
+        write_msgpack(&mut stream, &trace.frames).unwrap();
+        let mut input = &stream[..];
+        let first: Option<StackTrace> = read_msgpack(&mut input).unwrap();
+        assert_eq!(first.as_ref(), Some(&trace));
+        let second: Option<Vec<CallFrame>> = read_msgpack(&mut input).unwrap();
+        assert_eq!(second.unwrap().len(), 4);
+        assert_eq!(read_msgpack::<_, StackTrace>(&mut input).unwrap(), None);
+    }
+
+    #[test]
+    fn test_newer_format_version_is_rejected() {
+        let mut envelope = Envelope::new(trace());
+        envelope.format_version = FORMAT_VERSION + 1;
+        assert!(!envelope.is_supported());
+        let bytes = rmp_serde::to_vec_named(&envelope).unwrap();
+        let err = from_msgpack::<StackTrace>(&bytes).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
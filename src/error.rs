@@ -0,0 +1,151 @@
+//! The error type of the capture and export APIs.
+//!
+//! A bare `io::Error` says "Operation not permitted" and leaves the caller guessing which
+//! of a dozen syscalls failed. `TracerError` names the step instead, so that a tool can
+//! tell its user to relax `kernel.yama.ptrace_scope` rather than print an errno. It still
+//! converts into `io::Error` for code built on those, and into the matching exception in
+//! the Python bindings.
+
+use std::io;
+
+/// Why a capture, symbolication or export failed.
+#[derive(Debug, thiserror::Error)]
+pub enum TracerError {
+    /// Threads, registers or memory of the target could not be read.
+    #[error("stack capture failed: {0}")]
+    Capture(#[source] io::Error),
+    /// The symbols or debug info of `module` could not be loaded.
+    #[error("cannot symbolize {module}: {source}")]
+    Symbolication {
+        module: String,
+        #[source]
+        source: io::Error,
+    },
+    /// Not available on this platform or for this target.
+    #[error("unsupported: {0}")]
+    Unsupported(String),
+    /// The OS refused to let us inspect process `pid`: ptrace restrictions, a missing
+    /// debugging entitlement on macOS, or a process of another user.
+    #[error("permission denied on process {pid}: {source}")]
+    PermissionDenied {
+        pid: i32,
+        #[source]
+        source: io::Error,
+    },
+    /// An argument that can never work, such as attaching to the calling process.
+    #[error("invalid argument: {0}")]
+    InvalidArgument(String),
+    /// Reading or writing a file, socket or output stream failed.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl TracerError {
+    /// Classify `err`, returned while capturing process `pid`.
+    ///
+    /// `EPERM` and `EACCES` become `PermissionDenied`, `ENOSYS` and the like
+    /// `Unsupported`, anything else `Capture`.
+    pub fn capture(pid: i32, err: io::Error) -> TracerError {
+        match err.kind() {
+            io::ErrorKind::PermissionDenied => TracerError::PermissionDenied { pid, source: err },
+            io::ErrorKind::Unsupported => TracerError::Unsupported(err.to_string()),
+            _ => TracerError::Capture(err),
+        }
+    }
+
+    /// The `io::ErrorKind` closest to this error.
+    pub fn kind(&self) -> io::ErrorKind {
+        match self {
+            TracerError::Capture(err) | TracerError::Io(err) => err.kind(),
+            TracerError::Symbolication { source, .. } => source.kind(),
+            TracerError::Unsupported(_) => io::ErrorKind::Unsupported,
+            TracerError::PermissionDenied { .. } => io::ErrorKind::PermissionDenied,
+            TracerError::InvalidArgument(_) => io::ErrorKind::InvalidInput,
+        }
+    }
+}
+
+impl From<TracerError> for io::Error {
+    fn from(err: TracerError) -> io::Error {
+        match err {
+            TracerError::Io(err) => err,
+            err => io::Error::new(err.kind(), err),
+        }
+    }
+}
+
+#[cfg(feature = "python")]
+impl From<TracerError> for pyo3::PyErr {
+    fn from(err: TracerError) -> pyo3::PyErr {
+        use crate::python::exceptions;
+        use pyo3::exceptions::PyValueError;
+
+        let message = err.to_string();
+        match err {
+            TracerError::Capture(_) => exceptions::CaptureError::new_err(message),
+            TracerError::Symbolication { .. } => exceptions::SymbolicationError::new_err(message),
+            TracerError::Unsupported(_) => exceptions::UnsupportedError::new_err(message),
+            TracerError::PermissionDenied { .. } => exceptions::PermissionError::new_err(message),
+            TracerError::InvalidArgument(_) => PyValueError::new_err(message),
+            TracerError::Io(_) => exceptions::TracerError::new_err(message),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capture_classification() {
+        let err = TracerError::capture(42, io::Error::from_raw_os_error(libc::EPERM));
+        assert!(matches!(err, TracerError::PermissionDenied { pid: 42, .. }));
+        assert!(err
+            .to_string()
+            .starts_with("permission denied on process 42"));
+
+        let err = TracerError::capture(42, io::Error::from(io::ErrorKind::Unsupported));
+        assert!(matches!(err, TracerError::Unsupported(_)));
+
+        let err = TracerError::capture(42, io::Error::from_raw_os_error(libc::ESRCH));
+        assert!(matches!(err, TracerError::Capture(_)));
+        assert_eq!(err.kind(), io::Error::from_raw_os_error(libc::ESRCH).kind());
+    }
+
+    #[test]
+    fn test_into_io_error() {
+        let err = io::Error::from(TracerError::InvalidArgument("pid 0".into()));
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "invalid argument: pid 0");
+
+        // Plain io errors come back unchanged.
+        let err = io::Error::from(TracerError::from(io::Error::from_raw_os_error(
+            libc::ENOENT,
+        )));
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+    }
+
+    #[cfg(feature = "python")]
+    #[test]
+    fn test_python_exceptions() {
+        use crate::python::exceptions::{self, CaptureError, PermissionError, UnsupportedError};
+        use pyo3::{PyErr, Python};
+
+        Python::initialize();
+        Python::attach(|py| {
+            let eperm = io::Error::from_raw_os_error(libc::EPERM);
+            let err = PyErr::from(TracerError::capture(7, eperm));
+            assert!(err.is_instance_of::<PermissionError>(py));
+            // Permission errors are capture errors, and all of them tracer errors.
+            assert!(err.is_instance_of::<CaptureError>(py));
+            assert!(err.is_instance_of::<exceptions::TracerError>(py));
+            assert!(err.to_string().contains("process 7"));
+
+            let err = PyErr::from(TracerError::Unsupported("PyPy".into()));
+            assert!(err.is_instance_of::<UnsupportedError>(py));
+            assert!(!err.is_instance_of::<CaptureError>(py));
+            let err = PyErr::from(TracerError::from(io::Error::other("closed")));
+            assert!(err.is_instance_of::<exceptions::TracerError>(py));
+        });
+    }
+}
@@ -0,0 +1,197 @@
+//! Structured events about dumps and profiles, emitted through `tracing` (feature
+//! `tracing`) under the `mixed_stack_tracer` target. Without the feature every hook is a
+//! no-op, so call sites need no `cfg`.
+//!
+//! With no `tracing` subscriber installed the events are forwarded to the `log` crate.
+
+use crate::profile::Profile;
+
+/// What caused a dump of all thread stacks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub(crate) enum DumpTrigger {
+    Watchdog,
+    Periodic,
+    Signal,
+    Control,
+    #[cfg(feature = "http")]
+    Http,
+    #[cfg(feature = "grpc")]
+    Grpc,
+}
+
+impl DumpTrigger {
+    #[cfg_attr(not(feature = "tracing"), allow(dead_code))]
+    fn as_str(self) -> &'static str {
+        match self {
+            DumpTrigger::Watchdog => "watchdog",
+            DumpTrigger::Periodic => "periodic",
+            DumpTrigger::Signal => "signal",
+            DumpTrigger::Control => "control",
+            #[cfg(feature = "http")]
+            DumpTrigger::Http => "http",
+            #[cfg(feature = "grpc")]
+            DumpTrigger::Grpc => "grpc",
+        }
+    }
+}
+
+/// All thread stacks were captured; `threads` is the number of stacks.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub(crate) fn stacks_dumped(trigger: DumpTrigger, threads: usize) {
+    #[cfg(feature = "tracing")]
+    match trigger {
+        // A stall is a problem in its own right, the others are routine.
+        DumpTrigger::Watchdog => tracing::warn!(
+            target: "mixed_stack_tracer",
+            trigger = trigger.as_str(),
+            threads,
+            "stacks dumped"
+        ),
+        _ => tracing::info!(
+            target: "mixed_stack_tracer",
+            trigger = trigger.as_str(),
+            threads,
+            "stacks dumped"
+        ),
+    }
+}
+
+/// A sampling session ended with `profile`.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub(crate) fn profile_recorded(profile: &Profile) {
+    #[cfg(feature = "tracing")]
+    tracing::info!(
+        target: "mixed_stack_tracer",
+        samples = profile.total_samples,
+        dropped = profile.dropped_samples,
+        stacks = profile.stacks.len(),
+        "profile recorded"
+    );
+}
+
+#[cfg(feature = "tracing")]
+impl crate::stack_tracer::SignalTracer {
+    /// Log the native stack of the calling thread at `level`, one frame per line, e.g.
+    /// to record how an unexpected code path was reached.
+    #[inline(never)]
+    pub fn log_current_stack(level: tracing::Level) {
+        let mut frames = Self::capture_native_stack().into_frames();
+        // The leaf is this function itself.
+        if frames
+            .first()
+            .is_some_and(|f| f.func().contains("log_current_stack"))
+        {
+            frames.remove(0);
+        }
+        let mut stack = Vec::new();
+        crate::output::text::write_frames(&mut stack, &frames)
+            .expect("writing to a Vec cannot fail");
+        let stack = String::from_utf8_lossy(&stack);
+        let frames = frames.len();
+        macro_rules! log_at {
+            ($($level:ident => $macro:ident),*) => {
+                match level {
+                    $(tracing::Level::$level => tracing::$macro!(
+                        target: "mixed_stack_tracer",
+                        frames,
+                        "current stack:\n{}",
+                        stack
+                    ),)*
+                }
+            };
+        }
+        log_at!(ERROR => error, WARN => warn, INFO => info, DEBUG => debug, TRACE => trace);
+    }
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use super::*;
+    use std::fmt::Write as _;
+    use std::sync::{Arc, Mutex};
+
+    use tracing::field::{Field, Visit};
+    use tracing::span::{Attributes, Id, Record};
+    use tracing::{Event, Level, Metadata, Subscriber};
+
+    /// Records `level target: fields` of every event.
+    #[derive(Clone, Default)]
+    struct Collector(Arc<Mutex<Vec<String>>>);
+
+    struct Fields<'a>(&'a mut String);
+
+    impl Visit for Fields<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            let _ = write!(self.0, " {}={:?}", field.name(), value);
+        }
+    }
+
+    impl Subscriber for Collector {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, _: &Attributes<'_>) -> Id {
+            Id::from_u64(1)
+        }
+        fn record(&self, _: &Id, _: &Record<'_>) {}
+        fn record_follows_from(&self, _: &Id, _: &Id) {}
+        fn event(&self, event: &Event<'_>) {
+            let mut line = format!(
+                "{} {}:",
+                event.metadata().level(),
+                event.metadata().target()
+            );
+            event.record(&mut Fields(&mut line));
+            self.0.lock().unwrap().push(line);
+        }
+        fn enter(&self, _: &Id) {}
+        fn exit(&self, _: &Id) {}
+    }
+
+    #[test]
+    fn test_events() {
+        let collector = Collector::default();
+        tracing::subscriber::with_default(collector.clone(), || {
+            stacks_dumped(DumpTrigger::Watchdog, 3);
+            stacks_dumped(DumpTrigger::Signal, 2);
+            let profile = Profile {
+                total_samples: 10,
+                dropped_samples: 1,
+                ..Profile::default()
+            };
+            profile_recorded(&profile);
+        });
+        assert_eq!(
+            *collector.0.lock().unwrap(),
+            [
+                "WARN mixed_stack_tracer: message=stacks dumped trigger=\"watchdog\" threads=3",
+                "INFO mixed_stack_tracer: message=stacks dumped trigger=\"signal\" threads=2",
+                "INFO mixed_stack_tracer: message=profile recorded samples=10 dropped=1 stacks=0",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_log_current_stack() {
+        let collector = Collector::default();
+        tracing::subscriber::with_default(collector.clone(), || {
+            crate::stack_tracer::SignalTracer::log_current_stack(Level::DEBUG);
+        });
+        let events = collector.0.lock().unwrap();
+        assert_eq!(events.len(), 1);
+        assert!(events[0].starts_with("DEBUG mixed_stack_tracer: message=current stack:\n  #0 "));
+        assert!(
+            events[0].contains("test_log_current_stack"),
+            "{}",
+            events[0]
+        );
+        assert!(
+            !events[0].contains("SignalTracer>::log_current_stack"),
+            "{}",
+            events[0]
+        );
+    }
+}
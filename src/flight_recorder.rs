@@ -0,0 +1,378 @@
+//! Flight-recorder mode: the last seconds of sampled stacks, kept in memory.
+//!
+//! A `FlightRecorder` runs a `Sampler` and writes every merged sample, folded into
+//! `root;...;leaf`, to a fixed ring of slots instead of aggregating it. Nothing reaches
+//! the disk until asked: `samples` and `write_folded` read the window on demand, and
+//! `dump_on_crash` has the crash handler append it to its dump, answering "what was
+//! happening just before it died".
+//!
+//! Slots are sequence locks over atomic bytes: the collector never waits for a reader,
+//! and readers, the crash handler included, neither allocate nor lock; a slot rewritten
+//! while being read is skipped.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicI32, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::crash_handler;
+use crate::output::folded::{self, FoldedOptions};
+use crate::profile::TimedSample;
+use crate::sampler::{monotonic_ns, PythonStacksProvider, Sampler};
+use crate::unwind::UnwindStrategy;
+
+/// Bytes of folded stack a slot holds; longer stacks keep their leaf end.
+pub(crate) const SLOT_BYTES: usize = 2048;
+
+/// Options of `FlightRecorder::start_with`.
+pub struct FlightRecorderOptions {
+    retention: Duration,
+    capacity: usize,
+    unwind: UnwindStrategy,
+    python_stacks: Option<PythonStacksProvider>,
+}
+
+impl Default for FlightRecorderOptions {
+    fn default() -> Self {
+        FlightRecorderOptions {
+            retention: Duration::from_secs(10),
+            capacity: 4096,
+            unwind: UnwindStrategy::Dwarf,
+            python_stacks: None,
+        }
+    }
+}
+
+impl FlightRecorderOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Age of the oldest sample still reported, relative to the newest one.
+    pub fn retention(mut self, retention: Duration) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Number of samples the ring holds, across all threads; older ones are overwritten
+    /// even within `retention`. Each takes about `SLOT_BYTES` bytes.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// How the sampler walks native stacks (see `UnwindStrategy`).
+    pub fn unwind(mut self, strategy: UnwindStrategy) -> Self {
+        self.unwind = strategy;
+        self
+    }
+
+    /// Merge samples with the Python stacks of `provider`.
+    pub fn python_stacks(mut self, provider: PythonStacksProvider) -> Self {
+        self.python_stacks = Some(provider);
+        self
+    }
+
+    pub fn get_retention(&self) -> Duration {
+        self.retention
+    }
+
+    pub fn get_capacity(&self) -> usize {
+        self.capacity
+    }
+}
+
+/// One sample of the window.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecordedStack {
+    pub tid: i32,
+    /// Capture time in nanoseconds on `CLOCK_MONOTONIC`.
+    pub timestamp_ns: u64,
+    /// `root;...;leaf`, as `folded::fold_stack` writes it.
+    pub folded: String,
+}
+
+/// A sampler recording the last `retention` of stacks; see the module docs.
+pub struct FlightRecorder {
+    ring: Arc<StackRing>,
+    sampler: Option<Sampler>,
+}
+
+impl FlightRecorder {
+    /// Sample at `freq_hz`, keeping the last `retention` of native stacks.
+    pub fn start(freq_hz: u32, retention: Duration) -> io::Result<FlightRecorder> {
+        Self::start_with(freq_hz, FlightRecorderOptions::new().retention(retention))
+    }
+
+    /// Sample at `freq_hz` with `options`. Like any `Sampler`, only one can run at a time.
+    pub fn start_with(freq_hz: u32, options: FlightRecorderOptions) -> io::Result<FlightRecorder> {
+        let ring = Arc::new(StackRing::new(options.capacity, options.retention));
+        let observer = {
+            let ring = Arc::clone(&ring);
+            let fold = FoldedOptions::new();
+            Box::new(move |sample: &TimedSample| {
+                let folded = folded::fold_stack(&sample.frames, &fold);
+                ring.record(sample.tid, sample.timestamp_ns, &folded);
+            })
+        };
+        let sampler = Sampler::start_observed(
+            freq_hz,
+            options.unwind,
+            options.python_stacks,
+            Some(observer),
+        )?;
+        Ok(FlightRecorder {
+            ring,
+            sampler: Some(sampler),
+        })
+    }
+
+    /// Samples of the window, oldest first.
+    pub fn samples(&self) -> Vec<RecordedStack> {
+        let mut samples = Vec::new();
+        let mut buf = [0u8; SLOT_BYTES];
+        self.ring.for_each(&mut buf, |tid, timestamp_ns, stack| {
+            samples.push(RecordedStack {
+                tid,
+                timestamp_ns,
+                folded: String::from_utf8_lossy(stack).into_owned(),
+            });
+        });
+        samples
+    }
+
+    /// The window as folded lines, `stack count`, identical stacks summed.
+    pub fn write_folded<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut order = Vec::new();
+        let mut counts: HashMap<String, u64> = HashMap::new();
+        for sample in self.samples() {
+            let count = counts.entry(sample.folded.clone()).or_insert(0);
+            if *count == 0 {
+                order.push(sample.folded);
+            }
+            *count += 1;
+        }
+        for stack in order {
+            writeln!(out, "{} {}", stack, counts[&stack])?;
+        }
+        Ok(())
+    }
+
+    /// Have the crash handler (`crash_handler::install` or `enable`, installed separately)
+    /// append the window to its dump, one `timestamp_ns tid stack` line per sample.
+    /// Replaces the recorder registered before, if any, until this one is dropped.
+    pub fn dump_on_crash(&self) {
+        crash_handler::set_flight_recorder(Some(Arc::clone(&self.ring)));
+    }
+
+    /// Stop sampling and return the window.
+    pub fn stop(mut self) -> Vec<RecordedStack> {
+        if let Some(sampler) = self.sampler.take() {
+            sampler.stop();
+        }
+        self.samples()
+    }
+}
+
+impl Drop for FlightRecorder {
+    fn drop(&mut self) {
+        crash_handler::clear_flight_recorder(&self.ring);
+    }
+}
+
+/// The ring of folded stacks behind a `FlightRecorder`.
+pub(crate) struct StackRing {
+    slots: Box<[Slot]>,
+    next: AtomicUsize,
+    retention_ns: u64,
+}
+
+struct Slot {
+    /// 0 while never written, odd while being written.
+    seq: AtomicU64,
+    tid: AtomicI32,
+    timestamp_ns: AtomicU64,
+    len: AtomicUsize,
+    bytes: Box<[AtomicU8]>,
+}
+
+impl StackRing {
+    pub(crate) fn new(capacity: usize, retention: Duration) -> StackRing {
+        let slots = (0..capacity.max(1))
+            .map(|_| Slot {
+                seq: AtomicU64::new(0),
+                tid: AtomicI32::new(0),
+                timestamp_ns: AtomicU64::new(0),
+                len: AtomicUsize::new(0),
+                bytes: (0..SLOT_BYTES).map(|_| AtomicU8::new(0)).collect(),
+            })
+            .collect();
+        StackRing {
+            slots,
+            next: AtomicUsize::new(0),
+            retention_ns: retention.as_nanos().min(u64::MAX as u128) as u64,
+        }
+    }
+
+    /// Store a folded stack in the oldest slot, unless another writer holds it.
+    pub(crate) fn record(&self, tid: i32, timestamp_ns: u64, stack: &str) {
+        let slot = &self.slots[self.next.fetch_add(1, Ordering::Relaxed) % self.slots.len()];
+        let seq = slot.seq.load(Ordering::Relaxed);
+        if seq % 2 == 1
+            || slot
+                .seq
+                .compare_exchange(seq, seq + 1, Ordering::Acquire, Ordering::Relaxed)
+                .is_err()
+        {
+            return;
+        }
+        let stack = leaf_end(stack.as_bytes(), SLOT_BYTES);
+        slot.tid.store(tid, Ordering::Relaxed);
+        slot.timestamp_ns.store(timestamp_ns, Ordering::Relaxed);
+        slot.len.store(stack.len(), Ordering::Relaxed);
+        for (dst, src) in slot.bytes.iter().zip(stack) {
+            dst.store(*src, Ordering::Relaxed);
+        }
+        slot.seq.store(seq + 2, Ordering::Release);
+    }
+
+    /// Call `emit(tid, timestamp_ns, folded stack)` for every sample within the retention
+    /// of the newest one, oldest first, copying each into `buf`. Allocation-free and
+    /// lock-free, so usable from a signal handler.
+    pub(crate) fn for_each(
+        &self,
+        buf: &mut [u8; SLOT_BYTES],
+        mut emit: impl FnMut(i32, u64, &[u8]),
+    ) {
+        let newest = self
+            .slots
+            .iter()
+            .map(|s| s.timestamp_ns.load(Ordering::Relaxed))
+            .max()
+            .unwrap_or(0);
+        let oldest = newest.saturating_sub(self.retention_ns);
+        let start = self.next.load(Ordering::Relaxed);
+        for i in 0..self.slots.len() {
+            let slot = &self.slots[(start + i) % self.slots.len()];
+            let seq = slot.seq.load(Ordering::Acquire);
+            if seq == 0 || seq % 2 == 1 {
+                continue;
+            }
+            let tid = slot.tid.load(Ordering::Relaxed);
+            let timestamp_ns = slot.timestamp_ns.load(Ordering::Relaxed);
+            let len = slot.len.load(Ordering::Relaxed).min(SLOT_BYTES);
+            for (dst, src) in buf.iter_mut().zip(&slot.bytes[..len]) {
+                *dst = src.load(Ordering::Relaxed);
+            }
+            std::sync::atomic::fence(Ordering::Acquire);
+            if slot.seq.load(Ordering::Relaxed) != seq || timestamp_ns < oldest {
+                continue;
+            }
+            emit(tid, timestamp_ns, &buf[..len]);
+        }
+    }
+}
+
+/// The last `max` bytes of a folded stack, cut at a frame boundary.
+pub(crate) fn leaf_end(stack: &[u8], max: usize) -> &[u8] {
+    if stack.len() <= max {
+        return stack;
+    }
+    let tail = &stack[stack.len() - max..];
+    match tail.iter().position(|b| *b == b';') {
+        Some(at) => &tail[at + 1..],
+        None => tail,
+    }
+}
+
+/// `CLOCK_MONOTONIC` now, on the clock of `RecordedStack::timestamp_ns`.
+pub fn now_ns() -> u64 {
+    monotonic_ns()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::TEST_SAMPLER_LOCK;
+    use std::time::Instant;
+
+    fn ring_samples(ring: &StackRing) -> Vec<(i32, u64, String)> {
+        let mut out = Vec::new();
+        let mut buf = [0u8; SLOT_BYTES];
+        ring.for_each(&mut buf, |tid, ts, stack| {
+            out.push((tid, ts, String::from_utf8(stack.to_vec()).unwrap()))
+        });
+        out
+    }
+
+    #[test]
+    fn test_ring_keeps_window() {
+        let ring = StackRing::new(3, Duration::from_nanos(100));
+        assert!(ring_samples(&ring).is_empty());
+        ring.record(1, 10, "main;a");
+        ring.record(1, 150, "main;b");
+        ring.record(2, 160, "main;c");
+        // Older than 100ns before the newest sample.
+        assert_eq!(
+            ring_samples(&ring),
+            [
+                (1, 150, "main;b".to_string()),
+                (2, 160, "main;c".to_string())
+            ]
+        );
+
+        // Full: the oldest slot is overwritten, order stays oldest first.
+        ring.record(2, 170, "main;d");
+        let stacks: Vec<String> = ring_samples(&ring).into_iter().map(|s| s.2).collect();
+        assert_eq!(stacks, ["main;b", "main;c", "main;d"]);
+    }
+
+    #[test]
+    fn test_leaf_end() {
+        let long = format!("{};leaf", "frame;".repeat(SLOT_BYTES));
+        let kept = leaf_end(long.as_bytes(), SLOT_BYTES);
+        assert!(kept.len() <= SLOT_BYTES);
+        assert!(kept.starts_with(b"frame;"));
+        assert!(kept.ends_with(b";leaf"));
+        assert_eq!(leaf_end(b"a;b", SLOT_BYTES), b"a;b");
+    }
+
+    #[inline(never)]
+    fn spin(duration: Duration) -> u64 {
+        let start = Instant::now();
+        let mut acc = 0u64;
+        while start.elapsed() < duration {
+            acc = acc.wrapping_mul(31).wrapping_add(std::hint::black_box(7));
+        }
+        acc
+    }
+
+    #[test]
+    fn test_flight_recorder() {
+        let _guard = TEST_SAMPLER_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let options = FlightRecorderOptions::new()
+            .retention(Duration::from_secs(60))
+            .capacity(64);
+        assert_eq!(options.get_capacity(), 64);
+        let recorder = FlightRecorder::start_with(997, options).unwrap();
+        std::hint::black_box(spin(Duration::from_millis(300)));
+        // Readable while sampling, once the collector has drained a batch.
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while recorder.samples().is_empty() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(recorder.samples().len() <= 64);
+        let mut folded = Vec::new();
+        recorder.write_folded(&mut folded).unwrap();
+        assert!(String::from_utf8(folded).unwrap().lines().count() > 0);
+
+        let samples = recorder.stop();
+        assert!(!samples.is_empty());
+        assert!(samples
+            .windows(2)
+            .all(|w| w[0].timestamp_ns <= w[1].timestamp_ns));
+        assert!(samples.iter().all(|s| s.timestamp_ns <= now_ns()));
+        assert!(samples.iter().any(|s| s.folded.contains("spin")));
+    }
+}
@@ -0,0 +1,125 @@
+//! Fork safety of the sampler, the stack capture, dump handlers and background threads.
+//!
+//! `fork()` copies only the calling thread. Without these handlers a child forked while a
+//! `Sampler` runs (a `multiprocessing` worker, say) would keep a SIGPROF handler with no
+//! collector draining its ring, dump handlers would keep writing to the parent's wake-up
+//! pipes and hold them open, and the capture lock could stay held by a thread that no
+//! longer exists. Dropping the handles copied from the parent would join threads that are
+//! gone.
+//!
+//! The `pthread_atfork` handlers, registered by the first sampler, capture or dump handler,
+//! pause sampling in the parent while it forks, as a SIGPROF landing in `fork` restarts it,
+//! and hold the locks these use. The child comes out with all of them disabled: previous
+//! signal dispositions restored and inherited handles inert, dropped without touching the
+//! parent's threads. It can start a sampler or install dump handlers of its own.
+
+use std::sync::Once;
+#[cfg(target_os = "linux")]
+use std::thread::JoinHandle;
+
+static REGISTER: Once = Once::new();
+
+/// Register the fork handlers, once per process.
+pub(crate) fn register_handlers() {
+    REGISTER.call_once(|| unsafe {
+        libc::pthread_atfork(Some(prepare), Some(parent), Some(child));
+    });
+}
+
+extern "C" fn prepare() {
+    #[cfg(target_os = "linux")]
+    crate::sampler::pause_for_fork();
+    crate::threads::lock_capture_for_fork();
+}
+
+extern "C" fn parent() {
+    crate::threads::unlock_capture_after_fork();
+    #[cfg(target_os = "linux")]
+    crate::sampler::resume_after_fork();
+}
+
+extern "C" fn child() {
+    crate::threads::unlock_capture_after_fork();
+    #[cfg(target_os = "linux")]
+    {
+        crate::threads::reset_capture_in_child();
+        crate::sampler::disable_in_child();
+        crate::signal_dump::disable_in_child();
+    }
+}
+
+/// Handle of a thread this process started. In a child forked afterwards, where the thread
+/// does not exist, it is neither joined nor detached.
+#[cfg(target_os = "linux")]
+pub(crate) struct BackgroundThread<T> {
+    handle: Option<JoinHandle<T>>,
+    pid: u32,
+}
+
+#[cfg(target_os = "linux")]
+impl<T> BackgroundThread<T> {
+    pub(crate) fn new(handle: JoinHandle<T>) -> Self {
+        BackgroundThread {
+            handle: Some(handle),
+            pid: std::process::id(),
+        }
+    }
+
+    /// Whether this process is a child forked after the thread started.
+    pub(crate) fn is_forked(&self) -> bool {
+        std::process::id() != self.pid
+    }
+
+    /// Wait for the thread to finish; `None` if it panicked, or in a forked child.
+    pub(crate) fn join(mut self) -> Option<T> {
+        let handle = self.handle.take()?;
+        if self.is_forked() {
+            std::mem::forget(handle);
+            return None;
+        }
+        handle.join().ok()
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl<T> Drop for BackgroundThread<T> {
+    fn drop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            if self.is_forked() {
+                std::mem::forget(handle);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+
+    #[test]
+    fn test_background_thread_in_child() {
+        let (release, parked) = mpsc::channel::<()>();
+        let worker = BackgroundThread::new(thread::spawn(move || parked.recv().is_err()));
+        assert!(!worker.is_forked());
+
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            // Joining the parent's thread would wait forever.
+            let code = if worker.is_forked() && worker.join().is_none() {
+                0
+            } else {
+                1
+            };
+            unsafe { libc::_exit(code) };
+        }
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0);
+
+        drop(release);
+        assert_eq!(worker.join(), Some(true));
+    }
+}
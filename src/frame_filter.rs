@@ -0,0 +1,425 @@
+//! Dropping and trimming frames of merged stacks before they are exported.
+//!
+//! Deep ML stacks are mostly framework plumbing; a `FrameFilter` keeps the frames worth
+//! looking at. Rules apply in order: include/exclude patterns and the Python library
+//! rules drop single frames, recursion counting and collapsing fold repeated frames and
+//! call cycles, and `max_depth` finally keeps only the frames closest to the leaf.
+
+use regex::Regex;
+
+use crate::profile::{Profile, StackAggregator};
+use crate::stack_trace::truncate_frames;
+use crate::{CallFrame, FrameKind};
+
+/// Longest call cycle `collapse_recursion` looks for, e.g. a Python function and the
+/// interpreter frames between two of its calls.
+const MAX_CYCLE_LEN: usize = 16;
+
+/// Rules for `FrameFilter::apply`; keeps every frame by default.
+#[derive(Clone, Debug, Default)]
+pub struct FrameFilter {
+    include_func: Vec<Regex>,
+    exclude_func: Vec<Regex>,
+    include_file: Vec<Regex>,
+    exclude_file: Vec<Regex>,
+    drop_stdlib: bool,
+    drop_site_packages: bool,
+    collapse_recursion: bool,
+    count_recursion: bool,
+    in_app_only: bool,
+    max_depth: Option<usize>,
+}
+
+impl FrameFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Keep frames whose function matches `pattern`. Once any include pattern (on
+    /// functions or files) is given, only frames matching one of them are kept.
+    pub fn include_func(mut self, pattern: Regex) -> Self {
+        self.include_func.push(pattern);
+        self
+    }
+
+    /// Drop frames whose function matches `pattern`; wins over includes.
+    pub fn exclude_func(mut self, pattern: Regex) -> Self {
+        self.exclude_func.push(pattern);
+        self
+    }
+
+    /// Keep frames whose file matches `pattern`; see `include_func`.
+    pub fn include_file(mut self, pattern: Regex) -> Self {
+        self.include_file.push(pattern);
+        self
+    }
+
+    /// Drop frames whose file matches `pattern`; wins over includes.
+    pub fn exclude_file(mut self, pattern: Regex) -> Self {
+        self.exclude_file.push(pattern);
+        self
+    }
+
+    /// Drop Python frames of the standard library (`lib/pythonX.Y`, frozen modules).
+    pub fn drop_stdlib(mut self, drop: bool) -> Self {
+        self.drop_stdlib = drop;
+        self
+    }
+
+    /// Drop Python frames of installed packages (`site-packages`, `dist-packages`).
+    pub fn drop_site_packages(mut self, drop: bool) -> Self {
+        self.drop_site_packages = drop;
+        self
+    }
+
+    /// Drop Python frames not marked `in_app` (see `SignalTracer::classify_frames`), for an
+    /// "only my code" view. Other frames are left to the remaining rules.
+    pub fn in_app_only(mut self, only: bool) -> Self {
+        self.in_app_only = only;
+        self
+    }
+
+    /// Fold directly repeated call cycles (`f f f`, or `f eval f eval`) into one.
+    pub fn collapse_recursion(mut self, collapse: bool) -> Self {
+        self.collapse_recursion = collapse;
+        self
+    }
+
+    /// Collapse call cycles like `collapse_recursion`, annotating the leaf-most frame of
+    /// each collapsed cycle with the number of calls it stands for (`fib [x30]`), so deep
+    /// recursion stays one readable flame graph frame. Implies `collapse_recursion`.
+    pub fn count_recursion(mut self, count: bool) -> Self {
+        self.count_recursion = count;
+        self
+    }
+
+    /// Keep at most `depth` frames, the ones closest to the leaf, and end the stack with a
+    /// `[truncated N frames]` marker for the others (see `stack_trace::truncate_frames`).
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    pub fn get_max_depth(&self) -> Option<usize> {
+        self.max_depth
+    }
+
+    /// Whether the rules drop, fold or cut anything at all.
+    pub fn is_noop(&self) -> bool {
+        self.include_func.is_empty()
+            && self.exclude_func.is_empty()
+            && self.include_file.is_empty()
+            && self.exclude_file.is_empty()
+            && !self.drop_stdlib
+            && !self.drop_site_packages
+            && !self.collapse_recursion
+            && !self.count_recursion
+            && !self.in_app_only
+            && self.max_depth.is_none()
+    }
+
+    /// Whether the per-frame rules keep `frame`.
+    pub fn keeps(&self, frame: &CallFrame) -> bool {
+        let (func, file) = (frame.func(), frame.file());
+        if self.exclude_func.iter().any(|p| p.is_match(func))
+            || self.exclude_file.iter().any(|p| p.is_match(file))
+        {
+            return false;
+        }
+        if let CallFrame::PyFrame { in_app: false, .. } = frame {
+            if self.in_app_only {
+                return false;
+            }
+        }
+        if frame.kind() == FrameKind::Python {
+            let library = library_kind(file);
+            if self.drop_stdlib && library == Some(Library::Stdlib)
+                || self.drop_site_packages && library == Some(Library::Package)
+            {
+                return false;
+            }
+        }
+        let includes = self.include_func.len() + self.include_file.len();
+        includes == 0
+            || self.include_func.iter().any(|p| p.is_match(func))
+            || self.include_file.iter().any(|p| p.is_match(file))
+    }
+
+    /// Filter a leaf-first stack.
+    pub fn apply(&self, frames: Vec<CallFrame>) -> Vec<CallFrame> {
+        let mut frames: Vec<CallFrame> = frames.into_iter().filter(|f| self.keeps(f)).collect();
+        if self.collapse_recursion || self.count_recursion {
+            frames = collapse_cycles(frames, self.count_recursion);
+        }
+        if let Some(depth) = self.max_depth {
+            truncate_frames(&mut frames, depth);
+        }
+        frames
+    }
+
+    /// Filter every stack of `profile`, merging stacks of a thread that became equal.
+    pub fn apply_to_profile(&self, profile: Profile) -> Profile {
+        let mut aggregator = StackAggregator::default();
+        for stack in profile.stacks {
+            aggregator.add_weighted(
+                stack.tid,
+                stack.cpu,
+                stack.state,
+                &self.apply(stack.frames),
+                stack.count,
+                stack.weight_ns,
+            );
+        }
+        Profile {
+            total_samples: profile.total_samples,
+            dropped_samples: profile.dropped_samples,
+            ..aggregator.into_profile()
+        }
+    }
+}
+
+/// Where a Python source file lives.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Library {
+    /// The standard library, including frozen modules.
+    Stdlib,
+    /// An installed third-party package.
+    Package,
+}
+
+/// `None` for application code.
+pub(crate) fn library_kind(file: &str) -> Option<Library> {
+    if file.contains("/site-packages/") || file.contains("/dist-packages/") {
+        Some(Library::Package)
+    } else if file.contains("/lib/python") || file.starts_with("<frozen ") {
+        Some(Library::Stdlib)
+    } else {
+        None
+    }
+}
+
+/// Identity of a frame for recursion detection: the function, not the call site.
+fn same_function(a: &CallFrame, b: &CallFrame) -> bool {
+    a.kind() == b.kind() && a.func() == b.func() && a.file() == b.file()
+}
+
+/// Drop every repetition of a cycle that directly follows an occurrence of itself,
+/// keeping the leaf-most one. With `annotate`, the cycle's leaf-most frame is suffixed
+/// with ` [xN]`, N the calls of it the collapsed repetitions stood for.
+fn collapse_cycles(mut frames: Vec<CallFrame>, annotate: bool) -> Vec<CallFrame> {
+    let mut calls = vec![1; frames.len()];
+    let mut i = 0;
+    while i < frames.len() {
+        let mut collapsed = false;
+        for len in 1..=MAX_CYCLE_LEN.min((frames.len() - i) / 2) {
+            let repeats = |start: usize| {
+                start + 2 * len <= frames.len()
+                    && (0..len).all(|k| same_function(&frames[start + k], &frames[start + len + k]))
+            };
+            if repeats(i) {
+                let mut end = i + len;
+                while repeats(end - len) {
+                    end += len;
+                }
+                calls[i] += (i + len..end).step_by(len).map(|j| calls[j]).sum::<usize>();
+                frames.drain(i + len..end);
+                calls.drain(i + len..end);
+                collapsed = true;
+                break;
+            }
+        }
+        if collapsed {
+            // The frames before may now form a cycle with what follows.
+            i = i.saturating_sub(MAX_CYCLE_LEN);
+        } else {
+            i += 1;
+        }
+    }
+    if annotate {
+        for (frame, calls) in frames.iter_mut().zip(calls) {
+            annotate_repeats(frame, calls);
+        }
+    }
+    frames
+}
+
+/// Collapse call cycles, annotated with their call counts (`FrameFilter::count_recursion`).
+pub(crate) fn count_repeats(frames: Vec<CallFrame>) -> Vec<CallFrame> {
+    collapse_cycles(frames, true)
+}
+
+fn annotate_repeats(frame: &mut CallFrame, run: usize) {
+    if run < 2 {
+        return;
+    }
+    let name = match frame {
+        CallFrame::CFrame { func, .. }
+        | CallFrame::PyFrame { func, .. }
+        | CallFrame::InterpreterFrame { func, .. } => func,
+        CallFrame::GpuFrame { kernel, .. } => kernel,
+        CallFrame::Synthetic { label, .. } => label,
+    };
+    name.push_str(&format!(" [x{}]", run));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stack_trace::TRUNCATED_CATEGORY;
+    use crate::stack_tracer::SignalTracer;
+
+    fn names(frames: &[CallFrame]) -> Vec<&str> {
+        frames.iter().map(CallFrame::func).collect()
+    }
+
+    #[test]
+    fn test_patterns_and_libraries() {
+        let frames = vec![
+            CallFrame::native("0x1", "", "PyEval_EvalFrameDefault", 0),
+            CallFrame::python("0x2", "/usr/lib/python3.11/threading.py", "run", 1),
+            CallFrame::python(
+                "0x3",
+                "/venv/lib/python3.11/site-packages/torch/x.py",
+                "fwd",
+                2,
+            ),
+            CallFrame::python("0x4", "/srv/app/train.py", "step", 3),
+            CallFrame::synthetic("[GIL wait]", "gil"),
+        ];
+        let filter = FrameFilter::new()
+            .exclude_func(Regex::new("^PyEval").unwrap())
+            .drop_stdlib(true);
+        assert_eq!(
+            names(&filter.apply(frames.clone())),
+            vec!["fwd", "step", "[GIL wait]"]
+        );
+        let filter = FrameFilter::new().drop_site_packages(true).max_depth(2);
+        let cut = filter.apply(frames.clone());
+        assert_eq!(names(&cut[..2]), vec!["PyEval_EvalFrameDefault", "run"]);
+        assert_eq!(
+            cut[2],
+            CallFrame::synthetic("[truncated 2 frames]", TRUNCATED_CATEGORY)
+        );
+        let filter = FrameFilter::new()
+            .include_file(Regex::new("^/srv/app/").unwrap())
+            .include_func(Regex::new("GIL").unwrap());
+        assert_eq!(
+            names(&filter.apply(frames.clone())),
+            vec!["step", "[GIL wait]"]
+        );
+        let mut classified = frames;
+        SignalTracer::classify_frames(&mut classified);
+        let filter = FrameFilter::new().in_app_only(true);
+        assert_eq!(
+            names(&filter.apply(classified)),
+            vec!["PyEval_EvalFrameDefault", "step", "[GIL wait]"]
+        );
+    }
+
+    #[test]
+    fn test_collapse_recursion() {
+        let py = |func: &str, lineno| CallFrame::python("0x1", "a.py", func, lineno);
+        let eval = || CallFrame::native("0x2", "ceval.c", "_PyEval_EvalFrameDefault", 0);
+        let frames = vec![
+            py("leaf", 1),
+            py("fib", 2),
+            eval(),
+            py("fib", 3),
+            eval(),
+            py("fib", 3),
+            eval(),
+            py("main", 9),
+            py("main", 9),
+        ];
+        let filter = FrameFilter::new().collapse_recursion(true);
+        assert_eq!(
+            names(&filter.apply(frames)),
+            vec!["leaf", "fib", "_PyEval_EvalFrameDefault", "main"]
+        );
+    }
+
+    #[test]
+    fn test_count_recursion() {
+        let py = |func: &str, file: &str, lineno| CallFrame::python("0x1", file, func, lineno);
+        let frames = vec![
+            py("leaf", "a.py", 1),
+            py("fib", "a.py", 2),
+            py("fib", "a.py", 3),
+            py("fib", "a.py", 3),
+            py("fib", "b.py", 7),
+            py("main", "a.py", 9),
+        ];
+        let counted = FrameFilter::new().count_recursion(true).apply(frames);
+        assert_eq!(names(&counted), vec!["leaf", "fib [x3]", "fib", "main"]);
+        // The leaf-most call of the run is kept.
+        assert_eq!(counted[1].lineno(), 2);
+
+        let nested = vec![
+            py("fib", "a.py", 2),
+            py("fib", "a.py", 3),
+            py("main", "a.py", 9),
+            py("main", "a.py", 9),
+        ];
+        let filter = FrameFilter::new()
+            .count_recursion(true)
+            .collapse_recursion(true);
+        assert_eq!(names(&filter.apply(nested)), vec!["fib [x2]", "main [x2]"]);
+    }
+
+    #[test]
+    fn test_count_recursion_through_eval_frames() {
+        let py = |func: &str, lineno| CallFrame::python("0x1", "a.py", func, lineno);
+        let eval = || CallFrame::native("0x2", "ceval.c", "_PyEval_EvalFrameDefault", 0);
+        let frames = vec![
+            py("leaf", 1),
+            py("fib", 2),
+            eval(),
+            py("fib", 3),
+            eval(),
+            py("fib", 3),
+            eval(),
+            py("main", 9),
+        ];
+        let counted = FrameFilter::new().count_recursion(true).apply(frames);
+        assert_eq!(
+            names(&counted),
+            vec!["leaf", "fib [x3]", "_PyEval_EvalFrameDefault", "main"]
+        );
+
+        // Nested cycles add up: two runs of `a` then the `a b` cycle they form.
+        let a = || py("a", 1);
+        let b = || py("b", 2);
+        let counted =
+            FrameFilter::new()
+                .count_recursion(true)
+                .apply(vec![a(), a(), b(), a(), a(), b()]);
+        assert_eq!(names(&counted), vec!["a [x4]", "b"]);
+    }
+
+    #[test]
+    fn test_apply_to_profile() {
+        use crate::profile::SampledStack;
+
+        let stack = |funcs: &[&str], count| SampledStack {
+            tid: 1,
+            frames: funcs
+                .iter()
+                .map(|f| CallFrame::python("0x1", "a.py", *f, 1))
+                .collect(),
+            count,
+            weight_ns: count * 10,
+            ..SampledStack::default()
+        };
+        let profile = Profile {
+            stacks: vec![stack(&["a", "noise", "main"], 2), stack(&["a", "main"], 3)],
+            total_samples: 6,
+            dropped_samples: 1,
+        };
+        let filtered = FrameFilter::new()
+            .exclude_func(Regex::new("noise").unwrap())
+            .apply_to_profile(profile);
+        assert_eq!(filtered.stacks, vec![stack(&["a", "main"], 5)]);
+        assert_eq!(filtered.total_samples, 6);
+        assert_eq!(filtered.dropped_samples, 1);
+    }
+}
@@ -0,0 +1,202 @@
+//! Interned frames for high-volume aggregation.
+//!
+//! A `FrameTable` stores every distinct string once and every distinct frame once, so a
+//! stack becomes a `Vec<FrameId>` of small integers. Frames are converted back to
+//! `CallFrame`s at export time.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::{CallFrame, FrameKind};
+
+/// Handle of a frame in the `FrameTable` that produced it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FrameId(u32);
+
+impl FrameId {
+    pub fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct StrId(u32);
+
+/// What makes two frames the same frame: kind, function, file and line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct FrameKey {
+    kind: FrameKind,
+    func: StrId,
+    file: StrId,
+    lineno: i64,
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    key: FrameKey,
+    /// ip and raw name of the first frame interned under `key`.
+    ip: StrId,
+    raw_func: Option<StrId>,
+}
+
+/// Deduplicating store of frames and their strings.
+///
+/// Frames that only differ by ip (for Python frames, the frame object address), raw name or
+/// locals intern to the same id, which keeps the first one's ip and raw name; locals and
+/// inlined calls are not stored. This matches how profiles aggregate stacks.
+#[derive(Clone, Debug, Default)]
+pub struct FrameTable {
+    strings: Vec<Arc<str>>,
+    string_index: HashMap<Arc<str>, StrId>,
+    frames: Vec<Entry>,
+    frame_index: HashMap<FrameKey, FrameId>,
+}
+
+impl FrameTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of distinct frames.
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Id of `frame`, adding it on first sight. Allocates only for unseen strings.
+    pub fn intern(&mut self, frame: &CallFrame) -> FrameId {
+        let (ip, raw_func) = match frame {
+            CallFrame::CFrame { ip, raw_func, .. } => (ip.as_str(), raw_func.as_deref()),
+            CallFrame::PyFrame { ip, .. } => (ip.as_str(), None),
+            // An interpreter frame's runtime takes the ip slot.
+            CallFrame::InterpreterFrame { runtime, .. } => (runtime.as_str(), None),
+            CallFrame::GpuFrame { .. } | CallFrame::Synthetic { .. } => ("", None),
+        };
+        // A synthetic frame's category takes the file slot.
+        let file = match frame {
+            CallFrame::Synthetic { category, .. } => category,
+            _ => frame.file(),
+        };
+        let key = FrameKey {
+            kind: frame.kind(),
+            func: self.string(frame.func()),
+            file: self.string(file),
+            lineno: frame.lineno(),
+        };
+        if let Some(id) = self.frame_index.get(&key) {
+            return *id;
+        }
+
+        let id = FrameId(self.frames.len() as u32);
+        let entry = Entry {
+            key,
+            ip: self.string(ip),
+            raw_func: raw_func.map(|r| self.string(r)),
+        };
+        self.frames.push(entry);
+        self.frame_index.insert(key, id);
+        id
+    }
+
+    pub fn intern_stack(&mut self, frames: &[CallFrame]) -> Vec<FrameId> {
+        frames.iter().map(|f| self.intern(f)).collect()
+    }
+
+    /// Function name of `id`, without materializing the frame.
+    pub fn func(&self, id: FrameId) -> &str {
+        &self.strings[self.frames[id.index()].key.func.0 as usize]
+    }
+
+    /// Rebuild the `CallFrame` behind `id`.
+    pub fn frame(&self, id: FrameId) -> CallFrame {
+        let entry = &self.frames[id.index()];
+        let s = |id: StrId| self.strings[id.0 as usize].to_string();
+        match entry.key.kind {
+            FrameKind::Python => CallFrame::python(
+                s(entry.ip),
+                s(entry.key.file),
+                s(entry.key.func),
+                entry.key.lineno,
+            ),
+            FrameKind::Gpu => CallFrame::gpu(s(entry.key.func), None, 0),
+            FrameKind::Interpreter => CallFrame::interpreter(
+                s(entry.ip),
+                s(entry.key.file),
+                s(entry.key.func),
+                entry.key.lineno,
+            ),
+            FrameKind::Synthetic => CallFrame::synthetic(s(entry.key.func), s(entry.key.file)),
+            FrameKind::Native => {
+                let mut frame = CallFrame::native(
+                    s(entry.ip),
+                    s(entry.key.file),
+                    s(entry.key.func),
+                    entry.key.lineno,
+                );
+                if let (CallFrame::CFrame { raw_func, .. }, Some(raw)) =
+                    (&mut frame, entry.raw_func)
+                {
+                    *raw_func = Some(s(raw));
+                }
+                frame
+            }
+        }
+    }
+
+    pub fn stack(&self, ids: &[FrameId]) -> Vec<CallFrame> {
+        ids.iter().map(|id| self.frame(*id)).collect()
+    }
+
+    fn string(&mut self, s: &str) -> StrId {
+        if let Some(id) = self.string_index.get(s) {
+            return *id;
+        }
+        let id = StrId(self.strings.len() as u32);
+        let s: Arc<str> = Arc::from(s);
+        self.strings.push(Arc::clone(&s));
+        self.string_index.insert(s, id);
+        id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intern_deduplicates() {
+        let mut table = FrameTable::new();
+        let a = table.intern(&CallFrame::native("0x1", "lib.c", "leaf", 10));
+        let b = table.intern(&CallFrame::native("0x2", "lib.c", "leaf", 10));
+        let c = table.intern(&CallFrame::native("0x1", "lib.c", "leaf", 11));
+        let py = table.intern(&CallFrame::python("0x1", "lib.c", "leaf", 10));
+        assert_eq!(a, b);
+        assert_ne!(a, c);
+        assert_ne!(a, py);
+        assert_eq!(table.len(), 3);
+        // "lib.c", "leaf" and "0x1" are stored once.
+        assert_eq!(table.strings.len(), 3);
+        assert_eq!(table.func(c), "leaf");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut table = FrameTable::new();
+        let mut native = CallFrame::native("0x10", "lib.c", "f", 3);
+        if let CallFrame::CFrame { raw_func, .. } = &mut native {
+            *raw_func = Some("_Z1fv".to_string());
+        }
+        let stack = vec![
+            CallFrame::synthetic("[GIL wait]", "gil"),
+            native,
+            CallFrame::python("0x20", "a.py", "g", 7),
+            CallFrame::gpu("kernel", None, 0),
+            CallFrame::interpreter("julia", "a.jl", "g", 7),
+        ];
+        let ids = table.intern_stack(&stack);
+        assert_eq!(table.stack(&ids), stack);
+    }
+}
@@ -0,0 +1,324 @@
+//! GIL contention: spotting threads blocked on acquiring the GIL.
+//!
+//! Detection works on the native frames of a stack, so it needs no interpreter state: a
+//! thread waits for the GIL when it is inside CPython's `take_gil`, or when a blocking
+//! primitive (condition variable, futex, mutex) sits above one of the public acquisition
+//! entry points such as `PyEval_RestoreThread` -- which covers builds where the static
+//! `take_gil` symbol was stripped.
+//!
+//! `GilTimeline` turns a series of snapshots into per-thread hold and wait intervals, each
+//! with the merged stack the thread had when it started holding or waiting; see
+//! `RemoteProcess::trace_gil` and `output::chrome_trace::write_gil_trace`.
+
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::thread_stack::{ThreadId, ThreadStack};
+use crate::CallFrame;
+
+/// Label of the marker inserted at the leaf of waiting threads.
+pub const GIL_WAIT_LABEL: &str = "[GIL wait]";
+/// Category of GIL markers.
+pub const GIL_CATEGORY: &str = "gil";
+
+/// Public API functions that block until the calling thread owns the GIL.
+const ACQUIRE_FUNCTIONS: &[&str] = &[
+    "PyEval_RestoreThread",
+    "PyEval_AcquireThread",
+    "PyEval_AcquireLock",
+    "PyGILState_Ensure",
+];
+
+/// Blocking primitives a waiting thread sleeps in.
+const WAIT_FUNCTIONS: &[&str] = &[
+    "pthread_cond_wait",
+    "pthread_cond_timedwait",
+    "pthread_cond_clockwait",
+    "__pthread_cond_wait",
+    "__pthread_cond_timedwait",
+    "__pthread_cond_clockwait64",
+    "__futex_abstimed_wait_common",
+    "__futex_abstimed_wait_common64",
+    "__futex_abstimed_wait_cancelable64",
+    "pthread_mutex_lock",
+    "___pthread_mutex_lock",
+    "__lll_lock_wait",
+    "_PySemaphore_Wait",
+    "_PyMutex_LockTimed",
+    "PyCOND_TIMEDWAIT",
+    "syscall",
+];
+
+/// Whether the native frames of `frames` (leaf first) show a thread waiting for the GIL.
+pub fn is_waiting_for_gil(frames: &[CallFrame]) -> bool {
+    let mut waiting = false;
+    for frame in frames {
+        let CallFrame::CFrame { func, .. } = frame else {
+            continue;
+        };
+        let name = base_name(func);
+        if name == "take_gil" {
+            return true;
+        }
+        if WAIT_FUNCTIONS.contains(&name) {
+            waiting = true;
+        } else if waiting && ACQUIRE_FUNCTIONS.contains(&name) {
+            return true;
+        }
+    }
+    false
+}
+
+/// `take_gil.lto_priv.0` and `take_gil.constprop.0` are still `take_gil`.
+fn base_name(func: &str) -> &str {
+    func.split('.').next().unwrap_or(func)
+}
+
+impl ThreadStack {
+    /// Insert a `[GIL wait]` synthetic frame at the leaf when the thread waits for the GIL.
+    ///
+    /// The known GIL holder is never marked. Returns whether the marker is present.
+    pub fn annotate_gil_wait(&mut self) -> bool {
+        if self.is_gil_holder || !is_waiting_for_gil(&self.frames) {
+            return false;
+        }
+        let marked = matches!(
+            self.frames.first(),
+            Some(CallFrame::Synthetic { label, .. }) if label == GIL_WAIT_LABEL
+        );
+        if !marked {
+            self.frames
+                .insert(0, CallFrame::synthetic(GIL_WAIT_LABEL, GIL_CATEGORY));
+        }
+        true
+    }
+}
+
+/// What a thread was doing with the GIL during an interval.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GilEventKind {
+    Hold,
+    Wait,
+}
+
+impl GilEventKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            GilEventKind::Hold => "hold",
+            GilEventKind::Wait => "wait",
+        }
+    }
+
+    /// How `stack` relates to the GIL, `None` when it neither holds nor waits for it.
+    pub fn of(stack: &ThreadStack) -> Option<GilEventKind> {
+        if stack.is_gil_holder {
+            Some(GilEventKind::Hold)
+        } else if is_waiting_for_gil(&stack.frames) {
+            Some(GilEventKind::Wait)
+        } else {
+            None
+        }
+    }
+}
+
+/// A thread holding or waiting for the GIL from `start_ns` to `end_ns`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct GilInterval {
+    pub tid: ThreadId,
+    pub thread_name: String,
+    pub kind: GilEventKind,
+    pub start_ns: u64,
+    pub end_ns: u64,
+    /// Merged frames, leaf first, when the interval started: where the GIL was acquired
+    /// or waited for.
+    pub frames: Vec<CallFrame>,
+}
+
+impl GilInterval {
+    pub fn duration_ns(&self) -> u64 {
+        self.end_ns - self.start_ns
+    }
+}
+
+/// Builds GIL intervals from successive snapshots of every thread.
+///
+/// A thread's interval lasts from the first snapshot showing it in a state to the first
+/// one that does not, so intervals are only as precise as the snapshot rate and a hold
+/// shorter than the gap between two snapshots can go unseen.
+#[derive(Debug, Default)]
+pub struct GilTimeline {
+    open: HashMap<ThreadId, GilInterval>,
+    closed: Vec<GilInterval>,
+}
+
+impl GilTimeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the threads of a snapshot taken at `timestamp_ns`. Threads missing from it
+    /// have exited and their intervals end.
+    pub fn observe(&mut self, timestamp_ns: u64, stacks: &[ThreadStack]) {
+        let mut seen = HashSet::with_capacity(stacks.len());
+        for stack in stacks.iter().filter(|s| !s.greenlet) {
+            seen.insert(stack.tid);
+            let kind = GilEventKind::of(stack);
+            if self.open.get(&stack.tid).map(|open| open.kind) == kind {
+                continue;
+            }
+            self.close(stack.tid, timestamp_ns);
+            if let Some(kind) = kind {
+                self.open.insert(
+                    stack.tid,
+                    GilInterval {
+                        tid: stack.tid,
+                        thread_name: stack.name.clone(),
+                        kind,
+                        start_ns: timestamp_ns,
+                        end_ns: timestamp_ns,
+                        frames: stack.frames.clone(),
+                    },
+                );
+            }
+        }
+        let gone: Vec<ThreadId> = self
+            .open
+            .keys()
+            .filter(|tid| !seen.contains(*tid))
+            .copied()
+            .collect();
+        for tid in gone {
+            self.close(tid, timestamp_ns);
+        }
+    }
+
+    /// End the intervals still open at `timestamp_ns` and return all of them, ordered by
+    /// start time and tid.
+    pub fn finish(mut self, timestamp_ns: u64) -> Vec<GilInterval> {
+        let tids: Vec<ThreadId> = self.open.keys().copied().collect();
+        for tid in tids {
+            self.close(tid, timestamp_ns);
+        }
+        self.closed.sort_by_key(|i| (i.start_ns, i.tid));
+        self.closed
+    }
+
+    fn close(&mut self, tid: ThreadId, timestamp_ns: u64) {
+        if let Some(mut interval) = self.open.remove(&tid) {
+            interval.end_ns = timestamp_ns;
+            self.closed.push(interval);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::thread_stack::ThreadState;
+
+    fn cframe(name: &str) -> CallFrame {
+        CallFrame::native("0x0", "", name, 0)
+    }
+
+    fn thread(frames: Vec<CallFrame>, is_gil_holder: bool) -> ThreadStack {
+        ThreadStack {
+            tid: 1,
+            name: String::new(),
+            os_state: ThreadState::Sleeping,
+            is_gil_holder,
+            greenlet: false,
+            interpreter_id: None,
+            frames,
+        }
+    }
+
+    #[test]
+    fn test_is_waiting_for_gil() {
+        let take_gil = vec![
+            cframe("__futex_abstimed_wait_common"),
+            cframe("take_gil.lto_priv.0"),
+            cframe("PyEval_RestoreThread"),
+        ];
+        assert!(is_waiting_for_gil(&take_gil));
+
+        // Stripped take_gil: a wait primitive above an acquisition entry point.
+        let stripped = vec![
+            cframe("pthread_cond_timedwait"),
+            cframe("??"),
+            cframe("PyGILState_Ensure"),
+        ];
+        assert!(is_waiting_for_gil(&stripped));
+
+        // Releasing the GIL around a blocking read is not waiting for it.
+        let io = vec![
+            cframe("read"),
+            cframe("_Py_read"),
+            cframe("PyEval_RestoreThread"),
+        ];
+        assert!(!is_waiting_for_gil(&io));
+        assert!(!is_waiting_for_gil(&[cframe("pthread_cond_wait")]));
+    }
+
+    #[test]
+    fn test_annotate_gil_wait() {
+        let frames = vec![cframe("take_gil"), CallFrame::python("0x1", "a.py", "f", 1)];
+        let mut waiting = thread(frames.clone(), false);
+        assert!(waiting.annotate_gil_wait());
+        assert!(waiting.annotate_gil_wait());
+        assert_eq!(waiting.frames.len(), 3);
+        assert_eq!(
+            waiting.frames[0],
+            CallFrame::synthetic(GIL_WAIT_LABEL, GIL_CATEGORY)
+        );
+
+        let mut holder = thread(frames.clone(), true);
+        assert!(!holder.annotate_gil_wait());
+        assert_eq!(holder.frames, frames);
+    }
+
+    #[test]
+    fn test_gil_timeline() {
+        let on = |tid, holder, waiting: bool, func: &str| ThreadStack {
+            tid,
+            frames: if waiting {
+                vec![cframe("take_gil"), cframe(func)]
+            } else {
+                vec![cframe(func)]
+            },
+            ..thread(Vec::new(), holder)
+        };
+        let mut timeline = GilTimeline::new();
+        timeline.observe(10, &[on(1, true, false, "a"), on(2, false, true, "b")]);
+        timeline.observe(20, &[on(1, true, false, "a2"), on(2, false, true, "b")]);
+        // The GIL moves to thread 2, thread 1 goes back to waiting.
+        timeline.observe(30, &[on(1, false, true, "c"), on(2, true, false, "d")]);
+        // Thread 1 exits.
+        timeline.observe(40, &[on(2, true, false, "d")]);
+        let intervals = timeline.finish(50);
+
+        let summary: Vec<_> = intervals
+            .iter()
+            .map(|i| {
+                (
+                    i.tid,
+                    i.kind,
+                    i.start_ns,
+                    i.end_ns,
+                    i.frames.last().unwrap().func(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (1, GilEventKind::Hold, 10, 30, "a"),
+                (2, GilEventKind::Wait, 10, 30, "b"),
+                (1, GilEventKind::Wait, 30, 40, "c"),
+                (2, GilEventKind::Hold, 30, 50, "d"),
+            ]
+        );
+        assert_eq!(intervals[0].duration_ns(), 20);
+    }
+}
@@ -0,0 +1,585 @@
+//! gRPC service for central collectors (feature `grpc`), on tonic.
+//!
+//! `mixed_stack_tracer.v1.Tracer` (see `proto/mixed_stack_tracer.proto`):
+//!
+//! | rpc | response |
+//! |-----|----------|
+//! | `StreamSamples` | merged stacks sampled from now on, one `StackTrace` per sample, until cancelled |
+//! | `DumpNow` | current merged stacks of all threads |
+//! | `GetStatus` | pid, version, sampling state and counters |
+//!
+//! A collector subscribes to many processes, each running a `GrpcServer`, as the probing
+//! server does with its agents. Subscribers share one `Sampler`, started by the first and
+//! stopped after the last one leaves, at the frequency the first asked for; a subscriber
+//! too slow to keep up loses samples (`samples_dropped`) rather than slowing the others.
+//!
+//! As in `proto`, messages and service glue are written by hand, so building needs no
+//! `protoc`. `TracerClient` is the matching client.
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use prost::Message;
+use tokio::sync::{broadcast, oneshot};
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+use tokio_stream::wrappers::{BroadcastStream, TcpListenerStream};
+use tokio_stream::Stream;
+use tonic::body::Body;
+use tonic::codegen::{http, BoxFuture, Service};
+use tonic::server::{Grpc, NamedService, ServerStreamingService, UnaryService};
+use tonic::{Request, Response, Status};
+use tonic_prost::ProstCodec;
+
+use crate::events::{self, DumpTrigger};
+use crate::fork::BackgroundThread;
+use crate::profile::TimedSample;
+use crate::proto;
+use crate::sampler::{monotonic_ns, PythonStacksProvider, Sampler};
+use crate::stack_trace::truncated_count;
+use crate::stack_tracer::SignalTracer;
+use crate::unwind::UnwindStrategy;
+
+const DEFAULT_HZ: u32 = 99;
+/// Samples buffered per subscriber before the oldest are dropped.
+const SUBSCRIBER_BUFFER: usize = 1024;
+
+const SERVICE: &str = "mixed_stack_tracer.v1.Tracer";
+const STREAM_SAMPLES: &str = "/mixed_stack_tracer.v1.Tracer/StreamSamples";
+const DUMP_NOW: &str = "/mixed_stack_tracer.v1.Tracer/DumpNow";
+const GET_STATUS: &str = "/mixed_stack_tracer.v1.Tracer/GetStatus";
+
+#[derive(Clone, PartialEq, Message)]
+pub struct StreamSamplesRequest {
+    /// Sampling frequency, 0 for the default (99 Hz).
+    #[prost(uint32, tag = "1")]
+    pub frequency_hz: u32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct DumpNowRequest {}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ThreadDump {
+    #[prost(int32, tag = "1")]
+    pub tid: i32,
+    #[prost(string, tag = "2")]
+    pub name: String,
+    /// `ThreadState` in snake case, e.g. `sleeping`.
+    #[prost(string, tag = "3")]
+    pub state: String,
+    #[prost(bool, tag = "4")]
+    pub gil_holder: bool,
+    #[prost(message, repeated, tag = "5")]
+    pub frames: Vec<proto::CallFrame>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct DumpNowResponse {
+    #[prost(uint32, tag = "1")]
+    pub pid: u32,
+    #[prost(uint64, tag = "2")]
+    pub timestamp_unix_ns: u64,
+    #[prost(message, repeated, tag = "3")]
+    pub threads: Vec<ThreadDump>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct GetStatusRequest {}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct GetStatusResponse {
+    #[prost(uint32, tag = "1")]
+    pub pid: u32,
+    /// Version of this crate.
+    #[prost(string, tag = "2")]
+    pub version: String,
+    #[prost(bool, tag = "3")]
+    pub sampling: bool,
+    /// Frequency of the running sampler, 0 when not sampling.
+    #[prost(uint32, tag = "4")]
+    pub frequency_hz: u32,
+    #[prost(uint32, tag = "5")]
+    pub subscribers: u32,
+    /// Samples delivered, summed over subscribers.
+    #[prost(uint64, tag = "6")]
+    pub samples_sent: u64,
+    /// Samples lost by subscribers that fell behind.
+    #[prost(uint64, tag = "7")]
+    pub samples_dropped: u64,
+}
+
+type SharedProvider = Arc<Mutex<PythonStacksProvider>>;
+
+/// A running gRPC server, shut down when dropped.
+pub struct GrpcServer {
+    addr: SocketAddr,
+    shutdown: Option<oneshot::Sender<()>>,
+    thread: Option<BackgroundThread<()>>,
+}
+
+impl GrpcServer {
+    /// Serve native stacks on `addr`, e.g. `0.0.0.0:50051` (port 0 picks a free one).
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<GrpcServer> {
+        Self::bind_inner(addr, None)
+    }
+
+    /// Serve stacks merged with the Python stacks from `provider`.
+    pub fn bind_with_python(
+        addr: impl ToSocketAddrs,
+        provider: PythonStacksProvider,
+    ) -> io::Result<GrpcServer> {
+        Self::bind_inner(addr, Some(Arc::new(Mutex::new(provider))))
+    }
+
+    fn bind_inner(
+        addr: impl ToSocketAddrs,
+        provider: Option<SharedProvider>,
+    ) -> io::Result<GrpcServer> {
+        let listener = std::net::TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let addr = listener.local_addr()?;
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let service = TracerService {
+            hub: Arc::new(Hub::new(provider)),
+        };
+        let (shutdown, signal) = oneshot::channel::<()>();
+        let thread = thread::Builder::new()
+            .name("mst-grpc".to_string())
+            .spawn(move || {
+                runtime.block_on(async move {
+                    let listener = match tokio::net::TcpListener::from_std(listener) {
+                        Ok(listener) => listener,
+                        Err(_) => return,
+                    };
+                    let _ = tonic::transport::Server::builder()
+                        .add_service(service)
+                        .serve_with_incoming_shutdown(TcpListenerStream::new(listener), async {
+                            let _ = signal.await;
+                        })
+                        .await;
+                })
+            })?;
+        Ok(GrpcServer {
+            addr,
+            shutdown: Some(shutdown),
+            thread: Some(BackgroundThread::new(thread)),
+        })
+    }
+
+    /// Address the server listens on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for GrpcServer {
+    fn drop(&mut self) {
+        if self
+            .thread
+            .as_ref()
+            .is_some_and(BackgroundThread::is_forked)
+        {
+            // In a forked child: the server and its runtime stayed in the parent.
+            return;
+        }
+        if let Some(shutdown) = self.shutdown.take() {
+            let _ = shutdown.send(());
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// State shared by the calls of one server.
+struct Hub {
+    provider: Option<SharedProvider>,
+    samples: broadcast::Sender<proto::StackTrace>,
+    /// The shared sampler and its frequency, while anyone subscribes.
+    sampling: Mutex<Option<(Sampler, u32)>>,
+    subscribers: AtomicU32,
+    sent: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl Hub {
+    fn new(provider: Option<SharedProvider>) -> Hub {
+        Hub {
+            provider,
+            samples: broadcast::channel(SUBSCRIBER_BUFFER).0,
+            sampling: Mutex::new(None),
+            subscribers: AtomicU32::new(0),
+            sent: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    fn python_stacks(&self) -> Option<PythonStacksProvider> {
+        let provider = Arc::clone(self.provider.as_ref()?);
+        Some(Box::new(move || {
+            (provider.lock().unwrap_or_else(|e| e.into_inner()))()
+        }))
+    }
+
+    fn subscribe(hub: &Arc<Hub>, frequency_hz: u32) -> Result<SampleStream, Status> {
+        let mut sampling = hub.sampling.lock().unwrap_or_else(|e| e.into_inner());
+        if sampling.is_none() {
+            let frequency_hz = if frequency_hz == 0 {
+                DEFAULT_HZ
+            } else {
+                frequency_hz
+            };
+            let samples = hub.samples.clone();
+            let source = if hub.provider.is_some() {
+                proto::CaptureSource::Merged
+            } else {
+                proto::CaptureSource::Signal
+            };
+            let pid = std::process::id();
+            let epoch_offset = unix_ns().saturating_sub(monotonic_ns());
+            let observer = Box::new(move |sample: &TimedSample| {
+                let truncated_frames = sample.frames.last().and_then(truncated_count);
+                let _ = samples.send(proto::StackTrace {
+                    frames: sample.frames.iter().cloned().map(Into::into).collect(),
+                    timestamp_unix_ns: Some(epoch_offset + sample.timestamp_ns),
+                    pid: Some(pid),
+                    tid: Some(sample.tid),
+                    truncated: truncated_frames.is_some(),
+                    truncated_frames: truncated_frames.unwrap_or(0) as u32,
+                    source: source as i32,
+                    cpu: sample.cpu,
+                    state: proto::SampleState::from(sample.state) as i32,
+                    weight_ns: sample.weight_ns,
+                });
+            });
+            let sampler = Sampler::start_observed(
+                frequency_hz,
+                UnwindStrategy::Dwarf,
+                hub.python_stacks(),
+                Some(observer),
+            )
+            .map_err(|err| match err.kind() {
+                io::ErrorKind::AlreadyExists => {
+                    Status::failed_precondition("a sampler is already running")
+                }
+                io::ErrorKind::InvalidInput => Status::invalid_argument(err.to_string()),
+                _ => Status::internal(err.to_string()),
+            })?;
+            *sampling = Some((sampler, frequency_hz));
+        }
+        hub.subscribers.fetch_add(1, Ordering::SeqCst);
+        Ok(SampleStream {
+            samples: BroadcastStream::new(hub.samples.subscribe()),
+            hub: Arc::clone(hub),
+        })
+    }
+
+    fn unsubscribe(&self) {
+        let mut sampling = self.sampling.lock().unwrap_or_else(|e| e.into_inner());
+        if self.subscribers.fetch_sub(1, Ordering::SeqCst) == 1 {
+            // Joins the collector, which takes at most one drain interval.
+            if let Some((sampler, _)) = sampling.take() {
+                sampler.stop();
+            }
+        }
+    }
+
+    fn dump(&self) -> io::Result<DumpNowResponse> {
+        let python = self
+            .provider
+            .as_ref()
+            .map(|p| (p.lock().unwrap_or_else(|e| e.into_inner()))())
+            .unwrap_or_default();
+        let stacks = SignalTracer::capture_all_threads_with_python_stacks(python, None)?;
+        events::stacks_dumped(DumpTrigger::Grpc, stacks.len());
+        let threads = stacks
+            .into_iter()
+            .map(|stack| ThreadDump {
+                tid: stack.tid,
+                name: stack.name,
+                state: serde_json::to_value(stack.os_state)
+                    .ok()
+                    .and_then(|v| v.as_str().map(String::from))
+                    .unwrap_or_default(),
+                gil_holder: stack.is_gil_holder,
+                frames: stack.frames.into_iter().map(Into::into).collect(),
+            })
+            .collect();
+        Ok(DumpNowResponse {
+            pid: std::process::id(),
+            timestamp_unix_ns: unix_ns(),
+            threads,
+        })
+    }
+
+    fn status(&self) -> GetStatusResponse {
+        let sampling = self.sampling.lock().unwrap_or_else(|e| e.into_inner());
+        GetStatusResponse {
+            pid: std::process::id(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            sampling: sampling.is_some(),
+            frequency_hz: sampling.as_ref().map_or(0, |(_, hz)| *hz),
+            subscribers: self.subscribers.load(Ordering::SeqCst),
+            samples_sent: self.sent.load(Ordering::Relaxed),
+            samples_dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+fn unix_ns() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+/// The samples of one `StreamSamples` call; unsubscribes when the call ends.
+struct SampleStream {
+    samples: BroadcastStream<proto::StackTrace>,
+    hub: Arc<Hub>,
+}
+
+impl Stream for SampleStream {
+    type Item = Result<proto::StackTrace, Status>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match Pin::new(&mut self.samples).poll_next(cx) {
+                Poll::Ready(Some(Ok(sample))) => {
+                    self.hub.sent.fetch_add(1, Ordering::Relaxed);
+                    return Poll::Ready(Some(Ok(sample)));
+                }
+                Poll::Ready(Some(Err(BroadcastStreamRecvError::Lagged(n)))) => {
+                    self.hub.dropped.fetch_add(n, Ordering::Relaxed);
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl Drop for SampleStream {
+    fn drop(&mut self) {
+        self.hub.unsubscribe();
+    }
+}
+
+/// The `Tracer` service, routing calls by path.
+#[derive(Clone)]
+struct TracerService {
+    hub: Arc<Hub>,
+}
+
+impl NamedService for TracerService {
+    const NAME: &'static str = SERVICE;
+}
+
+struct StreamSamples(Arc<Hub>);
+
+impl ServerStreamingService<StreamSamplesRequest> for StreamSamples {
+    type Response = proto::StackTrace;
+    type ResponseStream = SampleStream;
+    type Future = BoxFuture<Response<SampleStream>, Status>;
+
+    fn call(&mut self, request: Request<StreamSamplesRequest>) -> Self::Future {
+        let result = Hub::subscribe(&self.0, request.get_ref().frequency_hz).map(Response::new);
+        Box::pin(async move { result })
+    }
+}
+
+struct DumpNow(Arc<Hub>);
+
+impl UnaryService<DumpNowRequest> for DumpNow {
+    type Response = DumpNowResponse;
+    type Future = BoxFuture<Response<DumpNowResponse>, Status>;
+
+    fn call(&mut self, _request: Request<DumpNowRequest>) -> Self::Future {
+        let hub = Arc::clone(&self.0);
+        Box::pin(async move {
+            // Capturing signals every thread and waits for them.
+            tokio::task::spawn_blocking(move || hub.dump())
+                .await
+                .map_err(|err| Status::internal(err.to_string()))?
+                .map(Response::new)
+                .map_err(|err| Status::internal(err.to_string()))
+        })
+    }
+}
+
+struct GetStatus(Arc<Hub>);
+
+impl UnaryService<GetStatusRequest> for GetStatus {
+    type Response = GetStatusResponse;
+    type Future = BoxFuture<Response<GetStatusResponse>, Status>;
+
+    fn call(&mut self, _request: Request<GetStatusRequest>) -> Self::Future {
+        let status = self.0.status();
+        Box::pin(async move { Ok(Response::new(status)) })
+    }
+}
+
+impl Service<http::Request<Body>> for TracerService {
+    type Response = http::Response<Body>;
+    type Error = std::convert::Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: http::Request<Body>) -> Self::Future {
+        let hub = Arc::clone(&self.hub);
+        match request.uri().path() {
+            STREAM_SAMPLES => Box::pin(async move {
+                let mut grpc = Grpc::new(ProstCodec::new());
+                Ok(grpc.server_streaming(StreamSamples(hub), request).await)
+            }),
+            DUMP_NOW => Box::pin(async move {
+                let mut grpc = Grpc::new(ProstCodec::new());
+                Ok(grpc.unary(DumpNow(hub), request).await)
+            }),
+            GET_STATUS => Box::pin(async move {
+                let mut grpc = Grpc::new(ProstCodec::new());
+                Ok(grpc.unary(GetStatus(hub), request).await)
+            }),
+            _ => Box::pin(async move { Ok(Status::unimplemented("").into_http()) }),
+        }
+    }
+}
+
+/// Client of the `Tracer` service, for collectors written in Rust.
+#[derive(Clone)]
+pub struct TracerClient {
+    inner: tonic::client::Grpc<tonic::transport::Channel>,
+}
+
+impl TracerClient {
+    /// Connect to `endpoint`, e.g. `http://10.0.0.5:50051`.
+    pub async fn connect(endpoint: impl Into<String>) -> Result<Self, tonic::transport::Error> {
+        let channel = tonic::transport::Endpoint::from_shared(endpoint.into())?
+            .connect()
+            .await?;
+        Ok(TracerClient {
+            inner: tonic::client::Grpc::new(channel),
+        })
+    }
+
+    /// Subscribe to samples; 0 asks for the default frequency.
+    pub async fn stream_samples(
+        &mut self,
+        frequency_hz: u32,
+    ) -> Result<tonic::Streaming<proto::StackTrace>, Status> {
+        self.ready().await?;
+        let request = Request::new(StreamSamplesRequest { frequency_hz });
+        let path = http::uri::PathAndQuery::from_static(STREAM_SAMPLES);
+        let response = self
+            .inner
+            .server_streaming(request, path, ProstCodec::new())
+            .await?;
+        Ok(response.into_inner())
+    }
+
+    /// Current stacks of all threads of the process.
+    pub async fn dump_now(&mut self) -> Result<DumpNowResponse, Status> {
+        self.ready().await?;
+        let path = http::uri::PathAndQuery::from_static(DUMP_NOW);
+        let response = self
+            .inner
+            .unary(Request::new(DumpNowRequest {}), path, ProstCodec::new())
+            .await?;
+        Ok(response.into_inner())
+    }
+
+    pub async fn get_status(&mut self) -> Result<GetStatusResponse, Status> {
+        self.ready().await?;
+        let path = http::uri::PathAndQuery::from_static(GET_STATUS);
+        let response = self
+            .inner
+            .unary(Request::new(GetStatusRequest {}), path, ProstCodec::new())
+            .await?;
+        Ok(response.into_inner())
+    }
+
+    async fn ready(&mut self) -> Result<(), Status> {
+        self.inner
+            .ready()
+            .await
+            .map_err(|err| Status::unavailable(err.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_stream::StreamExt;
+
+    #[test]
+    fn test_service() {
+        let _lock = crate::sampler::TEST_SAMPLER_LOCK
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let server = GrpcServer::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("http://{}", server.local_addr());
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let mut client = TracerClient::connect(endpoint).await.unwrap();
+            let status = client.get_status().await.unwrap();
+            assert_eq!(status.pid, std::process::id());
+            assert!(!status.sampling);
+
+            let dump = client.dump_now().await.unwrap();
+            assert!(dump.threads.iter().any(|t| t.name == "mst-grpc"));
+            assert!(dump.threads.iter().all(|t| !t.state.is_empty()));
+
+            let mut samples = client.stream_samples(500).await.unwrap();
+            let status = client.get_status().await.unwrap();
+            assert!(status.sampling);
+            assert_eq!((status.frequency_hz, status.subscribers), (500, 1));
+
+            // The spinning thread keeps SIGPROF ticking while we wait.
+            let stop = Arc::new(std::sync::atomic::AtomicBool::new(false));
+            let spinner = {
+                let stop = Arc::clone(&stop);
+                thread::spawn(move || {
+                    while !stop.load(Ordering::Relaxed) {
+                        std::hint::black_box(0u64);
+                    }
+                })
+            };
+            let sample = samples.next().await.unwrap().unwrap();
+            stop.store(true, Ordering::Relaxed);
+            spinner.join().unwrap();
+            assert_eq!(sample.pid, Some(std::process::id()));
+            assert!(sample.tid.is_some() && !sample.frames.is_empty());
+            assert!(sample.timestamp_unix_ns.unwrap() <= unix_ns());
+            assert!(sample.weight_ns > 0 && sample.cpu.is_some());
+
+            // Leaving stops the sampler.
+            drop(samples);
+            let mut status = client.get_status().await.unwrap();
+            for _ in 0..100 {
+                if !status.sampling {
+                    break;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                status = client.get_status().await.unwrap();
+            }
+            // Sampling again works once the last subscriber left.
+            assert!(Sampler::start(100).is_ok());
+            assert_eq!((status.sampling, status.subscribers), (false, 0));
+            assert!(status.samples_sent >= 1);
+        });
+    }
+}
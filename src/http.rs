@@ -0,0 +1,264 @@
+//! Embedded HTTP debug endpoints (feature `http`), after Go's `net/http/pprof`.
+//!
+//! | path | response |
+//! |------|----------|
+//! | `/debug/stacks` | current merged stacks of all threads as text, `?format=json` for JSON |
+//! | `/debug/flamegraph?seconds=N` | folded stacks of an N second profile (default 30) |
+//! | `/debug/pprof/profile?seconds=N` | gzipped pprof protobuf of an N second profile |
+//!
+//! Profiles also take `hz` (default 99). Only one profile can run at a time per process;
+//! concurrent requests get `409 Conflict`. Each request is served on its own thread.
+
+use std::io::{self, Cursor};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use tiny_http::{Header, Request, Response, Server};
+
+use crate::events::{self, DumpTrigger};
+use crate::fork::BackgroundThread;
+use crate::output::folded::{self, FoldedOptions};
+use crate::output::{pprof, text};
+use crate::profile::Profile;
+use crate::sampler::{PythonStacksProvider, Sampler};
+use crate::stack_tracer::SignalTracer;
+
+const DEFAULT_SECONDS: f64 = 30.0;
+const MAX_SECONDS: f64 = 600.0;
+const DEFAULT_HZ: u32 = 99;
+
+type SharedProvider = Arc<Mutex<PythonStacksProvider>>;
+
+/// A running debug server, shut down when dropped.
+pub struct DebugServer {
+    server: Arc<Server>,
+    addr: SocketAddr,
+    thread: Option<BackgroundThread<()>>,
+}
+
+impl DebugServer {
+    /// Serve native stacks on `addr`, e.g. `127.0.0.1:6060` (port 0 picks a free one).
+    pub fn bind(addr: impl ToSocketAddrs) -> io::Result<DebugServer> {
+        Self::bind_inner(addr, None)
+    }
+
+    /// Serve stacks merged with the Python stacks from `provider`.
+    pub fn bind_with_python(
+        addr: impl ToSocketAddrs,
+        provider: PythonStacksProvider,
+    ) -> io::Result<DebugServer> {
+        Self::bind_inner(addr, Some(Arc::new(Mutex::new(provider))))
+    }
+
+    fn bind_inner(
+        addr: impl ToSocketAddrs,
+        provider: Option<SharedProvider>,
+    ) -> io::Result<DebugServer> {
+        let server = Server::http(addr).map_err(io::Error::other)?;
+        let addr = server.server_addr().to_ip().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "not an IP listen address")
+        })?;
+        let server = Arc::new(server);
+        let thread = {
+            let server = Arc::clone(&server);
+            thread::Builder::new()
+                .name("mst-http".to_string())
+                .spawn(move || {
+                    for request in server.incoming_requests() {
+                        let provider = provider.clone();
+                        let _ = thread::Builder::new()
+                            .name("mst-http-req".to_string())
+                            .spawn(move || handle(request, provider.as_ref()));
+                    }
+                })?
+        };
+        Ok(DebugServer {
+            server,
+            addr,
+            thread: Some(BackgroundThread::new(thread)),
+        })
+    }
+
+    /// Address the server listens on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for DebugServer {
+    fn drop(&mut self) {
+        if self
+            .thread
+            .as_ref()
+            .is_some_and(BackgroundThread::is_forked)
+        {
+            // In a forked child: the server thread stayed in the parent.
+            return;
+        }
+        self.server.unblock();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+type HttpResponse = Response<Cursor<Vec<u8>>>;
+
+fn handle(request: Request, provider: Option<&SharedProvider>) {
+    let (path, query) = request.url().split_once('?').unwrap_or((request.url(), ""));
+    let response = match path {
+        "/debug/stacks" => stacks(provider, param(query, "format") == Some("json")),
+        "/debug/flamegraph" => profile_params(query).and_then(|(duration, hz)| {
+            let profile = record(provider, duration, hz)?;
+            let body = folded::profile_to_string(&profile, &FoldedOptions::new());
+            Ok(respond(200, "text/plain; charset=utf-8", body.into_bytes()))
+        }),
+        "/debug/pprof/profile" => profile_params(query).and_then(|(duration, hz)| {
+            let profile = record(provider, duration, hz)?;
+            let mut body = Vec::new();
+            pprof::write_profile(&mut body, &profile).map_err(server_error)?;
+            Ok(respond(200, "application/octet-stream", body))
+        }),
+        _ => Err(respond(404, "text/plain", b"not found\n".to_vec())),
+    };
+    let _ = request.respond(response.unwrap_or_else(|error| error));
+}
+
+fn stacks(provider: Option<&SharedProvider>, json: bool) -> Result<HttpResponse, HttpResponse> {
+    let python = provider
+        .map(|p| (p.lock().unwrap_or_else(|e| e.into_inner()))())
+        .unwrap_or_default();
+    let stacks = SignalTracer::capture_all_threads_with_python_stacks(python, None)
+        .map_err(|e| server_error(e.into()))?;
+    events::stacks_dumped(DumpTrigger::Http, stacks.len());
+    if json {
+        let body = serde_json::to_vec(&stacks).map_err(|e| server_error(e.into()))?;
+        Ok(respond(200, "application/json", body))
+    } else {
+        let mut body = Vec::new();
+        text::write_thread_stacks(&mut body, &stacks).map_err(server_error)?;
+        Ok(respond(200, "text/plain; charset=utf-8", body))
+    }
+}
+
+fn record(
+    provider: Option<&SharedProvider>,
+    duration: Duration,
+    hz: u32,
+) -> Result<Profile, HttpResponse> {
+    let sampler = match provider {
+        Some(provider) => {
+            let provider = Arc::clone(provider);
+            Sampler::start_with_python(
+                hz,
+                Box::new(move || (provider.lock().unwrap_or_else(|e| e.into_inner()))()),
+            )
+        }
+        None => Sampler::start(hz),
+    };
+    let sampler = sampler.map_err(|err| match err.kind() {
+        io::ErrorKind::AlreadyExists => {
+            respond(409, "text/plain", b"profile in progress\n".to_vec())
+        }
+        _ => server_error(err),
+    })?;
+    thread::sleep(duration);
+    Ok(sampler.stop())
+}
+
+fn profile_params(query: &str) -> Result<(Duration, u32), HttpResponse> {
+    let bad_request = |message: String| respond(400, "text/plain", message.into_bytes());
+    let seconds = match param(query, "seconds") {
+        None => DEFAULT_SECONDS,
+        Some(value) => value
+            .parse::<f64>()
+            .ok()
+            .filter(|s| *s > 0.0 && *s <= MAX_SECONDS)
+            .ok_or_else(|| bad_request(format!("invalid seconds `{}`\n", value)))?,
+    };
+    let hz = match param(query, "hz") {
+        None => DEFAULT_HZ,
+        Some(value) => value
+            .parse::<u32>()
+            .ok()
+            .filter(|hz| *hz > 0)
+            .ok_or_else(|| bad_request(format!("invalid hz `{}`\n", value)))?,
+    };
+    Ok((Duration::from_secs_f64(seconds), hz))
+}
+
+fn param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+fn respond(status: u16, content_type: &str, body: Vec<u8>) -> HttpResponse {
+    let header = Header::from_bytes("Content-Type", content_type).expect("valid header");
+    Response::from_data(body)
+        .with_status_code(status)
+        .with_header(header)
+}
+
+fn server_error(err: io::Error) -> HttpResponse {
+    respond(500, "text/plain", format!("{}\n", err).into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    fn get(addr: SocketAddr, path: &str) -> (u16, Vec<u8>) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET {} HTTP/1.0\r\nHost: localhost\r\n\r\n", path).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let split = response
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .expect("complete response");
+        let head = String::from_utf8_lossy(&response[..split]).to_string();
+        let status = head.split(' ').nth(1).unwrap().parse().unwrap();
+        (status, response[split + 4..].to_vec())
+    }
+
+    #[test]
+    fn test_param() {
+        assert_eq!(param("seconds=5&hz=10", "hz"), Some("10"));
+        assert_eq!(param("seconds=5", "hz"), None);
+        assert_eq!(param("", "seconds"), None);
+    }
+
+    #[test]
+    fn test_endpoints() {
+        let server = DebugServer::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr();
+
+        let (status, body) = get(addr, "/debug/stacks");
+        assert_eq!(status, 200);
+        assert!(String::from_utf8(body).unwrap().contains("\"mst-http\""));
+
+        let (status, body) = get(addr, "/debug/stacks?format=json");
+        assert_eq!(status, 200);
+        let stacks: Vec<crate::ThreadStack> = serde_json::from_slice(&body).unwrap();
+        assert!(stacks.iter().any(|s| s.name == "mst-http-req"));
+
+        assert_eq!(get(addr, "/debug/pprof/profile?seconds=-1").0, 400);
+        assert_eq!(get(addr, "/debug/flamegraph?hz=0").0, 400);
+        assert_eq!(get(addr, "/nope").0, 404);
+
+        let _lock = crate::sampler::TEST_SAMPLER_LOCK
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let (status, body) = get(addr, "/debug/pprof/profile?seconds=0.1&hz=200");
+        assert_eq!(status, 200);
+        assert_eq!(&body[..2], &[0x1f, 0x8b], "gzip magic");
+        assert_eq!(get(addr, "/debug/flamegraph?seconds=0.1").0, 200);
+    }
+}
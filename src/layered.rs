@@ -0,0 +1,197 @@
+//! Merging more than one interpreter stack into a native stack.
+//!
+//! A thread may re-enter interpreters several times: Python calling a C library that runs
+//! Lua callbacks, or a second CPython interpreter entered from an extension. Each logical
+//! stack is a `StackLayer` with the detector of the native frames evaluating it; the layers
+//! are merged one after the other into whatever the previous ones left native.
+
+use std::fmt;
+
+use crate::boundary::{BoundaryDetector, CPythonBoundaryDetector};
+use crate::merge_options::MergeOptions;
+use crate::stack_order::StackOrder;
+use crate::stack_tracer::SignalTracer;
+use crate::CallFrame;
+
+/// One logical stack for `SignalTracer::merge_layered`.
+pub struct StackLayer {
+    frames: Vec<CallFrame>,
+    detector: Box<dyn BoundaryDetector>,
+}
+
+impl StackLayer {
+    /// `frames` (in the options' python order), evaluated at the native frames `detector`
+    /// matches.
+    pub fn new(
+        frames: impl Into<Vec<CallFrame>>,
+        detector: impl BoundaryDetector + 'static,
+    ) -> Self {
+        StackLayer {
+            frames: frames.into(),
+            detector: Box::new(detector),
+        }
+    }
+
+    /// A CPython stack, at the default eval loop boundaries.
+    pub fn python(frames: impl Into<Vec<CallFrame>>) -> Self {
+        Self::new(frames, CPythonBoundaryDetector)
+    }
+
+    pub fn get_frames(&self) -> &[CallFrame] {
+        &self.frames
+    }
+}
+
+impl fmt::Debug for StackLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StackLayer")
+            .field("frames", &self.frames)
+            .finish_non_exhaustive()
+    }
+}
+
+impl SignalTracer {
+    /// Merge every layer into `native_stacks`, leaf first.
+    ///
+    /// Layers are merged in order with `options`, each with its own detector instead of the
+    /// options' one, and only native frames are boundaries, so frames placed by one layer
+    /// are never taken by the next. Layers whose detectors match the same frames (two
+    /// CPython interpreters) share those boundaries: pass them leaf first, and keep
+    /// `PythonExhausted::KeepNative` so that the leaf layer leaves the remaining
+    /// boundaries to the others.
+    pub fn merge_layered(
+        native_stacks: impl Into<Vec<CallFrame>>,
+        layers: impl IntoIterator<Item = StackLayer>,
+        options: &MergeOptions,
+    ) -> Vec<CallFrame> {
+        let root_first = options.native_stack_order() == StackOrder::RootFirst;
+        let mut merged = native_stacks.into();
+        let mut leaf_first = !root_first;
+        for layer in layers {
+            if leaf_first && root_first {
+                // The previous merge's output is leaf first.
+                merged.reverse();
+            }
+            merged = Self::merge_inner(layer.frames, merged, layer.detector.as_ref(), options).0;
+            leaf_first = true;
+        }
+        if !leaf_first {
+            merged.reverse();
+        }
+        merged
+    }
+
+    /// `merge_layered` with the tracer's options.
+    pub fn merge_layers(
+        &self,
+        native_stacks: impl Into<Vec<CallFrame>>,
+        layers: impl IntoIterator<Item = StackLayer>,
+    ) -> Vec<CallFrame> {
+        Self::merge_layered(native_stacks, layers, self.options())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::boundary::LuaJitBoundaryDetector;
+
+    fn cframe(name: &str) -> CallFrame {
+        CallFrame::native("0x0", "", name, 0)
+    }
+
+    fn pyframe(name: &str) -> CallFrame {
+        CallFrame::python("0x0", "", name, 0)
+    }
+
+    fn funcs(frames: &[CallFrame]) -> Vec<&str> {
+        frames.iter().map(CallFrame::func).collect()
+    }
+
+    /// Python calls a C library that runs a Lua callback, which calls back into Python.
+    fn python_lua_native() -> Vec<CallFrame> {
+        vec![
+            cframe("write"),
+            cframe("_PyEval_EvalFrameDefault"),
+            cframe("PyObject_Call"),
+            cframe("lua_pcall"),
+            cframe("plugin_dispatch"),
+            cframe("_PyEval_EvalFrameDefault"),
+            cframe("main"),
+        ]
+    }
+
+    #[test]
+    fn test_merge_layered() {
+        let layers = [
+            StackLayer::python(vec![pyframe("log"), pyframe("serve")]),
+            StackLayer::new(
+                vec![CallFrame::interpreter("lua", "plugin.lua", "on_request", 4)],
+                LuaJitBoundaryDetector,
+            ),
+        ];
+        let merged =
+            SignalTracer::merge_layered(python_lua_native(), layers, &MergeOptions::default());
+        assert_eq!(
+            funcs(&merged),
+            [
+                "write",
+                "log",
+                "PyObject_Call",
+                "on_request",
+                "plugin_dispatch",
+                "serve",
+                "main"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_layered_shared_boundaries() {
+        // A subinterpreter entered from an extension of the main one: both stacks sit on
+        // eval loop frames, the subinterpreter's closer to the leaf.
+        let native = vec![
+            cframe("_PyEval_EvalFrameDefault"),
+            cframe("_PyEval_EvalFrameDefault"),
+            cframe("run_in_subinterp"),
+            cframe("_PyEval_EvalFrameDefault"),
+            cframe("main"),
+        ];
+        let tracer = SignalTracer::new();
+        let merged = tracer.merge_layers(
+            native,
+            [
+                StackLayer::python(vec![pyframe("sub_leaf"), pyframe("sub_root")]),
+                StackLayer::python(vec![pyframe("enter")]),
+            ],
+        );
+        assert_eq!(
+            funcs(&merged),
+            ["sub_leaf", "sub_root", "run_in_subinterp", "enter", "main"]
+        );
+    }
+
+    #[test]
+    fn test_merge_layered_root_first() {
+        let mut native = python_lua_native();
+        native.reverse();
+        let options = MergeOptions::new().native_order(StackOrder::RootFirst);
+        let lua = vec![CallFrame::interpreter("lua", "plugin.lua", "on_request", 4)];
+        let merged = SignalTracer::merge_layered(
+            native.clone(),
+            [
+                StackLayer::python(vec![pyframe("log"), pyframe("serve")]),
+                StackLayer::new(lua, LuaJitBoundaryDetector),
+            ],
+            &options,
+        );
+        assert_eq!(
+            funcs(&merged)[..4],
+            ["write", "log", "PyObject_Call", "on_request"]
+        );
+
+        // Without layers the native stack comes back leaf first as well.
+        let merged = SignalTracer::merge_layered(native, [], &options);
+        assert_eq!(funcs(&merged).first(), Some(&"write"));
+    }
+}
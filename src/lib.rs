@@ -1,24 +1,509 @@
 //! mixed-stack-tracer: minimal crate exposing merge functionality for prototype/testing.
 
+#[cfg(all(
+    feature = "alloc_tracer",
+    any(target_os = "linux", target_os = "macos")
+))]
+pub mod alloc_tracer;
+#[cfg(target_os = "linux")]
+pub mod auto;
+pub mod boundary;
+pub mod call_tree;
+pub mod capture;
+pub mod classify;
+pub mod collectives;
+#[cfg(target_os = "linux")]
+pub mod control;
+#[cfg(unix)]
+pub mod crash_handler;
+#[cfg(all(feature = "cuda", target_os = "linux"))]
+pub mod cuda;
+pub mod deadlock;
+pub mod demangle;
+pub mod diff;
+#[cfg(target_os = "linux")]
+pub mod dumper;
+pub mod envelope;
+pub mod error;
+mod events;
+#[cfg(target_os = "linux")]
+pub mod flight_recorder;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+mod fork;
+pub mod frame_filter;
+pub mod frame_table;
+pub mod gil;
+#[cfg(all(feature = "grpc", target_os = "linux"))]
+pub mod grpc;
+#[cfg(all(feature = "http", target_os = "linux"))]
+pub mod http;
+pub mod layered;
+pub mod locals;
+#[cfg(target_os = "macos")]
+mod mach;
+pub mod merge_iter;
+pub mod merge_options;
+#[cfg(any(target_os = "linux", target_os = "macos", windows))]
+pub mod monitor;
+pub mod output;
+pub mod perf_script;
+pub mod profile;
+#[cfg(feature = "proto")]
+pub mod proto;
+#[cfg(all(feature = "pyroscope", target_os = "linux"))]
+pub mod pyroscope;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod ranks;
+#[cfg(any(target_os = "linux", target_os = "macos", windows))]
+pub mod remote;
+#[cfg(target_os = "linux")]
+pub mod sampler;
+#[cfg(target_os = "linux")]
+pub mod shm;
+#[cfg(unix)]
+mod signal_cell;
+#[cfg(target_os = "linux")]
+pub mod signal_dump;
+pub mod source;
+pub mod stack_hash;
+pub mod stack_order;
+pub mod stack_trace;
 pub mod stack_tracer;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub mod symbolize;
+pub mod thread_stack;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub mod threads;
+pub mod torch;
+pub mod traceback;
+pub mod trampoline;
+#[cfg(target_os = "linux")]
+pub mod unwind;
+pub mod validate;
+pub mod value;
+#[cfg(target_os = "linux")]
+pub mod watchdog;
 
 /// Public re-exports for convenience
-pub use crate::stack_tracer::SignalTracer;
+pub use crate::boundary::{
+    BoundaryDetector, BoundaryMatcher, CPythonBoundaryDetector, JuliaBoundaryDetector,
+    LuaJitBoundaryDetector, PyPyBoundaryDetector, PythonImplementation,
+};
+pub use crate::call_tree::{CallTree, CallTreeNode};
+pub use crate::classify::ClassifyOptions;
+pub use crate::collectives::{Collective, COLLECTIVE_CATEGORY};
+#[cfg(target_os = "linux")]
+pub use crate::control::ControlServer;
+#[cfg(all(feature = "cuda", target_os = "linux"))]
+pub use crate::cuda::{CudaLaunchTracker, KernelLaunch};
+pub use crate::deadlock::{Deadlock, LockGraph, LockId, LockKind};
+pub use crate::demangle::DemangleOptions;
+pub use crate::diff::{CallTreeDiff, Change, DiffEntry, FrameChange};
+#[cfg(target_os = "linux")]
+pub use crate::dumper::PeriodicDumper;
+pub use crate::error::TracerError;
+#[cfg(target_os = "linux")]
+pub use crate::flight_recorder::{FlightRecorder, FlightRecorderOptions, RecordedStack};
+pub use crate::frame_filter::FrameFilter;
+pub use crate::frame_table::{FrameId, FrameTable};
+#[cfg(all(feature = "grpc", target_os = "linux"))]
+pub use crate::grpc::{GrpcServer, TracerClient};
+#[cfg(all(feature = "http", target_os = "linux"))]
+pub use crate::http::DebugServer;
+pub use crate::layered::StackLayer;
+pub use crate::locals::{LocalsMode, LocalsPolicy};
+pub use crate::merge_iter::MergeIter;
+pub use crate::merge_options::{
+    ExtraPythonFrames, FramesPerBoundary, MergeOptions, PythonExhausted,
+};
+#[cfg(any(target_os = "linux", target_os = "macos", windows))]
+pub use crate::monitor::{ProgressReport, StackMonitor};
+pub use crate::profile::{Profile, SampleState, SampledStack, TimedSample};
+pub use crate::ranks::{RankAggregator, RankInfo, RankTrace};
+#[cfg(any(target_os = "linux", target_os = "macos", windows))]
+pub use crate::remote::{RemoteProcess, SessionProfile};
+#[cfg(target_os = "linux")]
+pub use crate::sampler::Sampler;
+#[cfg(target_os = "linux")]
+pub use crate::shm::{ShmExporter, ShmOptions, ShmReader, ShmWriter};
+pub use crate::source::{SourceCache, SourceContext};
+pub use crate::stack_hash::{Observed, StackDeduper, StackHash, StackId};
+pub use crate::stack_order::StackOrder;
+pub use crate::stack_trace::{CaptureSource, StackTrace, TRUNCATED_CATEGORY};
+pub use crate::stack_tracer::{BoundaryLink, LinkedFrame, SignalTracer};
+#[cfg(target_os = "linux")]
+pub use crate::symbolize::module_map::ModuleMap;
+#[cfg(target_os = "linux")]
+pub use crate::symbolize::offline::OfflineSymbolizer;
+#[cfg(target_os = "linux")]
+pub use crate::symbolize::Symbolizer;
+pub use crate::thread_stack::{ThreadId, ThreadStack, ThreadState};
+pub use crate::torch::TORCH_OP_CATEGORY;
+pub use crate::trampoline::{
+    SecondaryBoundaries, TrampolineAction, TrampolineKind, TrampolineOptions,
+};
+#[cfg(target_os = "linux")]
+pub use crate::unwind::{UnwindStrategy, UnwindTable, Unwinder};
+pub use crate::validate::{MergeResult, MergeWarning};
+pub use crate::value::Value;
+#[cfg(target_os = "linux")]
+pub use crate::watchdog::Watchdog;
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+/// A function call inlined into a native frame, as reported by the debug info.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InlineFrame {
+    pub func: String,
+    pub file: String,
+    pub lineno: i64,
+}
 
 /// A simple CallFrame model used in tests and examples.
-/// In real integration this would come from symbol resolution/demangling and probing_proto.
-#[derive(Clone, Debug, PartialEq, Eq)]
+/// In real integration this would come from symbol resolution/demangling and probing_proto;
+/// `proto/mixed_stack_tracer.proto` (feature `proto`) is the protobuf form.
+///
+/// Serialized internally tagged by `kind`, named like `FrameKind::as_str`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind")]
 pub enum CallFrame {
+    #[serde(rename = "native")]
     CFrame {
         ip: String,
         file: String,
         func: String,
         lineno: i64,
+        /// Mangled symbol name, kept by `demangle_frames_with` when asked to.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        raw_func: Option<String>,
+        /// Functions inlined into `func` at `ip`, innermost first. `func`, `file` and
+        /// `lineno` describe the physical (outermost) function.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        inlined: Vec<InlineFrame>,
+        /// Tag set by annotation passes, e.g. `torch-op` (see `annotate_torch_frames`).
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        category: Option<String>,
+        /// Source around `lineno`, attached by `attach_source`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        source: Option<Box<SourceContext>>,
+        /// File of the module `ip` lies in, set by `ModuleMap::annotate`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        module_path: Option<String>,
+        /// GNU build-id of that module, lowercase hex.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        build_id: Option<String>,
+        /// `ip` in the module's link-time address space (ip minus load bias), the address
+        /// `addr2line -e <module>` takes.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        offset: Option<u64>,
+        /// For an eval loop frame, address of the interpreter frame it was entered with,
+        /// when the capture backend could read it. Pairs the boundary with the python
+        /// frame of the same `frame_address` (see `merge_python_native_stacks_with`).
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        eval_frame: Option<u64>,
     },
+    #[serde(rename = "python")]
     PyFrame {
         ip: String,
         file: String,
         func: String,
         lineno: i64,
+        /// Selected locals of the frame; empty unless requested at capture time.
+        #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+        locals: HashMap<String, Value>,
+        /// Dotted module name derived from `file` by `classify_frames`, e.g. `torch.nn.modules.linear`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        module: Option<String>,
+        /// Top-level package of `module`, e.g. `torch`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        package: Option<String>,
+        /// Application code rather than the standard library or an installed package;
+        /// set by `classify_frames`.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        in_app: bool,
+        /// Source around `lineno`, attached by `attach_source`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        source: Option<Box<SourceContext>>,
+        /// Address of the interpreter frame (`_PyInterpreterFrame`, `PyFrameObject` before
+        /// 3.11) in the traced process, when known.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        frame_address: Option<u64>,
+    },
+    /// GPU work enqueued by the frame after it (its launch site), e.g. a CUDA kernel
+    /// reported by the `cuda` feature.
+    #[serde(rename = "gpu")]
+    GpuFrame {
+        /// Demangled kernel name.
+        kernel: String,
+        /// Device ordinal, when known.
+        device: Option<u32>,
+        /// Id correlating the launch with the profiler's GPU activity records.
+        correlation_id: u32,
+    },
+    /// Frame of an interpreted runtime other than Python (Lua, Julia, ...), merged into the
+    /// native stack at its own boundary frames like python frames are.
+    #[serde(rename = "interpreter")]
+    InterpreterFrame {
+        /// Runtime the frame belongs to, e.g. `lua` or `julia`.
+        runtime: String,
+        file: String,
+        func: String,
+        lineno: i64,
     },
-}
\ No newline at end of file
+    /// Semantic marker injected by a capture backend rather than unwound, such as
+    /// `[GIL wait]`, `[gc]` or `[cuda sync]`.
+    #[serde(rename = "synthetic")]
+    Synthetic {
+        label: String,
+        /// Free-form grouping, e.g. `gil`, `gc`, `cuda`.
+        category: String,
+    },
+}
+
+/// Kind of a `CallFrame`, for exporters and frame identity.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FrameKind {
+    Native,
+    Python,
+    Gpu,
+    Interpreter,
+    Synthetic,
+}
+
+impl FrameKind {
+    /// Lowercase name used by the exporters (`native`, `python`, `gpu`).
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FrameKind::Native => "native",
+            FrameKind::Python => "python",
+            FrameKind::Gpu => "gpu",
+            FrameKind::Interpreter => "interpreter",
+            FrameKind::Synthetic => "synthetic",
+        }
+    }
+}
+
+impl CallFrame {
+    /// Build a native (C/C++/Rust) frame.
+    pub fn native(
+        ip: impl Into<String>,
+        file: impl Into<String>,
+        func: impl Into<String>,
+        lineno: i64,
+    ) -> Self {
+        CallFrame::CFrame {
+            ip: ip.into(),
+            file: file.into(),
+            func: func.into(),
+            lineno,
+            raw_func: None,
+            inlined: Vec::new(),
+            category: None,
+            source: None,
+            module_path: None,
+            build_id: None,
+            offset: None,
+            eval_frame: None,
+        }
+    }
+
+    /// Build a Python frame.
+    pub fn python(
+        ip: impl Into<String>,
+        file: impl Into<String>,
+        func: impl Into<String>,
+        lineno: i64,
+    ) -> Self {
+        CallFrame::PyFrame {
+            ip: ip.into(),
+            file: file.into(),
+            func: func.into(),
+            lineno,
+            locals: HashMap::new(),
+            module: None,
+            package: None,
+            in_app: false,
+            source: None,
+            frame_address: None,
+        }
+    }
+
+    /// Build a GPU kernel frame.
+    pub fn gpu(kernel: impl Into<String>, device: Option<u32>, correlation_id: u32) -> Self {
+        CallFrame::GpuFrame {
+            kernel: kernel.into(),
+            device,
+            correlation_id,
+        }
+    }
+
+    /// Build a frame of another interpreted runtime.
+    pub fn interpreter(
+        runtime: impl Into<String>,
+        file: impl Into<String>,
+        func: impl Into<String>,
+        lineno: i64,
+    ) -> Self {
+        CallFrame::InterpreterFrame {
+            runtime: runtime.into(),
+            file: file.into(),
+            func: func.into(),
+            lineno,
+        }
+    }
+
+    /// Build a synthetic marker frame.
+    pub fn synthetic(label: impl Into<String>, category: impl Into<String>) -> Self {
+        CallFrame::Synthetic {
+            label: label.into(),
+            category: category.into(),
+        }
+    }
+
+    pub fn kind(&self) -> FrameKind {
+        match self {
+            CallFrame::CFrame { .. } => FrameKind::Native,
+            CallFrame::PyFrame { .. } => FrameKind::Python,
+            CallFrame::GpuFrame { .. } => FrameKind::Gpu,
+            CallFrame::InterpreterFrame { .. } => FrameKind::Interpreter,
+            CallFrame::Synthetic { .. } => FrameKind::Synthetic,
+        }
+    }
+
+    /// Function name (the kernel name for GPU frames, the label for synthetic ones).
+    pub fn func(&self) -> &str {
+        match self {
+            CallFrame::CFrame { func, .. }
+            | CallFrame::PyFrame { func, .. }
+            | CallFrame::InterpreterFrame { func, .. } => func,
+            CallFrame::GpuFrame { kernel, .. } => kernel,
+            CallFrame::Synthetic { label, .. } => label,
+        }
+    }
+
+    /// Source file, empty when unknown.
+    pub fn file(&self) -> &str {
+        match self {
+            CallFrame::CFrame { file, .. }
+            | CallFrame::PyFrame { file, .. }
+            | CallFrame::InterpreterFrame { file, .. } => file,
+            CallFrame::GpuFrame { .. } | CallFrame::Synthetic { .. } => "",
+        }
+    }
+
+    /// Line number, 0 when unknown.
+    pub fn lineno(&self) -> i64 {
+        match self {
+            CallFrame::CFrame { lineno, .. }
+            | CallFrame::PyFrame { lineno, .. }
+            | CallFrame::InterpreterFrame { lineno, .. } => *lineno,
+            CallFrame::GpuFrame { .. } | CallFrame::Synthetic { .. } => 0,
+        }
+    }
+
+    /// Native frame with inlined callees (innermost first) at the same ip.
+    pub fn with_inlined(mut self, frames: Vec<InlineFrame>) -> Self {
+        if let CallFrame::CFrame { inlined, .. } = &mut self {
+            *inlined = frames;
+        }
+        self
+    }
+
+    /// Python frame at `address` in the traced process; other frames are unchanged.
+    pub fn with_frame_address(mut self, address: u64) -> Self {
+        if let CallFrame::PyFrame { frame_address, .. } = &mut self {
+            *frame_address = Some(address);
+        }
+        self
+    }
+
+    /// Native eval loop frame entered with the interpreter frame at `address`; other
+    /// frames are unchanged.
+    pub fn with_eval_frame(mut self, address: u64) -> Self {
+        if let CallFrame::CFrame { eval_frame, .. } = &mut self {
+            *eval_frame = Some(address);
+        }
+        self
+    }
+
+    /// Split native frames with inlined callees into one frame per function, innermost
+    /// first, all sharing the physical frame's ip. Other frames pass through unchanged.
+    pub fn expand_inlined(frames: Vec<CallFrame>) -> Vec<CallFrame> {
+        let mut expanded = Vec::with_capacity(frames.len());
+        for frame in frames {
+            match frame {
+                CallFrame::CFrame {
+                    ip,
+                    file,
+                    func,
+                    lineno,
+                    raw_func,
+                    inlined,
+                    category,
+                    source,
+                    module_path,
+                    build_id,
+                    offset,
+                    eval_frame,
+                } if !inlined.is_empty() => {
+                    for inline in inlined {
+                        expanded.push(CallFrame::native(
+                            ip.clone(),
+                            inline.file,
+                            inline.func,
+                            inline.lineno,
+                        ));
+                    }
+                    expanded.push(CallFrame::CFrame {
+                        ip,
+                        file,
+                        func,
+                        lineno,
+                        raw_func,
+                        inlined: Vec::new(),
+                        category,
+                        source,
+                        module_path,
+                        build_id,
+                        offset,
+                        eval_frame,
+                    });
+                }
+                other => expanded.push(other),
+            }
+        }
+        expanded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expand_inlined() {
+        let inline = |func: &str, lineno| InlineFrame {
+            func: func.to_string(),
+            file: "lib.rs".to_string(),
+            lineno,
+        };
+        let frames = vec![
+            CallFrame::native("0x10", "lib.rs", "outer", 30)
+                .with_inlined(vec![inline("inner", 10), inline("middle", 20)]),
+            CallFrame::python("0x20", "a.py", "f", 1),
+        ];
+        assert_eq!(
+            CallFrame::expand_inlined(frames),
+            vec![
+                CallFrame::native("0x10", "lib.rs", "inner", 10),
+                CallFrame::native("0x10", "lib.rs", "middle", 20),
+                CallFrame::native("0x10", "lib.rs", "outer", 30),
+                CallFrame::python("0x20", "a.py", "f", 1),
+            ]
+        );
+    }
+}
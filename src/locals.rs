@@ -0,0 +1,287 @@
+//! What Python frame capture records of each frame's locals.
+//!
+//! Locals end up in logs, dump files and crash reports, so capture is opt-in and
+//! bounded: a `LocalsPolicy` chooses how much of each value is kept, which names are
+//! looked at, how long strings may get, and lets a hook redact secrets before a value
+//! leaves the process.
+
+use std::fmt;
+use std::sync::Arc;
+
+use regex::Regex;
+
+use crate::value::Value;
+
+/// Names `LocalsPolicy::redact_secrets` treats as secrets (case-insensitive).
+pub const SECRET_NAME_PATTERN: &str =
+    r"(?i)pass(word|wd)?|secret|token|api_?key|auth|credential|private_?key|session";
+
+/// Nesting depth up to which `LocalsMode::Full` captures containers element-wise.
+pub const DEFAULT_MAX_DEPTH: usize = 8;
+
+/// Approximate bytes `LocalsMode::Full` captures per frame by default.
+pub const DEFAULT_MAX_SIZE: usize = 64 * 1024;
+
+/// Placeholder stored in place of redacted values.
+pub const REDACTED: &str = "<redacted>";
+
+/// How much of each selected local is captured.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LocalsMode {
+    /// No locals at all.
+    #[default]
+    None,
+    /// Names only; the value is replaced by its type, e.g. `<dict>`.
+    NamesOnly,
+    /// Scalars and strings by value; other objects as `<type>` or `<type of N>` for
+    /// sized containers, without calling their `repr()`.
+    Shallow,
+    /// Scalars, strings and bytes by value, lists, tuples and dicts element-wise, and
+    /// anything else as its `repr()`; bounded by `max_depth` and `max_size`.
+    Full,
+}
+
+/// Replaces a captured value given the local's name, e.g. to mask secrets.
+pub type RedactHook = Arc<dyn Fn(&str, Value) -> Value + Send + Sync>;
+
+/// Rules for capturing Python locals; see the module docs.
+#[derive(Clone)]
+pub struct LocalsPolicy {
+    mode: LocalsMode,
+    names: Option<Vec<String>>,
+    pattern: Option<Regex>,
+    max_string_len: Option<usize>,
+    max_depth: usize,
+    max_size: Option<usize>,
+    redact: Option<RedactHook>,
+}
+
+impl Default for LocalsPolicy {
+    fn default() -> Self {
+        LocalsPolicy {
+            mode: LocalsMode::None,
+            names: None,
+            pattern: None,
+            max_string_len: None,
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_size: Some(DEFAULT_MAX_SIZE),
+            redact: None,
+        }
+    }
+}
+
+impl fmt::Debug for LocalsPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LocalsPolicy")
+            .field("mode", &self.mode)
+            .field("names", &self.names)
+            .field("pattern", &self.pattern.as_ref().map(Regex::as_str))
+            .field("max_string_len", &self.max_string_len)
+            .field("max_depth", &self.max_depth)
+            .field("max_size", &self.max_size)
+            .field("redact", &self.redact.is_some())
+            .finish()
+    }
+}
+
+impl LocalsPolicy {
+    /// Capture no locals.
+    pub fn none() -> Self {
+        Self::default()
+    }
+
+    pub fn names_only() -> Self {
+        Self::with_mode(LocalsMode::NamesOnly)
+    }
+
+    /// Shallow values with strings cut at 256 bytes.
+    pub fn shallow() -> Self {
+        Self::with_mode(LocalsMode::Shallow).max_string_len(256)
+    }
+
+    /// `repr()` values with strings cut at 1024 bytes.
+    pub fn full() -> Self {
+        Self::with_mode(LocalsMode::Full).max_string_len(1024)
+    }
+
+    pub fn with_mode(mode: LocalsMode) -> Self {
+        LocalsPolicy {
+            mode,
+            ..Self::default()
+        }
+    }
+
+    /// Only look at these names (default: every local of the frame).
+    pub fn names<S: AsRef<str>>(mut self, names: &[S]) -> Self {
+        self.names = Some(names.iter().map(|n| n.as_ref().to_string()).collect());
+        self
+    }
+
+    /// Only capture locals whose name matches `pattern`.
+    pub fn matching(mut self, pattern: Regex) -> Self {
+        self.pattern = Some(pattern);
+        self
+    }
+
+    /// Cut longer strings and reprs at `len` bytes, marking the cut with `...`; bytes
+    /// values are cut without a marker.
+    pub fn max_string_len(mut self, len: usize) -> Self {
+        self.max_string_len = Some(len);
+        self
+    }
+
+    /// Keep strings at full length.
+    pub fn unlimited_strings(mut self) -> Self {
+        self.max_string_len = None;
+        self
+    }
+
+    /// Capture containers nested up to `depth` levels; deeper ones are kept as
+    /// `<type of N>` (default `DEFAULT_MAX_DEPTH`). Containers holding themselves are
+    /// recorded like Python's `[...]` whatever the depth.
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.max_depth = depth;
+        self
+    }
+
+    /// Stop descending into containers once about `bytes` of one frame's locals were
+    /// captured; the rest of a container collapses to a `<N more>` entry (default
+    /// `DEFAULT_MAX_SIZE`).
+    pub fn max_size(mut self, bytes: usize) -> Self {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// Do not bound the total size of a frame's locals.
+    pub fn unlimited_size(mut self) -> Self {
+        self.max_size = None;
+        self
+    }
+
+    /// Pass every captured value through `hook` (after truncation).
+    pub fn redact(mut self, hook: impl Fn(&str, Value) -> Value + Send + Sync + 'static) -> Self {
+        self.redact = Some(Arc::new(hook));
+        self
+    }
+
+    /// Replace values of locals named like secrets (`SECRET_NAME_PATTERN`) by `REDACTED`.
+    pub fn redact_secrets(self) -> Self {
+        let secrets = Regex::new(SECRET_NAME_PATTERN).expect("valid secret pattern");
+        self.redact(move |name, value| {
+            if secrets.is_match(name) {
+                Value::Str(REDACTED.to_string())
+            } else {
+                value
+            }
+        })
+    }
+
+    pub fn mode(&self) -> LocalsMode {
+        self.mode
+    }
+
+    pub fn get_names(&self) -> Option<&[String]> {
+        self.names.as_deref()
+    }
+
+    pub fn get_max_string_len(&self) -> Option<usize> {
+        self.max_string_len
+    }
+
+    pub fn get_max_depth(&self) -> usize {
+        self.max_depth
+    }
+
+    pub fn get_max_size(&self) -> Option<usize> {
+        self.max_size
+    }
+
+    /// Whether any local is captured at all.
+    pub fn is_enabled(&self) -> bool {
+        self.mode != LocalsMode::None
+    }
+
+    /// Whether the local `name` is captured.
+    pub fn selects(&self, name: &str) -> bool {
+        self.is_enabled()
+            && self
+                .names
+                .as_ref()
+                .is_none_or(|names| names.iter().any(|n| n == name))
+            && self.pattern.as_ref().is_none_or(|p| p.is_match(name))
+    }
+
+    /// Apply the string limit and the redaction hook to a captured value.
+    pub fn finish(&self, name: &str, value: Value) -> Value {
+        self.redacted(name, self.truncated(value))
+    }
+
+    /// Cut string values, including nested ones and reprs, to the configured limit.
+    pub fn truncated(&self, value: Value) -> Value {
+        match self.max_string_len {
+            Some(len) => value.map_strings(&mut |s| truncate(s, len)),
+            None => value,
+        }
+    }
+
+    /// Pass a captured value through the redaction hook, if any.
+    pub fn redacted(&self, name: &str, value: Value) -> Value {
+        match &self.redact {
+            Some(hook) => hook(name, value),
+            None => value,
+        }
+    }
+}
+
+/// Cut `s` to at most `len` bytes on a char boundary, appending `...` when cut.
+pub fn truncate(mut s: String, len: usize) -> String {
+    if s.len() <= len {
+        return s;
+    }
+    let mut end = len;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    s.truncate(end);
+    s.push_str("...");
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_selects() {
+        assert!(!LocalsPolicy::none().selects("x"));
+        assert!(LocalsPolicy::shallow().selects("x"));
+        let named = LocalsPolicy::full().names(&["a", "b"]);
+        assert!(named.selects("a") && !named.selects("c"));
+        let pattern = LocalsPolicy::shallow().matching(Regex::new("^batch_").unwrap());
+        assert!(pattern.selects("batch_size") && !pattern.selects("size"));
+    }
+
+    #[test]
+    fn test_finish() {
+        let policy = LocalsPolicy::shallow().max_string_len(4).redact_secrets();
+        assert_eq!(
+            policy.finish("name", Value::Str("abcdef".to_string())),
+            Value::Str("abcd...".to_string())
+        );
+        assert_eq!(policy.finish("n", Value::Int(7)), Value::Int(7));
+        assert_eq!(
+            policy.finish("API_KEY", Value::Str("sk-123".to_string())),
+            Value::Str(REDACTED.to_string())
+        );
+        assert_eq!(
+            policy.finish("db_password", Value::Int(1)),
+            Value::Str(REDACTED.to_string())
+        );
+    }
+
+    #[test]
+    fn test_truncate_on_char_boundary() {
+        assert_eq!(truncate("héllo".to_string(), 2), "h...");
+        assert_eq!(truncate("short".to_string(), 10), "short");
+    }
+}
@@ -0,0 +1,277 @@
+//! Threads of a mach task (macOS): enumeration, suspension and register state.
+//!
+//! macOS has no `/proc` and no `tgkill`, so `threads.rs` captures the calling process the
+//! way a debugger would: every other thread is suspended in turn, its frame-pointer chain
+//! is walked through `mach_vm_read_overwrite`, and it is resumed. Apple's ABIs keep frame
+//! pointers, so system libraries unwind too. `RemoteProcess` does the same on a task port
+//! obtained with `task_for_pid`.
+//!
+//! Nothing here allocates between suspending a thread and resuming it (the thread may hold
+//! the malloc lock), including on errors: kernel return codes map onto plain errnos.
+
+use std::ffi::CStr;
+use std::io;
+
+use mach2::kern_return::{
+    kern_return_t, KERN_FAILURE, KERN_INVALID_ADDRESS, KERN_INVALID_ARGUMENT, KERN_INVALID_NAME,
+    KERN_INVALID_TASK, KERN_NO_ACCESS, KERN_PROTECTION_FAILURE, KERN_SUCCESS, KERN_TERMINATED,
+};
+use mach2::mach_port::mach_port_deallocate;
+use mach2::port::mach_port_t;
+use mach2::task::{task_resume, task_suspend, task_threads};
+use mach2::thread_act::{thread_get_state, thread_resume, thread_suspend};
+use mach2::traps::mach_task_self;
+use mach2::vm::mach_vm_deallocate;
+
+use crate::remote::memory::{walk_frame_pointers, ProcessMemory};
+use crate::thread_stack::{ThreadId, ThreadState};
+
+/// `kr` as an `io::Error`, mapped onto the nearest errno so that no allocation is needed.
+pub(crate) fn check(kr: kern_return_t) -> io::Result<()> {
+    let errno = match kr {
+        KERN_SUCCESS => return Ok(()),
+        KERN_INVALID_ADDRESS | KERN_PROTECTION_FAILURE => libc::EFAULT,
+        // What `task_for_pid` returns when the caller may not debug the target.
+        KERN_FAILURE | KERN_NO_ACCESS => libc::EPERM,
+        KERN_INVALID_NAME | KERN_INVALID_TASK | KERN_TERMINATED => libc::ESRCH,
+        KERN_INVALID_ARGUMENT => libc::EINVAL,
+        _ => libc::EIO,
+    };
+    Err(io::Error::from_raw_os_error(errno))
+}
+
+/// The calling process' task port.
+pub(crate) fn current_task() -> mach_port_t {
+    unsafe { mach_task_self() }
+}
+
+/// Send rights to every thread of `task`.
+pub(crate) fn task_thread_list(task: mach_port_t) -> io::Result<Vec<Thread>> {
+    let mut list = std::ptr::null_mut();
+    let mut count = 0;
+    check(unsafe { task_threads(task, &mut list, &mut count) })?;
+    let threads = unsafe { std::slice::from_raw_parts(list, count as usize) }
+        .iter()
+        .map(|port| Thread { port: *port })
+        .collect();
+    // The array itself was allocated in our address space by the kernel.
+    unsafe {
+        mach_vm_deallocate(
+            current_task(),
+            list as u64,
+            (count as usize * std::mem::size_of::<mach_port_t>()) as u64,
+        )
+    };
+    Ok(threads)
+}
+
+/// A send right to a thread, released on drop.
+#[derive(Debug)]
+pub(crate) struct Thread {
+    port: mach_port_t,
+}
+
+impl Thread {
+    /// System-wide thread id, as `pthread_threadid_np` and CPython's `native_thread_id`
+    /// report it.
+    pub(crate) fn id(&self) -> io::Result<ThreadId> {
+        let mut info: libc::thread_identifier_info = unsafe { std::mem::zeroed() };
+        self.info(
+            libc::THREAD_IDENTIFIER_INFO,
+            &mut info,
+            libc::THREAD_IDENTIFIER_INFO_COUNT,
+        )?;
+        Ok(info.thread_id as ThreadId)
+    }
+
+    /// The thread's `pthread_t`.
+    pub(crate) fn handle(&self) -> io::Result<u64> {
+        let mut info: libc::thread_identifier_info = unsafe { std::mem::zeroed() };
+        self.info(
+            libc::THREAD_IDENTIFIER_INFO,
+            &mut info,
+            libc::THREAD_IDENTIFIER_INFO_COUNT,
+        )?;
+        Ok(info.thread_handle)
+    }
+
+    /// Name set with `pthread_setname_np`, empty when there is none.
+    pub(crate) fn name(&self) -> io::Result<String> {
+        let mut info: libc::thread_extended_info = unsafe { std::mem::zeroed() };
+        self.info(
+            libc::THREAD_EXTENDED_INFO,
+            &mut info,
+            libc::THREAD_EXTENDED_INFO_COUNT,
+        )?;
+        let name = unsafe { CStr::from_ptr(info.pth_name.as_ptr()) };
+        Ok(name.to_string_lossy().into_owned())
+    }
+
+    /// Scheduler state; read it before suspending the thread.
+    pub(crate) fn state(&self) -> io::Result<ThreadState> {
+        let mut info: libc::thread_basic_info = unsafe { std::mem::zeroed() };
+        self.info(
+            libc::THREAD_BASIC_INFO,
+            &mut info,
+            libc::THREAD_BASIC_INFO_COUNT,
+        )?;
+        Ok(match info.run_state {
+            libc::TH_STATE_RUNNING => ThreadState::Running,
+            libc::TH_STATE_WAITING => ThreadState::Sleeping,
+            libc::TH_STATE_UNINTERRUPTIBLE => ThreadState::DiskSleep,
+            libc::TH_STATE_STOPPED | libc::TH_STATE_HALTED => ThreadState::Stopped,
+            _ => ThreadState::Unknown,
+        })
+    }
+
+    fn info<T>(&self, flavor: libc::c_int, out: &mut T, mut count: u32) -> io::Result<()> {
+        check(unsafe {
+            libc::thread_info(
+                self.port,
+                flavor as u32,
+                out as *mut T as libc::thread_info_t,
+                &mut count,
+            )
+        })
+    }
+
+    /// Stop the thread until the returned guard is dropped.
+    pub(crate) fn suspend(&self) -> io::Result<SuspendedThread<'_>> {
+        check(unsafe { thread_suspend(self.port) })?;
+        Ok(SuspendedThread { thread: self })
+    }
+
+    /// `(pc, sp, fp, lr)`; `lr` is 0 where return addresses live on the stack. Only
+    /// meaningful while the thread or its whole task is suspended.
+    #[cfg(target_arch = "aarch64")]
+    pub(crate) fn registers(&self) -> io::Result<(u64, u64, u64, u64)> {
+        use mach2::structs::arm_thread_state64_t;
+        use mach2::thread_status::ARM_THREAD_STATE64;
+
+        let mut state = arm_thread_state64_t::new();
+        let mut count = arm_thread_state64_t::count();
+        check(unsafe {
+            thread_get_state(
+                self.port,
+                ARM_THREAD_STATE64,
+                &mut state as *mut _ as *mut u32,
+                &mut count,
+            )
+        })?;
+        Ok((
+            strip_pac(state.__pc),
+            state.__sp,
+            state.__fp,
+            strip_pac(state.__lr),
+        ))
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    pub(crate) fn registers(&self) -> io::Result<(u64, u64, u64, u64)> {
+        use mach2::structs::x86_thread_state64_t;
+        use mach2::thread_status::x86_THREAD_STATE64;
+
+        let mut state = x86_thread_state64_t::new();
+        let mut count = x86_thread_state64_t::count();
+        check(unsafe {
+            thread_get_state(
+                self.port,
+                x86_THREAD_STATE64,
+                &mut state as *mut _ as *mut u32,
+                &mut count,
+            )
+        })?;
+        Ok((state.__rip, state.__rsp, state.__rbp, 0))
+    }
+
+    /// Return addresses of the thread, innermost instruction pointer first, appended to
+    /// `ips` up to `max_depth`. Only valid while the thread or its task is suspended;
+    /// reserve `max_depth` in `ips` beforehand to keep this allocation-free.
+    pub(crate) fn native_ips(
+        &self,
+        memory: &ProcessMemory,
+        ips: &mut Vec<u64>,
+        max_depth: usize,
+    ) -> io::Result<()> {
+        let (pc, sp, fp, lr) = self.registers()?;
+        ips.push(pc);
+        if lr != 0 {
+            ips.push(lr);
+        }
+        let first = ips.len();
+        walk_frame_pointers(memory, fp, sp, ips, max_depth);
+        for ip in &mut ips[first..] {
+            *ip = strip_pac(*ip);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Thread {
+    fn drop(&mut self) {
+        unsafe { mach_port_deallocate(current_task(), self.port) };
+    }
+}
+
+/// A thread held with `thread_suspend`; resumed again on drop.
+pub(crate) struct SuspendedThread<'a> {
+    thread: &'a Thread,
+}
+
+impl Drop for SuspendedThread<'_> {
+    fn drop(&mut self) {
+        unsafe { thread_resume(self.thread.port) };
+    }
+}
+
+/// A whole task held with `task_suspend`; resumed again on drop.
+pub(crate) struct SuspendedTask {
+    task: mach_port_t,
+}
+
+impl SuspendedTask {
+    pub(crate) fn new(task: mach_port_t) -> io::Result<SuspendedTask> {
+        check(unsafe { task_suspend(task) })?;
+        Ok(SuspendedTask { task })
+    }
+}
+
+impl Drop for SuspendedTask {
+    fn drop(&mut self) {
+        unsafe { task_resume(self.task) };
+    }
+}
+
+/// Drop the pointer authentication code arm64e code signs return addresses with.
+fn strip_pac(ip: u64) -> u64 {
+    if cfg!(target_arch = "aarch64") {
+        ip & 0x0000_7fff_ffff_ffff
+    } else {
+        ip
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_own_threads() {
+        let own = crate::threads::current_tid();
+        let threads = task_thread_list(current_task()).unwrap();
+        let me = threads.iter().find(|t| t.id().unwrap() == own).unwrap();
+        assert_eq!(me.state().unwrap(), ThreadState::Running);
+        assert_eq!(me.handle().unwrap(), unsafe { libc::pthread_self() } as u64);
+    }
+
+    #[test]
+    fn test_check() {
+        assert!(check(KERN_SUCCESS).is_ok());
+        let err = check(KERN_FAILURE).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert_eq!(
+            check(KERN_INVALID_ADDRESS).unwrap_err().raw_os_error(),
+            Some(libc::EFAULT)
+        );
+    }
+}
@@ -0,0 +1,435 @@
+//! Allocation-free merge over borrowed frames.
+//!
+//! `merge_iter` yields references into the input slices instead of building a new vector,
+//! so it can run in a hot sampling loop or, with an allocation-free boundary detector such
+//! as the default one, inside a signal handler. The owned merge shares the same walk.
+//!
+//! The one owned frame is the `[truncated N frames]` marker ending a stack cut by
+//! `MergeOptions::max_frames`, so a limit costs an allocation on the stacks it cuts.
+
+use std::borrow::Cow;
+
+use arrayvec::ArrayVec;
+
+use crate::boundary::BoundaryDetector;
+use crate::merge_options::{ExtraPythonFrames, FramesPerBoundary, MergeOptions, PythonExhausted};
+use crate::stack_order::StackOrder;
+use crate::stack_trace::truncation_marker;
+use crate::stack_tracer::SignalTracer;
+use crate::CallFrame;
+
+/// Source of the next merged frame: an index into the native or the python stack.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Pick {
+    Native(usize),
+    Python(usize),
+}
+
+/// Outcome of a completed walk, for `MergeResult` and the fallible merges.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct WalkCounts {
+    /// Python frames not placed in the merged stack.
+    pub(crate) leftover: usize,
+    /// Python frames appended after the outermost native frame, for want of a boundary.
+    pub(crate) appended: usize,
+    /// Boundaries met once python frames had run out.
+    pub(crate) unmatched_boundaries: usize,
+    /// Every boundary met, matched or not.
+    pub(crate) boundaries: usize,
+    /// Frames beyond `MergeOptions::max_frames`, replaced by a marker.
+    pub(crate) truncated: usize,
+}
+
+/// The merge walk itself, producing indices in output order.
+///
+/// Indices count from the leaf whatever the order of the inputs.
+pub(crate) struct Picks<'a> {
+    native: &'a [CallFrame],
+    native_order: StackOrder,
+    python: &'a [CallFrame],
+    python_order: StackOrder,
+    python_len: usize,
+    detector: &'a dyn BoundaryDetector,
+    keep_boundary_frames: bool,
+    on_python_exhausted: PythonExhausted,
+    on_extra_python_frames: ExtraPythonFrames,
+    frames_per_boundary: Option<&'a FramesPerBoundary>,
+    /// Native index receiving all leftover python frames (see `fills_last_boundary`).
+    last_boundary: Option<usize>,
+    /// Some python frame carries its `frame_address`, so boundaries with an `eval_frame`
+    /// are paired by address.
+    addressed: bool,
+    /// Boundaries that received python frames so far.
+    boundaries: usize,
+    /// Boundaries met after the python frames ran out.
+    exhausted: usize,
+    /// Python frames appended once the native stack was done.
+    appended: usize,
+    native_pos: usize,
+    python_pos: usize,
+    /// Python frames to emit before resuming the native walk.
+    python_pending: usize,
+    /// The last pick was a python frame following its kept boundary frame.
+    linked: bool,
+}
+
+impl<'a> Picks<'a> {
+    pub(crate) fn new(
+        python: &'a [CallFrame],
+        python_order: StackOrder,
+        native: &'a [CallFrame],
+        native_order: StackOrder,
+        detector: &'a dyn BoundaryDetector,
+        options: &'a MergeOptions,
+    ) -> Self {
+        let last_boundary = if options.fills_last_boundary() {
+            let len = native.len();
+            (0..len)
+                .rev()
+                .find(|i| detector.is_boundary(&native[native_order.leaf_index(len, *i)]))
+        } else {
+            None
+        };
+        Picks {
+            native,
+            native_order,
+            python,
+            python_order,
+            python_len: python.len(),
+            detector,
+            keep_boundary_frames: options.keeps_boundary_frames(),
+            on_python_exhausted: options.python_exhausted_policy(),
+            on_extra_python_frames: options.extra_python_frames_policy(),
+            frames_per_boundary: options.frames_per_boundary(),
+            last_boundary,
+            addressed: python.iter().any(|frame| {
+                matches!(
+                    frame,
+                    CallFrame::PyFrame {
+                        frame_address: Some(_),
+                        ..
+                    }
+                )
+            }),
+            boundaries: 0,
+            exhausted: 0,
+            appended: 0,
+            native_pos: 0,
+            python_pos: 0,
+            python_pending: 0,
+            linked: false,
+        }
+    }
+
+    /// Whether the last python pick is evaluated by the preceding kept boundary frame.
+    pub(crate) fn linked(&self) -> bool {
+        self.linked
+    }
+
+    /// Counts of the completed walk.
+    pub(crate) fn counts(&self) -> WalkCounts {
+        WalkCounts {
+            leftover: self.python_len - self.python_pos,
+            appended: self.appended,
+            unmatched_boundaries: self.exhausted,
+            boundaries: self.boundaries + self.exhausted,
+            truncated: 0,
+        }
+    }
+
+    /// Python frames evaluated by a boundary entered with the interpreter frame at
+    /// `address`: the run from the next python frame up to the one at that address, none
+    /// if it is not in the rest of the stack. Synthetic frames are not counted.
+    fn paired_take(&self, address: u64) -> usize {
+        let mut take = 0;
+        for pos in self.python_pos..self.python_len {
+            match &self.python[self.python_order.leaf_index(self.python_len, pos)] {
+                CallFrame::Synthetic { .. } => {}
+                CallFrame::PyFrame {
+                    frame_address: Some(at),
+                    ..
+                } if *at == address => return take + 1,
+                _ => take += 1,
+            }
+        }
+        0
+    }
+
+    fn next_python(&mut self) -> Pick {
+        self.python_pos += 1;
+        Pick::Python(self.python_pos - 1)
+    }
+
+    /// Next python frame of the run being placed. Synthetic frames travel with the python
+    /// frame after them instead of counting towards the run.
+    fn next_pending(&mut self) -> Pick {
+        let index = self
+            .python_order
+            .leaf_index(self.python_len, self.python_pos);
+        if !matches!(self.python[index], CallFrame::Synthetic { .. }) {
+            self.python_pending -= 1;
+        }
+        self.next_python()
+    }
+}
+
+impl Iterator for Picks<'_> {
+    type Item = Pick;
+
+    fn next(&mut self) -> Option<Pick> {
+        if self.python_pending > 0 && self.python_pos < self.python_len {
+            self.linked = self.keep_boundary_frames;
+            return Some(self.next_pending());
+        }
+        self.python_pending = 0;
+        self.linked = false;
+
+        while self.native_pos < self.native.len() {
+            let index = self.native_pos;
+            self.native_pos += 1;
+            let frame = &self.native[self.native_order.leaf_index(self.native.len(), index)];
+            if !self.detector.is_boundary(frame) {
+                return Some(Pick::Native(index));
+            }
+            if self.python_pos < self.python_len {
+                let remaining = self.python_len - self.python_pos;
+                let take = match frame {
+                    CallFrame::CFrame {
+                        eval_frame: Some(address),
+                        ..
+                    } if self.addressed => self.paired_take(*address),
+                    _ if self.last_boundary == Some(index) => remaining,
+                    _ => self
+                        .frames_per_boundary
+                        .map_or(1, |count| count(self.boundaries))
+                        .min(remaining),
+                };
+                self.boundaries += 1;
+                if take == 0 {
+                    // Evaluates no python frame: an ordinary native frame.
+                    return Some(Pick::Native(index));
+                }
+                self.python_pending = take;
+                if self.keep_boundary_frames {
+                    return Some(Pick::Native(index));
+                }
+                return Some(self.next_pending());
+            }
+            // No python frames left: apply the exhaustion policy
+            self.exhausted += 1;
+            match self.on_python_exhausted {
+                PythonExhausted::KeepNative => return Some(Pick::Native(index)),
+                PythonExhausted::DropBoundary => {}
+            }
+        }
+
+        // Python frames outnumbering the boundaries
+        match self.on_extra_python_frames {
+            ExtraPythonFrames::Append | ExtraPythonFrames::InterleaveAtLastBoundary => {
+                (self.python_pos < self.python_len).then(|| {
+                    self.appended += 1;
+                    self.next_python()
+                })
+            }
+            ExtraPythonFrames::Drop | ExtraPythonFrames::Error => None,
+        }
+    }
+}
+
+/// Merged stack as references into the python and native inputs, leaf first, and the
+/// truncation marker of a stack cut by `MergeOptions::max_frames`.
+pub struct MergeIter<'a> {
+    python: &'a [CallFrame],
+    python_order: StackOrder,
+    native: &'a [CallFrame],
+    native_order: StackOrder,
+    picks: Picks<'a>,
+    limit: usize,
+    emitted: usize,
+}
+
+impl<'a> Iterator for MergeIter<'a> {
+    type Item = Cow<'a, CallFrame>;
+
+    fn next(&mut self) -> Option<Cow<'a, CallFrame>> {
+        let pick = self.picks.next()?;
+        if self.emitted == self.limit {
+            let truncated = 1 + self.picks.by_ref().count();
+            return Some(Cow::Owned(truncation_marker(truncated)));
+        }
+        self.emitted += 1;
+        Some(Cow::Borrowed(match pick {
+            Pick::Native(i) => &self.native[self.native_order.leaf_index(self.native.len(), i)],
+            Pick::Python(i) => &self.python[self.python_order.leaf_index(self.python.len(), i)],
+        }))
+    }
+}
+
+impl SignalTracer {
+    /// Borrowing counterpart of `merge_python_native_stacks_with`: same order, no heap
+    /// allocation and no cloning, except for the marker of a stack cut by `max_frames`.
+    ///
+    /// Inlined calls recorded on native frames are neither expanded nor checked for
+    /// boundaries; use `CallFrame::expand_inlined` first when they matter.
+    pub fn merge_iter<'a>(
+        python_stacks: &'a [CallFrame],
+        native_stacks: &'a [CallFrame],
+        options: &'a MergeOptions,
+    ) -> MergeIter<'a> {
+        MergeIter {
+            python: python_stacks,
+            python_order: options.python_stack_order(),
+            native: native_stacks,
+            native_order: options.native_stack_order(),
+            picks: Picks::new(
+                python_stacks,
+                options.python_stack_order(),
+                native_stacks,
+                options.native_stack_order(),
+                options.detector(),
+                options,
+            ),
+            limit: options.frame_limit().unwrap_or(usize::MAX),
+            emitted: 0,
+        }
+    }
+
+    /// Merge into a fixed-capacity buffer (appending to what it already holds).
+    ///
+    /// Returns how many merged frames did not fit; those are the outermost ones.
+    pub fn merge_into<'a, const N: usize>(
+        python_stacks: &'a [CallFrame],
+        native_stacks: &'a [CallFrame],
+        options: &'a MergeOptions,
+        out: &mut ArrayVec<Cow<'a, CallFrame>, N>,
+    ) -> usize {
+        let mut merged = Self::merge_iter(python_stacks, native_stacks, options);
+        for frame in merged.by_ref() {
+            if out.try_push(frame).is_err() {
+                return 1 + merged.count();
+            }
+        }
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cframe(name: &str) -> CallFrame {
+        CallFrame::native("0x0", "", name, 0)
+    }
+
+    fn pyframe(name: &str) -> CallFrame {
+        CallFrame::python("0x0", "", name, 0)
+    }
+
+    fn func(frame: &CallFrame) -> &str {
+        frame.func()
+    }
+
+    fn stacks() -> (Vec<CallFrame>, Vec<CallFrame>) {
+        let native = vec![
+            cframe("A"),
+            cframe("PyEval_EvalFrameDefault"),
+            cframe("B"),
+            cframe("PyEval_EvalFrameDefault"),
+            cframe("PyEval_EvalFrameDefault"),
+        ];
+        (vec![pyframe("py1"), pyframe("py2")], native)
+    }
+
+    #[test]
+    fn test_merge_iter_matches_owned_merge() {
+        let (mut python, native) = stacks();
+        // One more python frame than there are boundaries.
+        python.extend([pyframe("py3"), pyframe("py4")]);
+        let all_options = [
+            MergeOptions::new(),
+            MergeOptions::new().keep_boundary_frames(true),
+            MergeOptions::new().on_python_exhausted(PythonExhausted::DropBoundary),
+            MergeOptions::new().on_extra_python_frames(ExtraPythonFrames::Drop),
+            MergeOptions::new()
+                .keep_boundary_frames(true)
+                .on_extra_python_frames(ExtraPythonFrames::InterleaveAtLastBoundary),
+        ];
+        for options in &all_options {
+            let borrowed: Vec<CallFrame> = SignalTracer::merge_iter(&python, &native, options)
+                .map(Cow::into_owned)
+                .collect();
+            let owned = SignalTracer::merge_python_native_stacks_with(
+                python.clone(),
+                native.clone(),
+                options,
+            );
+            assert_eq!(borrowed, owned, "{:?}", options);
+        }
+    }
+
+    #[test]
+    fn test_merge_iter_root_first_inputs() {
+        let (python, native) = stacks();
+        let expected: Vec<CallFrame> =
+            SignalTracer::merge_iter(&python, &native, &MergeOptions::new())
+                .map(Cow::into_owned)
+                .collect();
+
+        let (mut rev_python, mut rev_native) = (python.clone(), native.clone());
+        rev_python.reverse();
+        rev_native.reverse();
+        let options = MergeOptions::new()
+            .python_order(StackOrder::RootFirst)
+            .native_order(StackOrder::RootFirst);
+        let borrowed: Vec<CallFrame> = SignalTracer::merge_iter(&rev_python, &rev_native, &options)
+            .map(Cow::into_owned)
+            .collect();
+        assert_eq!(borrowed, expected);
+        let owned = SignalTracer::merge_python_native_stacks_with(rev_python, rev_native, &options);
+        assert_eq!(owned, expected);
+    }
+
+    #[test]
+    fn test_merge_into_reports_overflow() {
+        let (python, native) = stacks();
+        let options = MergeOptions::new();
+
+        let mut out: ArrayVec<Cow<CallFrame>, 8> = ArrayVec::new();
+        assert_eq!(
+            SignalTracer::merge_into(&python, &native, &options, &mut out),
+            0
+        );
+        let funcs: Vec<&str> = out.iter().map(|f| func(f)).collect();
+        assert_eq!(funcs, ["A", "py1", "B", "py2", "PyEval_EvalFrameDefault"]);
+
+        let mut small: ArrayVec<Cow<CallFrame>, 3> = ArrayVec::new();
+        assert_eq!(
+            SignalTracer::merge_into(&python, &native, &options, &mut small),
+            2
+        );
+        assert_eq!(func(&small[2]), "B");
+
+        let limited = MergeOptions::new().max_frames(3);
+        let mut out: ArrayVec<Cow<CallFrame>, 8> = ArrayVec::new();
+        SignalTracer::merge_into(&python, &native, &limited, &mut out);
+        let funcs: Vec<&str> = out.iter().map(|f| func(f)).collect();
+        assert_eq!(funcs, ["A", "py1", "B", "[truncated 2 frames]"]);
+    }
+
+    #[test]
+    fn test_merge_iter_max_frames_matches_owned_merge() {
+        let (python, native) = stacks();
+        for max in 0..=6 {
+            let options = MergeOptions::new().max_frames(max);
+            let borrowed: Vec<CallFrame> = SignalTracer::merge_iter(&python, &native, &options)
+                .map(Cow::into_owned)
+                .collect();
+            let owned = SignalTracer::merge_python_native_stacks_with(
+                python.clone(),
+                native.clone(),
+                &options,
+            );
+            assert_eq!(borrowed, owned, "max_frames({})", max);
+        }
+    }
+}
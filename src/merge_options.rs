@@ -0,0 +1,231 @@
+//! Tunable knobs for `SignalTracer::merge_python_native_stacks_with`.
+//! Defaults reproduce the behavior of `merge_python_native_stacks`.
+
+use std::fmt;
+
+use crate::boundary::{BoundaryDetector, CPythonBoundaryDetector};
+use crate::stack_order::StackOrder;
+use crate::CallFrame;
+
+/// What to do with a boundary frame once all python frames have been consumed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PythonExhausted {
+    /// Keep the native boundary frame so native context is not lost (default).
+    #[default]
+    KeepNative,
+    /// Drop the unmatched boundary frame from the merged stack.
+    DropBoundary,
+}
+
+/// What to do with python frames left over once every boundary has been used.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExtraPythonFrames {
+    /// Append them after the outermost native frame (default).
+    #[default]
+    Append,
+    /// Leave them out of the merged stack.
+    Drop,
+    /// Insert them all at the outermost boundary, where an eval loop running several
+    /// python frames would have been. Falls back to `Append` without any boundary.
+    InterleaveAtLastBoundary,
+    /// Make `try_merge_python_native_stacks_with` fail; infallible merges drop them.
+    Error,
+}
+
+/// Options controlling how python frames are spliced into a native stack.
+///
+/// Built with chained setters:
+///
+/// ```
+/// use mixed_stack_tracer::{MergeOptions, PythonExhausted};
+///
+/// let opts = MergeOptions::new()
+///     .keep_boundary_frames(true)
+///     .on_python_exhausted(PythonExhausted::DropBoundary);
+/// assert!(opts.keeps_boundary_frames());
+/// ```
+#[derive(Default)]
+pub struct MergeOptions {
+    boundary_detector: Option<Box<dyn BoundaryDetector>>,
+    keep_boundary_frames: bool,
+    on_python_exhausted: PythonExhausted,
+    on_extra_python_frames: ExtraPythonFrames,
+    python_order: StackOrder,
+    native_order: StackOrder,
+    validate_order: bool,
+    frames_per_boundary: Option<Box<FramesPerBoundary>>,
+    python_version: Option<(u8, u8)>,
+    max_frames: Option<usize>,
+}
+
+/// Callback for `MergeOptions::python_frames_per_boundary`.
+pub type FramesPerBoundary = dyn Fn(usize) -> usize + Send + Sync;
+
+impl MergeOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace the default PyEval heuristic with a custom boundary predicate.
+    pub fn boundary_predicate<F>(self, predicate: F) -> Self
+    where
+        F: Fn(&CallFrame) -> bool + Send + Sync + 'static,
+    {
+        self.boundary_detector(predicate)
+    }
+
+    /// Replace the default `CPythonBoundaryDetector` with another detector.
+    pub fn boundary_detector<D>(mut self, detector: D) -> Self
+    where
+        D: BoundaryDetector + 'static,
+    {
+        self.boundary_detector = Some(Box::new(detector));
+        self
+    }
+
+    /// Keep the native boundary frame and insert the python frame right after it,
+    /// instead of replacing the boundary frame.
+    pub fn keep_boundary_frames(mut self, keep: bool) -> Self {
+        self.keep_boundary_frames = keep;
+        self
+    }
+
+    /// Behavior for boundaries encountered after the python frames ran out.
+    pub fn on_python_exhausted(mut self, policy: PythonExhausted) -> Self {
+        self.on_python_exhausted = policy;
+        self
+    }
+
+    /// Behavior for python frames that outnumber the boundaries.
+    pub fn on_extra_python_frames(mut self, policy: ExtraPythonFrames) -> Self {
+        self.on_extra_python_frames = policy;
+        self
+    }
+
+    /// Order of the python stack passed to the merge (leaf first by default).
+    pub fn python_order(mut self, order: StackOrder) -> Self {
+        self.python_order = order;
+        self
+    }
+
+    /// Order of the native stack passed to the merge (leaf first by default).
+    pub fn native_order(mut self, order: StackOrder) -> Self {
+        self.native_order = order;
+        self
+    }
+
+    /// Make `try_merge_python_native_stacks_with` reject inputs that look reversed
+    /// relative to their declared order (see `SignalTracer::validate_stack_order`).
+    pub fn validate_order(mut self, validate: bool) -> Self {
+        self.validate_order = validate;
+        self
+    }
+
+    /// Consume a run of python frames per boundary instead of exactly one.
+    ///
+    /// `count(n)` gets the number of the boundary (0 for the leaf-most one that receives
+    /// python frames) and returns how many python frames it evaluates; 0 keeps it as a
+    /// plain native frame. Useful when the caller knows the interpreter's entry frames.
+    pub fn python_frames_per_boundary<F>(mut self, count: F) -> Self
+    where
+        F: Fn(usize) -> usize + Send + Sync + 'static,
+    {
+        self.frames_per_boundary = Some(Box::new(count));
+        self
+    }
+
+    /// Version of the interpreter that produced the python stack.
+    ///
+    /// From 3.11 on, python-to-python calls no longer go through a C eval frame, so there
+    /// are usually fewer boundaries than python frames. Without a
+    /// `python_frames_per_boundary` callback, the outermost boundary then evaluates every
+    /// frame left over by the inner ones, where plain python call chains run.
+    pub fn python_version_hint(mut self, major: u8, minor: u8) -> Self {
+        self.python_version = Some((major, minor));
+        self
+    }
+
+    /// Keep at most `max` frames of the merged stack, leaf first, and end it with a
+    /// `[truncated N frames]` marker for the others (see `stack_trace::truncate_frames`).
+    pub fn max_frames(mut self, max: usize) -> Self {
+        self.max_frames = Some(max);
+        self
+    }
+
+    /// Detector used for boundary frames (`CPythonBoundaryDetector` unless overridden).
+    pub fn detector(&self) -> &dyn BoundaryDetector {
+        match &self.boundary_detector {
+            Some(detector) => detector.as_ref(),
+            None => &CPythonBoundaryDetector,
+        }
+    }
+
+    pub fn keeps_boundary_frames(&self) -> bool {
+        self.keep_boundary_frames
+    }
+
+    pub fn python_exhausted_policy(&self) -> PythonExhausted {
+        self.on_python_exhausted
+    }
+
+    pub fn extra_python_frames_policy(&self) -> ExtraPythonFrames {
+        self.on_extra_python_frames
+    }
+
+    pub fn python_stack_order(&self) -> StackOrder {
+        self.python_order
+    }
+
+    pub fn native_stack_order(&self) -> StackOrder {
+        self.native_order
+    }
+
+    pub fn validates_order(&self) -> bool {
+        self.validate_order
+    }
+
+    /// The `python_frames_per_boundary` callback, if any.
+    pub fn frames_per_boundary(&self) -> Option<&FramesPerBoundary> {
+        self.frames_per_boundary.as_deref()
+    }
+
+    pub fn python_version(&self) -> Option<(u8, u8)> {
+        self.python_version
+    }
+
+    /// The `max_frames` limit, if any.
+    pub fn frame_limit(&self) -> Option<usize> {
+        self.max_frames
+    }
+
+    /// Whether the outermost boundary takes all remaining python frames, either by policy
+    /// or because of the version hint.
+    pub(crate) fn fills_last_boundary(&self) -> bool {
+        self.on_extra_python_frames == ExtraPythonFrames::InterleaveAtLastBoundary
+            || (self.frames_per_boundary.is_none()
+                && self.python_version.is_some_and(|v| v >= (3, 11)))
+    }
+}
+
+impl fmt::Debug for MergeOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MergeOptions")
+            .field(
+                "custom_boundary_detector",
+                &self.boundary_detector.is_some(),
+            )
+            .field("keep_boundary_frames", &self.keep_boundary_frames)
+            .field("on_python_exhausted", &self.on_python_exhausted)
+            .field("on_extra_python_frames", &self.on_extra_python_frames)
+            .field("python_order", &self.python_order)
+            .field("native_order", &self.native_order)
+            .field("validate_order", &self.validate_order)
+            .field(
+                "custom_frames_per_boundary",
+                &self.frames_per_boundary.is_some(),
+            )
+            .field("python_version", &self.python_version)
+            .field("max_frames", &self.max_frames)
+            .finish()
+    }
+}
@@ -0,0 +1,310 @@
+//! Hang triage: is a process making progress, or are its stacks frozen?
+//!
+//! `StackMonitor::is_progressing` takes a few snapshots of every thread, spaced by an
+//! interval, and compares them. A thread whose merged stack never changed is stuck for the
+//! whole span; otherwise the frames it kept from first to last snapshot show where it has
+//! been spending that time.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use mixed_stack_tracer::monitor::StackMonitor;
+//!
+//! let report = StackMonitor::is_progressing(4242, Duration::from_secs(2), 5)?;
+//! println!("{}", report);
+//! # Ok::<(), mixed_stack_tracer::TracerError>(())
+//! ```
+//!
+//! Stacks are compared like `StackHash` does, ignoring ips, so a loop spinning inside one
+//! native function looks stuck, while a Python loop moving between lines does not.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::error::TracerError;
+use crate::stack_hash::StackHash;
+use crate::thread_stack::{ThreadId, ThreadStack};
+use crate::CallFrame;
+
+/// How one thread's stack evolved across the snapshots it appeared in.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ThreadProgress {
+    pub tid: ThreadId,
+    pub name: String,
+    /// Snapshots the thread appeared in.
+    pub samples: usize,
+    /// Whether its stack differed between any two of them.
+    pub changed: bool,
+    /// How long the stack has been the same, up to the last snapshot; zero when the last
+    /// two snapshots differ.
+    pub stuck_for: Duration,
+    /// Frames, leaf first, present at the root of the stack in every snapshot: the whole
+    /// stack of an unchanged thread.
+    pub stuck_frames: Vec<CallFrame>,
+}
+
+/// Outcome of `StackMonitor::is_progressing`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProgressReport {
+    /// Whether the stack of any thread changed.
+    pub progressing: bool,
+    /// Time between the first and the last snapshot.
+    pub elapsed: Duration,
+    /// Sorted by tid.
+    pub threads: Vec<ThreadProgress>,
+}
+
+impl ProgressReport {
+    /// Threads whose stack never changed, over at least two snapshots.
+    pub fn stuck_threads(&self) -> impl Iterator<Item = &ThreadProgress> {
+        self.threads.iter().filter(|t| !t.changed && t.samples > 1)
+    }
+}
+
+impl fmt::Display for ProgressReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let changed = self.threads.iter().filter(|t| t.changed).count();
+        writeln!(
+            f,
+            "{}: {} of {} threads changed in {:.1}s",
+            if self.progressing {
+                "progressing"
+            } else {
+                "not progressing"
+            },
+            changed,
+            self.threads.len(),
+            self.elapsed.as_secs_f64()
+        )?;
+        for thread in self.stuck_threads() {
+            let leaf = thread
+                .stuck_frames
+                .first()
+                .map_or("<empty>", CallFrame::func);
+            writeln!(
+                f,
+                "  thread {} \"{}\" unchanged for {:.1}s in {}",
+                thread.tid,
+                thread.name,
+                thread.stuck_for.as_secs_f64(),
+                leaf
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Snapshot comparison for hang triage.
+pub struct StackMonitor;
+
+impl StackMonitor {
+    /// Take `samples` snapshots of every thread of process `pid`, `interval` apart, and
+    /// compare them (see `compare`). The calling process is captured in-process; other
+    /// processes through `RemoteProcess`, which needs ptrace permission on them.
+    pub fn is_progressing(
+        pid: i32,
+        interval: Duration,
+        samples: usize,
+    ) -> Result<ProgressReport, TracerError> {
+        let mut snapshots = Vec::with_capacity(samples);
+        let start = Instant::now();
+        let capture = capturer(pid)?;
+        for i in 0..samples.max(2) {
+            if i > 0 {
+                thread::sleep(interval);
+            }
+            snapshots.push((start.elapsed(), capture()?));
+        }
+        Ok(Self::compare(&snapshots))
+    }
+
+    /// Compare snapshots of all threads, each with the time it was taken at, oldest first.
+    pub fn compare(snapshots: &[(Duration, Vec<ThreadStack>)]) -> ProgressReport {
+        // Per thread: name, and the time and hashed stack of every snapshot it is in.
+        let mut history: BTreeMap<ThreadId, (&str, Vec<Seen<'_>>)> = BTreeMap::new();
+        for (at, stacks) in snapshots {
+            for stack in stacks.iter().filter(|s| !s.greenlet) {
+                let entry = history
+                    .entry(stack.tid)
+                    .or_insert((&stack.name, Vec::new()));
+                entry
+                    .1
+                    .push((*at, StackHash::of(&stack.frames), &stack.frames));
+            }
+        }
+
+        let threads: Vec<ThreadProgress> = history
+            .into_iter()
+            .map(|(tid, (name, seen))| {
+                let (last_at, last_hash, _) = *seen.last().expect("seen at least once");
+                let changed = seen.iter().any(|(_, hash, _)| *hash != last_hash);
+                // Start of the trailing run of identical stacks.
+                let run_start = seen
+                    .iter()
+                    .rev()
+                    .take_while(|(_, hash, _)| *hash == last_hash)
+                    .last()
+                    .map_or(last_at, |(at, _, _)| *at);
+                ThreadProgress {
+                    tid,
+                    name: name.to_string(),
+                    samples: seen.len(),
+                    changed,
+                    stuck_for: last_at - run_start,
+                    stuck_frames: common_root(seen.iter().map(|(_, _, frames)| *frames)),
+                }
+            })
+            .collect();
+        let elapsed = match (snapshots.first(), snapshots.last()) {
+            (Some((first, _)), Some((last, _))) => *last - *first,
+            _ => Duration::ZERO,
+        };
+        ProgressReport {
+            progressing: threads.iter().any(|t| t.changed),
+            elapsed,
+            threads,
+        }
+    }
+}
+
+/// A thread in one snapshot: when, and its stack hashed and as captured.
+type Seen<'a> = (Duration, StackHash, &'a [CallFrame]);
+
+/// Captures all threads of one process.
+type Capture = Box<dyn Fn() -> Result<Vec<ThreadStack>, TracerError>>;
+
+/// Capture function for the threads of `pid`.
+fn capturer(pid: i32) -> Result<Capture, TracerError> {
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    if pid == std::process::id() as i32 {
+        return Ok(Box::new(
+            crate::stack_tracer::SignalTracer::capture_all_threads,
+        ));
+    }
+    let process = crate::remote::RemoteProcess::attach(pid)?;
+    Ok(Box::new(move || process.dump()))
+}
+
+/// The frames, leaf first, that every stack of `stacks` ends with at its root.
+fn common_root<'a>(mut stacks: impl Iterator<Item = &'a [CallFrame]>) -> Vec<CallFrame> {
+    let Some(first) = stacks.next() else {
+        return Vec::new();
+    };
+    let mut common = first.len();
+    for stack in stacks {
+        common = first
+            .iter()
+            .rev()
+            .zip(stack.iter().rev())
+            .take(common)
+            .take_while(|(a, b)| same_frame(a, b))
+            .count();
+    }
+    first[first.len() - common..].to_vec()
+}
+
+fn same_frame(a: &CallFrame, b: &CallFrame) -> bool {
+    StackHash::of(std::slice::from_ref(a)) == StackHash::of(std::slice::from_ref(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::thread_stack::ThreadState;
+
+    fn thread(tid: ThreadId, funcs: &[&str]) -> ThreadStack {
+        // funcs are given root first for readability
+        ThreadStack {
+            tid,
+            name: format!("t{}", tid),
+            os_state: ThreadState::Sleeping,
+            is_gil_holder: false,
+            greenlet: false,
+            interpreter_id: None,
+            frames: funcs
+                .iter()
+                .rev()
+                .map(|f| CallFrame::native("0x0", "", *f, 0))
+                .collect(),
+        }
+    }
+
+    fn funcs(frames: &[CallFrame]) -> Vec<&str> {
+        frames.iter().map(CallFrame::func).collect()
+    }
+
+    #[test]
+    fn test_compare() {
+        let secs = Duration::from_secs;
+        let snapshots = vec![
+            (
+                secs(0),
+                vec![thread(1, &["main", "wait"]), thread(2, &["run", "a"])],
+            ),
+            (
+                secs(1),
+                vec![thread(1, &["main", "wait"]), thread(2, &["run", "b"])],
+            ),
+            (
+                secs(2),
+                vec![
+                    thread(1, &["main", "wait"]),
+                    thread(2, &["run", "b"]),
+                    thread(3, &["new"]),
+                ],
+            ),
+        ];
+        let report = StackMonitor::compare(&snapshots);
+
+        assert!(report.progressing);
+        assert_eq!(report.elapsed, secs(2));
+        let [main, worker, new] = &report.threads[..] else {
+            panic!("{:?}", report.threads);
+        };
+        assert!(!main.changed);
+        assert_eq!(main.stuck_for, secs(2));
+        assert_eq!(funcs(&main.stuck_frames), ["wait", "main"]);
+        assert!(worker.changed);
+        assert_eq!(worker.stuck_for, secs(1));
+        assert_eq!(funcs(&worker.stuck_frames), ["run"]);
+        assert_eq!((new.samples, new.stuck_for), (1, Duration::ZERO));
+
+        let stuck: Vec<ThreadId> = report.stuck_threads().map(|t| t.tid).collect();
+        assert_eq!(stuck, [1]);
+        let text = report.to_string();
+        assert!(
+            text.starts_with("progressing: 1 of 3 threads changed in 2.0s\n"),
+            "{}",
+            text
+        );
+        assert!(text.contains("thread 1 \"t1\" unchanged for 2.0s in wait"));
+
+        let frozen = StackMonitor::compare(&snapshots[..1]);
+        assert!(!frozen.progressing);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_is_progressing_self() {
+        let (sender, receiver) = std::sync::mpsc::channel::<()>();
+        let parked = thread::spawn(move || {
+            let tid = crate::threads::current_tid();
+            let _ = receiver.recv();
+            tid
+        });
+        thread::sleep(Duration::from_millis(50));
+
+        let report =
+            StackMonitor::is_progressing(std::process::id() as i32, Duration::from_millis(20), 3)
+                .unwrap();
+        drop(sender);
+        let tid = parked.join().unwrap();
+
+        let parked = report.threads.iter().find(|t| t.tid == tid).unwrap();
+        assert_eq!(parked.samples, 3);
+        assert!(!parked.changed);
+        assert!(parked.stuck_for >= Duration::from_millis(40));
+        assert!(!parked.stuck_frames.is_empty());
+    }
+}
@@ -0,0 +1,428 @@
+//! Chrome Trace Event JSON export (`chrome://tracing`, Perfetto UI, speedscope).
+//!
+//! Each thread becomes one track. In `EventStyle::Duration` consecutive samples sharing a
+//! stack prefix are turned into nested complete (`"X"`) events, like a flame chart; in
+//! `EventStyle::Instant` every sample is a thread-scoped instant event carrying its stack.
+//! Perfetto imports this JSON as is; no separate Perfetto protobuf writer is provided.
+//!
+//! `write_gil_trace` renders GIL hold and wait intervals the same way, one track per
+//! thread, so that convoys show up as waits lining up behind each hold.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use serde_json::{json, Value};
+
+use crate::gil::{GilEventKind, GilInterval, GIL_CATEGORY};
+use crate::profile::TimedSample;
+use crate::CallFrame;
+
+/// How samples are rendered on a thread's track.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EventStyle {
+    /// Nested duration slices reconstructed from consecutive samples (default).
+    #[default]
+    Duration,
+    /// One instant event per sample, with the merged stack in its args.
+    Instant,
+}
+
+/// Options for the Chrome trace exporter.
+#[derive(Clone, Debug)]
+pub struct TraceOptions {
+    pid: u32,
+    style: EventStyle,
+    sample_interval_ns: Option<u64>,
+}
+
+impl Default for TraceOptions {
+    fn default() -> Self {
+        TraceOptions {
+            pid: std::process::id(),
+            style: EventStyle::default(),
+            sample_interval_ns: None,
+        }
+    }
+}
+
+impl TraceOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Process id written on every event (defaults to the current process).
+    pub fn pid(mut self, pid: u32) -> Self {
+        self.pid = pid;
+        self
+    }
+
+    pub fn style(mut self, style: EventStyle) -> Self {
+        self.style = style;
+        self
+    }
+
+    /// Duration attributed to the last sample of a thread. Defaults to the smallest gap
+    /// seen between consecutive samples of that thread.
+    pub fn sample_interval_ns(mut self, interval: u64) -> Self {
+        self.sample_interval_ns = Some(interval);
+        self
+    }
+}
+
+/// Write `samples` as a Chrome trace JSON object.
+pub fn write_trace<W: Write>(
+    out: &mut W,
+    samples: &[TimedSample],
+    options: &TraceOptions,
+) -> io::Result<()> {
+    let mut threads: BTreeMap<i32, Vec<&TimedSample>> = BTreeMap::new();
+    for sample in samples {
+        threads.entry(sample.tid).or_default().push(sample);
+    }
+
+    let mut events = Vec::new();
+    for (tid, mut samples) in threads {
+        samples.sort_by_key(|s| s.timestamp_ns);
+        events.push(json!({
+            "name": "thread_name",
+            "ph": "M",
+            "pid": options.pid,
+            "tid": tid,
+            "args": { "name": format!("thread {}", tid) },
+        }));
+        match options.style {
+            EventStyle::Duration => duration_events(&mut events, tid, &samples, options),
+            EventStyle::Instant => instant_events(&mut events, tid, &samples, options),
+        }
+    }
+
+    let trace = json!({ "traceEvents": events, "displayTimeUnit": "ms" });
+    serde_json::to_writer(out, &trace).map_err(io::Error::from)
+}
+
+/// Convenience wrapper around `write_trace` returning the JSON text.
+pub fn trace_to_string(samples: &[TimedSample], options: &TraceOptions) -> String {
+    let mut out = Vec::new();
+    write_trace(&mut out, samples, options).expect("writing to a Vec cannot fail");
+    String::from_utf8(out).expect("serde_json emits UTF-8")
+}
+
+fn duration_events(
+    events: &mut Vec<Value>,
+    tid: i32,
+    samples: &[&TimedSample],
+    options: &TraceOptions,
+) {
+    let interval = options.sample_interval_ns.unwrap_or_else(|| {
+        samples
+            .windows(2)
+            .map(|w| w[1].timestamp_ns - w[0].timestamp_ns)
+            .filter(|gap| *gap > 0)
+            .min()
+            .unwrap_or(1_000_000)
+    });
+
+    // Frames currently open on the track, root first, with their start time.
+    let mut open: Vec<(&CallFrame, u64)> = Vec::new();
+    for sample in samples {
+        let stack: Vec<&CallFrame> = sample.frames.iter().rev().collect();
+        let common = open
+            .iter()
+            .zip(&stack)
+            .take_while(|((open, _), frame)| same_frame(open, frame))
+            .count();
+
+        while open.len() > common {
+            let (frame, start) = open.pop().expect("open is longer than common");
+            events.push(complete_event(
+                frame,
+                tid,
+                start,
+                sample.timestamp_ns,
+                options,
+            ));
+        }
+        for frame in &stack[common..] {
+            open.push((frame, sample.timestamp_ns));
+        }
+    }
+
+    if let Some(last) = samples.last() {
+        let end = last.timestamp_ns + interval;
+        while let Some((frame, start)) = open.pop() {
+            events.push(complete_event(frame, tid, start, end, options));
+        }
+    }
+}
+
+fn instant_events(
+    events: &mut Vec<Value>,
+    tid: i32,
+    samples: &[&TimedSample],
+    options: &TraceOptions,
+) {
+    for sample in samples {
+        let name = sample
+            .frames
+            .first()
+            .map(|f| frame_parts(f).1.to_string())
+            .unwrap_or_else(|| "<empty>".to_string());
+        let mut args =
+            json!({ "stack": stack_args(&sample.frames), "state": sample.state.as_str() });
+        if let Some(cpu) = sample.cpu {
+            args["cpu"] = json!(cpu);
+        }
+        if sample.weight_ns > 0 {
+            args["weight_ns"] = json!(sample.weight_ns);
+        }
+        events.push(json!({
+            "name": name,
+            "ph": "i",
+            "s": "t",
+            "pid": options.pid,
+            "tid": tid,
+            "ts": to_us(sample.timestamp_ns),
+            "args": args,
+        }));
+    }
+}
+
+/// Write GIL intervals as a Chrome trace JSON object: one complete event per interval,
+/// named `GIL hold` or `GIL wait`, with the stack it started at in its args.
+pub fn write_gil_trace<W: Write>(
+    out: &mut W,
+    intervals: &[GilInterval],
+    options: &TraceOptions,
+) -> io::Result<()> {
+    let mut names: BTreeMap<i32, &str> = BTreeMap::new();
+    for interval in intervals {
+        let name = names.entry(interval.tid).or_default();
+        if name.is_empty() {
+            *name = &interval.thread_name;
+        }
+    }
+
+    let mut events: Vec<Value> = names
+        .into_iter()
+        .map(|(tid, name)| {
+            let name = if name.is_empty() {
+                format!("thread {}", tid)
+            } else {
+                format!("{} ({})", name, tid)
+            };
+            json!({
+                "name": "thread_name",
+                "ph": "M",
+                "pid": options.pid,
+                "tid": tid,
+                "args": { "name": name },
+            })
+        })
+        .collect();
+    for interval in intervals {
+        let name = match interval.kind {
+            GilEventKind::Hold => "GIL hold",
+            GilEventKind::Wait => "GIL wait",
+        };
+        events.push(json!({
+            "name": name,
+            "cat": GIL_CATEGORY,
+            "ph": "X",
+            "pid": options.pid,
+            "tid": interval.tid,
+            "ts": to_us(interval.start_ns),
+            "dur": to_us(interval.duration_ns()),
+            "args": { "kind": interval.kind.as_str(), "stack": stack_args(&interval.frames) },
+        }));
+    }
+
+    let trace = json!({ "traceEvents": events, "displayTimeUnit": "ms" });
+    serde_json::to_writer(out, &trace).map_err(io::Error::from)
+}
+
+/// Root-first `func (file:line) [kind]` lines of leaf-first `frames`.
+fn stack_args(frames: &[CallFrame]) -> Vec<String> {
+    frames
+        .iter()
+        .rev()
+        .map(|f| {
+            let (kind, func, file, lineno) = frame_parts(f);
+            format!("{} ({}:{}) [{}]", func, file, lineno, kind)
+        })
+        .collect()
+}
+
+fn complete_event(
+    frame: &CallFrame,
+    tid: i32,
+    start_ns: u64,
+    end_ns: u64,
+    options: &TraceOptions,
+) -> Value {
+    let (kind, func, file, lineno) = frame_parts(frame);
+    json!({
+        "name": func,
+        "cat": kind,
+        "ph": "X",
+        "pid": options.pid,
+        "tid": tid,
+        "ts": to_us(start_ns),
+        "dur": to_us(end_ns - start_ns),
+        "args": { "file": file, "line": lineno },
+    })
+}
+
+fn frame_parts(frame: &CallFrame) -> (&'static str, &str, &str, i64) {
+    (
+        frame.kind().as_str(),
+        frame.func(),
+        frame.file(),
+        frame.lineno(),
+    )
+}
+
+/// Slices continue across samples as long as kind, function and file stay the same.
+fn same_frame(a: &CallFrame, b: &CallFrame) -> bool {
+    let (a_kind, a_func, a_file, _) = frame_parts(a);
+    let (b_kind, b_func, b_file, _) = frame_parts(b);
+    a_kind == b_kind && a_func == b_func && a_file == b_file
+}
+
+fn to_us(ns: u64) -> f64 {
+    ns as f64 / 1000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(tid: i32, ms: u64, funcs: &[&str]) -> TimedSample {
+        // funcs are given root first for readability
+        TimedSample {
+            tid,
+            timestamp_ns: ms * 1_000_000,
+            frames: funcs
+                .iter()
+                .rev()
+                .map(|f| CallFrame::native("0x0", "", *f, 0))
+                .collect(),
+            ..TimedSample::default()
+        }
+    }
+
+    fn slices(trace: &Value) -> Vec<(String, f64, f64)> {
+        let mut slices: Vec<_> = trace["traceEvents"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|e| e["ph"] == "X")
+            .map(|e| {
+                (
+                    e["name"].as_str().unwrap().to_string(),
+                    e["ts"].as_f64().unwrap(),
+                    e["dur"].as_f64().unwrap(),
+                )
+            })
+            .collect();
+        slices.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap().then(a.0.cmp(&b.0)));
+        slices
+    }
+
+    #[test]
+    fn test_duration_events_nest() {
+        let samples = vec![
+            sample(1, 0, &["main", "a"]),
+            sample(1, 1, &["main", "a"]),
+            sample(1, 2, &["main", "b"]),
+        ];
+        let opts = TraceOptions::new().pid(7);
+        let trace: Value = serde_json::from_str(&trace_to_string(&samples, &opts)).unwrap();
+
+        assert_eq!(
+            slices(&trace),
+            vec![
+                ("a".to_string(), 0.0, 2000.0),
+                ("main".to_string(), 0.0, 3000.0),
+                ("b".to_string(), 2000.0, 1000.0),
+            ]
+        );
+        let meta = &trace["traceEvents"][0];
+        assert_eq!(meta["ph"], "M");
+        assert_eq!(meta["pid"], 7);
+        assert_eq!(meta["args"]["name"], "thread 1");
+    }
+
+    #[test]
+    fn test_tracks_per_thread() {
+        let samples = vec![sample(2, 5, &["worker"]), sample(1, 0, &["main"])];
+        let opts = TraceOptions::new().sample_interval_ns(500_000);
+        let trace: Value = serde_json::from_str(&trace_to_string(&samples, &opts)).unwrap();
+
+        let tids: Vec<i64> = trace["traceEvents"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|e| e["ph"] == "X")
+            .map(|e| e["tid"].as_i64().unwrap())
+            .collect();
+        assert_eq!(tids, vec![1, 2]);
+        assert_eq!(slices(&trace)[0], ("main".to_string(), 0.0, 500.0));
+    }
+
+    #[test]
+    fn test_instant_events() {
+        let samples = vec![TimedSample {
+            cpu: Some(2),
+            weight_ns: 10_000_000,
+            ..sample(1, 3, &["main", "leaf"])
+        }];
+        let opts = TraceOptions::new().style(EventStyle::Instant);
+        let trace: Value = serde_json::from_str(&trace_to_string(&samples, &opts)).unwrap();
+
+        let event = &trace["traceEvents"][1];
+        assert_eq!(event["ph"], "i");
+        assert_eq!(event["name"], "leaf");
+        assert_eq!(event["ts"], 3000.0);
+        assert_eq!(
+            event["args"]["stack"],
+            json!(["main (:0) [native]", "leaf (:0) [native]"])
+        );
+        assert_eq!(event["args"]["state"], "on_cpu");
+        assert_eq!(event["args"]["cpu"], 2);
+        assert_eq!(event["args"]["weight_ns"], 10_000_000);
+    }
+
+    #[test]
+    fn test_gil_trace() {
+        let interval = |tid, kind, start_ms: u64, end_ms: u64| GilInterval {
+            tid,
+            thread_name: if tid == 1 {
+                "MainThread".to_string()
+            } else {
+                String::new()
+            },
+            kind,
+            start_ns: start_ms * 1_000_000,
+            end_ns: end_ms * 1_000_000,
+            frames: vec![CallFrame::python("0x1", "a.py", "step", 3)],
+        };
+        let intervals = vec![
+            interval(1, GilEventKind::Hold, 0, 5),
+            interval(2, GilEventKind::Wait, 1, 5),
+        ];
+        let mut out = Vec::new();
+        write_gil_trace(&mut out, &intervals, &TraceOptions::new().pid(7)).unwrap();
+        let trace: Value = serde_json::from_slice(&out).unwrap();
+
+        let events = trace["traceEvents"].as_array().unwrap();
+        assert_eq!(events[0]["args"]["name"], "MainThread (1)");
+        assert_eq!(events[1]["args"]["name"], "thread 2");
+        let wait = &events[3];
+        assert_eq!(wait["name"], "GIL wait");
+        assert_eq!(wait["cat"], "gil");
+        assert_eq!(wait["tid"], 2);
+        assert_eq!(wait["ts"], 1000.0);
+        assert_eq!(wait["dur"], 4000.0);
+        assert_eq!(wait["args"]["stack"], json!(["step (a.py:3) [python]"]));
+    }
+}
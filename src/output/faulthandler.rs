@@ -0,0 +1,119 @@
+//! Thread dumps in the text format of CPython's `faulthandler`, native frames included.
+//!
+//! ```text
+//! Current thread 0x00000000000004d2 (most recent call first):
+//!   Binary file "/lib/libc.so.6", at clock_nanosleep [0x10]
+//!   File "app.py", line 3 in run
+//!   Binary file "/usr/bin/python3", at main [0x40]
+//! ```
+//!
+//! Python frames are printed exactly as `faulthandler` prints them and native frames as
+//! its C stack dump (3.14) does, so parsers of fault logs keep finding the former and
+//! skip or pick up the latter. `crash_handler::enable` writes the same format from a
+//! signal handler.
+
+use std::io::{self, Write};
+
+use crate::thread_stack::{ThreadId, ThreadStack};
+use crate::CallFrame;
+
+/// Write one block per thread, separated by blank lines; `current` is announced as
+/// `Current thread`, as `faulthandler` does for the faulting one.
+pub fn write_thread_stacks<W: Write>(
+    out: &mut W,
+    stacks: &[ThreadStack],
+    current: Option<ThreadId>,
+) -> io::Result<()> {
+    for (i, stack) in stacks.iter().enumerate() {
+        if i > 0 {
+            writeln!(out)?;
+        }
+        let label = if current == Some(stack.tid) {
+            "Current thread"
+        } else {
+            "Thread"
+        };
+        writeln!(
+            out,
+            "{} 0x{:016x} (most recent call first):",
+            label, stack.tid as u64
+        )?;
+        write_frames(out, &stack.frames)?;
+    }
+    Ok(())
+}
+
+/// Write one line per frame, leaf first, or `  <no Python frame>` for an empty stack.
+pub fn write_frames<W: Write>(out: &mut W, frames: &[CallFrame]) -> io::Result<()> {
+    if frames.is_empty() {
+        return writeln!(out, "  <no Python frame>");
+    }
+    for frame in frames {
+        match frame {
+            CallFrame::PyFrame {
+                file, func, lineno, ..
+            } => writeln!(out, "  File \"{}\", line {} in {}", file, lineno, func)?,
+            CallFrame::CFrame { ip, file, func, .. } => match (file.is_empty(), func.as_str()) {
+                (true, "??" | "") => writeln!(out, "  <unknown> at {}", ip)?,
+                (false, "??" | "") => writeln!(out, "  Binary file \"{}\" [{}]", file, ip)?,
+                _ => writeln!(out, "  Binary file \"{}\", at {} [{}]", file, func, ip)?,
+            },
+            CallFrame::InterpreterFrame {
+                runtime,
+                file,
+                func,
+                lineno,
+            } => writeln!(
+                out,
+                "  {} file \"{}\", line {} in {}",
+                runtime, file, lineno, func
+            )?,
+            CallFrame::GpuFrame { kernel, .. } => writeln!(out, "  GPU kernel \"{}\"", kernel)?,
+            CallFrame::Synthetic { label, .. } => writeln!(out, "  <{}>", label)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::thread_stack::ThreadState;
+
+    #[test]
+    fn test_write_thread_stacks() {
+        let stack = |tid, frames| ThreadStack {
+            tid,
+            name: String::new(),
+            os_state: ThreadState::Running,
+            is_gil_holder: false,
+            greenlet: false,
+            interpreter_id: None,
+            frames,
+        };
+        let stacks = [
+            stack(
+                1234,
+                vec![
+                    CallFrame::native("0x10", "/lib/libc.so.6", "clock_nanosleep", 0),
+                    CallFrame::python("0x0", "app.py", "run", 3),
+                    CallFrame::native("0x30", "", "??", 0),
+                ],
+            ),
+            stack(1240, Vec::new()),
+        ];
+        let mut out = Vec::new();
+        write_thread_stacks(&mut out, &stacks, Some(1234)).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Current thread 0x00000000000004d2 (most recent call first):
+  Binary file \"/lib/libc.so.6\", at clock_nanosleep [0x10]
+  File \"app.py\", line 3 in run
+  <unknown> at 0x30
+
+Thread 0x00000000000004d8 (most recent call first):
+  <no Python frame>
+"
+        );
+    }
+}
@@ -0,0 +1,277 @@
+//! Brendan Gregg's folded stack format (`root;caller;leaf count`), as consumed by
+//! `flamegraph.pl` and inferno.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::frame_filter::count_repeats;
+use crate::profile::Profile;
+use crate::{CallFrame, FrameKind};
+
+/// Suffix appended to Python frames when kinds are annotated.
+pub const PYTHON_SUFFIX: &str = "_[py]";
+/// Suffix appended to native frames when kinds are annotated.
+pub const NATIVE_SUFFIX: &str = "_[native]";
+/// Suffix appended to GPU frames when kinds are annotated.
+pub const GPU_SUFFIX: &str = "_[gpu]";
+/// Suffix marking frames of other interpreted runtimes.
+pub const INTERPRETER_SUFFIX: &str = "_[interp]";
+
+/// Controls how frames are rendered into folded lines.
+#[derive(Clone, Debug, Default)]
+pub struct FoldedOptions {
+    include_location: bool,
+    annotate_kind: bool,
+    weights: bool,
+    count_recursion: bool,
+}
+
+impl FoldedOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Render frames as `func (file:line)` instead of just `func`.
+    pub fn include_location(mut self, include: bool) -> Self {
+        self.include_location = include;
+        self
+    }
+
+    /// Append `_[py]` / `_[native]` so flamegraph palettes can tell the two apart.
+    pub fn annotate_kind(mut self, annotate: bool) -> Self {
+        self.annotate_kind = annotate;
+        self
+    }
+
+    /// End profile lines with nanoseconds of weight instead of sample counts, for time
+    /// flame graphs. Stacks without a weight are left out.
+    pub fn weights(mut self, weights: bool) -> Self {
+        self.weights = weights;
+        self
+    }
+
+    /// Collapse recursive call cycles into one, named with the number of calls:
+    /// `main;fib [x30];leaf` (see `FrameFilter::count_recursion`).
+    pub fn count_recursion(mut self, count: bool) -> Self {
+        self.count_recursion = count;
+        self
+    }
+}
+
+/// Fold one merged stack (leaf first, as produced by the merge) into `root;...;leaf`.
+pub fn fold_stack(frames: &[CallFrame], options: &FoldedOptions) -> String {
+    let counted;
+    let frames = if options.count_recursion {
+        counted = count_repeats(frames.to_vec());
+        &counted
+    } else {
+        frames
+    };
+    frames
+        .iter()
+        .rev()
+        .map(|frame| frame_label(frame, options))
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+/// Write a single stack with its sample count as one folded line.
+pub fn write_stack<W: Write>(
+    out: &mut W,
+    frames: &[CallFrame],
+    count: u64,
+    options: &FoldedOptions,
+) -> io::Result<()> {
+    writeln!(out, "{} {}", fold_stack(frames, options), count)
+}
+
+/// Write an aggregated profile, summing identical stacks across threads.
+///
+/// Lines keep the order in which stacks first appear in the profile.
+pub fn write_profile<W: Write>(
+    out: &mut W,
+    profile: &Profile,
+    options: &FoldedOptions,
+) -> io::Result<()> {
+    let mut lines: Vec<(String, u64)> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+    for stack in &profile.stacks {
+        let value = if options.weights {
+            stack.weight_ns
+        } else {
+            stack.count
+        };
+        if options.weights && value == 0 {
+            continue;
+        }
+        let folded = fold_stack(&stack.frames, options);
+        match index.get(&folded) {
+            Some(i) => lines[*i].1 += value,
+            None => {
+                index.insert(folded.clone(), lines.len());
+                lines.push((folded, value));
+            }
+        }
+    }
+
+    for (folded, count) in lines {
+        writeln!(out, "{} {}", folded, count)?;
+    }
+    Ok(())
+}
+
+/// Convenience wrapper around `write_profile` returning the folded text.
+pub fn profile_to_string(profile: &Profile, options: &FoldedOptions) -> String {
+    let mut out = Vec::new();
+    write_profile(&mut out, profile, options).expect("writing to a Vec cannot fail");
+    String::from_utf8(out).expect("folded output is valid UTF-8")
+}
+
+fn frame_label(frame: &CallFrame, options: &FoldedOptions) -> String {
+    let (func, file, lineno) = (frame.func(), frame.file(), frame.lineno());
+    // Synthetic labels are bracketed already and get no suffix.
+    let suffix = match frame.kind() {
+        FrameKind::Native => NATIVE_SUFFIX,
+        FrameKind::Python => PYTHON_SUFFIX,
+        FrameKind::Gpu => GPU_SUFFIX,
+        FrameKind::Interpreter => INTERPRETER_SUFFIX,
+        FrameKind::Synthetic => "",
+    };
+
+    let mut label = sanitize(func);
+    if options.include_location && !file.is_empty() {
+        label.push_str(&format!(" ({}:{})", sanitize(file), lineno));
+    }
+    if options.annotate_kind {
+        label.push_str(suffix);
+    }
+    label
+}
+
+/// `;` separates frames and newlines separate stacks, so neither may appear in a label.
+fn sanitize(name: &str) -> String {
+    name.replace(';', ":").replace(['\n', '\r'], " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::SampledStack;
+
+    fn stack() -> Vec<CallFrame> {
+        // leaf first
+        vec![
+            CallFrame::native("0x1", "lib.c", "leaf", 10),
+            CallFrame::python("0x2", "app.py", "handler", 3),
+            CallFrame::native("0x3", "", "main", 0),
+        ]
+    }
+
+    #[test]
+    fn test_fold_stack_root_first() {
+        let folded = fold_stack(&stack(), &FoldedOptions::new());
+        assert_eq!(folded, "main;handler;leaf");
+    }
+
+    #[test]
+    fn test_fold_stack_with_location_and_kind() {
+        let opts = FoldedOptions::new()
+            .include_location(true)
+            .annotate_kind(true);
+        let folded = fold_stack(&stack(), &opts);
+        assert_eq!(
+            folded,
+            "main_[native];handler (app.py:3)_[py];leaf (lib.c:10)_[native]"
+        );
+    }
+
+    #[test]
+    fn test_fold_synthetic_and_gpu_frames() {
+        let mut frames = stack();
+        frames.insert(0, CallFrame::gpu("add_kernel", Some(0), 1));
+        frames.insert(0, CallFrame::synthetic("[cuda sync]", "cuda"));
+        let folded = fold_stack(&frames, &FoldedOptions::new().annotate_kind(true));
+        assert_eq!(
+            folded,
+            "main_[native];handler_[py];leaf_[native];add_kernel_[gpu];[cuda sync]"
+        );
+    }
+
+    #[test]
+    fn test_fold_stack_counts_recursion() {
+        let fib = |lineno| CallFrame::python("0x2", "fib.py", "fib", lineno);
+        let frames = vec![
+            CallFrame::native("0x1", "lib.c", "leaf", 10),
+            fib(2),
+            fib(3),
+            fib(3),
+            CallFrame::native("0x3", "", "main", 0),
+        ];
+        assert_eq!(
+            fold_stack(&frames, &FoldedOptions::new()),
+            "main;fib;fib;fib;leaf"
+        );
+        let opts = FoldedOptions::new()
+            .count_recursion(true)
+            .include_location(true);
+        assert_eq!(
+            fold_stack(&frames, &opts),
+            "main;fib [x3] (fib.py:2);leaf (lib.c:10)"
+        );
+    }
+
+    #[test]
+    fn test_sanitize_separators() {
+        let frames = vec![CallFrame::native("0x1", "", "a;b\nc", 0)];
+        assert_eq!(fold_stack(&frames, &FoldedOptions::new()), "a:b c");
+    }
+
+    #[test]
+    fn test_write_profile_sums_threads() {
+        let profile = Profile {
+            stacks: vec![
+                SampledStack {
+                    tid: 1,
+                    frames: stack(),
+                    count: 2,
+                    ..SampledStack::default()
+                },
+                SampledStack {
+                    tid: 2,
+                    frames: vec![CallFrame::native("0x3", "", "main", 0)],
+                    count: 1,
+                    ..SampledStack::default()
+                },
+                SampledStack {
+                    tid: 3,
+                    frames: stack(),
+                    count: 5,
+                    ..SampledStack::default()
+                },
+            ],
+            total_samples: 8,
+            dropped_samples: 0,
+        };
+
+        let text = profile_to_string(&profile, &FoldedOptions::new());
+        assert_eq!(text, "main;handler;leaf 7\nmain 1\n");
+    }
+
+    #[test]
+    fn test_write_profile_weights() {
+        let weighted = |count, weight_ns| SampledStack {
+            tid: 1,
+            frames: stack(),
+            count,
+            weight_ns,
+            ..SampledStack::default()
+        };
+        let profile = Profile {
+            stacks: vec![weighted(2, 20_000), weighted(1, 5_000), weighted(1, 0)],
+            total_samples: 4,
+            dropped_samples: 0,
+        };
+        let text = profile_to_string(&profile, &FoldedOptions::new().weights(true));
+        assert_eq!(text, "main;handler;leaf 25000\n");
+    }
+}
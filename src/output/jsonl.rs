@@ -0,0 +1,285 @@
+//! JSON Lines streaming: one captured record per line, written as it is produced.
+//!
+//! Long sessions can be recorded without keeping samples in memory. Records are the
+//! serde forms of `ThreadStack`, `TimedSample`, `StackTrace` (or anything `Serialize`),
+//! tagged with a `record` field so mixed streams stay readable line by line.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use flate2::write::GzEncoder;
+use serde::Serialize;
+
+use crate::profile::TimedSample;
+use crate::stack_trace::StackTrace;
+use crate::thread_stack::ThreadStack;
+
+/// Uncompressed bytes collected before a zstd frame is emitted.
+#[cfg(feature = "zstd")]
+const ZSTD_FRAME_BYTES: usize = 1 << 20;
+
+/// Compression of a JSON Lines stream.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    /// gzip; a file appended to across sessions holds one member per session, which
+    /// `zcat` and `flate2::read::MultiGzDecoder` read back as one stream.
+    Gzip,
+    /// zstd (feature `zstd`), as a sequence of frames of about 1 MiB each plus one per
+    /// `flush`; `zstdcat` reads them back as one stream.
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+enum Sink<W: Write> {
+    Plain(W),
+    Gzip(GzEncoder<W>),
+    #[cfg(feature = "zstd")]
+    Zstd {
+        out: W,
+        pending: Vec<u8>,
+    },
+}
+
+/// Writes records as JSON Lines to `W`.
+///
+/// Call `finish` to complete the compressed stream and get `W` back; dropping the writer
+/// finishes it too but ignores errors.
+pub struct StreamWriter<W: Write> {
+    sink: Option<Sink<W>>,
+    records: u64,
+}
+
+impl StreamWriter<BufWriter<File>> {
+    /// Append to the file at `path`, creating it if needed.
+    pub fn create(path: impl AsRef<Path>, compression: Compression) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self::new(BufWriter::new(file), compression))
+    }
+}
+
+impl<W: Write> StreamWriter<W> {
+    pub fn new(out: W, compression: Compression) -> Self {
+        let sink = match compression {
+            Compression::None => Sink::Plain(out),
+            Compression::Gzip => Sink::Gzip(GzEncoder::new(out, flate2::Compression::default())),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => Sink::Zstd {
+                out,
+                pending: Vec::new(),
+            },
+        };
+        StreamWriter {
+            sink: Some(sink),
+            records: 0,
+        }
+    }
+
+    /// Write `record` as one line, adding `"record": kind` when it serializes to an object.
+    pub fn write_record<T: Serialize>(&mut self, kind: &str, record: &T) -> io::Result<()> {
+        let mut value = serde_json::to_value(record).map_err(io::Error::from)?;
+        if let serde_json::Value::Object(fields) = &mut value {
+            fields.insert("record".to_string(), kind.into());
+        }
+        let mut line = serde_json::to_vec(&value).map_err(io::Error::from)?;
+        line.push(b'\n');
+        self.write_bytes(&line)?;
+        self.records += 1;
+        Ok(())
+    }
+
+    /// Write a captured thread as a `thread` record.
+    pub fn write_thread_stack(&mut self, stack: &ThreadStack) -> io::Result<()> {
+        self.write_record("thread", stack)
+    }
+
+    /// Write a timeline sample as a `sample` record.
+    pub fn write_sample(&mut self, sample: &TimedSample) -> io::Result<()> {
+        self.write_record("sample", sample)
+    }
+
+    /// Write a stack trace as a `trace` record.
+    pub fn write_stack_trace(&mut self, trace: &StackTrace) -> io::Result<()> {
+        self.write_record("trace", trace)
+    }
+
+    /// Records written so far.
+    pub fn records(&self) -> u64 {
+        self.records
+    }
+
+    /// Push everything written so far to `W`, as far as the compression allows.
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self.sink_mut() {
+            Sink::Plain(out) => out.flush(),
+            Sink::Gzip(encoder) => encoder.flush(),
+            #[cfg(feature = "zstd")]
+            Sink::Zstd { out, pending } => {
+                write_zstd_frame(out, pending)?;
+                out.flush()
+            }
+        }
+    }
+
+    /// Complete the stream and return the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        let sink = self.sink.take().expect("sink present until finished");
+        let mut out = match sink {
+            Sink::Plain(out) => out,
+            Sink::Gzip(encoder) => encoder.finish()?,
+            #[cfg(feature = "zstd")]
+            Sink::Zstd {
+                mut out,
+                mut pending,
+            } => {
+                write_zstd_frame(&mut out, &mut pending)?;
+                out
+            }
+        };
+        out.flush()?;
+        Ok(out)
+    }
+
+    fn sink_mut(&mut self) -> &mut Sink<W> {
+        self.sink.as_mut().expect("sink present until finished")
+    }
+
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match self.sink_mut() {
+            Sink::Plain(out) => out.write_all(bytes),
+            Sink::Gzip(encoder) => encoder.write_all(bytes),
+            #[cfg(feature = "zstd")]
+            Sink::Zstd { out, pending } => {
+                pending.extend_from_slice(bytes);
+                if pending.len() >= ZSTD_FRAME_BYTES {
+                    write_zstd_frame(out, pending)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<W: Write> Drop for StreamWriter<W> {
+    fn drop(&mut self) {
+        if self.sink.is_some() {
+            let _ = self.flush();
+        }
+    }
+}
+
+/// Compress `pending` into one zstd frame on `out`.
+#[cfg(feature = "zstd")]
+fn write_zstd_frame<W: Write>(out: &mut W, pending: &mut Vec<u8>) -> io::Result<()> {
+    if pending.is_empty() {
+        return Ok(());
+    }
+    let frame = ruzstd::encoding::compress_to_vec(
+        pending.as_slice(),
+        ruzstd::encoding::CompressionLevel::Fastest,
+    );
+    pending.clear();
+    out.write_all(&frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::SampleState;
+    use crate::thread_stack::ThreadState;
+    use crate::CallFrame;
+    use std::io::Read;
+
+    fn thread(tid: i32) -> ThreadStack {
+        ThreadStack {
+            tid,
+            name: "worker".to_string(),
+            os_state: ThreadState::Running,
+            is_gil_holder: false,
+            greenlet: false,
+            interpreter_id: None,
+            frames: vec![
+                CallFrame::native("0x10", "", "leaf", 0),
+                CallFrame::python("0x20", "app.py", "run", 3),
+            ],
+        }
+    }
+
+    fn parse_lines(text: &str) -> Vec<serde_json::Value> {
+        text.lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_stream_writer_lines() {
+        let mut writer = StreamWriter::new(Vec::new(), Compression::None);
+        writer.write_thread_stack(&thread(1)).unwrap();
+        writer
+            .write_sample(&TimedSample {
+                tid: 2,
+                timestamp_ns: 5,
+                frames: Vec::new(),
+                cpu: Some(3),
+                state: SampleState::OffCpu,
+                weight_ns: 1_000,
+            })
+            .unwrap();
+        assert_eq!(writer.records(), 2);
+        let out = writer.finish().unwrap();
+
+        let lines = parse_lines(std::str::from_utf8(&out).unwrap());
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["record"], "thread");
+        assert_eq!(lines[0]["frames"][1]["kind"], "python");
+        assert_eq!(lines[0]["frames"][1]["func"], "run");
+        assert_eq!(lines[1]["record"], "sample");
+        assert_eq!(lines[1]["timestamp_ns"], 5);
+        assert_eq!(lines[1]["cpu"], 3);
+        assert_eq!(lines[1]["state"], "off_cpu");
+        assert_eq!(lines[1]["weight_ns"], 1_000);
+
+        // Records read back without the tag field.
+        let stack: ThreadStack = serde_json::from_value(lines[0].clone()).unwrap();
+        assert_eq!(stack, thread(1));
+    }
+
+    #[test]
+    fn test_stream_writer_gzip() {
+        let mut writer = StreamWriter::new(Vec::new(), Compression::Gzip);
+        for tid in 0..3 {
+            writer.write_thread_stack(&thread(tid)).unwrap();
+        }
+        let out = writer.finish().unwrap();
+
+        let mut text = String::new();
+        flate2::read::GzDecoder::new(&out[..])
+            .read_to_string(&mut text)
+            .unwrap();
+        let tids: Vec<i64> = parse_lines(&text)
+            .iter()
+            .map(|line| line["tid"].as_i64().unwrap())
+            .collect();
+        assert_eq!(tids, [0, 1, 2]);
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_stream_writer_zstd_frames() {
+        let mut writer = StreamWriter::new(Vec::new(), Compression::Zstd);
+        writer.write_thread_stack(&thread(1)).unwrap();
+        writer.flush().unwrap();
+        writer.write_thread_stack(&thread(2)).unwrap();
+        let out = writer.finish().unwrap();
+
+        let mut text = String::new();
+        let mut input = &out[..];
+        while !input.is_empty() {
+            let mut decoder = ruzstd::decoding::StreamingDecoder::new(&mut input).unwrap();
+            decoder.read_to_string(&mut text).unwrap();
+        }
+        assert_eq!(parse_lines(&text).len(), 2);
+    }
+}
@@ -0,0 +1,13 @@
+//! Exporters turning merged stacks and aggregated profiles into external formats.
+
+pub mod chrome_trace;
+pub mod faulthandler;
+pub mod folded;
+pub mod jsonl;
+pub mod otlp;
+pub mod pprof;
+pub mod pretty;
+pub mod sentry;
+pub mod speedscope;
+pub mod text;
+mod wire;
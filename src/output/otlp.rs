@@ -0,0 +1,432 @@
+//! OpenTelemetry profiles export: an OTLP `ExportProfilesServiceRequest` protobuf, as
+//! POSTed to a collector's `/v1development/profiles` endpoint.
+//!
+//! The profiling signal is still in development upstream; field numbers follow
+//! `opentelemetry/proto/profiles/v1development/profiles.proto` of opentelemetry-proto
+//! v1.7.0, where the lookup tables live in a request-wide `ProfilesDictionary`. Like the
+//! pprof exporter the message is encoded by hand.
+//!
+//! Each sample carries a `thread.id` attribute, and `cpu.logical_number` when its CPU is
+//! known, and each location a `profile.frame.type` (`cpython`, `native`, `cuda` or
+//! `synthetic`). Resource attributes come from `OtlpOptions`.
+
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::pprof::parse_address;
+use super::wire::ProtoWriter;
+use crate::profile::Profile;
+use crate::CallFrame;
+
+/// Resource and timing metadata of an exported profile.
+#[derive(Clone, Debug)]
+pub struct OtlpOptions {
+    service_name: String,
+    pid: u32,
+    attributes: Vec<(String, String)>,
+    period: Duration,
+    start: Option<SystemTime>,
+    duration: Duration,
+}
+
+impl Default for OtlpOptions {
+    fn default() -> Self {
+        OtlpOptions {
+            service_name: "unknown_service".to_string(),
+            pid: std::process::id(),
+            attributes: Vec::new(),
+            period: Duration::from_millis(10),
+            start: None,
+            duration: Duration::ZERO,
+        }
+    }
+}
+
+impl OtlpOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `service.name` resource attribute (default `unknown_service`).
+    pub fn service_name(mut self, name: impl Into<String>) -> Self {
+        self.service_name = name.into();
+        self
+    }
+
+    /// `process.pid` resource attribute (default: this process).
+    pub fn pid(mut self, pid: u32) -> Self {
+        self.pid = pid;
+        self
+    }
+
+    /// Extra string resource attribute, e.g. `host.name` or `deployment.environment`.
+    pub fn attribute(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.attributes.push((key.into(), value.into()));
+        self
+    }
+
+    /// Sampling period (default 10ms, i.e. 100 Hz).
+    pub fn period(mut self, period: Duration) -> Self {
+        self.period = period;
+        self
+    }
+
+    /// Start and length of the recording.
+    pub fn time_range(mut self, start: SystemTime, duration: Duration) -> Self {
+        self.start = Some(start);
+        self.duration = duration;
+        self
+    }
+
+    pub fn get_service_name(&self) -> &str {
+        &self.service_name
+    }
+
+    pub fn get_pid(&self) -> u32 {
+        self.pid
+    }
+
+    pub fn get_period(&self) -> Duration {
+        self.period
+    }
+}
+
+/// Encode `profile` as an `ExportProfilesServiceRequest` with one resource, one scope and
+/// one profile.
+pub fn encode_export_request(profile: &Profile, options: &OtlpOptions) -> Vec<u8> {
+    let mut dictionary = Dictionary::default();
+    // Index 0 of the string table must be the empty string.
+    dictionary.string("");
+
+    let weighted = profile.is_weighted();
+    let labels_state = profile.weight_type() == "wall";
+    let mut location_indices = Vec::new();
+    let mut samples = Vec::with_capacity(profile.stacks.len());
+    for stack in &profile.stacks {
+        let start = location_indices.len() as i64;
+        // Leaf first, as in pprof.
+        for frame in &stack.frames {
+            location_indices.push(dictionary.location(frame));
+        }
+        let mut attributes = vec![dictionary.int_attribute("thread.id", stack.tid as i64)];
+        if let Some(cpu) = stack.cpu {
+            attributes.push(dictionary.int_attribute("cpu.logical_number", cpu as i64));
+        }
+        if labels_state {
+            attributes.push(dictionary.string_attribute("sample.state", stack.state.as_str()));
+        }
+
+        let mut sample = ProtoWriter::default();
+        sample.int64(1, start);
+        sample.int64(2, stack.frames.len() as i64);
+        if weighted {
+            sample.packed_int64(3, &[stack.count as i64, stack.weight_ns as i64]);
+        } else {
+            sample.packed_int64(3, &[stack.count as i64]);
+        }
+        sample.packed_int64(4, &attributes);
+        samples.push(sample);
+    }
+
+    let mut out = ProtoWriter::default();
+    let samples_type = value_type(&mut dictionary, "samples", "count");
+    out.message(1, &samples_type);
+    if weighted {
+        let weight_type = value_type(&mut dictionary, profile.weight_type(), "nanoseconds");
+        out.message(1, &weight_type);
+    }
+    for sample in &samples {
+        out.message(2, sample);
+    }
+    out.packed_int64(3, &location_indices);
+    if let Some(start) = options.start {
+        let nanos = start
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        out.int64(4, nanos as i64);
+        out.int64(5, options.duration.as_nanos() as i64);
+    }
+    let period_type = value_type(&mut dictionary, "cpu", "nanoseconds");
+    out.message(6, &period_type);
+    out.int64(7, options.period.as_nanos() as i64);
+    let profile_message = out;
+
+    let mut scope = ProtoWriter::default();
+    scope.bytes(1, env!("CARGO_PKG_NAME").as_bytes());
+    scope.bytes(2, env!("CARGO_PKG_VERSION").as_bytes());
+    let mut scope_profiles = ProtoWriter::default();
+    scope_profiles.message(1, &scope);
+    scope_profiles.message(2, &profile_message);
+
+    let mut resource = ProtoWriter::default();
+    resource.message(1, &string_key_value("service.name", &options.service_name));
+    resource.message(1, &int_key_value("process.pid", options.pid as i64));
+    for (key, value) in &options.attributes {
+        resource.message(1, &string_key_value(key, value));
+    }
+    let mut resource_profiles = ProtoWriter::default();
+    resource_profiles.message(1, &resource);
+    resource_profiles.message(2, &scope_profiles);
+
+    let mut request = ProtoWriter::default();
+    request.message(1, &resource_profiles);
+    request.message(2, &dictionary.encode());
+    request.buf
+}
+
+fn value_type(dictionary: &mut Dictionary, kind: &str, unit: &str) -> ProtoWriter {
+    let mut value_type = ProtoWriter::default();
+    value_type.int64(1, dictionary.string(kind));
+    value_type.int64(2, dictionary.string(unit));
+    value_type
+}
+
+fn string_key_value(key: &str, value: &str) -> ProtoWriter {
+    let mut any = ProtoWriter::default();
+    any.bytes(1, value.as_bytes());
+    key_value(key, &any)
+}
+
+fn int_key_value(key: &str, value: i64) -> ProtoWriter {
+    let mut any = ProtoWriter::default();
+    // Explicit field even for 0, so the attribute keeps its type.
+    any.key(3, 0);
+    any.varint(value as u64);
+    key_value(key, &any)
+}
+
+fn key_value(key: &str, value: &ProtoWriter) -> ProtoWriter {
+    let mut kv = ProtoWriter::default();
+    kv.bytes(1, key.as_bytes());
+    kv.message(2, value);
+    kv
+}
+
+fn frame_type(frame: &CallFrame) -> &'static str {
+    match frame {
+        CallFrame::CFrame { .. } => "native",
+        CallFrame::PyFrame { .. } => "cpython",
+        CallFrame::GpuFrame { .. } => "cuda",
+        CallFrame::InterpreterFrame { .. } => "interpreted",
+        CallFrame::Synthetic { .. } => "synthetic",
+    }
+}
+
+#[derive(Default)]
+struct Dictionary {
+    strings: Vec<String>,
+    string_index: HashMap<String, i64>,
+    functions: Vec<ProtoWriter>,
+    function_index: HashMap<(String, String), i64>,
+    locations: Vec<ProtoWriter>,
+    location_index: HashMap<(i64, u64, i64, &'static str), i64>,
+    attributes: Vec<ProtoWriter>,
+    attribute_index: HashMap<(String, String), i64>,
+}
+
+impl Dictionary {
+    fn string(&mut self, s: &str) -> i64 {
+        if let Some(i) = self.string_index.get(s) {
+            return *i;
+        }
+        let i = self.strings.len() as i64;
+        self.strings.push(s.to_string());
+        self.string_index.insert(s.to_string(), i);
+        i
+    }
+
+    fn function(&mut self, name: &str, file: &str) -> i64 {
+        let key = (name.to_string(), file.to_string());
+        if let Some(i) = self.function_index.get(&key) {
+            return *i;
+        }
+        let i = self.functions.len() as i64;
+        let name_idx = self.string(name);
+        let file_idx = self.string(file);
+        let mut function = ProtoWriter::default();
+        function.int64(1, name_idx);
+        function.int64(2, name_idx);
+        function.int64(3, file_idx);
+        self.functions.push(function);
+        self.function_index.insert(key, i);
+        i
+    }
+
+    fn attribute(&mut self, key: &str, shown: String, kv: ProtoWriter) -> i64 {
+        let index_key = (key.to_string(), shown);
+        if let Some(i) = self.attribute_index.get(&index_key) {
+            return *i;
+        }
+        let i = self.attributes.len() as i64;
+        self.attributes.push(kv);
+        self.attribute_index.insert(index_key, i);
+        i
+    }
+
+    fn int_attribute(&mut self, key: &str, value: i64) -> i64 {
+        self.attribute(key, format!("i{}", value), int_key_value(key, value))
+    }
+
+    fn string_attribute(&mut self, key: &str, value: &str) -> i64 {
+        self.attribute(key, format!("s{}", value), string_key_value(key, value))
+    }
+
+    fn location(&mut self, frame: &CallFrame) -> i64 {
+        // Only native frames have a code address; a PyFrame ip is the frame object's.
+        let address = match frame {
+            CallFrame::CFrame { ip, .. } => parse_address(ip),
+            _ => 0,
+        };
+        let lineno = frame.lineno();
+        let kind = frame_type(frame);
+        let function = self.function(frame.func(), frame.file());
+        let key = (function, address, lineno, kind);
+        if let Some(i) = self.location_index.get(&key) {
+            return *i;
+        }
+        let attribute = self.string_attribute("profile.frame.type", kind);
+
+        let i = self.locations.len() as i64;
+        let mut line = ProtoWriter::default();
+        line.int64(1, function);
+        line.int64(2, lineno);
+        let mut location = ProtoWriter::default();
+        location.uint64(2, address);
+        location.message(3, &line);
+        location.packed_int64(5, &[attribute]);
+        self.locations.push(location);
+        self.location_index.insert(key, i);
+        i
+    }
+
+    fn encode(&self) -> ProtoWriter {
+        let mut dictionary = ProtoWriter::default();
+        for location in &self.locations {
+            dictionary.message(2, location);
+        }
+        for function in &self.functions {
+            dictionary.message(3, function);
+        }
+        for s in &self.strings {
+            dictionary.bytes(5, s.as_bytes());
+        }
+        for attribute in &self.attributes {
+            dictionary.message(6, attribute);
+        }
+        dictionary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::wire::fields;
+    use crate::profile::{SampleState, SampledStack};
+
+    fn message(fields: &[(u64, Result<u64, Vec<u8>>)], field: u64) -> Vec<u8> {
+        fields
+            .iter()
+            .find(|(f, _)| *f == field)
+            .and_then(|(_, v)| v.clone().err())
+            .unwrap()
+    }
+
+    fn repeated(fields: &[(u64, Result<u64, Vec<u8>>)], field: u64) -> Vec<Vec<u8>> {
+        fields
+            .iter()
+            .filter(|(f, _)| *f == field)
+            .filter_map(|(_, v)| v.clone().err())
+            .collect()
+    }
+
+    #[test]
+    fn test_encode_export_request() {
+        let leaf = CallFrame::native("0x1000", "lib.c", "leaf", 10);
+        let py = CallFrame::python("0x7f00", "app.py", "handler", 3);
+        let profile = Profile {
+            stacks: vec![
+                SampledStack {
+                    tid: 42,
+                    frames: vec![leaf, py.clone()],
+                    count: 3,
+                    ..SampledStack::default()
+                },
+                SampledStack {
+                    tid: 43,
+                    frames: vec![py],
+                    count: 1,
+                    ..SampledStack::default()
+                },
+            ],
+            total_samples: 4,
+            dropped_samples: 0,
+        };
+        let options = OtlpOptions::new()
+            .service_name("trainer")
+            .pid(7)
+            .attribute("host.name", "gpu-01")
+            .time_range(UNIX_EPOCH + Duration::from_secs(1), Duration::from_secs(2));
+        let request = fields(&encode_export_request(&profile, &options));
+
+        let dictionary = fields(&message(&request, 2));
+        let strings: Vec<String> = repeated(&dictionary, 5)
+            .into_iter()
+            .map(|s| String::from_utf8(s).unwrap())
+            .collect();
+        assert_eq!(strings[0], "");
+        assert!(strings.contains(&"handler".to_string()));
+        assert_eq!(repeated(&dictionary, 2).len(), 2);
+        assert_eq!(repeated(&dictionary, 3).len(), 2);
+        // thread.id 42 and 43 plus the native and cpython frame types.
+        assert_eq!(repeated(&dictionary, 6).len(), 4);
+
+        let resource_profiles = fields(&message(&request, 1));
+        let resource = fields(&message(&resource_profiles, 1));
+        let attributes = repeated(&resource, 1);
+        assert_eq!(attributes.len(), 3);
+        assert_eq!(message(&fields(&attributes[0]), 1), b"service.name");
+        let pid = fields(&message(&fields(&attributes[1]), 2));
+        assert_eq!(pid, [(3, Ok(7))]);
+
+        let scope_profiles = fields(&message(&resource_profiles, 2));
+        let profile = fields(&message(&scope_profiles, 2));
+        // Locations of both samples, leaf first: leaf, handler, handler.
+        assert_eq!(message(&profile, 3), [0, 1, 1]);
+        let samples = repeated(&profile, 2);
+        assert_eq!(samples.len(), 2);
+        let second = fields(&samples[1]);
+        assert_eq!(second[0], (1, Ok(2)));
+        assert_eq!(second[1], (2, Ok(1)));
+        assert_eq!(second[2], (3, Err(vec![1])));
+        assert!(profile.contains(&(4, Ok(1_000_000_000))));
+        assert!(profile.contains(&(7, Ok(10_000_000))));
+    }
+
+    #[test]
+    fn test_encode_weighted_samples() {
+        let profile = Profile {
+            stacks: vec![SampledStack {
+                tid: 42,
+                frames: vec![CallFrame::native("0x1000", "lib.c", "leaf", 10)],
+                count: 2,
+                cpu: Some(3),
+                state: SampleState::OffCpu,
+                weight_ns: 300,
+            }],
+            total_samples: 2,
+            dropped_samples: 0,
+        };
+        let request = fields(&encode_export_request(&profile, &OtlpOptions::new()));
+        let resource_profiles = fields(&message(&request, 1));
+        let scope_profiles = fields(&message(&resource_profiles, 2));
+        let profile = fields(&message(&scope_profiles, 2));
+        assert_eq!(repeated(&profile, 1).len(), 2);
+        let sample = fields(&repeated(&profile, 2)[0]);
+        // The location offset is 0 and left out.
+        assert_eq!(sample[1], (3, Err(vec![2, 0xac, 0x02])));
+        // thread.id, cpu.logical_number and sample.state, after the frame type of the
+        // location.
+        assert_eq!(sample[2], (4, Err(vec![1, 2, 3])));
+    }
+}
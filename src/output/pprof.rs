@@ -0,0 +1,292 @@
+//! pprof export: gzipped `profile.proto` as read by `go tool pprof` and Grafana Pyroscope.
+//!
+//! The message is encoded by hand (the schema is small and stable), so no protobuf
+//! toolchain is needed at build time. Field numbers follow
+//! https://github.com/google/pprof/blob/main/proto/profile.proto.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use super::wire::ProtoWriter;
+use crate::profile::Profile;
+use crate::CallFrame;
+
+/// Write `profile` as a gzipped pprof protobuf.
+pub fn write_profile<W: Write>(out: W, profile: &Profile) -> io::Result<()> {
+    let mut encoder = GzEncoder::new(out, Compression::default());
+    encoder.write_all(&encode_profile(profile))?;
+    encoder.finish()?;
+    Ok(())
+}
+
+/// Encode `profile` as an uncompressed `perftools.profiles.Profile` message.
+///
+/// Weighted profiles get a second sample type, `cpu` or `wall` nanoseconds (see
+/// `Profile::weight_type`), and a `state` label once off-CPU stacks are present. Stacks
+/// with a known CPU get a numeric `cpu` label.
+pub fn encode_profile(profile: &Profile) -> Vec<u8> {
+    let mut builder = Builder::default();
+    // Index 0 of the string table must be the empty string.
+    builder.string("");
+    let samples_idx = builder.string("samples");
+    let count_idx = builder.string("count");
+    let thread_idx = builder.string("thread_id");
+    let weighted = profile.is_weighted();
+    let labels_state = profile.weight_type() == "wall";
+
+    let mut samples = Vec::with_capacity(profile.stacks.len());
+    for stack in &profile.stacks {
+        // pprof wants the leaf at location_id[0], which is the merge order already.
+        let locations: Vec<u64> = stack.frames.iter().map(|f| builder.location(f)).collect();
+
+        let mut sample = ProtoWriter::default();
+        sample.packed_uint64(1, &locations);
+        if weighted {
+            sample.packed_int64(2, &[stack.count as i64, stack.weight_ns as i64]);
+        } else {
+            sample.packed_int64(2, &[stack.count as i64]);
+        }
+        let mut label = ProtoWriter::default();
+        label.int64(1, thread_idx);
+        label.int64(3, stack.tid as i64);
+        sample.message(3, &label);
+        if let Some(cpu) = stack.cpu {
+            let mut label = ProtoWriter::default();
+            label.int64(1, builder.string("cpu"));
+            label.int64(3, cpu as i64);
+            sample.message(3, &label);
+        }
+        if labels_state {
+            let mut label = ProtoWriter::default();
+            label.int64(1, builder.string("state"));
+            label.int64(2, builder.string(stack.state.as_str()));
+            sample.message(3, &label);
+        }
+        samples.push(sample);
+    }
+
+    let mut out = ProtoWriter::default();
+    let mut sample_type = ProtoWriter::default();
+    sample_type.int64(1, samples_idx);
+    sample_type.int64(2, count_idx);
+    out.message(1, &sample_type);
+    if weighted {
+        let mut sample_type = ProtoWriter::default();
+        sample_type.int64(1, builder.string(profile.weight_type()));
+        sample_type.int64(2, builder.string("nanoseconds"));
+        out.message(1, &sample_type);
+    }
+    for sample in &samples {
+        out.message(2, sample);
+    }
+    for location in &builder.locations {
+        out.message(4, location);
+    }
+    for function in &builder.functions {
+        out.message(5, function);
+    }
+    for s in &builder.strings {
+        out.bytes(6, s.as_bytes());
+    }
+    out.buf
+}
+
+#[derive(Default)]
+struct Builder {
+    strings: Vec<String>,
+    string_index: HashMap<String, i64>,
+    functions: Vec<ProtoWriter>,
+    function_index: HashMap<(String, String), u64>,
+    locations: Vec<ProtoWriter>,
+    location_index: HashMap<(u64, u64, i64), u64>,
+}
+
+impl Builder {
+    fn string(&mut self, s: &str) -> i64 {
+        if let Some(i) = self.string_index.get(s) {
+            return *i;
+        }
+        let i = self.strings.len() as i64;
+        self.strings.push(s.to_string());
+        self.string_index.insert(s.to_string(), i);
+        i
+    }
+
+    fn function(&mut self, name: &str, file: &str) -> u64 {
+        let key = (name.to_string(), file.to_string());
+        if let Some(id) = self.function_index.get(&key) {
+            return *id;
+        }
+
+        let id = self.functions.len() as u64 + 1;
+        let name_idx = self.string(name);
+        let file_idx = self.string(file);
+        let mut function = ProtoWriter::default();
+        function.uint64(1, id);
+        function.int64(2, name_idx);
+        function.int64(3, name_idx);
+        function.int64(4, file_idx);
+        self.functions.push(function);
+        self.function_index.insert(key, id);
+        id
+    }
+
+    fn location(&mut self, frame: &CallFrame) -> u64 {
+        // Only native frames have a code address; a PyFrame ip is the frame object's.
+        let address = match frame {
+            CallFrame::CFrame { ip, .. } => parse_address(ip),
+            _ => 0,
+        };
+        let lineno = frame.lineno();
+        let function_id = self.function(frame.func(), frame.file());
+        let key = (function_id, address, lineno);
+        if let Some(id) = self.location_index.get(&key) {
+            return *id;
+        }
+
+        let id = self.locations.len() as u64 + 1;
+        let mut line = ProtoWriter::default();
+        line.uint64(1, function_id);
+        line.int64(2, lineno);
+        let mut location = ProtoWriter::default();
+        location.uint64(1, id);
+        location.uint64(3, address);
+        location.message(4, &line);
+        self.locations.push(location);
+        self.location_index.insert(key, id);
+        id
+    }
+}
+
+pub(super) fn parse_address(ip: &str) -> u64 {
+    let hex = ip.trim_start_matches("0x").trim_start_matches("0X");
+    u64::from_str_radix(hex, 16).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::wire::fields;
+    use crate::profile::{SampleState, SampledStack};
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    fn profile() -> Profile {
+        let leaf = CallFrame::native("0x1000", "lib.c", "leaf", 10);
+        let py = CallFrame::python("0x7f00", "app.py", "handler", 3);
+        Profile {
+            stacks: vec![
+                SampledStack {
+                    tid: 42,
+                    frames: vec![leaf.clone(), py.clone()],
+                    count: 3,
+                    ..SampledStack::default()
+                },
+                SampledStack {
+                    tid: 42,
+                    frames: vec![py],
+                    count: 1,
+                    ..SampledStack::default()
+                },
+            ],
+            total_samples: 4,
+            dropped_samples: 0,
+        }
+    }
+
+    #[test]
+    fn test_encode_profile_structure() {
+        let message = encode_profile(&profile());
+        let top = fields(&message);
+
+        let strings: Vec<String> = top
+            .iter()
+            .filter(|(f, _)| *f == 6)
+            .map(|(_, v)| String::from_utf8(v.clone().unwrap_err()).unwrap())
+            .collect();
+        assert_eq!(strings[0], "");
+        assert!(strings.contains(&"leaf".to_string()));
+        assert!(strings.contains(&"app.py".to_string()));
+
+        assert_eq!(top.iter().filter(|(f, _)| *f == 2).count(), 2);
+        assert_eq!(top.iter().filter(|(f, _)| *f == 4).count(), 2);
+        assert_eq!(top.iter().filter(|(f, _)| *f == 5).count(), 2);
+
+        // First sample: two locations (leaf first), value 3, thread label.
+        let sample = top
+            .iter()
+            .find(|(f, _)| *f == 2)
+            .unwrap()
+            .1
+            .clone()
+            .unwrap_err();
+        let sample = fields(&sample);
+        assert_eq!(sample[0], (1, Err(vec![1, 2])));
+        assert_eq!(sample[1], (2, Err(vec![3])));
+        let label = fields(sample[2].1.as_ref().unwrap_err());
+        assert_eq!(label[1], (3, Ok(42)));
+
+        // The native location keeps its address, the python one does not.
+        let locations: Vec<_> = top
+            .iter()
+            .filter(|(f, _)| *f == 4)
+            .map(|(_, v)| fields(v.as_ref().unwrap_err()))
+            .collect();
+        assert!(locations[0].contains(&(3, Ok(0x1000))));
+        assert!(!locations[1].iter().any(|(f, _)| *f == 3));
+    }
+
+    #[test]
+    fn test_encode_weighted_profile() {
+        let mut profile = profile();
+        profile.stacks[0].weight_ns = 30;
+        profile.stacks[1].weight_ns = 500;
+        profile.stacks[1].state = SampleState::OffCpu;
+        profile.stacks[1].cpu = Some(5);
+        let top = fields(&encode_profile(&profile));
+        let strings: Vec<String> = top
+            .iter()
+            .filter(|(f, _)| *f == 6)
+            .map(|(_, v)| String::from_utf8(v.clone().unwrap_err()).unwrap())
+            .collect();
+
+        let sample_types: Vec<_> = top
+            .iter()
+            .filter(|(f, _)| *f == 1)
+            .map(|(_, v)| fields(v.as_ref().unwrap_err()))
+            .collect();
+        assert_eq!(sample_types.len(), 2);
+        assert_eq!(
+            strings[sample_types[1][0].1.clone().unwrap() as usize],
+            "wall"
+        );
+        assert_eq!(
+            strings[sample_types[1][1].1.clone().unwrap() as usize],
+            "nanoseconds"
+        );
+
+        let second = top.iter().filter(|(f, _)| *f == 2).nth(1).unwrap();
+        let second = fields(second.1.as_ref().unwrap_err());
+        assert_eq!(second[1], (2, Err(vec![1, 0xf4, 0x03])));
+        let cpu = fields(second[3].1.as_ref().unwrap_err());
+        assert_eq!(strings[cpu[0].1.clone().unwrap() as usize], "cpu");
+        assert_eq!(cpu[1], (3, Ok(5)));
+        let state = fields(second[4].1.as_ref().unwrap_err());
+        assert_eq!(strings[state[0].1.clone().unwrap() as usize], "state");
+        assert_eq!(strings[state[1].1.clone().unwrap() as usize], "off_cpu");
+    }
+
+    #[test]
+    fn test_write_profile_is_gzipped() {
+        let mut out = Vec::new();
+        write_profile(&mut out, &profile()).unwrap();
+        assert_eq!(&out[..2], &[0x1f, 0x8b]);
+
+        let mut decoded = Vec::new();
+        GzDecoder::new(&out[..]).read_to_end(&mut decoded).unwrap();
+        assert_eq!(decoded, encode_profile(&profile()));
+    }
+}
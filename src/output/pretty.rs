@@ -0,0 +1,432 @@
+//! Multi-line dumps in the style of gdb's `bt` combined with `py-bt`.
+//!
+//! ```text
+//! Thread 7 "main" (Sleeping) [has GIL]
+//! #0  0x10 in clock_nanosleep (/lib/libc.so.6)
+//! #1  0x18 in inner (lib.rs:10) [inlined]
+//! #2  0x18 in outer (lib.rs:30)
+//! #3  File "app.py", line 3, in run
+//!         time.sleep(1)
+//! #4  [gpu] gemm_kernel on device 0
+//! #5  [gil] [GIL wait]
+//! ```
+//!
+//! Inlined calls are listed before the physical frame that contains them, sharing its ip.
+//! Source attached by `SignalTracer::attach_source` is shown under its frame, context
+//! lines dimmed.
+
+use std::io::{self, Write};
+
+use crate::source::SourceContext;
+use crate::thread_stack::ThreadStack;
+use crate::value::Value;
+use crate::CallFrame;
+
+const RESET: &str = "\x1b[0m";
+const DIM: &str = "\x1b[2m";
+const BOLD: &str = "\x1b[1m";
+const GREEN: &str = "\x1b[32m";
+const YELLOW: &str = "\x1b[33m";
+const BLUE: &str = "\x1b[34m";
+const MAGENTA: &str = "\x1b[35m";
+const CYAN: &str = "\x1b[36m";
+
+/// How `write_stack` renders frames.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PrettyOptions {
+    color: bool,
+    numbering: bool,
+    locals: bool,
+    source: bool,
+}
+
+impl Default for PrettyOptions {
+    fn default() -> Self {
+        PrettyOptions {
+            color: false,
+            numbering: true,
+            locals: true,
+            source: true,
+        }
+    }
+}
+
+impl PrettyOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Highlight with ANSI escape codes (default off).
+    pub fn color(mut self, color: bool) -> Self {
+        self.color = color;
+        self
+    }
+
+    /// Prefix frames with `#N` (default on).
+    pub fn numbering(mut self, numbering: bool) -> Self {
+        self.numbering = numbering;
+        self
+    }
+
+    /// List captured Python locals under their frame (default on).
+    pub fn locals(mut self, locals: bool) -> Self {
+        self.locals = locals;
+        self
+    }
+
+    /// Show attached source lines under their frame (default on).
+    pub fn source(mut self, source: bool) -> Self {
+        self.source = source;
+        self
+    }
+
+    pub fn get_color(&self) -> bool {
+        self.color
+    }
+
+    pub fn get_numbering(&self) -> bool {
+        self.numbering
+    }
+
+    pub fn get_locals(&self) -> bool {
+        self.locals
+    }
+
+    pub fn get_source(&self) -> bool {
+        self.source
+    }
+
+    fn paint(&self, style: &str, text: &str) -> String {
+        if self.color {
+            format!("{}{}{}", style, text, RESET)
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+/// Write a leaf-first stack, one frame per line.
+pub fn write_stack<W: Write>(
+    out: &mut W,
+    frames: &[CallFrame],
+    options: &PrettyOptions,
+) -> io::Result<()> {
+    let mut n = 0;
+    let mut line = |out: &mut W, text: String| -> io::Result<()> {
+        if options.numbering {
+            let number = format!("#{:<3}", n);
+            write!(out, "{}", options.paint(DIM, &number))?;
+        }
+        n += 1;
+        writeln!(out, "{}", text)
+    };
+
+    for frame in frames {
+        match frame {
+            CallFrame::CFrame {
+                ip,
+                file,
+                func,
+                lineno,
+                inlined,
+                source,
+                ..
+            } => {
+                for inline in inlined {
+                    let text = native(options, ip, &inline.func, &inline.file, inline.lineno);
+                    line(out, format!("{} {}", text, options.paint(DIM, "[inlined]")))?;
+                }
+                line(out, native(options, ip, func, file, *lineno))?;
+                write_source(out, source.as_deref(), options)?;
+            }
+            CallFrame::PyFrame {
+                file,
+                func,
+                lineno,
+                locals,
+                source,
+                ..
+            } => {
+                line(
+                    out,
+                    format!(
+                        "File \"{}\", line {}, in {}",
+                        options.paint(GREEN, file),
+                        lineno,
+                        options.paint(BOLD, &options.paint(CYAN, func))
+                    ),
+                )?;
+                write_source(out, source.as_deref(), options)?;
+                if options.locals {
+                    let mut locals: Vec<_> = locals.iter().collect();
+                    locals.sort_by(|a, b| a.0.cmp(b.0));
+                    for (name, value) in locals {
+                        writeln!(out, "        {} = {}", name, python_repr(value))?;
+                    }
+                }
+            }
+            CallFrame::GpuFrame { kernel, device, .. } => {
+                let mut text = format!("{} {}", options.paint(MAGENTA, "[gpu]"), kernel);
+                if let Some(device) = device {
+                    text.push_str(&format!(" on device {}", device));
+                }
+                line(out, text)?;
+            }
+            CallFrame::InterpreterFrame {
+                runtime,
+                file,
+                func,
+                lineno,
+            } => {
+                let tag = format!("[{}]", runtime);
+                line(
+                    out,
+                    format!(
+                        "{} {} ({})",
+                        options.paint(MAGENTA, &tag),
+                        options.paint(CYAN, func),
+                        options.paint(GREEN, &format!("{}:{}", file, lineno))
+                    ),
+                )?;
+            }
+            CallFrame::Synthetic { label, category } => {
+                let tag = format!("[{}]", category);
+                line(out, format!("{} {}", options.paint(MAGENTA, &tag), label))?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn native(options: &PrettyOptions, ip: &str, func: &str, file: &str, lineno: i64) -> String {
+    let mut text = format!(
+        "{} in {}",
+        options.paint(BLUE, ip),
+        options.paint(YELLOW, func)
+    );
+    match (file.is_empty(), lineno > 0) {
+        (true, _) => {}
+        (false, true) => {
+            let location = format!("{}:{}", file, lineno);
+            text.push_str(&format!(" ({})", options.paint(GREEN, &location)));
+        }
+        (false, false) => text.push_str(&format!(" ({})", options.paint(GREEN, file))),
+    }
+    text
+}
+
+/// The frame's line, stripped of the indentation it shares with its context lines.
+fn write_source<W: Write>(
+    out: &mut W,
+    source: Option<&SourceContext>,
+    options: &PrettyOptions,
+) -> io::Result<()> {
+    let Some(source) = source.filter(|_| options.source) else {
+        return Ok(());
+    };
+    let indent = |l: &str| l.len() - l.trim_start().len();
+    let common = source
+        .pre
+        .iter()
+        .chain(std::iter::once(&source.line))
+        .chain(&source.post)
+        .filter(|l| !l.trim().is_empty())
+        .map(|l| indent(l))
+        .min()
+        .unwrap_or(0);
+    let strip = |l: &str| l.get(common..).unwrap_or("").trim_end().to_string();
+    for pre in &source.pre {
+        writeln!(out, "        {}", options.paint(DIM, &strip(pre)))?;
+    }
+    writeln!(out, "        {}", strip(&source.line))?;
+    for post in &source.post {
+        writeln!(out, "        {}", options.paint(DIM, &strip(post)))?;
+    }
+    Ok(())
+}
+
+fn python_repr(value: &Value) -> String {
+    match value {
+        Value::None => "None".to_string(),
+        Value::Bool(true) => "True".to_string(),
+        Value::Bool(false) => "False".to_string(),
+        Value::Int(i) => i.to_string(),
+        Value::Float(f) => float_repr(f.0),
+        Value::Str(repr) | Value::Repr { repr, .. } => repr.clone(),
+        Value::List(items) => {
+            let items: Vec<String> = items.iter().map(item_repr).collect();
+            format!("[{}]", items.join(", "))
+        }
+        Value::Dict(entries) => {
+            let mut entries: Vec<String> = entries
+                .iter()
+                .map(|(k, v)| format!("{}: {}", quote(k), item_repr(v)))
+                .collect();
+            entries.sort();
+            format!("{{{}}}", entries.join(", "))
+        }
+        Value::Bytes(bytes) => format!("b'{}'", bytes.escape_ascii()),
+    }
+}
+
+/// Like `python_repr`, but strings inside containers are quoted as Python would.
+fn item_repr(value: &Value) -> String {
+    match value {
+        Value::Str(s) => quote(s),
+        other => python_repr(other),
+    }
+}
+
+/// Python's spelling: `1.0`, `1e-05`, `nan`, `inf`.
+fn float_repr(f: f64) -> String {
+    if f.is_nan() {
+        return "nan".to_string();
+    }
+    // Debug already says `inf`, and keeps the `.0` of integral values.
+    let repr = format!("{:?}", f);
+    match repr.split_once('e') {
+        Some((mantissa, exp)) if !exp.starts_with('-') => format!("{}e+{:0>2}", mantissa, exp),
+        Some((mantissa, exp)) => format!("{}e-{:0>2}", mantissa, &exp[1..]),
+        None => repr,
+    }
+}
+
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+/// Write every thread as a `Thread ...` header followed by its stack and a blank line.
+pub fn write_thread_stacks<W: Write>(
+    out: &mut W,
+    stacks: &[ThreadStack],
+    options: &PrettyOptions,
+) -> io::Result<()> {
+    for stack in stacks {
+        let header = format!("Thread {} \"{}\"", stack.tid, stack.name);
+        write!(
+            out,
+            "{} ({:?})",
+            options.paint(BOLD, &header),
+            stack.os_state
+        )?;
+        if stack.is_gil_holder {
+            write!(out, " [has GIL]")?;
+        }
+        if stack.greenlet {
+            write!(out, " [greenlet]")?;
+        }
+        if let Some(id) = stack.interpreter_id.filter(|id| *id != 0) {
+            write!(out, " [interpreter {}]", id)?;
+        }
+        writeln!(out)?;
+        write_stack(out, &stack.frames, options)?;
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// Convenience wrapper around `write_stack` returning the text.
+pub fn stack_to_string(frames: &[CallFrame], options: &PrettyOptions) -> String {
+    let mut out = Vec::new();
+    write_stack(&mut out, frames, options).expect("writing to a Vec cannot fail");
+    String::from_utf8(out).expect("pretty output is valid UTF-8")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    use crate::thread_stack::ThreadState;
+    use crate::InlineFrame;
+
+    fn frames() -> Vec<CallFrame> {
+        let mut py = CallFrame::python("0x7f00", "app.py", "run", 3);
+        if let CallFrame::PyFrame { locals, source, .. } = &mut py {
+            *source = Some(Box::new(SourceContext {
+                line: "        step(batch)".to_string(),
+                pre: vec!["    for batch in loader:".to_string()],
+                post: Vec::new(),
+            }));
+            locals.insert("name".to_string(), Value::Str("'job'".to_string()));
+            locals.insert("done".to_string(), Value::Bool(false));
+            locals.insert("lr".to_string(), Value::float(1e-5));
+            let shape = Value::Dict(HashMap::from([(
+                "k".to_string(),
+                Value::Bytes(b"\x01".to_vec()),
+            )]));
+            locals.insert(
+                "batch".to_string(),
+                Value::List(vec![Value::Int(1), Value::Str("it's".to_string()), shape]),
+            );
+        }
+        vec![
+            CallFrame::native("0x10", "/lib/libc.so.6", "clock_nanosleep", 0),
+            CallFrame::native("0x18", "lib.rs", "outer", 30).with_inlined(vec![InlineFrame {
+                func: "inner".to_string(),
+                file: "lib.rs".to_string(),
+                lineno: 10,
+            }]),
+            py,
+            CallFrame::gpu("gemm_kernel", Some(0), 1),
+            CallFrame::synthetic("[GIL wait]", "gil"),
+        ]
+    }
+
+    #[test]
+    fn test_write_stack() {
+        assert_eq!(
+            stack_to_string(&frames(), &PrettyOptions::new()),
+            "\
+#0  0x10 in clock_nanosleep (/lib/libc.so.6)
+#1  0x18 in inner (lib.rs:10) [inlined]
+#2  0x18 in outer (lib.rs:30)
+#3  File \"app.py\", line 3, in run
+        for batch in loader:
+            step(batch)
+        batch = [1, 'it\\'s', {'k': b'\\x01'}]
+        done = False
+        lr = 1e-05
+        name = 'job'
+#4  [gpu] gemm_kernel on device 0
+#5  [gil] [GIL wait]
+"
+        );
+        let plain = PrettyOptions::new()
+            .numbering(false)
+            .locals(false)
+            .source(false);
+        assert_eq!(
+            stack_to_string(&frames()[2..3], &plain),
+            "File \"app.py\", line 3, in run\n"
+        );
+    }
+
+    #[test]
+    fn test_color() {
+        let text = stack_to_string(&frames()[..1], &PrettyOptions::new().color(true));
+        assert_eq!(
+            text,
+            "\x1b[2m#0  \x1b[0m\x1b[34m0x10\x1b[0m in \x1b[33mclock_nanosleep\x1b[0m (\x1b[32m/lib/libc.so.6\x1b[0m)\n"
+        );
+    }
+
+    #[test]
+    fn test_write_thread_stacks() {
+        let stacks = [ThreadStack {
+            tid: 7,
+            name: "main".to_string(),
+            os_state: ThreadState::Sleeping,
+            is_gil_holder: true,
+            greenlet: false,
+            interpreter_id: None,
+            frames: frames()[..1].to_vec(),
+        }];
+        let mut out = Vec::new();
+        write_thread_stacks(&mut out, &stacks, &PrettyOptions::new()).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Thread 7 \"main\" (Sleeping) [has GIL]\n#0  0x10 in clock_nanosleep (/lib/libc.so.6)\n\n"
+        );
+    }
+}
@@ -0,0 +1,387 @@
+//! Sentry stack traces (https://develop.sentry.dev/sdk/data-model/event-payloads/stacktrace/).
+//!
+//! Sentry lists frames oldest first, so a merged (leaf-first) trace is reversed. Python
+//! frames get platform `python`, native and GPU frames `native` with their ip as
+//! `instruction_addr`, synthetic markers `other`. Inlined native calls become frames of
+//! their own at the same address.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::time::UNIX_EPOCH;
+
+use serde::Serialize;
+
+use crate::frame_filter::library_kind;
+use crate::stack_trace::StackTrace;
+use crate::value::Value;
+use crate::CallFrame;
+
+/// How frames are classified as in-app.
+#[derive(Clone, Debug, Default)]
+pub struct SentryOptions {
+    in_app_include: Vec<String>,
+    in_app_exclude: Vec<String>,
+}
+
+impl SentryOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Mark frames whose file starts with `prefix` as in-app. Once any prefix is given,
+    /// only matching frames are in-app; otherwise classified Python frames (see
+    /// `SignalTracer::classify_frames`) keep their flag, other Python frames outside
+    /// site-packages and the standard library are in-app, and native frames are not.
+    pub fn in_app_include(mut self, prefix: impl Into<String>) -> Self {
+        self.in_app_include.push(prefix.into());
+        self
+    }
+
+    /// Never mark frames whose file starts with `prefix` as in-app; wins over includes.
+    pub fn in_app_exclude(mut self, prefix: impl Into<String>) -> Self {
+        self.in_app_exclude.push(prefix.into());
+        self
+    }
+
+    pub fn get_in_app_include(&self) -> &[String] {
+        &self.in_app_include
+    }
+
+    pub fn get_in_app_exclude(&self) -> &[String] {
+        &self.in_app_exclude
+    }
+
+    fn in_app(&self, frame: &CallFrame) -> bool {
+        let file = frame.file();
+        if self
+            .in_app_exclude
+            .iter()
+            .any(|p| file.starts_with(p.as_str()))
+        {
+            return false;
+        }
+        if !self.in_app_include.is_empty() {
+            return self
+                .in_app_include
+                .iter()
+                .any(|p| file.starts_with(p.as_str()));
+        }
+        match frame {
+            // Classified by `SignalTracer::classify_frames`.
+            CallFrame::PyFrame {
+                module: Some(_),
+                in_app,
+                ..
+            } => *in_app,
+            CallFrame::PyFrame { .. } => !file.is_empty() && library_kind(file).is_none(),
+            _ => false,
+        }
+    }
+}
+
+/// Sentry `stacktrace` interface.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Stacktrace {
+    /// Oldest call first, the frame that was executing last.
+    pub frames: Vec<Frame>,
+}
+
+/// One Sentry stack frame.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Frame {
+    pub function: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub raw_function: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub filename: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lineno: Option<i64>,
+    /// `gpu` / `gpu:<device>` for GPU frames, the category for synthetic ones.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub module: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub package: Option<String>,
+    pub in_app: bool,
+    pub platform: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instruction_addr: Option<String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub vars: BTreeMap<String, serde_json::Value>,
+}
+
+/// Convert a merged, leaf-first stack into a Sentry stacktrace.
+pub fn to_stacktrace(frames: &[CallFrame], options: &SentryOptions) -> Stacktrace {
+    let mut sentry: Vec<Frame> = CallFrame::expand_inlined(frames.to_vec())
+        .iter()
+        .map(|frame| to_frame(frame, options))
+        .collect();
+    sentry.reverse();
+    Stacktrace { frames: sentry }
+}
+
+fn to_frame(frame: &CallFrame, options: &SentryOptions) -> Frame {
+    let known = |s: &str| (!s.is_empty()).then(|| s.to_string());
+    let line = |n: i64| (n > 0).then_some(n);
+    let in_app = options.in_app(frame);
+    match frame {
+        CallFrame::CFrame {
+            ip,
+            file,
+            func,
+            lineno,
+            raw_func,
+            ..
+        } => Frame {
+            function: func.clone(),
+            raw_function: raw_func.clone(),
+            filename: known(file),
+            lineno: line(*lineno),
+            module: None,
+            package: None,
+            in_app,
+            platform: "native",
+            instruction_addr: known(ip),
+            vars: BTreeMap::new(),
+        },
+        CallFrame::PyFrame {
+            file,
+            func,
+            lineno,
+            locals,
+            module,
+            package,
+            ..
+        } => Frame {
+            function: func.clone(),
+            raw_function: None,
+            filename: known(file),
+            lineno: line(*lineno),
+            module: module.clone(),
+            package: package.clone(),
+            in_app,
+            platform: "python",
+            instruction_addr: None,
+            vars: locals
+                .iter()
+                .map(|(name, value)| (name.clone(), to_json(value)))
+                .collect(),
+        },
+        CallFrame::GpuFrame { kernel, device, .. } => Frame {
+            function: kernel.clone(),
+            raw_function: None,
+            filename: None,
+            lineno: None,
+            module: None,
+            package: Some(match device {
+                Some(device) => format!("gpu:{}", device),
+                None => "gpu".to_string(),
+            }),
+            in_app,
+            platform: "native",
+            instruction_addr: None,
+            vars: BTreeMap::new(),
+        },
+        CallFrame::InterpreterFrame {
+            runtime,
+            file,
+            func,
+            lineno,
+        } => Frame {
+            function: func.clone(),
+            raw_function: None,
+            filename: known(file),
+            lineno: line(*lineno),
+            module: None,
+            package: Some(runtime.clone()),
+            in_app,
+            platform: "other",
+            instruction_addr: None,
+            vars: BTreeMap::new(),
+        },
+        CallFrame::Synthetic { label, category } => Frame {
+            function: label.clone(),
+            raw_function: None,
+            filename: None,
+            lineno: None,
+            module: None,
+            package: Some(category.clone()),
+            in_app,
+            platform: "other",
+            instruction_addr: None,
+            vars: BTreeMap::new(),
+        },
+    }
+}
+
+fn to_json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::None => serde_json::Value::Null,
+        Value::Bool(b) => (*b).into(),
+        Value::Int(i) => (*i).into(),
+        // JSON has no NaN or infinities; those are sent as Python spells them.
+        Value::Float(f) => serde_json::Number::from_f64(f.0).map_or_else(
+            || f.to_string().to_lowercase().into(),
+            serde_json::Value::Number,
+        ),
+        Value::Str(s) | Value::Repr { repr: s, .. } => s.clone().into(),
+        Value::List(items) => items.iter().map(to_json).collect(),
+        Value::Dict(entries) => entries
+            .iter()
+            .map(|(k, v)| (k.clone(), to_json(v)))
+            .collect::<serde_json::Map<_, _>>()
+            .into(),
+        Value::Bytes(bytes) => bytes.escape_ascii().to_string().into(),
+    }
+}
+
+/// A minimal Sentry event reporting `trace` as an exception of `kind` with `message`.
+///
+/// The event platform is `python` when the trace holds Python frames, `native` otherwise.
+pub fn exception_event(
+    trace: &StackTrace,
+    kind: &str,
+    message: &str,
+    options: &SentryOptions,
+) -> serde_json::Value {
+    let platform = if trace.iter().any(|f| matches!(f, CallFrame::PyFrame { .. })) {
+        "python"
+    } else {
+        "native"
+    };
+    let mut exception = serde_json::json!({
+        "type": kind,
+        "value": message,
+        "stacktrace": to_stacktrace(trace, options),
+    });
+    if let Some(tid) = trace.tid {
+        exception["thread_id"] = tid.into();
+    }
+    let mut event = serde_json::json!({
+        "platform": platform,
+        "exception": { "values": [exception] },
+    });
+    if let Some(timestamp) = trace
+        .timestamp
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+    {
+        event["timestamp"] = timestamp.as_secs_f64().into();
+    }
+    event
+}
+
+/// Write the Sentry stacktrace of `trace` as JSON.
+pub fn write_stacktrace<W: Write>(
+    out: &mut W,
+    trace: &StackTrace,
+    options: &SentryOptions,
+) -> io::Result<()> {
+    serde_json::to_writer(&mut *out, &to_stacktrace(trace, options))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stack_trace::CaptureSource;
+    use crate::InlineFrame;
+
+    fn trace() -> StackTrace {
+        let mut py = CallFrame::python("0x7f00", "/srv/app/train.py", "step", 42);
+        if let CallFrame::PyFrame { locals, .. } = &mut py {
+            locals.insert("lr".to_string(), Value::float(0.5));
+            locals.insert("epoch".to_string(), Value::Int(3));
+        }
+        StackTrace::captured(
+            vec![
+                CallFrame::native("0x10", "", "memcpy", 0).with_inlined(vec![InlineFrame {
+                    func: "copy_small".to_string(),
+                    file: "copy.c".to_string(),
+                    lineno: 7,
+                }]),
+                CallFrame::synthetic("[GIL wait]", "gil"),
+                CallFrame::python(
+                    "0x7f10",
+                    "/usr/lib/python3.11/site-packages/torch/nn/module.py",
+                    "forward",
+                    10,
+                ),
+                py,
+            ],
+            CaptureSource::Merged,
+        )
+    }
+
+    #[test]
+    fn test_to_stacktrace() {
+        let stacktrace = to_stacktrace(&trace(), &SentryOptions::new());
+        let summary: Vec<_> = stacktrace
+            .frames
+            .iter()
+            .map(|f| (f.function.as_str(), f.platform, f.in_app))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("step", "python", true),
+                ("forward", "python", false),
+                ("[GIL wait]", "other", false),
+                ("memcpy", "native", false),
+                ("copy_small", "native", false),
+            ]
+        );
+        let leaf = &stacktrace.frames[4];
+        assert_eq!(leaf.instruction_addr.as_deref(), Some("0x10"));
+        assert_eq!(leaf.filename.as_deref(), Some("copy.c"));
+        assert_eq!(stacktrace.frames[3].filename, None);
+        assert_eq!(stacktrace.frames[0].vars["lr"], serde_json::json!(0.5));
+        assert_eq!(stacktrace.frames[0].vars["epoch"], serde_json::json!(3));
+    }
+
+    #[test]
+    fn test_in_app_prefixes() {
+        let options = SentryOptions::new()
+            .in_app_include("/srv/app")
+            .in_app_include("/usr/lib")
+            .in_app_exclude("/usr/lib/python3.11/site-packages/torch");
+        let in_app: Vec<bool> = to_stacktrace(&trace(), &options)
+            .frames
+            .iter()
+            .map(|f| f.in_app)
+            .collect();
+        assert_eq!(in_app, [true, false, false, false, false]);
+    }
+
+    #[test]
+    fn test_classified_frames() {
+        let mut frames = vec![
+            CallFrame::python("0x1", "/opt/tools/util.py", "helper", 2),
+            CallFrame::python("0x2", "/srv/app/jobs/train.py", "main", 5),
+        ];
+        let options = crate::ClassifyOptions::new().app_root("/srv/app");
+        crate::SignalTracer::classify_frames_with(&mut frames, &options);
+        let sentry = to_stacktrace(&frames, &SentryOptions::new()).frames;
+        assert_eq!(sentry[0].module.as_deref(), Some("jobs.train"));
+        assert_eq!(sentry[0].package.as_deref(), Some("jobs"));
+        assert!(sentry[0].in_app);
+        // Outside the app root, although not a library path.
+        assert!(!sentry[1].in_app);
+    }
+
+    #[test]
+    fn test_exception_event() {
+        let mut trace = trace();
+        trace.tid = Some(7);
+        trace.timestamp = Some(UNIX_EPOCH + std::time::Duration::from_millis(1_500));
+        let event = exception_event(&trace, "Hang", "no heartbeat", &SentryOptions::new());
+        assert_eq!(event["platform"], "python");
+        assert_eq!(event["timestamp"], 1.5);
+        let exception = &event["exception"]["values"][0];
+        assert_eq!(exception["type"], "Hang");
+        assert_eq!(exception["thread_id"], 7);
+        assert_eq!(
+            exception["stacktrace"]["frames"][4]["function"],
+            "copy_small"
+        );
+        assert_eq!(exception["stacktrace"]["frames"][1].get("vars"), None);
+    }
+}
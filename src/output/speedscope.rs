@@ -0,0 +1,284 @@
+//! speedscope JSON export (https://www.speedscope.app/file-format-schema.json).
+//! One sampled profile per thread, sharing a single frames table.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+
+use serde::Serialize;
+
+use crate::frame_filter::count_repeats;
+use crate::profile::Profile;
+use crate::CallFrame;
+
+const SCHEMA: &str = "https://www.speedscope.app/file-format-schema.json";
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct File<'a> {
+    #[serde(rename = "$schema")]
+    schema: &'static str,
+    name: &'a str,
+    exporter: String,
+    active_profile_index: usize,
+    shared: Shared,
+    profiles: Vec<SampledProfile>,
+}
+
+#[derive(Serialize)]
+struct Shared {
+    frames: Vec<Frame>,
+}
+
+/// speedscope frame; `kind` is extra metadata ("python" / "native") ignored by the viewer.
+#[derive(Serialize)]
+struct Frame {
+    name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<i64>,
+    kind: &'static str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SampledProfile {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    name: String,
+    unit: &'static str,
+    start_value: u64,
+    end_value: u64,
+    samples: Vec<Vec<usize>>,
+    weights: Vec<u64>,
+}
+
+#[derive(Default)]
+struct FrameTable {
+    frames: Vec<Frame>,
+    index: HashMap<(&'static str, String, String, i64), usize>,
+}
+
+impl FrameTable {
+    fn intern(&mut self, frame: &CallFrame) -> usize {
+        let (kind, func, file, lineno) = (
+            frame.kind().as_str(),
+            frame.func(),
+            frame.file(),
+            frame.lineno(),
+        );
+
+        let key = (kind, func.to_string(), file.to_string(), lineno);
+        if let Some(i) = self.index.get(&key) {
+            return *i;
+        }
+
+        let i = self.frames.len();
+        self.frames.push(Frame {
+            name: func.to_string(),
+            file: (!file.is_empty()).then(|| file.to_string()),
+            line: (lineno > 0).then_some(lineno),
+            kind,
+        });
+        self.index.insert(key, i);
+        i
+    }
+}
+
+/// Controls how stacks are written by `write_profile_with`.
+#[derive(Clone, Debug, Default)]
+pub struct SpeedscopeOptions {
+    count_recursion: bool,
+}
+
+impl SpeedscopeOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Collapse recursive call cycles into one, named with the number of calls (see
+    /// `FrameFilter::count_recursion`).
+    pub fn count_recursion(mut self, count: bool) -> Self {
+        self.count_recursion = count;
+        self
+    }
+}
+
+/// Serialize `profile` as a speedscope document titled `name`. Weighted profiles are in
+/// nanoseconds, others in samples.
+pub fn write_profile<W: Write>(out: &mut W, profile: &Profile, name: &str) -> io::Result<()> {
+    write_profile_with(out, profile, name, &SpeedscopeOptions::new())
+}
+
+/// Like `write_profile`, with `options`.
+pub fn write_profile_with<W: Write>(
+    out: &mut W,
+    profile: &Profile,
+    name: &str,
+    options: &SpeedscopeOptions,
+) -> io::Result<()> {
+    let mut table = FrameTable::default();
+    let mut threads: BTreeMap<i32, SampledProfile> = BTreeMap::new();
+    let weighted = profile.is_weighted();
+
+    for stack in &profile.stacks {
+        let thread = threads.entry(stack.tid).or_insert_with(|| SampledProfile {
+            kind: "sampled",
+            name: format!("thread {}", stack.tid),
+            unit: if weighted { "nanoseconds" } else { "none" },
+            start_value: 0,
+            end_value: 0,
+            samples: Vec::new(),
+            weights: Vec::new(),
+        });
+        let counted;
+        let frames = if options.count_recursion {
+            counted = count_repeats(stack.frames.clone());
+            &counted
+        } else {
+            &stack.frames
+        };
+        // speedscope stacks are root first, merged stacks are leaf first
+        let sample = frames.iter().rev().map(|f| table.intern(f)).collect();
+        thread.samples.push(sample);
+        let weight = if weighted {
+            stack.weight_ns
+        } else {
+            stack.count
+        };
+        thread.weights.push(weight);
+        thread.end_value += weight;
+    }
+
+    let file = File {
+        schema: SCHEMA,
+        name,
+        exporter: format!("mixed-stack-tracer@{}", env!("CARGO_PKG_VERSION")),
+        active_profile_index: 0,
+        shared: Shared {
+            frames: table.frames,
+        },
+        profiles: threads.into_values().collect(),
+    };
+    serde_json::to_writer(out, &file).map_err(io::Error::from)
+}
+
+/// Convenience wrapper around `write_profile` returning the JSON text.
+pub fn profile_to_string(profile: &Profile, name: &str) -> String {
+    let mut out = Vec::new();
+    write_profile(&mut out, profile, name).expect("writing to a Vec cannot fail");
+    String::from_utf8(out).expect("serde_json emits UTF-8")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::SampledStack;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn test_speedscope_document() {
+        let leaf = CallFrame::native("0x1", "lib.c", "leaf", 10);
+        let py = CallFrame::python("0x2", "app.py", "handler", 3);
+        let main = CallFrame::native("0x3", "", "main", 0);
+        let profile = Profile {
+            stacks: vec![
+                SampledStack {
+                    tid: 20,
+                    frames: vec![leaf.clone(), py.clone(), main.clone()],
+                    count: 3,
+                    ..SampledStack::default()
+                },
+                SampledStack {
+                    tid: 10,
+                    frames: vec![main.clone()],
+                    count: 1,
+                    ..SampledStack::default()
+                },
+                SampledStack {
+                    tid: 20,
+                    frames: vec![py, main],
+                    count: 2,
+                    ..SampledStack::default()
+                },
+            ],
+            total_samples: 6,
+            dropped_samples: 0,
+        };
+
+        let doc: Value = serde_json::from_str(&profile_to_string(&profile, "run")).unwrap();
+        assert_eq!(doc["$schema"], SCHEMA);
+        assert_eq!(doc["name"], "run");
+        assert_eq!(
+            doc["shared"]["frames"],
+            json!([
+                {"name": "main", "kind": "native"},
+                {"name": "handler", "file": "app.py", "line": 3, "kind": "python"},
+                {"name": "leaf", "file": "lib.c", "line": 10, "kind": "native"},
+            ])
+        );
+
+        // Threads are sorted by tid.
+        let profiles = doc["profiles"].as_array().unwrap();
+        assert_eq!(profiles.len(), 2);
+        assert_eq!(profiles[0]["name"], "thread 10");
+        assert_eq!(profiles[0]["samples"], json!([[0]]));
+        assert_eq!(profiles[1]["type"], "sampled");
+        assert_eq!(profiles[1]["samples"], json!([[0, 1, 2], [0, 1]]));
+        assert_eq!(profiles[1]["weights"], json!([3, 2]));
+        assert_eq!(profiles[1]["endValue"], 5);
+        assert_eq!(profiles[1]["unit"], "none");
+    }
+
+    #[test]
+    fn test_speedscope_counts_recursion() {
+        let fib = |lineno| CallFrame::python("0x2", "fib.py", "fib", lineno);
+        let profile = Profile {
+            stacks: vec![SampledStack {
+                tid: 1,
+                frames: vec![
+                    fib(2),
+                    fib(3),
+                    fib(3),
+                    CallFrame::native("0x3", "", "main", 0),
+                ],
+                count: 1,
+                ..SampledStack::default()
+            }],
+            total_samples: 1,
+            dropped_samples: 0,
+        };
+        let mut out = Vec::new();
+        let options = SpeedscopeOptions::new().count_recursion(true);
+        write_profile_with(&mut out, &profile, "fib", &options).unwrap();
+        let doc: Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(
+            doc["shared"]["frames"],
+            json!([
+                {"name": "main", "kind": "native"},
+                {"name": "fib [x3]", "file": "fib.py", "line": 2, "kind": "python"},
+            ])
+        );
+        assert_eq!(doc["profiles"][0]["samples"], json!([[0, 1]]));
+    }
+
+    #[test]
+    fn test_speedscope_weighted() {
+        let profile = Profile {
+            stacks: vec![SampledStack {
+                tid: 1,
+                frames: vec![CallFrame::native("0x1", "", "main", 0)],
+                count: 2,
+                weight_ns: 20_000_000,
+                ..SampledStack::default()
+            }],
+            total_samples: 2,
+            dropped_samples: 0,
+        };
+        let doc: Value = serde_json::from_str(&profile_to_string(&profile, "run")).unwrap();
+        let thread = &doc["profiles"][0];
+        assert_eq!(thread["unit"], "nanoseconds");
+        assert_eq!(thread["weights"], json!([20_000_000]));
+        assert_eq!(thread["endValue"], 20_000_000);
+    }
+}
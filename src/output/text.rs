@@ -0,0 +1,134 @@
+//! Human-readable thread dumps, one block per thread, frames leaf first.
+//!
+//! ```text
+//! Thread 7 "main" (Sleeping) [has GIL]
+//!   #0 0x10 clock_nanosleep (/lib/libc.so.6)
+//!   #1 [py] run (app.py:3)
+//! ```
+
+use std::io::{self, Write};
+
+use crate::thread_stack::ThreadStack;
+use crate::CallFrame;
+
+/// Write `stacks` in the format of `mst dump`, each thread followed by a blank line.
+pub fn write_thread_stacks<W: Write>(out: &mut W, stacks: &[ThreadStack]) -> io::Result<()> {
+    for stack in stacks {
+        write!(
+            out,
+            "Thread {} \"{}\" ({:?})",
+            stack.tid, stack.name, stack.os_state
+        )?;
+        if stack.is_gil_holder {
+            write!(out, " [has GIL]")?;
+        }
+        if stack.greenlet {
+            write!(out, " [greenlet]")?;
+        }
+        if let Some(id) = stack.interpreter_id.filter(|id| *id != 0) {
+            write!(out, " [interpreter {}]", id)?;
+        }
+        writeln!(out)?;
+        write_frames(out, &stack.frames)?;
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// Write one `  #i ...` line per frame, as in a thread block of `write_thread_stacks`,
+/// followed by the frame's source line when one was attached (`attach_source`).
+pub fn write_frames<W: Write>(out: &mut W, frames: &[CallFrame]) -> io::Result<()> {
+    for (i, frame) in frames.iter().enumerate() {
+        match frame {
+            CallFrame::CFrame { ip, file, func, .. } => {
+                writeln!(out, "  #{} {} {} ({})", i, ip, func, file)?
+            }
+            CallFrame::PyFrame {
+                file, func, lineno, ..
+            } => writeln!(out, "  #{} [py] {} ({}:{})", i, func, file, lineno)?,
+            CallFrame::GpuFrame { kernel, .. } => writeln!(out, "  #{} [gpu] {}", i, kernel)?,
+            CallFrame::InterpreterFrame {
+                runtime,
+                file,
+                func,
+                lineno,
+            } => writeln!(out, "  #{} [{}] {} ({}:{})", i, runtime, func, file, lineno)?,
+            CallFrame::Synthetic { label, category } => {
+                writeln!(out, "  #{} [{}] {}", i, category, label)?
+            }
+        }
+        if let CallFrame::CFrame {
+            source: Some(source),
+            ..
+        }
+        | CallFrame::PyFrame {
+            source: Some(source),
+            ..
+        } = frame
+        {
+            writeln!(out, "        {}", source.line.trim())?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::thread_stack::ThreadState;
+
+    #[test]
+    fn test_write_thread_stacks() {
+        let mut python = CallFrame::python("0x20", "app.py", "run", 3);
+        if let CallFrame::PyFrame { source, .. } = &mut python {
+            *source = Some(Box::new(crate::SourceContext {
+                line: "    time.sleep(1)".to_string(),
+                pre: Vec::new(),
+                post: Vec::new(),
+            }));
+        }
+        let stacks = [ThreadStack {
+            tid: 7,
+            name: "main".to_string(),
+            os_state: ThreadState::Sleeping,
+            is_gil_holder: true,
+            greenlet: false,
+            interpreter_id: None,
+            frames: vec![
+                CallFrame::native("0x10", "/lib/libc.so.6", "clock_nanosleep", 0),
+                python,
+            ],
+        }];
+        let mut out = Vec::new();
+        write_thread_stacks(&mut out, &stacks).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Thread 7 \"main\" (Sleeping) [has GIL]\n  #0 0x10 clock_nanosleep (/lib/libc.so.6)\n  #1 [py] run (app.py:3)\n        time.sleep(1)\n\n"
+        );
+    }
+
+    #[test]
+    fn test_write_subinterpreter_thread() {
+        let mut stack = ThreadStack {
+            tid: 8,
+            name: "worker".to_string(),
+            os_state: ThreadState::Running,
+            is_gil_holder: false,
+            greenlet: false,
+            interpreter_id: Some(0),
+            frames: Vec::new(),
+        };
+        let header = |stack: &ThreadStack| {
+            let mut out = Vec::new();
+            write_thread_stacks(&mut out, std::slice::from_ref(stack)).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        // The main interpreter is not worth a tag.
+        assert_eq!(header(&stack), "Thread 8 \"worker\" (Running)\n\n");
+        stack.interpreter_id = Some(2);
+        assert_eq!(
+            header(&stack),
+            "Thread 8 \"worker\" (Running) [interpreter 2]\n\n"
+        );
+    }
+}
@@ -0,0 +1,89 @@
+//! Minimal protobuf wire-format encoding shared by the hand-written exporters.
+
+/// Minimal protobuf wire-format writer (varints and length-delimited fields only).
+#[derive(Default)]
+pub(crate) struct ProtoWriter {
+    pub(crate) buf: Vec<u8>,
+}
+
+impl ProtoWriter {
+    pub(crate) fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.buf.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.buf.push(value as u8);
+    }
+
+    pub(crate) fn key(&mut self, field: u32, wire_type: u8) {
+        self.varint(((field as u64) << 3) | wire_type as u64);
+    }
+
+    pub(crate) fn uint64(&mut self, field: u32, value: u64) {
+        // Proto3 scalars equal to zero are omitted.
+        if value != 0 {
+            self.key(field, 0);
+            self.varint(value);
+        }
+    }
+
+    pub(crate) fn int64(&mut self, field: u32, value: i64) {
+        self.uint64(field, value as u64);
+    }
+
+    pub(crate) fn bytes(&mut self, field: u32, data: &[u8]) {
+        self.key(field, 2);
+        self.varint(data.len() as u64);
+        self.buf.extend_from_slice(data);
+    }
+
+    pub(crate) fn message(&mut self, field: u32, message: &ProtoWriter) {
+        self.bytes(field, &message.buf);
+    }
+
+    pub(crate) fn packed_uint64(&mut self, field: u32, values: &[u64]) {
+        let mut packed = ProtoWriter::default();
+        for v in values {
+            packed.varint(*v);
+        }
+        self.bytes(field, &packed.buf);
+    }
+
+    pub(crate) fn packed_int64(&mut self, field: u32, values: &[i64]) {
+        let values: Vec<u64> = values.iter().map(|v| *v as u64).collect();
+        self.packed_uint64(field, &values);
+    }
+}
+
+/// Decode one level of a message into (field, varint or bytes) pairs.
+#[cfg(test)]
+pub(crate) fn fields(mut data: &[u8]) -> Vec<(u64, Result<u64, Vec<u8>>)> {
+    fn varint(data: &mut &[u8]) -> u64 {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let b = data[0];
+            *data = &data[1..];
+            value |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                return value;
+            }
+            shift += 7;
+        }
+    }
+
+    let mut out = Vec::new();
+    while !data.is_empty() {
+        let key = varint(&mut data);
+        match key & 7 {
+            0 => out.push((key >> 3, Ok(varint(&mut data)))),
+            2 => {
+                let len = varint(&mut data) as usize;
+                out.push((key >> 3, Err(data[..len].to_vec())));
+                data = &data[len..];
+            }
+            other => panic!("unexpected wire type {}", other),
+        }
+    }
+    out
+}
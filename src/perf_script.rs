@@ -0,0 +1,339 @@
+//! Native samples recorded by Linux `perf`, read from `perf script` output.
+//!
+//! ```text
+//! perf record -g -p <pid> -- sleep 10
+//! perf script > perf.txt
+//! ```
+//!
+//! Each sample becomes a leaf-first stack of `CFrame`s (file is the DSO, `[kernel.kallsyms]`
+//! frames are tagged with the `kernel` category), which can be merged with Python stacks
+//! captured by this crate or aggregated into a `Profile`. Binary `perf.data` files are not
+//! read directly; `perf script` is the stable interface to them.
+
+use std::io::{self, BufRead};
+
+use crate::profile::{Profile, SampleState, StackAggregator, TimedSample};
+use crate::stack_tracer::SignalTracer;
+use crate::CallFrame;
+
+/// Category of frames in the kernel image.
+pub const KERNEL_CATEGORY: &str = "kernel";
+/// Event of `perf record --off-cpu`, whose period is the time blocked in nanoseconds.
+pub const OFF_CPU_EVENT: &str = "offcpu-time";
+/// Software clock events, whose period is CPU time in nanoseconds.
+const CLOCK_EVENTS: [&str; 2] = ["cpu-clock", "task-clock"];
+
+/// One sample of `perf script` output.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PerfSample {
+    pub comm: String,
+    /// 0 when the output has only a tid column (e.g. `perf script -F comm,tid,...`).
+    pub pid: i32,
+    pub tid: i32,
+    pub cpu: Option<u32>,
+    /// perf clock time in nanoseconds.
+    pub timestamp_ns: u64,
+    /// Event count of the sample, 1 when not printed.
+    pub period: u64,
+    /// Event name, e.g. `cycles:u` or `cpu-clock`.
+    pub event: String,
+    /// Callchain, leaf first.
+    pub frames: Vec<CallFrame>,
+}
+
+impl PerfSample {
+    /// Merge this native stack with the Python stack captured for the same thread.
+    pub fn merge_python(&self, python: impl Into<Vec<CallFrame>>) -> Vec<CallFrame> {
+        SignalTracer::merge_python_native_stacks(python, self.frames.clone())
+    }
+
+    pub fn state(&self) -> SampleState {
+        if self.event_name() == OFF_CPU_EVENT {
+            SampleState::OffCpu
+        } else {
+            SampleState::OnCpu
+        }
+    }
+
+    /// Time the sample stands for: the period of clock and off-CPU events, 0 for hardware
+    /// events (cycles, instructions) or when no period was printed.
+    pub fn weight_ns(&self) -> u64 {
+        let event = self.event_name();
+        let timed = event == OFF_CPU_EVENT || CLOCK_EVENTS.contains(&event);
+        if timed && self.period > 1 {
+            self.period
+        } else {
+            0
+        }
+    }
+
+    pub fn to_timed_sample(&self) -> TimedSample {
+        TimedSample {
+            tid: self.tid,
+            timestamp_ns: self.timestamp_ns,
+            frames: self.frames.clone(),
+            cpu: self.cpu,
+            state: self.state(),
+            weight_ns: self.weight_ns(),
+        }
+    }
+
+    /// `event` without its modifiers (`cycles:u` is `cycles`).
+    fn event_name(&self) -> &str {
+        self.event.split(':').next().unwrap_or_default()
+    }
+}
+
+/// Parse the whole output of `perf script`.
+pub fn parse_perf_script(text: &str) -> Vec<PerfSample> {
+    let mut samples = Vec::new();
+    let mut current: Option<PerfSample> = None;
+    for line in text.lines() {
+        if line.starts_with('#') {
+            continue;
+        }
+        if line.trim().is_empty() {
+            samples.extend(current.take());
+        } else if line.starts_with(char::is_whitespace) {
+            if let (Some(sample), Some(frame)) = (current.as_mut(), parse_frame(line.trim())) {
+                sample.frames.push(frame);
+            }
+        } else {
+            samples.extend(current.take());
+            current = parse_header(line);
+        }
+    }
+    samples.extend(current);
+    samples
+}
+
+/// Like `parse_perf_script`, reading from `reader`.
+pub fn read_perf_script<R: BufRead>(mut reader: R) -> io::Result<Vec<PerfSample>> {
+    let mut text = String::new();
+    reader.read_to_string(&mut text)?;
+    Ok(parse_perf_script(&text))
+}
+
+/// Aggregate samples into a profile, one count per sample, weighted by the periods of
+/// time events (see `PerfSample::weight_ns`).
+pub fn to_profile(samples: &[PerfSample]) -> Profile {
+    let mut aggregator = StackAggregator::default();
+    for sample in samples {
+        aggregator.add_weighted(
+            sample.tid,
+            sample.cpu,
+            sample.state(),
+            &sample.frames,
+            1,
+            sample.weight_ns(),
+        );
+    }
+    aggregator.into_profile()
+}
+
+/// `comm pid/tid [cpu] secs.frac: [period] event: [ip sym (dso)]`; comm may contain spaces.
+fn parse_header(line: &str) -> Option<PerfSample> {
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    let time_at = tokens
+        .iter()
+        .position(|t| t.strip_suffix(':').is_some_and(is_timestamp))?;
+    let mut ids_at = time_at.checked_sub(1)?;
+    let cpu = tokens[ids_at]
+        .strip_prefix('[')
+        .and_then(|t| t.strip_suffix(']'))
+        .and_then(|t| t.parse().ok());
+    if cpu.is_some() {
+        ids_at = ids_at.checked_sub(1)?;
+    }
+    let (pid, tid) = match tokens[ids_at].split_once('/') {
+        Some((pid, tid)) => (pid.parse().ok()?, tid.parse().ok()?),
+        None => (0, tokens[ids_at].parse().ok()?),
+    };
+    let comm = tokens[..ids_at].join(" ");
+
+    let mut rest = tokens[time_at + 1..].iter();
+    let mut next = rest.next();
+    let period = match next.and_then(|t| t.parse().ok()) {
+        Some(period) => {
+            next = rest.next();
+            period
+        }
+        None => 1,
+    };
+    let event = next?.trim_end_matches(':').to_string();
+    // Without -g, perf prints the sampled ip on the header line.
+    let inline: Vec<&str> = rest.copied().collect();
+    let frames = parse_frame(&inline.join(" ")).into_iter().collect();
+
+    Some(PerfSample {
+        comm,
+        pid,
+        tid,
+        cpu,
+        timestamp_ns: parse_timestamp(tokens[time_at].trim_end_matches(':'))?,
+        period,
+        event,
+        frames,
+    })
+}
+
+/// `ip symbol+0xoff (dso)`; symbols may contain spaces and parentheses.
+fn parse_frame(line: &str) -> Option<CallFrame> {
+    let (ip, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    if ip.is_empty() || !ip.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    let rest = rest.trim();
+    let (symbol, dso) = match dso_start(rest) {
+        Some(i) => (&rest[..i], &rest[i + 1..rest.len() - 1]),
+        None => (rest, ""),
+    };
+    let symbol = strip_offset(symbol.trim());
+    let func = match symbol {
+        "" | "[unknown]" => "??",
+        symbol => symbol,
+    };
+    let file = if dso == "[unknown]" { "" } else { dso };
+
+    let mut frame = CallFrame::native(format!("0x{}", ip), file, func, 0);
+    if dso == "[kernel.kallsyms]" {
+        if let CallFrame::CFrame { category, .. } = &mut frame {
+            *category = Some(KERNEL_CATEGORY.to_string());
+        }
+    }
+    Some(frame)
+}
+
+/// Index of the `(` opening the trailing, possibly nested, `(dso)` group.
+fn dso_start(rest: &str) -> Option<usize> {
+    if !rest.ends_with(')') {
+        return None;
+    }
+    let mut depth = 0;
+    for (i, b) in rest.bytes().enumerate().rev() {
+        match b {
+            b')' => depth += 1,
+            b'(' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn strip_offset(symbol: &str) -> &str {
+    match symbol.rsplit_once("+0x") {
+        Some((name, offset)) if offset.bytes().all(|b| b.is_ascii_hexdigit()) => name,
+        _ => symbol,
+    }
+}
+
+fn is_timestamp(token: &str) -> bool {
+    token.split_once('.').is_some_and(|(s, f)| {
+        !s.is_empty() && s.bytes().chain(f.bytes()).all(|b| b.is_ascii_digit())
+    })
+}
+
+fn parse_timestamp(token: &str) -> Option<u64> {
+    let (secs, frac) = token.split_once('.')?;
+    let digits = frac.len().min(9);
+    let nanos: u64 = frac[..digits].parse().ok()?;
+    Some(secs.parse::<u64>().ok()? * 1_000_000_000 + nanos * 10u64.pow((9 - digits) as u32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCRIPT: &str = "\
+# ========
+# captured on: Tue Oct 14 10:00:00 2026
+python3 1234/1235 [002] 12345.678901:     250000 cycles:u: 
+\t    7f1234567890 PyEval_EvalFrameDefault+0x123 (/usr/lib/libpython3.11.so.1.0)
+\t    ffffffff81000000 do_syscall_64+0x5c ([kernel.kallsyms])
+\t    55d0a1b2c3d4 Foo::bar(int, char const*)+0x10 (/usr/bin/app (deleted))
+\t    7f00 [unknown] ([unknown])
+
+pt_main thread 1234/1240 12345.678902123: cpu-clock:  55d0a1b2c3d4 main+0x10 (/usr/bin/python3.11)
+
+";
+
+    #[test]
+    fn test_parse_perf_script() {
+        let samples = parse_perf_script(SCRIPT);
+        assert_eq!(samples.len(), 2);
+
+        let first = &samples[0];
+        assert_eq!(first.comm, "python3");
+        assert_eq!((first.pid, first.tid, first.cpu), (1234, 1235, Some(2)));
+        assert_eq!(first.timestamp_ns, 12_345_678_901_000);
+        assert_eq!(first.period, 250_000);
+        assert_eq!(first.event, "cycles:u");
+        assert_eq!(
+            first.frames[0],
+            CallFrame::native(
+                "0x7f1234567890",
+                "/usr/lib/libpython3.11.so.1.0",
+                "PyEval_EvalFrameDefault",
+                0
+            )
+        );
+        assert!(matches!(
+            &first.frames[1],
+            CallFrame::CFrame { func, category: Some(c), .. }
+                if func == "do_syscall_64" && c == KERNEL_CATEGORY
+        ));
+        assert_eq!(first.frames[2].func(), "Foo::bar(int, char const*)");
+        assert_eq!(first.frames[2].file(), "/usr/bin/app (deleted)");
+        assert_eq!(first.frames[3], CallFrame::native("0x7f00", "", "??", 0));
+
+        let second = &samples[1];
+        assert_eq!(second.comm, "pt_main thread");
+        assert_eq!((second.pid, second.tid, second.cpu), (1234, 1240, None));
+        assert_eq!(second.timestamp_ns, 12_345_678_902_123);
+        assert_eq!((second.period, second.event.as_str()), (1, "cpu-clock"));
+        assert_eq!(second.frames.len(), 1);
+        assert_eq!(second.frames[0].func(), "main");
+    }
+
+    #[test]
+    fn test_to_profile_and_merge() {
+        let mut samples = parse_perf_script(SCRIPT);
+        samples.push(samples[0].clone());
+        let profile = to_profile(&samples);
+        assert_eq!(profile.total_samples, 3);
+        assert_eq!(profile.stacks[0].count, 2);
+
+        let python = vec![CallFrame::python("0x1", "train.py", "step", 7)];
+        let merged = samples[0].merge_python(python);
+        assert_eq!(merged[0].func(), "step");
+        assert!(merged.iter().any(|f| f.func() == "do_syscall_64"));
+    }
+
+    #[test]
+    fn test_time_event_weights() {
+        let script = "\
+app 10/11 [001] 1.000000:    1000000 cpu-clock:u: 
+\t    1000 work+0x1 (/usr/bin/app)
+
+app 10/11 [003] 1.500000:   20000000 offcpu-time: 
+\t    2000 wait+0x1 (/usr/bin/app)
+
+";
+        let samples = parse_perf_script(script);
+        assert_eq!(samples[0].weight_ns(), 1_000_000);
+        assert_eq!(samples[1].state(), SampleState::OffCpu);
+        let timed = samples[1].to_timed_sample();
+        assert_eq!((timed.cpu, timed.weight_ns), (Some(3), 20_000_000));
+
+        // cycles count no time.
+        assert_eq!(parse_perf_script(SCRIPT)[0].weight_ns(), 0);
+        let profile = to_profile(&samples);
+        assert_eq!(profile.total_weight_ns(), 21_000_000);
+        assert_eq!(profile.weight_type(), "wall");
+    }
+}
@@ -0,0 +1,251 @@
+//! Aggregated sampling results shared by the sampler and the exporters.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::frame_table::{FrameId, FrameTable};
+use crate::CallFrame;
+
+/// Whether a sample was taken while its thread ran or while it was blocked.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum SampleState {
+    #[default]
+    OnCpu,
+    OffCpu,
+}
+
+impl SampleState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SampleState::OnCpu => "on_cpu",
+            SampleState::OffCpu => "off_cpu",
+        }
+    }
+}
+
+/// A merged stack together with the number of samples that hit it.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SampledStack {
+    pub tid: i32,
+    pub frames: Vec<CallFrame>,
+    pub count: u64,
+    /// CPU the thread ran on, when known; samples on different CPUs are counted apart.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<u32>,
+    #[serde(default)]
+    pub state: SampleState,
+    /// Time the samples stand for in nanoseconds (CPU time on-CPU, blocked time off-CPU);
+    /// 0 when the source only counts samples.
+    #[serde(default)]
+    pub weight_ns: u64,
+}
+
+/// Aggregated result of a sampling session.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Profile {
+    pub stacks: Vec<SampledStack>,
+    pub total_samples: u64,
+    /// Ticks lost because the ring was full when the signal fired.
+    pub dropped_samples: u64,
+}
+
+impl Profile {
+    /// Whether stacks carry time weights, so exporters can report nanoseconds instead of
+    /// sample counts.
+    pub fn is_weighted(&self) -> bool {
+        self.stacks.iter().any(|s| s.weight_ns > 0)
+    }
+
+    pub fn total_weight_ns(&self) -> u64 {
+        self.stacks.iter().map(|s| s.weight_ns).sum()
+    }
+
+    /// `cpu` when every stack was on-CPU, `wall` once blocked time is included: the
+    /// pprof sample type of the weights.
+    pub fn weight_type(&self) -> &'static str {
+        if self.stacks.iter().any(|s| s.state == SampleState::OffCpu) {
+            "wall"
+        } else {
+            "cpu"
+        }
+    }
+}
+
+/// One merged stack captured at a point in time, for timeline exporters.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TimedSample {
+    pub tid: i32,
+    /// Capture time in nanoseconds on a monotonic clock.
+    pub timestamp_ns: u64,
+    pub frames: Vec<CallFrame>,
+    /// CPU the thread ran on, when known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<u32>,
+    #[serde(default)]
+    pub state: SampleState,
+    /// As in `SampledStack`, for this one sample.
+    #[serde(default)]
+    pub weight_ns: u64,
+}
+
+type StackKey = (i32, Option<u32>, SampleState, Vec<FrameId>);
+
+/// Counts identical merged stacks per thread, CPU and state on interned frames, summing their
+/// weights, and builds the `Profile` only once sampling is over.
+#[derive(Debug, Default)]
+pub(crate) struct StackAggregator {
+    table: FrameTable,
+    index: HashMap<StackKey, usize>,
+    stacks: Vec<(StackKey, u64, u64)>,
+    total_samples: u64,
+}
+
+impl StackAggregator {
+    pub(crate) fn add(&mut self, tid: i32, frames: &[CallFrame]) {
+        self.add_count(tid, frames, 1);
+    }
+
+    /// Record `count` unweighted on-CPU samples of the same stack.
+    pub(crate) fn add_count(&mut self, tid: i32, frames: &[CallFrame], count: u64) {
+        self.add_weighted(tid, None, SampleState::OnCpu, frames, count, 0);
+    }
+
+    /// Record `count` samples of the same stack standing for `weight_ns` in total.
+    pub(crate) fn add_weighted(
+        &mut self,
+        tid: i32,
+        cpu: Option<u32>,
+        state: SampleState,
+        frames: &[CallFrame],
+        count: u64,
+        weight_ns: u64,
+    ) {
+        self.total_samples += count;
+        let key = (tid, cpu, state, self.table.intern_stack(frames));
+        match self.index.get(&key) {
+            Some(i) => {
+                self.stacks[*i].1 += count;
+                self.stacks[*i].2 += weight_ns;
+            }
+            None => {
+                self.index.insert(key.clone(), self.stacks.len());
+                self.stacks.push((key, count, weight_ns));
+            }
+        }
+    }
+
+    pub(crate) fn total_samples(&self) -> u64 {
+        self.total_samples
+    }
+
+    /// Stacks in first-seen order.
+    pub(crate) fn into_profile(self) -> Profile {
+        Profile {
+            stacks: self
+                .stacks
+                .iter()
+                .map(|((tid, cpu, state, ids), count, weight_ns)| SampledStack {
+                    tid: *tid,
+                    frames: self.table.stack(ids),
+                    count: *count,
+                    cpu: *cpu,
+                    state: *state,
+                    weight_ns: *weight_ns,
+                })
+                .collect(),
+            total_samples: self.total_samples,
+            dropped_samples: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stack_aggregator() {
+        let mut aggregator = StackAggregator::default();
+        let a = vec![CallFrame::python("0x1", "a.py", "f", 1)];
+        // Same function and line at another frame address: the same stack.
+        let a_again = vec![CallFrame::python("0x2", "a.py", "f", 1)];
+        aggregator.add(7, &a);
+        aggregator.add(8, &a);
+        aggregator.add(7, &a_again);
+        assert_eq!(aggregator.total_samples(), 3);
+
+        let profile = aggregator.into_profile();
+        assert_eq!(profile.total_samples, 3);
+        assert_eq!(
+            profile.stacks,
+            vec![
+                SampledStack {
+                    tid: 7,
+                    frames: a.clone(),
+                    count: 2,
+                    ..SampledStack::default()
+                },
+                SampledStack {
+                    tid: 8,
+                    frames: a,
+                    count: 1,
+                    ..SampledStack::default()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stack_aggregator_weights() {
+        let mut aggregator = StackAggregator::default();
+        let a = vec![CallFrame::python("0x1", "a.py", "f", 1)];
+        aggregator.add_weighted(7, None, SampleState::OnCpu, &a, 1, 10);
+        aggregator.add_weighted(7, None, SampleState::OnCpu, &a, 2, 20);
+        // Blocked in the same place is another stack.
+        aggregator.add_weighted(7, None, SampleState::OffCpu, &a, 1, 500);
+
+        let profile = aggregator.into_profile();
+        assert_eq!(profile.total_samples, 4);
+        assert_eq!(profile.stacks.len(), 2);
+        assert_eq!(
+            (profile.stacks[0].count, profile.stacks[0].weight_ns),
+            (3, 30)
+        );
+        assert_eq!(profile.stacks[1].state, SampleState::OffCpu);
+        assert!(profile.is_weighted());
+        assert_eq!(profile.total_weight_ns(), 530);
+        assert_eq!(profile.weight_type(), "wall");
+    }
+
+    #[test]
+    fn test_stack_aggregator_cpus() {
+        let mut aggregator = StackAggregator::default();
+        let a = vec![CallFrame::python("0x1", "a.py", "f", 1)];
+        aggregator.add_weighted(7, Some(0), SampleState::OnCpu, &a, 1, 10);
+        aggregator.add_weighted(7, Some(3), SampleState::OnCpu, &a, 1, 10);
+        aggregator.add_weighted(7, Some(0), SampleState::OnCpu, &a, 2, 20);
+
+        let profile = aggregator.into_profile();
+        let cpus: Vec<_> = profile.stacks.iter().map(|s| (s.cpu, s.count)).collect();
+        assert_eq!(cpus, vec![(Some(0), 3), (Some(3), 1)]);
+    }
+
+    #[test]
+    fn test_sampled_stack_serde_defaults() {
+        let stack: SampledStack =
+            serde_json::from_str(r#"{"tid":1,"frames":[],"count":2}"#).unwrap();
+        assert_eq!(stack.state, SampleState::OnCpu);
+        assert_eq!(stack.weight_ns, 0);
+        assert_eq!(stack.cpu, None);
+        let json = serde_json::to_string(&SampledStack {
+            state: SampleState::OffCpu,
+            ..stack
+        })
+        .unwrap();
+        assert!(json.contains(r#""state":"off_cpu""#), "{}", json);
+    }
+}
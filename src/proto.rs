@@ -0,0 +1,621 @@
+//! Protobuf messages of `proto/mixed_stack_tracer.proto` (feature `proto`).
+//!
+//! The messages are declared with prost's derive macros, mirroring the schema by hand like
+//! the pprof exporter does, so building needs no `protoc`. Conversions from the crate
+//! types are infallible; the reverse ones fail on frames without a `frame` variant.
+
+use std::collections::HashMap;
+use std::io;
+use std::time::{Duration, UNIX_EPOCH};
+
+use prost::Message;
+
+use crate::stack_trace::CaptureSource as Source;
+use crate::value::Value as LocalValue;
+
+#[derive(Clone, PartialEq, Message)]
+pub struct InlineFrame {
+    #[prost(string, tag = "1")]
+    pub func: String,
+    #[prost(string, tag = "2")]
+    pub file: String,
+    #[prost(int64, tag = "3")]
+    pub lineno: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct NativeFrame {
+    #[prost(string, tag = "1")]
+    pub ip: String,
+    #[prost(string, tag = "2")]
+    pub file: String,
+    #[prost(string, tag = "3")]
+    pub func: String,
+    #[prost(int64, tag = "4")]
+    pub lineno: i64,
+    #[prost(string, optional, tag = "5")]
+    pub raw_func: Option<String>,
+    #[prost(message, repeated, tag = "6")]
+    pub inlined: Vec<InlineFrame>,
+    #[prost(string, optional, tag = "7")]
+    pub category: Option<String>,
+    #[prost(message, optional, tag = "8")]
+    pub source: Option<SourceContext>,
+    #[prost(string, optional, tag = "9")]
+    pub module_path: Option<String>,
+    #[prost(string, optional, tag = "10")]
+    pub build_id: Option<String>,
+    #[prost(uint64, optional, tag = "11")]
+    pub offset: Option<u64>,
+    #[prost(uint64, optional, tag = "12")]
+    pub eval_frame: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct SourceContext {
+    #[prost(string, tag = "1")]
+    pub line: String,
+    #[prost(string, repeated, tag = "2")]
+    pub pre: Vec<String>,
+    #[prost(string, repeated, tag = "3")]
+    pub post: Vec<String>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Value {
+    #[prost(oneof = "value::Kind", tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10")]
+    pub kind: Option<value::Kind>,
+}
+
+pub mod value {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Kind {
+        #[prost(bool, tag = "1")]
+        None(bool),
+        #[prost(bool, tag = "2")]
+        BoolValue(bool),
+        #[prost(int64, tag = "3")]
+        IntValue(i64),
+        /// Float repr of older writers; read as `Float` when it parses, `Str` otherwise.
+        #[prost(string, tag = "4")]
+        FloatValue(String),
+        #[prost(string, tag = "5")]
+        StrValue(String),
+        #[prost(bytes, tag = "6")]
+        BytesValue(Vec<u8>),
+        #[prost(message, tag = "7")]
+        ListValue(super::ListValue),
+        #[prost(message, tag = "8")]
+        DictValue(super::DictValue),
+        #[prost(message, tag = "9")]
+        ReprValue(super::ReprValue),
+        #[prost(double, tag = "10")]
+        DoubleValue(f64),
+    }
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ListValue {
+    #[prost(message, repeated, tag = "1")]
+    pub items: Vec<Value>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct DictValue {
+    #[prost(map = "string, message", tag = "1")]
+    pub entries: HashMap<String, Value>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ReprValue {
+    #[prost(string, tag = "1")]
+    pub repr: String,
+    #[prost(string, tag = "2")]
+    pub type_name: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct PythonFrame {
+    #[prost(string, tag = "1")]
+    pub ip: String,
+    #[prost(string, tag = "2")]
+    pub file: String,
+    #[prost(string, tag = "3")]
+    pub func: String,
+    #[prost(int64, tag = "4")]
+    pub lineno: i64,
+    #[prost(map = "string, message", tag = "5")]
+    pub locals: HashMap<String, Value>,
+    #[prost(string, optional, tag = "6")]
+    pub module: Option<String>,
+    #[prost(string, optional, tag = "7")]
+    pub package: Option<String>,
+    #[prost(bool, tag = "8")]
+    pub in_app: bool,
+    #[prost(message, optional, tag = "9")]
+    pub source: Option<SourceContext>,
+    #[prost(uint64, optional, tag = "10")]
+    pub frame_address: Option<u64>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct GpuFrame {
+    #[prost(string, tag = "1")]
+    pub kernel: String,
+    #[prost(uint32, optional, tag = "2")]
+    pub device: Option<u32>,
+    #[prost(uint32, tag = "3")]
+    pub correlation_id: u32,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct SyntheticFrame {
+    #[prost(string, tag = "1")]
+    pub label: String,
+    #[prost(string, tag = "2")]
+    pub category: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct InterpreterFrame {
+    #[prost(string, tag = "1")]
+    pub runtime: String,
+    #[prost(string, tag = "2")]
+    pub file: String,
+    #[prost(string, tag = "3")]
+    pub func: String,
+    #[prost(int64, tag = "4")]
+    pub lineno: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct CallFrame {
+    #[prost(oneof = "call_frame::Frame", tags = "1, 2, 3, 4, 5")]
+    pub frame: Option<call_frame::Frame>,
+}
+
+pub mod call_frame {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Frame {
+        #[prost(message, tag = "1")]
+        Native(super::NativeFrame),
+        #[prost(message, tag = "2")]
+        Python(super::PythonFrame),
+        #[prost(message, tag = "3")]
+        Gpu(super::GpuFrame),
+        #[prost(message, tag = "4")]
+        Synthetic(super::SyntheticFrame),
+        #[prost(message, tag = "5")]
+        Interpreter(super::InterpreterFrame),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum CaptureSource {
+    Unknown = 0,
+    InProcess = 1,
+    Signal = 2,
+    Remote = 3,
+    Python = 4,
+    Merged = 5,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, prost::Enumeration)]
+#[repr(i32)]
+pub enum SampleState {
+    OnCpu = 0,
+    OffCpu = 1,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct StackTrace {
+    #[prost(message, repeated, tag = "1")]
+    pub frames: Vec<CallFrame>,
+    #[prost(uint64, optional, tag = "2")]
+    pub timestamp_unix_ns: Option<u64>,
+    #[prost(uint32, optional, tag = "3")]
+    pub pid: Option<u32>,
+    #[prost(int32, optional, tag = "4")]
+    pub tid: Option<i32>,
+    #[prost(bool, tag = "5")]
+    pub truncated: bool,
+    #[prost(enumeration = "CaptureSource", tag = "6")]
+    pub source: i32,
+    #[prost(uint32, optional, tag = "7")]
+    pub cpu: Option<u32>,
+    #[prost(enumeration = "SampleState", tag = "8")]
+    pub state: i32,
+    #[prost(uint64, tag = "9")]
+    pub weight_ns: u64,
+    #[prost(uint32, tag = "10")]
+    pub truncated_frames: u32,
+}
+
+/// Encode `trace` as a `mixed_stack_tracer.v1.StackTrace` message.
+pub fn encode_stack_trace(trace: &crate::StackTrace) -> Vec<u8> {
+    StackTrace::from(trace.clone()).encode_to_vec()
+}
+
+/// Decode a `mixed_stack_tracer.v1.StackTrace` message.
+pub fn decode_stack_trace(bytes: &[u8]) -> io::Result<crate::StackTrace> {
+    let message =
+        StackTrace::decode(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    message.try_into()
+}
+
+fn missing(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{} without a variant", what),
+    )
+}
+
+impl From<crate::InlineFrame> for InlineFrame {
+    fn from(frame: crate::InlineFrame) -> Self {
+        InlineFrame {
+            func: frame.func,
+            file: frame.file,
+            lineno: frame.lineno,
+        }
+    }
+}
+
+impl From<InlineFrame> for crate::InlineFrame {
+    fn from(frame: InlineFrame) -> Self {
+        crate::InlineFrame {
+            func: frame.func,
+            file: frame.file,
+            lineno: frame.lineno,
+        }
+    }
+}
+
+impl From<crate::SourceContext> for SourceContext {
+    fn from(source: crate::SourceContext) -> Self {
+        SourceContext {
+            line: source.line,
+            pre: source.pre,
+            post: source.post,
+        }
+    }
+}
+
+impl From<SourceContext> for crate::SourceContext {
+    fn from(source: SourceContext) -> Self {
+        crate::SourceContext {
+            line: source.line,
+            pre: source.pre,
+            post: source.post,
+        }
+    }
+}
+
+impl From<LocalValue> for Value {
+    fn from(value: LocalValue) -> Self {
+        use value::Kind;
+        let kind = match value {
+            LocalValue::None => Kind::None(true),
+            LocalValue::Bool(b) => Kind::BoolValue(b),
+            LocalValue::Int(i) => Kind::IntValue(i),
+            LocalValue::Float(f) => Kind::DoubleValue(f.0),
+            LocalValue::Str(s) => Kind::StrValue(s),
+            LocalValue::Bytes(b) => Kind::BytesValue(b),
+            LocalValue::List(items) => Kind::ListValue(ListValue {
+                items: items.into_iter().map(Value::from).collect(),
+            }),
+            LocalValue::Dict(entries) => Kind::DictValue(DictValue {
+                entries: entries.into_iter().map(|(k, v)| (k, v.into())).collect(),
+            }),
+            LocalValue::Repr { repr, type_name } => Kind::ReprValue(ReprValue { repr, type_name }),
+        };
+        Value { kind: Some(kind) }
+    }
+}
+
+impl From<Value> for LocalValue {
+    /// A value without a variant reads as `None`.
+    fn from(value: Value) -> Self {
+        use value::Kind;
+        match value.kind {
+            None | Some(Kind::None(_)) => LocalValue::None,
+            Some(Kind::BoolValue(b)) => LocalValue::Bool(b),
+            Some(Kind::IntValue(i)) => LocalValue::Int(i),
+            Some(Kind::FloatValue(repr)) => match repr.parse() {
+                Ok(f) => LocalValue::float(f),
+                Err(_) => LocalValue::Str(repr),
+            },
+            Some(Kind::DoubleValue(f)) => LocalValue::float(f),
+            Some(Kind::StrValue(s)) => LocalValue::Str(s),
+            Some(Kind::BytesValue(b)) => LocalValue::Bytes(b),
+            Some(Kind::ListValue(list)) => {
+                LocalValue::List(list.items.into_iter().map(LocalValue::from).collect())
+            }
+            Some(Kind::DictValue(dict)) => LocalValue::Dict(
+                dict.entries
+                    .into_iter()
+                    .map(|(k, v)| (k, v.into()))
+                    .collect(),
+            ),
+            Some(Kind::ReprValue(r)) => LocalValue::repr(r.repr, r.type_name),
+        }
+    }
+}
+
+impl From<crate::CallFrame> for CallFrame {
+    fn from(frame: crate::CallFrame) -> Self {
+        use call_frame::Frame;
+        let frame = match frame {
+            crate::CallFrame::CFrame {
+                ip,
+                file,
+                func,
+                lineno,
+                raw_func,
+                inlined,
+                category,
+                source,
+                module_path,
+                build_id,
+                offset,
+                eval_frame,
+            } => Frame::Native(NativeFrame {
+                ip,
+                file,
+                func,
+                lineno,
+                raw_func,
+                inlined: inlined.into_iter().map(Into::into).collect(),
+                category,
+                source: source.map(|s| (*s).into()),
+                module_path,
+                build_id,
+                offset,
+                eval_frame,
+            }),
+            crate::CallFrame::PyFrame {
+                ip,
+                file,
+                func,
+                lineno,
+                locals,
+                module,
+                package,
+                in_app,
+                source,
+                frame_address,
+            } => Frame::Python(PythonFrame {
+                ip,
+                file,
+                func,
+                lineno,
+                locals: locals.into_iter().map(|(k, v)| (k, v.into())).collect(),
+                module,
+                package,
+                in_app,
+                source: source.map(|s| (*s).into()),
+                frame_address,
+            }),
+            crate::CallFrame::GpuFrame {
+                kernel,
+                device,
+                correlation_id,
+            } => Frame::Gpu(GpuFrame {
+                kernel,
+                device,
+                correlation_id,
+            }),
+            crate::CallFrame::InterpreterFrame {
+                runtime,
+                file,
+                func,
+                lineno,
+            } => Frame::Interpreter(InterpreterFrame {
+                runtime,
+                file,
+                func,
+                lineno,
+            }),
+            crate::CallFrame::Synthetic { label, category } => {
+                Frame::Synthetic(SyntheticFrame { label, category })
+            }
+        };
+        CallFrame { frame: Some(frame) }
+    }
+}
+
+impl TryFrom<CallFrame> for crate::CallFrame {
+    type Error = io::Error;
+
+    fn try_from(frame: CallFrame) -> io::Result<Self> {
+        use call_frame::Frame;
+        Ok(match frame.frame.ok_or_else(|| missing("CallFrame"))? {
+            Frame::Native(f) => crate::CallFrame::CFrame {
+                ip: f.ip,
+                file: f.file,
+                func: f.func,
+                lineno: f.lineno,
+                raw_func: f.raw_func,
+                inlined: f.inlined.into_iter().map(Into::into).collect(),
+                category: f.category,
+                source: f.source.map(|s| Box::new(s.into())),
+                module_path: f.module_path,
+                build_id: f.build_id,
+                offset: f.offset,
+                eval_frame: f.eval_frame,
+            },
+            Frame::Python(f) => crate::CallFrame::PyFrame {
+                ip: f.ip,
+                file: f.file,
+                func: f.func,
+                lineno: f.lineno,
+                locals: f.locals.into_iter().map(|(k, v)| (k, v.into())).collect(),
+                module: f.module,
+                package: f.package,
+                in_app: f.in_app,
+                source: f.source.map(|s| Box::new(s.into())),
+                frame_address: f.frame_address,
+            },
+            Frame::Gpu(f) => crate::CallFrame::gpu(f.kernel, f.device, f.correlation_id),
+            Frame::Synthetic(f) => crate::CallFrame::synthetic(f.label, f.category),
+            Frame::Interpreter(f) => {
+                crate::CallFrame::interpreter(f.runtime, f.file, f.func, f.lineno)
+            }
+        })
+    }
+}
+
+impl From<crate::profile::SampleState> for SampleState {
+    fn from(state: crate::profile::SampleState) -> Self {
+        match state {
+            crate::profile::SampleState::OnCpu => SampleState::OnCpu,
+            crate::profile::SampleState::OffCpu => SampleState::OffCpu,
+        }
+    }
+}
+
+impl From<Source> for CaptureSource {
+    fn from(source: Source) -> Self {
+        match source {
+            Source::Unknown => CaptureSource::Unknown,
+            Source::InProcess => CaptureSource::InProcess,
+            Source::Signal => CaptureSource::Signal,
+            Source::Remote => CaptureSource::Remote,
+            Source::Python => CaptureSource::Python,
+            Source::Merged => CaptureSource::Merged,
+        }
+    }
+}
+
+impl From<CaptureSource> for Source {
+    fn from(source: CaptureSource) -> Self {
+        match source {
+            CaptureSource::Unknown => Source::Unknown,
+            CaptureSource::InProcess => Source::InProcess,
+            CaptureSource::Signal => Source::Signal,
+            CaptureSource::Remote => Source::Remote,
+            CaptureSource::Python => Source::Python,
+            CaptureSource::Merged => Source::Merged,
+        }
+    }
+}
+
+impl From<crate::StackTrace> for StackTrace {
+    fn from(trace: crate::StackTrace) -> Self {
+        StackTrace {
+            frames: trace.frames.into_iter().map(Into::into).collect(),
+            timestamp_unix_ns: trace
+                .timestamp
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_nanos() as u64),
+            pid: trace.pid,
+            tid: trace.tid,
+            truncated: trace.truncated,
+            truncated_frames: trace.truncated_frames as u32,
+            source: CaptureSource::from(trace.source) as i32,
+            ..StackTrace::default()
+        }
+    }
+}
+
+impl TryFrom<StackTrace> for crate::StackTrace {
+    type Error = io::Error;
+
+    /// Unknown capture sources (from newer writers) read as `Unknown`.
+    fn try_from(trace: StackTrace) -> io::Result<Self> {
+        Ok(crate::StackTrace {
+            frames: trace
+                .frames
+                .into_iter()
+                .map(TryInto::try_into)
+                .collect::<io::Result<_>>()?,
+            timestamp: trace
+                .timestamp_unix_ns
+                .map(|ns| UNIX_EPOCH + Duration::from_nanos(ns)),
+            pid: trace.pid,
+            tid: trace.tid,
+            truncated: trace.truncated,
+            truncated_frames: trace.truncated_frames as usize,
+            source: CaptureSource::try_from(trace.source)
+                .unwrap_or(CaptureSource::Unknown)
+                .into(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stack_trace_round_trip() {
+        let mut python = crate::CallFrame::python("0x20", "app.py", "run", 3);
+        if let crate::CallFrame::PyFrame { locals, source, .. } = &mut python {
+            *source = Some(Box::new(crate::SourceContext {
+                line: "    step()".to_string(),
+                pre: vec!["for _ in range(3):".to_string()],
+                post: Vec::new(),
+            }));
+            locals.insert("rate".to_string(), LocalValue::float(0.5));
+            locals.insert("done".to_string(), LocalValue::None);
+            locals.insert(
+                "batch".to_string(),
+                LocalValue::List(vec![
+                    LocalValue::Bytes(b"\x00".to_vec()),
+                    LocalValue::repr("tensor([1.])", "Tensor"),
+                ]),
+            );
+        }
+        let native = crate::CallFrame::native("0x10", "lib.rs", "outer", 30).with_inlined(vec![
+            crate::InlineFrame {
+                func: "inner".to_string(),
+                file: "lib.rs".to_string(),
+                lineno: 10,
+            },
+        ]);
+        let trace = crate::StackTrace {
+            frames: vec![
+                native,
+                python,
+                crate::CallFrame::gpu("add_kernel", None, 7),
+                crate::CallFrame::interpreter("lua", "init.lua", "on_load", 12),
+                crate::CallFrame::synthetic("[GIL wait]", "gil"),
+            ],
+            // Whole nanoseconds survive the conversion.
+            timestamp: Some(UNIX_EPOCH + Duration::from_nanos(1_700_000_000_123_456_789)),
+            pid: Some(42),
+            tid: Some(43),
+            truncated: true,
+            truncated_frames: 12,
+            source: Source::Remote,
+        };
+        let bytes = encode_stack_trace(&trace);
+        assert_eq!(decode_stack_trace(&bytes).unwrap(), trace);
+    }
+
+    #[test]
+    fn test_legacy_float_repr() {
+        let legacy = |repr: &str| Value {
+            kind: Some(value::Kind::FloatValue(repr.to_string())),
+        };
+        assert_eq!(LocalValue::from(legacy("0.25")), LocalValue::float(0.25));
+        assert_eq!(
+            LocalValue::from(legacy("inf")),
+            LocalValue::float(f64::INFINITY)
+        );
+        assert_eq!(
+            LocalValue::from(legacy("?")),
+            LocalValue::Str("?".to_string())
+        );
+    }
+
+    #[test]
+    fn test_frame_without_variant_is_rejected() {
+        let message = StackTrace {
+            frames: vec![CallFrame { frame: None }],
+            ..StackTrace::default()
+        };
+        let err = decode_stack_trace(&message.encode_to_vec()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,297 @@
+//! Continuous profiling into Grafana Pyroscope (feature `pyroscope`).
+//!
+//! `PyroscopeClient` pushes a `Profile` as folded stacks to the server's `/ingest` API;
+//! `PyroscopeAgent` keeps a `Sampler` running and pushes one profile per upload interval.
+//! Between two intervals the sampler is restarted, so a few milliseconds per interval go
+//! unsampled.
+
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::fork::BackgroundThread;
+use crate::output::folded::{self, FoldedOptions};
+use crate::profile::Profile;
+use crate::sampler::{PythonStacksProvider, Sampler};
+
+/// Uploads profiles of one application to a Pyroscope server.
+#[derive(Clone, Debug)]
+pub struct PyroscopeClient {
+    server: String,
+    app_name: String,
+    labels: Vec<(String, String)>,
+    sample_rate: u32,
+    timeout: Duration,
+}
+
+impl PyroscopeClient {
+    /// Client for `server` (e.g. `http://localhost:4040`) reporting as `app_name`.
+    pub fn new(server: impl Into<String>, app_name: impl Into<String>) -> Self {
+        PyroscopeClient {
+            server: server.into().trim_end_matches('/').to_string(),
+            app_name: app_name.into(),
+            labels: Vec::new(),
+            sample_rate: 100,
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Static label attached to every upload, e.g. `env=prod`.
+    pub fn label(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.labels.push((key.into(), value.into()));
+        self
+    }
+
+    /// Sampling rate reported with the uploads (default 100 Hz).
+    pub fn sample_rate(mut self, hz: u32) -> Self {
+        self.sample_rate = hz;
+        self
+    }
+
+    /// Timeout of one upload (default 10s).
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn get_app_name(&self) -> &str {
+        &self.app_name
+    }
+
+    pub fn get_sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// `/ingest` URL for a profile covering `from..until`.
+    pub fn ingest_url(&self, from: SystemTime, until: SystemTime) -> String {
+        let seconds = |t: SystemTime| t.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let labels: Vec<String> = self
+            .labels
+            .iter()
+            .map(|(k, v)| format!("{}={}", k, v))
+            .collect();
+        let name = format!("{}.cpu{{{}}}", self.app_name, labels.join(","));
+        format!(
+            "{}/ingest?name={}&from={}&until={}&format=folded&sampleRate={}&spyName=mixed-stack-tracer&units=samples&aggregationType=sum",
+            self.server,
+            percent_encode(&name),
+            seconds(from),
+            seconds(until),
+            self.sample_rate
+        )
+    }
+
+    /// Upload `profile`, recorded between `from` and `until`.
+    pub fn push(&self, profile: &Profile, from: SystemTime, until: SystemTime) -> io::Result<()> {
+        let body = folded::profile_to_string(profile, &FoldedOptions::new());
+        let url = self.ingest_url(from, until);
+        let agent: ureq::Agent = ureq::Agent::config_builder()
+            .timeout_global(Some(self.timeout))
+            .build()
+            .into();
+        agent
+            .post(&url)
+            .header("Content-Type", "text/plain")
+            .send(body)
+            .map_err(|e| io::Error::other(format!("{}: {}", self.server, e)))?;
+        Ok(())
+    }
+}
+
+fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b'~') {
+            out.push(b as char);
+        } else {
+            out.push_str(&format!("%{:02X}", b));
+        }
+    }
+    out
+}
+
+struct Shared {
+    stopped: Mutex<bool>,
+    wake: Condvar,
+}
+
+/// A sampler pushing to Pyroscope in the background, stopped when dropped.
+pub struct PyroscopeAgent {
+    shared: Arc<Shared>,
+    thread: Option<BackgroundThread<()>>,
+}
+
+impl PyroscopeAgent {
+    /// Sample at the client's rate and push every `interval` (Pyroscope expects 10s).
+    pub fn start(client: PyroscopeClient, interval: Duration) -> io::Result<PyroscopeAgent> {
+        Self::start_inner(client, interval, None)
+    }
+
+    /// Like `start`, where `provider` is called once per interval to build the Python
+    /// stacks provider of that interval's sampler.
+    pub fn start_with_python(
+        client: PyroscopeClient,
+        interval: Duration,
+        provider: impl FnMut() -> PythonStacksProvider + Send + 'static,
+    ) -> io::Result<PyroscopeAgent> {
+        Self::start_inner(client, interval, Some(Box::new(provider)))
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn start_inner(
+        client: PyroscopeClient,
+        interval: Duration,
+        mut provider: Option<Box<dyn FnMut() -> PythonStacksProvider + Send>>,
+    ) -> io::Result<PyroscopeAgent> {
+        if interval.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "upload interval must be positive",
+            ));
+        }
+        let mut start_sampler = move || match provider.as_mut() {
+            Some(provider) => Sampler::start_with_python(client.sample_rate, provider()),
+            None => Sampler::start(client.sample_rate),
+        };
+        // Fail early, e.g. when another sampler is already running.
+        let first = start_sampler()?;
+
+        let shared = Arc::new(Shared {
+            stopped: Mutex::new(false),
+            wake: Condvar::new(),
+        });
+        let thread = {
+            let shared = Arc::clone(&shared);
+            thread::Builder::new()
+                .name("mst-pyroscope".to_string())
+                .spawn(move || {
+                    let mut sampler = Some(first);
+                    let mut from = SystemTime::now();
+                    while let Some(running) = sampler.take() {
+                        let stopped = shared.stopped.lock().unwrap();
+                        let (stopped, _) = shared
+                            .wake
+                            .wait_timeout_while(stopped, interval, |stopped| !*stopped)
+                            .unwrap();
+                        let last = *stopped;
+                        drop(stopped);
+
+                        let profile = running.stop();
+                        let until = SystemTime::now();
+                        if !last {
+                            match start_sampler() {
+                                Ok(next) => sampler = Some(next),
+                                Err(err) => eprintln!(
+                                    "mixed-stack-tracer: pyroscope sampler restart failed: {}",
+                                    err
+                                ),
+                            }
+                        }
+                        if !profile.stacks.is_empty() {
+                            if let Err(err) = client.push(&profile, from, until) {
+                                eprintln!("mixed-stack-tracer: pyroscope upload failed: {}", err);
+                            }
+                        }
+                        from = until;
+                    }
+                })?
+        };
+        Ok(PyroscopeAgent {
+            shared,
+            thread: Some(BackgroundThread::new(thread)),
+        })
+    }
+}
+
+impl Drop for PyroscopeAgent {
+    /// Stops sampling and pushes the last partial interval.
+    fn drop(&mut self) {
+        if self
+            .thread
+            .as_ref()
+            .is_some_and(BackgroundThread::is_forked)
+        {
+            // In a forked child: the upload thread stayed in the parent.
+            return;
+        }
+        *self.shared.stopped.lock().unwrap() = true;
+        self.shared.wake.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profile::SampledStack;
+    use crate::CallFrame;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_ingest_url() {
+        let client = PyroscopeClient::new("http://localhost:4040/", "trainer")
+            .label("env", "prod")
+            .label("rank", "0")
+            .sample_rate(99);
+        let from = UNIX_EPOCH + Duration::from_secs(100);
+        let until = UNIX_EPOCH + Duration::from_secs(110);
+        assert_eq!(
+            client.ingest_url(from, until),
+            "http://localhost:4040/ingest?name=trainer.cpu%7Benv%3Dprod%2Crank%3D0%7D&from=100&until=110&format=folded&sampleRate=99&spyName=mixed-stack-tracer&units=samples&aggregationType=sum"
+        );
+    }
+
+    #[test]
+    fn test_push() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                if header == "\r\n" {
+                    break;
+                }
+                if let Some(value) = header.to_ascii_lowercase().strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            (request_line, String::from_utf8(body).unwrap())
+        });
+
+        let profile = Profile {
+            stacks: vec![SampledStack {
+                tid: 1,
+                frames: vec![
+                    CallFrame::native("0x10", "lib.c", "leaf", 1),
+                    CallFrame::python("0x20", "app.py", "main", 2),
+                ],
+                count: 5,
+                ..SampledStack::default()
+            }],
+            total_samples: 5,
+            dropped_samples: 0,
+        };
+        let client = PyroscopeClient::new(format!("http://{}", addr), "app");
+        client.push(&profile, UNIX_EPOCH, UNIX_EPOCH).unwrap();
+
+        let (request_line, body) = server.join().unwrap();
+        assert!(request_line.starts_with("POST /ingest?name=app.cpu%7B%7D&from=0"));
+        assert_eq!(body, "main;leaf 5\n");
+    }
+}
@@ -0,0 +1,174 @@
+//! asyncio task stacks.
+//!
+//! A suspended task has no frames on any thread: its coroutine chain hangs off the task
+//! (`cr_await` links each coroutine to the one it awaits). This walks that chain so every
+//! task gets a stack of its own, which can then be placed on the loop thread's stack.
+
+use pyo3::prelude::*;
+
+use super::to_call_frame;
+use crate::locals::LocalsPolicy;
+use crate::stack_tracer::SignalTracer;
+use crate::CallFrame;
+
+/// Coroutine stack of one asyncio task, leaf first.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TaskStack {
+    /// `Task.get_name()`.
+    pub name: String,
+    /// The task is the one currently executing on the loop thread.
+    pub running: bool,
+    /// Frames of the awaited coroutines, innermost first. Awaitables implemented in C
+    /// (futures, `asyncio.gather`) end the chain.
+    pub frames: Vec<CallFrame>,
+}
+
+impl TaskStack {
+    /// The task's logical python stack on the loop thread, to merge with that thread's
+    /// native stack.
+    ///
+    /// `loop_stack` is the loop thread's python stack (leaf first). The running task's
+    /// frames are already part of it; a suspended task is placed where the loop resumes
+    /// tasks, on top of `_run_once` and its callers.
+    pub fn on_loop_stack(&self, loop_stack: &[CallFrame]) -> Vec<CallFrame> {
+        if self.running {
+            return loop_stack.to_vec();
+        }
+        let resume = loop_stack
+            .iter()
+            .position(|f| f.func() == "_run_once")
+            .unwrap_or(loop_stack.len());
+        let mut frames = self.frames.clone();
+        frames.extend_from_slice(&loop_stack[resume..]);
+        frames
+    }
+}
+
+impl SignalTracer {
+    /// Stacks of all tasks of the event loop running on this thread.
+    ///
+    /// Fails with `RuntimeError` when no loop is running here (call it from a coroutine or
+    /// a loop callback); see `capture_asyncio_tasks_of` for another thread's loop.
+    pub fn capture_asyncio_tasks(py: Python<'_>) -> PyResult<Vec<TaskStack>> {
+        let event_loop = py.import("asyncio")?.call_method0("get_running_loop")?;
+        Self::capture_asyncio_tasks_of(&event_loop)
+    }
+
+    /// Stacks of all unfinished tasks of `event_loop`, in `asyncio.all_tasks` order.
+    pub fn capture_asyncio_tasks_of(event_loop: &Bound<'_, PyAny>) -> PyResult<Vec<TaskStack>> {
+        let asyncio = event_loop.py().import("asyncio")?;
+        let current = asyncio.call_method1("current_task", (event_loop,))?;
+        let tasks = asyncio.call_method1("all_tasks", (event_loop,))?;
+
+        let mut stacks = Vec::new();
+        for task in tasks.try_iter()? {
+            let task = task?;
+            stacks.push(TaskStack {
+                name: task.call_method0("get_name")?.extract()?,
+                running: task.is(&current),
+                frames: coroutine_frames(task.call_method0("get_coro")?)?,
+            });
+        }
+        Ok(stacks)
+    }
+}
+
+/// Frames of `awaitable` and everything it awaits, innermost first.
+fn coroutine_frames(awaitable: Bound<'_, PyAny>) -> PyResult<Vec<CallFrame>> {
+    let mut frames = Vec::new();
+    let mut current = Some(awaitable);
+    while let Some(awaitable) = current.take() {
+        // Coroutines, generators (`yield from` based coroutines) and async generators.
+        let Some((frame_attr, awaited_attr)) = [
+            ("cr_frame", "cr_await"),
+            ("gi_frame", "gi_yieldfrom"),
+            ("ag_frame", "ag_await"),
+        ]
+        .into_iter()
+        .find(|(frame, _)| awaitable.hasattr(*frame).unwrap_or(false)) else {
+            break;
+        };
+        let frame = awaitable.getattr(frame_attr)?;
+        if frame.is_none() {
+            // Finished (or not started) coroutine.
+            break;
+        }
+        frames.push(to_call_frame(&frame, &LocalsPolicy::none())?);
+        let awaited = awaitable.getattr(awaited_attr)?;
+        current = (!awaited.is_none()).then_some(awaited);
+    }
+    frames.reverse();
+    Ok(frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pyo3::types::PyDict;
+
+    #[pyfunction]
+    fn tasks(py: Python<'_>) -> PyResult<Vec<(String, bool, Vec<String>)>> {
+        let loop_stack = SignalTracer::capture_python_stack(py)?;
+        Ok(SignalTracer::capture_asyncio_tasks(py)?
+            .into_iter()
+            .map(|task| {
+                let on_loop: Vec<String> = task
+                    .on_loop_stack(&loop_stack)
+                    .iter()
+                    .map(|f| f.func().to_string())
+                    .collect();
+                (task.name, task.running, on_loop)
+            })
+            .collect())
+    }
+
+    #[test]
+    fn test_capture_asyncio_tasks() {
+        Python::initialize();
+        Python::attach(|py| {
+            let globals = PyDict::new(py);
+            globals
+                .set_item("tasks", wrap_pyfunction!(tasks, py).unwrap())
+                .unwrap();
+            py.run(
+                c"import asyncio
+async def leaf():
+    await asyncio.sleep(10)
+async def middle():
+    await leaf()
+async def main():
+    worker = asyncio.create_task(middle(), name='worker')
+    await asyncio.sleep(0)
+    found = tasks()
+    worker.cancel()
+    return found
+result = asyncio.run(main())
+",
+                Some(&globals),
+                None,
+            )
+            .unwrap();
+
+            let mut result: Vec<(String, bool, Vec<String>)> = globals
+                .get_item("result")
+                .unwrap()
+                .unwrap()
+                .extract()
+                .unwrap();
+            result.sort();
+            assert_eq!(result.len(), 2, "{:?}", result);
+
+            let (name, running, frames) = &result[1];
+            assert_eq!(name, "worker");
+            assert!(!running);
+            assert_eq!(frames[..3], ["sleep", "leaf", "middle"]);
+            assert!(frames.contains(&"_run_once".to_string()), "{:?}", frames);
+            assert!(!frames.contains(&"main".to_string()), "{:?}", frames);
+
+            // The running task is the loop thread's own stack.
+            let (_, running, frames) = &result[0];
+            assert!(running);
+            assert_eq!(frames.first().map(String::as_str), Some("main"));
+        });
+    }
+}
@@ -0,0 +1,920 @@
+//! The `mixed_stack_tracer` Python extension module.
+//!
+//! ```python
+//! import mixed_stack_tracer as mst
+//!
+//! with mst.Tracer(freq_hz=99) as tracer:
+//!     train_step()
+//! print(tracer.profile.to_folded())
+//! ```
+
+use std::sync::Mutex;
+
+use pyo3::exceptions::{PyRuntimeError, PyTypeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict, PyList};
+use pyo3::PyClassInitializer;
+use regex::Regex;
+
+use crate::classify::ClassifyOptions;
+use crate::error::TracerError;
+use crate::frame_filter::FrameFilter;
+use crate::output::folded::{self, FoldedOptions};
+use crate::output::speedscope;
+use crate::profile::Profile;
+#[cfg(target_os = "linux")]
+use crate::sampler::Sampler;
+use crate::stack_tracer::SignalTracer;
+#[cfg(target_os = "linux")]
+use crate::unwind::UnwindStrategy;
+use crate::{CallFrame, FrameKind, Value};
+
+/// The extension module; `maturin develop` builds and installs it (see `pyproject.toml`).
+#[pymodule]
+pub fn mixed_stack_tracer(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    super::exceptions::register(m)?;
+    m.add_class::<Frame>()?;
+    m.add_class::<NativeFrame>()?;
+    m.add_class::<PythonFrame>()?;
+    m.add_class::<PyProfile>()?;
+    m.add_class::<PyFrameFilter>()?;
+    m.add_class::<Tracer>()?;
+    m.add_class::<super::decorator::ProfiledFunction>()?;
+    m.add_function(wrap_pyfunction!(super::decorator::profile_mixed, m)?)?;
+    m.add_function(wrap_pyfunction!(merge_python_native_stacks, m)?)?;
+    m.add_function(wrap_pyfunction!(capture, m)?)?;
+    m.add_function(wrap_pyfunction!(classify_frames, m)?)?;
+    #[cfg(target_os = "linux")]
+    m.add_function(wrap_pyfunction!(deadlock_report, m)?)?;
+    m.add_function(wrap_pyfunction!(super::excepthook::install_excepthook, m)?)?;
+    m.add_function(wrap_pyfunction!(
+        super::excepthook::uninstall_excepthook,
+        m
+    )?)?;
+    Ok(())
+}
+
+/// One frame of a merged stack, read-only. Native and python frames are instances of the
+/// `CFrame` and `PyFrame` subclasses.
+#[pyclass(frozen, eq, from_py_object, subclass, module = "mixed_stack_tracer")]
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    frame: CallFrame,
+}
+
+impl Frame {
+    pub fn new(frame: CallFrame) -> Self {
+        Frame { frame }
+    }
+
+    pub fn call_frame(&self) -> &CallFrame {
+        &self.frame
+    }
+}
+
+#[pymethods]
+impl Frame {
+    /// `"native"`, `"python"`, `"gpu"`, `"interpreter"` or `"synthetic"`.
+    #[getter]
+    fn kind(&self) -> &'static str {
+        self.frame.kind().as_str()
+    }
+
+    #[getter]
+    fn func(&self) -> &str {
+        self.frame.func()
+    }
+
+    #[getter]
+    fn file(&self) -> &str {
+        self.frame.file()
+    }
+
+    #[getter]
+    fn lineno(&self) -> i64 {
+        self.frame.lineno()
+    }
+
+    /// Instruction pointer (native) or frame address (python), `None` for other kinds.
+    #[getter]
+    fn ip(&self) -> Option<&str> {
+        match &self.frame {
+            CallFrame::CFrame { ip, .. } | CallFrame::PyFrame { ip, .. } => Some(ip),
+            _ => None,
+        }
+    }
+
+    /// Interpreter frame address of python frames, when the capture recorded it.
+    #[getter]
+    fn frame_address(&self) -> Option<u64> {
+        match &self.frame {
+            CallFrame::PyFrame { frame_address, .. } => *frame_address,
+            _ => None,
+        }
+    }
+
+    /// Interpreter frame an eval loop native frame was entered with, when known.
+    #[getter]
+    fn eval_frame(&self) -> Option<u64> {
+        match &self.frame {
+            CallFrame::CFrame { eval_frame, .. } => *eval_frame,
+            _ => None,
+        }
+    }
+
+    /// Category of annotated native frames and synthetic markers.
+    #[getter]
+    fn category(&self) -> Option<&str> {
+        match &self.frame {
+            CallFrame::CFrame { category, .. } => category.as_deref(),
+            CallFrame::Synthetic { category, .. } => Some(category),
+            _ => None,
+        }
+    }
+
+    /// Dotted module of classified python frames, e.g. `torch.nn.modules.linear`.
+    #[getter]
+    fn module(&self) -> Option<&str> {
+        match &self.frame {
+            CallFrame::PyFrame { module, .. } => module.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Top-level package of classified python frames.
+    #[getter]
+    fn package(&self) -> Option<&str> {
+        match &self.frame {
+            CallFrame::PyFrame { package, .. } => package.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Whether a classified python frame is application code.
+    #[getter]
+    fn in_app(&self) -> bool {
+        matches!(self.frame, CallFrame::PyFrame { in_app: true, .. })
+    }
+
+    /// Source line attached to native or python frames, `None` if there is none.
+    #[getter]
+    fn source(&self) -> Option<&str> {
+        match &self.frame {
+            CallFrame::CFrame { source, .. } | CallFrame::PyFrame { source, .. } => {
+                source.as_ref().map(|s| s.line.as_str())
+            }
+            _ => None,
+        }
+    }
+
+    /// Captured locals of python frames, as Python objects.
+    #[getter]
+    fn locals<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        let dict = PyDict::new(py);
+        if let CallFrame::PyFrame { locals, .. } = &self.frame {
+            for (name, value) in locals {
+                dict.set_item(name, value_to_object(py, value)?)?;
+            }
+        }
+        Ok(dict)
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Frame(kind={:?}, func={:?}, file={:?}, lineno={})",
+            self.kind(),
+            self.func(),
+            self.file(),
+            self.lineno()
+        )
+    }
+}
+
+fn value_to_object<'py>(py: Python<'py>, value: &Value) -> PyResult<Bound<'py, PyAny>> {
+    Ok(match value {
+        Value::None => py.None().into_bound(py),
+        Value::Bool(b) => b.into_pyobject(py)?.to_owned().into_any(),
+        Value::Int(i) => i.into_pyobject(py)?.into_any(),
+        Value::Float(f) => f.0.into_pyobject(py)?.into_any(),
+        Value::Str(s) | Value::Repr { repr: s, .. } => s.into_pyobject(py)?.into_any(),
+        Value::List(items) => {
+            let list = PyList::empty(py);
+            for item in items {
+                list.append(value_to_object(py, item)?)?;
+            }
+            list.into_any()
+        }
+        Value::Dict(entries) => {
+            let dict = PyDict::new(py);
+            for (key, item) in entries {
+                dict.set_item(key, value_to_object(py, item)?)?;
+            }
+            dict.into_any()
+        }
+        Value::Bytes(bytes) => PyBytes::new(py, bytes).into_any(),
+    })
+}
+
+/// A native frame: `CFrame(ip, file, func, lineno)`.
+#[pyclass(frozen, extends = Frame, name = "CFrame", module = "mixed_stack_tracer")]
+pub struct NativeFrame;
+
+#[pymethods]
+impl NativeFrame {
+    #[new]
+    #[pyo3(signature = (ip, file, func, lineno = 0))]
+    fn py_new(ip: String, file: String, func: String, lineno: i64) -> PyClassInitializer<Self> {
+        PyClassInitializer::from(Frame::new(CallFrame::native(ip, file, func, lineno)))
+            .add_subclass(NativeFrame)
+    }
+}
+
+/// A python frame: `PyFrame(ip, file, func, lineno)`.
+#[pyclass(frozen, extends = Frame, name = "PyFrame", module = "mixed_stack_tracer")]
+pub struct PythonFrame;
+
+#[pymethods]
+impl PythonFrame {
+    #[new]
+    #[pyo3(signature = (ip, file, func, lineno = 0))]
+    fn py_new(ip: String, file: String, func: String, lineno: i64) -> PyClassInitializer<Self> {
+        PyClassInitializer::from(Frame::new(CallFrame::python(ip, file, func, lineno)))
+            .add_subclass(PythonFrame)
+    }
+}
+
+/// The Python object for `frame`, of the subclass matching its kind.
+pub(crate) fn frame_to_py(py: Python<'_>, frame: CallFrame) -> PyResult<Py<PyAny>> {
+    let kind = frame.kind();
+    let base = PyClassInitializer::from(Frame::new(frame));
+    Ok(match kind {
+        FrameKind::Native => Py::new(py, base.add_subclass(NativeFrame))?.into_any(),
+        FrameKind::Python => Py::new(py, base.add_subclass(PythonFrame))?.into_any(),
+        _ => Py::new(py, base)?.into_any(),
+    })
+}
+
+pub(crate) fn frames_to_py(
+    py: Python<'_>,
+    frames: impl IntoIterator<Item = CallFrame>,
+) -> PyResult<Vec<Py<PyAny>>> {
+    frames.into_iter().map(|f| frame_to_py(py, f)).collect()
+}
+
+/// Read a frame given as a `Frame` object or, for compatibility, as a dict with `ip`,
+/// `file`, `func`, `lineno` and optionally `kind` (`"native"` or `"python"`, defaulting to
+/// `default` if absent).
+pub(crate) fn frame_from_py(obj: &Bound<'_, PyAny>, default: FrameKind) -> PyResult<CallFrame> {
+    if let Ok(frame) = obj.cast::<Frame>() {
+        return Ok(frame.get().frame.clone());
+    }
+    let Ok(dict) = obj.cast::<PyDict>() else {
+        return Err(PyTypeError::new_err(format!(
+            "expected a Frame or a dict, got {}",
+            obj.get_type().name()?
+        )));
+    };
+    let field = |name: &str| dict.get_item(name);
+    let text = |name: &str| -> PyResult<String> {
+        Ok(match field(name)? {
+            Some(value) if !value.is_none() => value.str()?.to_string(),
+            _ => String::new(),
+        })
+    };
+    let lineno = match field("lineno")? {
+        Some(value) if !value.is_none() => value.extract()?,
+        _ => 0,
+    };
+    let kind = match field("kind")? {
+        Some(kind) => match kind.extract::<String>()?.as_str() {
+            "native" => FrameKind::Native,
+            "python" => FrameKind::Python,
+            other => {
+                return Err(PyTypeError::new_err(format!(
+                    "frame dicts must be native or python, got {:?}",
+                    other
+                )))
+            }
+        },
+        None => default,
+    };
+    let (ip, file, func) = (text("ip")?, text("file")?, text("func")?);
+    Ok(match kind {
+        FrameKind::Python => CallFrame::python(ip, file, func, lineno),
+        _ => CallFrame::native(ip, file, func, lineno),
+    })
+}
+
+/// Text report of the wait-for cycles between the threads of this process, through
+/// pthread mutexes and `threading` locks; `"No deadlock found."` when there is none.
+#[cfg(target_os = "linux")]
+#[pyfunction]
+fn deadlock_report(py: Python<'_>) -> PyResult<String> {
+    let deadlocks = SignalTracer::detect_deadlocks(py)?;
+    let mut out = Vec::new();
+    crate::deadlock::write_report(&mut out, &deadlocks)?;
+    Ok(String::from_utf8_lossy(&out).into_owned())
+}
+
+/// Merged mixed stack of the calling thread, leaf first: the caller's Python frames placed
+/// on the interpreter's native frames. `locals` names locals to snapshot in every python
+/// frame defining them; `filter` is a `FrameFilter` applied to the merged stack.
+#[pyfunction]
+#[pyo3(signature = (locals = Vec::new(), filter = None))]
+fn capture(
+    py: Python<'_>,
+    locals: Vec<String>,
+    filter: Option<PyRef<'_, PyFrameFilter>>,
+) -> PyResult<Vec<Py<PyAny>>> {
+    let locals: Vec<&str> = locals.iter().map(String::as_str).collect();
+    let python = SignalTracer::capture_python_stack_with_locals(py, &locals)?;
+    let native = SignalTracer::capture_native_stack();
+    let mut merged = SignalTracer::merge_traces(python, native).into_frames();
+    if let Some(filter) = filter {
+        merged = filter.filter.apply(merged);
+    }
+    frames_to_py(py, merged)
+}
+
+/// Copies of `frames` (`Frame` objects or dicts, leaf first) with `module`, `package`
+/// and `in_app` of the python frames filled in; `app_roots` name the application's
+/// source directories.
+#[pyfunction]
+#[pyo3(signature = (frames, app_roots = Vec::new()))]
+fn classify_frames(
+    py: Python<'_>,
+    frames: Vec<Bound<'_, PyAny>>,
+    app_roots: Vec<String>,
+) -> PyResult<Vec<Py<PyAny>>> {
+    let mut frames = frames
+        .iter()
+        .map(|f| frame_from_py(f, FrameKind::Python))
+        .collect::<PyResult<Vec<_>>>()?;
+    let options = app_roots
+        .into_iter()
+        .fold(ClassifyOptions::new(), ClassifyOptions::app_root);
+    SignalTracer::classify_frames_with(&mut frames, &options);
+    frames_to_py(py, frames)
+}
+
+/// `FrameFilter(include_func=[], exclude_func=[], include_file=[], exclude_file=[],
+/// drop_stdlib=False, drop_site_packages=False, in_app_only=False, collapse_recursion=False,
+/// count_recursion=False, max_depth=None)`;
+/// patterns are regular expressions searched in function names and file paths.
+#[pyclass(
+    frozen,
+    from_py_object,
+    name = "FrameFilter",
+    module = "mixed_stack_tracer"
+)]
+#[derive(Clone, Debug)]
+pub struct PyFrameFilter {
+    filter: FrameFilter,
+}
+
+#[pymethods]
+impl PyFrameFilter {
+    #[new]
+    #[pyo3(signature = (
+        include_func = Vec::new(),
+        exclude_func = Vec::new(),
+        include_file = Vec::new(),
+        exclude_file = Vec::new(),
+        drop_stdlib = false,
+        drop_site_packages = false,
+        in_app_only = false,
+        collapse_recursion = false,
+        count_recursion = false,
+        max_depth = None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn py_new(
+        include_func: Vec<String>,
+        exclude_func: Vec<String>,
+        include_file: Vec<String>,
+        exclude_file: Vec<String>,
+        drop_stdlib: bool,
+        drop_site_packages: bool,
+        in_app_only: bool,
+        collapse_recursion: bool,
+        count_recursion: bool,
+        max_depth: Option<usize>,
+    ) -> PyResult<Self> {
+        let regex = |pattern: &String| {
+            Regex::new(pattern).map_err(|e| PyValueError::new_err(e.to_string()))
+        };
+        let mut filter = FrameFilter::new()
+            .drop_stdlib(drop_stdlib)
+            .drop_site_packages(drop_site_packages)
+            .in_app_only(in_app_only)
+            .collapse_recursion(collapse_recursion)
+            .count_recursion(count_recursion);
+        for pattern in &include_func {
+            filter = filter.include_func(regex(pattern)?);
+        }
+        for pattern in &exclude_func {
+            filter = filter.exclude_func(regex(pattern)?);
+        }
+        for pattern in &include_file {
+            filter = filter.include_file(regex(pattern)?);
+        }
+        for pattern in &exclude_file {
+            filter = filter.exclude_file(regex(pattern)?);
+        }
+        if let Some(depth) = max_depth {
+            filter = filter.max_depth(depth);
+        }
+        Ok(PyFrameFilter { filter })
+    }
+
+    /// Filter a leaf-first stack of `Frame` objects or dicts.
+    fn apply(&self, py: Python<'_>, frames: Vec<Bound<'_, PyAny>>) -> PyResult<Vec<Py<PyAny>>> {
+        let frames = frames
+            .iter()
+            .map(|f| frame_from_py(f, FrameKind::Python))
+            .collect::<PyResult<Vec<_>>>()?;
+        frames_to_py(py, self.filter.apply(frames))
+    }
+
+    fn __repr__(&self) -> String {
+        format!("{:?}", self.filter)
+    }
+}
+
+/// Merge a python and a native stack (leaf first) given as `Frame` objects or dicts.
+#[pyfunction]
+fn merge_python_native_stacks(
+    py: Python<'_>,
+    python: Vec<Bound<'_, PyAny>>,
+    native: Vec<Bound<'_, PyAny>>,
+) -> PyResult<Vec<Py<PyAny>>> {
+    let python = python
+        .iter()
+        .map(|f| frame_from_py(f, FrameKind::Python))
+        .collect::<PyResult<Vec<_>>>()?;
+    let native = native
+        .iter()
+        .map(|f| frame_from_py(f, FrameKind::Native))
+        .collect::<PyResult<Vec<_>>>()?;
+    let merged = py.detach(|| SignalTracer::merge_python_native_stacks(python, native));
+    frames_to_py(py, merged)
+}
+
+/// `(tid, frames, count)` as returned by `Profile.stacks`.
+type ProfileStack = (i32, Vec<Py<PyAny>>, u64);
+
+/// Aggregated result of a sampling session.
+#[pyclass(
+    frozen,
+    from_py_object,
+    name = "Profile",
+    module = "mixed_stack_tracer"
+)]
+#[derive(Clone, Debug)]
+pub struct PyProfile {
+    profile: Profile,
+}
+
+impl PyProfile {
+    pub fn new(profile: Profile) -> Self {
+        PyProfile { profile }
+    }
+
+    pub fn profile(&self) -> &Profile {
+        &self.profile
+    }
+}
+
+#[pymethods]
+impl PyProfile {
+    #[getter]
+    fn total_samples(&self) -> u64 {
+        self.profile.total_samples
+    }
+
+    #[getter]
+    fn dropped_samples(&self) -> u64 {
+        self.profile.dropped_samples
+    }
+
+    /// Nanoseconds of CPU (or blocked) time the samples stand for.
+    #[getter]
+    fn total_weight_ns(&self) -> u64 {
+        self.profile.total_weight_ns()
+    }
+
+    /// `(tid, frames, count)` for every distinct stack, frames leaf first.
+    #[getter]
+    fn stacks(&self, py: Python<'_>) -> PyResult<Vec<ProfileStack>> {
+        self.profile
+            .stacks
+            .iter()
+            .map(|s| Ok((s.tid, frames_to_py(py, s.frames.clone())?, s.count)))
+            .collect()
+    }
+
+    /// Folded stacks for `flamegraph.pl` / inferno, in nanoseconds with `weights`.
+    #[pyo3(signature = (weights = false))]
+    fn to_folded(&self, weights: bool) -> String {
+        folded::profile_to_string(&self.profile, &FoldedOptions::new().weights(weights))
+    }
+
+    #[pyo3(signature = (name = "mixed-stack-tracer"))]
+    fn to_speedscope(&self, name: &str) -> String {
+        speedscope::profile_to_string(&self.profile, name)
+    }
+
+    fn __repr__(&self) -> String {
+        format!(
+            "Profile(stacks={}, total_samples={})",
+            self.profile.stacks.len(),
+            self.profile.total_samples
+        )
+    }
+}
+
+/// Sampler merging the Python stacks of all threads, on CPU or wall-clock time.
+#[cfg(target_os = "linux")]
+pub(super) fn start_sampling(freq_hz: u32, wall_clock: bool) -> PyResult<Sampler> {
+    let pid = std::process::id() as i32;
+    let provider = SignalTracer::python_stacks_provider();
+    let sampler = if wall_clock {
+        Sampler::start_wall_clock(freq_hz, UnwindStrategy::Dwarf, Some(provider))
+    } else {
+        Sampler::start_with_python(freq_hz, provider)
+    };
+    sampler.map_err(|err| TracerError::capture(pid, err).into())
+}
+
+#[cfg(target_os = "linux")]
+pub(super) fn stop_sampling(py: Python<'_>, sampler: Sampler) -> Profile {
+    // The collector thread takes the GIL to read Python stacks.
+    py.detach(|| sampler.stop())
+}
+
+/// Sampling session over all threads, usable as a context manager.
+///
+/// Only one tracer can sample at a time in a process. With `wall_clock`, threads are
+/// sampled whether they run or not, blocked ones off-CPU with their system call as leaf.
+#[pyclass(module = "mixed_stack_tracer")]
+pub struct Tracer {
+    freq_hz: u32,
+    wall_clock: bool,
+    #[cfg(target_os = "linux")]
+    sampler: Mutex<Option<Sampler>>,
+    profile: Mutex<Option<Profile>>,
+}
+
+#[pymethods]
+impl Tracer {
+    #[new]
+    #[pyo3(signature = (freq_hz = 99, wall_clock = false))]
+    fn py_new(freq_hz: u32, wall_clock: bool) -> Self {
+        Tracer {
+            freq_hz,
+            wall_clock,
+            #[cfg(target_os = "linux")]
+            sampler: Mutex::new(None),
+            profile: Mutex::new(None),
+        }
+    }
+
+    #[getter]
+    fn freq_hz(&self) -> u32 {
+        self.freq_hz
+    }
+
+    #[getter]
+    fn wall_clock(&self) -> bool {
+        self.wall_clock
+    }
+
+    /// Start sampling mixed stacks of every thread.
+    fn start(&self) -> PyResult<()> {
+        #[cfg(target_os = "linux")]
+        {
+            let mut sampler = self.sampler.lock().unwrap_or_else(|e| e.into_inner());
+            if sampler.is_some() {
+                return Err(PyRuntimeError::new_err("tracer already started"));
+            }
+            *sampler = Some(start_sampling(self.freq_hz, self.wall_clock)?);
+            Ok(())
+        }
+        #[cfg(not(target_os = "linux"))]
+        Err(TracerError::Unsupported("sampling is only supported on Linux".into()).into())
+    }
+
+    /// Stop sampling and return the profile, also kept as `profile`.
+    fn stop(&self, py: Python<'_>) -> PyResult<PyProfile> {
+        #[cfg(target_os = "linux")]
+        {
+            let sampler = self
+                .sampler
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .take();
+            let Some(sampler) = sampler else {
+                return Err(PyRuntimeError::new_err("tracer not started"));
+            };
+            let profile = stop_sampling(py, sampler);
+            *self.profile.lock().unwrap_or_else(|e| e.into_inner()) = Some(profile.clone());
+            Ok(PyProfile::new(profile))
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = py;
+            Err(PyRuntimeError::new_err("tracer not started"))
+        }
+    }
+
+    /// Profile of the last `stop`, `None` before that.
+    #[getter]
+    fn profile(&self) -> Option<PyProfile> {
+        let profile = self.profile.lock().unwrap_or_else(|e| e.into_inner());
+        profile.clone().map(PyProfile::new)
+    }
+
+    /// Merged mixed stack of the calling thread right now, leaf first, like `capture()`.
+    /// Works whether or not the tracer is sampling.
+    fn snapshot(&self, py: Python<'_>) -> PyResult<Vec<Py<PyAny>>> {
+        capture(py, Vec::new(), None)
+    }
+
+    fn __enter__(slf: PyRef<'_, Self>) -> PyResult<PyRef<'_, Self>> {
+        slf.start()?;
+        Ok(slf)
+    }
+
+    #[pyo3(signature = (*_args))]
+    fn __exit__(&self, py: Python<'_>, _args: &Bound<'_, pyo3::types::PyTuple>) -> PyResult<bool> {
+        self.stop(py)?;
+        // Do not swallow the exception raised in the block, if any.
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module(py: Python<'_>) -> Bound<'_, PyModule> {
+        let m = PyModule::new(py, "mixed_stack_tracer").unwrap();
+        mixed_stack_tracer(&m).unwrap();
+        m
+    }
+
+    #[test]
+    fn test_frame_attributes() {
+        Python::initialize();
+        Python::attach(|py| {
+            let mut frame = CallFrame::python("0x20", "app.py", "run", 3);
+            if let CallFrame::PyFrame { locals, .. } = &mut frame {
+                locals.insert("rate".to_string(), Value::float(0.5));
+            }
+            let frame = Bound::new(py, Frame::new(frame)).unwrap();
+            assert_eq!(
+                frame.getattr("kind").unwrap().extract::<String>().unwrap(),
+                "python"
+            );
+            assert_eq!(
+                frame.getattr("lineno").unwrap().extract::<i64>().unwrap(),
+                3
+            );
+            let rate: f64 = frame
+                .getattr("locals")
+                .unwrap()
+                .get_item("rate")
+                .unwrap()
+                .extract()
+                .unwrap();
+            assert_eq!(rate, 0.5);
+            assert!(frame.getattr("category").unwrap().is_none());
+            assert!(frame
+                .repr()
+                .unwrap()
+                .to_string()
+                .starts_with("Frame(kind=\"python\""));
+        });
+    }
+
+    #[test]
+    fn test_exception_hierarchy() {
+        Python::initialize();
+        Python::attach(|py| {
+            let globals = PyDict::new(py);
+            globals.set_item("mst", module(py)).unwrap();
+            py.run(
+                c"assert issubclass(mst.PermissionError, mst.CaptureError)
+assert issubclass(mst.CaptureError, mst.TracerError)
+assert issubclass(mst.UnsupportedError, mst.TracerError)
+assert issubclass(mst.SymbolicationError, mst.TracerError)
+assert not issubclass(mst.TracerError, ValueError)
+assert mst.PermissionError.__module__ == 'mixed_stack_tracer'
+",
+                Some(&globals),
+                None,
+            )
+            .unwrap();
+        });
+    }
+
+    #[test]
+    fn test_tracer_snapshot() {
+        Python::initialize();
+        Python::attach(|py| {
+            let globals = PyDict::new(py);
+            globals.set_item("mst", module(py)).unwrap();
+            py.run(
+                c"def inner():
+    return mst.Tracer().snapshot()
+frames = inner()
+",
+                Some(&globals),
+                None,
+            )
+            .unwrap();
+            let frames: Vec<Frame> = globals
+                .get_item("frames")
+                .unwrap()
+                .unwrap()
+                .extract()
+                .unwrap();
+            let python: Vec<&str> = frames
+                .iter()
+                .filter(|f| f.kind() == "python")
+                .map(|f| f.func())
+                .collect();
+            assert_eq!(python, ["inner", "<module>"]);
+            assert!(frames.iter().any(|f| f.kind() == "native"));
+        });
+    }
+
+    #[test]
+    fn test_capture() {
+        Python::initialize();
+        Python::attach(|py| {
+            let globals = PyDict::new(py);
+            globals.set_item("mst", module(py)).unwrap();
+            py.run(
+                c"def handler(request_id):
+    return mst.capture(locals=['request_id'])
+frames = handler(7)
+python = [f for f in frames if isinstance(f, mst.PyFrame)]
+",
+                Some(&globals),
+                None,
+            )
+            .unwrap();
+            let python = globals.get_item("python").unwrap().unwrap();
+            let leaf = python.get_item(0).unwrap();
+            assert_eq!(
+                leaf.getattr("func").unwrap().extract::<String>().unwrap(),
+                "handler"
+            );
+            let request_id: i64 = leaf
+                .getattr("locals")
+                .unwrap()
+                .get_item("request_id")
+                .unwrap()
+                .extract()
+                .unwrap();
+            assert_eq!(request_id, 7);
+            // The python frames are interleaved with the interpreter's native frames.
+            let frames: Vec<Frame> = globals
+                .get_item("frames")
+                .unwrap()
+                .unwrap()
+                .extract()
+                .unwrap();
+            let first_python = frames.iter().position(|f| f.kind() == "python").unwrap();
+            assert!(frames[first_python..].iter().any(|f| f.kind() == "native"));
+        });
+    }
+
+    #[test]
+    fn test_frame_filter() {
+        Python::initialize();
+        Python::attach(|py| {
+            let globals = PyDict::new(py);
+            globals.set_item("mst", module(py)).unwrap();
+            py.run(
+                c"f = mst.FrameFilter(exclude_func=['^noise$'], max_depth=2)
+frames = [
+    {'kind': 'python', 'func': 'leaf', 'file': 'a.py', 'lineno': 1},
+    {'kind': 'python', 'func': 'noise', 'file': 'a.py', 'lineno': 2},
+    {'kind': 'native', 'func': 'mid', 'file': 'b.c', 'lineno': 3},
+    {'kind': 'python', 'func': 'main', 'file': 'a.py', 'lineno': 4},
+]
+names = [fr.func for fr in f.apply(frames)]
+classified = mst.classify_frames(frames[:1], app_roots=['/srv'])
+only_app = mst.FrameFilter(in_app_only=True).apply(mst.classify_frames(frames))
+captured = mst.capture(filter=mst.FrameFilter(include_func=['^<module>$']))
+try:
+    mst.FrameFilter(include_file=['('])
+    bad = False
+except ValueError:
+    bad = True
+",
+                Some(&globals),
+                None,
+            )
+            .unwrap();
+            let get = |name: &str| globals.get_item(name).unwrap().unwrap();
+            let names: Vec<String> = get("names").extract().unwrap();
+            assert_eq!(names, vec!["leaf", "mid", "[truncated 1 frame]"]);
+            let classified = get("classified").get_item(0).unwrap();
+            assert_eq!(
+                classified
+                    .getattr("module")
+                    .unwrap()
+                    .extract::<Option<String>>()
+                    .unwrap(),
+                Some("a".to_string())
+            );
+            assert!(!classified
+                .getattr("in_app")
+                .unwrap()
+                .extract::<bool>()
+                .unwrap());
+            assert_eq!(get("only_app").len().unwrap(), 4);
+            let captured: Vec<Frame> = get("captured").extract().unwrap();
+            assert_eq!(captured.len(), 1);
+            assert_eq!(captured[0].func(), "<module>");
+            assert!(get("bad").extract::<bool>().unwrap());
+        });
+    }
+
+    #[test]
+    fn test_merge_accepts_frames_and_dicts() {
+        Python::initialize();
+        Python::attach(|py| {
+            let globals = PyDict::new(py);
+            globals.set_item("mst", module(py)).unwrap();
+            py.run(
+                c"native = [
+    mst.CFrame('0x1', 'libc.so', 'read'),
+    {'ip': '0x2', 'file': '', 'func': 'PyEval_EvalFrameDefault', 'lineno': 0},
+    mst.CFrame('0x3', '', 'main'),
+]
+python = [{'ip': '0x10', 'file': 'app.py', 'func': 'run', 'lineno': 3}]
+merged = mst.merge_python_native_stacks(python, native)
+funcs = [f.func for f in merged]
+types = [type(f).__name__ for f in merged]
+assert all(isinstance(f, mst.Frame) for f in merged)
+assert merged[1] == mst.PyFrame('0x10', 'app.py', 'run', 3)
+try:
+    mst.merge_python_native_stacks([42], [])
+except TypeError:
+    rejected = True
+",
+                Some(&globals),
+                None,
+            )
+            .unwrap();
+            let get = |name: &str| globals.get_item(name).unwrap().unwrap();
+            let funcs: Vec<String> = get("funcs").extract().unwrap();
+            assert_eq!(funcs, ["read", "run", "main"]);
+            let types: Vec<String> = get("types").extract().unwrap();
+            assert_eq!(types, ["CFrame", "PyFrame", "CFrame"]);
+            assert!(get("rejected").is_truthy().unwrap());
+        });
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_tracer_context_manager() {
+        let _guard = crate::sampler::TEST_SAMPLER_LOCK
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        Python::initialize();
+        Python::attach(|py| {
+            let globals = PyDict::new(py);
+            globals.set_item("mst", module(py)).unwrap();
+            py.run(
+                c"tracer = mst.Tracer(freq_hz=1000)
+with tracer:
+    sum(i * i for i in range(200000))
+profile = tracer.profile
+",
+                Some(&globals),
+                None,
+            )
+            .unwrap();
+            let profile: PyProfile = globals
+                .get_item("profile")
+                .unwrap()
+                .unwrap()
+                .extract()
+                .unwrap();
+            assert_eq!(
+                profile.profile().total_samples,
+                profile
+                    .profile()
+                    .stacks
+                    .iter()
+                    .map(|s| s.count)
+                    .sum::<u64>()
+            );
+        });
+    }
+}
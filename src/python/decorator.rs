@@ -0,0 +1,267 @@
+//! `@profile_mixed`: sample mixed stacks while a Python function runs.
+//!
+//! ```python
+//! @mst.profile_mixed(freq_hz=499, output="step.folded")
+//! def train_step(batch): ...
+//!
+//! train_step(batch)
+//! print(train_step.last_profile.total_samples)
+//! ```
+
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyTuple};
+
+use super::bindings::PyProfile;
+#[cfg(not(target_os = "linux"))]
+use crate::error::TracerError;
+use crate::output::folded::{self, FoldedOptions};
+use crate::output::speedscope;
+use crate::profile::Profile;
+
+/// File format written after each call.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    Folded,
+    Speedscope,
+}
+
+impl OutputFormat {
+    fn parse(name: &str) -> PyResult<Self> {
+        match name {
+            "folded" => Ok(OutputFormat::Folded),
+            "speedscope" => Ok(OutputFormat::Speedscope),
+            other => Err(PyValueError::new_err(format!(
+                "unknown format {:?}, expected \"folded\" or \"speedscope\"",
+                other
+            ))),
+        }
+    }
+}
+
+/// Decorate a function so every call is sampled.
+///
+/// Usable bare (`@profile_mixed`) or with options. The profile of the last call is kept
+/// as `last_profile` and, when `output` is given, written there in `format`
+/// (`"folded"` or `"speedscope"`), replacing the previous file.
+#[pyfunction]
+#[pyo3(signature = (func = None, *, freq_hz = 99, output = None, format = "folded"))]
+pub(super) fn profile_mixed(
+    py: Python<'_>,
+    func: Option<Py<PyAny>>,
+    freq_hz: u32,
+    output: Option<PathBuf>,
+    format: &str,
+) -> PyResult<Py<PyAny>> {
+    let settings = Settings {
+        freq_hz,
+        output,
+        format: OutputFormat::parse(format)?,
+    };
+    match func {
+        Some(func) => Ok(Py::new(py, ProfiledFunction::new(func, settings))?.into_any()),
+        // Called with options only: return the actual decorator.
+        None => Ok(Py::new(py, Decorator { settings })?.into_any()),
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Settings {
+    freq_hz: u32,
+    output: Option<PathBuf>,
+    format: OutputFormat,
+}
+
+#[pyclass(frozen, module = "mixed_stack_tracer")]
+struct Decorator {
+    settings: Settings,
+}
+
+#[pymethods]
+impl Decorator {
+    fn __call__(&self, py: Python<'_>, func: Py<PyAny>) -> PyResult<Py<ProfiledFunction>> {
+        Py::new(py, ProfiledFunction::new(func, self.settings.clone()))
+    }
+}
+
+/// A function wrapped by `profile_mixed`.
+#[pyclass(frozen, module = "mixed_stack_tracer")]
+pub struct ProfiledFunction {
+    func: Py<PyAny>,
+    settings: Settings,
+    /// A call is being sampled; recursive calls run unsampled inside it.
+    active: AtomicBool,
+    last_profile: Mutex<Option<Profile>>,
+}
+
+impl ProfiledFunction {
+    fn new(func: Py<PyAny>, settings: Settings) -> Self {
+        ProfiledFunction {
+            func,
+            settings,
+            active: AtomicBool::new(false),
+            last_profile: Mutex::new(None),
+        }
+    }
+
+    fn write_output(&self, py: Python<'_>, profile: &Profile) -> PyResult<()> {
+        let Some(path) = &self.settings.output else {
+            return Ok(());
+        };
+        let mut out = BufWriter::new(File::create(path)?);
+        match self.settings.format {
+            OutputFormat::Folded => {
+                folded::write_profile(&mut out, profile, &FoldedOptions::new())?
+            }
+            OutputFormat::Speedscope => {
+                let name: String = self.func.bind(py).getattr("__qualname__")?.extract()?;
+                speedscope::write_profile(&mut out, profile, &name)?
+            }
+        }
+        Ok(())
+    }
+}
+
+#[pymethods]
+impl ProfiledFunction {
+    #[pyo3(signature = (*args, **kwargs))]
+    fn __call__(
+        &self,
+        py: Python<'_>,
+        args: &Bound<'_, PyTuple>,
+        kwargs: Option<&Bound<'_, PyDict>>,
+    ) -> PyResult<Py<PyAny>> {
+        if self.active.swap(true, Ordering::SeqCst) {
+            return self.func.call(py, args, kwargs);
+        }
+        let result = self.sampled_call(py, args, kwargs);
+        self.active.store(false, Ordering::SeqCst);
+        result
+    }
+
+    /// Bind to the instance when decorating a method.
+    fn __get__(
+        slf: Py<Self>,
+        py: Python<'_>,
+        obj: Option<Py<PyAny>>,
+        _owner: Option<Py<PyAny>>,
+    ) -> PyResult<Py<PyAny>> {
+        match obj {
+            Some(obj) if !obj.is_none(py) => Ok(py
+                .import("types")?
+                .getattr("MethodType")?
+                .call1((slf, obj))?
+                .unbind()),
+            _ => Ok(slf.into_any()),
+        }
+    }
+
+    /// Profile of the last completed call, `None` before the first.
+    #[getter]
+    fn last_profile(&self) -> Option<PyProfile> {
+        let profile = self.last_profile.lock().unwrap_or_else(|e| e.into_inner());
+        profile.clone().map(PyProfile::new)
+    }
+
+    #[getter]
+    fn __wrapped__(&self, py: Python<'_>) -> Py<PyAny> {
+        self.func.clone_ref(py)
+    }
+
+    #[getter]
+    fn __name__(&self, py: Python<'_>) -> PyResult<Py<PyAny>> {
+        Ok(self.func.bind(py).getattr("__name__")?.unbind())
+    }
+}
+
+impl ProfiledFunction {
+    #[cfg(target_os = "linux")]
+    fn sampled_call(
+        &self,
+        py: Python<'_>,
+        args: &Bound<'_, PyTuple>,
+        kwargs: Option<&Bound<'_, PyDict>>,
+    ) -> PyResult<Py<PyAny>> {
+        let sampler = super::bindings::start_sampling(self.settings.freq_hz, false)?;
+        let result = self.func.call(py, args, kwargs);
+        let profile = super::bindings::stop_sampling(py, sampler);
+        self.write_output(py, &profile)?;
+        *self.last_profile.lock().unwrap_or_else(|e| e.into_inner()) = Some(profile);
+        result
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn sampled_call(
+        &self,
+        _py: Python<'_>,
+        _args: &Bound<'_, PyTuple>,
+        _kwargs: Option<&Bound<'_, PyDict>>,
+    ) -> PyResult<Py<PyAny>> {
+        Err(TracerError::Unsupported("sampling is only supported on Linux".into()).into())
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_mixed_decorator() {
+        let _guard = crate::sampler::TEST_SAMPLER_LOCK
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let output =
+            std::env::temp_dir().join(format!("mst-decorator-{}.folded", std::process::id()));
+        Python::initialize();
+        Python::attach(|py| {
+            let m = PyModule::new(py, "mixed_stack_tracer").unwrap();
+            super::super::mixed_stack_tracer(&m).unwrap();
+            let globals = PyDict::new(py);
+            globals.set_item("mst", m).unwrap();
+            globals.set_item("output", &output).unwrap();
+            py.run(
+                c"@mst.profile_mixed
+def fib(n):
+    return n if n < 2 else fib(n - 1) + fib(n - 2)
+
+class Model:
+    @mst.profile_mixed(freq_hz=997, output=output)
+    def step(self, n):
+        return sum(i * i for i in range(n))
+
+value = fib(15)
+total = Model().step(300000)
+profile = Model.step.last_profile
+",
+                Some(&globals),
+                None,
+            )
+            .unwrap();
+            let get = |name: &str| globals.get_item(name).unwrap().unwrap();
+            assert_eq!(get("value").extract::<i64>().unwrap(), 610);
+            assert!(get("profile").extract::<PyProfile>().is_ok());
+            let fib = get("fib");
+            assert_eq!(
+                fib.getattr("__name__")
+                    .unwrap()
+                    .extract::<String>()
+                    .unwrap(),
+                "fib"
+            );
+            assert!(!fib.getattr("last_profile").unwrap().is_none());
+
+            let err = py
+                .run(c"mst.profile_mixed(format='svg')", Some(&globals), None)
+                .unwrap_err();
+            assert!(err.is_instance_of::<PyValueError>(py));
+        });
+        assert!(output.exists());
+        std::fs::remove_file(output).unwrap();
+    }
+}
@@ -0,0 +1,285 @@
+//! `install_excepthook`: mixed stacks of uncaught exceptions.
+//!
+//! ```python
+//! mst.install_excepthook("/var/log/app/crashes.txt")
+//! ```
+//!
+//! The hooks wrap `sys.excepthook` and `threading.excepthook`. An exception reaching them
+//! has its traceback, the Python frames down to the `raise`, merged with the native stack
+//! of the hook (see `SignalTracer::merge_traces`) and written to the sink; the previous
+//! hook then runs as before. Native frames of the unwound calls are gone by then, so the
+//! traceback frames without an eval loop left are appended at the root.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+
+use pyo3::exceptions::{PySystemExit, PyTypeError};
+use pyo3::prelude::*;
+use pyo3::types::PyTuple;
+
+use super::bindings::frames_to_py;
+use crate::output::text;
+use crate::stack_tracer::SignalTracer;
+use crate::CallFrame;
+
+/// Where reports go.
+enum Sink {
+    /// `sys.stderr`, looked up at report time.
+    Stderr,
+    /// Appended to this file.
+    File(PathBuf),
+    /// An object with a `write` method.
+    Stream(Py<PyAny>),
+    /// Called with the exception and its merged frames.
+    Callback(Py<PyAny>),
+}
+
+impl Sink {
+    fn from_py(sink: Option<&Bound<'_, PyAny>>) -> PyResult<Sink> {
+        let Some(sink) = sink.filter(|s| !s.is_none()) else {
+            return Ok(Sink::Stderr);
+        };
+        if let Ok(path) = sink.extract::<PathBuf>() {
+            Ok(Sink::File(path))
+        } else if sink.hasattr("write")? {
+            Ok(Sink::Stream(sink.clone().unbind()))
+        } else if sink.is_callable() {
+            Ok(Sink::Callback(sink.clone().unbind()))
+        } else {
+            Err(PyTypeError::new_err(
+                "sink must be a path, a writable stream or a callable",
+            ))
+        }
+    }
+
+    fn clone_ref(&self, py: Python<'_>) -> Sink {
+        match self {
+            Sink::Stderr => Sink::Stderr,
+            Sink::File(path) => Sink::File(path.clone()),
+            Sink::Stream(stream) => Sink::Stream(stream.clone_ref(py)),
+            Sink::Callback(callback) => Sink::Callback(callback.clone_ref(py)),
+        }
+    }
+}
+
+/// Wrap `sys.excepthook` and `threading.excepthook` so that uncaught exceptions are
+/// reported with their mixed stack to `sink`: `None` for `sys.stderr`, a path to append
+/// to, a writable stream, or a callable taking `(exception, frames)`.
+///
+/// Installing again replaces the sink instead of reporting twice.
+#[pyfunction]
+#[pyo3(signature = (sink = None))]
+pub(super) fn install_excepthook(py: Python<'_>, sink: Option<Bound<'_, PyAny>>) -> PyResult<()> {
+    let sink = Sink::from_py(sink.as_ref())?;
+    for (module, threading) in [("sys", false), ("threading", true)] {
+        let module = py.import(module)?;
+        let mut previous = module.getattr("excepthook")?;
+        if let Ok(hook) = previous.cast::<ExceptHook>() {
+            previous = hook.get().previous.bind(py).clone();
+        }
+        let hook = ExceptHook {
+            previous: previous.unbind(),
+            sink: sink.clone_ref(py),
+            threading,
+        };
+        module.setattr("excepthook", Py::new(py, hook)?)?;
+    }
+    Ok(())
+}
+
+/// Restore the hooks `install_excepthook` wrapped.
+#[pyfunction]
+pub(super) fn uninstall_excepthook(py: Python<'_>) -> PyResult<()> {
+    for module in ["sys", "threading"] {
+        let module = py.import(module)?;
+        let current = module.getattr("excepthook")?;
+        if let Ok(hook) = current.cast::<ExceptHook>() {
+            module.setattr("excepthook", hook.get().previous.bind(py))?;
+        }
+    }
+    Ok(())
+}
+
+/// The hook `install_excepthook` installs, for `sys` or for `threading`.
+#[pyclass(frozen, module = "mixed_stack_tracer")]
+struct ExceptHook {
+    previous: Py<PyAny>,
+    sink: Sink,
+    /// Called as `threading.excepthook(args)` rather than `sys.excepthook(type, value, tb)`.
+    threading: bool,
+}
+
+#[pymethods]
+impl ExceptHook {
+    #[pyo3(signature = (*args))]
+    fn __call__(&self, py: Python<'_>, args: &Bound<'_, PyTuple>) -> PyResult<Py<PyAny>> {
+        let (exc, traceback, thread) = if self.threading {
+            let hook_args = args.get_item(0)?;
+            (
+                hook_args.getattr("exc_value")?,
+                hook_args.getattr("exc_traceback")?,
+                hook_args.getattr("thread")?,
+            )
+        } else {
+            (
+                args.get_item(1)?,
+                args.get_item(2)?,
+                py.None().into_bound(py),
+            )
+        };
+        // `threading` ignores `SystemExit`, and a failed report must not hide the exception.
+        if !exc.is_instance_of::<PySystemExit>() {
+            if let Err(err) = self.report(py, &exc, &traceback, &thread) {
+                err.write_unraisable(py, Some(self.previous.bind(py)));
+            }
+        }
+        self.previous.call1(py, args)
+    }
+}
+
+impl ExceptHook {
+    fn report(
+        &self,
+        py: Python<'_>,
+        exc: &Bound<'_, PyAny>,
+        traceback: &Bound<'_, PyAny>,
+        thread: &Bound<'_, PyAny>,
+    ) -> PyResult<()> {
+        let native = SignalTracer::capture_native_stack();
+        let frames = if traceback.is_none() {
+            native.frames
+        } else {
+            SignalTracer::merge_traces(SignalTracer::traceback_frames(traceback)?.into(), native)
+                .into_frames()
+        };
+        if let Sink::Callback(callback) = &self.sink {
+            callback.call1(py, (exc, frames_to_py(py, frames)?))?;
+            return Ok(());
+        }
+
+        let text = format_report(exc, thread, &frames)?;
+        match &self.sink {
+            Sink::Stderr => {
+                py.import("sys")?
+                    .getattr("stderr")?
+                    .call_method1("write", (text,))?;
+            }
+            Sink::File(path) => {
+                let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+                file.write_all(text.as_bytes())?;
+            }
+            Sink::Stream(stream) => {
+                stream.call_method1(py, "write", (text,))?;
+            }
+            Sink::Callback(_) => unreachable!("handled above"),
+        }
+        Ok(())
+    }
+}
+
+/// `Uncaught ValueError: bad input in thread "worker"`, then the frames as in `mst dump`.
+fn format_report(
+    exc: &Bound<'_, PyAny>,
+    thread: &Bound<'_, PyAny>,
+    frames: &[CallFrame],
+) -> PyResult<String> {
+    let name: String = exc.get_type().qualname()?.extract()?;
+    let message = exc.str()?.to_string();
+    let mut out = format!("Uncaught {}", name);
+    if !message.is_empty() {
+        out.push_str(": ");
+        out.push_str(&message);
+    }
+    if !thread.is_none() {
+        let thread: String = thread.getattr("name")?.extract()?;
+        out.push_str(&format!(" in thread {:?}", thread));
+    }
+    out.push('\n');
+    let mut body = Vec::new();
+    text::write_frames(&mut body, frames)?;
+    out.push_str(&String::from_utf8_lossy(&body));
+    out.push('\n');
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pyo3::types::PyDict;
+
+    #[test]
+    fn test_excepthook() {
+        Python::initialize();
+        Python::attach(|py| {
+            let m = PyModule::new(py, "mixed_stack_tracer").unwrap();
+            super::super::mixed_stack_tracer(&m).unwrap();
+            let globals = PyDict::new(py);
+            globals.set_item("mst", m).unwrap();
+            py.run(
+                c"import io, sys, threading
+original = sys.excepthook, threading.excepthook
+seen = []
+stream = io.StringIO()
+
+def fail():
+    raise ValueError('bad input')
+
+def report():
+    try:
+        fail()
+    except ValueError:
+        sys.excepthook(*sys.exc_info())
+
+stderr, sys.stderr = sys.stderr, io.StringIO()
+mst.install_excepthook(lambda exc, frames: seen.append((exc, frames)))
+report()
+# Installing again swaps the sink instead of chaining.
+mst.install_excepthook(stream)
+report()
+worker = threading.Thread(target=fail, name='worker')
+worker.start()
+worker.join()
+sys.stderr = stderr
+mst.uninstall_excepthook()
+restored = (sys.excepthook, threading.excepthook) == original
+
+exc, frames = seen[0]
+callback_ok = (
+    len(seen) == 1
+    and isinstance(exc, ValueError)
+    and frames[0].kind == 'native'
+    and [f.func for f in frames if f.kind == 'python'][:2] == ['fail', 'report']
+)
+text = stream.getvalue()
+",
+                Some(&globals),
+                None,
+            )
+            .unwrap();
+            let get = |name: &str| globals.get_item(name).unwrap().unwrap();
+            assert!(get("restored").is_truthy().unwrap());
+            assert!(get("callback_ok").is_truthy().unwrap());
+
+            let text: String = get("text").extract().unwrap();
+            let reports: Vec<&str> = text.split("Uncaught ").skip(1).collect();
+            assert_eq!(reports.len(), 2, "{}", text);
+            assert!(reports[0].starts_with("ValueError: bad input\n"));
+            assert!(reports[0].contains("[py] fail (<string>:7)"));
+            assert!(reports[0].contains("[py] report"));
+            assert!(reports[1].starts_with("ValueError: bad input in thread \"worker\"\n"));
+        });
+    }
+
+    #[test]
+    fn test_sink_from_py() {
+        Python::initialize();
+        Python::attach(|py| {
+            let number = 3i64.into_pyobject(py).unwrap().into_any();
+            assert!(Sink::from_py(Some(&number)).is_err());
+            let path = "/tmp/crashes.txt".into_pyobject(py).unwrap().into_any();
+            assert!(matches!(Sink::from_py(Some(&path)), Ok(Sink::File(_))));
+            assert!(matches!(Sink::from_py(None), Ok(Sink::Stderr)));
+        });
+    }
+}
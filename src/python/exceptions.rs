@@ -0,0 +1,62 @@
+//! Exceptions of the extension module.
+//!
+//! Everything the tracer raises for a failed capture derives from
+//! `mixed_stack_tracer.TracerError`, with subclasses for the variants of
+//! `crate::TracerError`, so that callers can tell a refused ptrace from an unsupported platform:
+//!
+//! ```python
+//! try:
+//!     tracer.start()
+//! except mst.UnsupportedError:
+//!     tracer = None  # sampling needs Linux
+//! except mst.TracerError as err:
+//!     print("cannot sample:", err)
+//! ```
+//!
+//! Bad arguments still raise `TypeError` and `ValueError`.
+
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+
+create_exception!(
+    mixed_stack_tracer,
+    TracerError,
+    PyException,
+    "Base class of the errors raised by the tracer."
+);
+create_exception!(
+    mixed_stack_tracer,
+    CaptureError,
+    TracerError,
+    "Threads, registers or memory of the target could not be read."
+);
+create_exception!(
+    mixed_stack_tracer,
+    PermissionError,
+    CaptureError,
+    "The OS refused to let the tracer inspect the target (ptrace, task_for_pid)."
+);
+create_exception!(
+    mixed_stack_tracer,
+    UnsupportedError,
+    TracerError,
+    "Not available on this platform or for this target."
+);
+create_exception!(
+    mixed_stack_tracer,
+    SymbolicationError,
+    TracerError,
+    "Symbols or debug info could not be loaded."
+);
+
+/// Add the exception types to the module.
+pub(super) fn register(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    let py = m.py();
+    m.add("TracerError", py.get_type::<TracerError>())?;
+    m.add("CaptureError", py.get_type::<CaptureError>())?;
+    m.add("PermissionError", py.get_type::<PermissionError>())?;
+    m.add("UnsupportedError", py.get_type::<UnsupportedError>())?;
+    m.add("SymbolicationError", py.get_type::<SymbolicationError>())?;
+    Ok(())
+}
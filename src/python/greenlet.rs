@@ -0,0 +1,136 @@
+//! Suspended greenlet stacks (gevent, eventlet).
+//!
+//! Only the greenlet running on a thread has its frames on that thread's stack; the others
+//! keep their top frame in `gr_frame` until switched back to. Greenlets are found through
+//! the garbage collector, which tracks every greenlet object.
+
+use pyo3::prelude::*;
+
+use super::walk_frames;
+use crate::locals::LocalsPolicy;
+use crate::stack_tracer::SignalTracer;
+use crate::CallFrame;
+
+/// Python stack of one suspended greenlet.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GreenletStack {
+    /// `name` of gevent greenlets, the greenlet's type name otherwise.
+    pub name: String,
+    /// The greenlet belongs to the calling thread, i.e. descends from its main greenlet.
+    pub current_thread: bool,
+    /// Frames saved at the last switch, leaf first.
+    pub frames: Vec<CallFrame>,
+}
+
+impl SignalTracer {
+    /// Stacks of all started, suspended greenlets of the process.
+    ///
+    /// Running greenlets are left out as their frames are on their thread's stack. Returns
+    /// an empty list when the `greenlet` module cannot be imported.
+    pub fn capture_greenlet_stacks(py: Python<'_>) -> PyResult<Vec<GreenletStack>> {
+        let Ok(greenlet) = py.import("greenlet") else {
+            return Ok(Vec::new());
+        };
+        let greenlet_type = greenlet.getattr("greenlet")?;
+        let own_root = root(greenlet.call_method0("getcurrent")?)?;
+
+        let mut stacks = Vec::new();
+        for object in py.import("gc")?.call_method0("get_objects")?.try_iter()? {
+            let object = object?;
+            if !object.is_instance(&greenlet_type)? || object.getattr("dead")?.is_truthy()? {
+                continue;
+            }
+            // None until started, and for the greenlet that is running.
+            let frame = object.getattr("gr_frame")?;
+            if frame.is_none() {
+                continue;
+            }
+            let name = match object.getattr("name").and_then(|n| n.extract::<String>()) {
+                Ok(name) => name,
+                Err(_) => object.get_type().name()?.to_string(),
+            };
+            stacks.push(GreenletStack {
+                name,
+                current_thread: root(object)?.is(&own_root),
+                frames: walk_frames(frame, &LocalsPolicy::none())?,
+            });
+        }
+        Ok(stacks)
+    }
+}
+
+/// The main greenlet of the thread `greenlet` was created on.
+fn root(greenlet: Bound<'_, PyAny>) -> PyResult<Bound<'_, PyAny>> {
+    let mut current = greenlet;
+    loop {
+        let parent = current.getattr("parent")?;
+        if parent.is_none() {
+            return Ok(current);
+        }
+        current = parent;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pyo3::types::PyDict;
+
+    /// Stand-in for the `greenlet` extension: a generator's frame is suspended the same
+    /// way a switched-out greenlet's is.
+    const FAKE_GREENLET: &std::ffi::CStr = c"import sys, types
+greenlet = types.ModuleType('greenlet')
+class Greenlet:
+    def __init__(self, parent=None, frame=None, dead=False):
+        self.parent, self.gr_frame, self.dead = parent, frame, dead
+greenlet.greenlet = Greenlet
+main = Greenlet()
+greenlet.getcurrent = lambda: main
+sys.modules['greenlet'] = greenlet
+
+class Named(Greenlet):
+    name = 'handler-1'
+def serve():
+    yield
+def handle():
+    yield from serve()
+gen = handle()
+next(gen)
+keep = [
+    Named(parent=main, frame=gen.gi_yieldfrom.gi_frame),
+    Greenlet(parent=Greenlet(), frame=gen.gi_frame),
+    Greenlet(parent=main, frame=gen.gi_frame, dead=True),
+    Greenlet(parent=main),
+]
+";
+
+    #[test]
+    fn test_capture_greenlet_stacks() {
+        Python::initialize();
+        Python::attach(|py| {
+            let globals = PyDict::new(py);
+            py.run(FAKE_GREENLET, Some(&globals), None).unwrap();
+            let mut stacks = SignalTracer::capture_greenlet_stacks(py).unwrap();
+            py.import("sys")
+                .unwrap()
+                .getattr("modules")
+                .unwrap()
+                .del_item("greenlet")
+                .unwrap();
+
+            stacks.sort_by(|a, b| a.name.cmp(&b.name));
+            assert_eq!(stacks.len(), 2, "{:?}", stacks);
+            assert_eq!(stacks[0].name, "Greenlet");
+            assert!(!stacks[0].current_thread);
+            assert_eq!(stacks[1].name, "handler-1");
+            assert!(stacks[1].current_thread);
+            // Generator frames have no `f_back` while suspended.
+            assert_eq!(stacks[1].frames.len(), 1);
+            assert_eq!(stacks[1].frames[0].func(), "serve");
+
+            assert!(SignalTracer::capture_greenlet_stacks(py)
+                .unwrap()
+                .is_empty());
+        });
+    }
+}
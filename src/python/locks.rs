@@ -0,0 +1,214 @@
+//! Owners and waiters of `threading` locks, for `crate::deadlock` (Linux).
+//!
+//! Up to CPython 3.12, `_thread.lock` and `_thread.RLock` objects point to a semaphore a
+//! blocked `acquire` sleeps on, so the futex of a waiting thread names the lock it waits
+//! for. RLocks also record the `threading.get_ident()` of their owner. From 3.13 locks are
+//! `PyMutex`es whose waiters park on a futex of their own, and no edge is found.
+
+use std::collections::HashMap;
+
+use pyo3::prelude::*;
+use pyo3::types::PyTuple;
+
+use crate::deadlock::{self, LockGraph, LockId, LockKind};
+use crate::stack_tracer::SignalTracer;
+use crate::thread_stack::{ThreadId, ThreadStack};
+use crate::CallFrame;
+
+/// Native functions of a thread blocked in `Lock.acquire` or `RLock.acquire`.
+const ACQUIRE_FUNCTIONS: &[&str] = &[
+    "PyThread_acquire_lock_timed",
+    "acquire_timed",
+    "lock_PyThread_acquire_lock",
+    "rlock_acquire",
+];
+
+/// `PyObject_HEAD` of a release build: `ob_refcnt` and `ob_type`.
+const OBJECT_HEAD: usize = 16;
+
+/// Layout of `lockobject` and `rlockobject` after the object head, up to 3.12.
+#[repr(C)]
+struct LockFields {
+    /// The `PyThread_type_lock`: a `sem_t *` on Linux.
+    lock: usize,
+    /// RLocks only.
+    owner: usize,
+    count: usize,
+}
+
+impl LockGraph {
+    /// Add the `threading` locks the threads of `stacks` (of this process) wait for, and
+    /// the owners of RLocks. Locks are found among the objects referenced by objects the
+    /// garbage collector tracks: globals, attributes and containers, not locals held
+    /// only by a running frame.
+    pub fn add_python_locks(
+        &mut self,
+        py: Python<'_>,
+        stacks: &[ThreadStack],
+    ) -> PyResult<&mut Self> {
+        if !layout_known(py)? {
+            return Ok(self);
+        }
+        let waiters: HashMap<usize, ThreadId> = stacks
+            .iter()
+            .filter(|s| !s.greenlet && in_acquire(&s.frames))
+            .filter_map(|s| Some((deadlock::futex_address("self", s.tid)? as usize, s.tid)))
+            .collect();
+        if waiters.is_empty() {
+            return Ok(self);
+        }
+
+        let native_ids = native_ids(py)?;
+        let thread = py.import("_thread")?;
+        let (lock_type, rlock_type) = (thread.getattr("LockType")?, thread.getattr("RLock")?);
+        let gc = py.import("gc")?;
+        let tracked = PyTuple::new(
+            py,
+            gc.call_method0("get_objects")?
+                .try_iter()?
+                .collect::<PyResult<Vec<_>>>()?,
+        )?;
+        for object in gc.call_method1("get_referents", tracked)?.try_iter()? {
+            let object = object?;
+            let object_type = object.get_type();
+            let kind = if object_type.is(&lock_type) {
+                LockKind::PythonLock
+            } else if object_type.is(&rlock_type) {
+                LockKind::PythonRLock
+            } else {
+                continue;
+            };
+            // Safety: exact instances of the two types, with the layout `layout_known`
+            // checked, kept alive by `object`.
+            let fields = unsafe {
+                &*(object
+                    .as_ptr()
+                    .cast::<u8>()
+                    .add(OBJECT_HEAD)
+                    .cast::<LockFields>())
+            };
+            let Some(tid) = waiters.get(&fields.lock) else {
+                continue;
+            };
+            let lock = LockId::new(kind, object.as_ptr() as u64);
+            self.add_waiter(*tid, lock);
+            if kind == LockKind::PythonRLock && fields.count > 0 {
+                if let Some(owner) = native_ids.get(&fields.owner) {
+                    self.add_owner(lock, *owner);
+                }
+            }
+        }
+        Ok(self)
+    }
+}
+
+impl SignalTracer {
+    /// Wait-for cycles between the threads of this process, through pthread mutexes and
+    /// `threading` locks (see `crate::deadlock`).
+    pub fn detect_deadlocks(py: Python<'_>) -> PyResult<Vec<deadlock::Deadlock>> {
+        let stacks = Self::capture_all_mixed_threads(py)?;
+        let mut graph = LockGraph::new();
+        graph
+            .add_pthread_mutexes(std::process::id() as i32, &stacks)
+            .add_python_locks(py, &stacks)?;
+        Ok(graph.find_deadlocks(&stacks))
+    }
+}
+
+/// Release builds of CPython before 3.13.
+fn layout_known(py: Python<'_>) -> PyResult<bool> {
+    let sys = py.import("sys")?;
+    let info = sys.getattr("version_info")?;
+    let version: (u8, u8) = (
+        info.getattr("major")?.extract()?,
+        info.getattr("minor")?.extract()?,
+    );
+    let name: String = sys.getattr("implementation")?.getattr("name")?.extract()?;
+    Ok(name == "cpython" && version < (3, 13) && !sys.hasattr("gettotalrefcount")?)
+}
+
+fn in_acquire(frames: &[CallFrame]) -> bool {
+    frames.iter().any(|frame| match frame {
+        CallFrame::CFrame { func, .. } => ACQUIRE_FUNCTIONS.contains(&func.as_str()),
+        _ => false,
+    })
+}
+
+/// `Thread.ident` to `Thread.native_id` of every thread known to `threading`.
+fn native_ids(py: Python<'_>) -> PyResult<HashMap<usize, ThreadId>> {
+    let mut ids = HashMap::new();
+    for thread in py
+        .import("threading")?
+        .call_method0("enumerate")?
+        .try_iter()?
+    {
+        let thread = thread?;
+        let ident: Option<usize> = thread.getattr("ident")?.extract()?;
+        let native_id: Option<ThreadId> = thread.getattr("native_id")?.extract()?;
+        if let (Some(ident), Some(native_id)) = (ident, native_id) {
+            ids.insert(ident, native_id);
+        }
+    }
+    Ok(ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pyo3::types::PyDict;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_detect_python_deadlock() {
+        Python::initialize();
+        Python::attach(|py| {
+            if !layout_known(py).unwrap() {
+                return;
+            }
+            let globals = PyDict::new(py);
+            py.run(
+                c"import threading
+first, second = threading.RLock(), threading.RLock()
+barrier = threading.Barrier(2)
+def take(a, b):
+    with a:
+        barrier.wait()
+        with b:
+            pass
+threads = [
+    threading.Thread(target=take, args=(first, second), daemon=True),
+    threading.Thread(target=take, args=(second, first), daemon=True),
+]
+for t in threads:
+    t.start()
+",
+                Some(&globals),
+                None,
+            )
+            .unwrap();
+            let mut tids: Vec<ThreadId> = globals
+                .get_item("threads")
+                .unwrap()
+                .unwrap()
+                .try_iter()
+                .unwrap()
+                .map(|t| t.unwrap().getattr("native_id").unwrap().extract().unwrap())
+                .collect();
+            tids.sort_unstable();
+
+            let start = Instant::now();
+            let deadlock = loop {
+                let found = SignalTracer::detect_deadlocks(py).unwrap();
+                if let Some(deadlock) = found.into_iter().find(|d| d.tids() == tids) {
+                    break deadlock;
+                }
+                assert!(start.elapsed() < Duration::from_secs(5));
+                py.detach(|| std::thread::sleep(Duration::from_millis(20)));
+            };
+            for thread in &deadlock.threads {
+                assert_eq!(thread.waits_for.kind, LockKind::PythonRLock);
+                assert!(thread.frames.iter().any(|f| f.func() == "take"));
+            }
+        });
+    }
+}
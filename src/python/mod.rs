@@ -0,0 +1,778 @@
+//! In-process Python stack capture through PyO3 (`python` feature).
+//! Frames are returned leaf first (innermost Python call at index 0), like `capture_native_stack`.
+
+mod asyncio;
+mod bindings;
+mod decorator;
+mod excepthook;
+pub mod exceptions;
+mod greenlet;
+#[cfg(target_os = "linux")]
+mod locks;
+
+pub use asyncio::TaskStack;
+pub use bindings::{mixed_stack_tracer, Frame, PyProfile, Tracer};
+pub use decorator::ProfiledFunction;
+pub use greenlet::GreenletStack;
+
+use std::collections::HashMap;
+
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::{
+    PyBool, PyByteArray, PyBytes, PyDict, PyFloat, PyFrozenSet, PyInt, PyList, PySet, PyString,
+    PyTuple,
+};
+
+use crate::boundary::PythonImplementation;
+use crate::locals::{LocalsMode, LocalsPolicy};
+use crate::stack_trace::{CaptureSource, StackTrace};
+use crate::stack_tracer::SignalTracer;
+use crate::thread_stack::ThreadId;
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+use crate::thread_stack::{ThreadStack, ThreadState};
+use crate::{CallFrame, Value};
+
+impl SignalTracer {
+    /// Walk the current thread's Python frames (`sys._getframe` / `f_back` chain).
+    ///
+    /// Returns an empty stack when no Python code is executing on this thread.
+    pub fn capture_python_stack(py: Python<'_>) -> PyResult<StackTrace> {
+        Self::capture_python_stack_with_locals(py, &[])
+    }
+
+    /// Like `capture_python_stack`, additionally snapshotting the named locals of every
+    /// frame that defines them (as with `LocalsPolicy::full`, strings uncut).
+    pub fn capture_python_stack_with_locals(
+        py: Python<'_>,
+        locals: &[&str],
+    ) -> PyResult<StackTrace> {
+        let policy = match locals {
+            [] => LocalsPolicy::none(),
+            names => LocalsPolicy::full().names(names).unlimited_strings(),
+        };
+        Self::capture_python_stack_with_policy(py, &policy)
+    }
+
+    /// Like `capture_python_stack`, recording the locals selected by `policy`.
+    pub fn capture_python_stack_with_policy(
+        py: Python<'_>,
+        policy: &LocalsPolicy,
+    ) -> PyResult<StackTrace> {
+        let sys = py.import("sys")?;
+        let frame = match sys.call_method1("_getframe", (0,)) {
+            Ok(frame) => Some(frame),
+            // "call stack is not deep enough": no Python frame on this thread
+            Err(err) if err.is_instance_of::<PyValueError>(py) => None,
+            Err(err) => return Err(err),
+        };
+
+        let frames = match frame {
+            Some(frame) => walk_frames(frame, policy)?,
+            None => Vec::new(),
+        };
+        Ok(StackTrace::captured(frames, CaptureSource::Python))
+    }
+
+    /// Frames of a traceback object (`exc.__traceback__`), leaf (the raising frame) first.
+    ///
+    /// Line numbers are those recorded in the traceback, where each frame was when the
+    /// exception passed through it.
+    pub fn traceback_frames(traceback: &Bound<'_, PyAny>) -> PyResult<Vec<CallFrame>> {
+        let mut frames = Vec::new();
+        let mut tb = traceback.clone();
+        while !tb.is_none() {
+            let mut frame = to_call_frame(&tb.getattr("tb_frame")?, &LocalsPolicy::none())?;
+            if let CallFrame::PyFrame { lineno, .. } = &mut frame {
+                // None when the line is unknown (3.12+)
+                *lineno = tb
+                    .getattr("tb_lineno")?
+                    .extract::<Option<i64>>()?
+                    .unwrap_or(0);
+            }
+            frames.push(frame);
+            tb = tb.getattr("tb_next")?;
+        }
+        frames.reverse();
+        Ok(frames)
+    }
+
+    /// Merge a traceback object with a native stack captured while the exception was being
+    /// handled, leaf first. See `merge_traceback_text` for formatted tracebacks.
+    pub fn merge_traceback(
+        traceback: &Bound<'_, PyAny>,
+        native_stacks: Vec<CallFrame>,
+    ) -> PyResult<Vec<CallFrame>> {
+        Ok(Self::merge_python_native_stacks(
+            Self::traceback_frames(traceback)?,
+            native_stacks,
+        ))
+    }
+
+    /// Python stacks of every thread known to `threading`, keyed by native thread id
+    /// (`Thread.native_id`, i.e. the OS tid on Linux).
+    ///
+    /// Threads that never touched the `threading` module are not reported.
+    pub fn capture_python_thread_stacks(
+        py: Python<'_>,
+    ) -> PyResult<HashMap<ThreadId, Vec<CallFrame>>> {
+        let current_frames = py.import("sys")?.call_method0("_current_frames")?;
+        let threads = py.import("threading")?.call_method0("enumerate")?;
+
+        let mut stacks = HashMap::new();
+        for thread in threads.try_iter()? {
+            let thread = thread?;
+            let native_id: Option<ThreadId> = thread.getattr("native_id")?.extract()?;
+            let ident = thread.getattr("ident")?;
+            let (Some(native_id), Ok(frame)) = (native_id, current_frames.get_item(ident)) else {
+                continue;
+            };
+            stacks.insert(native_id, walk_frames(frame, &LocalsPolicy::none())?);
+        }
+
+        Ok(stacks)
+    }
+
+    /// The implementation running the calling code, from `sys.implementation.name`.
+    pub fn python_implementation(py: Python<'_>) -> PyResult<PythonImplementation> {
+        let name: String = py
+            .import("sys")?
+            .getattr("implementation")?
+            .getattr("name")?
+            .extract()?;
+        Ok(PythonImplementation::from_name(&name))
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+impl SignalTracer {
+    /// Merged Python + native stacks of all threads, sorted by tid.
+    ///
+    /// Python stacks are paired with native ones through `Thread.native_id`. The calling
+    /// thread holds the GIL while capturing, so it is reported as the GIL holder, unless a
+    /// free-threaded interpreter runs with the GIL disabled. Under PyPy, native stacks are
+    /// split at PyPy's own boundary frames (see `PythonImplementation`).
+    ///
+    /// Suspended greenlets of the calling thread follow as logical threads with its tid
+    /// and python frames only (see `capture_greenlet_stacks`).
+    pub fn capture_all_mixed_threads(py: Python<'_>) -> PyResult<Vec<ThreadStack>> {
+        let python_stacks = Self::capture_python_thread_stacks(py)?;
+        let tid = crate::threads::current_tid();
+        let gil_holder = gil_enabled(py)?.then_some(tid);
+        let mut stacks = Self::capture_all_threads_with_python_stacks_with_detector(
+            python_stacks,
+            gil_holder,
+            Self::python_implementation(py)?.detector(),
+        )?;
+        stacks.extend(
+            Self::capture_greenlet_stacks(py)?
+                .into_iter()
+                .filter(|greenlet| greenlet.current_thread)
+                .map(|greenlet| ThreadStack {
+                    tid,
+                    name: greenlet.name,
+                    os_state: ThreadState::Sleeping,
+                    is_gil_holder: false,
+                    greenlet: true,
+                    interpreter_id: None,
+                    frames: greenlet.frames,
+                }),
+        );
+        Ok(stacks)
+    }
+
+    /// Provider for `Sampler::start_with_python` that snapshots all Python threads under the
+    /// GIL. The interpreter must already be initialized (e.g. inside an extension module).
+    #[cfg(target_os = "linux")]
+    pub fn python_stacks_provider() -> crate::sampler::PythonStacksProvider {
+        Box::new(|| Python::attach(|py| Self::capture_python_thread_stacks(py).unwrap_or_default()))
+    }
+}
+
+/// `sys._is_gil_enabled()` (3.13+); older interpreters always have the GIL.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn gil_enabled(py: Python<'_>) -> PyResult<bool> {
+    match py.import("sys")?.getattr("_is_gil_enabled") {
+        Ok(enabled) => enabled.call0()?.extract(),
+        Err(_) => Ok(true),
+    }
+}
+
+fn walk_frames<'py>(frame: Bound<'py, PyAny>, policy: &LocalsPolicy) -> PyResult<Vec<CallFrame>> {
+    let mut frames = Vec::new();
+    let mut frame = Some(frame);
+    while let Some(current) = frame {
+        frames.push(to_call_frame(&current, policy)?);
+        let back = current.getattr("f_back")?;
+        frame = if back.is_none() { None } else { Some(back) };
+    }
+    Ok(frames)
+}
+
+pub(crate) fn to_call_frame(
+    frame: &Bound<'_, PyAny>,
+    policy: &LocalsPolicy,
+) -> PyResult<CallFrame> {
+    let code = frame.getattr("f_code")?;
+    let file: String = code.getattr("co_filename")?.extract()?;
+    let func: String = code.getattr("co_name")?.extract()?;
+    // f_lineno may be None while a frame is being set up
+    let lineno: Option<i64> = frame.getattr("f_lineno")?.extract()?;
+
+    let mut captured = HashMap::new();
+    if policy.is_enabled() {
+        let f_locals = frame.getattr("f_locals")?;
+        let mut converter = ValueConverter::new(policy);
+        let mut capture = |name: &str, value: &Bound<'_, PyAny>| {
+            if policy.selects(name) {
+                let value = policy.redacted(name, converter.convert(value));
+                captured.insert(name.to_string(), value);
+            }
+        };
+        match policy.get_names() {
+            Some(names) => {
+                for name in names {
+                    if let Ok(value) = f_locals.get_item(name) {
+                        capture(name, &value);
+                    }
+                }
+            }
+            // `f_locals` is a dict, or a write-through proxy since 3.13; both have items().
+            None => {
+                for item in f_locals.call_method0("items")?.try_iter()? {
+                    let (name, value): (String, Bound<'_, PyAny>) = item?.extract()?;
+                    capture(&name, &value);
+                }
+            }
+        }
+    }
+
+    Ok(CallFrame::PyFrame {
+        // The frame object's address identifies the frame within the process.
+        ip: format!("{:#x}", frame.as_ptr() as usize),
+        file,
+        func,
+        lineno: lineno.unwrap_or(0),
+        locals: captured,
+        module: None,
+        package: None,
+        in_app: false,
+        source: None,
+        frame_address: None,
+    })
+}
+
+fn type_name(obj: &Bound<'_, PyAny>) -> String {
+    obj.get_type()
+        .name()
+        .map(|n| n.to_string())
+        .unwrap_or_else(|_| "object".to_string())
+}
+
+fn repr(obj: &Bound<'_, PyAny>) -> String {
+    obj.repr()
+        .map(|r| r.to_string())
+        .unwrap_or_else(|_| "<unrepresentable>".to_string())
+}
+
+/// Snapshots the locals of one frame under a `LocalsPolicy`, sharing its size budget
+/// across them. Placeholders like `<list of 3>` are never cut.
+struct ValueConverter<'a> {
+    policy: &'a LocalsPolicy,
+    /// Approximate bytes left for this frame's locals.
+    remaining: usize,
+    /// Addresses of the containers being converted, to spot cycles.
+    path: Vec<usize>,
+}
+
+impl<'a> ValueConverter<'a> {
+    fn new(policy: &'a LocalsPolicy) -> Self {
+        ValueConverter {
+            policy,
+            remaining: policy.get_max_size().unwrap_or(usize::MAX),
+            path: Vec::new(),
+        }
+    }
+
+    fn convert(&mut self, obj: &Bound<'_, PyAny>) -> Value {
+        match self.policy.mode() {
+            LocalsMode::None | LocalsMode::NamesOnly => Value::Str(format!("<{}>", type_name(obj))),
+            LocalsMode::Full => self.full(obj, 0),
+            LocalsMode::Shallow => match scalar(obj) {
+                Some(value) => self.policy.truncated(value),
+                None => sized_placeholder(obj),
+            },
+        }
+    }
+
+    fn full(&mut self, obj: &Bound<'_, PyAny>, depth: usize) -> Value {
+        if let Some(value) = scalar(obj) {
+            return self.leaf(value);
+        }
+        let address = obj.as_ptr() as usize;
+        let dict = obj.cast::<PyDict>();
+        let sequence = obj.is_instance_of::<PyList>() || obj.is_instance_of::<PyTuple>();
+        if !sequence && dict.is_err() {
+            let value = match (obj.cast::<PyBytes>(), obj.cast::<PyByteArray>()) {
+                (Ok(bytes), _) => Value::Bytes(self.cut(bytes.as_bytes())),
+                (_, Ok(bytes)) => Value::Bytes(self.cut(&bytes.to_vec())),
+                _ => Value::repr(repr(obj), type_name(obj)),
+            };
+            return self.leaf(value);
+        }
+        // Like Python's own repr of a container that contains itself.
+        if self.path.contains(&address) {
+            let marker = if dict.is_ok() { "{...}" } else { "[...]" };
+            return Value::repr(marker, type_name(obj));
+        }
+        if depth >= self.policy.get_max_depth() || self.remaining == 0 {
+            return sized_placeholder(obj);
+        }
+        self.charge(8);
+
+        self.path.push(address);
+        let value = if let Ok(dict) = dict {
+            let mut entries = HashMap::new();
+            for (i, (key, item)) in dict.iter().enumerate() {
+                if self.remaining == 0 {
+                    let more = format!("<{} more>", dict.len() - i);
+                    entries.insert("...".to_string(), Value::Str(more));
+                    break;
+                }
+                let key = match key.cast::<PyString>() {
+                    Ok(key) => key.to_string(),
+                    Err(_) => repr(&key),
+                };
+                self.charge(key.len());
+                entries.insert(key, self.full(&item, depth + 1));
+            }
+            Value::Dict(entries)
+        } else {
+            let len = obj.len().unwrap_or(0);
+            let mut values = Vec::new();
+            for (i, item) in obj.try_iter().into_iter().flatten().enumerate() {
+                if self.remaining == 0 {
+                    values.push(Value::Str(format!("<{} more>", len - i)));
+                    break;
+                }
+                match item {
+                    Ok(item) => values.push(self.full(&item, depth + 1)),
+                    Err(_) => break,
+                }
+            }
+            Value::List(values)
+        };
+        self.path.pop();
+        value
+    }
+
+    /// Truncate a scalar or repr and charge it to the budget.
+    fn leaf(&mut self, value: Value) -> Value {
+        let value = self.policy.truncated(value);
+        let size = match &value {
+            Value::Str(s) | Value::Repr { repr: s, .. } => s.len(),
+            Value::Bytes(b) => b.len(),
+            _ => 8,
+        };
+        self.charge(size);
+        value
+    }
+
+    fn cut(&self, bytes: &[u8]) -> Vec<u8> {
+        let len = self.policy.get_max_string_len().unwrap_or(usize::MAX);
+        bytes[..bytes.len().min(len)].to_vec()
+    }
+
+    fn charge(&mut self, size: usize) {
+        self.remaining = self.remaining.saturating_sub(size);
+    }
+}
+
+/// `None`, bools, floats, strings and ints that fit in an i64, by value.
+fn scalar(obj: &Bound<'_, PyAny>) -> Option<Value> {
+    if obj.is_none() {
+        Some(Value::None)
+    } else if obj.is_instance_of::<PyBool>() {
+        Some(Value::Bool(obj.is_truthy().unwrap_or(false)))
+    } else if obj.is_instance_of::<PyInt>() {
+        // Ints beyond i64 fall back to their repr
+        obj.extract::<i64>().ok().map(Value::Int)
+    } else if obj.is_instance_of::<PyFloat>() {
+        Some(Value::float(obj.extract::<f64>().unwrap_or(f64::NAN)))
+    } else if obj.is_instance_of::<PyString>() {
+        Some(Value::Str(obj.to_string()))
+    } else {
+        None
+    }
+}
+
+/// `<type of N>` for builtin sized containers, `<type>` otherwise.
+fn sized_placeholder(obj: &Bound<'_, PyAny>) -> Value {
+    // Only builtin containers: len() on other objects could run arbitrary code.
+    let sized = obj.is_instance_of::<PyList>()
+        || obj.is_instance_of::<PyTuple>()
+        || obj.is_instance_of::<PyDict>()
+        || obj.is_instance_of::<PySet>()
+        || obj.is_instance_of::<PyFrozenSet>()
+        || obj.is_instance_of::<PyBytes>();
+    match sized.then(|| obj.len()) {
+        Some(Ok(len)) => Value::Str(format!("<{} of {}>", type_name(obj), len)),
+        _ => Value::Str(format!("<{}>", type_name(obj))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pyo3::types::PyDict;
+
+    #[pyfunction]
+    fn capture(py: Python<'_>) -> PyResult<Vec<(String, i64, Option<String>)>> {
+        let frames = SignalTracer::capture_python_stack_with_locals(py, &["token"])?;
+        Ok(frames
+            .into_iter()
+            .map(|f| match f {
+                CallFrame::PyFrame {
+                    func,
+                    lineno,
+                    locals,
+                    ..
+                } => (
+                    func,
+                    lineno,
+                    locals.get("token").map(|v| format!("{:?}", v)),
+                ),
+                other => (other.func().to_string(), -1, None),
+            })
+            .collect())
+    }
+
+    #[test]
+    fn test_capture_python_stack() {
+        Python::initialize();
+        Python::attach(|py| {
+            let globals = PyDict::new(py);
+            globals
+                .set_item("capture", wrap_pyfunction!(capture, py).unwrap())
+                .unwrap();
+            py.run(
+                c"def inner():\n    token = 42\n    return capture()\ndef outer():\n    return inner()\nresult = outer()\n",
+                Some(&globals),
+                None,
+            )
+            .unwrap();
+
+            let result: Vec<(String, i64, Option<String>)> = globals
+                .get_item("result")
+                .unwrap()
+                .unwrap()
+                .extract()
+                .unwrap();
+            let names: Vec<&str> = result.iter().map(|(f, _, _)| f.as_str()).collect();
+            assert_eq!(names, vec!["inner", "outer", "<module>"]);
+            assert_eq!(result[0].1, 3);
+            assert_eq!(result[0].2.as_deref(), Some("Int(42)"));
+            assert_eq!(result[1].2, None);
+        });
+    }
+
+    #[pyfunction]
+    fn capture_shallow(py: Python<'_>) -> PyResult<Vec<(String, String)>> {
+        let policy = LocalsPolicy::shallow()
+            .matching(regex::Regex::new("^(api_key|items|name|obj)$").unwrap())
+            .max_string_len(4)
+            .redact_secrets();
+        let mut trace = SignalTracer::capture_python_stack_with_policy(py, &policy)?.into_frames();
+        let mut locals: Vec<(String, String)> = match trace.remove(0) {
+            CallFrame::PyFrame { locals, .. } => locals
+                .into_iter()
+                .map(|(k, v)| (k, format!("{:?}", v)))
+                .collect(),
+            _ => Vec::new(),
+        };
+        locals.sort();
+        Ok(locals)
+    }
+
+    #[test]
+    fn test_capture_with_policy() {
+        Python::initialize();
+        Python::attach(|py| {
+            let globals = PyDict::new(py);
+            globals
+                .set_item("capture", wrap_pyfunction!(capture_shallow, py).unwrap())
+                .unwrap();
+            py.run(
+                c"def f():\n    api_key = 'hunter2'\n    items = [1, 2, 3]\n    name = 'abcdefgh'\n    obj = object()\n    other = 1\n    return capture()\nresult = f()\n",
+                Some(&globals),
+                None,
+            )
+            .unwrap();
+
+            let result: Vec<(String, String)> = globals
+                .get_item("result")
+                .unwrap()
+                .unwrap()
+                .extract()
+                .unwrap();
+            let pair = |k: &str, v: &str| (k.to_string(), v.to_string());
+            assert_eq!(
+                result,
+                vec![
+                    pair("api_key", r#"Str("<redacted>")"#),
+                    pair("items", r#"Str("<list of 3>")"#),
+                    pair("name", r#"Str("abcd...")"#),
+                    pair("obj", r#"Str("<object>")"#),
+                ]
+            );
+        });
+    }
+
+    #[pyfunction]
+    fn thread_stack_names(py: Python<'_>) -> PyResult<Vec<String>> {
+        let tid = unsafe { libc::syscall(libc::SYS_gettid) } as i32;
+        let mut stacks = SignalTracer::capture_python_thread_stacks(py)?;
+        Ok(stacks
+            .remove(&tid)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|f| f.func().to_string())
+            .collect())
+    }
+
+    #[test]
+    fn test_capture_python_thread_stacks() {
+        Python::initialize();
+        Python::attach(|py| {
+            let globals = PyDict::new(py);
+            globals
+                .set_item(
+                    "thread_stack_names",
+                    wrap_pyfunction!(thread_stack_names, py).unwrap(),
+                )
+                .unwrap();
+            py.run(
+                c"import threading\nresult = []\ndef worker():\n    result.extend(thread_stack_names())\nt = threading.Thread(target=worker)\nt.start()\nt.join()\n",
+                Some(&globals),
+                None,
+            )
+            .unwrap();
+
+            let result: Vec<String> = globals
+                .get_item("result")
+                .unwrap()
+                .unwrap()
+                .extract()
+                .unwrap();
+            assert_eq!(result.first().map(String::as_str), Some("worker"));
+        });
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[pyfunction]
+    fn mixed_stack_names(py: Python<'_>) -> PyResult<Vec<String>> {
+        let tid = crate::threads::current_tid();
+        let stacks = SignalTracer::capture_all_mixed_threads(py)?;
+        let own = stacks.into_iter().find(|s| s.tid == tid).unwrap();
+        assert!(own.is_gil_holder);
+        Ok(own
+            .frames
+            .into_iter()
+            .filter_map(|f| match f {
+                CallFrame::PyFrame { func, .. } => Some(func),
+                _ => None,
+            })
+            .collect())
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    #[test]
+    fn test_capture_all_mixed_threads() {
+        Python::initialize();
+        Python::attach(|py| {
+            let globals = PyDict::new(py);
+            globals
+                .set_item(
+                    "mixed_stack_names",
+                    wrap_pyfunction!(mixed_stack_names, py).unwrap(),
+                )
+                .unwrap();
+            py.run(
+                c"import threading\nresult = []\ndef worker():\n    result.extend(mixed_stack_names())\nt = threading.Thread(target=worker)\nt.start()\nt.join()\n",
+                Some(&globals),
+                None,
+            )
+            .unwrap();
+
+            let result: Vec<String> = globals
+                .get_item("result")
+                .unwrap()
+                .unwrap()
+                .extract()
+                .unwrap();
+            assert!(result.contains(&"worker".to_string()), "{:?}", result);
+        });
+    }
+
+    #[test]
+    fn test_capture_without_python_frames() {
+        Python::initialize();
+        Python::attach(|py| {
+            let frames = SignalTracer::capture_python_stack(py).unwrap();
+            assert!(frames.is_empty());
+        });
+    }
+
+    #[inline(never)]
+    fn native_trampolines() -> Vec<crate::TrampolineKind> {
+        SignalTracer::capture_native_stack()
+            .into_frames()
+            .iter()
+            .filter_map(crate::TrampolineKind::of)
+            .collect()
+    }
+
+    #[test]
+    fn test_pyo3_trampolines() {
+        Python::initialize();
+        Python::attach(|py| {
+            let globals = PyDict::new(py);
+            let kinds = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+            let sink = kinds.clone();
+            let probe = pyo3::types::PyCFunction::new_closure(py, None, None, move |_, _| {
+                *sink.lock().unwrap() = native_trampolines();
+            })
+            .unwrap();
+            globals.set_item("probe", probe).unwrap();
+            py.run(c"probe()\n", Some(&globals), None).unwrap();
+            // Rust → Python (`Python::run`) → Rust (PyO3's closure trampoline).
+            let kinds = kinds.lock().unwrap();
+            assert!(
+                kinds.contains(&crate::TrampolineKind::PyO3Trampoline),
+                "{:?}",
+                kinds
+            );
+            assert!(
+                kinds.contains(&crate::TrampolineKind::PyO3Call),
+                "{:?}",
+                kinds
+            );
+        });
+    }
+
+    #[test]
+    fn test_python_implementation() {
+        Python::initialize();
+        Python::attach(|py| {
+            assert_eq!(
+                SignalTracer::python_implementation(py).unwrap(),
+                PythonImplementation::CPython
+            );
+        });
+    }
+
+    #[test]
+    fn test_traceback_frames() {
+        Python::initialize();
+        Python::attach(|py| {
+            let globals = PyDict::new(py);
+            py.run(
+                c"def inner():
+    raise ValueError('boom')
+def outer():
+    inner()
+try:
+    outer()
+except ValueError as e:
+    tb = e.__traceback__
+",
+                Some(&globals),
+                None,
+            )
+            .unwrap();
+            let tb = globals.get_item("tb").unwrap().unwrap();
+            let frames = SignalTracer::traceback_frames(&tb).unwrap();
+            let funcs: Vec<(&str, i64)> = frames.iter().map(|f| (f.func(), f.lineno())).collect();
+            assert_eq!(funcs, [("inner", 2), ("outer", 4), ("<module>", 6)]);
+
+            let native = vec![
+                CallFrame::native("0x1", "", "handler", 0),
+                CallFrame::native("0x2", "", "PyEval_EvalFrameDefault", 0),
+            ];
+            let merged = SignalTracer::merge_traceback(&tb, native).unwrap();
+            assert_eq!(merged[0].func(), "handler");
+            assert_eq!(merged[1].func(), "inner");
+            assert_eq!(merged.len(), 4);
+        });
+    }
+
+    #[test]
+    fn test_to_value() {
+        Python::initialize();
+        Python::attach(|py| {
+            let policy = LocalsPolicy::full();
+            let eval = |src: &std::ffi::CStr| {
+                ValueConverter::new(&policy).convert(&py.eval(src, None, None).unwrap())
+            };
+            assert_eq!(eval(c"None"), Value::None);
+            assert_eq!(eval(c"True"), Value::Bool(true));
+            assert_eq!(eval(c"7"), Value::Int(7));
+            assert_eq!(eval(c"2**70"), Value::repr("1180591620717411303424", "int"));
+            assert_eq!(eval(c"1.5"), Value::float(1.5));
+            assert_eq!(eval(c"'hi'"), Value::Str("hi".into()));
+            assert_eq!(
+                eval(c"[1, (b'x',)]"),
+                Value::List(vec![
+                    Value::Int(1),
+                    Value::List(vec![Value::Bytes(b"x".to_vec())])
+                ])
+            );
+            assert_eq!(
+                eval(c"{'a': None, 2: 'b'}"),
+                Value::Dict(HashMap::from([
+                    ("a".to_string(), Value::None),
+                    ("2".to_string(), Value::Str("b".into())),
+                ]))
+            );
+            assert_eq!(eval(c"object"), Value::repr("<class 'object'>", "type"));
+        });
+    }
+
+    #[test]
+    fn test_value_limits() {
+        Python::initialize();
+        Python::attach(|py| {
+            let convert = |policy: LocalsPolicy, src: &std::ffi::CStr| {
+                let globals = PyDict::new(py);
+                py.run(src, Some(&globals), None).unwrap();
+                let value = globals.get_item("v").unwrap().unwrap();
+                ValueConverter::new(&policy).convert(&value)
+            };
+
+            // Nesting beyond the depth limit is kept as a placeholder.
+            let nested = convert(LocalsPolicy::full().max_depth(2), c"v = [[[1, 2]]]");
+            let expected = Value::List(vec![Value::List(vec![Value::Str("<list of 2>".into())])]);
+            assert_eq!(nested, expected);
+
+            let cyclic = convert(LocalsPolicy::full(), c"v = {'self': None}\nv['self'] = [v]");
+            let expected = Value::Dict(HashMap::from([(
+                "self".to_string(),
+                Value::List(vec![Value::repr("{...}", "dict")]),
+            )]));
+            assert_eq!(cyclic, expected);
+
+            // 100 strings of 100 bytes each do not fit in 1000.
+            let capped = convert(
+                LocalsPolicy::full().max_size(1000),
+                c"v = ['x' * 100 for _ in range(100)]",
+            );
+            match capped {
+                Value::List(items) => {
+                    assert_eq!(items.len(), 11);
+                    assert_eq!(items[10], Value::Str("<90 more>".into()));
+                }
+                other => panic!("expected list, got {:?}", other),
+            }
+        });
+    }
+}
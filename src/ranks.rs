@@ -0,0 +1,365 @@
+//! Cross-rank aggregation for distributed jobs: which ranks' stacks differ from the rest?
+//!
+//! Every process captures a `RankTrace`, tagged with its rank from the environment
+//! (`RANK`, `LOCAL_RANK`, `WORLD_SIZE` as set by `torchrun`, or the MPI and Slurm
+//! equivalents) and its hostname, and ships it to one place; a `RankAggregator` groups
+//! identical thread stacks across ranks. In a healthy hang every rank waits in the same
+//! collective, so a stack most ranks share but one lacks, or one only a few ranks have,
+//! points at the rank holding everybody else up.
+//!
+//! ```text
+//! rank 17 (gpu-03) differs from 63 other ranks: 1 stack of its own, in ncclAllReduce
+//! ```
+//!
+//! Stacks are compared like `StackHash` does, ignoring ips.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::stack_hash::StackHash;
+use crate::thread_stack::ThreadStack;
+use crate::CallFrame;
+
+/// Environment variables holding the global rank, most specific first.
+const RANK_ENV: &[&str] = &["RANK", "OMPI_COMM_WORLD_RANK", "PMI_RANK", "SLURM_PROCID"];
+const LOCAL_RANK_ENV: &[&str] = &[
+    "LOCAL_RANK",
+    "OMPI_COMM_WORLD_LOCAL_RANK",
+    "MPI_LOCALRANKID",
+    "SLURM_LOCALID",
+];
+const WORLD_SIZE_ENV: &[&str] = &[
+    "WORLD_SIZE",
+    "OMPI_COMM_WORLD_SIZE",
+    "PMI_SIZE",
+    "SLURM_NTASKS",
+];
+
+/// Where a trace comes from in a distributed job.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct RankInfo {
+    pub rank: Option<u32>,
+    pub local_rank: Option<u32>,
+    pub world_size: Option<u32>,
+    pub hostname: String,
+    pub pid: u32,
+}
+
+impl RankInfo {
+    /// The calling process, with its rank read from the environment.
+    pub fn from_env() -> Self {
+        Self::from_vars(
+            |name| std::env::var(name).ok(),
+            hostname(),
+            std::process::id(),
+        )
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>, hostname: String, pid: u32) -> Self {
+        let first = |names: &[&str]| {
+            names
+                .iter()
+                .find_map(|name| var(name).and_then(|v| v.trim().parse().ok()))
+        };
+        RankInfo {
+            rank: first(RANK_ENV),
+            local_rank: first(LOCAL_RANK_ENV),
+            world_size: first(WORLD_SIZE_ENV),
+            hostname,
+            pid,
+        }
+    }
+}
+
+impl fmt::Display for RankInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.rank {
+            Some(rank) if self.hostname.is_empty() => write!(f, "rank {}", rank),
+            Some(rank) => write!(f, "rank {} ({})", rank, self.hostname),
+            None => write!(f, "pid {} ({})", self.pid, self.hostname),
+        }
+    }
+}
+
+/// `gethostname`, or `HOSTNAME` where there is none.
+fn hostname() -> String {
+    #[cfg(unix)]
+    {
+        let mut buf = [0u8; 256];
+        if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } == 0 {
+            let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+            return String::from_utf8_lossy(&buf[..len]).into_owned();
+        }
+    }
+    std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .unwrap_or_default()
+}
+
+/// The threads of one process at one point, tagged with its rank.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RankTrace {
+    pub info: RankInfo,
+    pub stacks: Vec<ThreadStack>,
+}
+
+impl RankTrace {
+    pub fn new(info: RankInfo, stacks: Vec<ThreadStack>) -> Self {
+        RankTrace { info, stacks }
+    }
+
+    /// Native stacks of all threads of the calling process, tagged from the environment.
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    pub fn capture() -> Result<Self, crate::TracerError> {
+        Ok(Self::new(
+            RankInfo::from_env(),
+            crate::SignalTracer::capture_all_threads()?,
+        ))
+    }
+}
+
+/// A stack and the ranks having at least one thread in it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StackGroup {
+    /// Leaf first.
+    pub frames: Vec<CallFrame>,
+    /// Sorted.
+    pub ranks: Vec<RankInfo>,
+}
+
+/// A rank whose stacks differ from those of the majority.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RankOutlier {
+    pub rank: RankInfo,
+    /// Stacks of this rank that at most a quarter of the ranks have, leaf first.
+    pub unusual: Vec<Vec<CallFrame>>,
+    /// Stacks more than half of the ranks have and this one does not.
+    pub missing: Vec<Vec<CallFrame>>,
+    /// Number of ranks ingested.
+    pub total_ranks: usize,
+}
+
+impl fmt::Display for RankOutlier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} differs from {} other ranks",
+            self.rank,
+            self.total_ranks - 1
+        )?;
+        let mut parts = Vec::new();
+        if !self.unusual.is_empty() {
+            let leaves: Vec<&str> = self.unusual.iter().filter_map(|s| leaf(s)).collect();
+            parts.push(format!(
+                "{} stack{} of its own, in {}",
+                self.unusual.len(),
+                if self.unusual.len() == 1 { "" } else { "s" },
+                leaves.join(", ")
+            ));
+        }
+        if !self.missing.is_empty() {
+            let leaves: Vec<&str> = self.missing.iter().filter_map(|s| leaf(s)).collect();
+            parts.push(format!("not in {}", leaves.join(", ")));
+        }
+        if !parts.is_empty() {
+            write!(f, ": {}", parts.join("; "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Innermost frame of a stack that is not a marker.
+fn leaf(frames: &[CallFrame]) -> Option<&str> {
+    frames
+        .iter()
+        .find(|f| !matches!(f, CallFrame::Synthetic { .. }))
+        .or(frames.first())
+        .map(CallFrame::func)
+}
+
+/// Groups the thread stacks of many ranks; a rank ingested twice keeps its last trace.
+#[derive(Debug, Default)]
+pub struct RankAggregator {
+    traces: BTreeMap<RankInfo, Vec<ThreadStack>>,
+}
+
+impl RankAggregator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, trace: RankTrace) {
+        let stacks = trace.stacks.into_iter().filter(|s| !s.greenlet).collect();
+        self.traces.insert(trace.info, stacks);
+    }
+
+    pub fn ranks(&self) -> impl Iterator<Item = &RankInfo> {
+        self.traces.keys()
+    }
+
+    /// Distinct stacks with the ranks having them, most widespread first.
+    pub fn groups(&self) -> Vec<StackGroup> {
+        let mut groups: HashMap<StackHash, StackGroup> = HashMap::new();
+        for (rank, stacks) in &self.traces {
+            for stack in stacks {
+                let group = groups
+                    .entry(StackHash::of(&stack.frames))
+                    .or_insert_with(|| StackGroup {
+                        frames: stack.frames.clone(),
+                        ranks: Vec::new(),
+                    });
+                // Ranks are visited in order, so a repeat is always the last one pushed.
+                if group.ranks.last() != Some(rank) {
+                    group.ranks.push(rank.clone());
+                }
+            }
+        }
+        let mut groups: Vec<StackGroup> = groups.into_values().collect();
+        groups.sort_by(|a, b| {
+            b.ranks
+                .len()
+                .cmp(&a.ranks.len())
+                .then_with(|| a.ranks.cmp(&b.ranks))
+                .then_with(|| StackHash::of(&a.frames).cmp(&StackHash::of(&b.frames)))
+        });
+        groups
+    }
+
+    /// Ranks lacking a stack most ranks share, or having stacks few others do, in rank
+    /// order. Needs at least three ranks to tell a majority.
+    pub fn outliers(&self) -> Vec<RankOutlier> {
+        let total = self.traces.len();
+        if total < 3 {
+            return Vec::new();
+        }
+        let groups = self.groups();
+        let common: Vec<&StackGroup> = groups
+            .iter()
+            .filter(|g| g.ranks.len() * 2 > total)
+            .collect();
+        let rare: Vec<&StackGroup> = groups
+            .iter()
+            .filter(|g| g.ranks.len() * 4 <= total)
+            .collect();
+
+        self.traces
+            .keys()
+            .filter_map(|rank| {
+                let has = |group: &StackGroup| group.ranks.binary_search(rank).is_ok();
+                let outlier = RankOutlier {
+                    rank: rank.clone(),
+                    unusual: rare
+                        .iter()
+                        .filter(|g| has(g))
+                        .map(|g| g.frames.clone())
+                        .collect(),
+                    missing: common
+                        .iter()
+                        .filter(|g| !has(g))
+                        .map(|g| g.frames.clone())
+                        .collect(),
+                    total_ranks: total,
+                };
+                (!outlier.unusual.is_empty() || !outlier.missing.is_empty()).then_some(outlier)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::thread_stack::ThreadState;
+
+    fn rank(rank: u32) -> RankInfo {
+        RankInfo {
+            rank: Some(rank),
+            hostname: format!("gpu-{:02}", rank / 8),
+            ..RankInfo::default()
+        }
+    }
+
+    fn trace(rank_id: u32, stacks: &[&[&str]]) -> RankTrace {
+        // funcs are given root first for readability
+        let stacks = stacks
+            .iter()
+            .enumerate()
+            .map(|(i, funcs)| ThreadStack {
+                tid: 100 + i as i32,
+                name: String::new(),
+                os_state: ThreadState::Sleeping,
+                is_gil_holder: false,
+                greenlet: false,
+                interpreter_id: None,
+                frames: funcs
+                    .iter()
+                    .rev()
+                    .map(|f| CallFrame::native("0x0", "", *f, 0))
+                    .collect(),
+            })
+            .collect();
+        RankTrace::new(rank(rank_id), stacks)
+    }
+
+    #[test]
+    fn test_rank_info_from_vars() {
+        let vars = |name: &str| match name {
+            "OMPI_COMM_WORLD_RANK" => Some("3".to_string()),
+            "LOCAL_RANK" => Some(" 1 ".to_string()),
+            "WORLD_SIZE" => Some("many".to_string()),
+            _ => None,
+        };
+        let info = RankInfo::from_vars(vars, "gpu-00".to_string(), 42);
+        assert_eq!(
+            (info.rank, info.local_rank, info.world_size),
+            (Some(3), Some(1), None)
+        );
+        assert_eq!(info.to_string(), "rank 3 (gpu-00)");
+        let info = RankInfo::from_vars(|_| None, "gpu-00".to_string(), 42);
+        assert_eq!(info.to_string(), "pid 42 (gpu-00)");
+        assert_eq!(RankInfo::from_env().pid, std::process::id());
+    }
+
+    #[test]
+    fn test_outliers() {
+        let mut aggregator = RankAggregator::new();
+        for id in 0..8 {
+            if id == 5 {
+                continue;
+            }
+            aggregator.add(trace(
+                id,
+                &[&["main", "train", "allreduce"], &["main", "loader"]],
+            ));
+        }
+        aggregator.add(trace(
+            5,
+            &[&["main", "train", "ncclAllReduce"], &["main", "loader"]],
+        ));
+
+        let groups = aggregator.groups();
+        assert_eq!(groups.len(), 3);
+        assert_eq!(groups[0].ranks.len(), 8);
+        assert_eq!(groups[0].frames[0].func(), "loader");
+        assert_eq!(groups[1].ranks.len(), 7);
+
+        let outliers = aggregator.outliers();
+        assert_eq!(outliers.len(), 1);
+        assert_eq!(outliers[0].rank, rank(5));
+        assert_eq!(outliers[0].unusual.len(), 1);
+        assert_eq!(outliers[0].missing[0][0].func(), "allreduce");
+        assert_eq!(
+            outliers[0].to_string(),
+            "rank 5 (gpu-00) differs from 7 other ranks: 1 stack of its own, in ncclAllReduce; \
+             not in allreduce"
+        );
+
+        // Two ranks cannot be told apart.
+        let mut pair = RankAggregator::new();
+        pair.add(trace(0, &[&["a"]]));
+        pair.add(trace(1, &[&["b"]]));
+        assert!(pair.outliers().is_empty());
+    }
+}
@@ -0,0 +1,428 @@
+//! Walking CPython interpreter state in a remote address space, with the structure layout
+//! `cpython_abi` describes for the target's version.
+
+use std::collections::HashMap;
+use std::io;
+
+use super::cpython_abi::{self, FrameModel, GilState, LineTable, PythonOffsets};
+use super::memory::ProcessMemory;
+use crate::thread_stack::ThreadId;
+use crate::CallFrame;
+
+/// Upper bounds that keep a corrupted or racing target from looping forever.
+const MAX_INTERPRETERS: usize = 256;
+const MAX_THREADS: usize = 4096;
+const MAX_FRAMES: usize = 1024;
+const MAX_STRING: u64 = 4096;
+
+/// `owner` of the shim frames 3.12+ pushes when C code calls into Python.
+const FRAME_OWNED_BY_CSTACK: u8 = 3;
+
+/// Offset of `tid` in glibc's `struct pthread`, which a `pthread_t` points to.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const PTHREAD_TID: u64 = 0x2d0;
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+const PTHREAD_TID: u64 = 0xd0;
+
+/// Offsets for the runtime at `runtime`: the target's own `_Py_DebugOffsets` from 3.13 on,
+/// the static table otherwise.
+pub(crate) fn runtime_offsets(
+    memory: &ProcessMemory,
+    version: (u8, u8),
+    runtime: u64,
+) -> Option<PythonOffsets> {
+    if version >= (3, 13) {
+        let described = memory
+            .read_vec(runtime, cpython_abi::DEBUG_OFFSETS_SIZE)
+            .ok()
+            .and_then(|raw| cpython_abi::from_debug_offsets(&raw));
+        if described.is_some() {
+            return described;
+        }
+    }
+    cpython_abi::offsets_for(version)
+}
+
+/// Python stacks of the threads of every interpreter.
+#[derive(Clone, Debug, Default)]
+pub struct PythonThreads {
+    /// Leaf-first frames keyed by native thread id.
+    pub stacks: HashMap<ThreadId, Vec<CallFrame>>,
+    /// Id of the interpreter each thread of `stacks` runs in; 0 is the main one.
+    pub interpreters: HashMap<ThreadId, i64>,
+    pub gil_holder: Option<ThreadId>,
+    /// A free-threaded build running without the GIL: threads neither hold nor wait for it.
+    pub gil_disabled: bool,
+}
+
+pub(crate) fn thread_stacks(
+    memory: &ProcessMemory,
+    offsets: &PythonOffsets,
+    runtime: u64,
+) -> io::Result<PythonThreads> {
+    let mut threads = PythonThreads::default();
+
+    // Newest first: the head of the list is the last interpreter created, the main one
+    // comes last. Subinterpreters may have a GIL of their own (3.12+).
+    let mut interp = memory.read_u64(runtime + offsets.runtime_interpreters_head)?;
+    let mut count = 0;
+    while interp != 0 && count < MAX_INTERPRETERS {
+        let id = memory.read_u64(interp + offsets.interp_id)? as i64;
+        let holder = gil_holder(memory, offsets, runtime, interp)?;
+        threads.gil_disabled = holder.is_none();
+        let mut tstate = memory.read_u64(interp + offsets.interp_threads_head)?;
+        let mut seen = 0;
+        while tstate != 0 && seen < MAX_THREADS {
+            let tid = thread_id(memory, offsets, tstate)?;
+            if Some(tstate) == holder {
+                threads.gil_holder = Some(tid);
+            }
+            let frames = frames(memory, offsets, tstate)?;
+            // A thread enters a subinterpreter from the older interpreter it runs in, so the
+            // frames it has there are further from the leaf.
+            match threads.stacks.get_mut(&tid) {
+                Some(inner) if !frames.is_empty() => inner.extend(frames),
+                Some(_) => {}
+                None => {
+                    threads.interpreters.insert(tid, id);
+                    threads.stacks.insert(tid, frames);
+                }
+            }
+            tstate = memory.read_u64(tstate + offsets.tstate_next)?;
+            seen += 1;
+        }
+        interp = memory.read_u64(interp + offsets.interp_next)?;
+        count += 1;
+    }
+    Ok(threads)
+}
+
+/// Address of the thread state holding the GIL, 0 when none does or it cannot be told,
+/// `None` when the GIL is disabled.
+fn gil_holder(
+    memory: &ProcessMemory,
+    offsets: &PythonOffsets,
+    runtime: u64,
+    interp: u64,
+) -> io::Result<Option<u64>> {
+    match offsets.gil {
+        Some(GilState::TstateCurrent(offset)) => memory.read_u64(runtime + offset).map(Some),
+        Some(GilState::CevalGil {
+            interp_gil,
+            last_holder,
+            locked,
+            enabled,
+        }) => {
+            let gil = memory.read_u64(interp + interp_gil)?;
+            if gil == 0 {
+                return Ok(Some(0));
+            }
+            if let Some(enabled) = enabled {
+                if memory.read_i32(gil + enabled)? == 0 {
+                    return Ok(None);
+                }
+            }
+            if memory.read_i32(gil + locked)? == 0 {
+                return Ok(Some(0));
+            }
+            memory.read_u64(gil + last_holder).map(Some)
+        }
+        None => Ok(Some(0)),
+    }
+}
+
+/// Native id of the thread owning `tstate`. Before 3.11 only the `pthread_t` is recorded.
+fn thread_id(memory: &ProcessMemory, offsets: &PythonOffsets, tstate: u64) -> io::Result<ThreadId> {
+    if let Some(offset) = offsets.tstate_native_thread_id {
+        return Ok(memory.read_u64(tstate + offset)? as ThreadId);
+    }
+    let handle = memory.read_u64(tstate + offsets.tstate_thread_id)?;
+    native_thread_id(memory, handle)
+}
+
+#[cfg(all(
+    target_os = "linux",
+    any(target_arch = "x86_64", target_arch = "aarch64")
+))]
+fn native_thread_id(memory: &ProcessMemory, handle: u64) -> io::Result<ThreadId> {
+    memory.read_i32(handle + PTHREAD_TID)
+}
+
+#[cfg(all(
+    target_os = "linux",
+    not(any(target_arch = "x86_64", target_arch = "aarch64"))
+))]
+fn native_thread_id(_: &ProcessMemory, _: u64) -> io::Result<ThreadId> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "struct pthread layout unknown on this architecture",
+    ))
+}
+
+/// Mach threads report their `pthread_t` as `thread_handle`.
+#[cfg(target_os = "macos")]
+fn native_thread_id(memory: &ProcessMemory, handle: u64) -> io::Result<ThreadId> {
+    crate::mach::task_thread_list(memory.task())?
+        .iter()
+        .find(|thread| thread.handle().ok() == Some(handle))
+        .map_or_else(|| Err(io::ErrorKind::NotFound.into()), |thread| thread.id())
+}
+
+/// `PyThread_get_thread_ident` is `GetCurrentThreadId` on Windows.
+#[cfg(windows)]
+fn native_thread_id(_: &ProcessMemory, id: u64) -> io::Result<ThreadId> {
+    Ok(id as ThreadId)
+}
+
+/// Python frames of one thread state, leaf first.
+fn frames(
+    memory: &ProcessMemory,
+    offsets: &PythonOffsets,
+    tstate: u64,
+) -> io::Result<Vec<CallFrame>> {
+    let mut frame = memory.read_u64(tstate + offsets.tstate_frame)?;
+    if offsets.frame_model == FrameModel::CFrame && frame != 0 {
+        frame = memory.read_u64(frame + offsets.cframe_current_frame)?;
+    }
+    let mut frames = Vec::new();
+    while frame != 0 && frames.len() < MAX_FRAMES {
+        let previous = memory.read_u64(frame + offsets.frame_previous)?;
+        if let Some(owner) = offsets.frame_owner {
+            if memory.read_u8(frame + owner)? == FRAME_OWNED_BY_CSTACK {
+                frame = previous;
+                continue;
+            }
+        }
+        let code = memory.read_u64(frame + offsets.frame_code)?;
+        let file = read_str(
+            memory,
+            offsets,
+            memory.read_u64(code + offsets.code_filename)?,
+        )?;
+        let func = read_str(memory, offsets, memory.read_u64(code + offsets.code_name)?)?;
+        let lineno = line_number(memory, offsets, frame, code)?;
+        frames.push(
+            CallFrame::python(format!("{:#x}", frame), file, func, lineno)
+                .with_frame_address(frame),
+        );
+        frame = previous;
+    }
+    Ok(frames)
+}
+
+/// Decode a compact `str` object; its characters follow the `PyASCIIObject` or
+/// `PyCompactUnicodeObject` header.
+fn read_str(memory: &ProcessMemory, offsets: &PythonOffsets, obj: u64) -> io::Result<String> {
+    let len = memory.read_u64(obj + offsets.str_length)?.min(MAX_STRING);
+    let state = memory.read_u32(obj + offsets.str_state)?;
+    let kind = (state >> 2) & 7;
+    let compact = state & (1 << 5) != 0;
+    let ascii = state & (1 << 6) != 0;
+    if !compact {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "legacy unicode objects are not supported",
+        ));
+    }
+
+    let data = obj
+        + if ascii {
+            offsets.str_ascii_data
+        } else {
+            offsets.str_compact_data
+        };
+    let bytes = memory.read_vec(data, (len * kind as u64) as usize)?;
+    Ok(match kind {
+        1 => bytes.iter().map(|b| *b as char).collect(),
+        2 => bytes
+            .chunks_exact(2)
+            .map(|c| u16::from_ne_bytes([c[0], c[1]]) as u32)
+            .filter_map(char::from_u32)
+            .collect(),
+        _ => bytes
+            .chunks_exact(4)
+            .map(|c| u32::from_ne_bytes([c[0], c[1], c[2], c[3]]))
+            .filter_map(char::from_u32)
+            .collect(),
+    })
+}
+
+fn line_number(
+    memory: &ProcessMemory,
+    offsets: &PythonOffsets,
+    frame: u64,
+    code: u64,
+) -> io::Result<i64> {
+    let first_line = memory.read_i32(code + offsets.code_firstlineno)? as i64;
+    let table = memory.read_u64(code + offsets.code_linetable)?;
+    let len = memory.read_u64(table + offsets.bytes_size)?.min(1 << 20) as usize;
+    let table = memory.read_vec(table + offsets.bytes_data, len)?;
+    Ok(match offsets.frame_model {
+        FrameModel::FrameObject => {
+            let lasti = memory.read_i32(frame + offsets.frame_instr)?.max(0) as u64;
+            match offsets.line_table {
+                LineTable::Lnotab => decode_lnotab(&table, first_line, lasti),
+                // 3.10 counts `f_lasti` in code units, its table in bytes.
+                _ => decode_linetable(&table, first_line, lasti * 2),
+            }
+        }
+        FrameModel::CFrame | FrameModel::Direct => {
+            let instr = memory.read_u64(frame + offsets.frame_instr)?;
+            let first_instr = code + offsets.code_code_adaptive;
+            // prev_instr sits one code unit before the first instruction in a fresh frame.
+            let unit = (instr.wrapping_sub(first_instr) as i64) / 2;
+            decode_location_table(&table, first_line, unit.max(0) as u64)
+        }
+    })
+}
+
+/// Line of byte offset `lasti` from a 3.8/3.9 `co_lnotab`.
+pub(crate) fn decode_lnotab(table: &[u8], first_line: i64, lasti: u64) -> i64 {
+    let mut line = first_line;
+    let mut addr = 0u64;
+    for pair in table.chunks_exact(2) {
+        addr += pair[0] as u64;
+        if addr > lasti {
+            break;
+        }
+        line += pair[1] as i8 as i64;
+    }
+    line
+}
+
+/// Line of byte offset `addr` from a 3.10 `co_linetable`.
+pub(crate) fn decode_linetable(table: &[u8], first_line: i64, addr: u64) -> i64 {
+    let mut line = first_line;
+    let mut end = 0u64;
+    for pair in table.chunks_exact(2) {
+        let start = end;
+        end += pair[0] as u64;
+        let delta = pair[1] as i8;
+        // -128: the range has no line; keep the last one.
+        if delta != -128 {
+            line += delta as i64;
+        }
+        if start <= addr && addr < end {
+            break;
+        }
+    }
+    line
+}
+
+/// Line of code unit `unit` from a 3.11+ `co_linetable` (see `Objects/locations.md`).
+pub(crate) fn decode_location_table(table: &[u8], first_line: i64, unit: u64) -> i64 {
+    fn varint(table: &[u8], pos: &mut usize) -> u64 {
+        let mut value = 0u64;
+        let mut shift = 0;
+        while let Some(b) = table.get(*pos) {
+            *pos += 1;
+            value |= ((b & 63) as u64) << shift;
+            shift += 6;
+            if b & 64 == 0 {
+                break;
+            }
+        }
+        value
+    }
+    fn svarint(table: &[u8], pos: &mut usize) -> i64 {
+        let v = varint(table, pos);
+        if v & 1 != 0 {
+            -((v >> 1) as i64)
+        } else {
+            (v >> 1) as i64
+        }
+    }
+
+    let mut line = first_line;
+    let mut addr = 0u64;
+    let mut pos = 0;
+    while let Some(first) = table.get(pos) {
+        pos += 1;
+        let code = (first >> 3) & 15;
+        let length = (first & 7) as u64 + 1;
+        match code {
+            // No location.
+            15 => {}
+            14 => {
+                line += svarint(table, &mut pos);
+                for _ in 0..3 {
+                    varint(table, &mut pos);
+                }
+            }
+            13 => line += svarint(table, &mut pos),
+            10..=12 => {
+                line += (code - 10) as i64;
+                pos += 2;
+            }
+            // Short forms: same line, one column byte.
+            _ => pos += 1,
+        }
+        if unit < addr + length {
+            return line;
+        }
+        addr += length;
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_location_table() {
+        // Entries: 2 units on line+1 (one-line form), 3 units line+2 (long form with
+        // svarint 4 = +2), then 1 unit without location.
+        let table = [
+            0x80 | (11 << 3) | 1,
+            0,
+            0,
+            0x80 | (14 << 3) | 2,
+            4,
+            0,
+            0,
+            0,
+            0x80 | (15 << 3),
+        ];
+        assert_eq!(decode_location_table(&table, 10, 0), 11);
+        assert_eq!(decode_location_table(&table, 10, 1), 11);
+        assert_eq!(decode_location_table(&table, 10, 2), 13);
+        assert_eq!(decode_location_table(&table, 10, 4), 13);
+        assert_eq!(decode_location_table(&table, 10, 5), 13);
+        // Past the end: last known line.
+        assert_eq!(decode_location_table(&table, 10, 100), 13);
+    }
+
+    #[cfg(all(
+        target_os = "linux",
+        any(target_arch = "x86_64", target_arch = "aarch64")
+    ))]
+    #[test]
+    fn test_native_thread_id() {
+        let memory = ProcessMemory::new(unsafe { libc::getpid() });
+        let handle = unsafe { libc::pthread_self() } as u64;
+        assert_eq!(
+            native_thread_id(&memory, handle).unwrap(),
+            crate::threads::current_tid()
+        );
+    }
+
+    #[test]
+    fn test_decode_lnotab() {
+        // Bytes 0..6 on the first line, 6..10 two lines down, 10.. one line back up.
+        let table = [6, 2, 4, (-1i8) as u8];
+        assert_eq!(decode_lnotab(&table, 5, 0), 5);
+        assert_eq!(decode_lnotab(&table, 5, 5), 5);
+        assert_eq!(decode_lnotab(&table, 5, 6), 7);
+        assert_eq!(decode_lnotab(&table, 5, 12), 6);
+    }
+
+    #[test]
+    fn test_decode_linetable() {
+        // [0, 4) line+1, [4, 6) without a line, [6, 10) line+2.
+        let table = [4, 1, 2, 0x80, 4, 2];
+        assert_eq!(decode_linetable(&table, 10, 0), 11);
+        assert_eq!(decode_linetable(&table, 10, 4), 11);
+        assert_eq!(decode_linetable(&table, 10, 8), 13);
+    }
+}
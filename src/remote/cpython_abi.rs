@@ -0,0 +1,445 @@
+//! Layout of CPython's interpreter structures, per minor version (3.8–3.13, 64-bit).
+//!
+//! Only the fields needed to list thread states, walk their frames and decode line numbers
+//! are described. Three frame layouts exist: `PyFrameObject` chains up to 3.10, the
+//! `_PyInterpreterFrame` reached through `tstate->cframe` in 3.11 and 3.12, and the one
+//! `tstate->current_frame` points to directly from 3.13 on. Everything sits at the same
+//! offset on all 64-bit platforms except `_PyRuntime.gilstate`, which follows a few
+//! platform mutexes.
+//!
+//! From 3.13 on `_PyRuntime` starts with `_Py_DebugOffsets`, the interpreter's own
+//! description of these layouts. It is preferred over the static table because it also
+//! covers free-threaded (`Py_GIL_DISABLED`) builds, whose larger object header moves every
+//! field of code and string objects.
+
+/// How a thread state reaches its innermost frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameModel {
+    /// `tstate->frame` is a `PyFrameObject`, linked through `f_back` (3.8–3.10).
+    FrameObject,
+    /// `tstate->cframe->current_frame` is a `_PyInterpreterFrame` (3.11, 3.12).
+    CFrame,
+    /// `tstate->current_frame` is a `_PyInterpreterFrame` (3.13).
+    Direct,
+}
+
+/// Encoding of `co_lnotab` / `co_linetable`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LineTable {
+    /// `(byte delta, line delta)` pairs, looked up by byte offset (3.8, 3.9).
+    Lnotab,
+    /// 3.10 `co_linetable`: like `Lnotab`, but a line delta of -128 means no line.
+    Compact,
+    /// 3.11+ location table, looked up by code unit (see `Objects/locations.md`).
+    Locations,
+}
+
+/// Where the thread state holding the GIL is recorded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GilState {
+    /// `_PyRuntime.gilstate.tstate_current`, at this offset (3.8–3.11).
+    TstateCurrent(u64),
+    /// `interp->ceval.gil` points to a `_gil_runtime_state` whose `last_holder` is only
+    /// meaningful while `locked` is set (3.12+). Free-threaded builds also have an
+    /// `enabled` flag there; the GIL is off unless an extension or `PYTHON_GIL=1` asks.
+    CevalGil {
+        interp_gil: u64,
+        last_holder: u64,
+        locked: u64,
+        enabled: Option<u64>,
+    },
+}
+
+/// Byte offsets into CPython's internal structures for one interpreter version.
+#[derive(Clone, Copy, Debug)]
+pub struct PythonOffsets {
+    pub version: (u8, u8),
+    /// Built with `Py_GIL_DISABLED` (`python3.13t`).
+    pub free_threaded: bool,
+    pub runtime_interpreters_head: u64,
+    /// `None` where the `_PyRuntime` layout of this platform is not known.
+    pub gil: Option<GilState>,
+    pub interp_next: u64,
+    /// `id` (an `int64_t`); the main interpreter is 0.
+    pub interp_id: u64,
+    pub interp_threads_head: u64,
+    pub tstate_next: u64,
+    /// `thread_id`: the `pthread_t` of the thread (its native id on Windows).
+    pub tstate_thread_id: u64,
+    /// `native_thread_id`, recorded from 3.11 on.
+    pub tstate_native_thread_id: Option<u64>,
+    pub frame_model: FrameModel,
+    /// `frame`, `cframe` or `current_frame`, depending on `frame_model`.
+    pub tstate_frame: u64,
+    pub cframe_current_frame: u64,
+    /// `f_code`, or `f_executable` in 3.13.
+    pub frame_code: u64,
+    /// `f_back`, or `previous` for interpreter frames.
+    pub frame_previous: u64,
+    /// `f_lasti` (an `int`), `prev_instr` (3.11, 3.12) or `instr_ptr` (3.13).
+    pub frame_instr: u64,
+    /// `owner` byte of interpreter frames, from 3.12 on where C-stack shim frames exist.
+    pub frame_owner: Option<u64>,
+    pub code_filename: u64,
+    pub code_name: u64,
+    pub code_firstlineno: u64,
+    /// `co_lnotab` before 3.10, `co_linetable` after.
+    pub code_linetable: u64,
+    /// First instruction, inline in the code object from 3.11 on.
+    pub code_code_adaptive: u64,
+    pub line_table: LineTable,
+    pub str_length: u64,
+    pub str_state: u64,
+    /// Start of the characters of compact ASCII and other compact `str` objects. The
+    /// headers shrank when `wstr` went away in 3.12.
+    pub str_ascii_data: u64,
+    pub str_compact_data: u64,
+    pub bytes_size: u64,
+    pub bytes_data: u64,
+}
+
+/// `_PyRuntime.gilstate.tstate_current` for 3.8, 3.9, 3.10 and 3.11.
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+const TSTATE_CURRENT: Option<[u64; 4]> = Some([1368, 568, 568, 576]);
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+const TSTATE_CURRENT: Option<[u64; 4]> = Some([1384, 584, 584, 592]);
+#[cfg(all(
+    target_os = "linux",
+    not(any(target_arch = "x86_64", target_arch = "aarch64"))
+))]
+const TSTATE_CURRENT: Option<[u64; 4]> = None;
+#[cfg(target_os = "macos")]
+const TSTATE_CURRENT: Option<[u64; 4]> = Some([1416, 616, 616, 624]);
+#[cfg(windows)]
+const TSTATE_CURRENT: Option<[u64; 4]> = Some([1296, 496, 496, 504]);
+
+const fn tstate_current(index: usize) -> Option<GilState> {
+    match TSTATE_CURRENT {
+        Some(offsets) => Some(GilState::TstateCurrent(offsets[index])),
+        None => None,
+    }
+}
+
+pub const PYTHON_3_8: PythonOffsets = PythonOffsets {
+    version: (3, 8),
+    free_threaded: false,
+    runtime_interpreters_head: 32,
+    gil: tstate_current(0),
+    interp_next: 0,
+    interp_id: 16,
+    interp_threads_head: 8,
+    tstate_next: 8,
+    tstate_thread_id: 176,
+    tstate_native_thread_id: None,
+    frame_model: FrameModel::FrameObject,
+    tstate_frame: 24,
+    cframe_current_frame: 0,
+    frame_code: 32,
+    frame_previous: 24,
+    frame_instr: 104,
+    frame_owner: None,
+    code_filename: 104,
+    code_name: 112,
+    code_firstlineno: 40,
+    code_linetable: 120,
+    code_code_adaptive: 0,
+    line_table: LineTable::Lnotab,
+    str_length: 16,
+    str_state: 32,
+    str_ascii_data: 48,
+    str_compact_data: 72,
+    bytes_size: 16,
+    bytes_data: 32,
+};
+
+pub const PYTHON_3_9: PythonOffsets = PythonOffsets {
+    version: (3, 9),
+    gil: tstate_current(1),
+    interp_id: 24,
+    ..PYTHON_3_8
+};
+
+pub const PYTHON_3_10: PythonOffsets = PythonOffsets {
+    version: (3, 10),
+    gil: tstate_current(2),
+    interp_id: 24,
+    frame_instr: 96,
+    line_table: LineTable::Compact,
+    ..PYTHON_3_8
+};
+
+pub const PYTHON_3_11: PythonOffsets = PythonOffsets {
+    version: (3, 11),
+    free_threaded: false,
+    runtime_interpreters_head: 40,
+    gil: tstate_current(3),
+    interp_next: 0,
+    interp_id: 48,
+    interp_threads_head: 16,
+    tstate_next: 8,
+    tstate_thread_id: 152,
+    tstate_native_thread_id: Some(160),
+    frame_model: FrameModel::CFrame,
+    tstate_frame: 56,
+    cframe_current_frame: 8,
+    frame_code: 32,
+    frame_previous: 48,
+    frame_instr: 56,
+    frame_owner: None,
+    code_filename: 112,
+    code_name: 120,
+    code_firstlineno: 72,
+    code_linetable: 136,
+    code_code_adaptive: 184,
+    line_table: LineTable::Locations,
+    str_length: 16,
+    str_state: 32,
+    str_ascii_data: 48,
+    str_compact_data: 72,
+    bytes_size: 16,
+    bytes_data: 32,
+};
+
+pub const PYTHON_3_12: PythonOffsets = PythonOffsets {
+    version: (3, 12),
+    free_threaded: false,
+    runtime_interpreters_head: 40,
+    gil: Some(GilState::CevalGil {
+        interp_gil: 384,
+        last_holder: 8,
+        locked: 16,
+        enabled: None,
+    }),
+    interp_next: 0,
+    interp_id: 8,
+    interp_threads_head: 72,
+    tstate_next: 8,
+    tstate_thread_id: 136,
+    tstate_native_thread_id: Some(144),
+    frame_model: FrameModel::CFrame,
+    tstate_frame: 56,
+    cframe_current_frame: 0,
+    frame_code: 0,
+    frame_previous: 8,
+    frame_instr: 56,
+    frame_owner: Some(70),
+    code_filename: 112,
+    code_name: 120,
+    code_firstlineno: 68,
+    code_linetable: 136,
+    code_code_adaptive: 192,
+    line_table: LineTable::Locations,
+    str_length: 16,
+    str_state: 32,
+    str_ascii_data: 40,
+    str_compact_data: 56,
+    bytes_size: 16,
+    bytes_data: 32,
+};
+
+pub const PYTHON_3_13: PythonOffsets = PythonOffsets {
+    version: (3, 13),
+    free_threaded: false,
+    // `_PyRuntime` starts with the `_Py_DebugOffsets` table from 3.13 on.
+    runtime_interpreters_head: 632,
+    gil: Some(GilState::CevalGil {
+        interp_gil: 16,
+        last_holder: 8,
+        locked: 16,
+        enabled: None,
+    }),
+    interp_next: 7264,
+    interp_id: 7272,
+    interp_threads_head: 7344,
+    tstate_next: 8,
+    tstate_thread_id: 152,
+    tstate_native_thread_id: Some(160),
+    frame_model: FrameModel::Direct,
+    tstate_frame: 72,
+    cframe_current_frame: 0,
+    frame_code: 0,
+    frame_previous: 8,
+    frame_instr: 56,
+    frame_owner: Some(70),
+    code_filename: 112,
+    code_name: 120,
+    code_firstlineno: 68,
+    code_linetable: 136,
+    code_code_adaptive: 200,
+    line_table: LineTable::Locations,
+    str_length: 16,
+    str_state: 32,
+    str_ascii_data: 40,
+    str_compact_data: 56,
+    bytes_size: 16,
+    bytes_data: 32,
+};
+
+/// Offsets for a CPython `major.minor`, if that version is supported.
+pub fn offsets_for(version: (u8, u8)) -> Option<PythonOffsets> {
+    match version {
+        (3, 8) => Some(PYTHON_3_8),
+        (3, 9) => Some(PYTHON_3_9),
+        (3, 10) => Some(PYTHON_3_10),
+        (3, 11) => Some(PYTHON_3_11),
+        (3, 12) => Some(PYTHON_3_12),
+        (3, 13) => Some(PYTHON_3_13),
+        _ => None,
+    }
+}
+
+/// Size of the 3.13 `_Py_DebugOffsets` table.
+pub const DEBUG_OFFSETS_SIZE: usize = 584;
+
+/// Offsets from the `_Py_DebugOffsets` table at the start of a 3.13 `_PyRuntime`, or
+/// `None` if `raw` is not one.
+pub fn from_debug_offsets(raw: &[u8]) -> Option<PythonOffsets> {
+    let field = |at: usize| Some(u64::from_ne_bytes(raw.get(at..at + 8)?.try_into().ok()?));
+    if raw.get(..8)? != b"xdebugpy" {
+        return None;
+    }
+    let hex = field(8)?;
+    let version = ((hex >> 24) as u8, (hex >> 16) as u8);
+    if version != (3, 13) {
+        return None;
+    }
+    let free_threaded = field(16)? != 0;
+    // Relative to the interpreter's own `_gil`, which `ceval.gil` points to.
+    let gil = field(120)?;
+    let ascii_size = field(560)?;
+    Some(PythonOffsets {
+        version,
+        free_threaded,
+        runtime_interpreters_head: field(40)?,
+        gil: Some(GilState::CevalGil {
+            interp_gil: field(112)?,
+            last_holder: field(144)?.checked_sub(gil)?,
+            locked: field(136)?.checked_sub(gil)?,
+            enabled: match free_threaded {
+                true => Some(field(128)?.checked_sub(gil)?),
+                false => None,
+            },
+        }),
+        interp_next: field(64)?,
+        interp_id: field(56)?,
+        interp_threads_head: field(72)?,
+        tstate_next: field(168)?,
+        tstate_thread_id: field(192)?,
+        tstate_native_thread_id: Some(field(200)?),
+        frame_model: FrameModel::Direct,
+        tstate_frame: field(184)?,
+        cframe_current_frame: 0,
+        frame_code: field(240)?,
+        frame_previous: field(232)?,
+        frame_instr: field(248)?,
+        frame_owner: Some(field(264)?),
+        code_filename: field(280)?,
+        code_name: field(288)?,
+        code_firstlineno: field(312)?,
+        code_linetable: field(304)?,
+        code_code_adaptive: field(344)?,
+        line_table: LineTable::Locations,
+        str_length: field(552)?,
+        str_state: field(544)?,
+        str_ascii_data: ascii_size,
+        // `utf8_length` and `utf8` follow the ASCII header.
+        str_compact_data: ascii_size + 16,
+        bytes_size: field(520)?,
+        bytes_data: field(528)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_offsets_for() {
+        for minor in 8..=13 {
+            assert_eq!(offsets_for((3, minor)).unwrap().version, (3, minor));
+        }
+        assert!(offsets_for((3, 7)).is_none());
+        assert!(offsets_for((3, 14)).is_none());
+        assert!(offsets_for((2, 7)).is_none());
+    }
+
+    #[test]
+    fn test_frame_models() {
+        assert_eq!(PYTHON_3_10.frame_model, FrameModel::FrameObject);
+        assert_eq!(PYTHON_3_10.line_table, LineTable::Compact);
+        assert_eq!(PYTHON_3_12.frame_model, FrameModel::CFrame);
+        assert_eq!(PYTHON_3_13.frame_model, FrameModel::Direct);
+        // Only 3.11+ records the native thread id itself.
+        assert!(PYTHON_3_10.tstate_native_thread_id.is_none());
+        assert!(PYTHON_3_11.tstate_native_thread_id.is_some());
+        assert!(matches!(PYTHON_3_12.gil, Some(GilState::CevalGil { .. })));
+    }
+
+    /// A `_Py_DebugOffsets` table as a free-threaded 3.13.1 stores it.
+    fn debug_offsets(free_threaded: bool) -> Vec<u8> {
+        let mut raw = vec![0u8; DEBUG_OFFSETS_SIZE];
+        let mut set = |at: usize, value: u64| raw[at..at + 8].copy_from_slice(&value.to_ne_bytes());
+        set(8, 0x030d01f0);
+        set(16, free_threaded as u64);
+        set(40, 632);
+        set(56, 7272);
+        set(64, 7264);
+        set(72, 7344);
+        set(112, 16);
+        set(120, 4096);
+        set(128, 4096 + 40);
+        set(136, 4096 + 16);
+        set(144, 4096 + 8);
+        set(168, 8);
+        set(184, 72);
+        set(192, 152);
+        set(200, 160);
+        set(232, 8);
+        set(240, 0);
+        set(248, 56);
+        set(264, 70);
+        set(280, 128);
+        set(288, 136);
+        set(304, 152);
+        set(312, 84);
+        set(344, 216);
+        set(520, 32);
+        set(528, 48);
+        set(544, 48);
+        set(552, 32);
+        set(560, 56);
+        raw[..8].copy_from_slice(b"xdebugpy");
+        raw
+    }
+
+    #[test]
+    fn test_from_debug_offsets() {
+        let offsets = from_debug_offsets(&debug_offsets(true)).unwrap();
+        assert_eq!(offsets.version, (3, 13));
+        assert!(offsets.free_threaded);
+        assert_eq!(offsets.code_filename, 128);
+        assert_eq!(offsets.interp_id, 7272);
+        assert_eq!(offsets.str_ascii_data, 56);
+        assert_eq!(offsets.str_compact_data, 72);
+        assert_eq!(
+            offsets.gil,
+            Some(GilState::CevalGil {
+                interp_gil: 16,
+                last_holder: 8,
+                locked: 16,
+                enabled: Some(40),
+            })
+        );
+        let default = from_debug_offsets(&debug_offsets(false)).unwrap();
+        assert!(!default.free_threaded);
+        assert!(matches!(
+            default.gil,
+            Some(GilState::CevalGil { enabled: None, .. })
+        ));
+
+        let mut corrupt = debug_offsets(true);
+        corrupt[0] = b'y';
+        assert!(from_debug_offsets(&corrupt).is_none());
+        assert!(from_debug_offsets(&corrupt[..100]).is_none());
+    }
+}
@@ -0,0 +1,529 @@
+//! eBPF sampling backend (feature `ebpf`): native stacks of a target collected in the
+//! kernel, without stopping its threads.
+//!
+//! A CPU-clock perf event is opened on every CPU with a small BPF program attached. On
+//! each tick that lands in the target, the program records the user stack in a
+//! `BPF_MAP_TYPE_STACK_TRACE` map (frame-pointer unwinding done by the kernel) and bumps
+//! a per-(thread, stack) counter. `RemoteProcess::record_ebpf` drains the counters
+//! periodically, symbolizes the stacks and merges them with a snapshot of the Python
+//! stacks taken at the same drain.
+//!
+//! Needs `CAP_BPF` + `CAP_PERFMON` (or root). Binaries built without frame pointers yield
+//! truncated stacks, as with `perf record -g`.
+
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::RemoteProcess;
+use crate::profile::{Profile, StackAggregator};
+use crate::stack_tracer::SignalTracer;
+use crate::thread_stack::ThreadId;
+use crate::CallFrame;
+
+/// Frames kept per stack (the kernel's `PERF_MAX_STACK_DEPTH` default).
+const MAX_DEPTH: usize = 127;
+const MAX_STACKS: u32 = 16 * 1024;
+const DRAIN_INTERVAL: Duration = Duration::from_millis(50);
+
+const BPF_MAP_CREATE: libc::c_int = 0;
+const BPF_MAP_LOOKUP_ELEM: libc::c_int = 1;
+const BPF_MAP_DELETE_ELEM: libc::c_int = 3;
+const BPF_MAP_GET_NEXT_KEY: libc::c_int = 4;
+const BPF_PROG_LOAD: libc::c_int = 5;
+
+const BPF_MAP_TYPE_HASH: u32 = 1;
+const BPF_MAP_TYPE_STACK_TRACE: u32 = 7;
+const BPF_PROG_TYPE_PERF_EVENT: u32 = 7;
+const BPF_F_USER_STACK: i32 = 1 << 8;
+const BPF_NOEXIST: i32 = 1;
+
+const BPF_FUNC_MAP_LOOKUP_ELEM: i32 = 1;
+const BPF_FUNC_MAP_UPDATE_ELEM: i32 = 2;
+const BPF_FUNC_GET_CURRENT_PID_TGID: i32 = 14;
+const BPF_FUNC_GET_STACKID: i32 = 27;
+
+const PERF_TYPE_SOFTWARE: u32 = 1;
+const PERF_COUNT_SW_CPU_CLOCK: u64 = 0;
+const PERF_ATTR_FLAG_FREQ: u64 = 1 << 10;
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 1 << 3;
+const PERF_EVENT_IOC_ENABLE: libc::c_ulong = 0x2400;
+const PERF_EVENT_IOC_SET_BPF: libc::c_ulong = 0x4004_2408;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct BpfInsn {
+    code: u8,
+    /// dst register in the low nibble, src in the high one.
+    regs: u8,
+    off: i16,
+    imm: i32,
+}
+
+impl BpfInsn {
+    const fn new(code: u8, dst: u8, src: u8, off: i16, imm: i32) -> Self {
+        BpfInsn {
+            code,
+            regs: (src << 4) | dst,
+            off,
+            imm,
+        }
+    }
+}
+
+/// Key of the counts map: which thread was running which stack.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+struct CountKey {
+    /// `tgid << 32 | tid`, as returned by `bpf_get_current_pid_tgid`.
+    pid_tgid: u64,
+    /// Id in the stack map, negative when the kernel failed to record the stack.
+    stack_id: i64,
+}
+
+/// The perf event program: filter on `tgid`, then `counts[{pid_tgid, stackid}] += 1`.
+fn sampling_program(tgid: u32, stacks_fd: i32, counts_fd: i32) -> Vec<BpfInsn> {
+    // Opcodes (class | op | source).
+    const MOV64_REG: u8 = 0xbf;
+    const MOV64_IMM: u8 = 0xb7;
+    const RSH64_IMM: u8 = 0x77;
+    const ADD64_IMM: u8 = 0x07;
+    const LD_IMM64: u8 = 0x18;
+    const STX_DW: u8 = 0x7b;
+    const ATOMIC_ADD_DW: u8 = 0xdb;
+    const JNE_IMM: u8 = 0x55;
+    const JEQ_IMM: u8 = 0x15;
+    const JA: u8 = 0x05;
+    const CALL: u8 = 0x85;
+    const EXIT: u8 = 0x95;
+    const PSEUDO_MAP_FD: u8 = 1;
+    let insn = BpfInsn::new;
+    let map = |dst: u8, fd: i32| {
+        [
+            insn(LD_IMM64, dst, PSEUDO_MAP_FD, 0, fd),
+            BpfInsn::default(),
+        ]
+    };
+
+    let mut program = vec![
+        insn(MOV64_REG, 6, 1, 0, 0), // r6 = ctx
+        insn(CALL, 0, 0, 0, BPF_FUNC_GET_CURRENT_PID_TGID),
+        insn(MOV64_REG, 7, 0, 0, 0),
+        insn(RSH64_IMM, 7, 0, 0, 32),
+        insn(JNE_IMM, 7, 0, 26, tgid as i32), // not the target: exit
+        insn(STX_DW, 10, 0, -16, 0),          // key.pid_tgid
+        insn(MOV64_REG, 1, 6, 0, 0),
+    ];
+    program.extend(map(2, stacks_fd));
+    program.extend([
+        insn(MOV64_IMM, 3, 0, 0, BPF_F_USER_STACK),
+        insn(CALL, 0, 0, 0, BPF_FUNC_GET_STACKID),
+        insn(STX_DW, 10, 0, -8, 0), // key.stack_id
+    ]);
+    program.extend(map(1, counts_fd));
+    program.extend([
+        insn(MOV64_REG, 2, 10, 0, 0),
+        insn(ADD64_IMM, 2, 0, 0, -16),
+        insn(CALL, 0, 0, 0, BPF_FUNC_MAP_LOOKUP_ELEM),
+        insn(JEQ_IMM, 0, 0, 3, 0), // new key: insert
+        insn(MOV64_IMM, 1, 0, 0, 1),
+        insn(ATOMIC_ADD_DW, 0, 1, 0, 0),
+        insn(JA, 0, 0, 10, 0), // exit
+        insn(MOV64_IMM, 1, 0, 0, 1),
+        insn(STX_DW, 10, 1, -24, 0), // value = 1
+    ]);
+    program.extend(map(1, counts_fd));
+    program.extend([
+        insn(MOV64_REG, 2, 10, 0, 0),
+        insn(ADD64_IMM, 2, 0, 0, -16),
+        insn(MOV64_REG, 3, 10, 0, 0),
+        insn(ADD64_IMM, 3, 0, 0, -24),
+        insn(MOV64_IMM, 4, 0, 0, BPF_NOEXIST),
+        insn(CALL, 0, 0, 0, BPF_FUNC_MAP_UPDATE_ELEM),
+        insn(MOV64_IMM, 0, 0, 0, 0),
+        insn(EXIT, 0, 0, 0, 0),
+    ]);
+    program
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct MapCreateAttr {
+    map_type: u32,
+    key_size: u32,
+    value_size: u32,
+    max_entries: u32,
+    map_flags: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct MapElemAttr {
+    map_fd: u32,
+    _pad: u32,
+    key: u64,
+    value: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct ProgLoadAttr {
+    prog_type: u32,
+    insn_cnt: u32,
+    insns: u64,
+    license: u64,
+    log_level: u32,
+    log_size: u32,
+    log_buf: u64,
+    kern_version: u32,
+    prog_flags: u32,
+}
+
+/// `struct perf_event_attr` up to `sample_max_stack` (`PERF_ATTR_SIZE_VER5`).
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    kind: u32,
+    size: u32,
+    config: u64,
+    sample_freq: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+    config2: u64,
+    branch_sample_type: u64,
+    sample_regs_user: u64,
+    sample_stack_user: u32,
+    clockid: i32,
+    sample_regs_intr: u64,
+    aux_watermark: u32,
+    sample_max_stack: u16,
+    _reserved: u16,
+}
+
+fn bpf<T>(cmd: libc::c_int, attr: &mut T) -> io::Result<libc::c_long> {
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_bpf,
+            cmd,
+            attr as *mut T,
+            mem::size_of::<T>() as libc::c_uint,
+        )
+    };
+    if ret < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+fn owned_fd(fd: libc::c_long) -> OwnedFd {
+    unsafe { OwnedFd::from_raw_fd(fd as i32) }
+}
+
+fn create_map(map_type: u32, key_size: u32, value_size: u32) -> io::Result<OwnedFd> {
+    let mut attr = MapCreateAttr {
+        map_type,
+        key_size,
+        value_size,
+        max_entries: MAX_STACKS,
+        map_flags: 0,
+    };
+    bpf(BPF_MAP_CREATE, &mut attr).map(owned_fd)
+}
+
+fn map_lookup<K, V>(map: &OwnedFd, key: &K, value: &mut V) -> io::Result<()> {
+    let mut attr = MapElemAttr {
+        map_fd: map.as_raw_fd() as u32,
+        key: key as *const K as u64,
+        value: value as *mut V as u64,
+        ..MapElemAttr::default()
+    };
+    bpf(BPF_MAP_LOOKUP_ELEM, &mut attr).map(drop)
+}
+
+fn map_delete<K>(map: &OwnedFd, key: &K) -> io::Result<()> {
+    let mut attr = MapElemAttr {
+        map_fd: map.as_raw_fd() as u32,
+        key: key as *const K as u64,
+        ..MapElemAttr::default()
+    };
+    bpf(BPF_MAP_DELETE_ELEM, &mut attr).map(drop)
+}
+
+/// All keys of `map`; keys added while iterating may be missed.
+fn map_keys<K: Copy + Default>(map: &OwnedFd) -> Vec<K> {
+    let mut keys = Vec::new();
+    let mut next = K::default();
+    let mut attr = MapElemAttr {
+        map_fd: map.as_raw_fd() as u32,
+        key: 0,
+        value: &mut next as *mut K as u64,
+        ..MapElemAttr::default()
+    };
+    while keys.len() < MAX_STACKS as usize && bpf(BPF_MAP_GET_NEXT_KEY, &mut attr).is_ok() {
+        keys.push(next);
+        attr.key = keys.last().unwrap() as *const K as u64;
+    }
+    keys
+}
+
+fn load_program(insns: &[BpfInsn]) -> io::Result<OwnedFd> {
+    // bpf_get_stackid is only available to GPL-compatible programs.
+    let license = c"GPL";
+    let mut attr = ProgLoadAttr {
+        prog_type: BPF_PROG_TYPE_PERF_EVENT,
+        insn_cnt: insns.len() as u32,
+        insns: insns.as_ptr() as u64,
+        license: license.as_ptr() as u64,
+        ..ProgLoadAttr::default()
+    };
+    match bpf(BPF_PROG_LOAD, &mut attr) {
+        Ok(fd) => Ok(owned_fd(fd)),
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => Err(err),
+        Err(err) => {
+            // Load again with the verifier log, for a useful error message.
+            let mut log = vec![0u8; 64 * 1024];
+            attr.log_level = 1;
+            attr.log_size = log.len() as u32;
+            attr.log_buf = log.as_mut_ptr() as u64;
+            let _ = bpf(BPF_PROG_LOAD, &mut attr);
+            let message = CStr::from_bytes_until_nul(&log)
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            Err(io::Error::new(
+                err.kind(),
+                format!("BPF program rejected: {}: {}", err, message.trim()),
+            ))
+        }
+    }
+}
+
+fn open_cpu_clock(cpu: i32, freq_hz: u32) -> io::Result<OwnedFd> {
+    let mut attr = PerfEventAttr {
+        kind: PERF_TYPE_SOFTWARE,
+        size: mem::size_of::<PerfEventAttr>() as u32,
+        config: PERF_COUNT_SW_CPU_CLOCK,
+        sample_freq: freq_hz as u64,
+        flags: PERF_ATTR_FLAG_FREQ,
+        ..PerfEventAttr::default()
+    };
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_perf_event_open,
+            &mut attr as *mut PerfEventAttr,
+            -1 as libc::pid_t,
+            cpu,
+            -1 as libc::c_int,
+            PERF_FLAG_FD_CLOEXEC,
+        )
+    };
+    if fd < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(owned_fd(fd))
+    }
+}
+
+/// Maps, program and perf events of one recording; closing them detaches everything.
+struct Session {
+    stacks: OwnedFd,
+    counts: OwnedFd,
+    _program: OwnedFd,
+    _events: Vec<OwnedFd>,
+}
+
+impl Session {
+    fn start(tgid: u32, freq_hz: u32) -> io::Result<Session> {
+        let stacks = create_map(
+            BPF_MAP_TYPE_STACK_TRACE,
+            mem::size_of::<u32>() as u32,
+            (MAX_DEPTH * mem::size_of::<u64>()) as u32,
+        )?;
+        let counts = create_map(
+            BPF_MAP_TYPE_HASH,
+            mem::size_of::<CountKey>() as u32,
+            mem::size_of::<u64>() as u32,
+        )?;
+        let program = load_program(&sampling_program(
+            tgid,
+            stacks.as_raw_fd(),
+            counts.as_raw_fd(),
+        ))?;
+
+        let cpus = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) }.max(1) as i32;
+        let mut events = Vec::new();
+        let mut last_error = None;
+        for cpu in 0..cpus {
+            // Offline CPUs fail to open; any online one is enough to go on.
+            let event = match open_cpu_clock(cpu, freq_hz) {
+                Ok(event) => event,
+                Err(err) => {
+                    last_error = Some(err);
+                    continue;
+                }
+            };
+            let fd = event.as_raw_fd();
+            if unsafe { libc::ioctl(fd, PERF_EVENT_IOC_SET_BPF as _, program.as_raw_fd()) } != 0
+                || unsafe { libc::ioctl(fd, PERF_EVENT_IOC_ENABLE as _, 0) } != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            events.push(event);
+        }
+        if events.is_empty() {
+            return Err(last_error.unwrap_or_else(|| io::Error::other("no CPU to sample")));
+        }
+        Ok(Session {
+            stacks,
+            counts,
+            _program: program,
+            _events: events,
+        })
+    }
+
+    /// Remove and return the counters recorded since the last drain.
+    fn drain_counts(&self) -> Vec<(CountKey, u64)> {
+        map_keys::<CountKey>(&self.counts)
+            .into_iter()
+            .filter_map(|key| {
+                let mut count = 0u64;
+                map_lookup(&self.counts, &key, &mut count).ok()?;
+                let _ = map_delete(&self.counts, &key);
+                Some((key, count))
+            })
+            .collect()
+    }
+
+    /// Instruction pointers of `stack_id`, leaf first, removed from the stack map.
+    fn take_stack(&self, stack_id: u32) -> Option<Vec<u64>> {
+        let mut ips = [0u64; MAX_DEPTH];
+        map_lookup(&self.stacks, &stack_id, &mut ips).ok()?;
+        let _ = map_delete(&self.stacks, &stack_id);
+        Some(ips.iter().copied().take_while(|ip| *ip != 0).collect())
+    }
+}
+
+impl RemoteProcess {
+    /// Sample merged stacks at `freq_hz` for `duration` with the eBPF backend, without
+    /// stopping the target's threads.
+    ///
+    /// Native samples are merged with the Python stacks read at the end of each drain
+    /// interval (50ms), so the Python part of a stack can lag the native one slightly.
+    /// Samples whose stack the kernel could not record count as dropped.
+    pub fn record_ebpf(&self, freq_hz: u32, duration: Duration) -> io::Result<Profile> {
+        if freq_hz == 0 || freq_hz > 100_000 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "sampling frequency must be within 1..=100000 Hz",
+            ));
+        }
+        let session = Session::start(self.pid() as u32, freq_hz)?;
+        let mut aggregator = StackAggregator::default();
+        let mut dropped = 0;
+        let deadline = Instant::now() + duration;
+        loop {
+            let last = Instant::now() >= deadline;
+            if !last {
+                thread::sleep(DRAIN_INTERVAL.min(deadline - Instant::now()));
+            }
+            let counts = session.drain_counts();
+            let mut python = match self.python_version() {
+                Some(_) => self.python_stacks().unwrap_or_default().stacks,
+                None => HashMap::new(),
+            };
+            let mut stacks: HashMap<u32, Option<Vec<CallFrame>>> = HashMap::new();
+            for (key, count) in counts {
+                let tid = key.pid_tgid as u32 as ThreadId;
+                let native = u32::try_from(key.stack_id).ok().and_then(|id| {
+                    stacks
+                        .entry(id)
+                        .or_insert_with(|| session.take_stack(id).map(|ips| self.symbolize(&ips)))
+                        .clone()
+                });
+                let Some(native) = native else {
+                    dropped += count;
+                    continue;
+                };
+                let frames = SignalTracer::merge_python_native_stacks(
+                    python.get(&tid).cloned().unwrap_or_default(),
+                    native,
+                );
+                for _ in 0..count {
+                    aggregator.add(tid, &frames);
+                }
+            }
+            python.clear();
+            if last {
+                break;
+            }
+        }
+        let mut profile = aggregator.into_profile();
+        profile.dropped_samples = dropped;
+        Ok(profile)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn test_sampling_program_layout() {
+        let program = sampling_program(1234, 5, 6);
+        assert_eq!(program.len(), 33);
+        assert_eq!(program.last().unwrap().code, 0x95);
+        // Both forward jumps to the exit and the lookup miss branch land where intended.
+        let target = |i: usize| (i as i64 + 1 + program[i].off as i64) as usize;
+        assert_eq!(program[4].imm, 1234);
+        assert_eq!(target(4), program.len() - 2);
+        assert_eq!(target(20), program.len() - 2);
+        assert_eq!(target(17), 21);
+        // Map fds are patched into the ld_imm64 pairs as pseudo map fds.
+        let maps: Vec<(i32, u8)> = program
+            .iter()
+            .filter(|i| i.code == 0x18)
+            .map(|i| (i.imm, i.regs >> 4))
+            .collect();
+        assert_eq!(maps, [(5, 1), (6, 1), (6, 1)]);
+        assert_eq!(mem::size_of::<BpfInsn>(), 8);
+        assert_eq!(mem::size_of::<PerfEventAttr>(), 112);
+    }
+
+    #[test]
+    fn test_record_ebpf() {
+        let Ok(mut child) = Command::new("sh")
+            .args(["-c", "while :; do :; done"])
+            .spawn()
+        else {
+            return;
+        };
+        let process = RemoteProcess::attach(child.id() as i32).unwrap();
+        let result = process.record_ebpf(200, Duration::from_millis(300));
+        let _ = child.kill();
+        let _ = child.wait();
+        let profile = match result {
+            Ok(profile) => profile,
+            // Unprivileged or BPF-less environments.
+            Err(err)
+                if matches!(
+                    err.raw_os_error(),
+                    Some(libc::EPERM | libc::EACCES | libc::ENOSYS | libc::EINVAL | libc::ENOENT)
+                ) =>
+            {
+                return
+            }
+            Err(err) => panic!("{}", err),
+        };
+        assert!(profile.total_samples > 0);
+    }
+}
@@ -0,0 +1,513 @@
+//! Attaching to another process on macOS.
+//!
+//! Same model as on Linux: CPython state is read with `mach_vm_read_overwrite`, and native
+//! stacks are walked along frame pointers while the task is suspended. Images are listed
+//! from dyld's `dyld_all_image_infos` in the target and resolved against their DWARF, a
+//! dSYM bundle next to them or one Spotlight knows about (see `symbolize::dsym`).
+//! Universal binaries only get symbol names. System libraries live in the dyld shared
+//! cache rather than on disk; their frames stay `"??"` with the image path as file.
+//!
+//! `task_for_pid` needs root, or a target signed with the `get-task-allow` entitlement
+//! (debug builds) and a caller allowed to debug it.
+
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use mach2::mach_port::mach_port_deallocate;
+use mach2::port::mach_port_t;
+use mach2::task::task_info;
+use mach2::task_info::{task_dyld_info, TASK_DYLD_INFO, TASK_DYLD_INFO_COUNT};
+use mach2::traps::task_for_pid;
+use object::read::ReadCache;
+use object::{Object, ObjectSymbol};
+
+use super::cpython::{self, PythonThreads};
+use super::memory::ProcessMemory;
+use super::{version_from_path, PythonRuntime};
+use crate::capture::MAX_COUNTED_FRAMES;
+use crate::error::TracerError;
+use crate::mach::{self, SuspendedTask, Thread};
+use crate::stack_trace::{CaptureSource, StackTrace};
+use crate::stack_tracer::SignalTracer;
+use crate::symbolize::dsym;
+use crate::symbolize::Module;
+use crate::thread_stack::{ThreadId, ThreadStack, ThreadState};
+use crate::CallFrame;
+
+pub(crate) const MAX_DEPTH: usize = 256;
+
+const MH_MAGIC_64: u32 = 0xfeed_facf;
+const LC_SEGMENT_64: u32 = 0x19;
+const LC_UUID: u32 = 0x1b;
+
+/// A Mach-O image loaded in the target.
+#[derive(Debug)]
+struct Image {
+    path: String,
+    /// Where its `__TEXT` segment is mapped in the target.
+    text: Range<u64>,
+    /// Load address minus link-time address.
+    slide: u64,
+    symbols: Option<Symbols>,
+}
+
+enum Symbols {
+    /// DWARF and symbol table through `addr2line`.
+    Dwarf(Box<Module>),
+    /// Symbol table of one slice of a universal binary, sorted by link-time address.
+    Names(Vec<(u64, String)>),
+}
+
+impl fmt::Debug for Symbols {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Symbols::Dwarf(_) => f.write_str("Dwarf"),
+            Symbols::Names(names) => write!(f, "Names({})", names.len()),
+        }
+    }
+}
+
+/// A process inspected from the outside.
+#[derive(Debug)]
+pub struct RemoteProcess {
+    pid: i32,
+    task: mach_port_t,
+    memory: ProcessMemory,
+    images: Vec<Image>,
+    python: Option<PythonRuntime>,
+}
+
+impl RemoteProcess {
+    /// Get the task port of process `pid`, load its images and symbols and locate its
+    /// CPython runtime.
+    ///
+    /// Succeeds for non-Python targets too; their stacks are then native only. The
+    /// calling process itself cannot be attached to.
+    pub fn attach(pid: i32) -> Result<RemoteProcess, TracerError> {
+        if pid == unsafe { libc::getpid() } {
+            return Err(TracerError::InvalidArgument(
+                "cannot attach to the calling process, use SignalTracer instead".into(),
+            ));
+        }
+        let mut task = 0;
+        mach::check(unsafe { task_for_pid(mach::current_task(), pid, &mut task) }).map_err(
+            |err| {
+                let err = io::Error::new(
+                    err.kind(),
+                    format!(
+                        "task_for_pid: {} (needs root, or a target signed with get-task-allow)",
+                        err
+                    ),
+                );
+                TracerError::capture(pid, err)
+            },
+        )?;
+        let memory = ProcessMemory::for_task(task);
+        let mut process = RemoteProcess {
+            pid,
+            task,
+            memory,
+            images: Vec::new(),
+            python: None,
+        };
+        process.images =
+            list_images(task, &memory).map_err(|err| TracerError::capture(pid, err))?;
+        process.python = process.find_python();
+        Ok(process)
+    }
+
+    pub fn pid(&self) -> i32 {
+        self.pid
+    }
+
+    /// `(major, minor)` of the CPython runtime found in the target, if any.
+    pub fn python_version(&self) -> Option<(u8, u8)> {
+        self.python.map(|p| p.offsets.version)
+    }
+
+    /// `_PyRuntime` is exported by `libpython3.X.dylib`, `Python.framework/.../Python` or a
+    /// statically linked `python3.X` executable; `Py_Version` too from 3.11 on.
+    fn find_python(&self) -> Option<PythonRuntime> {
+        self.images
+            .iter()
+            .filter(|image| image.path.to_ascii_lowercase().contains("python"))
+            .find_map(|image| {
+                let symbols = image_symbols(&image.path, &["__PyRuntime", "_Py_Version"])?;
+                let address = symbols[0]? + image.slide;
+                let version = symbols[1]
+                    .and_then(|addr| self.memory.read_u32(addr + image.slide).ok())
+                    .map(|hex| ((hex >> 24) as u8, (hex >> 16) as u8))
+                    .or_else(|| version_from_path(&image.path))?;
+                Some(PythonRuntime {
+                    offsets: cpython::runtime_offsets(&self.memory, version, address)?,
+                    address,
+                })
+            })
+    }
+
+    /// Python stacks of all interpreter threads. The target keeps running, so stacks may be
+    /// torn.
+    pub fn python_stacks(&self) -> Result<PythonThreads, TracerError> {
+        let Some(python) = self.python else {
+            return Err(TracerError::Unsupported(
+                "no supported CPython runtime in the target".into(),
+            ));
+        };
+        cpython::thread_stacks(&self.memory, &python.offsets, python.address)
+            .map_err(|err| TracerError::capture(self.pid, err))
+    }
+
+    /// Native stack of thread `tid`, suspending it for the duration of the unwind. A stack
+    /// deeper than 256 frames ends with a `[truncated N frames]` marker, N counting up to
+    /// 1024 more frames.
+    pub fn native_stack(&self, tid: ThreadId) -> Result<StackTrace, TracerError> {
+        let capture = |err| TracerError::capture(self.pid, err);
+        let thread = mach::task_thread_list(self.task)
+            .map_err(capture)?
+            .into_iter()
+            .find(|t| t.id().ok() == Some(tid))
+            .ok_or_else(|| capture(io::Error::from(io::ErrorKind::NotFound)))?;
+        let mut ips = Vec::with_capacity(MAX_DEPTH);
+        let suspended = thread.suspend().map_err(capture)?;
+        let walked = thread.native_ips(&self.memory, &mut ips, MAX_DEPTH + MAX_COUNTED_FRAMES);
+        drop(suspended);
+        walked.map_err(capture)?;
+        let mut trace = StackTrace {
+            frames: self.symbolize(&ips),
+            timestamp: Some(SystemTime::now()),
+            pid: Some(self.pid as u32),
+            tid: Some(tid),
+            truncated: false,
+            truncated_frames: 0,
+            source: CaptureSource::Remote,
+        };
+        trace.limit_frames(MAX_DEPTH);
+        Ok(trace)
+    }
+
+    /// Merged stacks of every thread, sorted by tid.
+    ///
+    /// The whole task is suspended while registers and interpreter state are read, so
+    /// Python and native stacks describe the same instant. Threads waiting for the GIL
+    /// start with a `[GIL wait]` frame.
+    pub fn dump(&self) -> Result<Vec<ThreadStack>, TracerError> {
+        let capture = |err| TracerError::capture(self.pid, err);
+        // Names and states first: once suspended, every thread reads as stopped.
+        let mut threads: Vec<(ThreadId, String, ThreadState, Thread)> =
+            mach::task_thread_list(self.task)
+                .map_err(capture)?
+                .into_iter()
+                .filter_map(|t| {
+                    let tid = t.id().ok()?;
+                    let name = t.name().unwrap_or_default();
+                    let state = t.state().unwrap_or(ThreadState::Unknown);
+                    Some((tid, name, state, t))
+                })
+                .collect();
+        threads.sort_unstable_by_key(|(tid, ..)| *tid);
+
+        let suspended = SuspendedTask::new(self.task).map_err(capture)?;
+        let native: Vec<Vec<u64>> = threads
+            .iter()
+            .map(|(.., thread)| {
+                let mut ips = Vec::with_capacity(MAX_DEPTH);
+                let _ = thread.native_ips(&self.memory, &mut ips, MAX_DEPTH);
+                ips
+            })
+            .collect();
+        let python = match self.python {
+            Some(_) => self.python_stacks(),
+            None => Ok(PythonThreads::default()),
+        };
+        drop(suspended);
+        let mut python = python?;
+
+        Ok(threads
+            .into_iter()
+            .zip(native)
+            .map(|((tid, name, os_state, _), ips)| {
+                let mut stack = ThreadStack {
+                    tid,
+                    name,
+                    os_state,
+                    is_gil_holder: python.gil_holder == Some(tid),
+                    greenlet: false,
+                    interpreter_id: python.interpreters.get(&tid).copied(),
+                    frames: SignalTracer::merge_python_native_stacks(
+                        python.stacks.remove(&tid).unwrap_or_default(),
+                        self.symbolize(&ips),
+                    ),
+                };
+                if !python.gil_disabled {
+                    stack.annotate_gil_wait();
+                }
+                stack
+            })
+            .collect())
+    }
+
+    /// Resolve raw ips against the target's images.
+    ///
+    /// Every ip but the first is a return address, so the call instruction itself is
+    /// looked up one byte earlier.
+    fn symbolize(&self, ips: &[u64]) -> Vec<CallFrame> {
+        let mut frames: Vec<CallFrame> = ips
+            .iter()
+            .enumerate()
+            .map(|(i, ip)| {
+                let hex = format!("{:#x}", ip);
+                let lookup = if i == 0 { *ip } else { ip.wrapping_sub(1) };
+                let Some(image) = self.images.iter().find(|i| i.text.contains(&lookup)) else {
+                    return CallFrame::native(hex, "", "??", 0);
+                };
+                match &image.symbols {
+                    Some(Symbols::Dwarf(module)) => {
+                        let mut functions = module.functions(lookup);
+                        // The last function is the physical one, the others were inlined.
+                        match functions.pop() {
+                            Some(outer) => {
+                                CallFrame::native(hex, outer.file, outer.func, outer.lineno)
+                                    .with_inlined(functions)
+                            }
+                            None => CallFrame::native(hex, image.path.as_str(), "??", 0),
+                        }
+                    }
+                    Some(Symbols::Names(names)) => {
+                        let probe = lookup.wrapping_sub(image.slide);
+                        let index = names.partition_point(|(addr, _)| *addr <= probe);
+                        let func = index.checked_sub(1).map_or("??", |i| names[i].1.as_str());
+                        CallFrame::native(hex, image.path.as_str(), func, 0)
+                    }
+                    None => CallFrame::native(hex, image.path.as_str(), "??", 0),
+                }
+            })
+            .collect();
+        // Symbol tables hold mangled names.
+        SignalTracer::demangle_frames(&mut frames);
+        frames
+    }
+}
+
+impl Drop for RemoteProcess {
+    fn drop(&mut self) {
+        unsafe { mach_port_deallocate(mach::current_task(), self.task) };
+    }
+}
+
+/// Images listed in the target's `dyld_all_image_infos`.
+fn list_images(task: mach_port_t, memory: &ProcessMemory) -> io::Result<Vec<Image>> {
+    let mut info = task_dyld_info {
+        all_image_info_addr: 0,
+        all_image_info_size: 0,
+        all_image_info_format: 0,
+    };
+    let mut count = TASK_DYLD_INFO_COUNT;
+    mach::check(unsafe {
+        task_info(
+            task,
+            TASK_DYLD_INFO,
+            &mut info as *mut task_dyld_info as *mut i32,
+            &mut count,
+        )
+    })?;
+    // `{ u32 version; u32 infoArrayCount; dyld_image_info *infoArray; ... }`; the array
+    // is briefly null while dyld updates it.
+    let mut array = 0;
+    let mut images = 0;
+    for _ in 0..10 {
+        images = memory.read_u32(info.all_image_info_addr + 4)?;
+        array = memory.read_u64(info.all_image_info_addr + 8)?;
+        if array != 0 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+    if array == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::WouldBlock,
+            "dyld is updating the image list",
+        ));
+    }
+
+    // `{ mach_header *imageLoadAddress; const char *imageFilePath; uintptr_t modDate; }`
+    Ok((0..u64::from(images))
+        .filter_map(|i| {
+            let entry = array + i * 24;
+            let load_address = memory.read_u64(entry).ok()?;
+            let path = read_c_string(memory, memory.read_u64(entry + 8).ok()?).ok()?;
+            let (text, uuid) = read_load_commands(memory, load_address).ok()?;
+            let slide = load_address.wrapping_sub(text.start);
+            Some(Image {
+                symbols: load_symbols(&path, uuid, slide),
+                text: load_address..load_address + (text.end - text.start),
+                slide,
+                path,
+            })
+        })
+        .collect())
+}
+
+/// Link-time range of `__TEXT` and the `LC_UUID` of the image mapped at `load_address`.
+fn read_load_commands(
+    memory: &ProcessMemory,
+    load_address: u64,
+) -> io::Result<(Range<u64>, Option<[u8; 16]>)> {
+    let header = memory.read_vec(load_address, 32)?;
+    let word = |bytes: &[u8], at: usize| u32::from_ne_bytes(bytes[at..at + 4].try_into().unwrap());
+    if word(&header, 0) != MH_MAGIC_64 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a 64-bit Mach-O image",
+        ));
+    }
+    let commands = memory.read_vec(load_address + 32, word(&header, 20) as usize)?;
+    parse_load_commands(&commands, word(&header, 16))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "image without __TEXT"))
+}
+
+fn parse_load_commands(commands: &[u8], count: u32) -> Option<(Range<u64>, Option<[u8; 16]>)> {
+    let u32_at = |at: usize| {
+        Some(u32::from_ne_bytes(
+            commands.get(at..at + 4)?.try_into().ok()?,
+        ))
+    };
+    let u64_at = |at: usize| {
+        Some(u64::from_ne_bytes(
+            commands.get(at..at + 8)?.try_into().ok()?,
+        ))
+    };
+    let (mut text, mut uuid) = (None, None);
+    let mut at = 0;
+    for _ in 0..count {
+        let (cmd, size) = (u32_at(at)?, u32_at(at + 4)? as usize);
+        match cmd {
+            // `segname[16]` follows `cmd` and `cmdsize`, then `vmaddr` and `vmsize`.
+            LC_SEGMENT_64 if commands.get(at + 8..at + 24)?.starts_with(b"__TEXT\0") => {
+                let start = u64_at(at + 24)?;
+                text = Some(start..start + u64_at(at + 32)?);
+            }
+            LC_UUID => uuid = commands.get(at + 8..at + 24)?.try_into().ok(),
+            _ => {}
+        }
+        if size == 0 {
+            break;
+        }
+        at += size;
+    }
+    Some((text?, uuid))
+}
+
+/// A NUL-terminated string, read without crossing into pages that may be unmapped.
+fn read_c_string(memory: &ProcessMemory, mut addr: u64) -> io::Result<String> {
+    let mut bytes = Vec::new();
+    while bytes.len() < 4096 {
+        let chunk = (4096 - addr % 4096).min(256) as usize;
+        let buf = memory.read_vec(addr, chunk)?;
+        if let Some(end) = buf.iter().position(|b| *b == 0) {
+            bytes.extend_from_slice(&buf[..end]);
+            break;
+        }
+        bytes.extend_from_slice(&buf);
+        addr += chunk as u64;
+    }
+    Ok(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Debug info of the image file at `path`, if it is on disk.
+fn load_symbols(path: &str, uuid: Option<[u8; 16]>, slide: u64) -> Option<Symbols> {
+    let cache = ReadCache::new(File::open(path).ok()?);
+    let (slice, fat) = dsym::host_slice(&cache)?;
+    if fat {
+        let file = object::File::parse(slice).ok()?;
+        let mut names: Vec<(u64, String)> = file
+            .symbol_map()
+            .symbols()
+            .iter()
+            // C symbols carry a leading underscore on Mach-O.
+            .map(|s| {
+                (
+                    s.address(),
+                    s.name().strip_prefix('_').unwrap_or(s.name()).to_string(),
+                )
+            })
+            .collect();
+        names.sort_unstable_by_key(|(addr, _)| *addr);
+        return Some(Symbols::Names(names));
+    }
+    // addr2line finds a dSYM next to the image by itself.
+    let adjacent = Path::new(&format!("{}.dSYM", path)).is_dir();
+    let dwarf = uuid
+        .filter(|_| !adjacent)
+        .and_then(dsym::find_dsym)
+        .unwrap_or_else(|| path.into());
+    let loader = addr2line::Loader::new(dwarf).ok()?;
+    Some(Symbols::Dwarf(Box::new(Module {
+        loader,
+        bias: slide,
+    })))
+}
+
+/// Link-time addresses of the symbols `names` (with their Mach-O underscore) of the image
+/// file at `path`.
+fn image_symbols(path: &str, names: &[&str]) -> Option<Vec<Option<u64>>> {
+    let cache = ReadCache::new(File::open(path).ok()?);
+    let (slice, _) = dsym::host_slice(&cache)?;
+    let file = object::File::parse(slice).ok()?;
+    Some(
+        names
+            .iter()
+            .map(|name| file.symbol_by_name(name).map(|s| s.address()))
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_load_commands() {
+        let mut commands = Vec::new();
+        // LC_SEGMENT_64 __TEXT at 0x1_0000_0000, 0x4000 bytes (cmdsize 72).
+        commands.extend_from_slice(&LC_SEGMENT_64.to_ne_bytes());
+        commands.extend_from_slice(&72u32.to_ne_bytes());
+        commands.extend_from_slice(b"__TEXT\0\0\0\0\0\0\0\0\0\0");
+        commands.extend_from_slice(&0x1_0000_0000u64.to_ne_bytes());
+        commands.extend_from_slice(&0x4000u64.to_ne_bytes());
+        commands.resize(72, 0);
+        commands.extend_from_slice(&LC_UUID.to_ne_bytes());
+        commands.extend_from_slice(&24u32.to_ne_bytes());
+        commands.extend_from_slice(&[7; 16]);
+
+        let (text, uuid) = parse_load_commands(&commands, 2).unwrap();
+        assert_eq!(text, 0x1_0000_0000..0x1_0000_4000);
+        assert_eq!(uuid, Some([7; 16]));
+        assert!(parse_load_commands(&commands[72..], 1).is_none());
+    }
+
+    #[test]
+    fn test_own_images() {
+        let memory = ProcessMemory::current();
+        let images = list_images(mach::current_task(), &memory).unwrap();
+        let exe = std::env::current_exe().unwrap();
+        let own = images
+            .iter()
+            .find(|i| Path::new(&i.path) == exe)
+            .expect("own executable listed");
+        let ip = test_own_images as *const () as u64;
+        assert!(own.text.contains(&ip));
+    }
+
+    #[test]
+    fn test_attach_rejects_self() {
+        assert!(matches!(
+            RemoteProcess::attach(unsafe { libc::getpid() }),
+            Err(TracerError::InvalidArgument(_))
+        ));
+    }
+}
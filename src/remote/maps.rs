@@ -0,0 +1,77 @@
+//! `/proc/<pid>/maps` parsing.
+
+use std::fs;
+use std::io;
+
+/// One line of `/proc/<pid>/maps`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemoryMap {
+    pub start: u64,
+    pub end: u64,
+    pub executable: bool,
+    pub writable: bool,
+    /// File offset of `start`.
+    pub offset: u64,
+    /// Backing file, empty for anonymous mappings; `[stack]` style names are kept as is.
+    pub path: String,
+}
+
+impl MemoryMap {
+    pub fn contains(&self, addr: u64) -> bool {
+        self.start <= addr && addr < self.end
+    }
+}
+
+/// Mappings of process `pid`, in address order.
+pub fn read_maps(pid: i32) -> io::Result<Vec<MemoryMap>> {
+    let maps = fs::read_to_string(format!("/proc/{}/maps", pid))?;
+    Ok(parse_maps(&maps))
+}
+
+pub(crate) fn parse_maps(maps: &str) -> Vec<MemoryMap> {
+    maps.lines().filter_map(parse_line).collect()
+}
+
+fn parse_line(line: &str) -> Option<MemoryMap> {
+    // start-end perms offset dev inode [path]; the path itself may contain spaces.
+    let mut fields = line.splitn(6, ' ');
+    let (start, end) = fields.next()?.split_once('-')?;
+    let perms = fields.next()?;
+    let offset = fields.next()?;
+    let _dev = fields.next()?;
+    let _inode = fields.next()?;
+    let path = fields.next().unwrap_or("").trim_start();
+    Some(MemoryMap {
+        start: u64::from_str_radix(start, 16).ok()?,
+        end: u64::from_str_radix(end, 16).ok()?,
+        executable: perms.as_bytes().get(2) == Some(&b'x'),
+        writable: perms.as_bytes().get(1) == Some(&b'w'),
+        offset: u64::from_str_radix(offset, 16).ok()?,
+        path: path.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_maps() {
+        let maps = parse_maps(
+            "55d0c0000000-55d0c0001000 r--p 00000000 08:01 1234  /usr/bin/my python\n\
+             7f0000001000-7f0000002000 r-xp 00001000 08:01 99   /lib/libpython3.11.so.1.0\n\
+             7ffd00000000-7ffd00021000 rw-p 00000000 00:00 0    [stack]\n\
+             7f0000003000-7f0000004000 rw-p 00000000 00:00 0\n",
+        );
+        assert_eq!(maps.len(), 4);
+        assert_eq!(maps[0].path, "/usr/bin/my python");
+        assert!(!maps[0].executable);
+        assert!(maps[1].executable);
+        assert!(!maps[1].writable);
+        assert!(maps[2].writable);
+        assert_eq!(maps[1].offset, 0x1000);
+        assert!(maps[1].contains(0x7f0000001800));
+        assert_eq!(maps[2].path, "[stack]");
+        assert_eq!(maps[3].path, "");
+    }
+}
@@ -0,0 +1,252 @@
+//! Reads from another process' address space through `process_vm_readv` on Linux,
+//! `mach_vm_read_overwrite` on macOS and `ReadProcessMemory` on Windows.
+
+use std::io;
+#[cfg(windows)]
+use std::os::windows::io::{AsRawHandle, FromRawHandle, OwnedHandle};
+#[cfg(windows)]
+use std::sync::Arc;
+
+/// Handle on the address space of a process. Reads need ptrace permission on the target.
+#[cfg(target_os = "linux")]
+#[derive(Clone, Copy, Debug)]
+pub struct ProcessMemory {
+    pid: i32,
+}
+
+/// Handle on the address space of a process: its mach task port, which stays owned by
+/// the caller.
+#[cfg(target_os = "macos")]
+#[derive(Clone, Copy, Debug)]
+pub struct ProcessMemory {
+    task: mach2::port::mach_port_t,
+}
+
+/// Handle on the address space of a process, opened with `PROCESS_VM_READ`.
+#[cfg(windows)]
+#[derive(Clone, Debug)]
+pub struct ProcessMemory {
+    handle: Arc<OwnedHandle>,
+}
+
+#[cfg(target_os = "linux")]
+impl ProcessMemory {
+    pub fn new(pid: i32) -> ProcessMemory {
+        ProcessMemory { pid }
+    }
+
+    /// Fill `buf` from `addr` in the target; short reads are errors.
+    pub fn read(&self, addr: u64, buf: &mut [u8]) -> io::Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let local = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let remote = libc::iovec {
+            iov_base: addr as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        let n = unsafe { libc::process_vm_readv(self.pid, &local, 1, &remote, 1, 0) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if n as usize != buf.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("short read at {:#x}", addr),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "macos")]
+impl ProcessMemory {
+    /// Memory of the calling process.
+    pub fn current() -> ProcessMemory {
+        Self::for_task(unsafe { mach2::traps::mach_task_self() })
+    }
+
+    pub fn for_task(task: mach2::port::mach_port_t) -> ProcessMemory {
+        ProcessMemory { task }
+    }
+
+    pub(crate) fn task(&self) -> mach2::port::mach_port_t {
+        self.task
+    }
+
+    /// Fill `buf` from `addr` in the target; short reads are errors. Does not allocate, so
+    /// it is safe to use while other threads of the caller are suspended.
+    pub fn read(&self, addr: u64, buf: &mut [u8]) -> io::Result<()> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let mut n = 0;
+        let kr = unsafe {
+            mach2::vm::mach_vm_read_overwrite(
+                self.task,
+                addr,
+                buf.len() as u64,
+                buf.as_mut_ptr() as u64,
+                &mut n,
+            )
+        };
+        crate::mach::check(kr)?;
+        if n as usize != buf.len() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+impl ProcessMemory {
+    /// Open process `pid` for reading its memory and querying its threads and modules.
+    pub fn open(pid: u32) -> io::Result<ProcessMemory> {
+        use windows_sys::Win32::System::Threading::{
+            OpenProcess, PROCESS_QUERY_INFORMATION, PROCESS_VM_READ,
+        };
+
+        let handle = unsafe { OpenProcess(PROCESS_QUERY_INFORMATION | PROCESS_VM_READ, 0, pid) };
+        if handle.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ProcessMemory {
+            handle: Arc::new(unsafe { OwnedHandle::from_raw_handle(handle) }),
+        })
+    }
+
+    /// The process handle, for the debug help and thread APIs.
+    pub(crate) fn handle(&self) -> windows_sys::Win32::Foundation::HANDLE {
+        self.handle.as_raw_handle()
+    }
+
+    /// Fill `buf` from `addr` in the target; short reads are errors.
+    pub fn read(&self, addr: u64, buf: &mut [u8]) -> io::Result<()> {
+        use windows_sys::Win32::System::Diagnostics::Debug::ReadProcessMemory;
+
+        if buf.is_empty() {
+            return Ok(());
+        }
+        let mut n = 0;
+        let ok = unsafe {
+            ReadProcessMemory(
+                self.handle(),
+                addr as *const std::ffi::c_void,
+                buf.as_mut_ptr() as *mut std::ffi::c_void,
+                buf.len(),
+                &mut n,
+            )
+        };
+        if ok == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if n != buf.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("short read at {:#x}", addr),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl ProcessMemory {
+    pub fn read_vec(&self, addr: u64, len: usize) -> io::Result<Vec<u8>> {
+        let mut buf = vec![0; len];
+        self.read(addr, &mut buf)?;
+        Ok(buf)
+    }
+
+    pub fn read_u64(&self, addr: u64) -> io::Result<u64> {
+        let mut buf = [0; 8];
+        self.read(addr, &mut buf)?;
+        Ok(u64::from_ne_bytes(buf))
+    }
+
+    pub fn read_u32(&self, addr: u64) -> io::Result<u32> {
+        let mut buf = [0; 4];
+        self.read(addr, &mut buf)?;
+        Ok(u32::from_ne_bytes(buf))
+    }
+
+    pub fn read_i32(&self, addr: u64) -> io::Result<i32> {
+        self.read_u32(addr).map(|v| v as i32)
+    }
+
+    pub fn read_u8(&self, addr: u64) -> io::Result<u8> {
+        let mut buf = [0; 1];
+        self.read(addr, &mut buf)?;
+        Ok(buf[0])
+    }
+}
+
+/// Follow `[fp] = caller fp, [fp + 8] = return address` while frames move up the stack,
+/// until `ips` holds `max_depth` addresses. Pushes stay within a capacity reserved up front.
+#[cfg(unix)]
+pub(crate) fn walk_frame_pointers(
+    memory: &ProcessMemory,
+    mut fp: u64,
+    sp: u64,
+    ips: &mut Vec<u64>,
+    max_depth: usize,
+) {
+    if fp < sp {
+        return;
+    }
+    while fp != 0 && fp.is_multiple_of(8) && ips.len() < max_depth {
+        let (Ok(next), Ok(ret)) = (memory.read_u64(fp), memory.read_u64(fp + 8)) else {
+            break;
+        };
+        if ret == 0 {
+            break;
+        }
+        ips.push(ret);
+        if next <= fp {
+            break;
+        }
+        fp = next;
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_walk_frame_pointers() {
+        // Two chained frames laid out in a local buffer: fp0 -> fp1 -> null.
+        let mut stack = [0u64; 4];
+        let base = stack.as_ptr() as u64;
+        stack[0] = base + 16;
+        stack[1] = 0x1111;
+        stack[2] = 0;
+        stack[3] = 0x2222;
+
+        let memory = ProcessMemory::new(unsafe { libc::getpid() });
+        let mut ips = vec![0x1000];
+        walk_frame_pointers(&memory, base, base, &mut ips, 256);
+        assert_eq!(ips, vec![0x1000, 0x1111, 0x2222]);
+
+        // A frame pointer below the stack pointer is not trusted.
+        let mut ips = Vec::new();
+        walk_frame_pointers(&memory, base, base + 8, &mut ips, 256);
+        assert!(ips.is_empty());
+        // The depth limit counts the ips already present.
+        let mut ips = vec![0x1000];
+        walk_frame_pointers(&memory, base, base, &mut ips, 2);
+        assert_eq!(ips, vec![0x1000, 0x1111]);
+        // Only read through raw addresses above.
+        std::hint::black_box(&stack);
+    }
+
+    #[test]
+    fn test_read_own_memory() {
+        let value: u64 = 0x1122_3344_5566_7788;
+        let memory = ProcessMemory::new(unsafe { libc::getpid() });
+        assert_eq!(memory.read_u64(&value as *const u64 as u64).unwrap(), value);
+        assert!(memory.read_u64(0).is_err());
+    }
+}
@@ -0,0 +1,859 @@
+//! Attach to another process (py-spy style) and capture its merged stacks (Linux, macOS
+//! and Windows).
+//!
+//! Python frames are rebuilt by reading CPython's interpreter state straight from the
+//! target's memory with `process_vm_readv`; native stacks are unwound from the registers
+//! of each thread while it is stopped with ptrace. The target does not need to link this
+//! crate, but the caller needs ptrace permission on it (same user and a permissive
+//! `kernel.yama.ptrace_scope`, or `CAP_SYS_PTRACE`). The backends in `macos.rs` and
+//! `windows.rs` do the same with mach task ports, and with `ReadProcessMemory`,
+//! `SuspendThread` and DbgHelp.
+
+pub mod cpython;
+pub mod cpython_abi;
+#[cfg(all(feature = "ebpf", target_os = "linux"))]
+pub mod ebpf;
+#[cfg(target_os = "macos")]
+mod macos;
+#[cfg(target_os = "linux")]
+pub mod maps;
+pub mod memory;
+#[cfg(target_os = "linux")]
+pub mod ptrace;
+#[cfg(target_os = "linux")]
+pub mod symbols;
+#[cfg(windows)]
+mod windows;
+
+use std::collections::BTreeMap;
+#[cfg(target_os = "linux")]
+use std::collections::HashSet;
+use std::thread;
+#[cfg(target_os = "linux")]
+use std::time::SystemTime;
+use std::time::{Duration, Instant};
+
+#[cfg(target_os = "linux")]
+use self::cpython::PythonThreads;
+use self::cpython_abi::PythonOffsets;
+#[cfg(target_os = "macos")]
+pub use self::macos::RemoteProcess;
+#[cfg(target_os = "linux")]
+use self::maps::MemoryMap;
+#[cfg(target_os = "linux")]
+use self::memory::ProcessMemory;
+#[cfg(target_os = "linux")]
+use self::ptrace::StoppedThread;
+#[cfg(target_os = "linux")]
+use self::symbols::ModuleSymbols;
+#[cfg(windows)]
+pub use self::windows::RemoteProcess;
+#[cfg(target_os = "linux")]
+use crate::boundary::PythonImplementation;
+#[cfg(target_os = "linux")]
+use crate::capture::MAX_COUNTED_FRAMES;
+use crate::error::TracerError;
+use crate::gil::{GilInterval, GilTimeline};
+use crate::profile::{Profile, StackAggregator};
+#[cfg(target_os = "linux")]
+use crate::stack_trace::{CaptureSource, StackTrace};
+#[cfg(target_os = "linux")]
+use crate::stack_tracer::SignalTracer;
+#[cfg(target_os = "linux")]
+use crate::thread_stack::{ThreadId, ThreadStack, ThreadState};
+#[cfg(target_os = "linux")]
+use crate::threads::{list_tasks, task_name, task_state};
+use crate::CallFrame;
+
+/// Location of the CPython runtime inside the target.
+#[derive(Clone, Copy, Debug)]
+struct PythonRuntime {
+    offsets: PythonOffsets,
+    /// Address of `_PyRuntime`.
+    address: u64,
+}
+
+/// A process inspected from the outside.
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+pub struct RemoteProcess {
+    pid: i32,
+    memory: ProcessMemory,
+    maps: Vec<MemoryMap>,
+    modules: Vec<ModuleSymbols>,
+    python: Option<PythonRuntime>,
+}
+
+#[cfg(target_os = "linux")]
+impl RemoteProcess {
+    /// Load the mappings and symbols of process `pid` and locate its CPython runtime.
+    ///
+    /// Succeeds for non-Python targets too; their stacks are then native only. The
+    /// calling process itself cannot be attached to.
+    pub fn attach(pid: i32) -> Result<RemoteProcess, TracerError> {
+        if pid == unsafe { libc::getpid() } {
+            return Err(TracerError::InvalidArgument(
+                "cannot attach to the calling process, use SignalTracer instead".into(),
+            ));
+        }
+        let maps = maps::read_maps(pid).map_err(|err| TracerError::capture(pid, err))?;
+        let mut seen = HashSet::new();
+        let modules = maps
+            .iter()
+            .filter(|m| m.executable && m.path.starts_with('/'))
+            .filter(|m| seen.insert(m.path.clone()))
+            .filter_map(|m| ModuleSymbols::load(pid, &m.path, &maps).ok())
+            .collect();
+
+        let mut process = RemoteProcess {
+            pid,
+            memory: ProcessMemory::new(pid),
+            maps,
+            modules,
+            python: None,
+        };
+        process.python = process.find_python();
+        Ok(process)
+    }
+
+    pub fn pid(&self) -> i32 {
+        self.pid
+    }
+
+    /// `(major, minor)` of the CPython runtime found in the target, if any.
+    pub fn python_version(&self) -> Option<(u8, u8)> {
+        self.python.map(|p| p.offsets.version)
+    }
+
+    fn find_python(&self) -> Option<PythonRuntime> {
+        self.modules.iter().find_map(|module| {
+            let address = module.address_of("_PyRuntime")?;
+            // `Py_Version` (3.11+) holds PY_VERSION_HEX; older builds only have the path.
+            let version = module
+                .address_of("Py_Version")
+                .and_then(|addr| self.memory.read_u32(addr).ok())
+                .map(|hex| ((hex >> 24) as u8, (hex >> 16) as u8))
+                .or_else(|| version_from_path(&module.path))?;
+            Some(PythonRuntime {
+                offsets: cpython::runtime_offsets(&self.memory, version, address)?,
+                address,
+            })
+        })
+    }
+
+    /// Python stacks of all interpreter threads. The target keeps running, so stacks may be
+    /// torn.
+    pub fn python_stacks(&self) -> Result<PythonThreads, TracerError> {
+        let Some(python) = self.python else {
+            // PyPy maps app-level code to names only inside the process (vmprof).
+            let pypy = self.modules.iter().any(|m| {
+                PythonImplementation::from_path(&m.path) == Some(PythonImplementation::PyPy)
+            });
+            return Err(TracerError::Unsupported(
+                if pypy {
+                    "PyPy targets can only be captured in-process"
+                } else {
+                    "no supported CPython runtime in the target"
+                }
+                .into(),
+            ));
+        };
+        cpython::thread_stacks(&self.memory, &python.offsets, python.address)
+            .map_err(|err| TracerError::capture(self.pid, err))
+    }
+
+    /// Native stack of thread `tid`, stopping it for the duration of the unwind. A stack
+    /// deeper than 256 frames ends with a `[truncated N frames]` marker, N counting up to
+    /// 1024 more frames.
+    pub fn native_stack(&self, tid: ThreadId) -> Result<StackTrace, TracerError> {
+        let capture = |err| TracerError::capture(self.pid, err);
+        let thread = StoppedThread::attach(tid).map_err(capture)?;
+        let ips = thread
+            .native_ips(&self.memory, ptrace::MAX_DEPTH + MAX_COUNTED_FRAMES)
+            .map_err(capture)?;
+        drop(thread);
+        let mut trace = StackTrace {
+            frames: self.symbolize(&ips),
+            timestamp: Some(SystemTime::now()),
+            pid: Some(self.pid as u32),
+            tid: Some(tid),
+            truncated: false,
+            truncated_frames: 0,
+            source: CaptureSource::Remote,
+        };
+        trace.limit_frames(ptrace::MAX_DEPTH);
+        Ok(trace)
+    }
+
+    /// Merged stacks of every thread, sorted by tid.
+    ///
+    /// All threads are stopped while registers and interpreter state are read, so Python
+    /// and native stacks describe the same instant. Threads waiting for the GIL start
+    /// with a `[GIL wait]` frame.
+    pub fn dump(&self) -> Result<Vec<ThreadStack>, TracerError> {
+        let pid = self.pid.to_string();
+        let tids = list_tasks(&pid).map_err(|err| TracerError::capture(self.pid, err))?;
+        // Threads that exit before they can be stopped are skipped, but a target none of
+        // whose threads can be stopped is an error (usually ptrace being refused).
+        let mut refused = None;
+        let stopped: Vec<(ThreadId, StoppedThread)> = tids
+            .iter()
+            .filter_map(|tid| match StoppedThread::attach(*tid) {
+                Ok(thread) => Some((*tid, thread)),
+                Err(err) => {
+                    refused.get_or_insert(err);
+                    None
+                }
+            })
+            .collect();
+        if let (true, Some(err)) = (stopped.is_empty(), refused) {
+            return Err(TracerError::capture(self.pid, err));
+        }
+        let native: Vec<(ThreadId, Vec<u64>)> = stopped
+            .iter()
+            .map(|(tid, thread)| {
+                (
+                    *tid,
+                    thread
+                        .native_ips(&self.memory, ptrace::MAX_DEPTH)
+                        .unwrap_or_default(),
+                )
+            })
+            .collect();
+        let python = match self.python {
+            Some(_) => self.python_stacks(),
+            None => Ok(PythonThreads::default()),
+        };
+        drop(stopped);
+        let mut python = python?;
+
+        Ok(native
+            .into_iter()
+            .map(|(tid, ips)| {
+                let mut stack = ThreadStack {
+                    tid,
+                    name: task_name(&pid, tid).unwrap_or_default(),
+                    os_state: task_state(&pid, tid).unwrap_or(ThreadState::Unknown),
+                    is_gil_holder: python.gil_holder == Some(tid),
+                    greenlet: false,
+                    interpreter_id: python.interpreters.get(&tid).copied(),
+                    frames: SignalTracer::merge_python_native_stacks(
+                        python.stacks.remove(&tid).unwrap_or_default(),
+                        self.symbolize(&ips),
+                    ),
+                };
+                if !python.gil_disabled {
+                    stack.annotate_gil_wait();
+                }
+                stack
+            })
+            .collect())
+    }
+
+    /// Resolve raw ips against the target's modules.
+    ///
+    /// Every ip but the first is a return address, so the call instruction itself is
+    /// looked up one byte earlier.
+    fn symbolize(&self, ips: &[u64]) -> Vec<CallFrame> {
+        let mut frames: Vec<CallFrame> = ips
+            .iter()
+            .enumerate()
+            .map(|(i, ip)| {
+                let lookup = if i == 0 { *ip } else { ip.wrapping_sub(1) };
+                let map = self.maps.iter().find(|m| m.contains(lookup));
+                let module = map.and_then(|m| self.modules.iter().find(|s| s.path == m.path));
+                let func = module
+                    .and_then(|s| s.lookup(lookup))
+                    .map_or("??", |(name, _)| name);
+                CallFrame::native(
+                    format!("{:#x}", ip),
+                    map.map_or("", |m| m.path.as_str()),
+                    func,
+                    0,
+                )
+            })
+            .collect();
+        // ELF symbol tables hold mangled names.
+        SignalTracer::demangle_frames(&mut frames);
+        frames
+    }
+}
+
+impl RemoteProcess {
+    /// Sample merged stacks every `interval` for `duration`, or until the target exits.
+    pub fn record(&self, interval: Duration, duration: Duration) -> Result<Profile, TracerError> {
+        let mut stacks = StackAggregator::default();
+        let mut missed = 0;
+        let deadline = Instant::now() + duration;
+        let mut next = Instant::now();
+        while next < deadline {
+            match self.dump() {
+                Ok(dump) => {
+                    for stack in dump {
+                        stacks.add(stack.tid, &stack.frames);
+                    }
+                }
+                // The first failure is the caller's problem, later ones mean the target is gone.
+                Err(err) if stacks.total_samples() == 0 => return Err(err),
+                Err(_) => break,
+            }
+
+            next += interval;
+            match next.checked_duration_since(Instant::now()) {
+                Some(wait) => thread::sleep(wait),
+                // Dumping is slower than the requested rate: skip the missed ticks.
+                None => {
+                    missed += 1;
+                    next = Instant::now();
+                }
+            }
+        }
+        let mut profile = stacks.into_profile();
+        profile.dropped_samples = missed;
+        Ok(profile)
+    }
+
+    /// GIL hold and wait intervals of every thread, from dumps taken every `interval` for
+    /// `duration` or until the target exits. Timestamps count from the first dump.
+    ///
+    /// Intervals are as precise as `interval`; see `GilTimeline`.
+    pub fn trace_gil(
+        &self,
+        interval: Duration,
+        duration: Duration,
+    ) -> Result<Vec<GilInterval>, TracerError> {
+        let mut timeline = GilTimeline::new();
+        let start = Instant::now();
+        let deadline = start + duration;
+        let mut next = start;
+        let mut dumped = false;
+        while next < deadline {
+            match self.dump() {
+                Ok(dump) => timeline.observe(elapsed_ns(start), &dump),
+                Err(err) if !dumped => return Err(err),
+                Err(_) => break,
+            }
+            dumped = true;
+
+            next += interval;
+            match next.checked_duration_since(Instant::now()) {
+                Some(wait) => thread::sleep(wait),
+                None => next = Instant::now(),
+            }
+        }
+        Ok(timeline.finish(elapsed_ns(start)))
+    }
+}
+
+/// Profiles of a process and the descendants followed while recording it, keyed by pid.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct SessionProfile {
+    pub processes: BTreeMap<i32, Profile>,
+    /// Command name (`comm`) of each process, when it could be read.
+    pub names: BTreeMap<i32, String>,
+    /// Ticks missed by the whole session. Every process is dumped on the same ticks, so
+    /// these are counted once here rather than in each profile.
+    pub dropped_samples: u64,
+}
+
+impl SessionProfile {
+    /// One profile of every process, each stack rooted at a `[pid <pid> <comm>]` frame of
+    /// category `PROCESS_CATEGORY`. Its dropped samples are the session's.
+    pub fn merged(&self) -> Profile {
+        let mut merged = Profile {
+            dropped_samples: self.dropped_samples,
+            ..Profile::default()
+        };
+        for (pid, profile) in &self.processes {
+            let label = match self.names.get(pid) {
+                Some(name) if !name.is_empty() => format!("[pid {} {}]", pid, name),
+                _ => format!("[pid {}]", pid),
+            };
+            let root = CallFrame::synthetic(label, PROCESS_CATEGORY);
+            merged.stacks.extend(profile.stacks.iter().map(|stack| {
+                let mut stack = stack.clone();
+                stack.frames.push(root.clone());
+                stack
+            }));
+            merged.total_samples += profile.total_samples;
+        }
+        merged
+    }
+}
+
+/// Category of the per-process root frames of `SessionProfile::merged`.
+pub const PROCESS_CATEGORY: &str = "process";
+
+#[cfg(target_os = "linux")]
+impl RemoteProcess {
+    /// Like `record`, also attaching to the children the target spawns, and theirs
+    /// (DataLoader workers, `torch.multiprocessing` spawns), as they appear.
+    ///
+    /// Descendants that cannot be attached to are left out, and a process that exits keeps
+    /// the samples taken so far. Recording stops at `duration` or once every followed
+    /// process has exited.
+    pub fn record_following(
+        &self,
+        interval: Duration,
+        duration: Duration,
+    ) -> Result<SessionProfile, TracerError> {
+        let mut aggregators: BTreeMap<i32, StackAggregator> = BTreeMap::new();
+        let mut children: BTreeMap<i32, RemoteProcess> = BTreeMap::new();
+        // Pids already tried, attached or not, and pids that exited.
+        let mut seen = HashSet::from([self.pid]);
+        let mut root_alive = true;
+        let mut missed = 0;
+        let deadline = Instant::now() + duration;
+        let mut next = Instant::now();
+        while next < deadline && (root_alive || !children.is_empty()) {
+            for pid in descendants(self.pid) {
+                if seen.insert(pid) {
+                    if let Ok(child) = RemoteProcess::attach(pid) {
+                        children.insert(pid, child);
+                    }
+                }
+            }
+            if root_alive {
+                match self.dump() {
+                    Ok(dump) => add_dump(aggregators.entry(self.pid).or_default(), dump),
+                    Err(err) if aggregators.is_empty() => return Err(err),
+                    Err(_) => root_alive = false,
+                }
+            }
+            children.retain(|pid, child| match child.dump() {
+                Ok(dump) => {
+                    add_dump(aggregators.entry(*pid).or_default(), dump);
+                    true
+                }
+                Err(_) => false,
+            });
+
+            next += interval;
+            match next.checked_duration_since(Instant::now()) {
+                Some(wait) => thread::sleep(wait),
+                None => {
+                    missed += 1;
+                    next = Instant::now();
+                }
+            }
+        }
+
+        let mut session = SessionProfile {
+            dropped_samples: missed,
+            ..SessionProfile::default()
+        };
+        for (pid, stacks) in aggregators {
+            let profile = stacks.into_profile();
+            if let Ok(name) = std::fs::read_to_string(format!("/proc/{}/comm", pid)) {
+                session.names.insert(pid, name.trim_end().to_string());
+            }
+            session.processes.insert(pid, profile);
+        }
+        Ok(session)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn add_dump(stacks: &mut StackAggregator, dump: Vec<ThreadStack>) {
+    for stack in dump {
+        stacks.add(stack.tid, &stack.frames);
+    }
+}
+
+/// Pids of the children of `pid`, their children and so on, sorted.
+#[cfg(target_os = "linux")]
+pub fn descendants(pid: i32) -> Vec<i32> {
+    let mut found = Vec::new();
+    let mut pending = vec![pid];
+    while let Some(parent) = pending.pop() {
+        for child in crate::threads::child_processes(parent).unwrap_or_default() {
+            if !found.contains(&child) {
+                found.push(child);
+                pending.push(child);
+            }
+        }
+    }
+    found.sort_unstable();
+    found
+}
+
+fn elapsed_ns(start: Instant) -> u64 {
+    start.elapsed().as_nanos() as u64
+}
+
+/// `(3, 11)` from paths like `/usr/lib/libpython3.11.so.1.0`, `/usr/bin/python3.11` or
+/// `/Library/Frameworks/Python.framework/Versions/3.11/Python`.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn version_from_path(path: &str) -> Option<(u8, u8)> {
+    let name = path.rsplit('/').next()?;
+    let rest = match name.find("python") {
+        Some(at) => &name[at + "python".len()..],
+        // `Python.framework/Versions/3.11/Python`
+        None => path.split_once("/Versions/")?.1,
+    };
+    let mut parts = rest.split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts
+        .next()?
+        .trim_end_matches(|c: char| !c.is_ascii_digit())
+        .parse()
+        .ok()?;
+    Some((major, minor))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::gil::GilEventKind;
+    use crate::profile::SampledStack;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+
+    const SCRIPT: &str = r#"
+import threading, time
+
+def worker_leaf():
+    while True:
+        time.sleep(0.01)
+
+def leaf():
+    while True:
+        time.sleep(0.01)
+
+def middle():
+    leaf()
+
+threading.Thread(target=worker_leaf, daemon=True).start()
+print("ready", flush=True)
+middle()
+"#;
+
+    /// The worker thread parks in a subinterpreter (`_interpreters` from 3.13 on).
+    const SUBINTERPRETER_SCRIPT: &str = r#"
+import threading, time
+try:
+    import _interpreters as interpreters
+except ImportError:
+    import _xxsubinterpreters as interpreters
+
+CODE = """
+import time
+def sub_leaf():
+    while True:
+        time.sleep(0.01)
+print("ready", flush=True)
+sub_leaf()
+"""
+
+def enter():
+    interpreters.run_string(interpreters.create(), CODE)
+
+threading.Thread(target=enter, daemon=True).start()
+while True:
+    time.sleep(0.01)
+"#;
+
+    /// Two threads contending for the GIL with pure-Python loops.
+    const CONVOY_SCRIPT: &str = r#"
+import threading
+
+def spin():
+    n = 0
+    while True:
+        n += 1
+
+threading.Thread(target=spin, daemon=True).start()
+print("ready", flush=True)
+spin()
+"#;
+
+    /// Spawns a worker process, like a DataLoader would, before parking.
+    #[cfg(target_os = "linux")]
+    const SPAWNING_SCRIPT: &str = r#"
+import subprocess, sys, time
+
+worker = subprocess.Popen([sys.executable, "-c", "import time\nwhile True: time.sleep(0.01)"])
+print("ready", flush=True)
+while True:
+    time.sleep(0.01)
+"#;
+
+    struct Target(Child);
+
+    impl Drop for Target {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    /// A python3 child parked in a known call chain, or None when python3 is missing.
+    fn spawn_python() -> Option<Target> {
+        spawn_script(SCRIPT)
+    }
+
+    /// A python3 child running `script`, once it printed `ready`.
+    fn spawn_script(script: &str) -> Option<Target> {
+        let mut child = Command::new("python3")
+            .args(["-c", script])
+            .stdout(Stdio::piped())
+            .spawn()
+            .ok()?;
+        let mut line = String::new();
+        BufReader::new(child.stdout.take().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+        assert_eq!(line.trim(), "ready");
+        Some(Target(child))
+    }
+
+    fn names(frames: &[CallFrame], python: bool) -> Vec<String> {
+        frames
+            .iter()
+            .filter_map(|f| match f {
+                CallFrame::PyFrame { func, .. } if python => Some(func.clone()),
+                CallFrame::CFrame { func, .. } if !python => Some(func.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_dump_python_process() {
+        let Some(target) = spawn_python() else {
+            return;
+        };
+        let process = RemoteProcess::attach(target.0.id() as i32).unwrap();
+        if process.python_version().is_none() {
+            // Interpreter version without known offsets.
+            return;
+        }
+
+        let python = process.python_stacks().unwrap();
+        let main = &python.stacks[&(target.0.id() as ThreadId)];
+        assert_eq!(names(main, true), ["leaf", "middle", "<module>"]);
+        match &main[0] {
+            CallFrame::PyFrame {
+                ip,
+                file,
+                lineno,
+                frame_address,
+                ..
+            } => {
+                assert_eq!(file, "<string>");
+                assert_eq!(*lineno, 10);
+                assert_eq!(
+                    frame_address.map(|a| format!("{:#x}", a)).as_ref(),
+                    Some(ip)
+                );
+            }
+            other => panic!("{:?}", other),
+        }
+
+        let stacks = process.dump().unwrap();
+        assert!(stacks.windows(2).all(|w| w[0].tid < w[1].tid));
+        let main = stacks
+            .iter()
+            .find(|s| s.tid == target.0.id() as ThreadId)
+            .unwrap();
+        let py = names(&main.frames, true);
+        assert_eq!(py, ["leaf", "middle", "<module>"]);
+        assert!(!names(&main.frames, false).is_empty());
+        // CPython 3.11 does not name OS threads, so find the worker by its frames.
+        assert!(stacks
+            .iter()
+            .any(|s| names(&s.frames, true).first().map(String::as_str) == Some("worker_leaf")));
+    }
+
+    #[test]
+    fn test_record_python_process() {
+        let Some(target) = spawn_python() else {
+            return;
+        };
+        let process = RemoteProcess::attach(target.0.id() as i32).unwrap();
+        if process.python_version().is_none() {
+            return;
+        }
+
+        let profile = process
+            .record(Duration::from_millis(20), Duration::from_millis(200))
+            .unwrap();
+        assert!(profile.total_samples >= 2);
+        assert_eq!(
+            profile.stacks.iter().map(|s| s.count).sum::<u64>(),
+            profile.total_samples
+        );
+        assert!(profile
+            .stacks
+            .iter()
+            .any(|s| names(&s.frames, true).first().map(String::as_str) == Some("leaf")));
+    }
+
+    #[test]
+    fn test_session_merged() {
+        let profile = |func: &str, dropped_samples| Profile {
+            stacks: vec![SampledStack {
+                tid: 1,
+                frames: vec![CallFrame::python("0x1", "a.py", func, 1)],
+                count: 2,
+                ..SampledStack::default()
+            }],
+            total_samples: 2,
+            dropped_samples,
+        };
+        let session = SessionProfile {
+            processes: BTreeMap::from([(10, profile("main", 0)), (11, profile("worker", 1))]),
+            names: BTreeMap::from([(10, "python".to_string())]),
+            dropped_samples: 3,
+        };
+        let merged = session.merged();
+        assert_eq!((merged.total_samples, merged.dropped_samples), (4, 3));
+        let roots: Vec<&str> = merged
+            .stacks
+            .iter()
+            .map(|s| s.frames.last().unwrap().func())
+            .collect();
+        assert_eq!(roots, ["[pid 10 python]", "[pid 11]"]);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_record_following() {
+        let Some(target) = spawn_script(SPAWNING_SCRIPT) else {
+            return;
+        };
+        let pid = target.0.id() as i32;
+        let process = RemoteProcess::attach(pid).unwrap();
+        let start = Instant::now();
+        while descendants(pid).is_empty() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let session = process
+            .record_following(Duration::from_millis(20), Duration::from_millis(200))
+            .unwrap();
+        assert_eq!(session.processes.len(), 2, "{:?}", session.names);
+        assert!(session.processes.values().all(|p| p.total_samples > 0));
+        assert!(session.names[&pid].starts_with("python"));
+
+        let merged = session.merged();
+        assert_eq!(
+            merged.total_samples,
+            session
+                .processes
+                .values()
+                .map(|p| p.total_samples)
+                .sum::<u64>()
+        );
+        assert_eq!(merged.dropped_samples, session.dropped_samples);
+        let roots: HashSet<String> = merged
+            .stacks
+            .iter()
+            .map(|s| s.frames.last().unwrap().func().to_string())
+            .collect();
+        assert_eq!(roots.len(), 2);
+        assert!(roots.contains(&format!("[pid {} {}]", pid, session.names[&pid])));
+
+        // The worker would outlive its parent.
+        for child in descendants(pid) {
+            unsafe { libc::kill(child, libc::SIGKILL) };
+        }
+    }
+
+    #[test]
+    fn test_trace_gil() {
+        let Some(target) = spawn_script(CONVOY_SCRIPT) else {
+            return;
+        };
+        let process = RemoteProcess::attach(target.0.id() as i32).unwrap();
+        if process.python_version().is_none() {
+            return;
+        }
+
+        let intervals = process
+            .trace_gil(Duration::from_millis(10), Duration::from_millis(300))
+            .unwrap();
+        let holds: Vec<_> = intervals
+            .iter()
+            .filter(|i| i.kind == GilEventKind::Hold)
+            .collect();
+        assert!(!holds.is_empty(), "{:?}", intervals);
+        assert!(holds
+            .iter()
+            .all(|i| names(&i.frames, true).first().map(String::as_str) == Some("spin")));
+        assert!(intervals.iter().all(|i| i.start_ns <= i.end_ns));
+    }
+
+    #[test]
+    fn test_dump_subinterpreter() {
+        let imports = |module: &str| {
+            Command::new("python3")
+                .args(["-c", &format!("import {}", module)])
+                .stderr(Stdio::null())
+                .status()
+                .is_ok_and(|status| status.success())
+        };
+        if !imports("_interpreters") && !imports("_xxsubinterpreters") {
+            return;
+        }
+        let Some(target) = spawn_script(SUBINTERPRETER_SCRIPT) else {
+            return;
+        };
+        let process = RemoteProcess::attach(target.0.id() as i32).unwrap();
+        if process.python_version().is_none() {
+            return;
+        }
+
+        let stacks = process.dump().unwrap();
+        let main = stacks
+            .iter()
+            .find(|s| s.tid == target.0.id() as ThreadId)
+            .unwrap();
+        assert_eq!(main.interpreter_id, Some(0));
+        let worker = stacks
+            .iter()
+            .find(|s| names(&s.frames, true).first().map(String::as_str) == Some("sub_leaf"))
+            .unwrap();
+        assert!(worker.interpreter_id.is_some_and(|id| id > 0));
+        // The frames of the main interpreter that entered the subinterpreter follow.
+        assert!(names(&worker.frames, true).contains(&"enter".to_string()));
+    }
+
+    #[test]
+    fn test_attach_errors() {
+        assert!(matches!(
+            RemoteProcess::attach(unsafe { libc::getpid() }),
+            Err(TracerError::InvalidArgument(_))
+        ));
+
+        let mut exited = Command::new("true").spawn().unwrap();
+        exited.wait().unwrap();
+        assert!(matches!(
+            RemoteProcess::attach(exited.id() as i32),
+            Err(TracerError::Capture(_))
+        ));
+
+        let target = Target(Command::new("sleep").arg("10").spawn().unwrap());
+        let process = RemoteProcess::attach(target.0.id() as i32).unwrap();
+        assert!(matches!(
+            process.python_stacks(),
+            Err(TracerError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn test_version_from_path() {
+        assert_eq!(
+            version_from_path("/usr/lib/libpython3.11.so.1.0"),
+            Some((3, 11))
+        );
+        assert_eq!(version_from_path("/usr/bin/python3.12"), Some((3, 12)));
+        assert_eq!(version_from_path("/usr/bin/python3.13t"), Some((3, 13)));
+        assert_eq!(version_from_path("/usr/bin/python3"), None);
+        assert_eq!(
+            version_from_path("/Library/Frameworks/Python.framework/Versions/3.12/Python"),
+            Some((3, 12))
+        );
+    }
+}
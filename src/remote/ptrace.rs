@@ -0,0 +1,95 @@
+//! Stopping remote threads with ptrace and walking their native stacks.
+//!
+//! Native unwinding follows the frame-pointer chain, so code built without frame pointers
+//! yields truncated stacks.
+
+use std::io;
+
+use super::memory::{walk_frame_pointers, ProcessMemory};
+use crate::thread_stack::ThreadId;
+
+pub(crate) const MAX_DEPTH: usize = 256;
+
+/// A thread stopped with `PTRACE_ATTACH`; detached again on drop.
+pub struct StoppedThread {
+    tid: ThreadId,
+}
+
+impl StoppedThread {
+    pub fn attach(tid: ThreadId) -> io::Result<StoppedThread> {
+        let attached = unsafe {
+            libc::ptrace(
+                libc::PTRACE_ATTACH,
+                tid,
+                std::ptr::null_mut::<libc::c_void>(),
+                std::ptr::null_mut::<libc::c_void>(),
+            )
+        };
+        if attached != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let thread = StoppedThread { tid };
+        let mut status = 0;
+        // Non-leader threads are only reported to waitpid with __WALL.
+        if unsafe { libc::waitpid(tid, &mut status, libc::__WALL) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(thread)
+    }
+
+    /// `(pc, sp, fp, lr)`; `lr` is 0 where return addresses live on the stack.
+    pub fn registers(&self) -> io::Result<(u64, u64, u64, u64)> {
+        let mut regs: libc::user_regs_struct = unsafe { std::mem::zeroed() };
+        let mut iov = libc::iovec {
+            iov_base: &mut regs as *mut _ as *mut libc::c_void,
+            iov_len: std::mem::size_of::<libc::user_regs_struct>(),
+        };
+        let ok = unsafe {
+            libc::ptrace(
+                libc::PTRACE_GETREGSET,
+                self.tid,
+                libc::NT_PRSTATUS as usize as *mut libc::c_void,
+                &mut iov as *mut _ as *mut libc::c_void,
+            )
+        };
+        if ok != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unpack_registers(&regs))
+    }
+
+    /// Return addresses of the stopped thread, innermost instruction pointer first, at
+    /// most `max_depth`.
+    pub fn native_ips(&self, memory: &ProcessMemory, max_depth: usize) -> io::Result<Vec<u64>> {
+        let (pc, sp, fp, lr) = self.registers()?;
+        let mut ips = vec![pc];
+        if lr != 0 {
+            ips.push(lr);
+        }
+        walk_frame_pointers(memory, fp, sp, &mut ips, max_depth);
+        Ok(ips)
+    }
+}
+
+impl Drop for StoppedThread {
+    fn drop(&mut self) {
+        unsafe {
+            libc::ptrace(
+                libc::PTRACE_DETACH,
+                self.tid,
+                std::ptr::null_mut::<libc::c_void>(),
+                std::ptr::null_mut::<libc::c_void>(),
+            )
+        };
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn unpack_registers(regs: &libc::user_regs_struct) -> (u64, u64, u64, u64) {
+    (regs.rip, regs.rsp, regs.rbp, 0)
+}
+
+#[cfg(target_arch = "aarch64")]
+fn unpack_registers(regs: &libc::user_regs_struct) -> (u64, u64, u64, u64) {
+    (regs.pc, regs.sp, regs.regs[29], regs.regs[30])
+}
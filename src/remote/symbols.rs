@@ -0,0 +1,97 @@
+//! ELF symbol tables of the modules mapped into a remote process.
+
+use std::fs;
+use std::io;
+
+use object::{Object, ObjectSegment, ObjectSymbol, SymbolKind};
+
+use super::maps::MemoryMap;
+
+/// Symbols of one mapped ELF file, with the load bias of its mapping in the target.
+#[derive(Debug)]
+pub struct ModuleSymbols {
+    pub path: String,
+    /// Runtime address minus link-time address.
+    pub bias: u64,
+    /// `(address, size, name)` at link-time addresses, sorted by address.
+    symbols: Vec<(u64, u64, String)>,
+}
+
+impl ModuleSymbols {
+    /// Load the symbols of `path` as mapped by process `pid`.
+    ///
+    /// The file is opened through `/proc/<pid>/root` so targets in other mount namespaces
+    /// (containers) resolve to their own files.
+    pub fn load(pid: i32, path: &str, maps: &[MemoryMap]) -> io::Result<ModuleSymbols> {
+        let data = fs::read(format!("/proc/{}/root{}", pid, path)).or_else(|_| fs::read(path))?;
+        let file = object::File::parse(&*data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+        let first_segment = file.segments().map(|s| s.address()).min().unwrap_or(0) & !0xfff;
+        let base = maps
+            .iter()
+            .filter(|m| m.path == path && m.offset == 0)
+            .map(|m| m.start)
+            .min()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "module is not mapped"))?;
+
+        let mut symbols: Vec<(u64, u64, String)> = file
+            .symbols()
+            .chain(file.dynamic_symbols())
+            .filter(|s| s.address() != 0 && matches!(s.kind(), SymbolKind::Text | SymbolKind::Data))
+            .filter_map(|s| Some((s.address(), s.size(), s.name().ok()?.to_string())))
+            .collect();
+        symbols.sort_unstable();
+        symbols.dedup_by(|a, b| a.0 == b.0 && a.2 == b.2);
+
+        Ok(ModuleSymbols {
+            path: path.to_string(),
+            bias: base.wrapping_sub(first_segment),
+            symbols,
+        })
+    }
+
+    /// Runtime address of symbol `name`.
+    pub fn address_of(&self, name: &str) -> Option<u64> {
+        self.symbols
+            .iter()
+            .find(|(_, _, n)| n == name)
+            .map(|(addr, _, _)| addr.wrapping_add(self.bias))
+    }
+
+    /// Symbol covering runtime address `addr`, with the offset into it.
+    pub fn lookup(&self, addr: u64) -> Option<(&str, u64)> {
+        let addr = addr.wrapping_sub(self.bias);
+        let i = self.symbols.partition_point(|(start, _, _)| *start <= addr);
+        let (start, size, name) = self.symbols.get(i.checked_sub(1)?)?;
+        // Zero-sized symbols (hand-written assembly) cover up to the next symbol.
+        if *size != 0 && addr >= start + size {
+            return None;
+        }
+        Some((name, addr - start))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::remote::maps::read_maps;
+
+    #[inline(never)]
+    fn local_marker() -> u64 {
+        std::hint::black_box(7)
+    }
+
+    #[test]
+    fn test_symbolize_own_executable() {
+        let pid = unsafe { libc::getpid() };
+        let maps = read_maps(pid).unwrap();
+        let exe = std::fs::read_link("/proc/self/exe").unwrap();
+        let symbols = ModuleSymbols::load(pid, exe.to_str().unwrap(), &maps).unwrap();
+
+        let addr = local_marker as *const () as u64;
+        let (name, offset) = symbols.lookup(addr + 1).unwrap();
+        assert!(name.contains("local_marker"), "{}", name);
+        assert_eq!(offset, 1);
+    }
+}
@@ -0,0 +1,523 @@
+//! Attaching to another process on Windows.
+//!
+//! Same model as on Linux: CPython state is read with `ReadProcessMemory`, and each
+//! thread is suspended while DbgHelp's `StackWalk64` unwinds it from its saved context.
+//! x64 code carries unwind tables, so no frame pointers are needed. Symbols and lines
+//! come from DbgHelp as well (exports, or PDBs found next to the modules or on
+//! `_NT_SYMBOL_PATH`). The caller needs `PROCESS_VM_READ`, `PROCESS_QUERY_INFORMATION`
+//! and thread suspend rights on the target, which a process of the same user has.
+
+use std::io;
+use std::os::windows::io::{AsRawHandle, FromRawHandle, OwnedHandle};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use windows_sys::Win32::Foundation::{LocalFree, HANDLE, INVALID_HANDLE_VALUE};
+use windows_sys::Win32::System::Diagnostics::Debug::{
+    SymCleanup, SymFromAddrW, SymGetLineFromAddrW64, SymInitializeW, SymSetOptions,
+    IMAGEHLP_LINEW64, SYMBOL_INFOW, SYMOPT_DEFERRED_LOADS, SYMOPT_LOAD_LINES, SYMOPT_UNDNAME,
+};
+use windows_sys::Win32::System::Diagnostics::ToolHelp::{
+    CreateToolhelp32Snapshot, Module32FirstW, Module32NextW, Thread32First, Thread32Next,
+    MODULEENTRY32W, TH32CS_SNAPMODULE, TH32CS_SNAPMODULE32, TH32CS_SNAPTHREAD, THREADENTRY32,
+};
+use windows_sys::Win32::System::Threading::{
+    GetCurrentProcessId, GetThreadDescription, OpenThread, ResumeThread, SuspendThread,
+    THREAD_GET_CONTEXT, THREAD_QUERY_LIMITED_INFORMATION, THREAD_SUSPEND_RESUME,
+};
+
+use super::cpython::{self, PythonThreads};
+use super::memory::ProcessMemory;
+use super::PythonRuntime;
+use crate::capture::MAX_COUNTED_FRAMES;
+use crate::error::TracerError;
+use crate::stack_trace::{CaptureSource, StackTrace};
+use crate::stack_tracer::SignalTracer;
+use crate::thread_stack::{ThreadId, ThreadStack, ThreadState};
+use crate::CallFrame;
+
+pub(crate) const MAX_DEPTH: usize = 256;
+
+/// Longest symbol name asked from DbgHelp, in UTF-16 units.
+const MAX_SYMBOL_NAME: usize = 1024;
+
+/// DbgHelp is single-threaded; every call into it holds this lock.
+static DBGHELP: Mutex<()> = Mutex::new(());
+
+/// A module mapped into the target.
+#[derive(Clone, Debug)]
+struct Module {
+    path: String,
+    base: u64,
+    size: u64,
+}
+
+/// A process inspected from the outside.
+#[derive(Debug)]
+pub struct RemoteProcess {
+    pid: i32,
+    memory: ProcessMemory,
+    modules: Vec<Module>,
+    python: Option<PythonRuntime>,
+}
+
+impl RemoteProcess {
+    /// Open process `pid`, load its module list and symbols and locate its CPython runtime.
+    ///
+    /// Succeeds for non-Python targets too; their stacks are then native only. The
+    /// calling process itself cannot be attached to.
+    pub fn attach(pid: i32) -> Result<RemoteProcess, TracerError> {
+        if pid as u32 == unsafe { GetCurrentProcessId() } {
+            return Err(TracerError::InvalidArgument(
+                "cannot attach to the calling process, use SignalTracer instead".into(),
+            ));
+        }
+        let capture = |err| TracerError::capture(pid, err);
+        let memory = ProcessMemory::open(pid as u32).map_err(capture)?;
+        let modules = list_modules(pid as u32).map_err(capture)?;
+        {
+            let _lock = DBGHELP.lock().unwrap_or_else(|e| e.into_inner());
+            unsafe {
+                SymSetOptions(SYMOPT_UNDNAME | SYMOPT_DEFERRED_LOADS | SYMOPT_LOAD_LINES);
+                if SymInitializeW(memory.handle(), std::ptr::null(), 1) == 0 {
+                    return Err(TracerError::Symbolication {
+                        module: "DbgHelp".into(),
+                        source: io::Error::last_os_error(),
+                    });
+                }
+            }
+        }
+        let mut process = RemoteProcess {
+            pid,
+            memory,
+            modules,
+            python: None,
+        };
+        process.python = process.find_python();
+        Ok(process)
+    }
+
+    pub fn pid(&self) -> i32 {
+        self.pid
+    }
+
+    /// `(major, minor)` of the CPython runtime found in the target, if any.
+    pub fn python_version(&self) -> Option<(u8, u8)> {
+        self.python.map(|p| p.offsets.version)
+    }
+
+    /// `_PyRuntime` is exported by `pythonXY.dll`, `Py_Version` too from 3.11 on.
+    fn find_python(&self) -> Option<PythonRuntime> {
+        self.modules.iter().find_map(|module| {
+            let from_name = version_from_dll_name(&module.path)?;
+            let exports = module_exports(&module.path, &["_PyRuntime", "Py_Version"]).ok()?;
+            let address = module.base + exports[0]?;
+            let version = exports[1]
+                .and_then(|rva| self.memory.read_u32(module.base + rva).ok())
+                .map(|hex| ((hex >> 24) as u8, (hex >> 16) as u8))
+                .unwrap_or(from_name);
+            Some(PythonRuntime {
+                offsets: cpython::runtime_offsets(&self.memory, version, address)?,
+                address,
+            })
+        })
+    }
+
+    /// Python stacks of all interpreter threads. The target keeps running, so stacks may be
+    /// torn.
+    pub fn python_stacks(&self) -> Result<PythonThreads, TracerError> {
+        let Some(python) = self.python else {
+            return Err(TracerError::Unsupported(
+                "no supported CPython runtime in the target".into(),
+            ));
+        };
+        cpython::thread_stacks(&self.memory, &python.offsets, python.address)
+            .map_err(|err| TracerError::capture(self.pid, err))
+    }
+
+    /// Native stack of thread `tid`, suspending it for the duration of the unwind. A stack
+    /// deeper than 256 frames ends with a `[truncated N frames]` marker, N counting up to
+    /// 1024 more frames.
+    pub fn native_stack(&self, tid: ThreadId) -> Result<StackTrace, TracerError> {
+        let capture = |err| TracerError::capture(self.pid, err);
+        let thread = SuspendedThread::open(tid as u32).map_err(capture)?;
+        let ips = self
+            .native_ips(&thread, MAX_DEPTH + MAX_COUNTED_FRAMES)
+            .map_err(capture)?;
+        drop(thread);
+        let mut trace = StackTrace {
+            frames: self.symbolize(&ips),
+            timestamp: Some(SystemTime::now()),
+            pid: Some(self.pid as u32),
+            tid: Some(tid),
+            truncated: false,
+            truncated_frames: 0,
+            source: CaptureSource::Remote,
+        };
+        trace.limit_frames(MAX_DEPTH);
+        Ok(trace)
+    }
+
+    /// Merged stacks of every thread, sorted by tid.
+    ///
+    /// All threads are suspended while contexts and interpreter state are read, so Python
+    /// and native stacks describe the same instant. Threads waiting for the GIL start
+    /// with a `[GIL wait]` frame.
+    pub fn dump(&self) -> Result<Vec<ThreadStack>, TracerError> {
+        let capture = |err| TracerError::capture(self.pid, err);
+        let mut tids = list_threads(self.pid as u32).map_err(capture)?;
+        tids.sort_unstable();
+        // Threads that exit before they can be suspended are skipped, but a target none of
+        // whose threads can be suspended is an error.
+        let mut refused = None;
+        let suspended: Vec<(u32, SuspendedThread)> = tids
+            .iter()
+            .filter_map(|tid| match SuspendedThread::open(*tid) {
+                Ok(thread) => Some((*tid, thread)),
+                Err(err) => {
+                    refused.get_or_insert(err);
+                    None
+                }
+            })
+            .collect();
+        if let (true, Some(err)) = (suspended.is_empty(), refused) {
+            return Err(capture(err));
+        }
+        let native: Vec<(u32, String, Vec<u64>)> = suspended
+            .iter()
+            .map(|(tid, thread)| {
+                let ips = self.native_ips(thread, MAX_DEPTH).unwrap_or_default();
+                (*tid, thread.description(), ips)
+            })
+            .collect();
+        let python = match self.python {
+            Some(_) => self.python_stacks(),
+            None => Ok(PythonThreads::default()),
+        };
+        drop(suspended);
+        let mut python = python?;
+
+        Ok(native
+            .into_iter()
+            .map(|(tid, name, ips)| {
+                let tid = tid as ThreadId;
+                let mut stack = ThreadStack {
+                    tid,
+                    name,
+                    os_state: ThreadState::Unknown,
+                    is_gil_holder: python.gil_holder == Some(tid),
+                    greenlet: false,
+                    interpreter_id: python.interpreters.get(&tid).copied(),
+                    frames: SignalTracer::merge_python_native_stacks(
+                        python.stacks.remove(&tid).unwrap_or_default(),
+                        self.symbolize(&ips),
+                    ),
+                };
+                if !python.gil_disabled {
+                    stack.annotate_gil_wait();
+                }
+                stack
+            })
+            .collect())
+    }
+
+    /// Unwind a suspended thread from its saved context, at most `max_depth` frames.
+    #[cfg(target_arch = "x86_64")]
+    fn native_ips(&self, thread: &SuspendedThread, max_depth: usize) -> io::Result<Vec<u64>> {
+        use windows_sys::Win32::System::Diagnostics::Debug::{
+            AddrModeFlat, GetThreadContext, StackWalk64, SymFunctionTableAccess64,
+            SymGetModuleBase64, CONTEXT, CONTEXT_FULL_AMD64, STACKFRAME64,
+        };
+        use windows_sys::Win32::System::SystemInformation::IMAGE_FILE_MACHINE_AMD64;
+
+        /// `GetThreadContext` wants a 16-byte aligned record.
+        #[repr(C, align(16))]
+        struct AlignedContext(CONTEXT);
+
+        let mut context: AlignedContext = unsafe { std::mem::zeroed() };
+        context.0.ContextFlags = CONTEXT_FULL_AMD64;
+        if unsafe { GetThreadContext(thread.handle(), &mut context.0) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut frame: STACKFRAME64 = unsafe { std::mem::zeroed() };
+        frame.AddrPC.Offset = context.0.Rip;
+        frame.AddrPC.Mode = AddrModeFlat;
+        frame.AddrStack.Offset = context.0.Rsp;
+        frame.AddrStack.Mode = AddrModeFlat;
+        frame.AddrFrame.Offset = context.0.Rbp;
+        frame.AddrFrame.Mode = AddrModeFlat;
+
+        let _lock = DBGHELP.lock().unwrap_or_else(|e| e.into_inner());
+        let mut ips = Vec::new();
+        while ips.len() < max_depth {
+            let walked = unsafe {
+                StackWalk64(
+                    IMAGE_FILE_MACHINE_AMD64 as u32,
+                    self.memory.handle(),
+                    thread.handle(),
+                    &mut frame,
+                    &mut context.0 as *mut CONTEXT as *mut std::ffi::c_void,
+                    None,
+                    Some(SymFunctionTableAccess64),
+                    Some(SymGetModuleBase64),
+                    None,
+                )
+            };
+            if walked == 0 || frame.AddrPC.Offset == 0 {
+                break;
+            }
+            ips.push(frame.AddrPC.Offset);
+        }
+        Ok(ips)
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn native_ips(&self, _thread: &SuspendedThread, _max_depth: usize) -> io::Result<Vec<u64>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "remote native unwinding is only implemented for x86_64",
+        ))
+    }
+
+    /// Resolve raw ips through DbgHelp.
+    ///
+    /// Every ip but the first is a return address, so the call instruction itself is
+    /// looked up one byte earlier.
+    fn symbolize(&self, ips: &[u64]) -> Vec<CallFrame> {
+        let _lock = DBGHELP.lock().unwrap_or_else(|e| e.into_inner());
+        let mut frames: Vec<CallFrame> = ips
+            .iter()
+            .enumerate()
+            .map(|(i, ip)| {
+                let lookup = if i == 0 { *ip } else { ip.wrapping_sub(1) };
+                let module = self
+                    .modules
+                    .iter()
+                    .find(|m| m.base <= lookup && lookup < m.base + m.size);
+                let func = self.symbol_name(lookup).unwrap_or_else(|| "??".to_string());
+                let (file, lineno) = self
+                    .line(lookup)
+                    .unwrap_or_else(|| (module.map_or_else(String::new, |m| m.path.clone()), 0));
+                CallFrame::native(format!("{:#x}", ip), file, func, lineno)
+            })
+            .collect();
+        // Exports of MinGW-built modules keep Itanium-mangled names.
+        SignalTracer::demangle_frames(&mut frames);
+        frames
+    }
+
+    fn symbol_name(&self, addr: u64) -> Option<String> {
+        // SYMBOL_INFOW followed by room for the name.
+        #[repr(C)]
+        struct Buffer {
+            info: SYMBOL_INFOW,
+            name: [u16; MAX_SYMBOL_NAME],
+        }
+        let mut buffer: Buffer = unsafe { std::mem::zeroed() };
+        buffer.info.SizeOfStruct = std::mem::size_of::<SYMBOL_INFOW>() as u32;
+        buffer.info.MaxNameLen = MAX_SYMBOL_NAME as u32;
+        let mut displacement = 0;
+        if unsafe {
+            SymFromAddrW(
+                self.memory.handle(),
+                addr,
+                &mut displacement,
+                &mut buffer.info,
+            )
+        } == 0
+        {
+            return None;
+        }
+        let len = (buffer.info.NameLen as usize).min(MAX_SYMBOL_NAME);
+        let name = unsafe { std::slice::from_raw_parts(buffer.info.Name.as_ptr(), len) };
+        Some(String::from_utf16_lossy(name))
+    }
+
+    fn line(&self, addr: u64) -> Option<(String, i64)> {
+        let mut line: IMAGEHLP_LINEW64 = unsafe { std::mem::zeroed() };
+        line.SizeOfStruct = std::mem::size_of::<IMAGEHLP_LINEW64>() as u32;
+        let mut displacement = 0;
+        if unsafe {
+            SymGetLineFromAddrW64(self.memory.handle(), addr, &mut displacement, &mut line)
+        } == 0
+        {
+            return None;
+        }
+        let file = unsafe { wide_c_str(line.FileName) };
+        Some((file, i64::from(line.LineNumber)))
+    }
+}
+
+impl Drop for RemoteProcess {
+    fn drop(&mut self) {
+        let _lock = DBGHELP.lock().unwrap_or_else(|e| e.into_inner());
+        unsafe { SymCleanup(self.memory.handle()) };
+    }
+}
+
+/// A thread held with `SuspendThread`; resumed again on drop.
+struct SuspendedThread {
+    handle: OwnedHandle,
+}
+
+impl SuspendedThread {
+    fn open(tid: u32) -> io::Result<SuspendedThread> {
+        let access = THREAD_SUSPEND_RESUME | THREAD_GET_CONTEXT | THREAD_QUERY_LIMITED_INFORMATION;
+        let handle = unsafe { OpenThread(access, 0, tid) };
+        if handle.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        let handle = unsafe { OwnedHandle::from_raw_handle(handle) };
+        if unsafe { SuspendThread(handle.as_raw_handle()) } == u32::MAX {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(SuspendedThread { handle })
+    }
+
+    fn handle(&self) -> HANDLE {
+        self.handle.as_raw_handle()
+    }
+
+    /// Name set with `SetThreadDescription`, empty when there is none.
+    fn description(&self) -> String {
+        let mut name = std::ptr::null_mut();
+        if unsafe { GetThreadDescription(self.handle(), &mut name) } < 0 || name.is_null() {
+            return String::new();
+        }
+        let description = unsafe { wide_c_str(name) };
+        unsafe { LocalFree(name as _) };
+        description
+    }
+}
+
+impl Drop for SuspendedThread {
+    fn drop(&mut self) {
+        unsafe { ResumeThread(self.handle()) };
+    }
+}
+
+/// A Toolhelp snapshot of `pid`, closed on drop.
+fn snapshot(flags: u32, pid: u32) -> io::Result<OwnedHandle> {
+    let handle = unsafe { CreateToolhelp32Snapshot(flags, pid) };
+    if handle == INVALID_HANDLE_VALUE {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { OwnedHandle::from_raw_handle(handle) })
+}
+
+fn list_threads(pid: u32) -> io::Result<Vec<u32>> {
+    // Thread snapshots always cover the whole system.
+    let snapshot = snapshot(TH32CS_SNAPTHREAD, 0)?;
+    let mut entry: THREADENTRY32 = unsafe { std::mem::zeroed() };
+    entry.dwSize = std::mem::size_of::<THREADENTRY32>() as u32;
+    let mut tids = Vec::new();
+    let mut more = unsafe { Thread32First(snapshot.as_raw_handle(), &mut entry) } != 0;
+    while more {
+        if entry.th32OwnerProcessID == pid {
+            tids.push(entry.th32ThreadID);
+        }
+        more = unsafe { Thread32Next(snapshot.as_raw_handle(), &mut entry) } != 0;
+    }
+    Ok(tids)
+}
+
+fn list_modules(pid: u32) -> io::Result<Vec<Module>> {
+    let snapshot = snapshot(TH32CS_SNAPMODULE | TH32CS_SNAPMODULE32, pid)?;
+    let mut entry: MODULEENTRY32W = unsafe { std::mem::zeroed() };
+    entry.dwSize = std::mem::size_of::<MODULEENTRY32W>() as u32;
+    let mut modules = Vec::new();
+    let mut more = unsafe { Module32FirstW(snapshot.as_raw_handle(), &mut entry) } != 0;
+    while more {
+        let len = entry
+            .szExePath
+            .iter()
+            .position(|c| *c == 0)
+            .unwrap_or(entry.szExePath.len());
+        modules.push(Module {
+            path: String::from_utf16_lossy(&entry.szExePath[..len]),
+            base: entry.modBaseAddr as u64,
+            size: u64::from(entry.modBaseSize),
+        });
+        more = unsafe { Module32NextW(snapshot.as_raw_handle(), &mut entry) } != 0;
+    }
+    Ok(modules)
+}
+
+/// Image-relative addresses of the exports `names` of the PE file at `path`.
+fn module_exports(path: &str, names: &[&str]) -> io::Result<Vec<Option<u64>>> {
+    use object::read::ReadCache;
+    use object::Object;
+
+    let invalid = |e: object::Error| io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+    let cache = ReadCache::new(std::fs::File::open(path)?);
+    let file = object::File::parse(&cache).map_err(invalid)?;
+    let exports = file.exports().map_err(invalid)?;
+    let base = file.relative_address_base();
+    Ok(names
+        .iter()
+        .map(|name| {
+            exports
+                .iter()
+                .find(|e| e.name() == name.as_bytes())
+                .map(|e| e.address() - base)
+        })
+        .collect())
+}
+
+/// `(3, 11)` from `C:\Python311\python311.dll`; the stable-ABI `python3.dll` has none.
+fn version_from_dll_name(path: &str) -> Option<(u8, u8)> {
+    let name = path.rsplit(['\\', '/']).next()?.to_ascii_lowercase();
+    let digits = name.strip_prefix("python")?.strip_suffix(".dll")?;
+    // Debug builds are `python311_d.dll`, free-threaded ones `python313t.dll`.
+    let digits = digits.strip_suffix("_d").unwrap_or(digits);
+    let digits = digits.strip_suffix('t').unwrap_or(digits);
+    if digits.len() < 2 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((digits[..1].parse().ok()?, digits[1..].parse().ok()?))
+}
+
+/// Copy a NUL-terminated UTF-16 string.
+unsafe fn wide_c_str(ptr: *const u16) -> String {
+    if ptr.is_null() {
+        return String::new();
+    }
+    let mut len = 0;
+    while *ptr.add(len) != 0 {
+        len += 1;
+    }
+    String::from_utf16_lossy(std::slice::from_raw_parts(ptr, len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_from_dll_name() {
+        assert_eq!(
+            version_from_dll_name(r"C:\Python311\python311.dll"),
+            Some((3, 11))
+        );
+        assert_eq!(version_from_dll_name("python38_d.dll"), Some((3, 8)));
+        assert_eq!(version_from_dll_name("python313t.dll"), Some((3, 13)));
+        assert_eq!(version_from_dll_name(r"C:\Python311\python3.dll"), None);
+        assert_eq!(version_from_dll_name("kernel32.dll"), None);
+    }
+
+    #[test]
+    fn test_attach_rejects_self() {
+        let pid = unsafe { GetCurrentProcessId() } as i32;
+        assert!(RemoteProcess::attach(pid).is_err());
+    }
+
+    #[test]
+    fn test_list_own_threads_and_modules() {
+        let pid = unsafe { GetCurrentProcessId() };
+        assert!(!list_threads(pid).unwrap().is_empty());
+        assert!(list_modules(pid)
+            .unwrap()
+            .iter()
+            .any(|m| m.path.to_ascii_lowercase().ends_with("kernel32.dll")));
+    }
+}
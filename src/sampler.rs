@@ -0,0 +1,920 @@
+//! SIGPROF-driven sampling profiler producing merged Python + native stacks.
+//!
+//! The signal handler only records raw instruction pointers into a fixed ring of slots.
+//! A collector thread drains the ring, symbolizes the ips, asks the optional Python stack
+//! provider for the interpreter stacks, merges both and aggregates identical stacks.
+//! The provider runs once per drained batch, up to `DRAIN_INTERVAL` after the ticks it
+//! serves, so a merged Python stack is where the thread was then rather than at the tick:
+//! such samples end in a `[python stack approximate]` root frame of category
+//! `APPROXIMATE_PYTHON_CATEGORY`.
+//! Every tick stands for one timer interval of CPU time, the weight of its sample.
+//!
+//! In wall-clock mode (`Sampler::start_wall_clock`) a ticker thread signals every thread
+//! instead, running or not, so that blocked threads show where they wait: their samples are
+//! off-CPU and their leaf is a synthetic frame naming the system call (`threads::blocked_in`),
+//! `[off-cpu]` when it is unknown.
+//! The signal interrupts the blocking call; most are restarted (`SA_RESTART`), the others
+//! return `EINTR`, which CPython and the Rust standard library retry.
+//!
+//! Sampling pauses while the process forks, and a forked child does not sample: its copy of
+//! the `Sampler` is inert and stopping it returns an empty profile (see `crate::fork`).
+
+use std::cell::UnsafeCell;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{
+    AtomicBool, AtomicI32, AtomicI64, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering,
+};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use crate::capture::{resolve_ip, trace_signal_context};
+use crate::events;
+use crate::fork::{self, BackgroundThread};
+use crate::profile::{Profile, SampleState, StackAggregator, TimedSample};
+use crate::signal_cell::SignalCell;
+use crate::stack_trace::truncation_marker;
+use crate::stack_tracer::SignalTracer;
+use crate::threads;
+use crate::unwind::{unwind_signal_context, UnwindStrategy, UnwindTable};
+use crate::CallFrame;
+
+const MAX_DEPTH: usize = 128;
+const RING_CAPACITY: usize = 256;
+const DRAIN_INTERVAL: Duration = Duration::from_millis(10);
+
+const SLOT_EMPTY: u8 = 0;
+const SLOT_WRITING: u8 = 1;
+const SLOT_READY: u8 = 2;
+
+/// Category of the root frame of samples merged with a Python stack snapshot taken after
+/// the tick, which may have moved on since.
+pub const APPROXIMATE_PYTHON_CATEGORY: &str = "python-approximate";
+
+/// Snapshot of the Python stacks of all threads, keyed by native thread id.
+///
+/// Called from the collector thread once per drained batch of samples, every 10 ms: all
+/// samples of a batch get the snapshot taken after their ticks, and are marked as
+/// approximate (see the module documentation).
+pub type PythonStacksProvider = Box<dyn FnMut() -> HashMap<i32, Vec<CallFrame>> + Send>;
+
+/// Called by the collector thread with every merged sample, timestamps on
+/// `CLOCK_MONOTONIC` as taken in the signal handler.
+pub(crate) type SampleObserver = Box<dyn FnMut(&TimedSample) + Send>;
+
+struct RawSample {
+    state: AtomicU8,
+    tid: AtomicI32,
+    time_ns: AtomicU64,
+    /// `NO_CPU` when `sched_getcpu` failed.
+    cpu: AtomicU32,
+    /// `WallTag` bits of wall-clock ticks, 0 for CPU timer ticks.
+    tag: AtomicU32,
+    /// Frames stored, frames past `MAX_DEPTH` only counted, and the stored ips.
+    data: UnsafeCell<(usize, usize, [usize; MAX_DEPTH])>,
+}
+
+// Slot payloads are only accessed by whoever moved `state` out of EMPTY/READY.
+unsafe impl Sync for RawSample {}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SAMPLE: RawSample = RawSample {
+    state: AtomicU8::new(SLOT_EMPTY),
+    tid: AtomicI32::new(0),
+    time_ns: AtomicU64::new(0),
+    cpu: AtomicU32::new(NO_CPU),
+    tag: AtomicU32::new(0),
+    data: UnsafeCell::new((0, 0, [0; MAX_DEPTH])),
+};
+
+const NO_CPU: u32 = u32::MAX;
+/// Threads the wall-clock ticker tags per tick; the others are still sampled, untagged.
+const MAX_WALL_THREADS: usize = 1024;
+/// Category of the synthetic leaf of off-CPU samples.
+pub const OFF_CPU_CATEGORY: &str = "off-cpu";
+
+static RING: [RawSample; RING_CAPACITY] = [EMPTY_SAMPLE; RING_CAPACITY];
+static NEXT_SLOT: AtomicUsize = AtomicUsize::new(0);
+static DROPPED: AtomicU64 = AtomicU64::new(0);
+static RUNNING: AtomicBool = AtomicBool::new(false);
+/// Unwinding of the running session; only replaced while no sampler is running, so the
+/// handler reads it unsynchronized. `None` is DWARF unwinding.
+static UNWIND: SignalCell<Option<(UnwindStrategy, UnwindTable)>> = SignalCell::new(None);
+/// Whether the handler looks its tag up in `WALL_TAGS`.
+static WALL_CLOCK: AtomicBool = AtomicBool::new(false);
+/// `(tid, tag)` of the threads signalled by the last wall-clock tick.
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_WALL_TAG: (AtomicI32, AtomicU32) = (AtomicI32::new(0), AtomicU32::new(0));
+static WALL_TAGS: [(AtomicI32, AtomicU32); MAX_WALL_THREADS] = [NO_WALL_TAG; MAX_WALL_THREADS];
+/// Serializes installing and removing the handler with the fork handlers.
+static SESSION: Mutex<()> = Mutex::new(());
+/// Tick interval of the session whose handler is installed, 0 when none is.
+static ARMED_INTERVAL_US: AtomicI64 = AtomicI64::new(0);
+/// SIGPROF disposition before the installed handler; serialized by `SESSION`.
+static PREVIOUS_ACTION: SignalCell<libc::sigaction> =
+    SignalCell::new(unsafe { std::mem::zeroed() });
+/// Set while the process forks: the wall-clock ticker signals no one.
+static FORK_PAUSED: AtomicBool = AtomicBool::new(false);
+/// Held by the collector while it symbolizes, so that no fork happens with the
+/// symbolizer's own lock taken by a thread the child will not have.
+static SYMBOLIZING: Mutex<()> = Mutex::new(());
+/// `SESSION` and `SYMBOLIZING`, held by the forking thread between the fork handlers.
+static FORK_GUARD: SignalCell<Option<ForkGuard>> = SignalCell::new(None);
+
+type ForkGuard = (MutexGuard<'static, ()>, MutexGuard<'static, ()>);
+
+/// Serializes tests that start a sampler, as only one can run per process.
+#[cfg(test)]
+pub(crate) static TEST_SAMPLER_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// What drives the ticks of a sampler.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Clock {
+    /// `ITIMER_PROF`: threads are sampled in proportion to the CPU time they use.
+    Cpu,
+    /// A ticker thread signals every thread at each tick of wall-clock time.
+    Wall,
+}
+
+/// Wall-clock tick of a thread, packed into `RawSample::tag`: bit 0 is always set, bit 1
+/// marks a blocked thread and the rest is the index + 1 of what it is blocked in, 0 if
+/// unknown.
+struct WallTag;
+
+impl WallTag {
+    const TICK: u32 = 1;
+    const OFF_CPU: u32 = 2;
+    const LABEL_SHIFT: u32 = 2;
+}
+
+/// Labels of `WallTag`s, interned by the ticker.
+type WallLabels = Arc<Mutex<Vec<String>>>;
+
+/// A running sampling session. Only one sampler can be active per process.
+pub struct Sampler {
+    stop: Arc<AtomicBool>,
+    collector: Option<BackgroundThread<Profile>>,
+    /// Wall-clock ticker and its own stop flag, stopped before the handler is removed.
+    ticker: Option<(Arc<AtomicBool>, BackgroundThread<()>)>,
+}
+
+impl Sampler {
+    /// Start sampling native stacks of the process at `freq_hz` ticks of CPU time.
+    pub fn start(freq_hz: u32) -> io::Result<Sampler> {
+        Self::start_with_unwind(freq_hz, UnwindStrategy::Dwarf, None)
+    }
+
+    /// Start sampling, merging each native sample with the Python stack of the same thread
+    /// as `provider` reports it when the sample is drained, not at the tick itself.
+    pub fn start_with_python(freq_hz: u32, provider: PythonStacksProvider) -> io::Result<Sampler> {
+        Self::start_with_unwind(freq_hz, UnwindStrategy::Dwarf, Some(provider))
+    }
+
+    /// Start sampling with native stacks walked by `strategy` (see `UnwindStrategy`),
+    /// optionally merged with Python stacks. Frame-pointer walking keeps the cost per
+    /// tick low enough for high sampling rates.
+    pub fn start_with_unwind(
+        freq_hz: u32,
+        strategy: UnwindStrategy,
+        provider: Option<PythonStacksProvider>,
+    ) -> io::Result<Sampler> {
+        Self::start_observed(freq_hz, strategy, provider, None)
+    }
+
+    /// Sample every thread at `freq_hz` ticks of wall-clock time, whether it runs or is
+    /// blocked, for profiles of where time goes rather than CPU. Samples of threads in the
+    /// `S` or `D` state are `SampleState::OffCpu`, with a leaf frame of category
+    /// `OFF_CPU_CATEGORY` such as `[futex]`; all weigh one tick interval. Signals interrupt
+    /// blocking calls (see the module documentation). Up to 1024 threads are tagged per tick.
+    pub fn start_wall_clock(
+        freq_hz: u32,
+        strategy: UnwindStrategy,
+        provider: Option<PythonStacksProvider>,
+    ) -> io::Result<Sampler> {
+        Self::start_inner(freq_hz, Clock::Wall, strategy, provider, None)
+    }
+
+    /// `start_with_unwind`, also handing every merged sample to `observer`.
+    pub(crate) fn start_observed(
+        freq_hz: u32,
+        strategy: UnwindStrategy,
+        provider: Option<PythonStacksProvider>,
+        observer: Option<SampleObserver>,
+    ) -> io::Result<Sampler> {
+        Self::start_inner(freq_hz, Clock::Cpu, strategy, provider, observer)
+    }
+
+    fn start_inner(
+        freq_hz: u32,
+        clock: Clock,
+        strategy: UnwindStrategy,
+        provider: Option<PythonStacksProvider>,
+        observer: Option<SampleObserver>,
+    ) -> io::Result<Sampler> {
+        if freq_hz == 0 || freq_hz > 1_000_000 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "sampling frequency must be within 1..=1000000 Hz",
+            ));
+        }
+        fork::register_handlers();
+        if RUNNING.swap(true, Ordering::SeqCst) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "a sampler is already running",
+            ));
+        }
+
+        let unwind = match strategy {
+            UnwindStrategy::Dwarf => None,
+            _ => match UnwindTable::current() {
+                Ok(table) => Some((strategy, table)),
+                Err(err) => {
+                    RUNNING.store(false, Ordering::SeqCst);
+                    return Err(err);
+                }
+            },
+        };
+        // No handler is installed while RUNNING was clear.
+        unsafe { *UNWIND.get() = unwind };
+        reset_ring();
+        WALL_CLOCK.store(clock == Clock::Wall, Ordering::SeqCst);
+
+        let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+        action.sa_sigaction = handle_sigprof as *const () as libc::sighandler_t;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+        unsafe { libc::sigemptyset(&mut action.sa_mask) };
+
+        let session = SESSION.lock().unwrap_or_else(|e| e.into_inner());
+        let previous_action = PREVIOUS_ACTION.get();
+        if unsafe { libc::sigaction(libc::SIGPROF, &action, previous_action) } != 0 {
+            RUNNING.store(false, Ordering::SeqCst);
+            return Err(io::Error::last_os_error());
+        }
+
+        let interval_us = 1_000_000 / freq_hz as i64;
+        let labels = WallLabels::default();
+        let collector_tid = Arc::new(AtomicI32::new(0));
+        let aggregator = Aggregator {
+            observer,
+            weight_ns: interval_us as u64 * 1_000,
+            labels: Arc::clone(&labels),
+            ..Aggregator::default()
+        };
+        let stop = Arc::new(AtomicBool::new(false));
+        let collector = {
+            let (stop, collector_tid) = (Arc::clone(&stop), Arc::clone(&collector_tid));
+            thread::Builder::new()
+                .name("mst-sampler".to_string())
+                .spawn(move || {
+                    collector_tid.store(threads::current_tid(), Ordering::SeqCst);
+                    collect(stop, aggregator, provider)
+                })
+        };
+        let collector = match collector {
+            Ok(handle) => BackgroundThread::new(handle),
+            Err(err) => {
+                unsafe { libc::sigaction(libc::SIGPROF, previous_action, std::ptr::null_mut()) };
+                RUNNING.store(false, Ordering::SeqCst);
+                return Err(err);
+            }
+        };
+        ARMED_INTERVAL_US.store(interval_us, Ordering::SeqCst);
+        drop(session);
+
+        let mut sampler = Sampler {
+            stop,
+            collector: Some(collector),
+            ticker: None,
+        };
+        match clock {
+            Clock::Cpu => set_timer(interval_us)?,
+            Clock::Wall => {
+                let stop = Arc::new(AtomicBool::new(false));
+                let interval = Duration::from_micros(interval_us as u64);
+                let ticker = {
+                    let stop = Arc::clone(&stop);
+                    thread::Builder::new()
+                        .name("mst-wall-clock".to_string())
+                        .spawn(move || tick_wall_clock(stop, interval, collector_tid, labels))?
+                };
+                sampler.ticker = Some((stop, BackgroundThread::new(ticker)));
+            }
+        }
+        Ok(sampler)
+    }
+
+    /// Stop sampling and return the aggregated profile.
+    pub fn stop(mut self) -> Profile {
+        self.shutdown()
+    }
+
+    /// Whether this is the copy a forked child inherited, which samples nothing.
+    pub(crate) fn is_forked(&self) -> bool {
+        self.collector
+            .as_ref()
+            .is_some_and(BackgroundThread::is_forked)
+    }
+
+    fn shutdown(&mut self) -> Profile {
+        if self.is_forked() {
+            // The child handler already removed the parent's session.
+            return Profile::default();
+        }
+        let Some(collector) = self.collector.take() else {
+            return Profile::default();
+        };
+
+        {
+            let _session = SESSION.lock().unwrap_or_else(|e| e.into_inner());
+            ARMED_INTERVAL_US.store(0, Ordering::SeqCst);
+            // Disarm first so no tick arrives once the default disposition is back.
+            let _ = set_timer(0);
+            if let Some((stop, ticker)) = self.ticker.take() {
+                stop.store(true, Ordering::SeqCst);
+                let _ = ticker.join();
+                // A thread blocked in `D` state may still have its signal pending: ignoring
+                // the signal discards it before the previous disposition, often the fatal
+                // default, is back.
+                let mut ignore: libc::sigaction = unsafe { std::mem::zeroed() };
+                ignore.sa_sigaction = libc::SIG_IGN;
+                unsafe { libc::sigaction(libc::SIGPROF, &ignore, std::ptr::null_mut()) };
+            }
+            unsafe { libc::sigaction(libc::SIGPROF, PREVIOUS_ACTION.get(), std::ptr::null_mut()) };
+        }
+
+        self.stop.store(true, Ordering::SeqCst);
+        let mut profile = collector.join().unwrap_or_default();
+        profile.dropped_samples = DROPPED.load(Ordering::SeqCst);
+        RUNNING.store(false, Ordering::SeqCst);
+        events::profile_recorded(&profile);
+        profile
+    }
+}
+
+impl Drop for Sampler {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+fn set_timer(interval_us: i64) -> io::Result<()> {
+    let interval = libc::timeval {
+        tv_sec: (interval_us / 1_000_000) as libc::time_t,
+        tv_usec: (interval_us % 1_000_000) as libc::suseconds_t,
+    };
+    let timer = libc::itimerval {
+        it_interval: interval,
+        it_value: interval,
+    };
+    if unsafe { libc::setitimer(libc::ITIMER_PROF, &timer, std::ptr::null_mut()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Fork prepare handler: hold `SESSION`, wait for the collector to be done symbolizing and
+/// stop ticks until the fork returns.
+pub(crate) fn pause_for_fork() {
+    let session = SESSION.lock().unwrap_or_else(|e| e.into_inner());
+    let symbolizing = SYMBOLIZING.lock().unwrap_or_else(|e| e.into_inner());
+    if ARMED_INTERVAL_US.load(Ordering::SeqCst) != 0 {
+        FORK_PAUSED.store(true, Ordering::SeqCst);
+        if !WALL_CLOCK.load(Ordering::SeqCst) {
+            let _ = set_timer(0);
+        }
+    }
+    // Only the forking thread, between the fork handlers, touches it.
+    unsafe { *FORK_GUARD.get() = Some((session, symbolizing)) };
+}
+
+/// Fork parent handler: resume the ticks paused by `pause_for_fork`.
+pub(crate) fn resume_after_fork() {
+    let interval_us = ARMED_INTERVAL_US.load(Ordering::SeqCst);
+    if interval_us != 0 && !WALL_CLOCK.load(Ordering::SeqCst) {
+        let _ = set_timer(interval_us);
+    }
+    FORK_PAUSED.store(false, Ordering::SeqCst);
+    unsafe { (*FORK_GUARD.get()).take() };
+}
+
+/// Fork child handler: drop the parent's session, whose collector and ticker did not
+/// follow. The timer is not inherited; the handler is replaced by the previous disposition.
+pub(crate) fn disable_in_child() {
+    if ARMED_INTERVAL_US.swap(0, Ordering::SeqCst) != 0 {
+        unsafe { libc::sigaction(libc::SIGPROF, PREVIOUS_ACTION.get(), std::ptr::null_mut()) };
+        WALL_CLOCK.store(false, Ordering::SeqCst);
+        reset_ring();
+    }
+    FORK_PAUSED.store(false, Ordering::SeqCst);
+    // No thread of the child is starting or stopping a sampler.
+    RUNNING.store(false, Ordering::SeqCst);
+    unsafe { (*FORK_GUARD.get()).take() };
+}
+
+fn reset_ring() {
+    for slot in RING.iter() {
+        slot.state.store(SLOT_EMPTY, Ordering::SeqCst);
+    }
+    NEXT_SLOT.store(0, Ordering::SeqCst);
+    DROPPED.store(0, Ordering::SeqCst);
+    for (tid, _) in WALL_TAGS.iter() {
+        tid.store(0, Ordering::SeqCst);
+    }
+}
+
+/// Body of the wall-clock ticker: tag then signal every thread of the process but the
+/// ticker and the collector, once per `interval`.
+fn tick_wall_clock(
+    stop: Arc<AtomicBool>,
+    interval: Duration,
+    collector_tid: Arc<AtomicI32>,
+    labels: WallLabels,
+) {
+    let pid = std::process::id() as libc::pid_t;
+    let own_tid = threads::current_tid();
+    let mut label_index: HashMap<String, u32> = HashMap::new();
+    while !stop.load(Ordering::SeqCst) {
+        if FORK_PAUSED.load(Ordering::SeqCst) {
+            thread::sleep(interval);
+            continue;
+        }
+        let started = std::time::Instant::now();
+        let skipped = [own_tid, collector_tid.load(Ordering::SeqCst)];
+        let tids = threads::list_threads().unwrap_or_default();
+        let mut slots = WALL_TAGS.iter();
+        for tid in tids.into_iter().filter(|t| !skipped.contains(t)) {
+            let blocked = threads::thread_state(tid).is_ok_and(|s| s.is_blocked());
+            let mut tag = WallTag::TICK;
+            if blocked {
+                tag |= WallTag::OFF_CPU;
+                if let Some(label) = threads::blocked_in(tid) {
+                    let next = label_index.len() as u32 + 1;
+                    let index = *label_index.entry(label.clone()).or_insert_with(|| {
+                        labels.lock().unwrap_or_else(|e| e.into_inner()).push(label);
+                        next
+                    });
+                    tag |= index << WallTag::LABEL_SHIFT;
+                }
+            }
+            if let Some((slot_tid, slot_tag)) = slots.next() {
+                slot_tag.store(tag, Ordering::Relaxed);
+                slot_tid.store(tid, Ordering::Release);
+            }
+            unsafe { libc::syscall(libc::SYS_tgkill, pid, tid, libc::SIGPROF) };
+        }
+        for (slot_tid, _) in slots {
+            slot_tid.store(0, Ordering::Relaxed);
+        }
+        thread::sleep(interval.saturating_sub(started.elapsed()));
+    }
+}
+
+/// Tag the ticker left for thread `tid`; async-signal-safe.
+fn wall_tag(tid: i32) -> u32 {
+    WALL_TAGS
+        .iter()
+        .find(|(slot_tid, _)| slot_tid.load(Ordering::Acquire) == tid)
+        .map_or(WallTag::TICK, |(_, tag)| tag.load(Ordering::Relaxed))
+}
+
+extern "C" fn handle_sigprof(
+    _sig: libc::c_int,
+    _info: *mut libc::siginfo_t,
+    context: *mut libc::c_void,
+) {
+    let saved_errno = unsafe { *libc::__errno_location() };
+
+    let slot = &RING[NEXT_SLOT.fetch_add(1, Ordering::Relaxed) % RING_CAPACITY];
+    if slot
+        .state
+        .compare_exchange(
+            SLOT_EMPTY,
+            SLOT_WRITING,
+            Ordering::Acquire,
+            Ordering::Relaxed,
+        )
+        .is_ok()
+    {
+        let tid = unsafe { libc::syscall(libc::SYS_gettid) } as i32;
+        slot.tid.store(tid, Ordering::Relaxed);
+        let tag = if WALL_CLOCK.load(Ordering::Relaxed) {
+            wall_tag(tid)
+        } else {
+            0
+        };
+        slot.tag.store(tag, Ordering::Relaxed);
+        slot.time_ns.store(monotonic_ns(), Ordering::Relaxed);
+        // Read from rseq or the vDSO, no lock taken.
+        let cpu = unsafe { libc::sched_getcpu() };
+        slot.cpu
+            .store(u32::try_from(cpu).unwrap_or(NO_CPU), Ordering::Relaxed);
+        let data = unsafe { &mut *slot.data.get() };
+        let handler = handle_sigprof as *const ();
+        let walked = match unsafe { &*UNWIND.get() } {
+            Some((strategy, table)) => unsafe {
+                unwind_signal_context(*strategy, table, context, handler, &mut data.2)
+            },
+            None => unsafe { trace_signal_context(handler, &mut data.2) },
+        };
+        data.0 = walked.min(MAX_DEPTH);
+        data.1 = walked - data.0;
+        slot.state.store(SLOT_READY, Ordering::Release);
+    } else {
+        DROPPED.fetch_add(1, Ordering::Relaxed);
+    }
+
+    unsafe { *libc::__errno_location() = saved_errno };
+}
+
+/// `CLOCK_MONOTONIC` in nanoseconds; async-signal-safe.
+pub(crate) fn monotonic_ns() -> u64 {
+    let mut now: libc::timespec = unsafe { std::mem::zeroed() };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    now.tv_sec as u64 * 1_000_000_000 + now.tv_nsec as u64
+}
+
+#[derive(Default)]
+struct Aggregator {
+    symbols: HashMap<usize, CallFrame>,
+    stacks: StackAggregator,
+    observer: Option<SampleObserver>,
+    /// Time one tick stands for.
+    weight_ns: u64,
+    labels: WallLabels,
+}
+
+/// What the handler recorded besides the ips.
+#[derive(Clone, Copy, Debug, Default)]
+struct RawMeta {
+    tid: i32,
+    timestamp_ns: u64,
+    cpu: Option<u32>,
+    tag: u32,
+    /// Frames of the root end that did not fit the slot.
+    truncated: usize,
+}
+
+impl Aggregator {
+    /// Add drained samples, each merged with the Python stack snapshot of its thread: a
+    /// batch can hold several samples of one thread, and all of them get it.
+    fn add_batch(
+        &mut self,
+        batch: Vec<(RawMeta, Vec<usize>)>,
+        python: &HashMap<i32, Vec<CallFrame>>,
+    ) {
+        for (raw, ips) in batch {
+            let python_frames = python.get(&raw.tid).cloned().unwrap_or_default();
+            self.add(raw, &ips, python_frames);
+        }
+    }
+
+    fn add(&mut self, raw: RawMeta, ips: &[usize], python: Vec<CallFrame>) {
+        let native: Vec<CallFrame> = ips
+            .iter()
+            .filter(|ip| **ip != 0)
+            .map(|ip| {
+                self.symbols
+                    .entry(*ip)
+                    .or_insert_with(|| resolve_ip(*ip))
+                    .clone()
+            })
+            .collect();
+        let approximate = !python.is_empty();
+        let mut frames = SignalTracer::merge_python_native_stacks(python, native);
+        if approximate {
+            frames.push(CallFrame::synthetic(
+                "[python stack approximate]",
+                APPROXIMATE_PYTHON_CATEGORY,
+            ));
+        }
+        if raw.truncated > 0 {
+            frames.push(truncation_marker(raw.truncated));
+        }
+        let state = if raw.tag & WallTag::OFF_CPU != 0 {
+            SampleState::OffCpu
+        } else {
+            SampleState::OnCpu
+        };
+        if state == SampleState::OffCpu {
+            let labels = self.labels.lock().unwrap_or_else(|e| e.into_inner());
+            let label = (raw.tag >> WallTag::LABEL_SHIFT)
+                .checked_sub(1)
+                .and_then(|label| labels.get(label as usize))
+                .map_or("off-cpu", String::as_str);
+            frames.insert(
+                0,
+                CallFrame::synthetic(format!("[{}]", label), OFF_CPU_CATEGORY),
+            );
+        }
+        let sample = TimedSample {
+            tid: raw.tid,
+            timestamp_ns: raw.timestamp_ns,
+            frames,
+            cpu: raw.cpu,
+            state,
+            weight_ns: self.weight_ns,
+        };
+        if let Some(observer) = self.observer.as_mut() {
+            observer(&sample);
+        }
+        self.stacks
+            .add_weighted(raw.tid, raw.cpu, state, &sample.frames, 1, sample.weight_ns);
+    }
+}
+
+fn collect(
+    stop: Arc<AtomicBool>,
+    mut aggregator: Aggregator,
+    mut provider: Option<PythonStacksProvider>,
+) -> Profile {
+    loop {
+        let stopping = stop.load(Ordering::SeqCst);
+        drain(&mut aggregator, &mut provider);
+        if stopping {
+            break;
+        }
+        thread::sleep(DRAIN_INTERVAL);
+    }
+    aggregator.stacks.into_profile()
+}
+
+fn drain(aggregator: &mut Aggregator, provider: &mut Option<PythonStacksProvider>) {
+    let mut batch = Vec::new();
+    for slot in RING.iter() {
+        if slot.state.load(Ordering::Acquire) != SLOT_READY {
+            continue;
+        }
+        let (len, truncated, ips) = unsafe { &*slot.data.get() };
+        let cpu = slot.cpu.load(Ordering::Relaxed);
+        let raw = RawMeta {
+            tid: slot.tid.load(Ordering::Relaxed),
+            timestamp_ns: slot.time_ns.load(Ordering::Relaxed),
+            cpu: (cpu != NO_CPU).then_some(cpu),
+            tag: slot.tag.load(Ordering::Relaxed),
+            truncated: *truncated,
+        };
+        batch.push((raw, ips[..*len].to_vec()));
+        slot.state.store(SLOT_EMPTY, Ordering::Release);
+    }
+    if batch.is_empty() {
+        return;
+    }
+
+    // The provider may wait for the GIL, held by a thread that forks: not under the lock.
+    let python = provider.as_mut().map(|p| p()).unwrap_or_default();
+    let _symbolizing = SYMBOLIZING.lock().unwrap_or_else(|e| e.into_inner());
+    aggregator.add_batch(batch, &python);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[inline(never)]
+    fn burn_cpu(duration: Duration) -> u64 {
+        let start = Instant::now();
+        let mut acc = 0u64;
+        while start.elapsed() < duration {
+            for i in 0..10_000u64 {
+                acc = acc.wrapping_mul(31).wrapping_add(std::hint::black_box(i));
+            }
+        }
+        acc
+    }
+
+    #[test]
+    fn test_sampler_collects_stacks() {
+        let _guard = TEST_SAMPLER_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let provider: PythonStacksProvider = Box::new(HashMap::new);
+        let sampler = Sampler::start_with_python(997, provider).unwrap();
+        assert!(Sampler::start(10).is_err());
+
+        std::hint::black_box(burn_cpu(Duration::from_millis(300)));
+        let profile = sampler.stop();
+
+        assert!(profile.total_samples > 0);
+        assert_eq!(
+            profile.stacks.iter().map(|s| s.count).sum::<u64>(),
+            profile.total_samples
+        );
+        // Every tick stands for its interval of CPU time.
+        assert_eq!(
+            profile.total_weight_ns(),
+            profile.total_samples * (1_000_000 / 997) * 1_000
+        );
+        assert_eq!(profile.weight_type(), "cpu");
+        assert!(profile
+            .stacks
+            .iter()
+            .any(|s| s.frames.iter().any(
+                |f| matches!(f, CallFrame::CFrame { func, .. } if func.contains("burn_cpu"))
+            )));
+
+        // The slot is free again once stopped.
+        Sampler::start(10).unwrap().stop();
+    }
+
+    #[test]
+    fn test_fork_while_sampling() {
+        let _guard = TEST_SAMPLER_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let sampler = Sampler::start(997).unwrap();
+        std::hint::black_box(burn_cpu(Duration::from_millis(100)));
+
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            let code = (|| {
+                let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+                unsafe { libc::sigaction(libc::SIGPROF, std::ptr::null(), &mut action) };
+                if action.sa_sigaction == handle_sigprof as *const () as libc::sighandler_t {
+                    return 1;
+                }
+                // The inherited copy stops at once, without the parent's collector...
+                if !sampler.is_forked() || sampler.stop().total_samples != 0 {
+                    return 2;
+                }
+                if SignalTracer::capture_all_threads().map_or(true, |s| s.len() != 1) {
+                    return 3;
+                }
+                // ...and the child can sample on its own.
+                let Ok(own) = Sampler::start(997) else {
+                    return 4;
+                };
+                std::hint::black_box(burn_cpu(Duration::from_millis(100)));
+                if own.stop().total_samples == 0 {
+                    return 5;
+                }
+                0
+            })();
+            unsafe { libc::_exit(code) };
+        }
+        let mut status = 0;
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        assert!(libc::WIFEXITED(status), "{}", status);
+        assert_eq!(libc::WEXITSTATUS(status), 0);
+
+        // The parent kept sampling.
+        assert!(!sampler.is_forked());
+        std::hint::black_box(burn_cpu(Duration::from_millis(100)));
+        assert!(sampler.stop().total_samples > 0);
+    }
+
+    #[test]
+    fn test_sampler_auto_unwind() {
+        let _guard = TEST_SAMPLER_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let sampler = Sampler::start_with_unwind(997, UnwindStrategy::Auto, None).unwrap();
+        std::hint::black_box(burn_cpu(Duration::from_millis(300)));
+        let profile = sampler.stop();
+        assert!(profile
+            .stacks
+            .iter()
+            .any(|s| s.frames.iter().any(
+                |f| matches!(f, CallFrame::CFrame { func, .. } if func.contains("burn_cpu"))
+            )));
+    }
+
+    #[test]
+    fn test_wall_clock_samples_blocked_threads() {
+        let _guard = TEST_SAMPLER_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (release_tx, release_rx) = std::sync::mpsc::channel::<()>();
+        let sleeper = thread::Builder::new()
+            .name("mst-test-sleep".to_string())
+            .spawn(move || release_rx.recv())
+            .unwrap();
+        let sampler = Sampler::start_wall_clock(200, UnwindStrategy::Dwarf, None).unwrap();
+        // The test thread itself is blocked too, in `sleep`.
+        thread::sleep(Duration::from_millis(300));
+        let profile = sampler.stop();
+        release_tx.send(()).unwrap();
+        sleeper.join().unwrap().unwrap();
+
+        let off_cpu: Vec<_> = profile
+            .stacks
+            .iter()
+            .filter(|s| s.state == SampleState::OffCpu)
+            .collect();
+        assert!(!off_cpu.is_empty());
+        assert_eq!(profile.weight_type(), "wall");
+        assert_eq!(profile.total_weight_ns(), profile.total_samples * 5_000_000);
+        // Blocked stacks end in the system call the thread waits in, or in `[off-cpu]`.
+        assert!(off_cpu.iter().all(|s| matches!(
+            s.frames.first(),
+            Some(CallFrame::Synthetic { category, .. }) if category == OFF_CPU_CATEGORY
+        )));
+    }
+
+    #[test]
+    fn test_aggregator_wall_tags() {
+        let mut aggregator = Aggregator::default();
+        aggregator.labels.lock().unwrap().push("futex".to_string());
+        let tag = WallTag::TICK | WallTag::OFF_CPU | (1 << WallTag::LABEL_SHIFT);
+        aggregator.add(
+            RawMeta {
+                tid: 7,
+                tag,
+                ..RawMeta::default()
+            },
+            &[],
+            Vec::new(),
+        );
+        let profile = aggregator.stacks.into_profile();
+        assert_eq!(profile.stacks[0].state, SampleState::OffCpu);
+        assert_eq!(
+            profile.stacks[0].frames,
+            vec![CallFrame::synthetic("[futex]", OFF_CPU_CATEGORY)]
+        );
+
+        // Blocked in a system call the ticker could not name.
+        let mut aggregator = Aggregator::default();
+        let tag = WallTag::TICK | WallTag::OFF_CPU;
+        aggregator.add(
+            RawMeta {
+                tid: 8,
+                tag,
+                ..RawMeta::default()
+            },
+            &[],
+            Vec::new(),
+        );
+        let profile = aggregator.stacks.into_profile();
+        assert_eq!(
+            profile.stacks[0].frames,
+            vec![CallFrame::synthetic("[off-cpu]", OFF_CPU_CATEGORY)]
+        );
+    }
+
+    #[test]
+    fn test_rejects_invalid_frequency() {
+        assert!(Sampler::start(0).is_err());
+    }
+
+    fn approximate() -> CallFrame {
+        CallFrame::synthetic("[python stack approximate]", APPROXIMATE_PYTHON_CATEGORY)
+    }
+
+    #[test]
+    fn test_aggregator_merges_python_frames() {
+        let mut aggregator = Aggregator::default();
+        let python = vec![CallFrame::python("0x0", "a.py", "f", 1)];
+        let raw = |tid, timestamp_ns| RawMeta {
+            tid,
+            timestamp_ns,
+            ..RawMeta::default()
+        };
+        aggregator.add(raw(7, 1), &[], python.clone());
+        aggregator.add(raw(7, 2), &[], python);
+        aggregator.add(raw(8, 3), &[], Vec::new());
+
+        let profile = aggregator.stacks.into_profile();
+        assert_eq!(profile.total_samples, 3);
+        assert_eq!(profile.stacks.len(), 2);
+        assert_eq!(profile.stacks[0].tid, 7);
+        assert_eq!(profile.stacks[0].count, 2);
+        assert!(profile.stacks[1].frames.is_empty());
+    }
+
+    #[test]
+    fn test_aggregator_marks_truncated_samples() {
+        let mut aggregator = Aggregator::default();
+        let raw = RawMeta {
+            tid: 7,
+            truncated: 40,
+            ..RawMeta::default()
+        };
+        let python = vec![CallFrame::python("0x0", "a.py", "f", 1)];
+        aggregator.add(raw, &[], python.clone());
+
+        let profile = aggregator.stacks.into_profile();
+        assert_eq!(
+            profile.stacks[0].frames,
+            [python[0].clone(), approximate(), truncation_marker(40)]
+        );
+    }
+
+    #[test]
+    fn test_add_batch_shares_python_frames() {
+        let mut aggregator = Aggregator::default();
+        let python = vec![CallFrame::python("0x0", "a.py", "f", 1)];
+        let raw = |tid, timestamp_ns| RawMeta {
+            tid,
+            timestamp_ns,
+            ..RawMeta::default()
+        };
+        // Several ticks of one busy thread land in the same drained batch.
+        let batch = (1..=3)
+            .map(|t| (raw(7, t), Vec::new()))
+            .chain([(raw(8, 4), Vec::new())])
+            .collect();
+        aggregator.add_batch(batch, &HashMap::from([(7, python.clone())]));
+
+        let profile = aggregator.stacks.into_profile();
+        assert_eq!(profile.total_samples, 4);
+        assert_eq!(profile.stacks.len(), 2);
+        assert_eq!((profile.stacks[0].tid, profile.stacks[0].count), (7, 3));
+        assert_eq!(profile.stacks[0].frames, [python[0].clone(), approximate()]);
+        assert!(profile.stacks[1].frames.is_empty());
+    }
+}
@@ -0,0 +1,480 @@
+//! Shared-memory export of samples for sidecar collectors.
+//!
+//! `ShmExporter` runs a `Sampler` and publishes every merged sample, folded into
+//! `root;...;leaf`, to a memory-mapped ring (a file, usually under `/dev/shm`). A collector
+//! in another process maps the same file and polls it: publishing a sample is a few stores,
+//! with no syscall and no wait for the reader, whose lag only costs it overwritten samples.
+//!
+//! Layout, little-endian on the platforms we run on (native byte order in general):
+//!
+//! ```text
+//! header, 64 bytes
+//!   0   [u8; 8]  magic "MSTSHM01"
+//!   8   u32      version (1)
+//!   12  u32      header size (64)
+//!   16  u32      slot count
+//!   20  u32      slot size in bytes, a multiple of 8
+//!   24  u64      records published so far (atomic)
+//!   32  u32      pid of the writer
+//!   36           reserved, zero
+//! slot count slots of slot size bytes; record n lives in slot n % slot count
+//!   0   u64      n + 1 once record n is complete, 0 while it is written (atomic)
+//!   8   u64      timestamp in nanoseconds, CLOCK_MONOTONIC
+//!   16  i32      thread id
+//!   20  u32      payload length
+//!   24  [u8]     payload: the folded stack, UTF-8, leaf end kept if too long
+//! ```
+//!
+//! To read record `n`: load the slot sequence (acquire) and expect `n + 1`, copy the
+//! fields, then load the sequence again; if it changed, the record was overwritten while
+//! copied and is lost. `ShmReader` does exactly that. There is one writer per ring.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::{fence, AtomicU64, Ordering};
+
+use crate::flight_recorder::leaf_end;
+use crate::output::folded::{self, FoldedOptions};
+use crate::profile::{Profile, TimedSample};
+use crate::sampler::{PythonStacksProvider, Sampler};
+use crate::unwind::UnwindStrategy;
+
+pub const MAGIC: [u8; 8] = *b"MSTSHM01";
+pub const VERSION: u32 = 1;
+pub const HEADER_SIZE: usize = 64;
+/// Bytes before the payload in a slot.
+pub const SLOT_HEADER_SIZE: usize = 24;
+
+const OFFSET_VERSION: usize = 8;
+const OFFSET_HEADER_SIZE: usize = 12;
+const OFFSET_SLOT_COUNT: usize = 16;
+const OFFSET_SLOT_SIZE: usize = 20;
+const OFFSET_PUBLISHED: usize = 24;
+const OFFSET_PID: usize = 32;
+
+/// Options of `ShmExporter::start_with`.
+pub struct ShmOptions {
+    slots: usize,
+    slot_size: usize,
+    unwind: UnwindStrategy,
+    python_stacks: Option<PythonStacksProvider>,
+}
+
+impl Default for ShmOptions {
+    fn default() -> Self {
+        ShmOptions {
+            slots: 4096,
+            slot_size: 2048,
+            unwind: UnwindStrategy::Dwarf,
+            python_stacks: None,
+        }
+    }
+}
+
+impl ShmOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of records the ring holds before the oldest is overwritten (default 4096).
+    pub fn slots(mut self, slots: usize) -> Self {
+        self.slots = slots.clamp(1, u32::MAX as usize);
+        self
+    }
+
+    /// Bytes per slot, header included (default 2048), rounded up to a multiple of 8.
+    pub fn slot_size(mut self, bytes: usize) -> Self {
+        self.slot_size = bytes.max(SLOT_HEADER_SIZE + 8).next_multiple_of(8);
+        self
+    }
+
+    /// How the sampler walks native stacks (see `UnwindStrategy`).
+    pub fn unwind(mut self, strategy: UnwindStrategy) -> Self {
+        self.unwind = strategy;
+        self
+    }
+
+    /// Merge samples with the Python stacks of `provider`.
+    pub fn python_stacks(mut self, provider: PythonStacksProvider) -> Self {
+        self.python_stacks = Some(provider);
+        self
+    }
+
+    pub fn get_slots(&self) -> usize {
+        self.slots
+    }
+
+    pub fn get_slot_size(&self) -> usize {
+        self.slot_size
+    }
+}
+
+/// A sampler publishing its samples to a shared-memory ring; see the module docs.
+pub struct ShmExporter {
+    sampler: Sampler,
+}
+
+impl ShmExporter {
+    /// Sample at `freq_hz` into a ring at `path` with the default options.
+    pub fn start(freq_hz: u32, path: impl AsRef<Path>) -> io::Result<ShmExporter> {
+        Self::start_with(freq_hz, path, ShmOptions::new())
+    }
+
+    /// Sample at `freq_hz` into a ring at `path`, created or truncated. Like any
+    /// `Sampler`, only one can run at a time.
+    pub fn start_with(
+        freq_hz: u32,
+        path: impl AsRef<Path>,
+        options: ShmOptions,
+    ) -> io::Result<ShmExporter> {
+        let mut writer = ShmWriter::create(path, options.slots, options.slot_size)?;
+        let fold = FoldedOptions::new();
+        let observer = Box::new(move |sample: &TimedSample| {
+            writer.publish(
+                sample.tid,
+                sample.timestamp_ns,
+                &folded::fold_stack(&sample.frames, &fold),
+            );
+        });
+        let sampler = Sampler::start_observed(
+            freq_hz,
+            options.unwind,
+            options.python_stacks,
+            Some(observer),
+        )?;
+        Ok(ShmExporter { sampler })
+    }
+
+    /// Stop sampling. The ring stays readable; the aggregated profile is returned too.
+    pub fn stop(self) -> Profile {
+        self.sampler.stop()
+    }
+}
+
+/// A file mapped shared into memory.
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// The mapping is only accessed through atomics and the seqlock protocol.
+unsafe impl Send for Mapping {}
+
+impl Mapping {
+    fn new(file: &File, len: usize, writable: bool) -> io::Result<Mapping> {
+        let prot = if writable {
+            libc::PROT_READ | libc::PROT_WRITE
+        } else {
+            libc::PROT_READ
+        };
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                prot,
+                libc::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping {
+            ptr: ptr as *mut u8,
+            len,
+        })
+    }
+
+    /// The atomic at `offset`, which must be 8-aligned and in bounds.
+    fn atomic(&self, offset: usize) -> &AtomicU64 {
+        debug_assert!(offset.is_multiple_of(8) && offset + 8 <= self.len);
+        unsafe { &*(self.ptr.add(offset) as *const AtomicU64) }
+    }
+
+    fn read<const N: usize>(&self, offset: usize) -> [u8; N] {
+        let mut bytes = [0u8; N];
+        unsafe { std::ptr::copy_nonoverlapping(self.ptr.add(offset), bytes.as_mut_ptr(), N) };
+        bytes
+    }
+
+    fn write(&self, offset: usize, bytes: &[u8]) {
+        debug_assert!(offset + bytes.len() <= self.len);
+        unsafe { std::ptr::copy_nonoverlapping(bytes.as_ptr(), self.ptr.add(offset), bytes.len()) };
+    }
+
+    fn u32(&self, offset: usize) -> u32 {
+        u32::from_ne_bytes(self.read(offset))
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as *mut libc::c_void, self.len) };
+    }
+}
+
+/// The writing end of a ring.
+pub struct ShmWriter {
+    map: Mapping,
+    slots: usize,
+    slot_size: usize,
+    published: u64,
+}
+
+impl ShmWriter {
+    /// Create (or truncate) the ring file at `path` with `slots` slots of `slot_size` bytes.
+    pub fn create(path: impl AsRef<Path>, slots: usize, slot_size: usize) -> io::Result<ShmWriter> {
+        let slots = slots.clamp(1, u32::MAX as usize);
+        let slot_size = slot_size.max(SLOT_HEADER_SIZE + 8).next_multiple_of(8);
+        if slot_size > u32::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "slot size must fit in 32 bits",
+            ));
+        }
+        let len = slots
+            .checked_mul(slot_size)
+            .and_then(|n| n.checked_add(HEADER_SIZE))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "ring too large"))?;
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        file.set_len(len as u64)?;
+        let map = Mapping::new(&file, len, true)?;
+
+        map.write(OFFSET_VERSION, &VERSION.to_ne_bytes());
+        map.write(OFFSET_HEADER_SIZE, &(HEADER_SIZE as u32).to_ne_bytes());
+        map.write(OFFSET_SLOT_COUNT, &(slots as u32).to_ne_bytes());
+        map.write(OFFSET_SLOT_SIZE, &(slot_size as u32).to_ne_bytes());
+        map.write(OFFSET_PID, &std::process::id().to_ne_bytes());
+        // The magic goes last: a reader that sees it sees a complete header.
+        fence(Ordering::Release);
+        map.write(0, &MAGIC);
+        Ok(ShmWriter {
+            map,
+            slots,
+            slot_size,
+            published: 0,
+        })
+    }
+
+    /// Publish one record, overwriting the oldest once the ring is full.
+    pub fn publish(&mut self, tid: i32, timestamp_ns: u64, stack: &str) {
+        let n = self.published;
+        let slot = HEADER_SIZE + (n % self.slots as u64) as usize * self.slot_size;
+        let payload = leaf_end(stack.as_bytes(), self.slot_size - SLOT_HEADER_SIZE);
+
+        let seq = self.map.atomic(slot);
+        seq.store(0, Ordering::Relaxed);
+        fence(Ordering::Release);
+        self.map.write(slot + 8, &timestamp_ns.to_ne_bytes());
+        self.map.write(slot + 16, &tid.to_ne_bytes());
+        self.map
+            .write(slot + 20, &(payload.len() as u32).to_ne_bytes());
+        self.map.write(slot + SLOT_HEADER_SIZE, payload);
+        seq.store(n + 1, Ordering::Release);
+
+        self.published = n + 1;
+        self.map
+            .atomic(OFFSET_PUBLISHED)
+            .store(self.published, Ordering::Release);
+    }
+}
+
+/// One record read back from a ring.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ShmRecord {
+    pub tid: i32,
+    /// Capture time in nanoseconds on `CLOCK_MONOTONIC`.
+    pub timestamp_ns: u64,
+    /// `root;...;leaf`, as `folded::fold_stack` writes it.
+    pub folded: String,
+}
+
+/// The reading end of a ring, for collectors written in Rust.
+pub struct ShmReader {
+    map: Mapping,
+    slots: usize,
+    slot_size: usize,
+    next: u64,
+    lost: u64,
+}
+
+impl ShmReader {
+    /// Map the ring at `path`, starting at the oldest record still in it.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<ShmReader> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len() as usize;
+        if file_len < HEADER_SIZE {
+            return Err(invalid("not a mixed-stack-tracer ring"));
+        }
+        let header = Mapping::new(&file, HEADER_SIZE, false)?;
+        if header.read::<8>(0) != MAGIC {
+            return Err(invalid("not a mixed-stack-tracer ring"));
+        }
+        fence(Ordering::Acquire);
+        if header.u32(OFFSET_VERSION) != VERSION
+            || header.u32(OFFSET_HEADER_SIZE) as usize != HEADER_SIZE
+        {
+            return Err(invalid("unsupported ring version"));
+        }
+        let slots = header.u32(OFFSET_SLOT_COUNT) as usize;
+        let slot_size = header.u32(OFFSET_SLOT_SIZE) as usize;
+        let len = HEADER_SIZE + slots * slot_size;
+        if slots == 0
+            || slot_size < SLOT_HEADER_SIZE
+            || !slot_size.is_multiple_of(8)
+            || file_len < len
+        {
+            return Err(invalid("corrupt ring header"));
+        }
+        drop(header);
+
+        let map = Mapping::new(&file, len, false)?;
+        let published = map.atomic(OFFSET_PUBLISHED).load(Ordering::Acquire);
+        Ok(ShmReader {
+            map,
+            slots,
+            slot_size,
+            next: published.saturating_sub(slots as u64),
+            lost: 0,
+        })
+    }
+
+    /// Pid of the process writing the ring.
+    pub fn writer_pid(&self) -> u32 {
+        self.map.u32(OFFSET_PID)
+    }
+
+    /// Records published since the last poll, oldest first.
+    pub fn poll(&mut self) -> Vec<ShmRecord> {
+        let published = self.map.atomic(OFFSET_PUBLISHED).load(Ordering::Acquire);
+        if published.saturating_sub(self.next) > self.slots as u64 {
+            let oldest = published - self.slots as u64;
+            self.lost += oldest - self.next;
+            self.next = oldest;
+        }
+        let mut records = Vec::new();
+        while self.next < published {
+            match self.read(self.next) {
+                Some(record) => records.push(record),
+                None => self.lost += 1,
+            }
+            self.next += 1;
+        }
+        records
+    }
+
+    /// Records overwritten before they could be read.
+    pub fn lost(&self) -> u64 {
+        self.lost
+    }
+
+    fn read(&self, n: u64) -> Option<ShmRecord> {
+        let slot = HEADER_SIZE + (n % self.slots as u64) as usize * self.slot_size;
+        let seq = self.map.atomic(slot);
+        if seq.load(Ordering::Acquire) != n + 1 {
+            return None;
+        }
+        let timestamp_ns = u64::from_ne_bytes(self.map.read(slot + 8));
+        let tid = i32::from_ne_bytes(self.map.read(slot + 16));
+        let len = (self.map.u32(slot + 20) as usize).min(self.slot_size - SLOT_HEADER_SIZE);
+        let mut payload = vec![0u8; len];
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                self.map.ptr.add(slot + SLOT_HEADER_SIZE),
+                payload.as_mut_ptr(),
+                len,
+            )
+        };
+        fence(Ordering::Acquire);
+        if seq.load(Ordering::Relaxed) != n + 1 {
+            return None;
+        }
+        Some(ShmRecord {
+            tid,
+            timestamp_ns,
+            folded: String::from_utf8_lossy(&payload).into_owned(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sampler::TEST_SAMPLER_LOCK;
+    use std::time::{Duration, Instant};
+
+    fn ring_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("mst-shm-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_ring_layout_and_overwrite() {
+        let path = ring_path("ring");
+        let mut writer = ShmWriter::create(&path, 2, 40).unwrap();
+        let file = std::fs::read(&path).unwrap();
+        assert_eq!(&file[..8], b"MSTSHM01");
+        assert_eq!(file.len(), HEADER_SIZE + 2 * 40);
+
+        let mut reader = ShmReader::open(&path).unwrap();
+        assert_eq!(reader.writer_pid(), std::process::id());
+        assert!(reader.poll().is_empty());
+        writer.publish(7, 100, "main;a");
+        let records = reader.poll();
+        assert_eq!(
+            records,
+            [ShmRecord {
+                tid: 7,
+                timestamp_ns: 100,
+                folded: "main;a".into()
+            }]
+        );
+
+        // Three more into two slots: the reader lags by one. The long stack keeps its leaf.
+        writer.publish(7, 200, "main;b");
+        writer.publish(8, 300, "main;c");
+        writer.publish(8, 400, "main;first;second;leaf");
+        let stacks: Vec<String> = reader.poll().into_iter().map(|r| r.folded).collect();
+        assert_eq!(stacks, ["main;c", "second;leaf"]);
+        assert_eq!(reader.lost(), 1);
+
+        drop((writer, reader));
+        std::fs::write(&path, b"not a ring").unwrap();
+        assert!(ShmReader::open(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[inline(never)]
+    fn spin(duration: Duration) -> u64 {
+        let start = Instant::now();
+        let mut acc = 0u64;
+        while start.elapsed() < duration {
+            acc = acc.wrapping_mul(31).wrapping_add(std::hint::black_box(7));
+        }
+        acc
+    }
+
+    #[test]
+    fn test_exporter() {
+        let _guard = TEST_SAMPLER_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let path = ring_path("exporter");
+        let exporter = ShmExporter::start_with(997, &path, ShmOptions::new().slots(1024)).unwrap();
+        let mut reader = ShmReader::open(&path).unwrap();
+        std::hint::black_box(spin(Duration::from_millis(300)));
+        let profile = exporter.stop();
+
+        let records = reader.poll();
+        assert!(!records.is_empty());
+        assert!(records.iter().any(|r| r.folded.contains("spin")));
+        assert!(profile.total_samples > 0);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
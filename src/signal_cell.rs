@@ -0,0 +1,19 @@
+//! Static storage shared between signal handlers and regular code.
+
+use std::cell::UnsafeCell;
+
+/// An `UnsafeCell` usable in statics. Callers serialize access themselves, typically
+/// with an atomic state flag that the signal handler flips before touching the data.
+pub(crate) struct SignalCell<T>(UnsafeCell<T>);
+
+unsafe impl<T> Sync for SignalCell<T> {}
+
+impl<T> SignalCell<T> {
+    pub(crate) const fn new(value: T) -> Self {
+        SignalCell(UnsafeCell::new(value))
+    }
+
+    pub(crate) fn get(&self) -> *mut T {
+        self.0.get()
+    }
+}
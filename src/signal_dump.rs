@@ -0,0 +1,326 @@
+//! On-demand dumps: `kill -USR1 <pid>` writes the stacks of every thread to a sink.
+//!
+//! The signal handler only writes a byte to a pipe. A dedicated thread waits on the
+//! pipe and does the actual capture, so the dump can allocate, symbolize and take the
+//! GIL like any other code. Signals arriving while a dump is in progress are merged
+//! into one follow-up dump.
+//!
+//! A forked child does not inherit the handlers: they are removed and the wake-up pipes
+//! closed in the child (see `crate::fork`).
+
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use crate::events::{self, DumpTrigger};
+use crate::fork::{self, BackgroundThread};
+use crate::output::text;
+use crate::sampler::PythonStacksProvider;
+use crate::signal_cell::SignalCell;
+use crate::stack_tracer::SignalTracer;
+
+/// Signals below this number can be used for dumps.
+const MAX_SIGNAL: usize = 65;
+
+#[allow(clippy::declare_interior_mutable_const)]
+const NO_FD: AtomicI32 = AtomicI32::new(-1);
+
+/// Write end of the wake-up pipe of each installed signal.
+static WAKE_FDS: [AtomicI32; MAX_SIGNAL] = [NO_FD; MAX_SIGNAL];
+/// Disposition each installed signal had before; written by the installer of its wake-up
+/// pipe.
+static PREVIOUS_ACTIONS: SignalCell<[libc::sigaction; MAX_SIGNAL]> =
+    SignalCell::new(unsafe { std::mem::zeroed() });
+
+/// Destination of on-demand dumps.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DumpSink {
+    Stderr,
+    /// Appended to, opened anew for every dump.
+    File(PathBuf),
+}
+
+/// An installed dump handler; dropping it restores the previous signal disposition.
+pub struct SignalDump {
+    signal: libc::c_int,
+    dumps: Arc<AtomicU64>,
+    thread: Option<BackgroundThread<()>>,
+}
+
+impl SignalDump {
+    pub fn signal(&self) -> libc::c_int {
+        self.signal
+    }
+
+    /// Number of dumps written so far.
+    pub fn dumps_written(&self) -> u64 {
+        self.dumps.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for SignalDump {
+    fn drop(&mut self) {
+        if self
+            .thread
+            .as_ref()
+            .is_some_and(BackgroundThread::is_forked)
+        {
+            // Removed by the fork handler; the signal may have a handler of the child's now.
+            return;
+        }
+        unsafe {
+            libc::sigaction(
+                self.signal,
+                previous_action(self.signal),
+                std::ptr::null_mut(),
+            )
+        };
+        // Closing the write end lets the dump thread see EOF and exit.
+        let fd = WAKE_FDS[self.signal as usize].swap(-1, Ordering::SeqCst);
+        if fd >= 0 {
+            unsafe { libc::close(fd) };
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl SignalTracer {
+    /// Dump native stacks of all threads to `sink` whenever `signal` arrives, typically
+    /// `libc::SIGUSR1` or `libc::SIGUSR2`.
+    pub fn install_dump_on_signal(signal: libc::c_int, sink: DumpSink) -> io::Result<SignalDump> {
+        install(signal, sink, None)
+    }
+
+    /// Like `install_dump_on_signal`, merging the Python stacks from `provider`.
+    pub fn install_dump_on_signal_with_python(
+        signal: libc::c_int,
+        sink: DumpSink,
+        provider: PythonStacksProvider,
+    ) -> io::Result<SignalDump> {
+        install(signal, sink, Some(provider))
+    }
+}
+
+fn install(
+    signal: libc::c_int,
+    sink: DumpSink,
+    provider: Option<PythonStacksProvider>,
+) -> io::Result<SignalDump> {
+    if signal <= 0 || signal as usize >= MAX_SIGNAL {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid signal {}", signal),
+        ));
+    }
+    if signal == libc::SIGPROF || signal == crate::threads::dump_signal() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("signal {} is used by the stack capture itself", signal),
+        ));
+    }
+
+    fork::register_handlers();
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let [read_fd, write_fd] = fds;
+    let close_both = || unsafe {
+        libc::close(read_fd);
+        libc::close(write_fd);
+    };
+    // A full pipe already holds a pending dump; the handler must never block on it.
+    unsafe { libc::fcntl(write_fd, libc::F_SETFL, libc::O_NONBLOCK) };
+    if WAKE_FDS[signal as usize]
+        .compare_exchange(-1, write_fd, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
+    {
+        close_both();
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("a dump handler is already installed for signal {}", signal),
+        ));
+    }
+
+    let dumps = Arc::new(AtomicU64::new(0));
+    let thread = {
+        let dumps = Arc::clone(&dumps);
+        thread::Builder::new()
+            .name("mst-signal-dump".to_string())
+            .spawn(move || wait_and_dump(read_fd, signal, &sink, provider, &dumps))
+    };
+    let thread = match thread {
+        Ok(thread) => BackgroundThread::new(thread),
+        Err(err) => {
+            WAKE_FDS[signal as usize].store(-1, Ordering::SeqCst);
+            close_both();
+            return Err(err);
+        }
+    };
+
+    let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+    action.sa_sigaction = handle_dump_request as *const () as libc::sighandler_t;
+    action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+    unsafe { libc::sigemptyset(&mut action.sa_mask) };
+    if unsafe { libc::sigaction(signal, &action, previous_action(signal)) } != 0 {
+        let err = io::Error::last_os_error();
+        WAKE_FDS[signal as usize].store(-1, Ordering::SeqCst);
+        unsafe { libc::close(write_fd) };
+        let _ = thread.join();
+        return Err(err);
+    }
+    Ok(SignalDump {
+        signal,
+        dumps,
+        thread: Some(thread),
+    })
+}
+
+/// Slot of `PREVIOUS_ACTIONS` of `signal`, owned by whoever installed its wake-up pipe.
+fn previous_action(signal: libc::c_int) -> *mut libc::sigaction {
+    unsafe { (*PREVIOUS_ACTIONS.get()).as_mut_ptr().add(signal as usize) }
+}
+
+/// Fork child handler: restore the dispositions of the installed signals and close the
+/// write ends of their pipes, which would keep the parent's dump threads from seeing EOF.
+pub(crate) fn disable_in_child() {
+    for (signal, fd) in WAKE_FDS.iter().enumerate() {
+        let fd = fd.swap(-1, Ordering::SeqCst);
+        if fd >= 0 {
+            let signal = signal as libc::c_int;
+            unsafe {
+                libc::sigaction(signal, previous_action(signal), std::ptr::null_mut());
+                libc::close(fd);
+            }
+        }
+    }
+}
+
+extern "C" fn handle_dump_request(
+    sig: libc::c_int,
+    _info: *mut libc::siginfo_t,
+    _ctx: *mut libc::c_void,
+) {
+    let Some(fd) = WAKE_FDS
+        .get(sig as usize)
+        .map(|fd| fd.load(Ordering::SeqCst))
+    else {
+        return;
+    };
+    if fd >= 0 {
+        unsafe {
+            let errno = *libc::__errno_location();
+            libc::write(fd, [1u8].as_ptr().cast(), 1);
+            *libc::__errno_location() = errno;
+        }
+    }
+}
+
+fn wait_and_dump(
+    read_fd: libc::c_int,
+    signal: libc::c_int,
+    sink: &DumpSink,
+    mut provider: Option<PythonStacksProvider>,
+    dumps: &AtomicU64,
+) {
+    let mut buf = [0u8; 64];
+    loop {
+        let n = unsafe { libc::read(read_fd, buf.as_mut_ptr().cast(), buf.len()) };
+        if n == 0 {
+            break;
+        }
+        if n < 0 {
+            if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            break;
+        }
+        match dump(signal, sink, provider.as_mut()) {
+            Ok(()) => {
+                dumps.fetch_add(1, Ordering::Relaxed);
+            }
+            Err(err) => eprintln!("mixed-stack-tracer: signal dump failed: {}", err),
+        }
+    }
+    unsafe { libc::close(read_fd) };
+}
+
+fn dump(
+    signal: libc::c_int,
+    sink: &DumpSink,
+    provider: Option<&mut PythonStacksProvider>,
+) -> io::Result<()> {
+    let python = provider.map(|p| p()).unwrap_or_default();
+    let stacks = SignalTracer::capture_all_threads_with_python_stacks(python, None)?;
+    events::stacks_dumped(DumpTrigger::Signal, stacks.len());
+    let mut out: Box<dyn Write> = match sink {
+        DumpSink::Stderr => Box::new(io::stderr().lock()),
+        DumpSink::File(path) => Box::new(io::BufWriter::new(
+            OpenOptions::new().create(true).append(true).open(path)?,
+        )),
+    };
+    writeln!(
+        out,
+        "mixed-stack-tracer: {} received, stacks of {} threads:\n",
+        signal_name(signal),
+        stacks.len()
+    )?;
+    text::write_thread_stacks(&mut out, &stacks)?;
+    out.flush()
+}
+
+fn signal_name(sig: libc::c_int) -> String {
+    match sig {
+        libc::SIGUSR1 => "SIGUSR1".to_string(),
+        libc::SIGUSR2 => "SIGUSR2".to_string(),
+        libc::SIGQUIT => "SIGQUIT".to_string(),
+        libc::SIGHUP => "SIGHUP".to_string(),
+        other => format!("signal {}", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_dump_on_signal() {
+        let path = std::env::temp_dir().join(format!("mst-signal-dump-{}.txt", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let guard =
+            SignalTracer::install_dump_on_signal(libc::SIGUSR2, DumpSink::File(path.clone()))
+                .unwrap();
+        let again = SignalTracer::install_dump_on_signal(libc::SIGUSR2, DumpSink::Stderr);
+        assert_eq!(again.err().unwrap().kind(), io::ErrorKind::AlreadyExists);
+
+        unsafe { libc::kill(libc::getpid(), libc::SIGUSR2) };
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while guard.dumps_written() == 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        drop(guard);
+
+        let dump = std::fs::read_to_string(&path).unwrap();
+        assert!(
+            dump.starts_with("mixed-stack-tracer: SIGUSR2 received"),
+            "{}",
+            dump
+        );
+        assert!(dump.contains("\"mst-signal-dump\""), "{}", dump);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_reserved_signals_rejected() {
+        for sig in [0, libc::SIGPROF, crate::threads::dump_signal(), 200] {
+            let err = SignalTracer::install_dump_on_signal(sig, DumpSink::Stderr).err();
+            assert_eq!(err.unwrap().kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+}
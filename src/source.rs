@@ -0,0 +1,233 @@
+//! Lines of source code attached to frames, like Python tracebacks show them.
+//!
+//! `SignalTracer::attach_source` reads the files frames point at through a `SourceCache`,
+//! which keeps recently used files up to a byte budget, and stores the frame's line with
+//! optional context lines in the frame's `source`.
+
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::stack_tracer::SignalTracer;
+use crate::CallFrame;
+
+/// Bytes of source a `SourceCache::default()` keeps.
+pub const DEFAULT_CACHE_SIZE: usize = 16 * 1024 * 1024;
+
+/// Longest line kept in a `SourceContext`; longer ones (minified or generated code) are cut.
+const MAX_LINE_LEN: usize = 512;
+
+/// The line a frame is at, with the lines around it. Line ends are stripped, indentation
+/// is kept.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SourceContext {
+    pub line: String,
+    /// Lines before `line`, top to bottom.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pre: Vec<String>,
+    /// Lines after `line`, top to bottom.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub post: Vec<String>,
+}
+
+/// Source files by path, evicting the least recently used ones beyond `capacity` bytes.
+/// Files that cannot be read are remembered as such.
+#[derive(Debug)]
+pub struct SourceCache {
+    capacity: usize,
+    size: usize,
+    tick: u64,
+    files: HashMap<String, Entry>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    lines: Option<Arc<Vec<String>>>,
+    size: usize,
+    last_used: u64,
+}
+
+impl Default for SourceCache {
+    fn default() -> Self {
+        SourceCache::new(DEFAULT_CACHE_SIZE)
+    }
+}
+
+impl SourceCache {
+    /// A cache holding up to about `capacity` bytes of source.
+    pub fn new(capacity: usize) -> Self {
+        SourceCache {
+            capacity,
+            size: 0,
+            tick: 0,
+            files: HashMap::new(),
+        }
+    }
+
+    /// Bytes of source currently held.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Lines of `path`, `None` when it cannot be read (or is `<string>` and the like).
+    pub fn lines(&mut self, path: &str) -> Option<Arc<Vec<String>>> {
+        self.tick += 1;
+        if let Some(entry) = self.files.get_mut(path) {
+            entry.last_used = self.tick;
+            return entry.lines.clone();
+        }
+        let read = if path.is_empty() || path.starts_with('<') {
+            None
+        } else {
+            fs::read(path).ok()
+        };
+        let size = read.as_ref().map_or(0, Vec::len);
+        let lines = read.map(|bytes| {
+            let text = String::from_utf8_lossy(&bytes);
+            Arc::new(text.lines().map(str::to_string).collect::<Vec<_>>())
+        });
+        // Too large to keep: hand it out once.
+        if size > self.capacity {
+            return lines;
+        }
+        self.evict(size);
+        self.size += size;
+        self.files.insert(
+            path.to_string(),
+            Entry {
+                lines: lines.clone(),
+                size,
+                last_used: self.tick,
+            },
+        );
+        lines
+    }
+
+    /// Line `lineno` (1-based) of `path` with `context` lines before and after it.
+    pub fn context(&mut self, path: &str, lineno: i64, context: usize) -> Option<SourceContext> {
+        let index = usize::try_from(lineno).ok()?.checked_sub(1)?;
+        let lines = self.lines(path)?;
+        let line = lines.get(index)?;
+        let cut = |lines: &[String]| lines.iter().map(|l| clip(l)).collect();
+        Some(SourceContext {
+            line: clip(line),
+            pre: cut(&lines[index.saturating_sub(context)..index]),
+            post: cut(&lines[index + 1..(index + 1 + context).min(lines.len())]),
+        })
+    }
+
+    /// Drop least recently used files until `incoming` more bytes fit.
+    fn evict(&mut self, incoming: usize) {
+        while self.size + incoming > self.capacity {
+            let Some(oldest) = self
+                .files
+                .iter()
+                .filter(|(_, entry)| entry.size > 0)
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(path, _)| path.clone())
+            else {
+                break;
+            };
+            if let Some(entry) = self.files.remove(&oldest) {
+                self.size -= entry.size;
+            }
+        }
+    }
+}
+
+fn clip(line: &str) -> String {
+    if line.len() <= MAX_LINE_LEN {
+        return line.to_string();
+    }
+    crate::locals::truncate(line.to_string(), MAX_LINE_LEN)
+}
+
+impl SignalTracer {
+    /// Set `source` of every native and Python frame with a known file and line to that
+    /// line plus `context` lines on each side. Frames whose file cannot be read keep
+    /// `None`; inlined calls of a native frame get no source.
+    pub fn attach_source(frames: &mut [CallFrame], cache: &mut SourceCache, context: usize) {
+        for frame in frames {
+            match frame {
+                CallFrame::CFrame {
+                    file,
+                    lineno,
+                    source,
+                    ..
+                }
+                | CallFrame::PyFrame {
+                    file,
+                    lineno,
+                    source,
+                    ..
+                } => *source = cache.context(file, *lineno, context).map(Box::new),
+                CallFrame::GpuFrame { .. }
+                | CallFrame::InterpreterFrame { .. }
+                | CallFrame::Synthetic { .. } => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_source(name: &str, lines: usize) -> String {
+        let path = std::env::temp_dir().join(format!("mst-source-{}-{}", std::process::id(), name));
+        let text: String = (1..=lines).map(|i| format!("line {}\n", i)).collect();
+        fs::write(&path, text).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn test_attach_source() {
+        let path = write_source("a.py", 5);
+        let mut frames = vec![
+            CallFrame::python("0x1", path.as_str(), "f", 2),
+            CallFrame::native("0x2", path.as_str(), "g", 9),
+            CallFrame::python("0x3", "<string>", "h", 1),
+        ];
+        let mut cache = SourceCache::default();
+        SignalTracer::attach_source(&mut frames, &mut cache, 1);
+        let CallFrame::PyFrame { source, .. } = &frames[0] else {
+            unreachable!()
+        };
+        assert_eq!(
+            source.as_deref(),
+            Some(&SourceContext {
+                line: "line 2".to_string(),
+                pre: vec!["line 1".to_string()],
+                post: vec!["line 3".to_string()],
+            })
+        );
+        // Past the end of the file, and a pseudo-file.
+        assert!(matches!(&frames[1], CallFrame::CFrame { source: None, .. }));
+        assert!(matches!(
+            &frames[2],
+            CallFrame::PyFrame { source: None, .. }
+        ));
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_cache_eviction() {
+        // 71 bytes per file.
+        let (a, b) = (write_source("a", 10), write_source("b", 10));
+        let mut cache = SourceCache::new(100);
+        assert!(cache.lines(&a).is_some());
+        assert_eq!(cache.size(), 71);
+        assert!(cache.lines(&b).is_some());
+        assert_eq!(cache.size(), 71);
+        assert!(!cache.files.contains_key(&a));
+
+        let big = write_source("big", 20);
+        assert_eq!(cache.lines(&big).map(|l| l.len()), Some(20));
+        assert!(!cache.files.contains_key(&big));
+        for path in [a, b, big] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+}
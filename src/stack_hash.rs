@@ -0,0 +1,228 @@
+//! Stable stack hashing and deduplication for streaming many samples.
+//!
+//! A stack is identified like in `FrameTable`: kind, function, file and line of every
+//! frame (inlined calls included). Ips and locals are ignored, so the same code path hashes
+//! the same in every process and run.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::CallFrame;
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// 64-bit FNV-1a hash of a frame sequence, stable across builds and platforms.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct StackHash(pub u64);
+
+impl StackHash {
+    pub fn of(frames: &[CallFrame]) -> StackHash {
+        let mut hasher = Fnv(FNV_OFFSET);
+        for frame in frames {
+            match frame {
+                CallFrame::CFrame {
+                    func,
+                    file,
+                    lineno,
+                    inlined,
+                    ..
+                } => {
+                    hasher.write(b"n");
+                    for inline in inlined {
+                        hasher.location(&inline.func, &inline.file, inline.lineno);
+                    }
+                    hasher.location(func, file, *lineno);
+                }
+                CallFrame::PyFrame {
+                    func, file, lineno, ..
+                } => {
+                    hasher.write(b"p");
+                    hasher.location(func, file, *lineno);
+                }
+                CallFrame::GpuFrame { kernel, .. } => {
+                    hasher.write(b"g");
+                    hasher.location(kernel, "", 0);
+                }
+                CallFrame::InterpreterFrame {
+                    runtime,
+                    func,
+                    file,
+                    lineno,
+                } => {
+                    hasher.write(b"i");
+                    hasher.write(runtime.as_bytes());
+                    hasher.location(func, file, *lineno);
+                }
+                CallFrame::Synthetic { label, category } => {
+                    hasher.write(b"s");
+                    hasher.location(label, category, 0);
+                }
+            }
+        }
+        StackHash(hasher.0)
+    }
+}
+
+impl fmt::Display for StackHash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
+struct Fnv(u64);
+
+impl Fnv {
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            self.0 ^= *b as u64;
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    fn location(&mut self, func: &str, file: &str, lineno: i64) {
+        // Length prefixes keep ("ab", "c") and ("a", "bc") apart.
+        self.write(&(func.len() as u64).to_le_bytes());
+        self.write(func.as_bytes());
+        self.write(&(file.len() as u64).to_le_bytes());
+        self.write(file.as_bytes());
+        self.write(&lineno.to_le_bytes());
+    }
+}
+
+/// Dense id handed out by a `StackDeduper`, in first-seen order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct StackId(pub u32);
+
+/// Outcome of `StackDeduper::observe`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Observed {
+    /// First occurrence: the full stack has to be sent or stored once.
+    New(StackId),
+    /// Repeat of an earlier stack: its id is enough.
+    Seen(StackId),
+}
+
+impl Observed {
+    pub fn id(self) -> StackId {
+        match self {
+            Observed::New(id) | Observed::Seen(id) => id,
+        }
+    }
+}
+
+/// Maps repeated stacks to ids and counts occurrences. Stacks are told apart by
+/// `StackHash` alone.
+#[derive(Clone, Debug, Default)]
+pub struct StackDeduper {
+    ids: HashMap<StackHash, StackId>,
+    entries: Vec<(StackHash, u64)>,
+}
+
+impl StackDeduper {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn observe(&mut self, frames: &[CallFrame]) -> Observed {
+        self.observe_hash(StackHash::of(frames))
+    }
+
+    /// Like `observe`, for a hash computed elsewhere (e.g. by a remote producer).
+    pub fn observe_hash(&mut self, hash: StackHash) -> Observed {
+        if let Some(id) = self.ids.get(&hash) {
+            self.entries[id.0 as usize].1 += 1;
+            return Observed::Seen(*id);
+        }
+        let id = StackId(self.entries.len() as u32);
+        self.ids.insert(hash, id);
+        self.entries.push((hash, 1));
+        Observed::New(id)
+    }
+
+    /// Number of distinct stacks.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn count(&self, id: StackId) -> u64 {
+        self.entries
+            .get(id.0 as usize)
+            .map_or(0, |(_, count)| *count)
+    }
+
+    pub fn hash(&self, id: StackId) -> Option<StackHash> {
+        self.entries.get(id.0 as usize).map(|(hash, _)| *hash)
+    }
+
+    /// `(id, hash, count)` of every distinct stack, in first-seen order.
+    pub fn iter(&self) -> impl Iterator<Item = (StackId, StackHash, u64)> + '_ {
+        self.entries
+            .iter()
+            .enumerate()
+            .map(|(i, (hash, count))| (StackId(i as u32), *hash, *count))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Value;
+
+    fn stack() -> Vec<CallFrame> {
+        vec![
+            CallFrame::native("0x1", "lib.c", "leaf", 10),
+            CallFrame::python("0x2", "app.py", "handler", 3),
+        ]
+    }
+
+    #[test]
+    fn test_hash_ignores_ips_and_locals() {
+        let mut other = vec![
+            CallFrame::native("0x99", "lib.c", "leaf", 10),
+            CallFrame::python("0x98", "app.py", "handler", 3),
+        ];
+        if let CallFrame::PyFrame { locals, .. } = &mut other[1] {
+            locals.insert("x".to_string(), Value::Int(1));
+        }
+        assert_eq!(StackHash::of(&stack()), StackHash::of(&other));
+
+        let moved = vec![
+            CallFrame::native("0x1", "lib.c", "leaf", 11),
+            CallFrame::python("0x2", "app.py", "handler", 3),
+        ];
+        assert_ne!(StackHash::of(&stack()), StackHash::of(&moved));
+        // Native and Python frames never collide by kind alone.
+        assert_ne!(
+            StackHash::of(&[CallFrame::native("0x0", "f", "g", 1)]),
+            StackHash::of(&[CallFrame::python("0x0", "f", "g", 1)])
+        );
+    }
+
+    #[test]
+    fn test_hash_is_stable() {
+        assert_eq!(StackHash::of(&[]), StackHash(FNV_OFFSET));
+        // Pinned so a change to the encoding is noticed: hashes are meant to be stored.
+        assert_eq!(StackHash::of(&stack()).to_string(), "d2f2e3cb01a6e58e");
+    }
+
+    #[test]
+    fn test_deduper() {
+        let mut deduper = StackDeduper::new();
+        let first = deduper.observe(&stack());
+        let other = deduper.observe(&stack()[..1]);
+        let again = deduper.observe(&stack());
+        assert_eq!(first, Observed::New(StackId(0)));
+        assert_eq!(other, Observed::New(StackId(1)));
+        assert_eq!(again, Observed::Seen(StackId(0)));
+        assert_eq!(deduper.len(), 2);
+        assert_eq!(deduper.count(first.id()), 2);
+        assert_eq!(deduper.hash(other.id()), Some(StackHash::of(&stack()[..1])));
+        let counts: Vec<u64> = deduper.iter().map(|(_, _, c)| c).collect();
+        assert_eq!(counts, [2, 1]);
+    }
+}
@@ -0,0 +1,139 @@
+//! Stack direction. Merged stacks are always leaf first; inputs may come either way and
+//! are normalized by the merge (see `MergeOptions::python_order` / `native_order`).
+
+use std::fmt;
+use std::io;
+
+use crate::stack_tracer::SignalTracer;
+use crate::CallFrame;
+
+/// Order of the frames in a stack.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StackOrder {
+    /// Innermost frame at index 0 (what `backtrace` and the CPython frame chain give).
+    #[default]
+    LeafFirst,
+    /// Outermost frame (`main`, `<module>`) at index 0.
+    RootFirst,
+}
+
+impl StackOrder {
+    /// Guess the order of `frames` from functions that only ever appear at the root of a
+    /// stack. `None` when there is no such frame or the stack is too short to tell.
+    pub fn guess(frames: &[CallFrame]) -> Option<StackOrder> {
+        if frames.len() < 2 {
+            return None;
+        }
+        let half = frames.len() / 2;
+        let mut roots = frames
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| is_root_frame(f))
+            .map(|(i, _)| i);
+        let first = roots.next()?;
+        let last = roots.next_back().unwrap_or(first);
+        if last < half {
+            Some(StackOrder::RootFirst)
+        } else if first >= frames.len() - half {
+            Some(StackOrder::LeafFirst)
+        } else {
+            None
+        }
+    }
+}
+
+impl StackOrder {
+    /// Index into a stack of `len` frames in this order of the `i`th frame counted from
+    /// the leaf (the mapping is its own inverse).
+    pub(crate) fn leaf_index(self, len: usize, i: usize) -> usize {
+        match self {
+            StackOrder::LeafFirst => i,
+            StackOrder::RootFirst => len - 1 - i,
+        }
+    }
+}
+
+impl fmt::Display for StackOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StackOrder::LeafFirst => "leaf-first",
+            StackOrder::RootFirst => "root-first",
+        })
+    }
+}
+
+/// Entry points of processes and threads, native and python.
+const ROOT_FUNCTIONS: &[&str] = &[
+    "_start",
+    "__libc_start_main",
+    "__libc_start_call_main",
+    "main",
+    "Py_Main",
+    "Py_BytesMain",
+    "Py_RunMain",
+    "pymain_main",
+    "start_thread",
+    "thread_start",
+    "clone",
+    "clone3",
+    "<module>",
+    "_bootstrap",
+];
+
+fn is_root_frame(frame: &CallFrame) -> bool {
+    ROOT_FUNCTIONS.contains(&frame.func())
+}
+
+impl SignalTracer {
+    /// Fail with `InvalidData` when `frames` look like the opposite of `order`, e.g.
+    /// `Py_Main` at the leaf of a supposedly leaf-first stack.
+    pub fn validate_stack_order(frames: &[CallFrame], order: StackOrder) -> io::Result<()> {
+        match StackOrder::guess(frames) {
+            Some(guessed) if guessed != order => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("stack given as {} looks {}", order, guessed),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cframe(name: &str) -> CallFrame {
+        CallFrame::native("0x0", "", name, 0)
+    }
+
+    #[test]
+    fn test_guess_order() {
+        let leaf_first = vec![
+            cframe("read"),
+            cframe("work"),
+            cframe("Py_Main"),
+            cframe("main"),
+        ];
+        assert_eq!(StackOrder::guess(&leaf_first), Some(StackOrder::LeafFirst));
+        let mut root_first = leaf_first.clone();
+        root_first.reverse();
+        assert_eq!(StackOrder::guess(&root_first), Some(StackOrder::RootFirst));
+
+        assert_eq!(StackOrder::guess(&[cframe("a"), cframe("b")]), None);
+        assert_eq!(StackOrder::guess(&[cframe("main")]), None);
+        // Root functions at both ends: no opinion.
+        assert_eq!(
+            StackOrder::guess(&[cframe("main"), cframe("x"), cframe("_start")]),
+            None
+        );
+    }
+
+    #[test]
+    fn test_validate_stack_order() {
+        let frames = vec![cframe("Py_Main"), cframe("work"), cframe("read")];
+        let err = SignalTracer::validate_stack_order(&frames, StackOrder::LeafFirst).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(SignalTracer::validate_stack_order(&frames, StackOrder::RootFirst).is_ok());
+        assert!(SignalTracer::validate_stack_order(&[cframe("a")], StackOrder::LeafFirst).is_ok());
+    }
+}
@@ -1,171 +1,362 @@
-//! Merge logic for Python + native stacks (prototype).
-//! Contains tests that validate several merging scenarios.
+//! `StackTrace`: captured frames together with where and when they were captured.
+//!
+//! Capture entry points return it; it dereferences to its `Vec<CallFrame>` and converts from
+//! and into one, so code that worked on plain frame vectors keeps working.
+//!
+//! Stacks cut to a frame limit end with a `[truncated N frames]` marker (see
+//! `truncate_frames`) standing for the root end that was dropped.
 
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+
+use crate::merge_options::{ExtraPythonFrames, MergeOptions};
+use crate::stack_tracer::SignalTracer;
+use crate::thread_stack::ThreadId;
 use crate::CallFrame;
 
-/// SignalTracer with merge function (prototype)
-#[derive(Debug)]
-pub struct SignalTracer;
+/// How the frames of a `StackTrace` were obtained.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CaptureSource {
+    /// Built from bare frames.
+    #[default]
+    Unknown,
+    /// Unwound by the calling thread itself (`capture_native_stack`).
+    InProcess,
+    /// Unwound in a signal handler on the sampled thread.
+    Signal,
+    /// Read from another process (`RemoteProcess`).
+    Remote,
+    /// Walked through the Python interpreter (`python` feature).
+    Python,
+    /// Python and native stacks merged (`SignalTracer::merge_traces`).
+    Merged,
+}
 
-impl SignalTracer {
-    /// Merge python_stacks into native_stacks using heuristic boundaries (PyEval_*).
-    ///
-    /// Rules:
-    /// - Traverse native_stacks in order; for each frame, detect if it's a Python boundary (e.g., PyEval).
-    /// - On Python boundary:
-    ///   * if a python frame is available, consume exactly one python frame and push it into merged (advance index)
-    ///   * otherwise (no python frame available), keep the native frame to avoid losing native context
-    /// - On native frame: push native frame
-    /// - After traversal, append any remaining python frames to merged
-    pub fn merge_python_native_stacks(
-        mut python_stacks: Vec<CallFrame>,
-        native_stacks: Vec<CallFrame>,
-    ) -> Vec<CallFrame> {
-        let mut merged = Vec::with_capacity(native_stacks.len() + python_stacks.len());
-        let mut python_frame_index: usize = 0;
-
-        #[derive(Debug)]
-        enum MergeType {
-            MergeNativeFrame,
-            MergePythonFrame,
-        }
+/// Category of the `[truncated N frames]` marker ending a stack cut to a frame limit.
+pub const TRUNCATED_CATEGORY: &str = "truncated";
 
-        // Detect PyEval-like boundaries in a robust manner using substring checks.
-        fn get_merge_strategy(frame: &CallFrame) -> MergeType {
-            let func = match frame {
-                CallFrame::CFrame { func, .. } => func.as_str(),
-                CallFrame::PyFrame { func, .. } => func.as_str(),
-            };
-
-            let is_py_eval = func.contains("PyEval_EvalFrame")
-                || func.contains("PyEval_EvalCode")
-                || func.starts_with("PyEval")
-                || func.contains("EvalFrameDefault")
-                || func.contains("EvalFrameEx");
-
-            if is_py_eval {
-                MergeType::MergePythonFrame
-            } else {
-                MergeType::MergeNativeFrame
-            }
-        }
+/// Frames of one stack, leaf first, with capture metadata.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StackTrace {
+    pub frames: Vec<CallFrame>,
+    /// Capture time, if recorded.
+    pub timestamp: Option<SystemTime>,
+    pub pid: Option<u32>,
+    pub tid: Option<ThreadId>,
+    /// Frames beyond the capture depth limit were dropped from the root end.
+    pub truncated: bool,
+    /// How many, when known: the count of the marker ending `frames`.
+    #[serde(default)]
+    pub truncated_frames: usize,
+    pub source: CaptureSource,
+}
 
-        for native_frame in native_stacks.into_iter() {
-            match get_merge_strategy(&native_frame) {
-                MergeType::MergeNativeFrame => merged.push(native_frame),
-                MergeType::MergePythonFrame => {
-                    if python_frame_index < python_stacks.len() {
-                        let py_frame = python_stacks[python_frame_index].clone();
-                        merged.push(py_frame);
-                        python_frame_index += 1;
-                    } else {
-                        // No python frames left: preserve native frame
-                        merged.push(native_frame);
-                    }
-                }
-            }
+impl StackTrace {
+    /// Frames captured now by `source` in the calling process.
+    pub fn captured(frames: Vec<CallFrame>, source: CaptureSource) -> Self {
+        StackTrace {
+            frames,
+            timestamp: Some(SystemTime::now()),
+            pid: Some(std::process::id()),
+            tid: current_tid(),
+            truncated: false,
+            truncated_frames: 0,
+            source,
         }
+    }
+
+    pub fn into_frames(self) -> Vec<CallFrame> {
+        self.frames
+    }
 
-        // Append remaining python frames (avoid dropping extra python frames)
-        if python_frame_index < python_stacks.len() {
-            merged.extend_from_slice(&python_stacks[python_frame_index..]);
+    /// Keep the `max_frames` leaf-most frames and replace the others by a marker, recording
+    /// how many it stands for (see `truncate_frames`).
+    pub fn limit_frames(&mut self, max_frames: usize) -> &mut Self {
+        let dropped = truncate_frames(&mut self.frames, max_frames);
+        if dropped > 0 {
+            self.truncated = true;
+            self.truncated_frames = dropped;
         }
+        self
+    }
+}
 
-        merged
+/// Marker standing for `count` frames dropped from the root end of a stack.
+pub fn truncation_marker(count: usize) -> CallFrame {
+    let label = match count {
+        1 => "[truncated 1 frame]".to_string(),
+        _ => format!("[truncated {} frames]", count),
+    };
+    CallFrame::synthetic(label, TRUNCATED_CATEGORY)
+}
+
+/// How many frames `frame` stands for if it is a truncation marker.
+pub fn truncated_count(frame: &CallFrame) -> Option<usize> {
+    match frame {
+        CallFrame::Synthetic { label, category } if category == TRUNCATED_CATEGORY => {
+            let rest = label.strip_prefix("[truncated ")?;
+            let count = rest
+                .strip_suffix(" frames]")
+                .or_else(|| rest.strip_suffix(" frame]"))?;
+            count.parse().ok()
+        }
+        _ => None,
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::CallFrame;
-
-    fn cframe(name: &str) -> CallFrame {
-        CallFrame::CFrame {
-            ip: "0x0".to_string(),
-            file: "".to_string(),
-            func: name.to_string(),
-            lineno: 0,
+/// Cut `frames`, leaf first, to their `max_frames` leaf-most frames and end them with a
+/// `truncation_marker` for the others. Returns how many frames the marker stands for, 0
+/// when the stack fits. A marker left by an earlier cut is not a frame: its count adds up.
+pub fn truncate_frames(frames: &mut Vec<CallFrame>, max_frames: usize) -> usize {
+    let earlier = match frames.last().and_then(truncated_count) {
+        Some(count) => {
+            frames.pop();
+            count
         }
+        None => 0,
+    };
+    let dropped = earlier + frames.len().saturating_sub(max_frames);
+    frames.truncate(max_frames);
+    if dropped > 0 {
+        frames.push(truncation_marker(dropped));
     }
+    dropped
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn current_tid() -> Option<ThreadId> {
+    Some(crate::threads::current_tid())
+}
 
-    fn pyframe(name: &str) -> CallFrame {
-        CallFrame::PyFrame {
-            ip: "0x0".to_string(),
-            file: "".to_string(),
-            func: name.to_string(),
-            lineno: 0,
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn current_tid() -> Option<ThreadId> {
+    None
+}
+
+impl From<Vec<CallFrame>> for StackTrace {
+    fn from(frames: Vec<CallFrame>) -> Self {
+        StackTrace {
+            frames,
+            ..StackTrace::default()
         }
     }
+}
 
-    fn funcs(frames: &[CallFrame]) -> Vec<String> {
-        frames
-            .iter()
-            .map(|f| match f {
-                CallFrame::CFrame { func, .. } => func.clone(),
-                CallFrame::PyFrame { func, .. } => func.clone(),
-            })
-            .collect()
+impl From<StackTrace> for Vec<CallFrame> {
+    fn from(trace: StackTrace) -> Self {
+        trace.frames
     }
+}
 
-    #[test]
-    fn test_simple_insert() {
-        // native: A -> PyEval -> B
-        // python: py1 -> py2
-        let native = vec![cframe("A"), cframe("PyEval_EvalFrameDefault"), cframe("B")];
-        let python = vec![pyframe("py1"), pyframe("py2")];
+impl Deref for StackTrace {
+    type Target = Vec<CallFrame>;
 
-        let merged = SignalTracer::merge_python_native_stacks(python, native);
-        let got = funcs(&merged);
+    fn deref(&self) -> &Vec<CallFrame> {
+        &self.frames
+    }
+}
 
-        // Expect: A, py1, B, py2
-        assert_eq!(got, vec!["A", "py1", "B", "py2"]);
+impl DerefMut for StackTrace {
+    fn deref_mut(&mut self) -> &mut Vec<CallFrame> {
+        &mut self.frames
     }
+}
+
+impl PartialEq<Vec<CallFrame>> for StackTrace {
+    fn eq(&self, other: &Vec<CallFrame>) -> bool {
+        self.frames == *other
+    }
+}
+
+impl IntoIterator for StackTrace {
+    type Item = CallFrame;
+    type IntoIter = std::vec::IntoIter<CallFrame>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.frames.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a StackTrace {
+    type Item = &'a CallFrame;
+    type IntoIter = std::slice::Iter<'a, CallFrame>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.frames.iter()
+    }
+}
+
+impl SignalTracer {
+    /// Merge two captured traces like `merge_python_native_stacks`.
+    ///
+    /// The result describes the native trace's thread and instant; it is truncated when
+    /// either input was.
+    pub fn merge_traces(python: StackTrace, native: StackTrace) -> StackTrace {
+        Self::merge_traces_with(python, native, &MergeOptions::default())
+    }
+
+    /// `merge_traces` with `options`. Frames beyond `MergeOptions::max_frames` are counted
+    /// in `truncated_frames`, along with those the inputs had dropped.
+    pub fn merge_traces_with(
+        python: StackTrace,
+        native: StackTrace,
+        options: &MergeOptions,
+    ) -> StackTrace {
+        Self::merge_traces_counted(python, native, options).0
+    }
+
+    /// Like `merge_traces_with`, but fails like `try_merge_python_native_stacks_with`.
+    pub fn try_merge_traces_with(
+        python: StackTrace,
+        native: StackTrace,
+        options: &MergeOptions,
+    ) -> io::Result<StackTrace> {
+        if options.validates_order() {
+            Self::validate_stack_order(&python, options.python_stack_order())?;
+            Self::validate_stack_order(&native, options.native_stack_order())?;
+        }
+        let (merged, leftover) = Self::merge_traces_counted(python, native, options);
+        if leftover > 0 && options.extra_python_frames_policy() == ExtraPythonFrames::Error {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} python frames left without a boundary", leftover),
+            ));
+        }
+        Ok(merged)
+    }
+
+    /// The merged trace and how many python frames were left without a boundary.
+    fn merge_traces_counted(
+        python: StackTrace,
+        native: StackTrace,
+        options: &MergeOptions,
+    ) -> (StackTrace, usize) {
+        let (mut python_frames, mut native_frames) = (python.frames, native.frames);
+        // Markers of the inputs would land mid-stack; the merge ends with one of its own.
+        let mut dropped = 0;
+        for frames in [&mut python_frames, &mut native_frames] {
+            if let Some(count) = frames.last().and_then(truncated_count) {
+                frames.pop();
+                dropped += count;
+            }
+        }
+        let (mut frames, counts) =
+            Self::merge_inner(python_frames, native_frames, options.detector(), options);
+        if counts.truncated > 0 {
+            frames.pop();
+        }
+        dropped += counts.truncated;
+        if dropped > 0 {
+            frames.push(truncation_marker(dropped));
+        }
+        let merged = StackTrace {
+            frames,
+            timestamp: native.timestamp.or(python.timestamp),
+            pid: native.pid.or(python.pid),
+            tid: native.tid.or(python.tid),
+            truncated: python.truncated || native.truncated || counts.truncated > 0,
+            truncated_frames: dropped,
+            source: CaptureSource::Merged,
+        };
+        (merged, counts.leftover)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
     #[test]
-    fn test_python_shortage() {
-        // native: PyEval, PyEval, C
-        // python: only py1
-        let native = vec![
-            cframe("PyEval_EvalFrameDefault"),
-            cframe("PyEval_EvalFrameDefault"),
-            cframe("C"),
-        ];
-        let python = vec![pyframe("py1")];
-
-        let merged = SignalTracer::merge_python_native_stacks(python, native);
-        let got = funcs(&merged);
-
-        // Expect:
-        // first PyEval -> py1
-        // second PyEval -> no python left, keep native PyEval
-        // then C
-        assert_eq!(got, vec!["py1", "PyEval_EvalFrameDefault", "C"]);
+    fn test_merge_traces_keeps_native_metadata() {
+        let python = StackTrace::from(vec![CallFrame::python("0x0", "a.py", "f", 1)]);
+        let mut native = StackTrace::captured(
+            vec![
+                CallFrame::native("0x1", "", "leaf", 0),
+                CallFrame::native("0x2", "", "PyEval_EvalFrameDefault", 0),
+            ],
+            CaptureSource::InProcess,
+        );
+        native.truncated = true;
+
+        let merged = SignalTracer::merge_traces(python, native.clone());
+        assert_eq!(merged.source, CaptureSource::Merged);
+        assert_eq!(merged.pid, Some(std::process::id()));
+        assert_eq!(
+            (merged.tid, merged.timestamp),
+            (native.tid, native.timestamp)
+        );
+        assert!(merged.truncated);
+        let funcs: Vec<&str> = merged.iter().map(|f| f.func()).collect();
+        assert_eq!(funcs, ["leaf", "f"]);
+        assert_eq!(Vec::from(merged).len(), 2);
+    }
+
+    fn native(funcs: &[&str]) -> Vec<CallFrame> {
+        funcs
+            .iter()
+            .map(|f| CallFrame::native("0x0", "", *f, 0))
+            .collect()
     }
 
     #[test]
-    fn test_python_extra() {
-        // native has no PyEval, python has frames => all python frames appended
-        let native = vec![cframe("A"), cframe("B")];
-        let python = vec![pyframe("py1"), pyframe("py2")];
+    fn test_limit_frames() {
+        let mut trace = StackTrace::from(native(&["a", "b", "c", "d", "e"]));
+        trace.limit_frames(5);
+        assert!(!trace.truncated);
+        assert_eq!(trace.len(), 5);
 
-        let merged = SignalTracer::merge_python_native_stacks(python, native);
-        let got = funcs(&merged);
+        trace.limit_frames(3);
+        assert!(trace.truncated);
+        assert_eq!(trace.truncated_frames, 2);
+        assert_eq!(trace[3], truncation_marker(2));
+        assert_eq!(trace[3].func(), "[truncated 2 frames]");
 
-        // Expect: A, B, py1, py2
-        assert_eq!(got, vec!["A", "B", "py1", "py2"]);
+        // Cutting again counts the frames dropped before.
+        trace.limit_frames(1);
+        assert_eq!(trace.truncated_frames, 4);
+        let funcs: Vec<&str> = trace.iter().map(|f| f.func()).collect();
+        assert_eq!(funcs, ["a", "[truncated 4 frames]"]);
+        assert_eq!(truncated_count(&trace[0]), None);
     }
 
     #[test]
-    fn test_no_python_frames() {
-        // native has PyEval markers, but no python frames at all
-        let native = vec![cframe("X"), cframe("PyEval_EvalFrameDefault"), cframe("Y")];
-        let python: Vec<CallFrame> = vec![];
+    fn test_truncation_marker_wording() {
+        assert_eq!(truncation_marker(1).func(), "[truncated 1 frame]");
+        assert_eq!(truncation_marker(3).func(), "[truncated 3 frames]");
+        for count in [1, 3] {
+            assert_eq!(truncated_count(&truncation_marker(count)), Some(count));
+        }
+    }
 
-        let merged = SignalTracer::merge_python_native_stacks(python, native);
-        let got = funcs(&merged);
+    #[test]
+    fn test_merge_traces_with_max_frames() {
+        let python = StackTrace::from(vec![
+            CallFrame::python("0x0", "a.py", "f", 1),
+            CallFrame::python("0x0", "a.py", "<module>", 9),
+        ]);
+        let native = StackTrace::from(native(&[
+            "leaf",
+            "PyEval_EvalFrameDefault",
+            "PyEval_EvalFrameDefault",
+            "main",
+        ]));
+        let options = MergeOptions::new().max_frames(2);
+        let merged = SignalTracer::merge_traces_with(python.clone(), native.clone(), &options);
+        let funcs: Vec<&str> = merged.iter().map(|f| f.func()).collect();
+        assert_eq!(funcs, ["leaf", "f", "[truncated 2 frames]"]);
+        assert!(merged.truncated);
+        assert_eq!(merged.truncated_frames, 2);
 
-        // Expect: preserve native PyEval since no python frames to insert
-        assert_eq!(got, vec!["X", "PyEval_EvalFrameDefault", "Y"]);
+        // Frames the inputs dropped add up, under a single marker.
+        let mut short = native;
+        short.limit_frames(3);
+        let merged = SignalTracer::merge_traces_with(python, short, &options);
+        assert_eq!(merged.truncated_frames, 2);
+        assert_eq!(merged.len(), 3);
+        assert_eq!(merged[2], truncation_marker(2));
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,907 @@
+//! Merge logic for Python + native stacks (prototype).
+//! Contains tests that validate several merging scenarios.
+
+use std::fmt;
+use std::io;
+use std::ops::Range;
+#[cfg(target_os = "linux")]
+use std::sync::Mutex;
+
+use crate::boundary::BoundaryDetector;
+#[cfg(target_os = "linux")]
+use crate::error::TracerError;
+use crate::merge_iter::{Pick, Picks, WalkCounts};
+use crate::merge_options::{ExtraPythonFrames, MergeOptions};
+use crate::stack_order::StackOrder;
+use crate::stack_trace::{truncation_marker, StackTrace};
+#[cfg(target_os = "linux")]
+use crate::symbolize::Symbolizer;
+use crate::CallFrame;
+
+/// How a merged frame relates to the boundary pairing (see `SignalTracer::merge_linked`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BoundaryLink {
+    /// Not part of a kept boundary pair.
+    Unlinked,
+    /// A kept native boundary frame; it evaluates the python frames at these indices.
+    Parent { children: Range<usize> },
+    /// A python frame evaluated by the boundary frame at `parent`.
+    Child { parent: usize },
+}
+
+/// A merged frame with its boundary relationship.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LinkedFrame {
+    pub frame: CallFrame,
+    pub link: BoundaryLink,
+}
+
+/// Merges Python and native stacks.
+///
+/// The associated functions (`SignalTracer::merge_python_native_stacks_with` and the
+/// post-processing passes) take their options per call. An instance holds the options
+/// and the caches repeated merges reuse, and is `Send + Sync`, so one tracer can serve
+/// many threads:
+///
+/// ```
+/// use mixed_stack_tracer::{CallFrame, MergeOptions, SignalTracer};
+///
+/// let tracer = SignalTracer::with_options(MergeOptions::new().keep_boundary_frames(true));
+/// let merged = tracer.merge(
+///     vec![CallFrame::python("0x1", "app.py", "main", 3)],
+///     vec![CallFrame::native("0x2", "", "PyEval_EvalFrameDefault", 0)],
+/// );
+/// assert_eq!(merged.len(), 2);
+/// ```
+#[derive(Default)]
+pub struct SignalTracer {
+    options: MergeOptions,
+    /// Symbolizer of the calling process, created on first use.
+    #[cfg(target_os = "linux")]
+    symbolizer: Mutex<Option<Symbolizer>>,
+}
+
+impl SignalTracer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A tracer merging with `options`.
+    pub fn with_options(options: MergeOptions) -> Self {
+        // The remaining fields only exist on Linux.
+        #[allow(clippy::needless_update)]
+        SignalTracer {
+            options,
+            ..Self::default()
+        }
+    }
+
+    pub fn options(&self) -> &MergeOptions {
+        &self.options
+    }
+
+    /// Merge one pair of stacks with the tracer's options; see
+    /// `merge_python_native_stacks_with`.
+    pub fn merge(
+        &self,
+        python_stacks: impl Into<Vec<CallFrame>>,
+        native_stacks: impl Into<Vec<CallFrame>>,
+    ) -> Vec<CallFrame> {
+        Self::merge_python_native_stacks_with(python_stacks, native_stacks, &self.options)
+    }
+
+    /// Fallible `merge`; see `try_merge_python_native_stacks_with`.
+    pub fn try_merge(
+        &self,
+        python_stacks: impl Into<Vec<CallFrame>>,
+        native_stacks: impl Into<Vec<CallFrame>>,
+    ) -> io::Result<Vec<CallFrame>> {
+        Self::try_merge_python_native_stacks_with(python_stacks, native_stacks, &self.options)
+    }
+
+    /// `merge` of two captured traces, keeping their metadata; see `merge_traces_with`.
+    pub fn merge_trace(&self, python: StackTrace, native: StackTrace) -> StackTrace {
+        Self::merge_traces_with(python, native, &self.options)
+    }
+
+    /// Fallible `merge_trace`; see `try_merge_traces_with`.
+    pub fn try_merge_trace(
+        &self,
+        python: StackTrace,
+        native: StackTrace,
+    ) -> io::Result<StackTrace> {
+        Self::try_merge_traces_with(python, native, &self.options)
+    }
+
+    /// Merge many `(python, native)` stack pairs in parallel on the rayon thread pool,
+    /// e.g. every sample of a large profile. Results are in input order.
+    #[cfg(feature = "parallel")]
+    pub fn merge_batch<I, P, N>(&self, stacks: I) -> Vec<Vec<CallFrame>>
+    where
+        I: rayon::iter::IntoParallelIterator<Item = (P, N)>,
+        P: Into<Vec<CallFrame>>,
+        N: Into<Vec<CallFrame>>,
+    {
+        use rayon::iter::ParallelIterator;
+
+        stacks
+            .into_par_iter()
+            .map(|(python, native)| self.merge(python, native))
+            .collect()
+    }
+
+    /// `merge_batch` of captured traces, keeping their metadata like `merge_trace`.
+    #[cfg(feature = "parallel")]
+    pub fn merge_trace_batch<I>(&self, traces: I) -> Vec<StackTrace>
+    where
+        I: rayon::iter::IntoParallelIterator<Item = (StackTrace, StackTrace)>,
+    {
+        use rayon::iter::ParallelIterator;
+
+        traces
+            .into_par_iter()
+            .map(|(python, native)| self.merge_trace(python, native))
+            .collect()
+    }
+
+    /// Resolve unresolved native frames of the calling process through the tracer's
+    /// symbolizer, whose debug info stays cached between calls.
+    #[cfg(target_os = "linux")]
+    pub fn symbolize(&self, frames: &mut [CallFrame]) -> Result<(), TracerError> {
+        let mut symbolizer = self.symbolizer.lock().unwrap_or_else(|e| e.into_inner());
+        if symbolizer.is_none() {
+            let loaded = Symbolizer::new().map_err(|source| TracerError::Symbolication {
+                module: "/proc/self/maps".into(),
+                source,
+            })?;
+            *symbolizer = Some(loaded);
+        }
+        if let Some(symbolizer) = symbolizer.as_mut() {
+            symbolizer.fill_frames(frames);
+        }
+        Ok(())
+    }
+
+    /// Merge python_stacks into native_stacks using heuristic boundaries (PyEval_*).
+    ///
+    /// Rules:
+    /// - Traverse native_stacks in order; for each frame, detect if it's a Python boundary (e.g., PyEval).
+    /// - On Python boundary:
+    ///   * if a python frame is available, consume exactly one python frame and push it into merged (advance index)
+    ///   * otherwise (no python frame available), keep the native frame to avoid losing native context
+    /// - On native frame: push native frame
+    /// - After traversal, append any remaining python frames to merged
+    ///
+    /// Pairing is exact instead when the capture recorded addresses: a boundary with an
+    /// `eval_frame` takes the python frames up to the one whose `frame_address` matches
+    /// (none if no remaining frame does), whatever the options say about counts.
+    pub fn merge_python_native_stacks(
+        python_stacks: impl Into<Vec<CallFrame>>,
+        native_stacks: impl Into<Vec<CallFrame>>,
+    ) -> Vec<CallFrame> {
+        Self::merge_python_native_stacks_with(
+            python_stacks,
+            native_stacks,
+            &MergeOptions::default(),
+        )
+    }
+
+    /// Same as `merge_python_native_stacks`, but with a caller supplied boundary detector.
+    pub fn merge_python_native_stacks_with_detector(
+        python_stacks: impl Into<Vec<CallFrame>>,
+        native_stacks: impl Into<Vec<CallFrame>>,
+        detector: &dyn BoundaryDetector,
+    ) -> Vec<CallFrame> {
+        Self::merge_inner(
+            python_stacks.into(),
+            native_stacks.into(),
+            detector,
+            &MergeOptions::default(),
+        )
+        .0
+    }
+
+    /// Same as `merge_python_native_stacks`, but with boundary detection, boundary
+    /// retention, exhaustion and extra-python-frame behavior taken from `options`.
+    pub fn merge_python_native_stacks_with(
+        python_stacks: impl Into<Vec<CallFrame>>,
+        native_stacks: impl Into<Vec<CallFrame>>,
+        options: &MergeOptions,
+    ) -> Vec<CallFrame> {
+        Self::merge_inner(
+            python_stacks.into(),
+            native_stacks.into(),
+            options.detector(),
+            options,
+        )
+        .0
+    }
+
+    /// Like `merge_python_native_stacks_with`, but fails with `InvalidData` when python
+    /// frames outnumber the boundaries under `ExtraPythonFrames::Error`, or when an input
+    /// looks reversed under `MergeOptions::validate_order`.
+    pub fn try_merge_python_native_stacks_with(
+        python_stacks: impl Into<Vec<CallFrame>>,
+        native_stacks: impl Into<Vec<CallFrame>>,
+        options: &MergeOptions,
+    ) -> io::Result<Vec<CallFrame>> {
+        let (python_stacks, native_stacks) = (python_stacks.into(), native_stacks.into());
+        if options.validates_order() {
+            Self::validate_stack_order(&python_stacks, options.python_stack_order())?;
+            Self::validate_stack_order(&native_stacks, options.native_stack_order())?;
+        }
+        let (merged, counts) =
+            Self::merge_inner(python_stacks, native_stacks, options.detector(), options);
+        let leftover = counts.leftover;
+        if leftover > 0 && options.extra_python_frames_policy() == ExtraPythonFrames::Error {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} python frames left without a boundary", leftover),
+            ));
+        }
+        Ok(merged)
+    }
+
+    /// Merge like `merge_python_native_stacks_with`, recording for each frame which
+    /// boundary evaluates which python frame.
+    ///
+    /// Links only exist with `MergeOptions::keep_boundary_frames`: otherwise the boundary
+    /// frame is replaced and every frame is `Unlinked`. The frames are the same as the
+    /// plain merge's, so native frame counts are preserved exactly when boundaries are kept.
+    pub fn merge_linked(
+        python_stacks: impl Into<Vec<CallFrame>>,
+        native_stacks: impl Into<Vec<CallFrame>>,
+        options: &MergeOptions,
+    ) -> Vec<LinkedFrame> {
+        let mut merged: Vec<LinkedFrame> = Vec::new();
+        let mut last_native: Option<usize> = None;
+        Self::merge_walk(
+            python_stacks.into(),
+            native_stacks.into(),
+            options.detector(),
+            options,
+            |frame, linked| {
+                let index = merged.len();
+                let link = match (linked, last_native) {
+                    (true, Some(parent)) => {
+                        let parent_link = &mut merged[parent].link;
+                        match parent_link {
+                            BoundaryLink::Parent { children } => children.end = index + 1,
+                            _ => {
+                                *parent_link = BoundaryLink::Parent {
+                                    children: index..index + 1,
+                                }
+                            }
+                        }
+                        BoundaryLink::Child { parent }
+                    }
+                    _ => BoundaryLink::Unlinked,
+                };
+                if let CallFrame::CFrame { .. } = frame {
+                    last_native = Some(index);
+                }
+                merged.push(LinkedFrame { frame, link });
+            },
+        );
+        merged
+    }
+
+    /// Merged stack and the counts of the walk (python frames not placed, ...).
+    pub(crate) fn merge_inner(
+        python_stacks: Vec<CallFrame>,
+        native_stacks: Vec<CallFrame>,
+        detector: &dyn BoundaryDetector,
+        options: &MergeOptions,
+    ) -> (Vec<CallFrame>, WalkCounts) {
+        let mut merged = Vec::new();
+        let counts = Self::merge_walk(
+            python_stacks,
+            native_stacks,
+            detector,
+            options,
+            |frame, _| merged.push(frame),
+        );
+        (merged, counts)
+    }
+
+    /// Hand merged frames to `emit` in order, with whether each one is a python frame
+    /// linked to the kept boundary before it.
+    fn merge_walk(
+        python_stacks: Vec<CallFrame>,
+        native_stacks: Vec<CallFrame>,
+        detector: &dyn BoundaryDetector,
+        options: &MergeOptions,
+        mut emit: impl FnMut(CallFrame, bool),
+    ) -> WalkCounts {
+        let (mut python_stacks, mut native_stacks) = (python_stacks, native_stacks);
+        if options.python_stack_order() == StackOrder::RootFirst {
+            python_stacks.reverse();
+        }
+        if options.native_stack_order() == StackOrder::RootFirst {
+            native_stacks.reverse();
+        }
+        // Inlined callees are frames of their own, both for boundary detection (an inlined
+        // eval loop is still a boundary) and in the merged output.
+        let native_stacks = CallFrame::expand_inlined(native_stacks);
+        let mut walk = Picks::new(
+            &python_stacks,
+            StackOrder::LeafFirst,
+            &native_stacks,
+            StackOrder::LeafFirst,
+            detector,
+            options,
+        );
+        let picks: Vec<(Pick, bool)> = std::iter::from_fn(|| {
+            let pick = walk.next()?;
+            Some((pick, walk.linked()))
+        })
+        .collect();
+        let mut counts = walk.counts();
+        let limit = options.frame_limit().unwrap_or(usize::MAX);
+
+        // Picks are increasing within each source, so frames are moved out in one pass each.
+        let mut native_frames = native_stacks.into_iter();
+        let mut python_frames = python_stacks.into_iter();
+        let (mut native_next, mut python_next) = (0, 0);
+        let mut emitted = 0;
+        for (pick, linked) in picks {
+            if emitted == limit {
+                counts.truncated += 1;
+                continue;
+            }
+            let frame = match pick {
+                Pick::Native(i) => {
+                    let skipped = i - native_next;
+                    native_next = i + 1;
+                    native_frames.nth(skipped)
+                }
+                Pick::Python(i) => {
+                    let skipped = i - python_next;
+                    python_next = i + 1;
+                    python_frames.nth(skipped)
+                }
+            };
+            if let Some(frame) = frame {
+                emit(frame, linked);
+                emitted += 1;
+            }
+        }
+        if counts.truncated > 0 {
+            emit(truncation_marker(counts.truncated), false);
+        }
+        counts
+    }
+}
+
+impl fmt::Debug for SignalTracer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SignalTracer")
+            .field("options", &self.options)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merge_options::PythonExhausted;
+    use crate::CallFrame;
+
+    fn cframe(name: &str) -> CallFrame {
+        CallFrame::native("0x0", "", name, 0)
+    }
+
+    fn pyframe(name: &str) -> CallFrame {
+        CallFrame::python("0x0", "", name, 0)
+    }
+
+    fn funcs(frames: &[CallFrame]) -> Vec<String> {
+        frames.iter().map(|f| f.func().to_string()).collect()
+    }
+
+    #[test]
+    fn test_inlined_boundary() {
+        // The eval loop was inlined into its caller: the inlined frame is the boundary.
+        let inlined = crate::InlineFrame {
+            func: "_PyEval_EvalFrameDefault".to_string(),
+            file: String::new(),
+            lineno: 0,
+        };
+        let native = vec![cframe("A"), cframe("run_eval").with_inlined(vec![inlined])];
+        let python = vec![pyframe("py1"), pyframe("py2")];
+
+        let merged = SignalTracer::merge_python_native_stacks(python, native);
+        assert_eq!(funcs(&merged), vec!["A", "py1", "run_eval", "py2"]);
+    }
+
+    #[test]
+    fn test_simple_insert() {
+        // native: A -> PyEval -> B
+        // python: py1 -> py2
+        let native = vec![cframe("A"), cframe("PyEval_EvalFrameDefault"), cframe("B")];
+        let python = vec![pyframe("py1"), pyframe("py2")];
+
+        let merged = SignalTracer::merge_python_native_stacks(python, native);
+        let got = funcs(&merged);
+
+        // Expect: A, py1, B, py2
+        assert_eq!(got, vec!["A", "py1", "B", "py2"]);
+    }
+
+    #[test]
+    fn test_python_shortage() {
+        // native: PyEval, PyEval, C
+        // python: only py1
+        let native = vec![
+            cframe("PyEval_EvalFrameDefault"),
+            cframe("PyEval_EvalFrameDefault"),
+            cframe("C"),
+        ];
+        let python = vec![pyframe("py1")];
+
+        let merged = SignalTracer::merge_python_native_stacks(python, native);
+        let got = funcs(&merged);
+
+        // Expect:
+        // first PyEval -> py1
+        // second PyEval -> no python left, keep native PyEval
+        // then C
+        assert_eq!(got, vec!["py1", "PyEval_EvalFrameDefault", "C"]);
+    }
+
+    #[test]
+    fn test_python_extra() {
+        // native has no PyEval, python has frames => all python frames appended
+        let native = vec![cframe("A"), cframe("B")];
+        let python = vec![pyframe("py1"), pyframe("py2")];
+
+        let merged = SignalTracer::merge_python_native_stacks(python, native);
+        let got = funcs(&merged);
+
+        // Expect: A, B, py1, py2
+        assert_eq!(got, vec!["A", "B", "py1", "py2"]);
+    }
+
+    #[test]
+    fn test_extra_python_frames_policies() {
+        let native = vec![
+            cframe("A"),
+            cframe("PyEval_EvalFrameDefault"),
+            cframe("main"),
+        ];
+        let python = vec![pyframe("py1"), pyframe("py2"), pyframe("py3")];
+        let merge = |policy| {
+            let opts = MergeOptions::new().on_extra_python_frames(policy);
+            SignalTracer::try_merge_python_native_stacks_with(python.clone(), native.clone(), &opts)
+                .map(|merged| funcs(&merged))
+        };
+
+        assert_eq!(
+            merge(ExtraPythonFrames::Append).unwrap(),
+            vec!["A", "py1", "main", "py2", "py3"]
+        );
+        assert_eq!(
+            merge(ExtraPythonFrames::Drop).unwrap(),
+            vec!["A", "py1", "main"]
+        );
+        assert_eq!(
+            merge(ExtraPythonFrames::InterleaveAtLastBoundary).unwrap(),
+            vec!["A", "py1", "py2", "py3", "main"]
+        );
+        let err = merge(ExtraPythonFrames::Error).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // Python frames that all found a boundary are never an error.
+        let opts = MergeOptions::new().on_extra_python_frames(ExtraPythonFrames::Error);
+        assert!(SignalTracer::try_merge_python_native_stacks_with(
+            python[..1].to_vec(),
+            native.clone(),
+            &opts
+        )
+        .is_ok());
+        // The infallible merge drops them.
+        assert_eq!(
+            funcs(&SignalTracer::merge_python_native_stacks_with(
+                python, native, &opts
+            )),
+            vec!["A", "py1", "main"]
+        );
+    }
+
+    #[test]
+    fn test_root_first_inputs() {
+        // native: B -> PyEval -> A, python: py2 -> py1 (root first)
+        let native = vec![cframe("B"), cframe("PyEval_EvalFrameDefault"), cframe("A")];
+        let python = vec![pyframe("py2"), pyframe("py1")];
+        let opts = MergeOptions::new()
+            .native_order(StackOrder::RootFirst)
+            .python_order(StackOrder::RootFirst);
+
+        let merged = SignalTracer::merge_python_native_stacks_with(python, native, &opts);
+        // The merged stack is leaf first regardless.
+        assert_eq!(funcs(&merged), vec!["A", "py1", "B", "py2"]);
+    }
+
+    #[test]
+    fn test_validate_order_rejects_reversed_input() {
+        let native = vec![
+            cframe("main"),
+            cframe("Py_Main"),
+            cframe("PyEval_EvalFrameDefault"),
+            cframe("read"),
+        ];
+        let python = vec![pyframe("py1")];
+        let opts = MergeOptions::new().validate_order(true);
+        let err = SignalTracer::try_merge_python_native_stacks_with(
+            python.clone(),
+            native.clone(),
+            &opts,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let opts = opts.native_order(StackOrder::RootFirst);
+        let merged = SignalTracer::try_merge_python_native_stacks_with(python, native, &opts);
+        assert_eq!(
+            funcs(&merged.unwrap()),
+            vec!["read", "py1", "Py_Main", "main"]
+        );
+    }
+
+    #[test]
+    fn test_synthetic_frames() {
+        let native = vec![
+            cframe("A"),
+            CallFrame::synthetic("[cuda sync]", "cuda"),
+            cframe("PyEval_EvalFrameDefault"),
+            cframe("B"),
+            cframe("PyEval_EvalFrameDefault"),
+        ];
+        // A marker in the python stack does not use up a boundary of its own.
+        let python = vec![
+            CallFrame::synthetic("[gc]", "gc"),
+            pyframe("py1"),
+            pyframe("py2"),
+        ];
+
+        let merged = SignalTracer::merge_python_native_stacks(python, native);
+        assert_eq!(
+            funcs(&merged),
+            vec!["A", "[cuda sync]", "[gc]", "py1", "B", "py2"]
+        );
+    }
+
+    #[test]
+    fn test_no_python_frames() {
+        // native has PyEval markers, but no python frames at all
+        let native = vec![cframe("X"), cframe("PyEval_EvalFrameDefault"), cframe("Y")];
+        let python: Vec<CallFrame> = vec![];
+
+        let merged = SignalTracer::merge_python_native_stacks(python, native);
+        let got = funcs(&merged);
+
+        // Expect: preserve native PyEval since no python frames to insert
+        assert_eq!(got, vec!["X", "PyEval_EvalFrameDefault", "Y"]);
+    }
+
+    #[test]
+    fn test_custom_boundary_predicate() {
+        // a custom interpreter whose boundary symbol is "interp_eval"
+        let native = vec![
+            cframe("main"),
+            cframe("interp_eval"),
+            cframe("PyEval_EvalFrameDefault"),
+        ];
+        let python = vec![pyframe("py1"), pyframe("py2")];
+        let opts = MergeOptions::new().boundary_predicate(|f| match f {
+            CallFrame::CFrame { func, .. } => func == "interp_eval",
+            _ => false,
+        });
+
+        let merged = SignalTracer::merge_python_native_stacks_with(python, native, &opts);
+        let got = funcs(&merged);
+
+        // PyEval is an ordinary native frame under the custom predicate
+        assert_eq!(got, vec!["main", "py1", "PyEval_EvalFrameDefault", "py2"]);
+    }
+
+    #[test]
+    fn test_keep_boundary_frames() {
+        let native = vec![cframe("A"), cframe("PyEval_EvalFrameDefault"), cframe("B")];
+        let python = vec![pyframe("py1")];
+        let opts = MergeOptions::new().keep_boundary_frames(true);
+
+        let merged = SignalTracer::merge_python_native_stacks_with(python, native, &opts);
+        let got = funcs(&merged);
+
+        assert_eq!(got, vec!["A", "PyEval_EvalFrameDefault", "py1", "B"]);
+    }
+
+    #[test]
+    fn test_merge_linked_annotates_kept_boundaries() {
+        let native = vec![
+            cframe("A"),
+            cframe("PyEval_EvalFrameDefault"),
+            cframe("B"),
+            cframe("PyEval_EvalFrameDefault"),
+        ];
+        let python = vec![pyframe("py1"), pyframe("py2"), pyframe("py3")];
+        let opts = MergeOptions::new().keep_boundary_frames(true);
+
+        let linked = SignalTracer::merge_linked(python.clone(), native.clone(), &opts);
+        let frames: Vec<CallFrame> = linked.iter().map(|l| l.frame.clone()).collect();
+        assert_eq!(
+            frames,
+            SignalTracer::merge_python_native_stacks_with(python.clone(), native.clone(), &opts)
+        );
+        let links: Vec<BoundaryLink> = linked.into_iter().map(|l| l.link).collect();
+        assert_eq!(
+            links,
+            vec![
+                BoundaryLink::Unlinked,
+                BoundaryLink::Parent { children: 2..3 },
+                BoundaryLink::Child { parent: 1 },
+                BoundaryLink::Unlinked,
+                BoundaryLink::Parent { children: 5..6 },
+                BoundaryLink::Child { parent: 4 },
+                // Appended after the walk: no boundary evaluates it.
+                BoundaryLink::Unlinked,
+            ]
+        );
+
+        // Replaced boundaries leave nothing to link.
+        let replaced = SignalTracer::merge_linked(python, native, &MergeOptions::new());
+        assert!(replaced.iter().all(|l| l.link == BoundaryLink::Unlinked));
+    }
+
+    #[test]
+    fn test_python_frames_per_boundary() {
+        // leaf -> PyEval(h) -> numpy_c -> PyEval(g, f, <module>) -> Py_RunMain
+        let native = vec![
+            cframe("sleep"),
+            cframe("PyEval_EvalFrameDefault"),
+            cframe("numpy_c"),
+            cframe("PyEval_EvalFrameDefault"),
+            cframe("Py_RunMain"),
+        ];
+        let python = vec![
+            pyframe("h"),
+            pyframe("g"),
+            pyframe("f"),
+            pyframe("<module>"),
+        ];
+        let expected = vec!["sleep", "h", "numpy_c", "g", "f", "<module>", "Py_RunMain"];
+
+        let opts = MergeOptions::new().python_frames_per_boundary(|n| [1, 3][n]);
+        let merged =
+            SignalTracer::merge_python_native_stacks_with(python.clone(), native.clone(), &opts);
+        assert_eq!(funcs(&merged), expected);
+
+        let opts = MergeOptions::new().python_version_hint(3, 11);
+        let merged =
+            SignalTracer::merge_python_native_stacks_with(python.clone(), native.clone(), &opts);
+        assert_eq!(funcs(&merged), expected);
+
+        // Before 3.11 every python call had its own eval frame.
+        let opts = MergeOptions::new().python_version_hint(3, 10);
+        let merged =
+            SignalTracer::merge_python_native_stacks_with(python.clone(), native.clone(), &opts);
+        assert_eq!(
+            funcs(&merged),
+            vec!["sleep", "h", "numpy_c", "g", "Py_RunMain", "f", "<module>"]
+        );
+
+        // A count of 0 leaves the boundary as a native frame; kept boundaries link the run.
+        let opts = MergeOptions::new()
+            .keep_boundary_frames(true)
+            .python_frames_per_boundary(|n| [0, 4][n]);
+        let linked = SignalTracer::merge_linked(python, native, &opts);
+        assert_eq!(linked[1].link, BoundaryLink::Unlinked);
+        assert_eq!(linked[3].link, BoundaryLink::Parent { children: 4..8 });
+        assert_eq!(linked[7].link, BoundaryLink::Child { parent: 3 });
+    }
+
+    #[test]
+    fn test_drop_boundary_when_python_exhausted() {
+        let native = vec![
+            cframe("PyEval_EvalFrameDefault"),
+            cframe("PyEval_EvalFrameDefault"),
+            cframe("C"),
+        ];
+        let python = vec![pyframe("py1")];
+        let opts = MergeOptions::new().on_python_exhausted(PythonExhausted::DropBoundary);
+
+        let merged = SignalTracer::merge_python_native_stacks_with(python, native, &opts);
+        let got = funcs(&merged);
+
+        assert_eq!(got, vec!["py1", "C"]);
+    }
+
+    #[test]
+    fn test_merge_by_frame_address() {
+        let eval = |address| cframe("_PyEval_EvalFrameDefault").with_eval_frame(address);
+        let py = |name, address| pyframe(name).with_frame_address(address);
+        // 3.11+: the leaf eval loop runs `inner` and `outer` (entered with `outer`), a C
+        // extension re-enters python for `callback`; one eval loop belongs to another
+        // interpreter's frame.
+        let native = vec![
+            cframe("write"),
+            eval(0x300),
+            cframe("call_callback"),
+            eval(0x200),
+            eval(0x900),
+            cframe("PyEval_EvalCode"),
+            cframe("main"),
+        ];
+        let python = vec![
+            py("callback", 0x300),
+            py("inner", 0x100),
+            py("outer", 0x200),
+            py("<module>", 0x50),
+        ];
+
+        let merged = SignalTracer::merge_python_native_stacks(python, native);
+        assert_eq!(
+            funcs(&merged),
+            vec![
+                "write",
+                "callback",
+                "call_callback",
+                "inner",
+                "outer",
+                "_PyEval_EvalFrameDefault",
+                "<module>",
+                "main"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_by_frame_address_needs_addresses() {
+        // Without python addresses the eval frames pair positionally.
+        let native = vec![
+            cframe("_PyEval_EvalFrameDefault").with_eval_frame(0x200),
+            cframe("main"),
+        ];
+        let python = vec![pyframe("inner"), pyframe("outer")];
+        let merged = SignalTracer::merge_python_native_stacks(python, native);
+        assert_eq!(funcs(&merged), vec!["inner", "main", "outer"]);
+    }
+
+    #[test]
+    fn test_merge_with_detector() {
+        struct PyPyDetector;
+        impl BoundaryDetector for PyPyDetector {
+            fn is_boundary(&self, frame: &CallFrame) -> bool {
+                matches!(frame, CallFrame::CFrame { func, .. } if func.starts_with("pypy_g_"))
+            }
+        }
+
+        let native = vec![cframe("main"), cframe("pypy_g_execute_frame"), cframe("B")];
+        let python = vec![pyframe("py1")];
+
+        let merged =
+            SignalTracer::merge_python_native_stacks_with_detector(python, native, &PyPyDetector);
+        let got = funcs(&merged);
+
+        assert_eq!(got, vec!["main", "py1", "B"]);
+    }
+
+    #[test]
+    fn test_merge_pypy_stack() {
+        let native = vec![
+            cframe("pypy_g_ll_os_ll_os_read"),
+            cframe("pypy_g_dispatch_bytecode__AccessDirect_None"),
+            cframe("pypy_g_execute_frame__AccessDirect_None"),
+            cframe("pypy_g_call_function"),
+            cframe("pypy_g_dispatch_bytecode__AccessDirect_None"),
+            cframe("pypy_g_execute_frame__AccessDirect_None"),
+            cframe("pypy_main_function"),
+        ];
+        let python = vec![pyframe("read_config"), pyframe("<module>")];
+
+        let merged = SignalTracer::merge_python_native_stacks_with_detector(
+            python,
+            native,
+            crate::PythonImplementation::PyPy.detector(),
+        );
+        let got = funcs(&merged);
+
+        assert_eq!(
+            got,
+            vec![
+                "pypy_g_ll_os_ll_os_read",
+                "pypy_g_dispatch_bytecode__AccessDirect_None",
+                "read_config",
+                "pypy_g_call_function",
+                "pypy_g_dispatch_bytecode__AccessDirect_None",
+                "<module>",
+                "pypy_main_function",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_shared_tracer() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<SignalTracer>();
+        assert_send_sync::<MergeOptions>();
+        assert_send_sync::<CallFrame>();
+        assert_send_sync::<crate::FrameFilter>();
+        assert_send_sync::<crate::LocalsPolicy>();
+        assert_send_sync::<crate::Profile>();
+
+        let tracer = SignalTracer::with_options(MergeOptions::new().keep_boundary_frames(true));
+        let merged: Vec<Vec<String>> = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..4)
+                .map(|_| {
+                    scope.spawn(|| {
+                        funcs(&tracer.merge(
+                            vec![pyframe("py1")],
+                            vec![cframe("A"), cframe("PyEval_EvalFrameDefault")],
+                        ))
+                    })
+                })
+                .collect();
+            workers.into_iter().map(|w| w.join().unwrap()).collect()
+        });
+        assert!(merged
+            .iter()
+            .all(|m| m == &["A", "PyEval_EvalFrameDefault", "py1"]));
+    }
+
+    #[test]
+    fn test_tracer_merge_trace() {
+        let tracer = SignalTracer::with_options(
+            MergeOptions::new()
+                .keep_boundary_frames(true)
+                .on_extra_python_frames(ExtraPythonFrames::Error),
+        );
+        let native = StackTrace {
+            pid: Some(42),
+            ..StackTrace::from(vec![cframe("A"), cframe("PyEval_EvalFrameDefault")])
+        };
+        let merged = tracer.merge_trace(vec![pyframe("py1")].into(), native.clone());
+        assert_eq!(funcs(&merged), ["A", "PyEval_EvalFrameDefault", "py1"]);
+        assert_eq!(merged.pid, Some(42));
+        assert_eq!(merged.source, crate::CaptureSource::Merged);
+
+        let extra = vec![pyframe("py1"), pyframe("py2")];
+        assert!(tracer.try_merge_trace(extra.into(), native).is_err());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_instance_symbolize() {
+        let ip = test_instance_symbolize as *const () as u64;
+        let mut frames = vec![CallFrame::native(format!("{:#x}", ip), "", "??", 0)];
+        let tracer = SignalTracer::new();
+        tracer.symbolize(&mut frames).unwrap();
+        assert!(frames[0].func().ends_with("test_instance_symbolize"));
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn test_merge_batch() {
+        let tracer = SignalTracer::new();
+        let stacks: Vec<_> = (0..1000)
+            .map(|i| {
+                (
+                    vec![pyframe(&format!("py{}", i))],
+                    vec![cframe("A"), cframe("PyEval_EvalFrameDefault")],
+                )
+            })
+            .collect();
+        let merged = tracer.merge_batch(stacks.clone());
+        assert_eq!(merged.len(), 1000);
+        for (i, stack) in merged.iter().enumerate() {
+            assert_eq!(funcs(stack), vec!["A".to_string(), format!("py{}", i)]);
+        }
+
+        let traces = stacks
+            .into_iter()
+            .map(|(python, native)| (python.into(), native.into()));
+        let merged = tracer.merge_trace_batch(traces.collect::<Vec<_>>());
+        assert_eq!(funcs(&merged[7]), ["A", "py7"]);
+    }
+}
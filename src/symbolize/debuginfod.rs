@@ -0,0 +1,222 @@
+//! debuginfod client: fetches separate debug info of stripped binaries by ELF build-id.
+//!
+//! Follows the elfutils conventions: servers come from `DEBUGINFOD_URLS`, downloads land in
+//! `<cache>/<build-id>/debuginfo` and are reused on later runs.
+
+use std::env;
+use std::fs::{self, File};
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Queries a list of debuginfod servers, caching results on disk.
+#[derive(Clone, Debug)]
+pub struct DebuginfodClient {
+    urls: Vec<String>,
+    cache_dir: PathBuf,
+    timeout: Duration,
+}
+
+impl DebuginfodClient {
+    pub fn new(urls: Vec<String>, cache_dir: impl Into<PathBuf>) -> Self {
+        DebuginfodClient {
+            urls: urls
+                .into_iter()
+                .map(|u| u.trim_end_matches('/').to_string())
+                .collect(),
+            cache_dir: cache_dir.into(),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Client configured like elfutils: `DEBUGINFOD_URLS` (space separated),
+    /// `DEBUGINFOD_CACHE_PATH` and `DEBUGINFOD_TIMEOUT` (seconds). `None` without servers.
+    pub fn from_env() -> Option<Self> {
+        Self::from_vars(|name| env::var(name).ok())
+    }
+
+    fn from_vars(var: impl Fn(&str) -> Option<String>) -> Option<Self> {
+        let urls: Vec<String> = var("DEBUGINFOD_URLS")?
+            .split_whitespace()
+            .map(String::from)
+            .collect();
+        if urls.is_empty() {
+            return None;
+        }
+        let cache_dir = var("DEBUGINFOD_CACHE_PATH")
+            .map(PathBuf::from)
+            .or_else(|| var("XDG_CACHE_HOME").map(|d| PathBuf::from(d).join("debuginfod_client")))
+            .or_else(|| var("HOME").map(|d| PathBuf::from(d).join(".cache/debuginfod_client")))?;
+        let mut client = Self::new(urls, cache_dir);
+        if let Some(secs) = var("DEBUGINFOD_TIMEOUT").and_then(|s| s.parse().ok()) {
+            client.timeout = Duration::from_secs(secs);
+        }
+        Some(client)
+    }
+
+    /// Per-request timeout for each server.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Path of the debug info for `build_id`, downloading it on a cache miss. Servers are
+    /// tried in order; the last error is returned when none has the file.
+    pub fn fetch_debuginfo(&self, build_id: &[u8]) -> io::Result<PathBuf> {
+        let hex = super::module_map::hex(build_id);
+        let dir = self.cache_dir.join(&hex);
+        let cached = dir.join("debuginfo");
+        if cached.is_file() {
+            return Ok(cached);
+        }
+
+        fs::create_dir_all(&dir)?;
+        let agent: ureq::Agent = ureq::Agent::config_builder()
+            .timeout_global(Some(self.timeout))
+            .build()
+            .into();
+        let mut last_error =
+            io::Error::new(io::ErrorKind::NotFound, "no debuginfod server configured");
+        for url in &self.urls {
+            let url = format!("{}/buildid/{}/debuginfo", url, hex);
+            match download(&agent, &url, &dir) {
+                Ok(partial) => {
+                    // Publish atomically so concurrent readers never see a partial file.
+                    fs::rename(&partial, &cached)?;
+                    return Ok(cached);
+                }
+                Err(err) => last_error = err,
+            }
+        }
+        Err(last_error)
+    }
+}
+
+fn download(agent: &ureq::Agent, url: &str, dir: &std::path::Path) -> io::Result<PathBuf> {
+    let mut response = agent
+        .get(url)
+        .call()
+        .map_err(|e| io::Error::other(format!("{}: {}", url, e)))?;
+    let partial = dir.join(format!(".debuginfo.{}", std::process::id()));
+    let result = File::create(&partial)
+        .and_then(|mut file| io::copy(&mut response.body_mut().as_reader(), &mut file));
+    match result {
+        Ok(_) => Ok(partial),
+        Err(err) => {
+            let _ = fs::remove_file(&partial);
+            Err(err)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Serve `requests` HTTP requests: 200 with `body` for `path`, 404 otherwise.
+    fn serve(path: &'static str, body: &'static [u8], requests: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for stream in listener.incoming().take(requests) {
+                let mut stream = stream.unwrap();
+                let mut request_line = String::new();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                reader.read_line(&mut request_line).unwrap();
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header.trim().is_empty() {
+                        break;
+                    }
+                }
+                let found = request_line.split_whitespace().nth(1) == Some(path);
+                let (status, body) = if found {
+                    ("200 OK", body)
+                } else {
+                    ("404 Not Found", &b""[..])
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    body.len()
+                )
+                .unwrap();
+                stream.write_all(body).unwrap();
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    fn cache_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("mst-debuginfod-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_fetch_and_cache() {
+        let missing = serve("/none", b"", 1);
+        let server = serve("/buildid/abcd/debuginfo", b"ELF debug", 1);
+        let cache = cache_dir("fetch");
+        let client = DebuginfodClient::new(vec![missing, format!("{}/", server)], &cache);
+
+        let path = client.fetch_debuginfo(&[0xab, 0xcd]).unwrap();
+        assert_eq!(path, cache.join("abcd/debuginfo"));
+        assert_eq!(fs::read(&path).unwrap(), b"ELF debug");
+        // Both servers are done serving: the second fetch comes from the cache.
+        assert_eq!(client.fetch_debuginfo(&[0xab, 0xcd]).unwrap(), path);
+        fs::remove_dir_all(&cache).unwrap();
+    }
+
+    #[test]
+    fn test_fetch_not_found() {
+        let server = serve("/none", b"", 1);
+        let cache = cache_dir("missing");
+        let client = DebuginfodClient::new(vec![server], &cache);
+        assert!(client.fetch_debuginfo(&[0x01]).is_err());
+        assert!(!cache.join("01/debuginfo").exists());
+        let _ = fs::remove_dir_all(&cache);
+    }
+
+    #[test]
+    fn test_from_vars() {
+        let vars = |pairs: &'static [(&'static str, &'static str)]| {
+            move |name: &str| {
+                pairs
+                    .iter()
+                    .find(|(k, _)| *k == name)
+                    .map(|(_, v)| v.to_string())
+            }
+        };
+        assert!(DebuginfodClient::from_vars(vars(&[("HOME", "/h")])).is_none());
+        assert!(DebuginfodClient::from_vars(vars(&[("DEBUGINFOD_URLS", " ")])).is_none());
+
+        let client = DebuginfodClient::from_vars(vars(&[
+            ("DEBUGINFOD_URLS", "https://a.example https://b.example/"),
+            ("HOME", "/h"),
+            ("DEBUGINFOD_TIMEOUT", "5"),
+        ]))
+        .unwrap();
+        assert_eq!(client.urls, ["https://a.example", "https://b.example"]);
+        assert_eq!(
+            client.cache_dir,
+            PathBuf::from("/h/.cache/debuginfod_client")
+        );
+        assert_eq!(client.timeout, Duration::from_secs(5));
+
+        let client = DebuginfodClient::from_vars(vars(&[
+            ("DEBUGINFOD_URLS", "https://a.example"),
+            ("DEBUGINFOD_CACHE_PATH", "/c"),
+            ("HOME", "/h"),
+        ]))
+        .unwrap();
+        assert_eq!(client.cache_dir, PathBuf::from("/c"));
+    }
+}
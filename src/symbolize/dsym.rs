@@ -0,0 +1,99 @@
+//! Locating the debug info of Mach-O images (macOS).
+//!
+//! `addr2line` already picks up a `Foo.dSYM` bundle next to the binary it loads. Bundles
+//! kept elsewhere (Xcode archives, `DerivedData`, a local symbol store) are found the way
+//! the DebugSymbols framework finds them: Spotlight indexes the UUIDs of every bundle as
+//! `com_apple_xcode_dsym_uuids`.
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use object::read::macho::{FatArch, MachOFatFile32, MachOFatFile64};
+use object::read::{ReadCache, ReadCacheRange, ReadRef};
+use object::{Architecture, FileKind, Object};
+
+#[cfg(target_arch = "aarch64")]
+const HOST: Architecture = Architecture::Aarch64;
+#[cfg(target_arch = "x86_64")]
+const HOST: Architecture = Architecture::X86_64;
+
+/// DWARF file of the dSYM bundle matching `uuid`, asking Spotlight where bundles are.
+pub fn find_dsym(uuid: [u8; 16]) -> Option<PathBuf> {
+    let query = format!("com_apple_xcode_dsym_uuids == {}", format_uuid(uuid));
+    let output = Command::new("mdfind").arg(query).output().ok()?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|bundle| dwarf_file(Path::new(bundle), uuid))
+}
+
+/// The file under `<bundle>/Contents/Resources/DWARF` whose `LC_UUID` is `uuid`.
+pub fn dwarf_file(bundle: &Path, uuid: [u8; 16]) -> Option<PathBuf> {
+    std::fs::read_dir(bundle.join("Contents/Resources/DWARF"))
+        .ok()?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .find(|path| file_uuid(path) == Some(uuid))
+}
+
+/// `LC_UUID` of the Mach-O file at `path`, of this machine's slice for universal binaries.
+pub fn file_uuid(path: &Path) -> Option<[u8; 16]> {
+    let cache = ReadCache::new(File::open(path).ok()?);
+    let (slice, _) = host_slice(&cache)?;
+    object::File::parse(slice).ok()?.mach_uuid().ok()?
+}
+
+/// This machine's part of a Mach-O file: all of it, or one architecture of a universal
+/// binary. The flag tells which; `addr2line` only loads thin files.
+pub(crate) fn host_slice(cache: &ReadCache<File>) -> Option<(ReadCacheRange<'_, File>, bool)> {
+    let fat = match FileKind::parse(cache).ok()? {
+        FileKind::MachOFat32 => MachOFatFile32::parse(cache)
+            .ok()?
+            .arches()
+            .iter()
+            .find(|arch| arch.architecture() == HOST)
+            .map(|arch| arch.file_range()),
+        FileKind::MachOFat64 => MachOFatFile64::parse(cache)
+            .ok()?
+            .arches()
+            .iter()
+            .find(|arch| arch.architecture() == HOST)
+            .map(|arch| arch.file_range()),
+        _ => return Some((cache.range(0, cache.len().ok()?), false)),
+    };
+    let (offset, size) = fat?;
+    Some((cache.range(offset, size), true))
+}
+
+/// `uuid` in the upper-case, dashed form Spotlight stores.
+fn format_uuid(uuid: [u8; 16]) -> String {
+    let hex: String = uuid.iter().map(|b| format!("{:02X}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_uuid() {
+        let uuid = [
+            0x1d, 0x2c, 0x3b, 0x4a, 0x59, 0x68, 0x77, 0x86, 0x95, 0xa4, 0xb3, 0xc2, 0xd1, 0xe0,
+            0xff, 0x0e,
+        ];
+        assert_eq!(format_uuid(uuid), "1D2C3B4A-5968-7786-95A4-B3C2D1E0FF0E");
+    }
+
+    #[test]
+    fn test_own_uuid() {
+        // The Apple linker always emits LC_UUID.
+        let exe = std::env::current_exe().unwrap();
+        assert!(file_uuid(&exe).is_some());
+    }
+}
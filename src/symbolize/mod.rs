@@ -0,0 +1,352 @@
+//! DWARF symbolication of raw instruction pointers through `addr2line` (Linux, macOS).
+//!
+//! An ip is mapped to its module through `/proc/<pid>/maps`, then resolved against that
+//! module's debug info; the symbol table is the fallback when there is none. Calls inlined
+//! at the ip are reported in `CFrame::inlined`. On macOS only the per-module part is
+//! shared: `RemoteProcess` maps ips to images itself, and `dsym` finds their debug info.
+
+#[cfg(all(feature = "debuginfod", target_os = "linux"))]
+pub mod debuginfod;
+#[cfg(target_os = "macos")]
+pub mod dsym;
+#[cfg(target_os = "linux")]
+pub mod module_map;
+#[cfg(target_os = "linux")]
+pub mod offline;
+
+#[cfg(target_os = "linux")]
+use std::collections::HashMap;
+#[cfg(target_os = "linux")]
+use std::fs::File;
+#[cfg(target_os = "linux")]
+use std::io;
+#[cfg(target_os = "linux")]
+use std::path::PathBuf;
+
+#[cfg(target_os = "linux")]
+use object::read::ReadCache;
+#[cfg(target_os = "linux")]
+use object::{Object, ObjectSection, ObjectSegment};
+
+#[cfg(all(feature = "debuginfod", target_os = "linux"))]
+use self::debuginfod::DebuginfodClient;
+
+#[cfg(target_os = "linux")]
+use crate::remote::maps::{read_maps, MemoryMap};
+#[cfg(target_os = "linux")]
+use crate::CallFrame;
+use crate::InlineFrame;
+
+/// Resolves ips of one process, caching the debug info of each module it touches.
+#[cfg(target_os = "linux")]
+pub struct Symbolizer {
+    pid: i32,
+    maps: Vec<MemoryMap>,
+    /// Keyed by module path; `None` remembers modules that failed to load.
+    modules: HashMap<String, Option<Module>>,
+    #[cfg(feature = "debuginfod")]
+    debuginfod: Option<DebuginfodClient>,
+}
+
+pub(crate) struct Module {
+    pub(crate) loader: addr2line::Loader,
+    /// Runtime address minus link-time address.
+    pub(crate) bias: u64,
+}
+
+#[cfg(target_os = "linux")]
+impl Symbolizer {
+    /// Symbolizer for the calling process.
+    pub fn new() -> io::Result<Symbolizer> {
+        Self::for_process(unsafe { libc::getpid() })
+    }
+
+    /// Symbolizer for the process `pid`, reading its modules through `/proc/<pid>/root`.
+    ///
+    /// With the `debuginfod` feature, servers from `DEBUGINFOD_URLS` are queried for
+    /// modules without debug info.
+    pub fn for_process(pid: i32) -> io::Result<Symbolizer> {
+        Ok(Symbolizer {
+            pid,
+            maps: read_maps(pid)?,
+            modules: HashMap::new(),
+            #[cfg(feature = "debuginfod")]
+            debuginfod: DebuginfodClient::from_env(),
+        })
+    }
+
+    /// Use `client` for modules without debug info instead of the environment's servers.
+    #[cfg(feature = "debuginfod")]
+    pub fn with_debuginfod(mut self, client: DebuginfodClient) -> Self {
+        self.debuginfod = Some(client);
+        self
+    }
+
+    /// Re-read the memory maps, e.g. after the target loaded more libraries.
+    pub fn refresh(&mut self) -> io::Result<()> {
+        self.maps = read_maps(self.pid)?;
+        Ok(())
+    }
+
+    /// Frame for the instruction at `ip`, with the calls inlined there in `inlined`.
+    /// Unknown ips yield a `"??"` frame.
+    pub fn symbolize(&mut self, ip: u64) -> CallFrame {
+        self.resolve(ip, format!("{:#x}", ip))
+    }
+
+    /// Symbolize a stack of raw ips as produced by a stack walk, one frame per ip.
+    ///
+    /// All but the first ip are return addresses, looked up one byte earlier so they land
+    /// on the call instruction; the frames keep the original ip.
+    pub fn symbolize_stack(&mut self, ips: &[u64]) -> Vec<CallFrame> {
+        ips.iter()
+            .enumerate()
+            .map(|(i, ip)| {
+                let lookup = if i == 0 { *ip } else { ip.wrapping_sub(1) };
+                self.resolve(lookup, format!("{:#x}", ip))
+            })
+            .collect()
+    }
+
+    /// Fill in unresolved native frames (func `"??"`) from their ip, in place.
+    pub fn fill_frames(&mut self, frames: &mut [CallFrame]) {
+        for frame in frames {
+            let CallFrame::CFrame { ip, func, .. } = frame else {
+                continue;
+            };
+            if func != "??" {
+                continue;
+            }
+            let Ok(addr) = u64::from_str_radix(ip.trim_start_matches("0x"), 16) else {
+                continue;
+            };
+            let ip = ip.clone();
+            *frame = self.resolve(addr, ip);
+        }
+    }
+
+    fn resolve(&mut self, ip: u64, hex: String) -> CallFrame {
+        let Some(map) = self.maps.iter().find(|m| m.contains(ip)) else {
+            return CallFrame::native(hex, "", "??", 0);
+        };
+        let path = map.path.clone();
+        let mut functions = if path.starts_with('/') {
+            if !self.modules.contains_key(&path) {
+                let module = Module::load(self.pid, &path, &self.maps, |id| {
+                    self.separate_debug_info(id)
+                });
+                self.modules.insert(path.clone(), module.ok());
+            }
+            self.modules[&path]
+                .as_ref()
+                .map(|module| module.functions(ip))
+                .unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+
+        // The last function is the physical one, the others were inlined into it.
+        match functions.pop() {
+            Some(outer) => {
+                CallFrame::native(hex, outer.file, outer.func, outer.lineno).with_inlined(functions)
+            }
+            None => CallFrame::native(hex, path, "??", 0),
+        }
+    }
+
+    /// Debug info kept apart from a module, looked up by its build-id.
+    #[cfg(feature = "debuginfod")]
+    fn separate_debug_info(&self, build_id: &[u8]) -> Option<PathBuf> {
+        self.debuginfod.as_ref()?.fetch_debuginfo(build_id).ok()
+    }
+
+    #[cfg(not(feature = "debuginfod"))]
+    fn separate_debug_info(&self, _build_id: &[u8]) -> Option<PathBuf> {
+        None
+    }
+}
+
+impl Module {
+    /// `debug_info` is asked for a separate debug file when the module has no DWARF.
+    #[cfg(target_os = "linux")]
+    fn load(
+        pid: i32,
+        path: &str,
+        maps: &[MemoryMap],
+        debug_info: impl FnOnce(&[u8]) -> Option<PathBuf>,
+    ) -> io::Result<Module> {
+        let on_disk = format!("/proc/{}/root{}", pid, path);
+        let on_disk = if File::open(&on_disk).is_ok() {
+            on_disk
+        } else {
+            path.to_string()
+        };
+        let info = inspect(&on_disk, path, maps)?;
+        let dwarf = match (&info.build_id, info.has_debug_info) {
+            (Some(build_id), false) => debug_info(build_id),
+            _ => None,
+        };
+        let loader = addr2line::Loader::new(dwarf.unwrap_or_else(|| PathBuf::from(&on_disk)))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        Ok(Module {
+            loader,
+            bias: info.bias,
+        })
+    }
+
+    /// Functions covering `ip`, innermost inlined call first.
+    pub(crate) fn functions(&self, ip: u64) -> Vec<InlineFrame> {
+        let probe = ip.wrapping_sub(self.bias);
+        let mut frames = Vec::new();
+        if let Ok(mut iter) = self.loader.find_frames(probe) {
+            while let Ok(Some(frame)) = iter.next() {
+                let func = frame
+                    .function
+                    .as_ref()
+                    .and_then(|f| f.demangle().ok())
+                    .map(|n| n.into_owned());
+                let Some(func) = func else { continue };
+                let (file, line) = frame.location.map_or((None, None), |l| (l.file, l.line));
+                frames.push(InlineFrame {
+                    func,
+                    file: file.unwrap_or_default().to_string(),
+                    lineno: line.map_or(0, i64::from),
+                });
+            }
+        }
+        if frames.is_empty() {
+            if let Some(symbol) = self.loader.find_symbol(probe) {
+                let name = addr2line::demangle_auto(symbol.into(), None).into_owned();
+                frames.push(InlineFrame {
+                    func: name,
+                    file: String::new(),
+                    lineno: 0,
+                });
+            }
+        }
+        frames
+    }
+}
+
+/// What symbolization needs to know about a module file before loading its DWARF.
+#[cfg(target_os = "linux")]
+pub(crate) struct ModuleInfo {
+    /// Runtime address minus link-time address.
+    bias: u64,
+    has_debug_info: bool,
+    build_id: Option<Vec<u8>>,
+}
+
+/// Read the headers of `path`: load bias (from its lowest loadable segment and its
+/// offset-0 mapping), presence of `.debug_info` and the build-id note.
+///
+/// Only headers are read, through a page cache, so large libraries stay cheap.
+#[cfg(target_os = "linux")]
+pub(crate) fn inspect(on_disk: &str, path: &str, maps: &[MemoryMap]) -> io::Result<ModuleInfo> {
+    let invalid = |e: object::Error| io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+    let cache = ReadCache::new(File::open(on_disk)?);
+    let file = object::File::parse(&cache).map_err(invalid)?;
+    let first_segment = file.segments().map(|s| s.address()).min().unwrap_or(0) & !0xfff;
+    let base = maps
+        .iter()
+        .filter(|m| m.path == path && m.offset == 0)
+        .map(|m| m.start)
+        .min()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "module is not mapped"))?;
+    Ok(ModuleInfo {
+        bias: base.wrapping_sub(first_segment),
+        has_debug_info: file
+            .section_by_name(".debug_info")
+            .is_some_and(|s| s.size() > 0),
+        build_id: file.build_id().map_err(invalid)?.map(<[u8]>::to_vec),
+    })
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use crate::stack_tracer::SignalTracer;
+
+    fn parse_ip(frame: &CallFrame) -> u64 {
+        match frame {
+            CallFrame::CFrame { ip, .. } => u64::from_str_radix(&ip[2..], 16).unwrap(),
+            _ => panic!("expected a native frame"),
+        }
+    }
+
+    #[inline(always)]
+    fn inlined_capture() -> Vec<CallFrame> {
+        SignalTracer::capture_native_stack().into_frames()
+    }
+
+    #[inline(never)]
+    fn outer_capture() -> Vec<CallFrame> {
+        inlined_capture()
+    }
+
+    #[test]
+    fn test_symbolize_function_address() {
+        let mut symbolizer = Symbolizer::new().unwrap();
+        match &symbolizer.symbolize(outer_capture as *const () as u64) {
+            CallFrame::CFrame {
+                func, file, lineno, ..
+            } => {
+                assert!(func.ends_with("outer_capture"), "{}", func);
+                assert!(file.ends_with("symbolize/mod.rs"), "{}", file);
+                assert!(*lineno > 0);
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_symbolize_reports_inlined_calls() {
+        let captured = outer_capture();
+        let ip = parse_ip(&captured[0]);
+        let mut symbolizer = Symbolizer::new().unwrap();
+        // The leaf is a return address into outer_capture, where inlined_capture was inlined.
+        let frames = symbolizer.symbolize_stack(&[0, ip]);
+        assert_eq!(frames.len(), 2);
+        match &frames[1] {
+            CallFrame::CFrame { func, inlined, .. } => {
+                assert!(func.ends_with("outer_capture"), "{}", func);
+                assert_eq!(inlined.len(), 1, "{:?}", inlined);
+                assert!(inlined[0].func.ends_with("inlined_capture"));
+                assert!(inlined[0].file.ends_with("symbolize/mod.rs"));
+            }
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(parse_ip(&frames[1]), ip);
+    }
+
+    #[test]
+    fn test_inspect_own_executable() {
+        let exe = std::fs::read_link("/proc/self/exe").unwrap();
+        let exe = exe.to_str().unwrap();
+        let maps = read_maps(unsafe { libc::getpid() }).unwrap();
+        let info = inspect(exe, exe, &maps).unwrap();
+        // Test binaries are built with debug info.
+        assert!(info.has_debug_info);
+        assert!(inspect(exe, "/not/mapped", &maps).is_err());
+    }
+
+    #[test]
+    fn test_fill_frames() {
+        let mut symbolizer = Symbolizer::new().unwrap();
+        let ip = format!("{:#x}", outer_capture as *const () as u64);
+        let mut frames = vec![
+            CallFrame::native(ip, "", "??", 0),
+            CallFrame::native("0x1", "", "kept", 3),
+        ];
+        symbolizer.fill_frames(&mut frames);
+        assert!(
+            matches!(&frames[0], CallFrame::CFrame { func, .. } if func.ends_with("outer_capture"))
+        );
+        assert!(matches!(&frames[1], CallFrame::CFrame { func, .. } if func == "kept"));
+        // Unmapped addresses stay unresolved.
+        assert_eq!(
+            symbolizer.symbolize(0x10),
+            CallFrame::native("0x10", "", "??", 0)
+        );
+    }
+}
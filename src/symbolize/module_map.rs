@@ -0,0 +1,145 @@
+//! Module, build-id and module-relative address of native frames, for symbolicating a
+//! trace on another machine than the one that captured it.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+
+use super::inspect;
+use crate::remote::maps::{read_maps, MemoryMap};
+use crate::stack_tracer::SignalTracer;
+use crate::CallFrame;
+
+/// Modules mapped into a process: what `annotate` records about each frame's ip.
+#[derive(Clone, Debug)]
+pub struct ModuleMap {
+    maps: Vec<MemoryMap>,
+    /// Keyed by module path; `None` for files whose headers could not be read.
+    modules: HashMap<String, Option<ModuleId>>,
+}
+
+/// Identity of one mapped module file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ModuleId {
+    pub path: String,
+    /// GNU build-id, lowercase hex.
+    pub build_id: Option<String>,
+    /// Runtime address minus link-time address.
+    pub bias: u64,
+}
+
+impl ModuleMap {
+    /// Modules of the calling process, from `/proc/self/maps`.
+    pub fn current() -> io::Result<ModuleMap> {
+        Self::for_process(unsafe { libc::getpid() })
+    }
+
+    /// Modules of process `pid`; their headers are read through `/proc/<pid>/root`.
+    pub fn for_process(pid: i32) -> io::Result<ModuleMap> {
+        let maps = read_maps(pid)?;
+        let mut modules = HashMap::new();
+        for map in maps
+            .iter()
+            .filter(|m| m.executable && m.path.starts_with('/'))
+        {
+            if modules.contains_key(&map.path) {
+                continue;
+            }
+            let on_disk = format!("/proc/{}/root{}", pid, map.path);
+            let on_disk = if File::open(&on_disk).is_ok() {
+                on_disk
+            } else {
+                map.path.clone()
+            };
+            let module = inspect(&on_disk, &map.path, &maps)
+                .ok()
+                .map(|info| ModuleId {
+                    path: map.path.clone(),
+                    build_id: info.build_id.as_deref().map(hex),
+                    bias: info.bias,
+                });
+            modules.insert(map.path.clone(), module);
+        }
+        Ok(ModuleMap { maps, modules })
+    }
+
+    /// The module `ip` lies in, if it is a mapped file.
+    pub fn module(&self, ip: u64) -> Option<&ModuleId> {
+        let map = self.maps.iter().find(|m| m.contains(ip))?;
+        self.modules.get(&map.path)?.as_ref()
+    }
+
+    /// Set `module_path`, `build_id` and `offset` of every native frame whose ip lies in
+    /// a mapped module. Other frames are left alone.
+    pub fn annotate(&self, frames: &mut [CallFrame]) {
+        for frame in frames {
+            let CallFrame::CFrame {
+                ip,
+                module_path,
+                build_id,
+                offset,
+                ..
+            } = frame
+            else {
+                continue;
+            };
+            let Ok(addr) = u64::from_str_radix(ip.trim_start_matches("0x"), 16) else {
+                continue;
+            };
+            if let Some(module) = self.module(addr) {
+                *module_path = Some(module.path.clone());
+                *build_id = module.build_id.clone();
+                *offset = Some(addr.wrapping_sub(module.bias));
+            }
+        }
+    }
+}
+
+impl SignalTracer {
+    /// Record each native frame's module, build-id and module-relative address from
+    /// `/proc/self/maps`; see `ModuleMap::annotate`.
+    pub fn annotate_modules(frames: &mut [CallFrame]) -> io::Result<()> {
+        ModuleMap::current()?.annotate(frames);
+        Ok(())
+    }
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_annotate_own_frames() {
+        let ip = test_annotate_own_frames as *const () as u64;
+        let mut frames = vec![
+            CallFrame::native(format!("{:#x}", ip), "", "??", 0),
+            CallFrame::native("0x10", "", "??", 0),
+            CallFrame::python("0x20", "a.py", "f", 1),
+        ];
+        SignalTracer::annotate_modules(&mut frames).unwrap();
+        let exe = std::fs::read_link("/proc/self/exe").unwrap();
+        match &frames[0] {
+            CallFrame::CFrame {
+                module_path,
+                build_id,
+                offset,
+                ..
+            } => {
+                assert_eq!(module_path.as_deref(), exe.to_str());
+                // rustc links with a build-id note on Linux.
+                assert!(
+                    build_id.as_ref().is_some_and(|id| id.len() >= 16),
+                    "{:?}",
+                    build_id
+                );
+                assert!(offset.is_some_and(|o| o <= ip));
+            }
+            other => panic!("{:?}", other),
+        }
+        assert_eq!(frames[1], CallFrame::native("0x10", "", "??", 0));
+    }
+}
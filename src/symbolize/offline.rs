@@ -0,0 +1,257 @@
+//! Symbolication of traces captured elsewhere, from their module-relative addresses.
+//!
+//! Production binaries are often stripped; `ModuleMap::annotate` records each native
+//! frame's module, build-id and offset at capture time, and an `OfflineSymbolizer` later
+//! resolves them against debug files collected in a directory.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use object::read::ReadCache;
+use object::Object;
+
+use super::module_map::hex;
+use super::Module;
+use crate::CallFrame;
+
+/// Resolves annotated native frames against the debug files in one directory.
+///
+/// A module's debug file is looked up, in order, as
+/// - `.build-id/ab/cdef....debug` (the layout of `/usr/lib/debug`),
+/// - `abcdef.../debuginfo` (the layout of a debuginfod cache),
+/// - `<module name>.debug` or `<module name>`, e.g. an unstripped copy of `libfoo.so`;
+///   these are only used when their build-id matches the frame's.
+pub struct OfflineSymbolizer {
+    dir: PathBuf,
+    /// Keyed by build-id, or module path for modules without one; `None` remembers
+    /// modules with no usable debug file.
+    modules: HashMap<String, Option<Module>>,
+}
+
+impl OfflineSymbolizer {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        OfflineSymbolizer {
+            dir: dir.into(),
+            modules: HashMap::new(),
+        }
+    }
+
+    /// Resolve native frames that are unresolved (func `"??"`) and carry an `offset`, in
+    /// place. The frame keeps its ip and module fields; frames without a debug file are
+    /// left alone.
+    pub fn fill_frames(&mut self, frames: &mut [CallFrame]) {
+        for frame in frames {
+            let CallFrame::CFrame {
+                file,
+                func,
+                lineno,
+                inlined,
+                module_path,
+                build_id,
+                offset: Some(offset),
+                ..
+            } = frame
+            else {
+                continue;
+            };
+            if func != "??" {
+                continue;
+            }
+            let Some(module) = self.module(module_path.as_deref(), build_id.as_deref()) else {
+                continue;
+            };
+            let mut functions = module.functions(*offset);
+            // The last function is the physical one, the others were inlined into it.
+            if let Some(outer) = functions.pop() {
+                (*file, *func, *lineno) = (outer.file, outer.func, outer.lineno);
+                *inlined = functions;
+            }
+        }
+    }
+
+    /// Symbolize a JSON Lines trace as written by `StreamWriter`: the `frames` of every
+    /// record are filled in, everything else is copied as is. Returns the number of lines.
+    pub fn symbolize_jsonl<R: BufRead, W: Write>(
+        &mut self,
+        input: R,
+        mut output: W,
+    ) -> io::Result<u64> {
+        let mut lines = 0;
+        for line in input.lines() {
+            let line = line?;
+            lines += 1;
+            if line.trim().is_empty() {
+                writeln!(output, "{}", line)?;
+                continue;
+            }
+            let mut record: serde_json::Value =
+                serde_json::from_str(&line).map_err(io::Error::from)?;
+            if let Some(value) = record.get_mut("frames") {
+                let mut frames: Vec<CallFrame> =
+                    serde_json::from_value(value.take()).map_err(io::Error::from)?;
+                self.fill_frames(&mut frames);
+                *value = serde_json::to_value(&frames).map_err(io::Error::from)?;
+            }
+            serde_json::to_writer(&mut output, &record).map_err(io::Error::from)?;
+            output.write_all(b"\n")?;
+        }
+        output.flush()?;
+        Ok(lines)
+    }
+
+    fn module(&mut self, path: Option<&str>, build_id: Option<&str>) -> Option<&Module> {
+        let key = build_id.or(path)?.to_string();
+        if !self.modules.contains_key(&key) {
+            let module = self
+                .debug_file(path, build_id)
+                .and_then(|file| addr2line::Loader::new(file).ok())
+                // Offsets are already link-time addresses.
+                .map(|loader| Module { loader, bias: 0 });
+            self.modules.insert(key.clone(), module);
+        }
+        self.modules[&key].as_ref()
+    }
+
+    fn debug_file(&self, path: Option<&str>, build_id: Option<&str>) -> Option<PathBuf> {
+        if let Some(id) = build_id.filter(|id| id.len() > 2) {
+            let candidates = [
+                self.dir
+                    .join(".build-id")
+                    .join(&id[..2])
+                    .join(format!("{}.debug", &id[2..])),
+                self.dir.join(id).join("debuginfo"),
+            ];
+            if let Some(found) = candidates.into_iter().find(|p| p.is_file()) {
+                return Some(found);
+            }
+        }
+        let name = Path::new(path?).file_name()?.to_str()?;
+        [format!("{}.debug", name), name.to_string()]
+            .into_iter()
+            .map(|n| self.dir.join(n))
+            .find(|p| {
+                p.is_file() && (build_id.is_none() || file_build_id(p).as_deref() == build_id)
+            })
+    }
+
+    /// Symbolize the JSON Lines trace at `input` into `output` with the debug files in
+    /// `symbol_dir`; see `symbolize_jsonl`.
+    pub fn symbolize_file(
+        input: impl AsRef<Path>,
+        output: impl Write,
+        symbol_dir: impl Into<PathBuf>,
+    ) -> io::Result<u64> {
+        let input = io::BufReader::new(File::open(input)?);
+        OfflineSymbolizer::new(symbol_dir).symbolize_jsonl(input, output)
+    }
+}
+
+fn file_build_id(path: &Path) -> Option<String> {
+    let cache = ReadCache::new(File::open(path).ok()?);
+    let file = object::File::parse(&cache).ok()?;
+    file.build_id().ok()?.map(hex)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::symbolize::module_map::ModuleMap;
+
+    #[inline(never)]
+    fn marker() -> u64 {
+        std::hint::black_box(marker as *const () as u64)
+    }
+
+    /// A frame of this test binary as a stripped capture would record it.
+    fn raw_frame() -> CallFrame {
+        let mut frames = vec![CallFrame::native(format!("{:#x}", marker()), "", "??", 0)];
+        ModuleMap::current().unwrap().annotate(&mut frames);
+        frames.pop().unwrap()
+    }
+
+    fn symbol_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mst-offline-{}-{}", std::process::id(), name));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_fill_frames_by_build_id_and_name() {
+        let exe = std::fs::read_link("/proc/self/exe").unwrap();
+        let frame = raw_frame();
+        let CallFrame::CFrame {
+            ip: raw_ip,
+            build_id: Some(id),
+            ..
+        } = &frame
+        else {
+            panic!("{:?}", frame);
+        };
+
+        let by_id = symbol_dir("by-id");
+        let nested = by_id.join(".build-id").join(&id[..2]);
+        std::fs::create_dir_all(&nested).unwrap();
+        std::os::unix::fs::symlink(&exe, nested.join(format!("{}.debug", &id[2..]))).unwrap();
+        let by_name = symbol_dir("by-name");
+        std::os::unix::fs::symlink(&exe, by_name.join(exe.file_name().unwrap())).unwrap();
+
+        for dir in [&by_id, &by_name] {
+            let mut frames = vec![frame.clone()];
+            OfflineSymbolizer::new(dir).fill_frames(&mut frames);
+            let CallFrame::CFrame { ip, func, file, .. } = &frames[0] else {
+                unreachable!()
+            };
+            assert!(func.ends_with("marker"), "{}", func);
+            assert!(file.ends_with("offline.rs"), "{}", file);
+            assert_eq!(ip, raw_ip);
+        }
+
+        // A file of the same name with another build-id is not used.
+        let mut wrong = frame.clone();
+        if let CallFrame::CFrame { build_id, .. } = &mut wrong {
+            *build_id = Some("00".repeat(20));
+        }
+        let mut frames = vec![wrong.clone()];
+        OfflineSymbolizer::new(&by_name).fill_frames(&mut frames);
+        assert_eq!(frames, vec![wrong]);
+
+        for dir in [by_id, by_name] {
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_symbolize_jsonl() {
+        let exe = std::fs::read_link("/proc/self/exe").unwrap();
+        let dir = symbol_dir("jsonl");
+        std::os::unix::fs::symlink(&exe, dir.join(exe.file_name().unwrap())).unwrap();
+
+        let record = serde_json::json!({
+            "record": "sample",
+            "tid": 7,
+            "timestamp_ns": 1,
+            "frames": [raw_frame(), CallFrame::python("0x1", "a.py", "f", 2)],
+        });
+        let input = format!("{}\n\n{}\n", record, serde_json::json!({"record": "meta"}));
+        let mut output = Vec::new();
+        let lines = OfflineSymbolizer::new(&dir)
+            .symbolize_jsonl(input.as_bytes(), &mut output)
+            .unwrap();
+        assert_eq!(lines, 3);
+
+        let text = String::from_utf8(output).unwrap();
+        let out: Vec<&str> = text.lines().collect();
+        let sample: serde_json::Value = serde_json::from_str(out[0]).unwrap();
+        assert_eq!(sample["tid"], 7);
+        assert!(sample["frames"][0]["func"]
+            .as_str()
+            .unwrap()
+            .ends_with("marker"));
+        assert_eq!(sample["frames"][1]["func"], "f");
+        assert_eq!(out[1], "");
+        assert_eq!(out[2], r#"{"record":"meta"}"#);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,77 @@
+//! Per-thread capture results: the merged stack plus thread metadata.
+
+use serde::{Deserialize, Serialize};
+
+use crate::CallFrame;
+
+/// OS thread id (the kernel tid on Linux).
+pub type ThreadId = i32;
+
+/// Scheduler state of a thread at capture time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ThreadState {
+    Running,
+    /// Interruptible sleep: waiting on a lock, I/O, a condition variable, ...
+    Sleeping,
+    /// Uninterruptible sleep, usually blocked in the kernel on disk or device I/O.
+    DiskSleep,
+    Stopped,
+    Zombie,
+    Idle,
+    Unknown,
+}
+
+impl ThreadState {
+    /// Map the state letter of `/proc/<pid>/task/<tid>/stat`.
+    pub fn from_proc_code(code: char) -> Self {
+        match code {
+            'R' => ThreadState::Running,
+            'S' => ThreadState::Sleeping,
+            'D' => ThreadState::DiskSleep,
+            'T' | 't' => ThreadState::Stopped,
+            'Z' | 'X' => ThreadState::Zombie,
+            'I' => ThreadState::Idle,
+            _ => ThreadState::Unknown,
+        }
+    }
+
+    /// True when the thread was not running, i.e. blocked on something.
+    pub fn is_blocked(&self) -> bool {
+        matches!(self, ThreadState::Sleeping | ThreadState::DiskSleep)
+    }
+}
+
+/// Merged stack of one thread together with what is known about the thread.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ThreadStack {
+    pub tid: ThreadId,
+    /// Thread name (`comm` on Linux), empty if unavailable.
+    pub name: String,
+    pub os_state: ThreadState,
+    /// Whether the thread held the GIL; false when the GIL holder is unknown.
+    pub is_gil_holder: bool,
+    /// Logical thread: a suspended greenlet of thread `tid`, with python frames only.
+    #[serde(default)]
+    pub greenlet: bool,
+    /// CPython interpreter the thread's Python frames run in (0 is the main one), when
+    /// known.
+    #[serde(default)]
+    pub interpreter_id: Option<i64>,
+    /// Merged frames, leaf first.
+    pub frames: Vec<CallFrame>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_thread_state_codes() {
+        assert_eq!(ThreadState::from_proc_code('R'), ThreadState::Running);
+        assert_eq!(ThreadState::from_proc_code('D'), ThreadState::DiskSleep);
+        assert_eq!(ThreadState::from_proc_code('?'), ThreadState::Unknown);
+        assert!(ThreadState::Sleeping.is_blocked());
+        assert!(!ThreadState::Running.is_blocked());
+    }
+}
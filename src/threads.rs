@@ -0,0 +1,589 @@
+//! Capture and merge the stacks of every thread of the current process (Linux, macOS).
+//!
+//! On Linux, threads are enumerated through `/proc/self/task`. Each one is asked to unwind
+//! itself by a dedicated realtime signal; its handler stores raw ips into a shared slot
+//! that the capturing thread symbolizes afterwards. On macOS they are listed with
+//! `task_threads` and suspended in turn while their frame pointers are walked from the
+//! outside (see `mach.rs`). Either way threads are visited one at a time.
+
+use std::collections::HashMap;
+#[cfg(target_os = "linux")]
+use std::fs;
+use std::io;
+#[cfg(target_os = "linux")]
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::{Mutex, MutexGuard};
+#[cfg(target_os = "linux")]
+use std::thread;
+#[cfg(target_os = "linux")]
+use std::time::{Duration, Instant};
+
+use crate::boundary::{BoundaryDetector, CPythonBoundaryDetector};
+use crate::capture::resolve_ip;
+#[cfg(target_os = "linux")]
+use crate::capture::trace_signal_context;
+use crate::error::TracerError;
+#[cfg(target_os = "macos")]
+use crate::mach;
+#[cfg(target_os = "macos")]
+use crate::remote::memory::ProcessMemory;
+use crate::signal_cell::SignalCell;
+use crate::stack_tracer::SignalTracer;
+use crate::thread_stack::{ThreadId, ThreadStack, ThreadState};
+use crate::CallFrame;
+
+const MAX_DEPTH: usize = 256;
+/// How long a thread gets to answer before it is reported with an empty native stack.
+#[cfg(target_os = "linux")]
+const THREAD_TIMEOUT: Duration = Duration::from_millis(200);
+
+#[cfg(target_os = "linux")]
+const IDLE: u8 = 0;
+#[cfg(target_os = "linux")]
+const REQUESTED: u8 = 1;
+#[cfg(target_os = "linux")]
+const WRITING: u8 = 2;
+#[cfg(target_os = "linux")]
+const DONE: u8 = 3;
+
+static CAPTURE_LOCK: Mutex<()> = Mutex::new(());
+/// `CAPTURE_LOCK`, held by the forking thread from the prepare handler to the parent and
+/// child handlers (see `crate::fork`).
+static FORK_GUARD: SignalCell<Option<MutexGuard<'static, ()>>> = SignalCell::new(None);
+#[cfg(target_os = "linux")]
+static SLOT_STATE: AtomicU8 = AtomicU8::new(IDLE);
+#[cfg(target_os = "linux")]
+static SLOT: SignalCell<(usize, [usize; MAX_DEPTH])> = SignalCell::new((0, [0; MAX_DEPTH]));
+
+impl SignalTracer {
+    /// Native stacks of all threads of this process, sorted by tid.
+    pub fn capture_all_threads() -> Result<Vec<ThreadStack>, TracerError> {
+        Self::capture_all_threads_with_python_stacks(HashMap::new(), None)
+    }
+
+    /// Like `capture_all_threads`, merging each native stack with the Python stack of the
+    /// same tid from `python_stacks` (see `capture_python_thread_stacks`).
+    ///
+    /// `gil_holder` names the thread known to hold the GIL, if any. Other threads blocked
+    /// on acquiring it get a `[GIL wait]` frame at the leaf (see `annotate_gil_wait`).
+    pub fn capture_all_threads_with_python_stacks(
+        python_stacks: HashMap<ThreadId, Vec<CallFrame>>,
+        gil_holder: Option<ThreadId>,
+    ) -> Result<Vec<ThreadStack>, TracerError> {
+        Self::capture_all_threads_with_python_stacks_with_detector(
+            python_stacks,
+            gil_holder,
+            &CPythonBoundaryDetector,
+        )
+    }
+
+    /// Same as `capture_all_threads_with_python_stacks`, with the boundary detector of
+    /// another interpreter (see `PythonImplementation::detector`).
+    pub fn capture_all_threads_with_python_stacks_with_detector(
+        mut python_stacks: HashMap<ThreadId, Vec<CallFrame>>,
+        gil_holder: Option<ThreadId>,
+        detector: &dyn BoundaryDetector,
+    ) -> Result<Vec<ThreadStack>, TracerError> {
+        let pid = std::process::id() as i32;
+        let raw = capture_raw_stacks().map_err(|err| TracerError::capture(pid, err))?;
+
+        let mut symbols: HashMap<usize, CallFrame> = HashMap::new();
+        let mut stacks = Vec::with_capacity(raw.len());
+        for (tid, ips) in raw {
+            let native: Vec<CallFrame> = ips
+                .iter()
+                .filter(|ip| **ip != 0)
+                .map(|ip| {
+                    symbols
+                        .entry(*ip)
+                        .or_insert_with(|| resolve_ip(*ip))
+                        .clone()
+                })
+                .collect();
+            let python = python_stacks.remove(&tid).unwrap_or_default();
+            let mut stack = ThreadStack {
+                tid,
+                name: thread_name(tid).unwrap_or_default(),
+                os_state: thread_state(tid).unwrap_or(ThreadState::Unknown),
+                is_gil_holder: gil_holder == Some(tid),
+                greenlet: false,
+                interpreter_id: None,
+                frames: Self::merge_python_native_stacks_with_detector(python, native, detector),
+            };
+            stack.annotate_gil_wait();
+            stacks.push(stack);
+        }
+        Ok(stacks)
+    }
+}
+
+/// Tids listed under `/proc/self/task`.
+#[cfg(target_os = "linux")]
+pub fn list_threads() -> io::Result<Vec<ThreadId>> {
+    list_tasks("self")
+}
+
+/// Name of a thread of this process (`/proc/self/task/<tid>/comm`).
+#[cfg(target_os = "linux")]
+pub fn thread_name(tid: ThreadId) -> io::Result<String> {
+    task_name("self", tid)
+}
+
+/// Scheduler state of a thread of this process (`/proc/self/task/<tid>/stat`).
+#[cfg(target_os = "linux")]
+pub fn thread_state(tid: ThreadId) -> io::Result<ThreadState> {
+    task_state("self", tid)
+}
+
+/// What a blocked thread of this process waits in: the name of its system call
+/// (`/proc/self/task/<tid>/syscall`), else the kernel function of `wchan`. `None` when
+/// the thread runs or the kernel does not tell.
+#[cfg(target_os = "linux")]
+pub fn blocked_in(tid: ThreadId) -> Option<String> {
+    let syscall = fs::read_to_string(format!("/proc/self/task/{}/syscall", tid)).ok();
+    if let Some(nr) = syscall.as_deref().and_then(parse_syscall_nr) {
+        return Some(syscall_name(nr).map_or_else(|| format!("syscall {}", nr), str::to_string));
+    }
+    let wchan = fs::read_to_string(format!("/proc/self/task/{}/wchan", tid)).ok()?;
+    let wchan = wchan.trim();
+    (!wchan.is_empty() && wchan != "0").then(|| wchan.to_string())
+}
+
+/// First field of `/proc/<pid>/task/<tid>/syscall`: `running`, `-1` outside a system
+/// call, or the number of the one in progress.
+#[cfg(target_os = "linux")]
+fn parse_syscall_nr(syscall: &str) -> Option<libc::c_long> {
+    syscall
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+        .filter(|nr| *nr >= 0)
+}
+
+/// Names of the system calls threads usually block in.
+#[cfg(target_os = "linux")]
+fn syscall_name(nr: libc::c_long) -> Option<&'static str> {
+    #[cfg(target_arch = "x86_64")]
+    const ARCH_ONLY: &[(libc::c_long, &str)] = &[
+        (libc::SYS_poll, "poll"),
+        (libc::SYS_select, "select"),
+        (libc::SYS_epoll_wait, "epoll_wait"),
+        (libc::SYS_accept, "accept"),
+        (libc::SYS_pause, "pause"),
+    ];
+    #[cfg(not(target_arch = "x86_64"))]
+    const ARCH_ONLY: &[(libc::c_long, &str)] = &[];
+    const COMMON: &[(libc::c_long, &str)] = &[
+        (libc::SYS_read, "read"),
+        (libc::SYS_write, "write"),
+        (libc::SYS_readv, "readv"),
+        (libc::SYS_writev, "writev"),
+        (libc::SYS_pread64, "pread64"),
+        (libc::SYS_pwrite64, "pwrite64"),
+        (libc::SYS_openat, "openat"),
+        (libc::SYS_ioctl, "ioctl"),
+        (libc::SYS_fsync, "fsync"),
+        (libc::SYS_fdatasync, "fdatasync"),
+        (libc::SYS_futex, "futex"),
+        (libc::SYS_nanosleep, "nanosleep"),
+        (libc::SYS_clock_nanosleep, "clock_nanosleep"),
+        (libc::SYS_ppoll, "ppoll"),
+        (libc::SYS_pselect6, "pselect6"),
+        (libc::SYS_epoll_pwait, "epoll_pwait"),
+        (libc::SYS_accept4, "accept4"),
+        (libc::SYS_connect, "connect"),
+        (libc::SYS_recvfrom, "recvfrom"),
+        (libc::SYS_recvmsg, "recvmsg"),
+        (libc::SYS_sendto, "sendto"),
+        (libc::SYS_sendmsg, "sendmsg"),
+        (libc::SYS_wait4, "wait4"),
+        (libc::SYS_waitid, "waitid"),
+        (libc::SYS_rt_sigtimedwait, "rt_sigtimedwait"),
+    ];
+    COMMON
+        .iter()
+        .chain(ARCH_ONLY)
+        .find(|(n, _)| *n == nr)
+        .map(|(_, name)| *name)
+}
+
+/// Tids of process `pid` (a number or `self`), sorted.
+#[cfg(target_os = "linux")]
+pub(crate) fn list_tasks(pid: &str) -> io::Result<Vec<ThreadId>> {
+    let mut tids = Vec::new();
+    for entry in fs::read_dir(format!("/proc/{}/task", pid))? {
+        if let Some(tid) = entry?.file_name().to_str().and_then(|s| s.parse().ok()) {
+            tids.push(tid);
+        }
+    }
+    tids.sort_unstable();
+    Ok(tids)
+}
+
+/// Pids of the direct children of process `pid`, sorted: from
+/// `/proc/<pid>/task/*/children`, or by scanning the parent of every process on kernels
+/// built without it.
+#[cfg(target_os = "linux")]
+pub(crate) fn child_processes(pid: i32) -> io::Result<Vec<i32>> {
+    let mut children = Vec::new();
+    let mut listed = false;
+    for tid in list_tasks(&pid.to_string())? {
+        let Ok(list) = fs::read_to_string(format!("/proc/{}/task/{}/children", pid, tid)) else {
+            continue;
+        };
+        listed = true;
+        children.extend(
+            list.split_whitespace()
+                .filter_map(|c| c.parse::<i32>().ok()),
+        );
+    }
+    if !listed {
+        for entry in fs::read_dir("/proc")? {
+            let Some(child) = entry?.file_name().to_str().and_then(|s| s.parse().ok()) else {
+                continue;
+            };
+            let stat = fs::read_to_string(format!("/proc/{}/stat", child)).unwrap_or_default();
+            if parse_stat_ppid(&stat) == Some(pid) {
+                children.push(child);
+            }
+        }
+    }
+    children.sort_unstable();
+    children.dedup();
+    Ok(children)
+}
+
+/// The parent pid is the second field after the parenthesized comm.
+#[cfg(target_os = "linux")]
+fn parse_stat_ppid(stat: &str) -> Option<i32> {
+    let rest = &stat[stat.rfind(')')? + 1..];
+    rest.split_whitespace().nth(1)?.parse().ok()
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn task_name(pid: &str, tid: ThreadId) -> io::Result<String> {
+    let comm = fs::read_to_string(format!("/proc/{}/task/{}/comm", pid, tid))?;
+    Ok(comm.trim_end_matches('\n').to_string())
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn task_state(pid: &str, tid: ThreadId) -> io::Result<ThreadState> {
+    let stat = fs::read_to_string(format!("/proc/{}/task/{}/stat", pid, tid))?;
+    Ok(parse_stat_state(&stat))
+}
+
+/// The state letter follows the parenthesized comm, which may itself contain `)`.
+#[cfg(target_os = "linux")]
+fn parse_stat_state(stat: &str) -> ThreadState {
+    stat.rfind(')')
+        .and_then(|i| stat[i + 1..].trim_start().chars().next())
+        .map_or(ThreadState::Unknown, ThreadState::from_proc_code)
+}
+
+/// Tid of the calling thread.
+#[cfg(target_os = "linux")]
+pub fn current_tid() -> ThreadId {
+    unsafe { libc::syscall(libc::SYS_gettid) as ThreadId }
+}
+
+/// Thread ids of this process (`task_threads`), sorted.
+#[cfg(target_os = "macos")]
+pub fn list_threads() -> io::Result<Vec<ThreadId>> {
+    let mut tids: Vec<ThreadId> = mach::task_thread_list(mach::current_task())?
+        .iter()
+        .filter_map(|t| t.id().ok())
+        .collect();
+    tids.sort_unstable();
+    Ok(tids)
+}
+
+/// Name of a thread of this process, as set with `pthread_setname_np`.
+#[cfg(target_os = "macos")]
+pub fn thread_name(tid: ThreadId) -> io::Result<String> {
+    own_thread(tid)?.name()
+}
+
+/// Scheduler state of a thread of this process.
+#[cfg(target_os = "macos")]
+pub fn thread_state(tid: ThreadId) -> io::Result<ThreadState> {
+    own_thread(tid)?.state()
+}
+
+/// Id of the calling thread (`pthread_threadid_np`).
+#[cfg(target_os = "macos")]
+pub fn current_tid() -> ThreadId {
+    let mut id = 0;
+    unsafe { libc::pthread_threadid_np(0 as libc::pthread_t, &mut id) };
+    id as ThreadId
+}
+
+#[cfg(target_os = "macos")]
+fn own_thread(tid: ThreadId) -> io::Result<mach::Thread> {
+    mach::task_thread_list(mach::current_task())?
+        .into_iter()
+        .find(|t| t.id().ok() == Some(tid))
+        .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) fn dump_signal() -> libc::c_int {
+    // glibc keeps the first realtime signals for itself; SIGRTMIN() already skips those.
+    libc::SIGRTMIN() + 3
+}
+
+/// Keep captures out of the fork, so that the child does not inherit the lock held by a
+/// thread it does not have.
+pub(crate) fn lock_capture_for_fork() {
+    let guard = CAPTURE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    // Only the forking thread, between the fork handlers, touches it.
+    unsafe { *FORK_GUARD.get() = Some(guard) };
+}
+
+/// Release the lock taken by `lock_capture_for_fork`, in the parent or the child.
+pub(crate) fn unlock_capture_after_fork() {
+    unsafe { (*FORK_GUARD.get()).take() };
+}
+
+/// Withdraw the request to a thread of the parent the child may have inherited.
+#[cfg(target_os = "linux")]
+pub(crate) fn reset_capture_in_child() {
+    SLOT_STATE.store(IDLE, Ordering::SeqCst);
+}
+
+#[cfg(target_os = "linux")]
+fn capture_raw_stacks() -> io::Result<Vec<(ThreadId, Vec<usize>)>> {
+    crate::fork::register_handlers();
+    let _guard = CAPTURE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let sig = dump_signal();
+
+    let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+    action.sa_sigaction = handle_dump_signal as *const () as libc::sighandler_t;
+    action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+    unsafe { libc::sigemptyset(&mut action.sa_mask) };
+    let mut previous: libc::sigaction = unsafe { std::mem::zeroed() };
+    if unsafe { libc::sigaction(sig, &action, &mut previous) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let result = list_threads().map(|tids| {
+        let own = current_tid();
+        tids.into_iter()
+            .map(|tid| {
+                let ips = if tid == own {
+                    capture_own_ips()
+                } else {
+                    capture_thread_ips(tid, sig)
+                };
+                (tid, ips)
+            })
+            .collect()
+    });
+
+    unsafe { libc::sigaction(sig, &previous, std::ptr::null_mut()) };
+    result
+}
+
+#[cfg(target_os = "macos")]
+fn capture_raw_stacks() -> io::Result<Vec<(ThreadId, Vec<usize>)>> {
+    crate::fork::register_handlers();
+    let _guard = CAPTURE_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let memory = ProcessMemory::current();
+    let own = current_tid();
+    let mut stacks = Vec::new();
+    for thread in mach::task_thread_list(mach::current_task())? {
+        // Threads that exit meanwhile are skipped.
+        let Ok(tid) = thread.id() else { continue };
+        let ips = if tid == own {
+            capture_own_ips()
+        } else {
+            let mut ips = Vec::with_capacity(MAX_DEPTH);
+            if let Ok(suspended) = thread.suspend() {
+                let _ = thread.native_ips(&memory, &mut ips, MAX_DEPTH);
+                drop(suspended);
+            }
+            ips.into_iter().map(|ip| ip as usize).collect()
+        };
+        stacks.push((tid, ips));
+    }
+    stacks.sort_unstable_by_key(|(tid, _)| *tid);
+    Ok(stacks)
+}
+
+fn capture_own_ips() -> Vec<usize> {
+    let mut ips = Vec::new();
+    backtrace::trace(|frame| {
+        ips.push(frame.ip() as usize);
+        ips.len() < MAX_DEPTH
+    });
+    ips
+}
+
+#[cfg(target_os = "linux")]
+fn capture_thread_ips(tid: ThreadId, sig: libc::c_int) -> Vec<usize> {
+    SLOT_STATE.store(REQUESTED, Ordering::SeqCst);
+    let pid = unsafe { libc::getpid() };
+    if unsafe { libc::syscall(libc::SYS_tgkill, pid, tid, sig) } != 0 {
+        // Thread exited since /proc was read.
+        SLOT_STATE.store(IDLE, Ordering::SeqCst);
+        return Vec::new();
+    }
+
+    let deadline = Instant::now() + THREAD_TIMEOUT;
+    loop {
+        match SLOT_STATE.load(Ordering::Acquire) {
+            DONE => break,
+            REQUESTED if Instant::now() >= deadline => {
+                // Withdraw the request unless the handler just picked it up.
+                if SLOT_STATE
+                    .compare_exchange(REQUESTED, IDLE, Ordering::SeqCst, Ordering::SeqCst)
+                    .is_ok()
+                {
+                    return Vec::new();
+                }
+            }
+            _ => thread::yield_now(),
+        }
+    }
+
+    let (len, ips) = unsafe { &*SLOT.get() };
+    let ips = ips[..*len].to_vec();
+    SLOT_STATE.store(IDLE, Ordering::SeqCst);
+    ips
+}
+
+#[cfg(target_os = "linux")]
+extern "C" fn handle_dump_signal(
+    _sig: libc::c_int,
+    _info: *mut libc::siginfo_t,
+    _context: *mut libc::c_void,
+) {
+    if SLOT_STATE
+        .compare_exchange(REQUESTED, WRITING, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        // Late delivery after a timeout: nobody is waiting any more.
+        return;
+    }
+
+    let saved_errno = unsafe { *libc::__errno_location() };
+    let slot = unsafe { &mut *SLOT.get() };
+    let walked = unsafe { trace_signal_context(handle_dump_signal as *const (), &mut slot.1) };
+    slot.0 = walked.min(MAX_DEPTH);
+    SLOT_STATE.store(DONE, Ordering::Release);
+    unsafe { *libc::__errno_location() = saved_errno };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+
+    #[inline(never)]
+    fn parked_worker(ready: mpsc::Sender<ThreadId>, release: mpsc::Receiver<()>) {
+        #[cfg(target_os = "linux")]
+        unsafe {
+            libc::prctl(libc::PR_SET_NAME, c"parked".as_ptr())
+        };
+        #[cfg(target_os = "macos")]
+        unsafe {
+            libc::pthread_setname_np(c"parked".as_ptr())
+        };
+        ready.send(current_tid()).unwrap();
+        release.recv().unwrap();
+    }
+
+    fn funcs(frames: &[CallFrame]) -> Vec<String> {
+        frames.iter().map(|f| f.func().to_string()).collect()
+    }
+
+    #[test]
+    fn test_capture_all_threads() {
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel();
+        let worker = thread::spawn(move || parked_worker(ready_tx, release_rx));
+        let worker_tid = ready_rx.recv().unwrap();
+
+        let python =
+            HashMap::from([(worker_tid, vec![CallFrame::python("0x0", "w.py", "run", 1)])]);
+        let stacks =
+            SignalTracer::capture_all_threads_with_python_stacks(python, Some(worker_tid)).unwrap();
+        release_tx.send(()).unwrap();
+        worker.join().unwrap();
+
+        assert!(stacks.iter().any(|s| s.tid == current_tid()));
+        assert!(stacks.windows(2).all(|w| w[0].tid < w[1].tid));
+        let worker_stack = stacks.iter().find(|s| s.tid == worker_tid).unwrap();
+        assert_eq!(worker_stack.name, "parked");
+        assert!(worker_stack.is_gil_holder);
+        assert_eq!(worker_stack.os_state, ThreadState::Sleeping);
+        let worker_funcs = funcs(&worker_stack.frames);
+        assert!(
+            worker_funcs.iter().any(|f| f.contains("parked_worker")),
+            "{:?}",
+            worker_funcs
+        );
+        // No eval boundary in a Rust thread: the python frame is appended.
+        assert_eq!(worker_funcs.last().map(String::as_str), Some("run"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_blocked_in() {
+        assert_eq!(parse_syscall_nr("running"), None);
+        assert_eq!(parse_syscall_nr("-1 0x7ffd 0x0"), None);
+        assert_eq!(
+            parse_syscall_nr(&format!(
+                "{} 0x1 0x2 0x3 0x4 0x5 0x6 0x7ff 0x7ff",
+                libc::SYS_futex
+            ))
+            .and_then(syscall_name),
+            Some("futex")
+        );
+
+        let (ready_tx, ready_rx) = mpsc::channel();
+        let (release_tx, release_rx) = mpsc::channel();
+        let worker = thread::spawn(move || parked_worker(ready_tx, release_rx));
+        let worker_tid = ready_rx.recv().unwrap();
+        // /proc may hide the syscall file (e.g. without ptrace rights), never fail on it.
+        let blocked = (0..100).find_map(|_| {
+            thread::sleep(Duration::from_millis(5));
+            blocked_in(worker_tid)
+        });
+        release_tx.send(()).unwrap();
+        worker.join().unwrap();
+        if let Some(blocked) = blocked {
+            assert_eq!(blocked, "futex");
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_parse_stat_state() {
+        assert_eq!(parse_stat_state("12 (a) b) R 1 2"), ThreadState::Running);
+        assert_eq!(parse_stat_state("12 (worker) S 1"), ThreadState::Sleeping);
+        assert_eq!(parse_stat_state("garbage"), ThreadState::Unknown);
+        assert_eq!(parse_stat_ppid("12 (a) b) R 7 12"), Some(7));
+        assert_eq!(parse_stat_ppid("garbage"), None);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_child_processes() {
+        let mut child = std::process::Command::new("sleep")
+            .arg("5")
+            .spawn()
+            .unwrap();
+        let children = child_processes(std::process::id() as i32);
+        let _ = child.kill();
+        let _ = child.wait();
+        assert!(children.unwrap().contains(&(child.id() as i32)));
+    }
+
+    #[test]
+    fn test_list_threads_contains_self() {
+        let tids = list_threads().unwrap();
+        assert!(tids.contains(&current_tid()));
+    }
+}
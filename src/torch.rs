@@ -0,0 +1,162 @@
+//! Annotation pass for PyTorch / libtorch native frames.
+//!
+//! Demangled libtorch symbols are long (`at::_ops::add_Tensor::call(at::Tensor const&,
+//! ...)`); this pass tags operator, autograd and CUDA launch frames with
+//! `TORCH_OP_CATEGORY` and gives them a short operator name such as `aten::add_Tensor`.
+//! It expects demangled names, so run it after `demangle_frames`.
+
+use crate::stack_tracer::SignalTracer;
+use crate::CallFrame;
+
+/// Category given to recognized libtorch frames.
+pub const TORCH_OP_CATEGORY: &str = "torch-op";
+
+/// Host-side kernel launch entry points of the CUDA runtime and driver APIs.
+const CUDA_LAUNCH_SHIMS: &[&str] = &[
+    "cudaLaunchKernel",
+    "cudaLaunchKernel_ptsz",
+    "cudaLaunchKernelExC",
+    "cudaLaunchCooperativeKernel",
+    "cuLaunchKernel",
+    "cuLaunchKernelEx",
+    "cuLaunchCooperativeKernel",
+];
+
+impl SignalTracer {
+    /// Tag libtorch frames with `TORCH_OP_CATEGORY` and rename them to their operator name.
+    ///
+    /// The full symbol is kept in `raw_func` unless that already holds the mangled one.
+    /// Frames that are not libtorch's are left untouched.
+    pub fn annotate_torch_frames(frames: &mut [CallFrame]) {
+        for frame in frames {
+            let CallFrame::CFrame {
+                func,
+                raw_func,
+                category,
+                ..
+            } = frame
+            else {
+                continue;
+            };
+            if let Some(name) = torch_op_name(func) {
+                let symbol = std::mem::replace(func, name);
+                raw_func.get_or_insert(symbol);
+                *category = Some(TORCH_OP_CATEGORY.to_string());
+            }
+        }
+    }
+}
+
+/// Short operator name of a demangled libtorch symbol, `None` for other functions.
+///
+/// - `at::_ops::add_Tensor::call(...)` → `aten::add_Tensor`
+/// - `at::native::(anonymous namespace)::conv2d_impl(...)` → `aten::conv2d_impl`
+/// - `torch::autograd::generated::MulBackward0::apply(...)` → `autograd::MulBackward0`
+/// - `torch::autograd::Engine::execute(...)` → `autograd::Engine::execute`
+/// - `cudaLaunchKernel` → `cuda::cudaLaunchKernel`
+pub fn torch_op_name(symbol: &str) -> Option<String> {
+    let path = qualified_name(symbol);
+    if CUDA_LAUNCH_SHIMS.contains(&path.as_str()) {
+        return Some(format!("cuda::{}", path));
+    }
+    if let Some(rest) = path.strip_prefix("at::_ops::") {
+        let op = rest.split("::").next()?;
+        return Some(format!("aten::{}", op));
+    }
+    if path.starts_with("at::") {
+        let op = path.rsplit("::").next()?;
+        return Some(format!("aten::{}", op));
+    }
+    if let Some(rest) = path.strip_prefix("torch::autograd::generated::") {
+        let node = rest.split("::").next()?;
+        return Some(format!("autograd::{}", node));
+    }
+    path.strip_prefix("torch::autograd::")
+        .map(|rest| format!("autograd::{}", rest))
+}
+
+/// `ns::func` of a demangled symbol: without anonymous namespaces, template arguments,
+/// parameters or return type.
+pub(crate) fn qualified_name(symbol: &str) -> String {
+    let symbol = symbol.replace("(anonymous namespace)::", "");
+    let mut out = String::new();
+    let mut depth = 0usize;
+    for c in symbol.chars() {
+        match c {
+            '<' => depth += 1,
+            '>' => depth = depth.saturating_sub(1),
+            '(' if depth == 0 => break,
+            // A space outside template arguments ends the return type.
+            ' ' if depth == 0 => out.clear(),
+            _ if depth == 0 => out.push(c),
+            _ => {}
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_torch_op_name() {
+        let cases = [
+            (
+                "at::_ops::add_Tensor::call(at::Tensor const&, at::Tensor const&, c10::Scalar const&)",
+                Some("aten::add_Tensor"),
+            ),
+            (
+                "at::native::(anonymous namespace)::conv2d_impl(at::Tensor const&)",
+                Some("aten::conv2d_impl"),
+            ),
+            (
+                "void at::native::cpu_kernel_vec<at::native::AddFunctor<float> >(at::TensorIteratorBase&)",
+                Some("aten::cpu_kernel_vec"),
+            ),
+            (
+                "torch::autograd::generated::MulBackward0::apply(std::vector<at::Tensor>&&)",
+                Some("autograd::MulBackward0"),
+            ),
+            (
+                "torch::autograd::Engine::execute(std::vector<torch::autograd::Edge> const&)",
+                Some("autograd::Engine::execute"),
+            ),
+            ("cudaLaunchKernel", Some("cuda::cudaLaunchKernel")),
+            ("std::vector<at::Tensor>::push_back(at::Tensor&&)", None),
+            ("main", None),
+        ];
+        for (symbol, expected) in cases {
+            assert_eq!(torch_op_name(symbol).as_deref(), expected, "{}", symbol);
+        }
+    }
+
+    #[test]
+    fn test_annotate_torch_frames() {
+        let symbol = "at::_ops::mm::call(at::Tensor const&, at::Tensor const&)";
+        let mut frames = vec![
+            CallFrame::native("0x1", "", symbol, 0),
+            CallFrame::native("0x2", "", "main", 0),
+            CallFrame::python("0x3", "model.py", "forward", 7),
+        ];
+        SignalTracer::annotate_torch_frames(&mut frames);
+
+        let CallFrame::CFrame {
+            func,
+            raw_func,
+            category,
+            ..
+        } = &frames[0]
+        else {
+            panic!("native frame expected");
+        };
+        assert_eq!(func, "aten::mm");
+        assert_eq!(raw_func.as_deref(), Some(symbol));
+        assert_eq!(category.as_deref(), Some(TORCH_OP_CATEGORY));
+        assert_eq!(frames[1], CallFrame::native("0x2", "", "main", 0));
+        assert_eq!(
+            frames[2],
+            CallFrame::python("0x3", "model.py", "forward", 7)
+        );
+    }
+}
@@ -0,0 +1,101 @@
+//! Python tracebacks as call frames.
+//!
+//! A traceback records the Python frames between the handler and the `raise`, so merging
+//! it with a native stack captured while handling the exception (in a crash reporter, an
+//! excepthook) shows the mixed stack at raise time. The `python` feature adds
+//! `merge_traceback` for live traceback objects; this module handles the text printed by
+//! `traceback.format_exc()`.
+
+use crate::stack_tracer::SignalTracer;
+use crate::CallFrame;
+
+const HEADER: &str = "Traceback (most recent call last):";
+
+/// Frames of a formatted traceback, leaf (the raising frame) first.
+///
+/// For chained exceptions only the last traceback, the one of the exception that
+/// propagated, is read. Text without a traceback header yields no frames.
+pub fn parse_traceback(text: &str) -> Vec<CallFrame> {
+    let Some(start) = text.rfind(HEADER) else {
+        return Vec::new();
+    };
+    let mut frames: Vec<CallFrame> = text[start + HEADER.len()..]
+        .lines()
+        .filter_map(|line| parse_file_line(line.trim_start()))
+        .collect();
+    frames.reverse();
+    frames
+}
+
+/// `File "<path>", line <n>, in <func>`; source, caret and exception lines are skipped.
+fn parse_file_line(line: &str) -> Option<CallFrame> {
+    let rest = line.strip_prefix("File \"")?;
+    let (file, rest) = rest.rsplit_once("\", line ")?;
+    let (lineno, func) = rest.split_once(", in ")?;
+    Some(CallFrame::python(
+        "",
+        file,
+        func.trim_end(),
+        lineno.parse().ok()?,
+    ))
+}
+
+impl SignalTracer {
+    /// Merge the frames of a `traceback.format_exc()` text with a native stack captured
+    /// while the exception was being handled, leaf first.
+    pub fn merge_traceback_text(traceback: &str, native_stacks: Vec<CallFrame>) -> Vec<CallFrame> {
+        Self::merge_python_native_stacks(parse_traceback(traceback), native_stacks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHAINED: &str = r#"Traceback (most recent call last):
+  File "/app/io.py", line 4, in read
+    open(path)
+FileNotFoundError: [Errno 2] No such file or directory: 'x'
+
+During handling of the above exception, another exception occurred:
+
+Traceback (most recent call last):
+  File "/app/main.py", line 12, in <module>
+    main()
+  File "/app/main.py", line 9, in main
+    load(path, retries=3)
+    ~~~~^^^^^^^^^^^^^^^^^
+  File "/app/load.py", line 2, in load
+    raise RuntimeError("load failed")
+RuntimeError: load failed
+"#;
+
+    #[test]
+    fn test_parse_traceback_reads_last_chain_link() {
+        assert_eq!(
+            parse_traceback(CHAINED),
+            vec![
+                CallFrame::python("", "/app/load.py", "load", 2),
+                CallFrame::python("", "/app/main.py", "main", 9),
+                CallFrame::python("", "/app/main.py", "<module>", 12),
+            ]
+        );
+        assert!(parse_traceback("RuntimeError: no traceback").is_empty());
+    }
+
+    #[test]
+    fn test_merge_traceback_text() {
+        let native = vec![
+            CallFrame::native("0x1", "", "report_crash", 0),
+            CallFrame::native("0x2", "", "_PyEval_EvalFrameDefault", 0),
+            CallFrame::native("0x3", "", "_PyEval_EvalFrameDefault", 0),
+            CallFrame::native("0x4", "", "_PyEval_EvalFrameDefault", 0),
+            CallFrame::native("0x5", "", "main", 0),
+        ];
+        let funcs: Vec<String> = SignalTracer::merge_traceback_text(CHAINED, native)
+            .iter()
+            .map(|f| f.func().to_string())
+            .collect();
+        assert_eq!(funcs, ["report_crash", "load", "main", "<module>", "main"]);
+    }
+}
@@ -0,0 +1,443 @@
+//! Cython-generated functions and C-extension call trampolines in native stacks.
+//!
+//! Cython compiles `def f` into a Python-facing wrapper (`__pyx_pw_*`) calling the
+//! implementation (`__pyx_pf_*`); CPython reaches extension functions through shims such
+//! as `cfunction_call` or `method_vectorcall_*`. These frames can be kept as is, collapsed
+//! or relabeled with the Python-level name, and can serve as secondary merge boundaries.
+//!
+//! Rust applications embedding Python through PyO3 add two more: the call shims entering
+//! Python (`Py<T>::call1`, `Python::run`, ...) and the trampolines through which Python
+//! enters `#[pyfunction]`s (`pyo3::impl_::trampoline::*`). A
+//! Rust→Python→Rust stack reads `rust, trampoline, eval loop, call shim, rust`.
+
+use crate::boundary::{BoundaryDetector, CPythonBoundaryDetector};
+use crate::stack_tracer::SignalTracer;
+use crate::CallFrame;
+
+/// Kind of trampoline frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TrampolineKind {
+    /// `__pyx_pw_*`: argument-parsing wrapper of a Cython `def`.
+    CythonWrapper,
+    /// `__pyx_pf_*`: body of a Cython `def`.
+    CythonFunction,
+    /// CPython's shims into extension functions (`cfunction_call`, `method_vectorcall_*`).
+    ExtensionCall,
+    /// PyO3 calling into Python from Rust (`Py<T>::call*`, `call_method*`, `Python::run`).
+    PyO3Call,
+    /// PyO3's entry from Python into a Rust function (`pyo3::impl_::trampoline::*`).
+    PyO3Trampoline,
+}
+
+const EXTENSION_TRAMPOLINES: &[&str] = &[
+    "cfunction_call",
+    "cfunction_vectorcall_FASTCALL",
+    "cfunction_vectorcall_FASTCALL_KEYWORDS",
+    "cfunction_vectorcall_NOARGS",
+    "cfunction_vectorcall_O",
+    "method_vectorcall",
+];
+
+/// Last path segment of PyO3 functions that call into Python.
+const PYO3_CALLS: &[&str] = &[
+    "call",
+    "call0",
+    "call1",
+    "call_method",
+    "call_method0",
+    "call_method1",
+    "run",
+    "eval",
+    "run_bound",
+    "eval_bound",
+];
+
+impl TrampolineKind {
+    /// Kind of `frame`, if it is a native trampoline.
+    pub fn of(frame: &CallFrame) -> Option<TrampolineKind> {
+        let CallFrame::CFrame { func, .. } = frame else {
+            return None;
+        };
+        if func.starts_with("__pyx_pw_") {
+            Some(TrampolineKind::CythonWrapper)
+        } else if func.starts_with("__pyx_pf_") {
+            Some(TrampolineKind::CythonFunction)
+        } else if func.starts_with("method_vectorcall")
+            || EXTENSION_TRAMPOLINES.contains(&func.as_str())
+        {
+            Some(TrampolineKind::ExtensionCall)
+        } else if func.contains("pyo3::impl_::trampoline::") {
+            Some(TrampolineKind::PyO3Trampoline)
+        } else if func.contains("pyo3::") && PYO3_CALLS.contains(&last_segment(func)) {
+            Some(TrampolineKind::PyO3Call)
+        } else {
+            None
+        }
+    }
+
+    pub fn is_cython(self) -> bool {
+        matches!(
+            self,
+            TrampolineKind::CythonWrapper | TrampolineKind::CythonFunction
+        )
+    }
+
+    pub fn is_pyo3(self) -> bool {
+        matches!(
+            self,
+            TrampolineKind::PyO3Call | TrampolineKind::PyO3Trampoline
+        )
+    }
+}
+
+/// Function name of a Rust path, without its hash (`<Bound<T> as PyAnyMethods>::call1`
+/// and `pyo3::instance::Py<T>::call1::h0123456789abcdef` both give `call1`).
+fn last_segment(func: &str) -> &str {
+    let func = match func.rsplit_once("::h") {
+        Some((path, hash)) if hash.len() == 16 && hash.bytes().all(|b| b.is_ascii_hexdigit()) => {
+            path
+        }
+        _ => func,
+    };
+    func.rsplit("::").next().unwrap_or(func)
+}
+
+/// Dotted Python-level name of a Cython function symbol.
+///
+/// `__pyx_pw_5numpy_6random_6mtrand_11RandomState_23random_sample` becomes
+/// `numpy.random.mtrand.RandomState.random_sample`: every component is length-prefixed
+/// except the last, whose number is Cython's function counter.
+pub fn cython_python_name(symbol: &str) -> Option<String> {
+    let mut rest = symbol
+        .strip_prefix("__pyx_pw_")
+        .or_else(|| symbol.strip_prefix("__pyx_pf_"))?;
+    let mut parts = Vec::new();
+    loop {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        if digits == 0 {
+            return None;
+        }
+        let len: usize = rest[..digits].parse().ok()?;
+        let body = &rest[digits..];
+        match body.get(len..) {
+            Some(tail) if tail.starts_with('_') && tail.len() > 1 => {
+                parts.push(&body[..len]);
+                rest = &tail[1..];
+            }
+            _ => {
+                if body.is_empty() {
+                    return None;
+                }
+                parts.push(body);
+                return Some(parts.join("."));
+            }
+        }
+    }
+}
+
+/// What to do with one kind of trampoline frame.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TrampolineAction {
+    /// Leave the frame untouched (default).
+    #[default]
+    Keep,
+    /// Remove it: extension shims are dropped, Cython wrappers are dropped when directly
+    /// followed by their implementation frame.
+    Collapse,
+    /// Replace the C symbol by the Python-level name (Cython only; the symbol moves to
+    /// `raw_func`).
+    Relabel,
+}
+
+/// Per-kind actions for `SignalTracer::rewrite_trampolines`.
+#[derive(Clone, Debug, Default)]
+pub struct TrampolineOptions {
+    cython: TrampolineAction,
+    extension: TrampolineAction,
+    pyo3: TrampolineAction,
+}
+
+impl TrampolineOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Action for `__pyx_pw_*` / `__pyx_pf_*` frames.
+    pub fn cython(mut self, action: TrampolineAction) -> Self {
+        self.cython = action;
+        self
+    }
+
+    /// Action for extension call shims. `Relabel` has no Python name to use and keeps them.
+    pub fn extension_calls(mut self, action: TrampolineAction) -> Self {
+        self.extension = action;
+        self
+    }
+
+    /// Action for PyO3 call shims and trampolines. `Relabel` keeps them as well.
+    pub fn pyo3(mut self, action: TrampolineAction) -> Self {
+        self.pyo3 = action;
+        self
+    }
+}
+
+impl SignalTracer {
+    /// Collapse or relabel trampoline frames of a native (or merged) stack, leaf first.
+    pub fn rewrite_trampolines(
+        frames: Vec<CallFrame>,
+        options: &TrampolineOptions,
+    ) -> Vec<CallFrame> {
+        let mut out: Vec<CallFrame> = Vec::with_capacity(frames.len());
+        for mut frame in frames {
+            let Some(kind) = TrampolineKind::of(&frame) else {
+                out.push(frame);
+                continue;
+            };
+            let action = if kind.is_cython() {
+                options.cython
+            } else if kind.is_pyo3() {
+                options.pyo3
+            } else {
+                options.extension
+            };
+            match (action, kind) {
+                (TrampolineAction::Collapse, TrampolineKind::ExtensionCall) => continue,
+                (TrampolineAction::Collapse, k) if k.is_pyo3() => continue,
+                (TrampolineAction::Collapse, TrampolineKind::CythonWrapper)
+                    if wraps(&frame, out.last()) =>
+                {
+                    continue
+                }
+                (TrampolineAction::Relabel, k) if k.is_cython() => relabel(&mut frame),
+                _ => {}
+            }
+            out.push(frame);
+        }
+        out
+    }
+}
+
+/// Whether the wrapper `frame` calls `callee`, the implementation of the same function.
+fn wraps(frame: &CallFrame, callee: Option<&CallFrame>) -> bool {
+    let (CallFrame::CFrame { func, .. }, Some(CallFrame::CFrame { func: inner, .. })) =
+        (frame, callee)
+    else {
+        return false;
+    };
+    inner.starts_with("__pyx_pf_")
+        && cython_python_name(func).is_some()
+        && cython_python_name(func) == cython_python_name(inner)
+}
+
+fn relabel(frame: &mut CallFrame) {
+    if let CallFrame::CFrame { func, raw_func, .. } = frame {
+        if let Some(name) = cython_python_name(func) {
+            let symbol = std::mem::replace(func, name);
+            raw_func.get_or_insert(symbol);
+        }
+    }
+}
+
+/// Boundary detector that also treats trampoline frames of the given kinds as boundaries.
+///
+/// With `TrampolineKind::PyO3Call`, a Rust caller's shim stands for the Python frame it
+/// enters. Use it when the interpreter's own symbols are missing (statically linked,
+/// stripped libpython); otherwise the eval loop already marks that frame and each call
+/// would count twice.
+#[derive(Clone, Debug)]
+pub struct SecondaryBoundaries<D = CPythonBoundaryDetector> {
+    primary: D,
+    kinds: Vec<TrampolineKind>,
+}
+
+impl SecondaryBoundaries {
+    /// The default CPython detector plus `kinds`.
+    pub fn new(kinds: &[TrampolineKind]) -> Self {
+        Self::with_primary(CPythonBoundaryDetector, kinds)
+    }
+}
+
+impl<D: BoundaryDetector> SecondaryBoundaries<D> {
+    pub fn with_primary(primary: D, kinds: &[TrampolineKind]) -> Self {
+        SecondaryBoundaries {
+            primary,
+            kinds: kinds.to_vec(),
+        }
+    }
+}
+
+impl<D: BoundaryDetector> BoundaryDetector for SecondaryBoundaries<D> {
+    fn is_boundary(&self, frame: &CallFrame) -> bool {
+        self.primary.is_boundary(frame)
+            || TrampolineKind::of(frame).is_some_and(|k| self.kinds.contains(&k))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MergeOptions;
+
+    const PW: &str = "__pyx_pw_5numpy_6random_6mtrand_11RandomState_23random_sample";
+    const PF: &str = "__pyx_pf_5numpy_6random_6mtrand_11RandomState_22random_sample";
+
+    fn cframe(name: &str) -> CallFrame {
+        CallFrame::native("0x0", "", name, 0)
+    }
+
+    fn funcs(frames: &[CallFrame]) -> Vec<&str> {
+        frames.iter().map(CallFrame::func).collect()
+    }
+
+    #[test]
+    fn test_cython_python_name() {
+        let name = "numpy.random.mtrand.RandomState.random_sample";
+        assert_eq!(cython_python_name(PW).as_deref(), Some(name));
+        assert_eq!(cython_python_name(PF).as_deref(), Some(name));
+        assert_eq!(
+            cython_python_name("__pyx_pw_6my_mod_1foo").as_deref(),
+            Some("my_mod.foo")
+        );
+        assert_eq!(cython_python_name("__pyx_pw_"), None);
+        assert_eq!(cython_python_name("PyObject_Call"), None);
+    }
+
+    #[test]
+    fn test_trampoline_kind() {
+        assert_eq!(
+            TrampolineKind::of(&cframe(PW)),
+            Some(TrampolineKind::CythonWrapper)
+        );
+        assert_eq!(
+            TrampolineKind::of(&cframe("method_vectorcall_O")),
+            Some(TrampolineKind::ExtensionCall)
+        );
+        assert_eq!(TrampolineKind::of(&cframe("cfunction_callx")), None);
+        assert_eq!(
+            TrampolineKind::of(&cframe(
+                "<pyo3::instance::Bound<pyo3::types::any::PyAny> as pyo3::types::any::PyAnyMethods>::call1"
+            )),
+            Some(TrampolineKind::PyO3Call)
+        );
+        assert_eq!(
+            TrampolineKind::of(&cframe("pyo3::marker::Python::run::h0123456789abcdef")),
+            Some(TrampolineKind::PyO3Call)
+        );
+        assert_eq!(
+            TrampolineKind::of(&cframe("pyo3::impl_::trampoline::fastcall_with_keywords")),
+            Some(TrampolineKind::PyO3Trampoline)
+        );
+        assert_eq!(
+            TrampolineKind::of(&cframe("pyo3::instance::Py<T>::getattr")),
+            None
+        );
+        assert_eq!(TrampolineKind::of(&cframe("my_app::call")), None);
+        assert_eq!(
+            TrampolineKind::of(&CallFrame::python("0x0", "", "cfunction_call", 0)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_rewrite_trampolines() {
+        let frames = vec![
+            cframe("leaf"),
+            cframe(PF),
+            cframe(PW),
+            cframe("cfunction_call"),
+            cframe("_PyEval_EvalFrameDefault"),
+        ];
+        let opts = TrampolineOptions::new()
+            .cython(TrampolineAction::Collapse)
+            .extension_calls(TrampolineAction::Collapse);
+        let collapsed = SignalTracer::rewrite_trampolines(frames.clone(), &opts);
+        assert_eq!(funcs(&collapsed), ["leaf", PF, "_PyEval_EvalFrameDefault"]);
+
+        let opts = TrampolineOptions::new().cython(TrampolineAction::Relabel);
+        let relabeled = SignalTracer::rewrite_trampolines(frames.clone(), &opts);
+        assert_eq!(
+            funcs(&relabeled)[1],
+            "numpy.random.mtrand.RandomState.random_sample"
+        );
+        assert!(
+            matches!(&relabeled[2], CallFrame::CFrame { raw_func: Some(raw), .. } if raw == PW)
+        );
+        assert_eq!(funcs(&relabeled)[3], "cfunction_call");
+
+        // A wrapper whose implementation was inlined away is kept.
+        let lone = vec![cframe("leaf"), cframe(PW)];
+        let opts = TrampolineOptions::new().cython(TrampolineAction::Collapse);
+        assert_eq!(SignalTracer::rewrite_trampolines(lone.clone(), &opts), lone);
+    }
+
+    #[test]
+    fn test_secondary_boundaries() {
+        let detector = SecondaryBoundaries::new(&[TrampolineKind::CythonWrapper]);
+        assert!(detector.is_boundary(&cframe(PW)));
+        assert!(detector.is_boundary(&cframe("_PyEval_EvalFrameDefault")));
+        assert!(!detector.is_boundary(&cframe(PF)));
+
+        let native = vec![cframe("leaf"), cframe(PW), cframe("main")];
+        let python = vec![CallFrame::python("0x0", "m.pyx", "random_sample", 1)];
+        let opts = MergeOptions::new().boundary_detector(detector);
+        let merged = SignalTracer::merge_python_native_stacks_with(python, native, &opts);
+        assert_eq!(funcs(&merged), ["leaf", "random_sample", "main"]);
+    }
+
+    /// Rust `main` calls Python `handler`, which calls back into the `#[pyfunction]`
+    /// `on_event`; leaf first.
+    fn pyo3_sandwich(eval_loop: &str) -> Vec<CallFrame> {
+        vec![
+            cframe("app::on_event"),
+            cframe("app::__pyfunction_on_event"),
+            cframe("pyo3::impl_::trampoline::fastcall_with_keywords"),
+            cframe("cfunction_vectorcall_FASTCALL_KEYWORDS"),
+            cframe(eval_loop),
+            cframe("PyObject_Call"),
+            cframe("pyo3::instance::Py<T>::call1"),
+            cframe("app::main"),
+        ]
+    }
+
+    #[test]
+    fn test_pyo3_sandwich() {
+        let python = || vec![CallFrame::python("0x0", "handler.py", "handler", 3)];
+        let collapse = TrampolineOptions::new()
+            .pyo3(TrampolineAction::Collapse)
+            .extension_calls(TrampolineAction::Collapse);
+
+        // Eval loop symbols present: the default detector places `handler`, PyO3's
+        // frames are noise to collapse.
+        let merged = SignalTracer::merge_python_native_stacks(
+            python(),
+            pyo3_sandwich("_PyEval_EvalFrameDefault"),
+        );
+        assert_eq!(
+            funcs(&SignalTracer::rewrite_trampolines(merged, &collapse)),
+            [
+                "app::on_event",
+                "app::__pyfunction_on_event",
+                "handler",
+                "PyObject_Call",
+                "app::main"
+            ]
+        );
+
+        // Stripped libpython: the call shim is the boundary.
+        let opts = MergeOptions::new()
+            .boundary_detector(SecondaryBoundaries::new(&[TrampolineKind::PyO3Call]));
+        let merged =
+            SignalTracer::merge_python_native_stacks_with(python(), pyo3_sandwich("??"), &opts);
+        assert_eq!(
+            funcs(&SignalTracer::rewrite_trampolines(merged, &collapse)),
+            [
+                "app::on_event",
+                "app::__pyfunction_on_event",
+                "??",
+                "PyObject_Call",
+                "handler",
+                "app::main"
+            ]
+        );
+    }
+}
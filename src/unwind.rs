@@ -0,0 +1,517 @@
+//! Native unwinding strategies: frame-pointer walking and DWARF CFI (Linux).
+//!
+//! Walking the frame-pointer chain is a few loads per frame and needs no unwind tables,
+//! but only works through code built with frame pointers. DWARF unwinding (the system
+//! unwinder behind `backtrace`, driven by `.eh_frame`) works everywhere but is much
+//! slower. `UnwindStrategy::Auto` takes the fast path when every module on the stack
+//! keeps frame pointers and falls back to DWARF otherwise. Whether a module keeps them
+//! is decided once per module from its function prologues.
+
+use std::fs::File;
+use std::io;
+use std::ops::Range;
+
+use object::read::ReadCache;
+use object::{Architecture, Object, ObjectSection, ObjectSymbol, SymbolKind};
+
+use crate::capture::{resolve_ip, MAX_COUNTED_FRAMES};
+use crate::remote::maps::read_maps;
+use crate::stack_trace::{truncation_marker, CaptureSource, StackTrace};
+use crate::stack_tracer::SignalTracer;
+
+/// Function symbols whose prologue is checked per module.
+const PROLOGUE_SAMPLES: usize = 256;
+
+/// Functions shorter than this are thunks or stubs that never set up a frame.
+const MIN_FUNCTION_SIZE: u64 = 16;
+
+/// How native stacks are walked.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum UnwindStrategy {
+    /// Follow the frame-pointer chain. Fast, but stacks through code built without frame
+    /// pointers come out short or skip frames.
+    FramePointer,
+    /// DWARF call frame information, through the system unwinder.
+    Dwarf,
+    /// Frame pointers when every module on the stack keeps them, DWARF otherwise
+    /// (default).
+    #[default]
+    Auto,
+}
+
+/// A mapped range of executable code and whether its module keeps frame pointers.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct CodeRange {
+    range: Range<u64>,
+    frame_pointers: bool,
+}
+
+/// What the unwinders need to know about the address space: which code keeps frame
+/// pointers, and which memory a frame-pointer chain may point into.
+///
+/// A snapshot of `/proc/self/maps`; libraries loaded and threads started later are
+/// unknown to it, and stacks through them fall back to DWARF under `Auto`.
+#[derive(Clone, Debug, Default)]
+pub struct UnwindTable {
+    /// Sorted by start address.
+    code: Vec<CodeRange>,
+    /// Writable mappings, where stacks live; sorted by start address.
+    writable: Vec<Range<u64>>,
+}
+
+impl UnwindTable {
+    /// Table of the calling process. Reads the prologues of every mapped module once.
+    pub fn current() -> io::Result<UnwindTable> {
+        let maps = read_maps(unsafe { libc::getpid() })?;
+        let mut modules: Vec<(String, bool)> = Vec::new();
+        let mut table = UnwindTable::default();
+        for map in &maps {
+            if map.writable {
+                table.writable.push(map.start..map.end);
+            }
+            if !map.executable {
+                continue;
+            }
+            let frame_pointers = if !map.path.starts_with('/') {
+                // [vdso], JIT code and other anonymous code.
+                false
+            } else if let Some((_, known)) = modules.iter().find(|(p, _)| *p == map.path) {
+                *known
+            } else {
+                let detected = detect_frame_pointers(&map.path).unwrap_or(false);
+                modules.push((map.path.clone(), detected));
+                detected
+            };
+            table.code.push(CodeRange {
+                range: map.start..map.end,
+                frame_pointers,
+            });
+        }
+        Ok(table)
+    }
+
+    /// Whether the module holding `ip` keeps frame pointers; `None` for unmapped ips.
+    pub fn frame_pointers(&self, ip: u64) -> Option<bool> {
+        let index = self.code.partition_point(|c| c.range.end <= ip);
+        self.code
+            .get(index)
+            .filter(|c| c.range.contains(&ip))
+            .map(|c| c.frame_pointers)
+    }
+
+    /// Strategy `Auto` picks for a frame at `ip`.
+    pub fn strategy_for(&self, ip: u64) -> UnwindStrategy {
+        match self.frame_pointers(ip) {
+            Some(true) => UnwindStrategy::FramePointer,
+            _ => UnwindStrategy::Dwarf,
+        }
+    }
+
+    /// End of the writable mapping holding `addr`, the highest address a stack walk
+    /// starting there may read.
+    fn writable_end(&self, addr: u64) -> Option<u64> {
+        let index = self.writable.partition_point(|r| r.end <= addr);
+        self.writable
+            .get(index)
+            .filter(|r| r.contains(&addr))
+            .map(|r| r.end)
+    }
+
+    fn all_frame_pointers(&self, ips: &[usize]) -> bool {
+        ips.iter()
+            .all(|ip| self.frame_pointers(*ip as u64) == Some(true))
+    }
+}
+
+/// Unwinds the calling thread with a fixed strategy.
+#[derive(Clone, Debug)]
+pub struct Unwinder {
+    strategy: UnwindStrategy,
+    table: UnwindTable,
+}
+
+impl Unwinder {
+    /// An unwinder for `strategy`; `Auto` reads the module table up front.
+    pub fn new(strategy: UnwindStrategy) -> io::Result<Unwinder> {
+        let table = match strategy {
+            UnwindStrategy::Auto => UnwindTable::current()?,
+            _ => UnwindTable::default(),
+        };
+        Ok(Unwinder { strategy, table })
+    }
+
+    pub fn strategy(&self) -> UnwindStrategy {
+        self.strategy
+    }
+
+    pub fn table(&self) -> &UnwindTable {
+        &self.table
+    }
+
+    /// Store the return addresses of the calling thread into `out`, leaf first, starting
+    /// at the caller of `unwind`. Returns how many were written.
+    #[inline(never)]
+    pub fn unwind(&self, out: &mut [usize]) -> usize {
+        if self.strategy != UnwindStrategy::Dwarf {
+            let written = unsafe { walk_own_frame_pointers(out) }.min(out.len());
+            if self.strategy == UnwindStrategy::FramePointer
+                || written > 0 && self.table.all_frame_pointers(&out[..written])
+            {
+                return written;
+            }
+        }
+        dwarf_unwind(Self::unwind as *const (), out)
+    }
+
+    /// Walk and resolve the calling thread's stack, up to `max_depth` frames. A deeper
+    /// stack ends with a `[truncated N frames]` marker, N counting up to 1024 more frames.
+    pub fn capture(&self, max_depth: usize) -> StackTrace {
+        let mut ips = vec![0; max_depth + MAX_COUNTED_FRAMES];
+        let len = self.unwind(&mut ips);
+        let kept = len.min(max_depth);
+        let mut frames: Vec<_> = ips[..kept].iter().map(|ip| resolve_ip(*ip)).collect();
+        let dropped = len - kept;
+        if dropped > 0 {
+            frames.push(truncation_marker(dropped));
+        }
+        let mut trace = StackTrace::captured(frames, CaptureSource::InProcess);
+        trace.truncated = dropped > 0;
+        trace.truncated_frames = dropped;
+        trace
+    }
+}
+
+impl SignalTracer {
+    /// Like `capture_native_stack`, walking the stack with `strategy`; see `Unwinder`.
+    pub fn capture_native_stack_with(
+        strategy: UnwindStrategy,
+        max_depth: usize,
+    ) -> io::Result<StackTrace> {
+        Ok(Unwinder::new(strategy)?.capture(max_depth))
+    }
+}
+
+/// DWARF-unwind the calling thread, dropping frames up to and including `skip_through`.
+fn dwarf_unwind(skip_through: *const (), out: &mut [usize]) -> usize {
+    if out.is_empty() {
+        return 0;
+    }
+    let mut count = 0;
+    let mut own = None;
+    backtrace::trace(|frame| {
+        if std::ptr::eq(frame.symbol_address() as *const (), skip_through) {
+            own = Some(count);
+        }
+        out[count] = frame.ip() as usize;
+        count += 1;
+        count < out.len()
+    });
+    let first = own.map_or(0, |i| (i + 1).min(count));
+    out.copy_within(first..count, 0);
+    count - first
+}
+
+/// Follow the frame-pointer chain of the calling thread, within its stack.
+#[inline(always)]
+unsafe fn walk_own_frame_pointers(out: &mut [usize]) -> usize {
+    let Some(stack_end) = own_stack_end() else {
+        return 0;
+    };
+    let sp = std::ptr::addr_of!(stack_end) as usize;
+    walk_frame_pointers(frame_address(), sp..stack_end, out)
+}
+
+/// Unwind an interrupted thread from inside a signal handler, with the registers saved
+/// in `context`. `handler` is the signal handler, skipped under DWARF unwinding. Returns
+/// how many frames the stack has, like `trace_signal_context`: those past `out` are only
+/// counted.
+///
+/// Only reads memory the table knows to be a writable mapping, so it is usable from
+/// async-signal context.
+pub(crate) unsafe fn unwind_signal_context(
+    strategy: UnwindStrategy,
+    table: &UnwindTable,
+    context: *mut libc::c_void,
+    handler: *const (),
+    out: &mut [usize],
+) -> usize {
+    if strategy != UnwindStrategy::Dwarf && !context.is_null() && !out.is_empty() {
+        if let Some((pc, sp, fp)) = context_registers(context) {
+            out[0] = pc;
+            let walked = match table.writable_end(sp as u64) {
+                Some(end) => 1 + walk_frame_pointers(fp, sp..end as usize, &mut out[1..]),
+                None => 1,
+            };
+            if strategy == UnwindStrategy::FramePointer
+                || table.all_frame_pointers(&out[..walked.min(out.len())])
+            {
+                return walked;
+            }
+        }
+    }
+    crate::capture::trace_signal_context(handler, out)
+}
+
+/// Follow a frame-pointer chain starting at `fp`, storing return addresses into `out`.
+/// Returns how many the chain has; past a full `out`, up to `MAX_COUNTED_FRAMES` more
+/// are only counted.
+///
+/// Each frame record is the caller's frame pointer followed by the return address. The
+/// walk stops at a null return address, or when the next record is not above the current
+/// one (the chain must move towards the stack base) or leaves `stack`. Records are only
+/// read inside `stack`.
+pub(crate) unsafe fn walk_frame_pointers(
+    mut fp: usize,
+    stack: Range<usize>,
+    out: &mut [usize],
+) -> usize {
+    let word = std::mem::size_of::<usize>();
+    let mut count = 0;
+    while count < out.len() + MAX_COUNTED_FRAMES
+        && fp >= stack.start
+        && fp.is_multiple_of(word)
+        && fp.checked_add(2 * word).is_some_and(|end| end <= stack.end)
+    {
+        let next = *(fp as *const usize);
+        let ret = *((fp + word) as *const usize);
+        if ret == 0 {
+            break;
+        }
+        if let Some(slot) = out.get_mut(count) {
+            *slot = ret;
+        }
+        count += 1;
+        if next <= fp {
+            break;
+        }
+        fp = next;
+    }
+    count
+}
+
+/// The frame pointer register of the calling function.
+#[inline(always)]
+fn frame_address() -> usize {
+    let fp: usize;
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        std::arch::asm!("mov {}, rbp", out(reg) fp, options(nomem, nostack, preserves_flags));
+    }
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        std::arch::asm!("mov {}, x29", out(reg) fp, options(nomem, nostack, preserves_flags));
+    }
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        fp = 0;
+    }
+    fp
+}
+
+/// `(pc, sp, fp)` saved in a signal handler's `ucontext_t`.
+unsafe fn context_registers(context: *mut libc::c_void) -> Option<(usize, usize, usize)> {
+    let context = &*(context as *const libc::ucontext_t);
+    #[cfg(target_arch = "x86_64")]
+    {
+        let regs = &context.uc_mcontext.gregs;
+        Some((
+            regs[libc::REG_RIP as usize] as usize,
+            regs[libc::REG_RSP as usize] as usize,
+            regs[libc::REG_RBP as usize] as usize,
+        ))
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        let mcontext = &context.uc_mcontext;
+        Some((
+            mcontext.pc as usize,
+            mcontext.sp as usize,
+            mcontext.regs[29] as usize,
+        ))
+    }
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        let _ = context;
+        None
+    }
+}
+
+/// Top (highest address) of the calling thread's stack.
+fn own_stack_end() -> Option<usize> {
+    unsafe {
+        let mut attr: libc::pthread_attr_t = std::mem::zeroed();
+        if libc::pthread_getattr_np(libc::pthread_self(), &mut attr) != 0 {
+            return None;
+        }
+        let mut addr = std::ptr::null_mut();
+        let mut size = 0;
+        let found = libc::pthread_attr_getstack(&attr, &mut addr, &mut size) == 0;
+        libc::pthread_attr_destroy(&mut attr);
+        found.then(|| addr as usize + size)
+    }
+}
+
+/// Whether most sizeable functions of the module at `path` set up a frame record.
+fn detect_frame_pointers(path: &str) -> io::Result<bool> {
+    let invalid = |e: object::Error| io::Error::new(io::ErrorKind::InvalidData, e.to_string());
+    let cache = ReadCache::new(File::open(path)?);
+    let file = object::File::parse(&cache).map_err(invalid)?;
+    let arch = file.architecture();
+    let functions: Vec<_> = file
+        .symbols()
+        .chain(file.dynamic_symbols())
+        .filter(|s| s.kind() == SymbolKind::Text && s.size() >= MIN_FUNCTION_SIZE)
+        .take(PROLOGUE_SAMPLES)
+        .collect();
+    let mut checked = 0;
+    let mut framed = 0;
+    for symbol in functions {
+        let Some(section) = symbol
+            .section_index()
+            .and_then(|i| file.section_by_index(i).ok())
+        else {
+            continue;
+        };
+        let Ok(Some(code)) = section.data_range(symbol.address(), 32.min(symbol.size())) else {
+            continue;
+        };
+        checked += 1;
+        if sets_up_frame(arch, code) {
+            framed += 1;
+        }
+    }
+    Ok(checked > 0 && framed * 2 >= checked)
+}
+
+/// Whether `code`, the start of a function, saves and sets the frame pointer.
+fn sets_up_frame(arch: Architecture, code: &[u8]) -> bool {
+    match arch {
+        Architecture::X86_64 => {
+            // endbr64
+            let code = code.strip_prefix(&[0xf3, 0x0f, 0x1e, 0xfa]).unwrap_or(code);
+            // push %rbp; mov %rsp,%rbp
+            code.starts_with(&[0x55, 0x48, 0x89, 0xe5])
+        }
+        Architecture::Aarch64 => {
+            let insns: Vec<u32> = code
+                .chunks_exact(4)
+                .take(6)
+                .map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]]))
+                .collect();
+            // stp x29, x30, [sp, #imm](!) ... mov/add x29, sp, #imm
+            let saves = insns
+                .iter()
+                .any(|i| i & 0xffc0_7fff == 0xa980_7bfd || i & 0xffc0_7fff == 0xa900_7bfd);
+            let sets = insns.iter().any(|i| i & 0xffc0_03ff == 0x9100_03fd);
+            saves && sets
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_walk_frame_pointers() {
+        // Three frame records on a fake stack, the last one ending the chain.
+        let mut stack = [0usize; 16];
+        let base = stack.as_ptr() as usize;
+        let word = std::mem::size_of::<usize>();
+        let record = |i: usize| base + i * word;
+        stack[2] = record(6);
+        stack[3] = 0x1111;
+        stack[6] = record(10);
+        stack[7] = 0x2222;
+        stack[10] = 0;
+        stack[11] = 0x3333;
+        let bounds = base..base + stack.len() * word;
+
+        let mut out = [0; 8];
+        let written = unsafe { walk_frame_pointers(record(2), bounds.clone(), &mut out) };
+        assert_eq!(&out[..written], &[0x1111, 0x2222, 0x3333]);
+
+        // Frames past `out` are counted, not stored; the stack range bounds the walk.
+        let mut short = [0; 2];
+        assert_eq!(
+            unsafe { walk_frame_pointers(record(2), bounds.clone(), &mut short) },
+            3
+        );
+        assert_eq!(short, [0x1111, 0x2222]);
+        assert_eq!(
+            unsafe { walk_frame_pointers(record(2), base..record(7), &mut out) },
+            1
+        );
+        // A chain pointing downwards is cut.
+        stack[6] = record(1);
+        let start = std::hint::black_box(&stack).as_ptr() as usize + 2 * word;
+        let written = unsafe { walk_frame_pointers(start, bounds, &mut out) };
+        assert_eq!(&out[..written], &[0x1111, 0x2222]);
+    }
+
+    #[test]
+    fn test_prologue_detection() {
+        let x86 = Architecture::X86_64;
+        assert!(sets_up_frame(x86, &[0x55, 0x48, 0x89, 0xe5, 0x90]));
+        assert!(sets_up_frame(
+            x86,
+            &[0xf3, 0x0f, 0x1e, 0xfa, 0x55, 0x48, 0x89, 0xe5]
+        ));
+        // sub $0x18,%rsp
+        assert!(!sets_up_frame(x86, &[0x48, 0x83, 0xec, 0x18]));
+
+        let arm = Architecture::Aarch64;
+        let code = |insns: &[u32]| {
+            insns
+                .iter()
+                .flat_map(|i| i.to_le_bytes())
+                .collect::<Vec<_>>()
+        };
+        // stp x29, x30, [sp, #-16]!; mov x29, sp
+        assert!(sets_up_frame(arm, &code(&[0xa9bf7bfd, 0x910003fd])));
+        // paciasp; sub sp, sp, #0x30; stp x29, x30, [sp, #0x20]; add x29, sp, #0x20
+        assert!(sets_up_frame(
+            arm,
+            &code(&[0xd503233f, 0xd100c3ff, 0xa9027bfd, 0x910083fd])
+        ));
+        // ret
+        assert!(!sets_up_frame(arm, &code(&[0xd65f03c0])));
+    }
+
+    #[inline(never)]
+    fn capture_with(strategy: UnwindStrategy) -> StackTrace {
+        let trace = SignalTracer::capture_native_stack_with(strategy, 64).unwrap();
+        std::hint::black_box(trace)
+    }
+
+    #[test]
+    fn test_strategies() {
+        let dwarf = capture_with(UnwindStrategy::Dwarf);
+        assert!(
+            dwarf.iter().any(|f| f.func().contains("capture_with")),
+            "{:?}",
+            dwarf
+        );
+        // Without frame pointers in this binary `Auto` is DWARF; with them it walks the
+        // chain. Either way the caller is found.
+        let auto = capture_with(UnwindStrategy::Auto);
+        assert!(auto.iter().any(|f| f.func().contains("capture_with")));
+        // Walking frame pointers through code without them stays within the stack.
+        assert!(capture_with(UnwindStrategy::FramePointer).len() <= 64);
+
+        let short = SignalTracer::capture_native_stack_with(UnwindStrategy::Dwarf, 2).unwrap();
+        assert_eq!(short.len(), 3);
+        assert!(short.truncated && short.truncated_frames > 0);
+        assert_eq!(
+            crate::stack_trace::truncated_count(&short[2]),
+            Some(short.truncated_frames)
+        );
+
+        let table = UnwindTable::current().unwrap();
+        let ip = test_strategies as *const () as u64;
+        assert!(table.frame_pointers(ip).is_some());
+        assert_eq!(table.frame_pointers(0), None);
+        assert_eq!(table.strategy_for(0), UnwindStrategy::Dwarf);
+    }
+}
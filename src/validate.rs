@@ -0,0 +1,227 @@
+//! Sanity checks for merged stacks.
+//!
+//! A merge never fails on bad input, it just produces a wrong stack: an unwinder that lost
+//! frames leaves python frames without a boundary, one that stopped early leaves eval
+//! loops without python frames. `MergeWarning` names these cases so that they can be
+//! logged or counted instead of showing up as odd flamegraphs.
+
+use std::fmt;
+
+use crate::boundary::{BoundaryDetector, CPythonBoundaryDetector};
+use crate::merge_options::MergeOptions;
+use crate::stack_tracer::SignalTracer;
+use crate::CallFrame;
+
+/// Something suspicious about a merged stack.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum MergeWarning {
+    /// More python frames than boundaries could take: the extra ones were appended at the
+    /// root or dropped, depending on `ExtraPythonFrames`.
+    PythonFrameCountMismatch { python: usize, boundaries: usize },
+    /// Boundary frames met once the python frames had run out, usually a python stack
+    /// captured at another moment than the native one.
+    UnmatchedBoundaries { count: usize },
+    /// The leaf frame repeats right after itself, as unwinders reporting the interrupted
+    /// instruction pointer twice produce.
+    DuplicatedLeaf,
+}
+
+impl fmt::Display for MergeWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MergeWarning::PythonFrameCountMismatch { python, boundaries } => {
+                write!(f, "{} python frames for {} boundaries", python, boundaries)
+            }
+            MergeWarning::UnmatchedBoundaries { count } => {
+                write!(f, "{} boundaries without a python frame", count)
+            }
+            MergeWarning::DuplicatedLeaf => write!(f, "duplicated leaf frame"),
+        }
+    }
+}
+
+/// A merged stack with the warnings raised while merging it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MergeResult {
+    pub frames: Vec<CallFrame>,
+    pub warnings: Vec<MergeWarning>,
+}
+
+impl MergeResult {
+    /// No warning was raised.
+    pub fn is_clean(&self) -> bool {
+        self.warnings.is_empty()
+    }
+}
+
+impl SignalTracer {
+    /// Check an already merged stack, leaf first, for the default CPython boundaries.
+    ///
+    /// Works from the output alone, so it has to guess: python frames after the outermost
+    /// native frame count as appended leftovers, and boundary frames still present as
+    /// unmatched (a merge with `keep_boundary_frames` reports all of them). The checks of
+    /// `merge_python_native_stacks_checked` are exact.
+    pub fn validate(frames: &[CallFrame]) -> Vec<MergeWarning> {
+        Self::validate_with(frames, &CPythonBoundaryDetector)
+    }
+
+    /// `validate` with another runtime's boundaries.
+    pub fn validate_with(
+        frames: &[CallFrame],
+        detector: &dyn BoundaryDetector,
+    ) -> Vec<MergeWarning> {
+        let mut warnings = Vec::new();
+        let python = frames
+            .iter()
+            .filter(|f| matches!(f, CallFrame::PyFrame { .. }))
+            .count();
+        let trailing = frames
+            .iter()
+            .rev()
+            .take_while(|f| !matches!(f, CallFrame::CFrame { .. }))
+            .filter(|f| matches!(f, CallFrame::PyFrame { .. }))
+            .count();
+        if trailing > 0 && trailing < frames.len() {
+            warnings.push(MergeWarning::PythonFrameCountMismatch {
+                python,
+                boundaries: python - trailing,
+            });
+        }
+        let unmatched = frames.iter().filter(|f| detector.is_boundary(f)).count();
+        if unmatched > 0 {
+            warnings.push(MergeWarning::UnmatchedBoundaries { count: unmatched });
+        }
+        if duplicated_leaf(frames) {
+            warnings.push(MergeWarning::DuplicatedLeaf);
+        }
+        warnings
+    }
+
+    /// Merge like `merge_python_native_stacks_with`, reporting what went wrong on the way.
+    pub fn merge_python_native_stacks_checked(
+        python_stacks: impl Into<Vec<CallFrame>>,
+        native_stacks: impl Into<Vec<CallFrame>>,
+        options: &MergeOptions,
+    ) -> MergeResult {
+        let python_stacks = python_stacks.into();
+        let python = python_stacks.len();
+        let (frames, counts) = Self::merge_inner(
+            python_stacks,
+            native_stacks.into(),
+            options.detector(),
+            options,
+        );
+
+        let mut warnings = Vec::new();
+        if counts.leftover + counts.appended > 0 {
+            warnings.push(MergeWarning::PythonFrameCountMismatch {
+                python,
+                boundaries: counts.boundaries,
+            });
+        }
+        if counts.unmatched_boundaries > 0 {
+            warnings.push(MergeWarning::UnmatchedBoundaries {
+                count: counts.unmatched_boundaries,
+            });
+        }
+        if duplicated_leaf(&frames) {
+            warnings.push(MergeWarning::DuplicatedLeaf);
+        }
+        MergeResult { frames, warnings }
+    }
+
+    /// `merge_python_native_stacks_checked` with the tracer's options.
+    pub fn merge_checked(
+        &self,
+        python_stacks: impl Into<Vec<CallFrame>>,
+        native_stacks: impl Into<Vec<CallFrame>>,
+    ) -> MergeResult {
+        Self::merge_python_native_stacks_checked(python_stacks, native_stacks, self.options())
+    }
+}
+
+/// The two leaf-most frames are the same native frame.
+fn duplicated_leaf(frames: &[CallFrame]) -> bool {
+    match frames {
+        [CallFrame::CFrame { ip: a, func: f, .. }, CallFrame::CFrame { ip: b, func: g, .. }, ..] => {
+            a == b && f == g
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ExtraPythonFrames;
+
+    fn cframe(ip: &str, name: &str) -> CallFrame {
+        CallFrame::native(ip, "", name, 0)
+    }
+
+    fn pyframe(name: &str) -> CallFrame {
+        CallFrame::python("0x0", "", name, 0)
+    }
+
+    #[test]
+    fn test_merge_checked_clean() {
+        let native = vec![
+            cframe("0x1", "read"),
+            cframe("0x2", "_PyEval_EvalFrameDefault"),
+            cframe("0x3", "main"),
+        ];
+        let result = SignalTracer::new().merge_checked(vec![pyframe("f")], native);
+        assert!(result.is_clean(), "{:?}", result.warnings);
+        assert_eq!(result.frames.len(), 3);
+        assert!(SignalTracer::validate(&result.frames).is_empty());
+    }
+
+    #[test]
+    fn test_merge_checked_warnings() {
+        // Two python frames, one boundary.
+        let native = vec![
+            cframe("0x1", "read"),
+            cframe("0x1", "read"),
+            cframe("0x2", "_PyEval_EvalFrameDefault"),
+            cframe("0x3", "main"),
+        ];
+        let python = vec![pyframe("f"), pyframe("g")];
+        let result = SignalTracer::merge_python_native_stacks_checked(
+            python.clone(),
+            native.clone(),
+            &MergeOptions::new(),
+        );
+        assert_eq!(
+            result.warnings,
+            vec![
+                MergeWarning::PythonFrameCountMismatch {
+                    python: 2,
+                    boundaries: 1
+                },
+                MergeWarning::DuplicatedLeaf,
+            ]
+        );
+        assert_eq!(SignalTracer::validate(&result.frames), result.warnings);
+        assert_eq!(
+            result.warnings[0].to_string(),
+            "2 python frames for 1 boundaries"
+        );
+
+        // Dropped leftovers are reported as well.
+        let options = MergeOptions::new().on_extra_python_frames(ExtraPythonFrames::Drop);
+        let result =
+            SignalTracer::merge_python_native_stacks_checked(python, native.clone(), &options);
+        assert!(matches!(
+            result.warnings[0],
+            MergeWarning::PythonFrameCountMismatch { .. }
+        ));
+
+        // No python frames at all.
+        let result = SignalTracer::new().merge_checked(Vec::new(), native[1..].to_vec());
+        assert_eq!(
+            result.warnings,
+            vec![MergeWarning::UnmatchedBoundaries { count: 1 }]
+        );
+        assert_eq!(SignalTracer::validate(&result.frames), result.warnings);
+    }
+}
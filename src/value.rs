@@ -0,0 +1,172 @@
+//! Captured values of Python locals attached to `CallFrame::PyFrame`.
+
+use std::collections::HashMap;
+
+use ordered_float::OrderedFloat;
+use serde::{Deserialize, Serialize};
+
+/// A snapshot of a Python object.
+///
+/// Floats are `OrderedFloat`s so `CallFrame` can stay `Eq` (NaN equals itself). Lists,
+/// tuples and dicts are captured element-wise; anything else is kept as a `Repr`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "lowercase")]
+pub enum Value {
+    None,
+    Bool(bool),
+    Int(i64),
+    /// Serialized as a JSON number; NaN and the infinities, which JSON cannot express, as
+    /// the strings `nan`, `inf` and `-inf`. Reading also accepts the `repr()` strings older
+    /// versions wrote.
+    Float(#[serde(with = "float")] OrderedFloat<f64>),
+    Str(String),
+    /// A list or tuple.
+    List(Vec<Value>),
+    /// A dict; keys that are not strings are stored as their `repr()`.
+    Dict(HashMap<String, Value>),
+    /// A `bytes` or `bytearray`.
+    Bytes(Vec<u8>),
+    /// Fallback for other objects: their `repr()` and type name, e.g. `Tensor`.
+    Repr {
+        repr: String,
+        type_name: String,
+    },
+}
+
+impl Value {
+    /// A `Float` holding `f`.
+    pub fn float(f: f64) -> Self {
+        Value::Float(OrderedFloat(f))
+    }
+
+    /// A `Repr` of an object of type `type_name`.
+    pub fn repr(repr: impl Into<String>, type_name: impl Into<String>) -> Self {
+        Value::Repr {
+            repr: repr.into(),
+            type_name: type_name.into(),
+        }
+    }
+
+    /// Apply `f` to every string held by the value, including nested ones.
+    pub fn map_strings(self, f: &mut impl FnMut(String) -> String) -> Self {
+        match self {
+            Value::Str(s) => Value::Str(f(s)),
+            Value::Repr { repr, type_name } => Value::Repr {
+                repr: f(repr),
+                type_name,
+            },
+            Value::List(items) => {
+                Value::List(items.into_iter().map(|v| v.map_strings(f)).collect())
+            }
+            Value::Dict(entries) => Value::Dict(
+                entries
+                    .into_iter()
+                    .map(|(k, v)| (k, v.map_strings(f)))
+                    .collect(),
+            ),
+            other => other,
+        }
+    }
+}
+
+mod float {
+    use ordered_float::OrderedFloat;
+    use serde::de::{self, Deserializer, Visitor};
+    use serde::Serializer;
+    use std::fmt;
+
+    pub fn serialize<S: Serializer>(
+        f: &OrderedFloat<f64>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match f.0 {
+            f if f.is_finite() => serializer.serialize_f64(f),
+            f if f.is_nan() => serializer.serialize_str("nan"),
+            f if f > 0.0 => serializer.serialize_str("inf"),
+            _ => serializer.serialize_str("-inf"),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<OrderedFloat<f64>, D::Error> {
+        struct FloatVisitor;
+
+        impl Visitor<'_> for FloatVisitor {
+            type Value = OrderedFloat<f64>;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a number or a float repr string")
+            }
+
+            fn visit_f64<E: de::Error>(self, v: f64) -> Result<Self::Value, E> {
+                Ok(OrderedFloat(v))
+            }
+
+            fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+                Ok(OrderedFloat(v as f64))
+            }
+
+            fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+                Ok(OrderedFloat(v as f64))
+            }
+
+            // Python reprs (`0.5`, `1e-05`, `nan`, `inf`) all parse as Rust floats.
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                v.parse()
+                    .map(OrderedFloat)
+                    .map_err(|_| E::invalid_value(de::Unexpected::Str(v), &self))
+            }
+        }
+
+        deserializer.deserialize_any(FloatVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serde_nested() {
+        let mut dict = HashMap::new();
+        dict.insert(
+            "w".to_string(),
+            Value::List(vec![Value::Int(1), Value::Bytes(b"ab".to_vec())]),
+        );
+        let value = Value::List(vec![
+            Value::Dict(dict),
+            Value::repr("tensor([1.])", "Tensor"),
+        ]);
+        let json = serde_json::to_string(&value).unwrap();
+        assert_eq!(
+            json,
+            r#"{"type":"list","value":[{"type":"dict","value":{"w":{"type":"list","value":[{"type":"int","value":1},{"type":"bytes","value":[97,98]}]}}},{"type":"repr","value":{"repr":"tensor([1.])","type_name":"Tensor"}}]}"#
+        );
+        assert_eq!(serde_json::from_str::<Value>(&json).unwrap(), value);
+    }
+
+    #[test]
+    fn test_serde_float() {
+        let json = |v: f64| serde_json::to_string(&Value::float(v)).unwrap();
+        assert_eq!(json(0.5), r#"{"type":"float","value":0.5}"#);
+        assert_eq!(json(f64::NAN), r#"{"type":"float","value":"nan"}"#);
+        assert_eq!(
+            json(f64::NEG_INFINITY),
+            r#"{"type":"float","value":"-inf"}"#
+        );
+
+        let read = |s: &str| serde_json::from_str::<Value>(s).unwrap();
+        assert_eq!(read(r#"{"type":"float","value":2}"#), Value::float(2.0));
+        // Written by versions that kept the repr string.
+        assert_eq!(
+            read(r#"{"type":"float","value":"1e-05"}"#),
+            Value::float(1e-5)
+        );
+        assert_eq!(
+            read(r#"{"type":"float","value":"nan"}"#),
+            Value::float(f64::NAN)
+        );
+        assert!(serde_json::from_str::<Value>(r#"{"type":"float","value":"x"}"#).is_err());
+    }
+}
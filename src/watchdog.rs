@@ -0,0 +1,224 @@
+//! Stall watchdog: dumps merged stacks of all threads when heartbeats stop arriving.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//! use mixed_stack_tracer::watchdog::Watchdog;
+//!
+//! let watchdog = Watchdog::new(Duration::from_secs(30))?;
+//! loop {
+//!     // ... one unit of work, e.g. a training step ...
+//!     watchdog.heartbeat();
+//! }
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! The dump fires once per stall; the next heartbeat re-arms it.
+
+use std::io::{self, Write};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::events::{self, DumpTrigger};
+use crate::fork::BackgroundThread;
+use crate::output::text;
+use crate::sampler::PythonStacksProvider;
+use crate::stack_tracer::SignalTracer;
+use crate::thread_stack::ThreadStack;
+
+/// Called on the watchdog thread with the stacks captured when a stall is detected.
+pub type StallHandler = Box<dyn FnMut(&[ThreadStack]) + Send>;
+
+struct State {
+    last_beat: Instant,
+    fired: bool,
+    stalls: u64,
+    stopped: bool,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    wake: Condvar,
+}
+
+/// A running watchdog thread, stopped when dropped.
+pub struct Watchdog {
+    timeout: Duration,
+    shared: Arc<Shared>,
+    thread: Option<BackgroundThread<()>>,
+}
+
+impl Watchdog {
+    /// Watch for `timeout` without a heartbeat, then write native stacks to stderr.
+    pub fn new(timeout: Duration) -> io::Result<Watchdog> {
+        Self::with_handler(timeout, None, Box::new(log_stall(timeout)))
+    }
+
+    /// Like `new`, merging the native stacks with the Python stacks from `provider`.
+    ///
+    /// The provider typically needs the GIL, so a stall in a thread holding it is only
+    /// reported once the GIL is released.
+    pub fn with_python(timeout: Duration, provider: PythonStacksProvider) -> io::Result<Watchdog> {
+        Self::with_handler(timeout, Some(provider), Box::new(log_stall(timeout)))
+    }
+
+    /// Watch for `timeout` without a heartbeat, then pass the captured stacks to `handler`.
+    pub fn with_handler(
+        timeout: Duration,
+        provider: Option<PythonStacksProvider>,
+        handler: StallHandler,
+    ) -> io::Result<Watchdog> {
+        if timeout.is_zero() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "watchdog timeout must be positive",
+            ));
+        }
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                last_beat: Instant::now(),
+                fired: false,
+                stalls: 0,
+                stopped: false,
+            }),
+            wake: Condvar::new(),
+        });
+        let thread = {
+            let shared = Arc::clone(&shared);
+            thread::Builder::new()
+                .name("mst-watchdog".to_string())
+                .spawn(move || watch(&shared, timeout, provider, handler))?
+        };
+        Ok(Watchdog {
+            timeout,
+            shared,
+            thread: Some(BackgroundThread::new(thread)),
+        })
+    }
+
+    /// Record progress, postponing the next dump by a full timeout.
+    pub fn heartbeat(&self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.last_beat = Instant::now();
+        state.fired = false;
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Number of stalls reported so far.
+    pub fn stalls(&self) -> u64 {
+        self.shared.state.lock().unwrap().stalls
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        if self
+            .thread
+            .as_ref()
+            .is_some_and(BackgroundThread::is_forked)
+        {
+            // In a forked child: the watchdog thread stayed in the parent.
+            return;
+        }
+        self.shared.state.lock().unwrap().stopped = true;
+        self.shared.wake.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn watch(
+    shared: &Shared,
+    timeout: Duration,
+    mut provider: Option<PythonStacksProvider>,
+    mut handler: StallHandler,
+) {
+    let mut state = shared.state.lock().unwrap();
+    loop {
+        if state.stopped {
+            return;
+        }
+        let deadline = state.last_beat + timeout;
+        let now = Instant::now();
+        if state.fired || now < deadline {
+            // After a reported stall only a heartbeat or stop is worth waking for.
+            let wait = if state.fired { timeout } else { deadline - now };
+            state = shared.wake.wait_timeout(state, wait).unwrap().0;
+            continue;
+        }
+
+        state.fired = true;
+        state.stalls += 1;
+        drop(state);
+        let python = provider.as_mut().map(|p| p()).unwrap_or_default();
+        // The thread holding the GIL is not known from here; leave the annotation out.
+        if let Ok(stacks) = SignalTracer::capture_all_threads_with_python_stacks(python, None) {
+            events::stacks_dumped(DumpTrigger::Watchdog, stacks.len());
+            handler(&stacks);
+        }
+        state = shared.state.lock().unwrap();
+    }
+}
+
+fn log_stall(timeout: Duration) -> impl FnMut(&[ThreadStack]) + Send {
+    move |stacks| {
+        let mut err = io::stderr().lock();
+        let _ = writeln!(
+            err,
+            "mixed-stack-tracer: no heartbeat for {:?}, stacks of {} threads:\n",
+            timeout,
+            stacks.len()
+        );
+        let _ = text::write_thread_stacks(&mut err, stacks);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    fn counting_handler() -> (StallHandler, mpsc::Receiver<usize>) {
+        let (tx, rx) = mpsc::channel();
+        let handler: StallHandler = Box::new(move |stacks: &[ThreadStack]| {
+            let _ = tx.send(stacks.len());
+        });
+        (handler, rx)
+    }
+
+    #[test]
+    fn test_stall_dumps_once() {
+        let (handler, rx) = counting_handler();
+        let watchdog = Watchdog::with_handler(Duration::from_millis(50), None, handler).unwrap();
+        let threads = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(threads >= 2, "expected the test and watchdog threads");
+        // No re-fire while the stall persists.
+        assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+        assert_eq!(watchdog.stalls(), 1);
+
+        watchdog.heartbeat();
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(watchdog.stalls(), 2);
+    }
+
+    #[test]
+    fn test_heartbeats_prevent_dump() {
+        let (handler, rx) = counting_handler();
+        let watchdog = Watchdog::with_handler(Duration::from_secs(2), None, handler).unwrap();
+        for _ in 0..20 {
+            thread::sleep(Duration::from_millis(10));
+            watchdog.heartbeat();
+        }
+        drop(watchdog);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_zero_timeout_rejected() {
+        assert!(Watchdog::new(Duration::ZERO).is_err());
+    }
+}